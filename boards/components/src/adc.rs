@@ -5,13 +5,16 @@
 //! Components for using ADC capsules.
 
 use capsules_core::adc::AdcDedicated;
+use capsules_core::adc::AdcSampleTimeout;
 use capsules_core::adc::AdcVirtualized;
 use capsules_core::virtualizers::virtual_adc::{AdcDevice, MuxAdc};
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil::adc;
+use kernel::hil::time::{self, Alarm};
 
 #[macro_export]
 macro_rules! adc_mux_component_static {
@@ -45,6 +48,23 @@ macro_rules! adc_syscall_component_helper {
     };};
 }
 
+#[macro_export]
+macro_rules! adc_sample_timeout_component_static {
+    ($A:ty $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let timeout = kernel::static_buf!(
+            capsules_core::adc::AdcSampleTimeout<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+
+        (alarm, timeout)
+    };};
+}
+
 #[macro_export]
 macro_rules! adc_dedicated_component_static {
     ($A:ty $(,)?) => {{
@@ -147,6 +167,45 @@ impl Component for AdcVirtualComponent {
     }
 }
 
+pub struct AdcSampleTimeoutComponent<A: 'static + time::Alarm<'static>> {
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    adc: &'static AdcVirtualized<'static>,
+    timeout_ms: u32,
+}
+
+impl<A: 'static + time::Alarm<'static>> AdcSampleTimeoutComponent<A> {
+    pub fn new(
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        adc: &'static AdcVirtualized<'static>,
+        timeout_ms: u32,
+    ) -> Self {
+        AdcSampleTimeoutComponent {
+            alarm_mux,
+            adc,
+            timeout_ms,
+        }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>> Component for AdcSampleTimeoutComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<AdcSampleTimeout<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static AdcSampleTimeout<'static, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        alarm.setup();
+
+        let timeout = s.1.write(AdcSampleTimeout::new(alarm, self.timeout_ms));
+        alarm.set_alarm_client(timeout);
+        self.adc.set_sample_timeout(timeout);
+
+        timeout
+    }
+}
+
 pub type AdcDedicatedComponentType<A> = capsules_core::adc::AdcDedicated<'static, A>;
 
 pub struct AdcDedicatedComponent<
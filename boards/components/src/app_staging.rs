@@ -0,0 +1,150 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for staging app binaries in the internal flash.
+//!
+//! The staging region and the app slots are both in the flash `F`. The board
+//! must call `install()` on the result before the kernel loop starts, and
+//! load the processes from the `InstallClient` once the staged image is
+//! installed.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let app_staging = components::app_staging::AppStagingComponent::new(
+//!     board_kernel,
+//!     capsules_extra::app_staging::DRIVER_NUM,
+//!     &base_peripherals.nvmc,
+//!     0xC0000,                               // Start of the staging region.
+//!     0x20000,                               // Length of the staging region.
+//!     core::ptr::addr_of!(_sapps) as usize, // Start of the first slot.
+//!     0x10000,                               // Size of a slot.
+//!     4,                                     // Number of slots.
+//!     ShortId::Fixed(updater_id),
+//! )
+//! .finalize(components::app_staging_component_static!(
+//!     nrf52840::nvmc::Nvmc,
+//!     512
+//! ));
+//! app_staging.set_install_client(process_loader);
+//! let _ = app_staging.install();
+//! ```
+
+use capsules_extra::app_staging::AppStaging;
+use capsules_extra::nonvolatile_to_pages::NonvolatileToPages;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::process::ShortId;
+
+#[macro_export]
+macro_rules! app_staging_component_static {
+    ($F:ty, $buffer_size: literal) => {{
+        let buffer = kernel::static_buf!([u8; $buffer_size]);
+        let page_buffer = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+        let nv_to_page = kernel::static_buf!(
+            capsules_extra::nonvolatile_to_pages::NonvolatileToPages<'static, $F>
+        );
+        let app_staging = kernel::static_buf!(capsules_extra::app_staging::AppStaging<'static>);
+        (buffer, page_buffer, nv_to_page, app_staging)
+    };};
+}
+
+pub struct AppStagingComponent<
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, NonvolatileToPages<'static, F>>,
+    const BUF_LEN: usize,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    flash: &'static F,
+    staging_start: usize,
+    staging_length: usize,
+    slots_start: usize,
+    slot_size: usize,
+    slot_count: usize,
+    updater: ShortId,
+}
+
+impl<
+        F: 'static
+            + hil::flash::Flash
+            + hil::flash::HasClient<'static, NonvolatileToPages<'static, F>>,
+        const BUF_LEN: usize,
+    > AppStagingComponent<F, BUF_LEN>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        flash: &'static F,
+        staging_start: usize,
+        staging_length: usize,
+        slots_start: usize,
+        slot_size: usize,
+        slot_count: usize,
+        updater: ShortId,
+    ) -> AppStagingComponent<F, BUF_LEN> {
+        AppStagingComponent {
+            board_kernel,
+            driver_num,
+            flash,
+            staging_start,
+            staging_length,
+            slots_start,
+            slot_size,
+            slot_count,
+            updater,
+        }
+    }
+}
+
+impl<
+        F: 'static
+            + hil::flash::Flash
+            + hil::flash::HasClient<'static, NonvolatileToPages<'static, F>>,
+        const BUF_LEN: usize,
+    > Component for AppStagingComponent<F, BUF_LEN>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<NonvolatileToPages<'static, F>>,
+        &'static mut MaybeUninit<AppStaging<'static>>,
+    );
+    type Output = &'static AppStaging<'static>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let buffer = static_buffer.0.write([0; BUF_LEN]);
+
+        let flash_pagebuffer = static_buffer
+            .1
+            .write(<F as hil::flash::Flash>::Page::default());
+
+        let nv_to_page = static_buffer
+            .2
+            .write(NonvolatileToPages::new(self.flash, flash_pagebuffer));
+        hil::flash::HasClient::set_client(self.flash, nv_to_page);
+
+        let app_staging = static_buffer.3.write(AppStaging::new(
+            nv_to_page,
+            self.staging_start,
+            self.staging_length,
+            nv_to_page,
+            self.slots_start,
+            self.slot_size,
+            self.slot_count,
+            self.updater,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            buffer,
+        ));
+
+        nv_to_page.set_client(app_staging);
+
+        app_staging
+    }
+}
@@ -4,6 +4,9 @@
 
 //! Components for the HD447880 LCD controller.
 //!
+//! The size of the display, in characters, is given by the `width` and
+//! `height` parameters, for example 16x2 or 20x4.
+//!
 //! Usage
 //! -----
 //! ```rust
@@ -23,7 +26,10 @@
 //!                                                      // data 6 pin
 //!                                                      gpio_ports.pins[5][15].as_ref().unwrap(),
 //!                                                      // data 7 pin
-//!                                                      gpio_ports.pins[6][14].as_ref().unwrap())
+//!                                                      gpio_ports.pins[6][14].as_ref().unwrap(),
+//!                                                      EntryDirection::LeftToRight,
+//!                                                      // entry shift
+//!                                                      false)
 //!     .finalize(
 //!     components::hd44780_component_static!(
 //!         stm32f429zi::tim2::Tim2,
@@ -34,7 +40,7 @@
 //! ```
 
 use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
-use capsules_extra::hd44780::HD44780;
+use capsules_extra::hd44780::{EntryDirection, HD44780};
 use core::mem::MaybeUninit;
 use kernel::component::Component;
 use kernel::hil::time;
@@ -69,6 +75,8 @@ pub struct HD44780Component<A: 'static + time::Alarm<'static>> {
     data_5_pin: &'static dyn kernel::hil::gpio::Pin,
     data_6_pin: &'static dyn kernel::hil::gpio::Pin,
     data_7_pin: &'static dyn kernel::hil::gpio::Pin,
+    entry_direction: EntryDirection,
+    entry_shift: bool,
 }
 
 impl<A: 'static + time::Alarm<'static>> HD44780Component<A> {
@@ -82,6 +90,8 @@ impl<A: 'static + time::Alarm<'static>> HD44780Component<A> {
         data_5_pin: &'static dyn kernel::hil::gpio::Pin,
         data_6_pin: &'static dyn kernel::hil::gpio::Pin,
         data_7_pin: &'static dyn kernel::hil::gpio::Pin,
+        entry_direction: EntryDirection,
+        entry_shift: bool,
     ) -> HD44780Component<A> {
         HD44780Component {
             alarm_mux,
@@ -93,6 +103,8 @@ impl<A: 'static + time::Alarm<'static>> HD44780Component<A> {
             data_5_pin,
             data_6_pin,
             data_7_pin,
+            entry_direction,
+            entry_shift,
         }
     }
 }
@@ -122,8 +134,11 @@ impl<A: 'static + time::Alarm<'static>> Component for HD44780Component<A> {
             lcd_alarm,
             self.width,
             self.height,
+            self.entry_direction,
+            self.entry_shift,
         ));
         lcd_alarm.set_alarm_client(hd44780);
+        kernel::deferred_call::DeferredCallClient::register(hd44780);
 
         hd44780
     }
@@ -9,7 +9,14 @@
 //! Usage
 //! -----
 //! ```rust
-//! let l3gd20 = components::l3gd20::L3gd20Component::new(spi_mux, stm32f429zi::gpio::PinId::PE03).finalize(
+//! let l3gd20 = components::l3gd20::L3gd20Component::new(
+//!     spi_mux,
+//!     stm32f429zi::gpio::PinId::PE03,
+//!     None,
+//!     board_kernel,
+//!     capsules_extra::l3gd20::DRIVER_NUM,
+//! )
+//! .finalize(
 //!     components::l3gd20_component_static!(stm32f429zi::spi::Spi));
 //! ```
 
@@ -19,6 +26,7 @@ use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
+use kernel::hil::gpio;
 use kernel::hil::spi;
 use kernel::hil::spi::SpiMasterDevice;
 
@@ -48,6 +56,7 @@ pub type L3gd20ComponentType<S> = capsules_extra::l3gd20::L3gd20Spi<'static, S>;
 pub struct L3gd20Component<S: 'static + spi::SpiMaster<'static>> {
     spi_mux: &'static MuxSpiMaster<'static, S>,
     chip_select: S::ChipSelect,
+    interrupt_pin: Option<&'static dyn gpio::InterruptPin<'static>>,
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
 }
@@ -56,12 +65,14 @@ impl<S: 'static + spi::SpiMaster<'static>> L3gd20Component<S> {
     pub fn new(
         spi_mux: &'static MuxSpiMaster<'static, S>,
         chip_select: S::ChipSelect,
+        interrupt_pin: Option<&'static dyn gpio::InterruptPin<'static>>,
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
     ) -> L3gd20Component<S> {
         L3gd20Component {
             spi_mux,
             chip_select,
+            interrupt_pin,
             board_kernel,
             driver_num,
         }
@@ -93,10 +104,17 @@ impl<S: 'static + spi::SpiMaster<'static>> Component for L3gd20Component<S> {
             .3
             .write([0; capsules_extra::l3gd20::RX_BUF_LEN]);
 
-        let l3gd20 = static_buffer
-            .1
-            .write(L3gd20Spi::new(spi_device, txbuffer, rxbuffer, grant));
+        let l3gd20 = static_buffer.1.write(L3gd20Spi::new(
+            spi_device,
+            self.interrupt_pin,
+            txbuffer,
+            rxbuffer,
+            grant,
+        ));
         spi_device.set_client(l3gd20);
+        self.interrupt_pin.map(|pin| {
+            pin.set_client(l3gd20);
+        });
 
         // TODO verify SPI return value
         let _ = l3gd20.configure();
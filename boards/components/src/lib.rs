@@ -12,6 +12,7 @@ pub mod alarm;
 pub mod analog_comparator;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod app_staging;
 pub mod appid;
 pub mod ble;
 pub mod bme280;
@@ -9,7 +9,14 @@
 //! Usage
 //! -----
 //! ```rust
-//! let lsm303dlhc = components::lsm303dlhc::Lsm303dlhcI2CComponent::new(i2c_mux, board_kernel, driver_num)
+//! let lsm303dlhc = components::lsm303dlhc::Lsm303dlhcI2CComponent::new(
+//!     i2c_mux,
+//!     None,
+//!     None,
+//!     None,
+//!     board_kernel,
+//!     driver_num,
+//! )
 //!    .finalize(components::lsm303dlhc_component_static!());
 //!
 //! lsm303dlhc.configure(
@@ -27,6 +34,7 @@ use capsules_extra::lsm303dlhc::Lsm303dlhcI2C;
 use capsules_extra::lsm303xx;
 use core::mem::MaybeUninit;
 use kernel::component::Component;
+use kernel::hil::gpio;
 use kernel::hil::i2c;
 
 // Setup static space for the objects.
@@ -53,6 +61,7 @@ pub struct Lsm303dlhcI2CComponent<I: 'static + i2c::I2CMaster<'static>> {
     i2c_mux: &'static MuxI2C<'static, I>,
     accelerometer_i2c_address: u8,
     magnetometer_i2c_address: u8,
+    interrupt_pin: Option<&'static dyn gpio::InterruptPin<'static>>,
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
 }
@@ -62,6 +71,7 @@ impl<I: 'static + i2c::I2CMaster<'static>> Lsm303dlhcI2CComponent<I> {
         i2c_mux: &'static MuxI2C<'static, I>,
         accelerometer_i2c_address: Option<u8>,
         magnetometer_i2c_address: Option<u8>,
+        interrupt_pin: Option<&'static dyn gpio::InterruptPin<'static>>,
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
     ) -> Lsm303dlhcI2CComponent<I> {
@@ -71,6 +81,7 @@ impl<I: 'static + i2c::I2CMaster<'static>> Lsm303dlhcI2CComponent<I> {
                 .unwrap_or(lsm303xx::ACCELEROMETER_BASE_ADDRESS),
             magnetometer_i2c_address: magnetometer_i2c_address
                 .unwrap_or(lsm303xx::MAGNETOMETER_BASE_ADDRESS),
+            interrupt_pin,
             board_kernel,
             driver_num,
         }
@@ -99,14 +110,18 @@ impl<I: 'static + i2c::I2CMaster<'static>> Component for Lsm303dlhcI2CComponent<
             .1
             .write(I2CDevice::new(self.i2c_mux, self.magnetometer_i2c_address));
 
-        let lsm303dlhc = static_buffer.3.write(Lsm303dlhcI2C::new(
+        let lsm303dlhc = static_buffer.3.write(Lsm303dlhcI2C::new_default_identity(
             accelerometer_i2c,
             magnetometer_i2c,
+            self.interrupt_pin,
             buffer,
             self.board_kernel.create_grant(self.driver_num, &grant_cap),
         ));
         accelerometer_i2c.set_client(lsm303dlhc);
         magnetometer_i2c.set_client(lsm303dlhc);
+        self.interrupt_pin.map(|pin| {
+            pin.set_client(lsm303dlhc);
+        });
 
         lsm303dlhc
     }
@@ -2,16 +2,20 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright Tock Contributors 2022.
 
-//! Component for LPS25HB pressure sensor.
+//! Component for LTC294X battery gas gauges.
 //!
 //! Usage
 //! -----
 //!
 //! ```rust
-//! let ltc294x = components::Ltc294xComponent::new(i2c_mux, 0x64, None)
-//!     .finalize(components::ltc294x_component_static!());
-//! let ltc294x_driver = components::Ltc294xDriverComponent::new(ltc294x, board_kernel, DRIVER_NUM)
-//!     .finalize(components::ltc294x_driver_component_static!());
+//! let ltc294x = components::ltc294x::Ltc294xComponent::new(i2c_mux, 0x64, None)
+//!     .finalize(components::ltc294x_component_static!(stm32f429zi::i2c::I2C));
+//! let ltc294x_driver = components::ltc294x::Ltc294xDriverComponent::new(
+//!     ltc294x,
+//!     board_kernel,
+//!     capsules_extra::ltc294x::DRIVER_NUM,
+//! )
+//! .finalize(components::ltc294x_driver_component_static!(stm32f429zi::i2c::I2C));
 //! ```
 
 use capsules_core::virtualizers::virtual_i2c::{I2CDevice, MuxI2C};
@@ -43,8 +47,13 @@ macro_rules! ltc294x_component_static {
 
 #[macro_export]
 macro_rules! ltc294x_driver_component_static {
-    () => {{
-        kernel::static_buf!(capsules_extra::ltc294x::LTC294XDriver<'static>)
+    ($I:ty $(,)?) => {{
+        kernel::static_buf!(
+            capsules_extra::ltc294x::LTC294XDriver<
+                'static,
+                capsules_core::virtualizers::virtual_i2c::I2CDevice<'static, $I>,
+            >
+        )
     };};
 }
 
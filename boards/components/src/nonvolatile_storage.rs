@@ -4,8 +4,14 @@
 
 //! Component for non-volatile storage Drivers.
 //!
-//! This provides one component, NonvolatileStorageComponent, which provides
-//! a system call interface to non-volatile storage.
+//! This provides two components:
+//!
+//! * NonvolatileStorageComponent provides a system call interface to
+//!   non-volatile storage on top of a flash controller. Flash is written and
+//!   erased by pages, of the size of the page type of the controller.
+//! * NonvolatileStorageDriverComponent provides the same interface to a device
+//!   that already implements `hil::nonvolatile_storage::NonvolatileStorage`,
+//!   such as an FRAM chip, whose geometry is given.
 //!
 //! Usage
 //! -----
@@ -13,6 +19,7 @@
 //! let nonvolatile_storage = components::nonvolatile_storage::NonvolatileStorageComponent::new(
 //!     board_kernel,
 //!     &sam4l::flashcalw::FLASH_CONTROLLER,
+//!     0x80000,
 //!     0x60000,
 //!     0x20000,
 //!     core::ptr::addr_of!(_sstorage) as usize,
@@ -21,9 +28,26 @@
 //! .finalize(components::nonvolatile_storage_component_static!(
 //!     sam4l::flashcalw::FLASHCALW
 //! ));
+//!
+//! let nonvolatile_storage =
+//!     components::nonvolatile_storage::NonvolatileStorageDriverComponent::new(
+//!         board_kernel,
+//!         capsules_extra::nonvolatile_storage_driver::DRIVER_NUM,
+//!         fm25cl,
+//!         0x0000,
+//!         0x4000,
+//!         0x4000,
+//!         0x4000,
+//!         StorageGeometry {
+//!             page_size: 1,
+//!             erase_size: 1,
+//!             total_size: 0x8000,
+//!         },
+//!     )
+//!     .finalize(components::nonvolatile_storage_driver_component_static!());
 //! ```
 
-use capsules_extra::nonvolatile_storage_driver::NonvolatileStorage;
+use capsules_extra::nonvolatile_storage_driver::{NonvolatileStorage, StorageGeometry};
 use capsules_extra::nonvolatile_to_pages::NonvolatileToPages;
 use core::mem::MaybeUninit;
 use kernel::capabilities;
@@ -48,6 +72,18 @@ macro_rules! nonvolatile_storage_component_static {
     };};
 }
 
+#[macro_export]
+macro_rules! nonvolatile_storage_driver_component_static {
+    () => {{
+        let ns = kernel::static_buf!(
+            capsules_extra::nonvolatile_storage_driver::NonvolatileStorage<'static>
+        );
+        let buffer = kernel::static_buf!([u8; capsules_extra::nonvolatile_storage_driver::BUF_LEN]);
+
+        (ns, buffer)
+    };};
+}
+
 pub type NonvolatileStorageComponentType = NonvolatileStorage<'static>;
 
 pub struct NonvolatileStorageComponent<
@@ -56,6 +92,7 @@ pub struct NonvolatileStorageComponent<
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
     flash: &'static F,
+    flash_size: usize,
     userspace_start: usize,
     userspace_length: usize,
     kernel_start: usize,
@@ -72,6 +109,7 @@ impl<
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
         flash: &'static F,
+        flash_size: usize,
         userspace_start: usize,
         userspace_length: usize,
         kernel_start: usize,
@@ -81,6 +119,7 @@ impl<
             board_kernel,
             driver_num,
             flash,
+            flash_size,
             userspace_start,
             userspace_length,
             kernel_start,
@@ -113,6 +152,7 @@ impl<
         let flash_pagebuffer = static_buffer
             .0
             .write(<F as hil::flash::Flash>::Page::default());
+        let page_size = flash_pagebuffer.as_mut().len();
 
         let nv_to_page = static_buffer
             .1
@@ -126,9 +166,80 @@ impl<
             self.userspace_length, // Length of userspace accessible region
             self.kernel_start,    // Start address of kernel region
             self.kernel_length,   // Length of kernel region
+            StorageGeometry {
+                page_size,
+                erase_size: page_size,
+                total_size: self.flash_size,
+            },
             buffer,
         ));
         hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, nonvolatile_storage);
+        kernel::deferred_call::DeferredCallClient::register(nonvolatile_storage);
+        nonvolatile_storage
+    }
+}
+
+pub struct NonvolatileStorageDriverComponent {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    userspace_start: usize,
+    userspace_length: usize,
+    kernel_start: usize,
+    kernel_length: usize,
+    geometry: StorageGeometry,
+}
+
+impl NonvolatileStorageDriverComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        userspace_start: usize,
+        userspace_length: usize,
+        kernel_start: usize,
+        kernel_length: usize,
+        geometry: StorageGeometry,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            storage,
+            userspace_start,
+            userspace_length,
+            kernel_start,
+            kernel_length,
+            geometry,
+        }
+    }
+}
+
+impl Component for NonvolatileStorageDriverComponent {
+    type StaticInput = (
+        &'static mut MaybeUninit<NonvolatileStorage<'static>>,
+        &'static mut MaybeUninit<[u8; capsules_extra::nonvolatile_storage_driver::BUF_LEN]>,
+    );
+    type Output = &'static NonvolatileStorage<'static>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let buffer = static_buffer
+            .1
+            .write([0; capsules_extra::nonvolatile_storage_driver::BUF_LEN]);
+
+        let nonvolatile_storage = static_buffer.0.write(NonvolatileStorage::new(
+            self.storage,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            self.userspace_start,
+            self.userspace_length,
+            self.kernel_start,
+            self.kernel_length,
+            self.geometry,
+            buffer,
+        ));
+        self.storage.set_client(nonvolatile_storage);
+        kernel::deferred_call::DeferredCallClient::register(nonvolatile_storage);
         nonvolatile_storage
     }
 }
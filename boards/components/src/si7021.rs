@@ -13,6 +13,21 @@
 //! let si7021 = SI7021Component::new(mux_i2c, mux_alarm, 0x40).finalize(
 //!     components::si7021_component_static!(sam4l::ast::Ast));
 //! ```
+//!
+//! The heater and the resolution are exposed to userspace by the
+//! Si7021DriverComponent:
+//!
+//! ```rust
+//! let si7021_driver = Si7021DriverComponent::new(
+//!     si7021,
+//!     board_kernel,
+//!     capsules_extra::si7021::DRIVER_NUM,
+//! )
+//! .finalize(components::si7021_driver_component_static!(
+//!     sam4l::ast::Ast,
+//!     sam4l::i2c::I2CHw
+//! ));
+//! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
 // Last modified: 6/20/2018
@@ -21,8 +36,10 @@ use core::mem::MaybeUninit;
 
 use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules_core::virtualizers::virtual_i2c::{I2CDevice, MuxI2C};
-use capsules_extra::si7021::SI7021;
+use capsules_extra::si7021::{Si7021Driver, SI7021};
+use kernel::capabilities;
 use kernel::component::Component;
+use kernel::create_capability;
 use kernel::hil::i2c;
 use kernel::hil::time::{self, Alarm};
 
@@ -48,6 +65,19 @@ macro_rules! si7021_component_static {
     };};
 }
 
+#[macro_export]
+macro_rules! si7021_driver_component_static {
+    ($A:ty, $I:ty $(,)? ) => {{
+        kernel::static_buf!(
+            capsules_extra::si7021::Si7021Driver<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+                capsules_core::virtualizers::virtual_i2c::I2CDevice<'static, $I>,
+            >
+        )
+    };};
+}
+
 pub type SI7021ComponentType<A, I> = capsules_extra::si7021::SI7021<'static, A, I>;
 
 pub struct SI7021Component<A: 'static + time::Alarm<'static>, I: 'static + i2c::I2CMaster<'static>>
@@ -105,3 +135,50 @@ impl<A: 'static + time::Alarm<'static>, I: 'static + i2c::I2CMaster<'static>> Co
         si7021
     }
 }
+
+pub type Si7021DriverComponentType<A, I> = Si7021Driver<'static, A, I>;
+
+pub struct Si7021DriverComponent<
+    A: 'static + time::Alarm<'static>,
+    I: 'static + i2c::I2CMaster<'static>,
+> {
+    si7021: &'static SI7021<'static, VirtualMuxAlarm<'static, A>, I2CDevice<'static, I>>,
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+}
+
+impl<A: 'static + time::Alarm<'static>, I: 'static + i2c::I2CMaster<'static>>
+    Si7021DriverComponent<A, I>
+{
+    pub fn new(
+        si7021: &'static SI7021<'static, VirtualMuxAlarm<'static, A>, I2CDevice<'static, I>>,
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+    ) -> Self {
+        Si7021DriverComponent {
+            si7021,
+            board_kernel,
+            driver_num,
+        }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>, I: 'static + i2c::I2CMaster<'static>> Component
+    for Si7021DriverComponent<A, I>
+{
+    type StaticInput = &'static mut MaybeUninit<
+        Si7021Driver<'static, VirtualMuxAlarm<'static, A>, I2CDevice<'static, I>>,
+    >;
+    type Output =
+        &'static Si7021Driver<'static, VirtualMuxAlarm<'static, A>, I2CDevice<'static, I>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let grant = self.board_kernel.create_grant(self.driver_num, &grant_cap);
+
+        let si7021_driver = s.write(Si7021Driver::new(self.si7021, grant));
+        self.si7021.set_config_client(si7021_driver);
+
+        si7021_driver
+    }
+}
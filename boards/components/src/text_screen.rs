@@ -18,8 +18,12 @@
 //!
 //! ```rust
 //! let text_screen =
-//!     components::text_screen::TextScreenComponent::new(board_kernel, tft)
-//!         .finalize(components::text_screen_component_static!(40960));
+//!     components::text_screen::TextScreenComponent::new(
+//!         board_kernel,
+//!         capsules_extra::text_screen::DRIVER_NUM,
+//!         lcd,
+//!     )
+//!     .finalize(components::text_screen_component_static!(40960));
 //! ```
 //!
 
@@ -33,7 +37,7 @@ use kernel::create_capability;
 macro_rules! text_screen_component_static {
     ($s:literal $(,)?) => {{
         let buffer = kernel::static_buf!([u8; $s]);
-        let screen = kernel::static_buf!(capsules_extra::screen::TextScreen);
+        let screen = kernel::static_buf!(capsules_extra::text_screen::TextScreen<'static>);
 
         (buffer, screen)
     };};
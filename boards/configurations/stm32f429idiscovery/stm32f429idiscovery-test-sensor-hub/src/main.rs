@@ -0,0 +1,759 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Reference "sensor hub" configuration of the STM32F429I Discovery board.
+//!
+//! This board spells out how the sensor, storage and display capsules are
+//! composed: which muxes they share, which grants they create and which
+//! clients are connected in each `finalize()`. The peripherals are breakout
+//! boards wired to the headers, see the README for the pinout.
+//!
+//! - <https://www.st.com/en/evaluation-tools/32f429idiscovery.html>
+
+#![no_std]
+// Disable this attribute when documenting, as a workaround for
+// https://github.com/rust-lang/rust/issues/62184.
+#![cfg_attr(not(doc), no_main)]
+#![deny(missing_docs)]
+
+use core::ptr::{addr_of, addr_of_mut};
+
+use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+use capsules_core::virtualizers::virtual_i2c::I2CDevice;
+use capsules_core::virtualizers::virtual_spi::VirtualSpiMasterDevice;
+use capsules_extra::lsm303xx;
+use capsules_extra::nonvolatile_storage_driver::StorageGeometry;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::hil::led::LedHigh;
+use kernel::platform::{KernelResources, SyscallDriverLookup};
+use kernel::scheduler::round_robin::RoundRobinSched;
+use kernel::{create_capability, debug, static_init};
+
+use stm32f429zi::gpio::{AlternateFunction, Mode, PinId, PortId};
+use stm32f429zi::interrupt_service::Stm32f429ziDefaultPeripherals;
+
+/// Support routines for debugging I/O.
+pub mod io;
+
+// Number of concurrent processes this platform supports.
+const NUM_PROCS: usize = 4;
+
+// Actual memory for holding the active process structures.
+static mut PROCESSES: [Option<&'static dyn kernel::process::Process>; NUM_PROCS] =
+    [None, None, None, None];
+
+static mut CHIP: Option<&'static stm32f429zi::chip::Stm32f4xx<Stm32f429ziDefaultPeripherals>> =
+    None;
+static mut PROCESS_PRINTER: Option<&'static kernel::process::ProcessPrinterText> = None;
+
+// How should the kernel respond when a process faults.
+const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::PanicFaultPolicy {};
+
+/// Dummy buffer that causes the linker to reserve enough space for the stack.
+#[no_mangle]
+#[link_section = ".stack_buffer"]
+pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
+
+/// How long a sample of the ADC may take before the channel is given up on.
+const ADC_SAMPLE_TIMEOUT_MS: u32 = 100;
+
+/// The FM25CL is split in two: the first half for userspace, the second half
+/// for the kernel.
+const FRAM_USERSPACE_START: usize = 0x0000;
+const FRAM_USERSPACE_LENGTH: usize = 0x4000;
+const FRAM_KERNEL_START: usize = 0x4000;
+const FRAM_KERNEL_LENGTH: usize = 0x4000;
+/// FRAM has no pages, every byte is written and erased on its own.
+const FRAM_GEOMETRY: StorageGeometry = StorageGeometry {
+    page_size: 1,
+    erase_size: 1,
+    total_size: FRAM_USERSPACE_LENGTH + FRAM_KERNEL_LENGTH,
+};
+
+type Alarm = VirtualMuxAlarm<'static, stm32f429zi::tim2::Tim2<'static>>;
+type I2CSensor = I2CDevice<'static, stm32f429zi::i2c::I2C<'static>>;
+type SpiSensor = VirtualSpiMasterDevice<'static, stm32f429zi::spi::Spi<'static>>;
+
+type L3GD20Sensor = components::l3gd20::L3gd20ComponentType<SpiSensor>;
+type Lsm303dlhcSensor = capsules_extra::lsm303dlhc::Lsm303dlhcI2C<'static, I2CSensor>;
+type SI7021Sensor = components::si7021::SI7021ComponentType<Alarm, I2CSensor>;
+type TemperatureDriver = components::temperature::TemperatureComponentType<SI7021Sensor>;
+type HumidityDriver = components::humidity::HumidityComponentType<SI7021Sensor>;
+type Si7021Driver = components::si7021::Si7021DriverComponentType<Alarm, I2CSensor>;
+type Ltc294xDriver = capsules_extra::ltc294x::LTC294XDriver<'static, I2CSensor>;
+type RngDriver = components::rng::RngComponentType<stm32f429zi::trng::Trng<'static>>;
+
+/// A structure representing this platform that holds references to all
+/// capsules for this platform.
+struct STM32F429IDiscoverySensorHub {
+    console: &'static capsules_core::console::Console<'static>,
+    ipc: kernel::ipc::IPC<{ NUM_PROCS as u8 }>,
+    led: &'static capsules_core::led::LedDriver<
+        'static,
+        LedHigh<'static, stm32f429zi::gpio::Pin<'static>>,
+        2,
+    >,
+    button: &'static capsules_core::button::Button<'static, stm32f429zi::gpio::Pin<'static>>,
+    alarm: &'static capsules_core::alarm::AlarmDriver<'static, Alarm>,
+    adc: &'static capsules_core::adc::AdcVirtualized<'static>,
+    l3gd20: &'static L3GD20Sensor,
+    lsm303dlhc: &'static Lsm303dlhcSensor,
+    ninedof: &'static capsules_extra::ninedof::NineDof<'static>,
+    temperature: &'static TemperatureDriver,
+    humidity: &'static HumidityDriver,
+    si7021: &'static Si7021Driver,
+    ltc294x: &'static Ltc294xDriver,
+    nonvolatile_storage:
+        &'static capsules_extra::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    text_screen: &'static capsules_extra::text_screen::TextScreen<'static>,
+    rng: &'static RngDriver,
+
+    scheduler: &'static RoundRobinSched<'static>,
+    systick: cortexm4::systick::SysTick,
+}
+
+/// Mapping of integer syscalls to objects that implement syscalls.
+impl SyscallDriverLookup for STM32F429IDiscoverySensorHub {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn kernel::syscall::SyscallDriver>) -> R,
+    {
+        match driver_num {
+            capsules_core::console::DRIVER_NUM => f(Some(self.console)),
+            capsules_core::led::DRIVER_NUM => f(Some(self.led)),
+            capsules_core::button::DRIVER_NUM => f(Some(self.button)),
+            capsules_core::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules_core::adc::DRIVER_NUM => f(Some(self.adc)),
+            capsules_extra::l3gd20::DRIVER_NUM => f(Some(self.l3gd20)),
+            capsules_extra::lsm303dlhc::DRIVER_NUM => f(Some(self.lsm303dlhc)),
+            capsules_extra::ninedof::DRIVER_NUM => f(Some(self.ninedof)),
+            capsules_extra::temperature::DRIVER_NUM => f(Some(self.temperature)),
+            capsules_extra::humidity::DRIVER_NUM => f(Some(self.humidity)),
+            capsules_extra::si7021::DRIVER_NUM => f(Some(self.si7021)),
+            capsules_extra::ltc294x::DRIVER_NUM => f(Some(self.ltc294x)),
+            capsules_extra::nonvolatile_storage_driver::DRIVER_NUM => {
+                f(Some(self.nonvolatile_storage))
+            }
+            capsules_extra::text_screen::DRIVER_NUM => f(Some(self.text_screen)),
+            capsules_core::rng::DRIVER_NUM => f(Some(self.rng)),
+            kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            _ => f(None),
+        }
+    }
+}
+
+impl
+    KernelResources<
+        stm32f429zi::chip::Stm32f4xx<
+            'static,
+            stm32f429zi::interrupt_service::Stm32f429ziDefaultPeripherals<'static>,
+        >,
+    > for STM32F429IDiscoverySensorHub
+{
+    type SyscallDriverLookup = Self;
+    type SyscallFilter = ();
+    type ProcessFault = ();
+    type Scheduler = RoundRobinSched<'static>;
+    type SchedulerTimer = cortexm4::systick::SysTick;
+    type WatchDog = ();
+    type ContextSwitchCallback = ();
+
+    fn syscall_driver_lookup(&self) -> &Self::SyscallDriverLookup {
+        self
+    }
+    fn syscall_filter(&self) -> &Self::SyscallFilter {
+        &()
+    }
+    fn process_fault(&self) -> &Self::ProcessFault {
+        &()
+    }
+    fn scheduler(&self) -> &Self::Scheduler {
+        self.scheduler
+    }
+    fn scheduler_timer(&self) -> &Self::SchedulerTimer {
+        &self.systick
+    }
+    fn watchdog(&self) -> &Self::WatchDog {
+        &()
+    }
+    fn context_switch_callback(&self) -> &Self::ContextSwitchCallback {
+        &()
+    }
+}
+
+/// Helper function called during bring-up that configures DMA.
+unsafe fn setup_dma(
+    dma1: &stm32f429zi::dma::Dma1,
+    dma1_streams: &'static [stm32f429zi::dma::Stream<'static, stm32f429zi::dma::Dma1>; 8],
+    spi3: &'static stm32f429zi::spi::Spi,
+    dma2: &stm32f429zi::dma::Dma2,
+    dma2_streams: &'static [stm32f429zi::dma::Stream<'static, stm32f429zi::dma::Dma2>; 8],
+    usart1: &'static stm32f429zi::usart::Usart<stm32f429zi::dma::Dma2>,
+) {
+    use stm32f429zi::dma::{Dma1Peripheral, Dma2Peripheral};
+    use stm32f429zi::{spi, usart};
+
+    dma1.enable_clock();
+    dma2.enable_clock();
+
+    let usart1_tx_stream = &dma2_streams[Dma2Peripheral::USART1_TX.get_stream_idx()];
+    let usart1_rx_stream = &dma2_streams[Dma2Peripheral::USART1_RX.get_stream_idx()];
+
+    usart1.set_dma(
+        usart::TxDMA(usart1_tx_stream),
+        usart::RxDMA(usart1_rx_stream),
+    );
+
+    usart1_tx_stream.set_client(usart1);
+    usart1_rx_stream.set_client(usart1);
+
+    usart1_tx_stream.setup(Dma2Peripheral::USART1_TX);
+    usart1_rx_stream.setup(Dma2Peripheral::USART1_RX);
+
+    cortexm4::nvic::Nvic::new(Dma2Peripheral::USART1_TX.get_stream_irqn()).enable();
+    cortexm4::nvic::Nvic::new(Dma2Peripheral::USART1_RX.get_stream_irqn()).enable();
+
+    // SPI3 only transfers over DMA, the L3GD20 and the FM25CL share it.
+    let spi3_tx_stream = &dma1_streams[Dma1Peripheral::SPI3_TX.get_stream_idx()];
+    let spi3_rx_stream = &dma1_streams[Dma1Peripheral::SPI3_RX.get_stream_idx()];
+
+    spi3.set_dma(spi::TxDMA(spi3_tx_stream), spi::RxDMA(spi3_rx_stream));
+
+    spi3_tx_stream.set_client(spi3);
+    spi3_rx_stream.set_client(spi3);
+
+    spi3_tx_stream.setup(Dma1Peripheral::SPI3_TX);
+    spi3_rx_stream.setup(Dma1Peripheral::SPI3_RX);
+
+    cortexm4::nvic::Nvic::new(Dma1Peripheral::SPI3_TX.get_stream_irqn()).enable();
+    cortexm4::nvic::Nvic::new(Dma1Peripheral::SPI3_RX.get_stream_irqn()).enable();
+}
+
+/// Helper function called during bring-up that configures multiplexed I/O.
+unsafe fn set_pin_primary_functions(
+    syscfg: &stm32f429zi::syscfg::Syscfg,
+    i2c1: &'static stm32f429zi::i2c::I2C<'static>,
+    spi3: &stm32f429zi::spi::Spi,
+    gpio_ports: &'static stm32f429zi::gpio::GpioPorts<'static>,
+) {
+    use kernel::hil::gpio::{Configure, FloatingState, Output};
+
+    syscfg.enable_clock();
+
+    gpio_ports.get_port_from_port_id(PortId::A).enable_clock();
+    gpio_ports.get_port_from_port_id(PortId::B).enable_clock();
+    gpio_ports.get_port_from_port_id(PortId::C).enable_clock();
+    gpio_ports.get_port_from_port_id(PortId::E).enable_clock();
+    gpio_ports.get_port_from_port_id(PortId::F).enable_clock();
+    gpio_ports.get_port_from_port_id(PortId::G).enable_clock();
+
+    // User LD4 (red) is connected to PG14. Configure PG14 as `debug_gpio!(0, ...)`
+    gpio_ports.get_pin(PinId::PG14).map(|pin| {
+        pin.make_output();
+
+        // Configure kernel debug gpios as early as possible
+        kernel::debug::assign_gpios(Some(pin), None, None);
+    });
+
+    // Configure USART1 on Pins PA09 and PA10.
+    // USART1 is connected to ST-LINK virtual COM port on Rev.1 of the Stm32f429i Discovery board
+    gpio_ports.get_pin(PinId::PA09).map(|pin| {
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF7 is USART1_TX
+        pin.set_alternate_function(AlternateFunction::AF7);
+    });
+    gpio_ports.get_pin(PinId::PA10).map(|pin| {
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF7 is USART1_RX
+        pin.set_alternate_function(AlternateFunction::AF7);
+    });
+
+    // User button B1 is connected on pa00
+    gpio_ports.get_pin(PinId::PA00).map(|pin| {
+        pin.enable_interrupt();
+    });
+    cortexm4::nvic::Nvic::new(stm32f429zi::nvic::EXTI0).enable();
+
+    // ADC channels 3 (PA03) and 10 (PC00)
+    gpio_ports.get_pin(PinId::PA03).map(|pin| {
+        pin.set_mode(Mode::AnalogMode);
+    });
+    gpio_ports.get_pin(PinId::PC00).map(|pin| {
+        pin.set_mode(Mode::AnalogMode);
+    });
+
+    // I2C1 on PB08 (SCL) and PB09 (SDA) has the LSM303DLHC, the SI7021 and
+    // the LTC294X connected
+    for pin_id in [PinId::PB08, PinId::PB09] {
+        gpio_ports.get_pin(pin_id).map(|pin| {
+            pin.set_mode_output_opendrain();
+            pin.set_mode(Mode::AlternateFunctionMode);
+            pin.set_floating_state(FloatingState::PullNone);
+            // AF4 is I2C
+            pin.set_alternate_function(AlternateFunction::AF4);
+        });
+    }
+    i2c1.enable_clock();
+    i2c1.set_speed(stm32f429zi::i2c::I2CSpeed::Speed100k, 16);
+    // A sensor may still hold SDA low from before the reset.
+    i2c1.set_bus_pins(
+        gpio_ports.get_pin(PinId::PB08).unwrap(),
+        gpio_ports.get_pin(PinId::PB09).unwrap(),
+    );
+    let _ = i2c1.bus_recover();
+    cortexm4::nvic::Nvic::new(stm32f429zi::nvic::I2C1_EV).enable();
+    cortexm4::nvic::Nvic::new(stm32f429zi::nvic::I2C1_ER).enable();
+
+    // SPI3 on PC10 (SCK), PC11 (MISO) and PC12 (MOSI) has the L3GD20 and the
+    // FM25CL connected
+    for pin_id in [PinId::PC10, PinId::PC11, PinId::PC12] {
+        gpio_ports.get_pin(pin_id).map(|pin| {
+            pin.set_mode(Mode::AlternateFunctionMode);
+            pin.set_floating_state(FloatingState::PullNone);
+            // AF6 is SPI3
+            pin.set_alternate_function(AlternateFunction::AF6);
+        });
+    }
+    // Chip selects of the L3GD20 (PE02) and the FM25CL (PE04), active low
+    for pin_id in [PinId::PE02, PinId::PE04] {
+        gpio_ports.get_pin(pin_id).map(|pin| {
+            pin.make_output();
+            pin.set();
+        });
+    }
+    spi3.enable_clock();
+}
+
+/// Helper function for miscellaneous peripheral functions
+unsafe fn setup_peripherals(tim2: &stm32f429zi::tim2::Tim2, trng: &stm32f429zi::trng::Trng) {
+    // USART1 IRQn is 37
+    cortexm4::nvic::Nvic::new(stm32f429zi::nvic::USART1).enable();
+
+    // TIM2 IRQn is 28
+    tim2.enable_clock();
+    tim2.start();
+    cortexm4::nvic::Nvic::new(stm32f429zi::nvic::TIM2).enable();
+
+    // RNG
+    trng.enable_clock();
+    cortexm4::nvic::Nvic::new(stm32f429zi::stm32f429zi_nvic::HASH_RNG).enable();
+}
+
+/// Statically initialize the core peripherals for the chip.
+///
+/// This is in a separate, inline(never) function so that its stack frame is
+/// removed when this function returns. Otherwise, the stack space used for
+/// these static_inits is wasted.
+#[inline(never)]
+unsafe fn create_peripherals() -> (
+    &'static mut Stm32f429ziDefaultPeripherals<'static>,
+    &'static stm32f429zi::syscfg::Syscfg<'static>,
+    &'static stm32f429zi::dma::Dma1<'static>,
+    &'static stm32f429zi::dma::Dma2<'static>,
+) {
+    // We use the default HSI 16Mhz clock
+    let rcc = static_init!(stm32f429zi::rcc::Rcc, stm32f429zi::rcc::Rcc::new());
+    let syscfg = static_init!(
+        stm32f429zi::syscfg::Syscfg,
+        stm32f429zi::syscfg::Syscfg::new(rcc)
+    );
+    let exti = static_init!(
+        stm32f429zi::exti::Exti,
+        stm32f429zi::exti::Exti::new(syscfg)
+    );
+    let dma1 = static_init!(stm32f429zi::dma::Dma1, stm32f429zi::dma::Dma1::new(rcc));
+    let dma2 = static_init!(stm32f429zi::dma::Dma2, stm32f429zi::dma::Dma2::new(rcc));
+    let peripherals = static_init!(
+        Stm32f429ziDefaultPeripherals,
+        Stm32f429ziDefaultPeripherals::new(rcc, exti, dma1, dma2)
+    );
+    (peripherals, syscfg, dma1, dma2)
+}
+
+/// Main function
+///
+/// This is called after RAM initialization is complete.
+#[no_mangle]
+pub unsafe fn main() {
+    stm32f429zi::init();
+
+    let (peripherals, syscfg, dma1, dma2) = create_peripherals();
+    peripherals.init();
+    let base_peripherals = &peripherals.stm32f4;
+
+    setup_peripherals(&base_peripherals.tim2, &peripherals.trng);
+
+    set_pin_primary_functions(
+        syscfg,
+        &base_peripherals.i2c1,
+        &base_peripherals.spi3,
+        &base_peripherals.gpio_ports,
+    );
+
+    setup_dma(
+        dma1,
+        &base_peripherals.dma1_streams,
+        &base_peripherals.spi3,
+        dma2,
+        &base_peripherals.dma2_streams,
+        &base_peripherals.usart1,
+    );
+
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&*addr_of!(PROCESSES)));
+
+    let chip = static_init!(
+        stm32f429zi::chip::Stm32f4xx<Stm32f429ziDefaultPeripherals>,
+        stm32f429zi::chip::Stm32f4xx::new(peripherals)
+    );
+    CHIP = Some(chip);
+
+    // UART
+
+    // Create a shared UART channel for kernel debug.
+    base_peripherals.usart1.enable_clock();
+    let uart_mux = components::console::UartMuxComponent::new(&base_peripherals.usart1, 115200)
+        .finalize(components::uart_mux_component_static!());
+
+    io::WRITER.set_initialized();
+
+    // Create capabilities that the board needs to call certain protected kernel
+    // functions.
+    let memory_allocation_capability = create_capability!(capabilities::MemoryAllocationCapability);
+    let main_loop_capability = create_capability!(capabilities::MainLoopCapability);
+    let process_management_capability =
+        create_capability!(capabilities::ProcessManagementCapability);
+
+    // Setup the console.
+    let console = components::console::ConsoleComponent::new(
+        board_kernel,
+        capsules_core::console::DRIVER_NUM,
+        uart_mux,
+    )
+    .finalize(components::console_component_static!());
+    // Create the debugger object that handles calls to `debug!()`.
+    components::debug_writer::DebugWriterComponent::new(uart_mux)
+        .finalize(components::debug_writer_component_static!());
+
+    // LEDs
+
+    let gpio_ports = &base_peripherals.gpio_ports;
+
+    let led = components::led::LedsComponent::new().finalize(components::led_component_static!(
+        LedHigh<'static, stm32f429zi::gpio::Pin>,
+        LedHigh::new(gpio_ports.get_pin(PinId::PG13).unwrap()),
+        LedHigh::new(gpio_ports.get_pin(PinId::PG14).unwrap()),
+    ));
+
+    // BUTTONs
+    let button = components::button::ButtonComponent::new(
+        board_kernel,
+        capsules_core::button::DRIVER_NUM,
+        components::button_component_helper!(
+            stm32f429zi::gpio::Pin,
+            (
+                gpio_ports.get_pin(PinId::PA00).unwrap(),
+                kernel::hil::gpio::ActivationMode::ActiveHigh,
+                kernel::hil::gpio::FloatingState::PullNone
+            )
+        ),
+    )
+    .finalize(components::button_component_static!(stm32f429zi::gpio::Pin));
+
+    // ALARM
+
+    // Every capsule that needs time, including the ADC sample timeout, gets
+    // its own virtual alarm from this mux.
+    let tim2 = &base_peripherals.tim2;
+    let mux_alarm = components::alarm::AlarmMuxComponent::new(tim2).finalize(
+        components::alarm_mux_component_static!(stm32f429zi::tim2::Tim2),
+    );
+
+    let alarm = components::alarm::AlarmDriverComponent::new(
+        board_kernel,
+        capsules_core::alarm::DRIVER_NUM,
+        mux_alarm,
+    )
+    .finalize(components::alarm_component_static!(stm32f429zi::tim2::Tim2));
+
+    // ADC
+
+    // Each channel is an `AdcDevice` on the mux, the syscall driver
+    // virtualizes the channels between processes.
+    let adc_mux = components::adc::AdcMuxComponent::new(&base_peripherals.adc1)
+        .finalize(components::adc_mux_component_static!(stm32f429zi::adc::Adc));
+
+    let adc_channel_0 =
+        components::adc::AdcComponent::new(adc_mux, stm32f429zi::adc::Channel::Channel3)
+            .finalize(components::adc_component_static!(stm32f429zi::adc::Adc));
+
+    let adc_channel_1 =
+        components::adc::AdcComponent::new(adc_mux, stm32f429zi::adc::Channel::Channel10)
+            .finalize(components::adc_component_static!(stm32f429zi::adc::Adc));
+
+    let adc_syscall =
+        components::adc::AdcVirtualComponent::new(board_kernel, capsules_core::adc::DRIVER_NUM)
+            .finalize(components::adc_syscall_component_helper!(
+                adc_channel_0,
+                adc_channel_1,
+            ));
+
+    components::adc::AdcSampleTimeoutComponent::new(mux_alarm, adc_syscall, ADC_SAMPLE_TIMEOUT_MS)
+        .finalize(components::adc_sample_timeout_component_static!(
+            stm32f429zi::tim2::Tim2
+        ));
+
+    // I2C
+
+    let mux_i2c = components::i2c::I2CMuxComponent::new(&base_peripherals.i2c1, None)
+        .finalize(components::i2c_mux_component_static!(stm32f429zi::i2c::I2C));
+
+    // SPI
+
+    let spi_mux = components::spi::SpiMuxComponent::new(&base_peripherals.spi3)
+        .finalize(components::spi_mux_component_static!(stm32f429zi::spi::Spi));
+
+    // NINEDOF
+
+    // The L3GD20 (gyroscope) and the LSM303DLHC (accelerometer and
+    // magnetometer) have their own drivers and are also combined behind the
+    // ninedof driver.
+    let l3gd20 = components::l3gd20::L3gd20Component::new(
+        spi_mux,
+        gpio_ports.get_pin(PinId::PE02).unwrap(),
+        None,
+        board_kernel,
+        capsules_extra::l3gd20::DRIVER_NUM,
+    )
+    .finalize(components::l3gd20_component_static!(stm32f429zi::spi::Spi));
+
+    if let Err(error) = l3gd20.power_on() {
+        debug!("Failed to power on L3GD20 sensor ({:?})", error);
+    }
+
+    let lsm303dlhc = components::lsm303dlhc::Lsm303dlhcI2CComponent::new(
+        mux_i2c,
+        None,
+        None,
+        None,
+        board_kernel,
+        capsules_extra::lsm303dlhc::DRIVER_NUM,
+    )
+    .finalize(components::lsm303dlhc_component_static!(
+        stm32f429zi::i2c::I2C
+    ));
+
+    if let Err(error) = lsm303dlhc.configure(
+        lsm303xx::Lsm303AccelDataRate::DataRate25Hz,
+        false,
+        lsm303xx::Lsm303Scale::Scale2G,
+        false,
+        true,
+        lsm303xx::Lsm303MagnetoDataRate::DataRate3_0Hz,
+        lsm303xx::Lsm303Range::Range1_9G,
+    ) {
+        debug!("Failed to configure LSM303DLHC sensor ({:?})", error);
+    }
+
+    let ninedof = components::ninedof::NineDofComponent::new(
+        board_kernel,
+        capsules_extra::ninedof::DRIVER_NUM,
+    )
+    .finalize(components::ninedof_component_static!(l3gd20, lsm303dlhc));
+
+    // SI7021
+
+    // The sensor is shared by the temperature and humidity drivers, its own
+    // driver configures the heater and the resolution.
+    let si7021 = components::si7021::SI7021Component::new(mux_i2c, mux_alarm, 0x40).finalize(
+        components::si7021_component_static!(stm32f429zi::tim2::Tim2, stm32f429zi::i2c::I2C),
+    );
+
+    let temperature = components::temperature::TemperatureComponent::new(
+        board_kernel,
+        capsules_extra::temperature::DRIVER_NUM,
+        si7021,
+    )
+    .finalize(components::temperature_component_static!(SI7021Sensor));
+
+    let humidity = components::humidity::HumidityComponent::new(
+        board_kernel,
+        capsules_extra::humidity::DRIVER_NUM,
+        si7021,
+    )
+    .finalize(components::humidity_component_static!(SI7021Sensor));
+
+    let si7021_driver = components::si7021::Si7021DriverComponent::new(
+        si7021,
+        board_kernel,
+        capsules_extra::si7021::DRIVER_NUM,
+    )
+    .finalize(components::si7021_driver_component_static!(
+        stm32f429zi::tim2::Tim2,
+        stm32f429zi::i2c::I2C
+    ));
+
+    // LTC294X
+
+    let ltc294x = components::ltc294x::Ltc294xComponent::new(mux_i2c, 0x64, None)
+        .finalize(components::ltc294x_component_static!(stm32f429zi::i2c::I2C));
+
+    let ltc294x_driver = components::ltc294x::Ltc294xDriverComponent::new(
+        ltc294x,
+        board_kernel,
+        capsules_extra::ltc294x::DRIVER_NUM,
+    )
+    .finalize(components::ltc294x_driver_component_static!(
+        stm32f429zi::i2c::I2C
+    ));
+
+    // NONVOLATILE STORAGE
+
+    // The FM25CL implements the nonvolatile storage HIL itself, so it does not
+    // need the flash page adapter.
+    let fm25cl =
+        components::fm25cl::Fm25clComponent::new(spi_mux, gpio_ports.get_pin(PinId::PE04).unwrap())
+            .finalize(components::fm25cl_component_static!(stm32f429zi::spi::Spi));
+
+    let nonvolatile_storage =
+        components::nonvolatile_storage::NonvolatileStorageDriverComponent::new(
+            board_kernel,
+            capsules_extra::nonvolatile_storage_driver::DRIVER_NUM,
+            fm25cl,
+            FRAM_USERSPACE_START,
+            FRAM_USERSPACE_LENGTH,
+            FRAM_KERNEL_START,
+            FRAM_KERNEL_LENGTH,
+            FRAM_GEOMETRY,
+        )
+        .finalize(components::nonvolatile_storage_driver_component_static!());
+
+    // TEXT SCREEN
+
+    let lcd = components::hd44780::HD44780Component::new(
+        mux_alarm,
+        16,
+        2,
+        // rs pin
+        gpio_ports.get_pin(PinId::PF12).unwrap(),
+        // en pin
+        gpio_ports.get_pin(PinId::PF13).unwrap(),
+        // data 4 pin
+        gpio_ports.get_pin(PinId::PE09).unwrap(),
+        // data 5 pin
+        gpio_ports.get_pin(PinId::PE11).unwrap(),
+        // data 6 pin
+        gpio_ports.get_pin(PinId::PF14).unwrap(),
+        // data 7 pin
+        gpio_ports.get_pin(PinId::PE13).unwrap(),
+        capsules_extra::hd44780::EntryDirection::LeftToRight,
+        false,
+    )
+    .finalize(components::hd44780_component_static!(
+        stm32f429zi::tim2::Tim2
+    ));
+
+    let text_screen = components::text_screen::TextScreenComponent::new(
+        board_kernel,
+        capsules_extra::text_screen::DRIVER_NUM,
+        lcd,
+    )
+    .finalize(components::text_screen_component_static!(32));
+
+    // RNG
+
+    let rng = components::rng::RngComponent::new(
+        board_kernel,
+        capsules_core::rng::DRIVER_NUM,
+        &peripherals.trng,
+    )
+    .finalize(components::rng_component_static!(stm32f429zi::trng::Trng));
+
+    let process_printer = components::process_printer::ProcessPrinterTextComponent::new()
+        .finalize(components::process_printer_text_component_static!());
+    PROCESS_PRINTER = Some(process_printer);
+
+    // PROCESS CONSOLE
+    let process_console = components::process_console::ProcessConsoleComponent::new(
+        board_kernel,
+        uart_mux,
+        mux_alarm,
+        process_printer,
+        Some(cortexm4::support::reset),
+    )
+    .finalize(components::process_console_component_static!(
+        stm32f429zi::tim2::Tim2
+    ));
+    let _ = process_console.start();
+
+    let scheduler = components::sched::round_robin::RoundRobinComponent::new(&*addr_of!(PROCESSES))
+        .finalize(components::round_robin_component_static!(NUM_PROCS));
+
+    let sensor_hub = STM32F429IDiscoverySensorHub {
+        console,
+        ipc: kernel::ipc::IPC::new(
+            board_kernel,
+            kernel::ipc::DRIVER_NUM,
+            &memory_allocation_capability,
+        ),
+        led,
+        button,
+        alarm,
+        adc: adc_syscall,
+        l3gd20,
+        lsm303dlhc,
+        ninedof,
+        temperature,
+        humidity,
+        si7021: si7021_driver,
+        ltc294x: ltc294x_driver,
+        nonvolatile_storage,
+        text_screen,
+        rng,
+
+        scheduler,
+        systick: cortexm4::systick::SysTick::new(),
+    };
+
+    debug!("Initialization complete. Entering main loop");
+
+    // These symbols are defined in the linker script.
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+        /// End of the ROM region containing app images.
+        static _eapps: u8;
+        /// Beginning of the RAM region for app memory.
+        static mut _sappmem: u8;
+        /// End of the RAM region for app memory.
+        static _eappmem: u8;
+    }
+
+    kernel::process::load_processes(
+        board_kernel,
+        chip,
+        core::slice::from_raw_parts(
+            core::ptr::addr_of!(_sapps),
+            core::ptr::addr_of!(_eapps) as usize - core::ptr::addr_of!(_sapps) as usize,
+        ),
+        core::slice::from_raw_parts_mut(
+            core::ptr::addr_of_mut!(_sappmem),
+            core::ptr::addr_of!(_eappmem) as usize - core::ptr::addr_of!(_sappmem) as usize,
+        ),
+        &mut *addr_of_mut!(PROCESSES),
+        &FAULT_RESPONSE,
+        &process_management_capability,
+    )
+    .unwrap_or_else(|err| {
+        debug!("Error loading processes!");
+        debug!("{:?}", err);
+    });
+
+    board_kernel.kernel_loop(
+        &sensor_hub,
+        chip,
+        Some(&sensor_hub.ipc),
+        &main_loop_capability,
+    );
+}
@@ -0,0 +1,20 @@
+# Licensed under the Apache License, Version 2.0 or the MIT License.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+# Copyright Tock Contributors 2024.
+
+# Shared makefile for building the tock kernel for STM32F429I Discovery test
+# boards.
+
+OPENOCD=openocd
+
+# Default target for installing the kernel.
+.PHONY: install
+install: flash
+
+.PHONY: flash-debug
+flash-debug: $(TOCK_ROOT_DIRECTORY)target/$(TARGET)/debug/$(PLATFORM).bin
+	$(OPENOCD) -c "source [find board/stm32f429discovery.cfg]; init; reset halt; program $< verify 0x08000000; reset; shutdown"
+
+.PHONY: flash
+flash: $(TOCK_ROOT_DIRECTORY)target/$(TARGET)/release/$(PLATFORM).bin
+	$(OPENOCD) -c "source [find board/stm32f429discovery.cfg]; init; reset halt; program $< verify 0x08000000; reset; shutdown"
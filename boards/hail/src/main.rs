@@ -427,6 +427,7 @@ unsafe fn start() -> (
         capsules_core::adc::DRIVER_NUM,
     )
     .finalize(components::adc_dedicated_component_static!(sam4l::adc::Adc));
+    adc.set_channel_config(&peripherals.adc);
 
     // Setup RNG
     let rng = components::rng::RngComponent::new(
@@ -0,0 +1,784 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Virtualized ADC syscall driver shared by several apps, and dedicated ADC
+//! syscall driver sampling into app buffers.
+
+use std::cell::RefCell;
+
+use capsules_core::adc::{
+    pack_samples_channel, unpack_samples_channel, AdcDedicated, AdcSampleTimeout, AdcVirtualized,
+    DRIVER_NUM, MAX_PACKED_SAMPLES, SAMPLING_FAILED,
+};
+use kernel::hil::adc::{self, Adc, AdcChannel, AdcHighSpeed};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, Apps, Board, FakeAdcChannel, FakeAlarm, FakeHighSpeedAdc};
+
+const SINGLE_SAMPLE: usize = 0;
+const SINGLE_BUFFER: usize = 2;
+
+fn setup(
+    channel_count: usize,
+    app_count: usize,
+) -> (
+    Vec<&'static FakeAdcChannel<'static>>,
+    &'static AdcVirtualized<'static>,
+    Apps,
+) {
+    let board = Board::new();
+    let channels: Vec<&'static FakeAdcChannel<'static>> = (0..channel_count)
+        .map(|_| leak(FakeAdcChannel::new(None)))
+        .collect();
+    let drivers: &'static [&'static dyn AdcChannel<'static>] = Box::leak(
+        channels
+            .iter()
+            .map(|channel| *channel as &dyn AdcChannel<'static>)
+            .collect(),
+    );
+    let adc = leak(AdcVirtualized::new(drivers, board.create_grant(DRIVER_NUM)));
+    for channel in &channels {
+        channel.set_client(adc);
+    }
+    let apps = board.load_apps(app_count);
+    for app in 0..app_count {
+        apps.subscribe(app, DRIVER_NUM, 0);
+    }
+    apps.run(&[(DRIVER_NUM, adc as &dyn SyscallDriver)]);
+    for app in 0..app_count {
+        apps.take_returns(app);
+    }
+    (channels, adc, apps)
+}
+
+fn run(apps: &Apps, adc: &'static AdcVirtualized<'static>) {
+    apps.run(&[(DRIVER_NUM, adc as &dyn SyscallDriver)]);
+}
+
+fn is_success(returns: &[SyscallReturn]) -> bool {
+    matches!(returns, [SyscallReturn::Success])
+}
+
+#[test]
+fn synchronous_samples_start_once() {
+    let (channels, adc, apps) = setup(1, 3);
+    channels[0].complete_synchronously(&[10, 20, 30]);
+
+    for app in 0..3 {
+        apps.command(app, DRIVER_NUM, 1, 0, 0);
+    }
+    run(&apps, adc);
+
+    // Every command starts one sample, which completes before the command
+    // returns, and only its app gets the upcall.
+    assert_eq!(channels[0].starts(), 3);
+    for (app, sample) in [10, 20, 30].into_iter().enumerate() {
+        assert!(is_success(&apps.take_returns(app)));
+        assert_eq!(
+            apps.take_upcalls(app),
+            vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, sample])]
+        );
+    }
+}
+
+#[test]
+fn racing_apps_get_their_own_samples() {
+    let (channels, adc, apps) = setup(2, 3);
+
+    apps.command(0, DRIVER_NUM, 1, 1, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    apps.command(2, DRIVER_NUM, 1, 1, 0);
+    run(&apps, adc);
+    for app in 0..3 {
+        assert!(is_success(&apps.take_returns(app)));
+    }
+
+    // Only the first command started, the others wait for it.
+    assert_eq!((channels[0].starts(), channels[1].starts()), (0, 1));
+
+    assert!(channels[1].deliver(0x100));
+    run(&apps, adc);
+    assert_eq!((channels[0].starts(), channels[1].starts()), (1, 1));
+
+    assert!(channels[0].deliver(0x200));
+    run(&apps, adc);
+    assert_eq!((channels[0].starts(), channels[1].starts()), (1, 2));
+
+    assert!(channels[1].deliver(0x300));
+    run(&apps, adc);
+    assert!(!channels[0].is_requested() && !channels[1].is_requested());
+
+    // Each sample reaches the app that asked for it, with its channel.
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 1, 0x100])]
+    );
+    assert_eq!(
+        apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 0x200])]
+    );
+    assert_eq!(
+        apps.take_upcalls(2),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 1, 0x300])]
+    );
+}
+
+#[test]
+fn failed_start_is_reported() {
+    let (channels, adc, apps) = setup(1, 2);
+
+    // A kernel user of the channel makes the app's sample fail to start.
+    assert_eq!(channels[0].sample(), Ok(()));
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::BUSY)]
+    ));
+    assert!(channels[0].deliver(1));
+    run(&apps, adc);
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // An app waiting for its sample cannot queue another one.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Success,
+            SyscallReturn::Failure(ErrorCode::BUSY)
+        ]
+    ));
+    assert!(channels[0].deliver(2));
+    run(&apps, adc);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 2])]
+    );
+    assert_eq!(channels[0].starts(), 2);
+}
+
+#[test]
+fn terminated_app_is_skipped() {
+    let (channels, adc, apps) = setup(1, 3);
+
+    for app in 0..3 {
+        apps.command(app, DRIVER_NUM, 1, 0, 0);
+    }
+    run(&apps, adc);
+    apps.terminate(1);
+
+    // The sample queued by the terminated app is never started.
+    assert!(channels[0].deliver(1));
+    run(&apps, adc);
+    assert!(channels[0].deliver(3));
+    run(&apps, adc);
+    assert_eq!(channels[0].starts(), 2);
+    assert!(!channels[0].is_requested());
+
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 1])]
+    );
+    assert_eq!(
+        apps.take_upcalls(2),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 3])]
+    );
+}
+
+/// The virtualized ADC of `setup()` with samples timing out after 50 ms.
+fn setup_with_timeout(
+    channel_count: usize,
+    app_count: usize,
+) -> (
+    Vec<&'static FakeAdcChannel<'static>>,
+    &'static AdcVirtualized<'static>,
+    Apps,
+    &'static FakeAlarm<'static, Freq1KHz>,
+) {
+    let (channels, adc, apps) = setup(channel_count, app_count);
+    let alarm = leak(FakeAlarm::new());
+    let timeout = leak(AdcSampleTimeout::new(alarm, 50));
+    alarm.set_alarm_client(timeout);
+    adc.set_sample_timeout(timeout);
+    (channels, adc, apps, alarm)
+}
+
+/// The upcall of a sample on `channel` that timed out.
+fn timed_out(channel: usize) -> (usize, usize, [usize; 3]) {
+    (
+        DRIVER_NUM,
+        0,
+        [SAMPLING_FAILED, channel, usize::from(ErrorCode::FAIL)],
+    )
+}
+
+#[test]
+fn lost_sample_times_out() {
+    let (channels, adc, apps, alarm) = setup_with_timeout(1, 2);
+
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert!(is_success(&apps.take_returns(1)));
+    assert_eq!(alarm.armed_dt(), Some(50));
+
+    // The channel drops the first sample, its app is told and the queued
+    // sample starts.
+    assert!(alarm.fire());
+    run(&apps, adc);
+    assert_eq!(apps.take_upcalls(0), vec![timed_out(0)]);
+    assert_eq!(channels[0].starts(), 2);
+    assert!(channels[0].is_requested());
+    assert_eq!(adc.sample_timeouts(), 1);
+    assert!(!adc.is_unhealthy(0));
+
+    // And recovers.
+    assert!(channels[0].deliver(0x42));
+    run(&apps, adc);
+    assert_eq!(
+        apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 0x42])]
+    );
+    assert_eq!(alarm.armed_dt(), None);
+
+    // The app can sample again.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert!(channels[0].deliver(0x43));
+    run(&apps, adc);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 0x43])]
+    );
+}
+
+#[test]
+fn synchronous_sample_cancels_its_timeout() {
+    let (channels, adc, apps, alarm) = setup_with_timeout(1, 1);
+    channels[0].complete_synchronously(&[7]);
+
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert_eq!(alarm.armed_dt(), None);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 7])]
+    );
+}
+
+#[test]
+fn repeated_timeouts_mark_the_channel_unhealthy() {
+    let (channels, adc, apps, alarm) = setup_with_timeout(2, 1);
+
+    let query = |command| {
+        apps.command(0, DRIVER_NUM, command, 0, 0);
+        run(&apps, adc);
+        apps.take_returns(0)
+    };
+    let sample = |channel| {
+        apps.command(0, DRIVER_NUM, 1, channel, 0);
+        run(&apps, adc);
+        apps.take_returns(0);
+    };
+
+    sample(0);
+    assert!(alarm.fire());
+    sample(0);
+    assert!(alarm.fire());
+    run(&apps, adc);
+    assert_eq!(apps.take_upcalls(0), vec![timed_out(0), timed_out(0)]);
+    assert!(adc.is_unhealthy(0));
+    assert!(matches!(
+        query(101)[..],
+        [SyscallReturn::Failure(ErrorCode::FAIL)]
+    ));
+    assert!(matches!(
+        query(102)[..],
+        [SyscallReturn::Failure(ErrorCode::FAIL)]
+    ));
+    assert!(matches!(query(103)[..], [SyscallReturn::SuccessU32(2)]));
+
+    // The other channel is fine.
+    apps.command(0, DRIVER_NUM, 101, 1, 0);
+    run(&apps, adc);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [SyscallReturn::SuccessU32(_)]
+    ));
+
+    // A successful sample clears it.
+    sample(0);
+    assert!(channels[0].deliver(1));
+    assert!(!adc.is_unhealthy(0));
+    assert!(matches!(query(101)[..], [SyscallReturn::SuccessU32(_)]));
+    assert!(matches!(query(103)[..], [SyscallReturn::SuccessU32(2)]));
+}
+
+/// A capsule taking samples from the virtualized ADC.
+#[derive(Default)]
+struct KernelClient {
+    samples: RefCell<Vec<u16>>,
+}
+
+impl adc::Client for KernelClient {
+    fn sample_ready(&self, sample: u16) {
+        self.samples.borrow_mut().push(sample);
+    }
+}
+
+#[test]
+fn kernel_sample_reaches_the_kernel_client() {
+    let (channels, adc, apps) = setup(1, 1);
+    assert_eq!(adc.sample_once(0), Err(ErrorCode::FAIL));
+    let client = leak(KernelClient::default());
+    adc.set_kernel_client(client);
+    assert_eq!(adc.sample_once(1), Err(ErrorCode::NODEVICE));
+
+    assert_eq!(adc.sample_once(0), Ok(()));
+    assert_eq!(adc.sample_once(0), Err(ErrorCode::BUSY));
+    // The app waits for the kernel sample.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert_eq!(channels[0].starts(), 1);
+
+    assert!(channels[0].deliver(0x10));
+    run(&apps, adc);
+    assert_eq!(client.samples.take(), vec![0x10]);
+    assert!(apps.take_upcalls(0).is_empty());
+    assert_eq!(channels[0].starts(), 2);
+
+    assert!(channels[0].deliver(0x20));
+    run(&apps, adc);
+    assert!(client.samples.take().is_empty());
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 0x20])]
+    );
+}
+
+#[test]
+fn kernel_sample_is_queued_with_app_samples() {
+    let (channels, adc, apps) = setup(2, 2);
+    let client = leak(KernelClient::default());
+    adc.set_kernel_client(client);
+
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    assert_eq!(adc.sample_once(1), Ok(()));
+    assert_eq!((channels[0].starts(), channels[1].starts()), (1, 0));
+
+    // The kernel sample goes before the queued sample of the second app.
+    assert!(channels[0].deliver(1));
+    assert_eq!((channels[0].starts(), channels[1].starts()), (1, 1));
+    assert!(channels[1].deliver(2));
+    assert_eq!(client.samples.take(), vec![2]);
+    assert_eq!((channels[0].starts(), channels[1].starts()), (2, 1));
+    assert!(channels[0].deliver(3));
+    run(&apps, adc);
+
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 1])]
+    );
+    assert_eq!(
+        apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 3])]
+    );
+}
+
+type Dedicated = AdcDedicated<'static, FakeHighSpeedAdc<'static>>;
+
+/// Room for 300 samples, more than two ADC buffers hold.
+const APP_BUFFER_LEN: usize = 600;
+
+fn setup_dedicated() -> (&'static FakeHighSpeedAdc<'static>, &'static Dedicated, Apps) {
+    let board = Board::new();
+    let hardware = leak(FakeHighSpeedAdc::new());
+    let channels: &'static [usize] = leak([0, 1]);
+    let adc = leak(AdcDedicated::new(
+        hardware,
+        board.create_grant(DRIVER_NUM),
+        channels,
+        Box::leak(Box::new([0; 128])),
+        Box::leak(Box::new([0; 128])),
+        Box::leak(Box::new([0; 128])),
+    ));
+    hardware.set_client(adc);
+    hardware.set_highspeed_client(adc);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, APP_BUFFER_LEN);
+    run_dedicated(&apps, adc);
+    apps.take_returns(0);
+    (hardware, adc, apps)
+}
+
+fn run_dedicated(apps: &Apps, adc: &'static Dedicated) {
+    apps.run(&[(DRIVER_NUM, adc as &dyn SyscallDriver)]);
+}
+
+/// Starts sampling `count` samples on channel 1 into the app buffer.
+fn sample_buffer(apps: &Apps, adc: &'static Dedicated, count: usize) -> Vec<SyscallReturn> {
+    apps.command(0, DRIVER_NUM, 3, pack_samples_channel(count, 1), 1000);
+    run_dedicated(apps, adc);
+    apps.take_returns(0)
+}
+
+/// Fills ADC buffers until the operation is done, returns their lengths.
+fn fill_buffers(hardware: &FakeHighSpeedAdc, apps: &Apps, adc: &'static Dedicated) -> Vec<usize> {
+    let mut lengths = Vec::new();
+    while let Some(length) = hardware.fill_buffer() {
+        lengths.push(length);
+        run_dedicated(apps, adc);
+    }
+    lengths
+}
+
+/// The app buffer holding samples `1..=count`.
+fn samples(count: usize) -> Vec<u8> {
+    (1..=count as u16).flat_map(u16::to_le_bytes).collect()
+}
+
+/// Checks the upcall of a single buffer sampling of `count` samples.
+fn assert_buffer_upcall(apps: &Apps, count: usize) {
+    let upcalls = apps.take_upcalls(0);
+    assert_eq!(upcalls.len(), 1);
+    let (driver, subscribe, [mode, len_chan, _]) = upcalls[0];
+    assert_eq!((driver, subscribe), (DRIVER_NUM, 0));
+    assert_eq!(mode, SINGLE_BUFFER);
+    assert_eq!(unpack_samples_channel(len_chan), (count, 1));
+}
+
+#[test]
+fn buffer_sampling_of_one_sample() {
+    let (hardware, adc, apps) = setup_dedicated();
+    let before = apps.read_memory(0, APP_BUFFER_LEN);
+
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert_buffer_upcall(&apps, 1);
+    assert!(!hardware.is_sampling());
+
+    // The rest of the buffer is untouched.
+    let after = apps.read_memory(0, APP_BUFFER_LEN);
+    assert_eq!(after[..2], samples(1)[..]);
+    assert_eq!(after[2..], before[2..]);
+}
+
+#[test]
+fn buffer_sampling_of_the_buffer_capacity() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    assert!(is_success(&sample_buffer(&apps, adc, 300)));
+    // Two ADC buffers, then the third one for the remaining samples.
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![128, 128, 44]);
+    assert_buffer_upcall(&apps, 300);
+    assert_eq!(apps.read_memory(0, APP_BUFFER_LEN), samples(300));
+}
+
+#[test]
+fn buffer_sampling_over_capacity_is_rejected() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    assert!(matches!(
+        sample_buffer(&apps, adc, 301)[..],
+        [SyscallReturn::Failure(ErrorCode::SIZE)]
+    ));
+    assert!(!hardware.is_sampling());
+
+    // The ADC is still available, and no count fills the buffer.
+    assert!(is_success(&sample_buffer(&apps, adc, 0)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![128, 128, 44]);
+    assert_buffer_upcall(&apps, 300);
+}
+
+#[test]
+fn failed_continuous_sampling_is_reported() {
+    let (hardware, adc, apps) = setup_dedicated();
+    apps.allow_readwrite_at(0, DRIVER_NUM, 1, APP_BUFFER_LEN, APP_BUFFER_LEN);
+    run_dedicated(&apps, adc);
+    apps.take_returns(0);
+
+    apps.command(0, DRIVER_NUM, 4, 1, 1000);
+    run_dedicated(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert_eq!(hardware.fill_buffer(), Some(128));
+    run_dedicated(&apps, adc);
+    assert!(hardware.fill_buffer().is_some());
+    run_dedicated(&apps, adc);
+    apps.take_upcalls(0);
+
+    // The app learns that the stream ended, rather than waiting for it.
+    assert!(hardware.fail_buffer(ErrorCode::FAIL));
+    run_dedicated(&apps, adc);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            0,
+            [SAMPLING_FAILED, 1, usize::from(ErrorCode::FAIL)]
+        )]
+    );
+    assert!(!hardware.is_sampling());
+
+    // Every ADC buffer came back, the app can sample again.
+    assert!(is_success(&sample_buffer(&apps, adc, 300)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![128, 128, 44]);
+    assert_buffer_upcall(&apps, 300);
+}
+
+#[test]
+fn stopped_sampling_ends_without_upcall() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    assert!(is_success(&sample_buffer(&apps, adc, 300)));
+    apps.command(0, DRIVER_NUM, 5, 0, 0);
+    run_dedicated(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert!(!hardware.is_sampling());
+    assert!(!hardware.fail_buffer(ErrorCode::FAIL));
+    assert!(apps.take_upcalls(0).is_empty());
+}
+
+#[test]
+fn packed_words_round_trip() {
+    for samples in [0, 1, 0xFFFF, MAX_PACKED_SAMPLES] {
+        for channel in [0, 1, 0xFF] {
+            let word = pack_samples_channel(samples, channel);
+            assert!(word <= u32::MAX as usize);
+            assert_eq!(unpack_samples_channel(word), (samples, channel));
+        }
+    }
+    assert_eq!(pack_samples_channel(0x12_3456, 0x78), 0x1234_5678);
+    assert_eq!(pack_samples_channel(MAX_PACKED_SAMPLES, 0xFF), 0xFFFF_FFFF);
+    // Bits above 32 stay in the number of samples, to be refused.
+    assert_eq!(unpack_samples_channel(0x1_0000_0101), (0x100_0001, 1));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic]
+fn packing_too_many_samples_is_a_bug() {
+    pack_samples_channel(MAX_PACKED_SAMPLES + 1, 0);
+}
+
+#[test]
+fn buffer_sampling_beyond_the_packed_range_is_rejected() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    // A count that would not fit in the upcall.
+    apps.command(0, DRIVER_NUM, 3, (MAX_PACKED_SAMPLES + 1) << 8 | 1, 1000);
+    run_dedicated(&apps, adc);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::SIZE)]
+    ));
+    assert!(!hardware.is_sampling());
+}
+
+#[test]
+fn buffer_sampling_copies_odd_lengths_exactly() {
+    // Ends inside the first ADC buffer, one sample into the second one, and
+    // one sample into the third one, copied at growing offsets.
+    for (count, lengths) in [
+        (45, vec![45]),
+        (129, vec![128, 1]),
+        (257, vec![128, 128, 1]),
+    ] {
+        let (hardware, adc, apps) = setup_dedicated();
+        let before = apps.read_memory(0, APP_BUFFER_LEN);
+
+        assert!(is_success(&sample_buffer(&apps, adc, count)));
+        assert_eq!(fill_buffers(hardware, &apps, adc), lengths);
+        assert_buffer_upcall(&apps, count);
+
+        let after = apps.read_memory(0, APP_BUFFER_LEN);
+        assert_eq!(after[..2 * count], samples(count)[..]);
+        assert_eq!(after[2 * count..], before[2 * count..]);
+    }
+}
+
+#[test]
+fn buffer_sampling_into_a_buffer_smaller_than_the_adc_buffer() {
+    let (hardware, adc, apps) = setup_dedicated();
+    // 25 samples away from the start of the app memory, with a trailing byte
+    // that no sample fills.
+    let (offset, len) = (102, 51);
+    apps.allow_readwrite_at(0, DRIVER_NUM, 0, offset, len);
+    run_dedicated(&apps, adc);
+    apps.take_returns(0);
+    let before = apps.read_memory(0, APP_BUFFER_LEN);
+
+    assert!(is_success(&sample_buffer(&apps, adc, 0)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![25]);
+    assert_buffer_upcall(&apps, 25);
+
+    let after = apps.read_memory(0, APP_BUFFER_LEN);
+    assert_eq!(after[offset..offset + 50], samples(25)[..]);
+    assert_eq!(after[..offset], before[..offset]);
+    assert_eq!(after[offset + 50..], before[offset + 50..]);
+}
+
+/// Stores a configuration word for `channel`, with command 105.
+fn configure(
+    apps: &Apps,
+    adc: &'static Dedicated,
+    channel: usize,
+    config: u32,
+) -> Vec<SyscallReturn> {
+    apps.command(0, DRIVER_NUM, 105, channel, config as usize);
+    run_dedicated(apps, adc);
+    apps.take_returns(0)
+}
+
+#[test]
+fn channel_configuration_needs_chip_support() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    assert!(matches!(
+        configure(&apps, adc, 1, 3)[..],
+        [SyscallReturn::Failure(ErrorCode::NOSUPPORT)]
+    ));
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert!(hardware.take_configured().is_empty());
+}
+
+#[test]
+fn channel_configuration_is_applied_before_each_sampling() {
+    let (hardware, adc, apps) = setup_dedicated();
+    adc.set_channel_config(hardware);
+
+    assert!(matches!(
+        configure(&apps, adc, 2, 3)[..],
+        [SyscallReturn::Failure(ErrorCode::INVAL)]
+    ));
+    // Channel 0 is configured, channel 1 keeps the default configuration.
+    assert!(is_success(&configure(&apps, adc, 0, 3)));
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert!(hardware.take_configured().is_empty());
+
+    assert!(is_success(&configure(&apps, adc, 1, 0x21)));
+    for _ in 0..2 {
+        assert!(is_success(&sample_buffer(&apps, adc, 1)));
+        assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    }
+    assert_eq!(hardware.take_configured(), vec![(1, 0x21), (1, 0x21)]);
+
+    // Once removed, the default configuration is used again.
+    apps.command(0, DRIVER_NUM, 106, 1, 0);
+    run_dedicated(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert!(hardware.take_configured().is_empty());
+}
+
+#[test]
+fn unsupported_channel_configuration_fails_the_sampling() {
+    let (hardware, adc, apps) = setup_dedicated();
+    adc.set_channel_config(hardware);
+
+    assert!(is_success(&configure(&apps, adc, 1, 0x8000_0000)));
+    assert!(matches!(
+        sample_buffer(&apps, adc, 1)[..],
+        [SyscallReturn::Failure(ErrorCode::INVAL)]
+    ));
+    assert!(!hardware.is_sampling());
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // A supported word makes the channel usable again.
+    assert!(is_success(&configure(&apps, adc, 1, 1)));
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert_eq!(hardware.take_configured(), vec![(1, 1)]);
+}
+
+#[test]
+fn restarted_app_does_not_get_the_sample_of_its_previous_instance() {
+    let (channels, adc, apps) = setup(1, 2);
+
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+
+    // App 0 crashes while its sample is in progress, and its new instance
+    // asks for a sample of its own, which waits like the one of app 1.
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, adc);
+    apps.take_returns(0);
+
+    assert!(channels[0].deliver(1));
+    run(&apps, adc);
+    assert!(channels[0].deliver(2));
+    run(&apps, adc);
+    assert!(channels[0].deliver(3));
+    run(&apps, adc);
+    assert!(!channels[0].is_requested());
+
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 2])]
+    );
+    assert_eq!(
+        apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [SINGLE_SAMPLE, 0, 3])]
+    );
+}
+
+/// Crashes and restarts the app of `setup_dedicated()`, which subscribes and
+/// shares its buffer again.
+fn restart_dedicated(apps: &Apps, adc: &'static Dedicated) {
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, APP_BUFFER_LEN);
+    run_dedicated(apps, adc);
+    apps.take_returns(0);
+}
+
+#[test]
+fn restarted_app_does_not_get_the_buffer_of_its_previous_instance() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    assert!(is_success(&sample_buffer(&apps, adc, 300)));
+    assert_eq!(hardware.fill_buffer(), Some(128));
+    run_dedicated(&apps, adc);
+    restart_dedicated(&apps, adc);
+
+    // The new instance is the owner now, the sampling of its previous
+    // instance stops and never calls back.
+    assert!(is_success(&sample_buffer(&apps, adc, 1)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![1]);
+    assert_buffer_upcall(&apps, 1);
+    assert!(apps.take_upcalls(0).is_empty());
+}
+
+#[test]
+fn restart_ends_continuous_sampling_of_the_previous_instance() {
+    let (hardware, adc, apps) = setup_dedicated();
+
+    apps.allow_readwrite_at(0, DRIVER_NUM, 1, APP_BUFFER_LEN, APP_BUFFER_LEN);
+    run_dedicated(&apps, adc);
+    apps.take_returns(0);
+    apps.command(0, DRIVER_NUM, 4, 1, 1000);
+    run_dedicated(&apps, adc);
+    assert!(is_success(&apps.take_returns(0)));
+    restart_dedicated(&apps, adc);
+
+    // Continuous sampling never ends on its own, the ADC would stay taken.
+    assert!(is_success(&sample_buffer(&apps, adc, 2)));
+    assert_eq!(fill_buffers(hardware, &apps, adc), vec![2]);
+    assert_buffer_upcall(&apps, 2);
+}
@@ -0,0 +1,281 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! ADC threshold crossings sampled with an alarm on shared channels.
+
+use capsules_extra::adc_threshold::{
+    pack_channel_period, pack_thresholds, AdcThreshold, CROSSED_HIGH, CROSSED_LOW, DRIVER_NUM,
+};
+use kernel::hil::adc::AdcChannel;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, Apps, Board, FakeAdcChannel, FakeAlarm};
+
+type Threshold = AdcThreshold<'static, FakeAlarm<'static, Freq1KHz>>;
+
+struct Fixture {
+    threshold: &'static Threshold,
+    alarm: &'static FakeAlarm<'static, Freq1KHz>,
+    channels: Vec<&'static FakeAdcChannel<'static>>,
+    apps: Apps,
+}
+
+fn setup(channel_count: usize, app_count: usize) -> Fixture {
+    let board = Board::new();
+    let alarm = leak(FakeAlarm::new());
+    let channels: Vec<&'static FakeAdcChannel<'static>> = (0..channel_count)
+        .map(|_| leak(FakeAdcChannel::new(None)))
+        .collect();
+    let drivers: &'static [&'static dyn AdcChannel<'static>] = Box::leak(
+        channels
+            .iter()
+            .map(|channel| *channel as &dyn AdcChannel<'static>)
+            .collect(),
+    );
+    let threshold = leak(AdcThreshold::new(
+        drivers,
+        alarm,
+        board.create_grant(DRIVER_NUM),
+    ));
+    alarm.set_alarm_client(threshold);
+    for channel in &channels {
+        channel.set_client(threshold);
+    }
+    let apps = board.load_apps(app_count);
+    for app in 0..app_count {
+        apps.subscribe(app, DRIVER_NUM, 0);
+    }
+    let fixture = Fixture {
+        threshold,
+        alarm,
+        channels,
+        apps,
+    };
+    fixture.run();
+    for app in 0..app_count {
+        fixture.apps.take_returns(app);
+    }
+    fixture
+}
+
+impl Fixture {
+    fn run(&self) {
+        self.apps
+            .run(&[(DRIVER_NUM, self.threshold as &dyn SyscallDriver)]);
+    }
+
+    fn command(&self, app: usize, command: usize, data1: usize, data2: usize) -> SyscallReturn {
+        self.apps.command(app, DRIVER_NUM, command, data1, data2);
+        self.run();
+        let mut returns = self.apps.take_returns(app);
+        assert_eq!(returns.len(), 1);
+        returns.remove(0)
+    }
+
+    fn configure(&self, app: usize, channel: usize, period_ms: u32, low: u16, high: u16) {
+        let result = self.command(
+            app,
+            1,
+            pack_channel_period(channel, period_ms),
+            pack_thresholds(low, high),
+        );
+        assert!(matches!(result, SyscallReturn::Success));
+    }
+
+    fn start(&self, app: usize) {
+        assert!(matches!(self.command(app, 2, 0, 0), SyscallReturn::Success));
+    }
+}
+
+#[test]
+fn crossings_use_hysteresis() {
+    let f = setup(1, 1);
+    f.configure(0, 0, 100, 1000, 2000);
+    f.start(0);
+
+    // The first sample is taken right away and only sets the side.
+    assert!(f.channels[0].deliver(500));
+    assert_eq!(f.alarm.armed_dt(), Some(100));
+
+    // Samples inside the band, or up to the low threshold, do not cross.
+    let mut upcalls = Vec::new();
+    for sample in [1500, 1999, 1000, 2000, 1500, 1001, 1000, 2500] {
+        assert!(f.alarm.fire());
+        assert!(f.channels[0].deliver(sample));
+        f.run();
+        upcalls.extend(f.apps.take_upcalls(0));
+    }
+    assert_eq!(
+        upcalls,
+        vec![
+            (DRIVER_NUM, 0, [CROSSED_HIGH, 0, 2000]),
+            (DRIVER_NUM, 0, [CROSSED_LOW, 0, 1000]),
+            (DRIVER_NUM, 0, [CROSSED_HIGH, 0, 2500]),
+        ]
+    );
+}
+
+#[test]
+fn first_sample_above_sets_the_side() {
+    let f = setup(1, 1);
+    f.configure(0, 0, 10, 1000, 2000);
+    f.start(0);
+    assert!(f.channels[0].deliver(3000));
+    f.alarm.fire();
+    assert!(f.channels[0].deliver(2500));
+    f.run();
+    assert!(f.apps.take_upcalls(0).is_empty());
+
+    f.alarm.fire();
+    assert!(f.channels[0].deliver(900));
+    f.run();
+    assert_eq!(
+        f.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [CROSSED_LOW, 0, 900])]
+    );
+}
+
+#[test]
+fn configuration_is_checked() {
+    let f = setup(2, 1);
+    assert!(matches!(
+        f.command(0, 0, 0, 0),
+        SyscallReturn::SuccessU32(2)
+    ));
+
+    let cases = [
+        (
+            pack_channel_period(2, 10),
+            pack_thresholds(1, 2),
+            ErrorCode::NODEVICE,
+        ),
+        (
+            pack_channel_period(0, 0),
+            pack_thresholds(1, 2),
+            ErrorCode::INVAL,
+        ),
+        (
+            pack_channel_period(0, 10),
+            pack_thresholds(2, 2),
+            ErrorCode::INVAL,
+        ),
+        (
+            pack_channel_period(0, 10),
+            pack_thresholds(3, 2),
+            ErrorCode::INVAL,
+        ),
+    ];
+    for (data1, data2, error) in cases {
+        assert!(matches!(
+            f.command(0, 1, data1, data2),
+            SyscallReturn::Failure(e) if e == error
+        ));
+    }
+
+    // Starting needs a configuration, and sampling apps cannot change it.
+    assert!(matches!(
+        f.command(0, 2, 0, 0),
+        SyscallReturn::Failure(ErrorCode::INVAL)
+    ));
+    f.configure(0, 1, 10, 1, 2);
+    f.start(0);
+    assert!(matches!(
+        f.command(0, 2, 0, 0),
+        SyscallReturn::Failure(ErrorCode::ALREADY)
+    ));
+    assert!(matches!(
+        f.command(0, 1, pack_channel_period(0, 10), pack_thresholds(1, 2)),
+        SyscallReturn::Failure(ErrorCode::BUSY)
+    ));
+    assert!(f.channels[1].is_requested());
+    assert!(!f.channels[0].is_requested());
+}
+
+#[test]
+fn stopping_ends_sampling() {
+    let f = setup(1, 1);
+    f.configure(0, 0, 10, 1000, 2000);
+    f.start(0);
+    assert!(f.channels[0].deliver(500));
+
+    assert!(matches!(f.command(0, 3, 0, 0), SyscallReturn::Success));
+    assert_eq!(f.alarm.armed_dt(), None);
+    assert!(matches!(
+        f.command(0, 3, 0, 0),
+        SyscallReturn::Failure(ErrorCode::ALREADY)
+    ));
+
+    // A sample in progress when the app stops is dropped.
+    f.start(0);
+    assert!(matches!(f.command(0, 3, 0, 0), SyscallReturn::Success));
+    assert!(f.channels[0].deliver(3000));
+    f.run();
+    assert!(f.apps.take_upcalls(0).is_empty());
+    assert_eq!(f.channels[0].starts(), 2);
+}
+
+#[test]
+fn apps_share_a_channel() {
+    let f = setup(1, 2);
+    f.configure(0, 0, 10, 1000, 2000);
+    f.configure(1, 0, 20, 100, 200);
+    f.start(0);
+    f.start(1);
+
+    // The second app waits for the first sample to complete.
+    assert_eq!(f.channels[0].starts(), 1);
+    assert!(f.channels[0].deliver(500));
+    assert_eq!(f.channels[0].starts(), 2);
+    assert!(f.channels[0].deliver(500));
+
+    // The alarm follows the earliest period.
+    assert_eq!(f.alarm.armed_dt(), Some(10));
+    f.alarm.fire();
+    assert!(f.channels[0].deliver(2500));
+    assert!(!f.channels[0].is_requested());
+    assert_eq!(f.alarm.armed_dt(), Some(10));
+
+    // Both periods end together, and the samples are taken in turn.
+    f.alarm.fire();
+    assert!(f.channels[0].deliver(1500));
+    assert!(f.channels[0].deliver(50));
+    f.run();
+    assert_eq!(
+        f.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [CROSSED_HIGH, 0, 2500])]
+    );
+    assert_eq!(
+        f.apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [CROSSED_LOW, 0, 50])]
+    );
+}
+
+#[test]
+fn terminated_apps_are_not_sampled() {
+    let f = setup(1, 2);
+    f.configure(0, 0, 10, 1000, 2000);
+    f.configure(1, 0, 10, 1000, 2000);
+    f.start(0);
+    f.start(1);
+
+    // The sample of the terminated app is ignored, the other app's is taken.
+    f.apps.terminate(0);
+    assert!(f.channels[0].deliver(500));
+    assert!(f.channels[0].deliver(500));
+    f.alarm.fire();
+    assert!(f.channels[0].deliver(2500));
+    assert!(!f.channels[0].is_requested());
+    f.run();
+    assert_eq!(
+        f.apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [CROSSED_HIGH, 0, 2500])]
+    );
+
+    f.apps.terminate(1);
+    f.alarm.fire();
+    assert!(!f.channels[0].is_requested());
+    assert_eq!(f.alarm.armed_dt(), None);
+}
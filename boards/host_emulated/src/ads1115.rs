@@ -0,0 +1,276 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! ADS1115 channels over a scripted I2C device.
+
+use std::cell::RefCell;
+
+use capsules_core::adc::{AdcVirtualized, DRIVER_NUM};
+use capsules_extra::ads1115::{Ads1115, Ads1115Channel, Gain, InputMux, BUF_LEN};
+use kernel::hil::adc::{AdcChannel, Client};
+use kernel::hil::gpio::Interrupt;
+use kernel::hil::i2c;
+use kernel::hil::time::{Alarm, Freq1MHz};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+use crate::fixtures::{
+    leak, leak_buffer, Board, FakeAlarm, PinLog, RecordingPin, ScriptedI2CDevice,
+};
+
+type Alarm1MHz = FakeAlarm<'static, Freq1MHz>;
+type Chip = Ads1115<'static, Alarm1MHz, ScriptedI2CDevice<'static>>;
+type Channel = Ads1115Channel<'static, Alarm1MHz, ScriptedI2CDevice<'static>>;
+
+/// Config register for a single-shot conversion of AIN0 with the +/-4.096 V
+/// range at 128 SPS, comparator disabled.
+const AIN0_CONFIG: [u8; 2] = [0xC3, 0x83];
+/// Same for AIN1 with the +/-2.048 V range.
+const AIN1_CONFIG: [u8; 2] = [0xD5, 0x83];
+
+#[derive(Default)]
+struct Samples {
+    samples: RefCell<Vec<u16>>,
+}
+
+impl Client for Samples {
+    fn sample_ready(&self, sample: u16) {
+        self.samples.borrow_mut().push(sample);
+    }
+}
+
+/// Asks for the next sample of its channel as soon as it gets one.
+#[derive(Default)]
+struct Resampling {
+    channel: OptionalCell<&'static Channel>,
+    samples: RefCell<Vec<u16>>,
+}
+
+impl Client for Resampling {
+    fn sample_ready(&self, sample: u16) {
+        self.samples.borrow_mut().push(sample);
+        self.channel.map(|channel| channel.sample());
+    }
+}
+
+struct Fixture {
+    i2c: &'static ScriptedI2CDevice<'static>,
+    alarm: &'static Alarm1MHz,
+    chip: &'static Chip,
+}
+
+fn setup(ready_pin: Option<&'static RecordingPin<'static>>) -> Fixture {
+    let i2c = leak(ScriptedI2CDevice::new());
+    let alarm = leak(FakeAlarm::new());
+    let chip = leak(Ads1115::new(
+        i2c,
+        alarm,
+        ready_pin.map(|pin| pin as &dyn kernel::hil::gpio::InterruptPin),
+        leak_buffer(BUF_LEN),
+    ));
+    i2c.set_client(chip);
+    alarm.set_alarm_client(chip);
+    if let Some(pin) = ready_pin {
+        pin.set_client(chip);
+    }
+    Fixture { i2c, alarm, chip }
+}
+
+fn channel(fixture: &Fixture, mux: InputMux, gain: Gain) -> (&'static Channel, &'static Samples) {
+    let channel = leak(Ads1115Channel::new(fixture.chip, mux, gain));
+    channel.setup();
+    let samples = leak(Samples::default());
+    channel.set_client(samples);
+    (channel, samples)
+}
+
+/// Completes a conversion detected by polling, reporting `conversion` as the
+/// conversion register value.
+fn complete_polled_conversion(fixture: &Fixture, conversion: [u8; 2]) {
+    // Config write, OS bit poll, conversion read.
+    assert!(fixture.i2c.complete());
+    assert!(fixture.alarm.fire());
+    fixture.i2c.push_response(Ok(vec![0x83, 0x83]));
+    assert!(fixture.i2c.complete());
+    fixture.i2c.push_response(Ok(conversion.to_vec()));
+    assert!(fixture.i2c.complete());
+}
+
+#[test]
+fn polled_sample() {
+    let fixture = setup(None);
+    let (ain0, samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+    assert_eq!(ain0.get_resolution_bits(), 16);
+    assert_eq!(ain0.get_voltage_reference_mv(), Some(4096));
+
+    assert_eq!(ain0.sample(), Ok(()));
+    assert_eq!(ain0.sample(), Err(ErrorCode::BUSY));
+    assert!(fixture.i2c.complete());
+    assert_eq!(fixture.alarm.armed_dt(), Some(7_900));
+
+    // The first poll finds the conversion still running.
+    assert!(fixture.alarm.fire());
+    fixture.i2c.push_response(Ok(vec![0x43, 0x83]));
+    assert!(fixture.i2c.complete());
+    assert_eq!(fixture.alarm.armed_dt(), Some(1_000));
+
+    assert!(fixture.alarm.fire());
+    fixture.i2c.push_response(Ok(vec![0xC3, 0x83]));
+    assert!(fixture.i2c.complete());
+    fixture.i2c.push_response(Ok(vec![0x12, 0x34]));
+    assert!(fixture.i2c.complete());
+
+    assert_eq!(
+        fixture.i2c.take_written(),
+        vec![
+            vec![0x01, AIN0_CONFIG[0], AIN0_CONFIG[1]],
+            vec![0x01],
+            vec![0x01],
+            vec![0x00],
+        ]
+    );
+    assert_eq!(samples.samples.take(), vec![0x2468]);
+    assert!(!fixture.i2c.is_pending());
+}
+
+#[test]
+fn ready_pin_sample() {
+    let log = leak(PinLog::default());
+    let pin = leak(RecordingPin::new(0, log));
+    let fixture = setup(Some(pin));
+    let (ain0, samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+
+    // ALERT/RDY is set up before the first conversion only.
+    for conversion in [[0x7F, 0xFF], [0xFF, 0x00]] {
+        assert_eq!(ain0.sample(), Ok(()));
+        while fixture.i2c.complete() {}
+        assert_eq!(fixture.alarm.armed_dt(), None);
+        assert!(pin.trigger());
+        fixture.i2c.push_response(Ok(conversion.to_vec()));
+        assert!(fixture.i2c.complete());
+    }
+
+    assert_eq!(
+        fixture.i2c.take_written(),
+        vec![
+            vec![0x03, 0x80, 0x00],
+            vec![0x02, 0x00, 0x00],
+            vec![0x01, AIN0_CONFIG[0], 0x80],
+            vec![0x00],
+            vec![0x01, AIN0_CONFIG[0], 0x80],
+            vec![0x00],
+        ]
+    );
+    // Negative differential readings are reported as 0.
+    assert_eq!(samples.samples.take(), vec![0xFFFE, 0x0000]);
+}
+
+#[test]
+fn concurrent_samples_are_queued() {
+    let fixture = setup(None);
+    let (ain0, ain0_samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+    let (ain1, ain1_samples) = channel(&fixture, InputMux::Ain1, Gain::Fsr2048);
+    assert_eq!(ain1.get_voltage_reference_mv(), Some(2048));
+
+    assert_eq!(ain0.sample(), Ok(()));
+    assert_eq!(ain1.sample(), Ok(()));
+
+    // Only one conversion is on the bus at a time.
+    complete_polled_conversion(&fixture, [0x00, 0x10]);
+    let first = fixture.i2c.take_written();
+    assert!(fixture.i2c.is_pending());
+    complete_polled_conversion(&fixture, [0x00, 0x20]);
+    let second = fixture.i2c.take_written();
+    assert!(!fixture.i2c.is_pending());
+
+    let (ain0_written, ain1_written) = if first[0][1..] == AIN0_CONFIG {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    assert_eq!(ain0_written[0][1..], AIN0_CONFIG);
+    assert_eq!(ain1_written[0][1..], AIN1_CONFIG);
+    assert_eq!(ain0_samples.samples.take().len(), 1);
+    assert_eq!(ain1_samples.samples.take().len(), 1);
+}
+
+#[test]
+fn failed_start_is_returned() {
+    let fixture = setup(None);
+    let (ain0, samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+
+    fixture.i2c.fail_next_start(i2c::Error::Busy);
+    assert_eq!(ain0.sample(), Err(ErrorCode::BUSY));
+    assert!(!fixture.i2c.is_pending());
+
+    // The channel can be sampled again.
+    assert_eq!(ain0.sample(), Ok(()));
+    complete_polled_conversion(&fixture, [0x00, 0x10]);
+    assert_eq!(samples.samples.take(), vec![0x0020]);
+}
+
+#[test]
+fn queued_channel_is_not_starved() {
+    let fixture = setup(None);
+    let (ain0, ain0_samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+    let ain1 = leak(Ads1115Channel::new(
+        fixture.chip,
+        InputMux::Ain1,
+        Gain::Fsr2048,
+    ));
+    ain1.setup();
+    let resampling = leak(Resampling::default());
+    resampling.channel.set(ain1);
+    ain1.set_client(resampling);
+
+    assert_eq!(ain1.sample(), Ok(()));
+    assert_eq!(ain0.sample(), Ok(()));
+
+    // AIN1 asks again as soon as it gets its sample, AIN0 still goes next.
+    let mut configs = Vec::new();
+    for conversion in [[0x00, 0x10], [0x00, 0x20], [0x00, 0x30]] {
+        complete_polled_conversion(&fixture, conversion);
+        configs.push(fixture.i2c.take_written()[0][1..].to_vec());
+    }
+    assert_eq!(configs, vec![AIN1_CONFIG, AIN0_CONFIG, AIN1_CONFIG]);
+    assert_eq!(resampling.samples.take(), vec![0x0020, 0x0060]);
+    assert_eq!(ain0_samples.samples.take(), vec![0x0040]);
+}
+
+#[test]
+fn stopped_sample_is_dropped() {
+    let fixture = setup(None);
+    let (ain0, samples) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+
+    assert_eq!(ain0.sample(), Ok(()));
+    assert_eq!(ain0.stop_sampling(), Ok(()));
+    complete_polled_conversion(&fixture, [0x00, 0x10]);
+
+    assert!(samples.samples.take().is_empty());
+    assert!(!fixture.i2c.is_pending());
+}
+
+#[test]
+fn channels_plug_into_adc_virtualized() {
+    let board = Board::new();
+    let fixture = setup(None);
+    let (ain0, _) = channel(&fixture, InputMux::Ain0, Gain::Fsr4096);
+    let (ain1, _) = channel(&fixture, InputMux::Ain1, Gain::Fsr2048);
+    let channels: &'static [&'static dyn AdcChannel<'static>] = leak([
+        ain0 as &dyn AdcChannel<'static>,
+        ain1 as &dyn AdcChannel<'static>,
+    ]);
+    let adc = leak(AdcVirtualized::new(
+        channels,
+        board.create_grant(DRIVER_NUM),
+    ));
+    ain0.set_client(adc);
+    ain1.set_client(adc);
+
+    // Without a process waiting the sample is delivered to the virtualizer
+    // and dropped there.
+    assert_eq!(ain0.sample(), Ok(()));
+    complete_polled_conversion(&fixture, [0x00, 0x10]);
+    assert!(!fixture.i2c.is_pending());
+}
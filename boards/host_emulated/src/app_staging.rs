@@ -0,0 +1,286 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! App staging, installing staged images from a RAM backed storage into a
+//! RAM backed app flash.
+
+use std::cell::RefCell;
+
+use capsules_extra::app_staging::{
+    encode_padding, AppStaging, InstallClient, DRIVER_NUM, HEADER_LEN, PADDING_LEN,
+};
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::process::ShortId;
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::utilities::helpers::crc32_posix;
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, Board, RamStorage};
+
+const STAGING_START: usize = 0x100;
+const STAGING_LENGTH: usize = 0x300;
+const SLOT_SIZE: usize = 0x100;
+const SLOT_COUNT: usize = 4;
+
+#[derive(Default)]
+struct Installed {
+    results: RefCell<Vec<Result<Option<usize>, ErrorCode>>>,
+}
+
+impl InstallClient for Installed {
+    fn install_done(&self, result: Result<Option<usize>, ErrorCode>) {
+        self.results.borrow_mut().push(result);
+    }
+}
+
+struct Fixture {
+    board: Board,
+    staging: &'static RamStorage<'static>,
+    flash: &'static RamStorage<'static>,
+    app_staging: &'static AppStaging<'static>,
+    installed: &'static Installed,
+}
+
+fn setup() -> Fixture {
+    let board = Board::new();
+    let staging = leak(RamStorage::new(STAGING_START + STAGING_LENGTH));
+    let flash = leak(RamStorage::new(SLOT_COUNT * SLOT_SIZE));
+    // The buffer is smaller than an image, so that images are copied in
+    // several chunks.
+    let app_staging = leak(AppStaging::new(
+        staging,
+        STAGING_START,
+        STAGING_LENGTH,
+        flash,
+        0,
+        SLOT_SIZE,
+        SLOT_COUNT,
+        ShortId::Fixed(1.try_into().unwrap()),
+        board.create_grant(DRIVER_NUM),
+        leak_buffer(64),
+    ));
+    staging.set_client(app_staging);
+    flash.set_client(app_staging);
+    let installed = leak(Installed::default());
+    app_staging.set_install_client(installed);
+    Fixture {
+        board,
+        staging,
+        flash,
+        app_staging,
+        installed,
+    }
+}
+
+fn image(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7) as u8).collect()
+}
+
+/// Stages `image` for `slot`, as an updater would have committed it.
+fn stage(fixture: &Fixture, image: &[u8], slot: u32) {
+    let header: Vec<u8> = [0x5354_4147, image.len() as u32, crc32_posix(image), slot]
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect();
+    fixture.staging.set_contents(STAGING_START, &header);
+    fixture
+        .staging
+        .set_contents(STAGING_START + HEADER_LEN, image);
+}
+
+fn install(fixture: &Fixture) -> Vec<Result<Option<usize>, ErrorCode>> {
+    assert_eq!(fixture.app_staging.install(), Ok(()));
+    while fixture.staging.complete() || fixture.flash.complete() {}
+    fixture.installed.results.take()
+}
+
+fn header(fixture: &Fixture) -> Vec<u8> {
+    fixture.staging.contents(STAGING_START, HEADER_LEN)
+}
+
+fn erased_flash() -> Vec<u8> {
+    vec![0xff; SLOT_COUNT * SLOT_SIZE]
+}
+
+#[test]
+fn nothing_staged() {
+    let fixture = setup();
+    assert_eq!(install(&fixture), vec![Ok(None)]);
+    assert_eq!(
+        fixture.flash.contents(0, SLOT_COUNT * SLOT_SIZE),
+        erased_flash()
+    );
+}
+
+#[test]
+fn staged_image_is_copied_to_its_slot() {
+    let fixture = setup();
+    let image = image(200);
+    stage(&fixture, &image, 2);
+
+    assert_eq!(install(&fixture), vec![Ok(Some(2))]);
+    assert_eq!(fixture.flash.contents(2 * SLOT_SIZE, 200), image);
+    // The other slots are untouched.
+    let mut expected = erased_flash();
+    expected[2 * SLOT_SIZE..2 * SLOT_SIZE + 200].copy_from_slice(&image);
+    assert_eq!(fixture.flash.contents(0, SLOT_COUNT * SLOT_SIZE), expected);
+
+    // The header is cleared, the image is only installed once.
+    assert_eq!(header(&fixture), vec![0; HEADER_LEN]);
+    assert_eq!(install(&fixture), vec![Ok(None)]);
+}
+
+#[test]
+fn corrupted_image_is_rejected() {
+    let fixture = setup();
+    let image = image(200);
+    stage(&fixture, &image, 1);
+    fixture
+        .staging
+        .set_contents(STAGING_START + HEADER_LEN + 150, &[image[150] ^ 0x01]);
+
+    assert_eq!(install(&fixture), vec![Err(ErrorCode::FAIL)]);
+    assert_eq!(
+        fixture.flash.contents(0, SLOT_COUNT * SLOT_SIZE),
+        erased_flash()
+    );
+    assert_eq!(header(&fixture), vec![0; HEADER_LEN]);
+}
+
+#[test]
+fn invalid_header_is_rejected() {
+    let fixture = setup();
+
+    // The slot does not exist, the image does not fit in a slot, or it is
+    // empty.
+    for (length, slot) in [(200, 4), (SLOT_SIZE + 1, 0), (0, 0)] {
+        stage(&fixture, &image(length), slot);
+        assert_eq!(install(&fixture), vec![Err(ErrorCode::INVAL)]);
+        assert_eq!(header(&fixture), vec![0; HEADER_LEN]);
+    }
+    assert_eq!(
+        fixture.flash.contents(0, SLOT_COUNT * SLOT_SIZE),
+        erased_flash()
+    );
+}
+
+/// The TBF header of a padding binary filling a slot.
+fn padding() -> Vec<u8> {
+    let mut padding = vec![0; PADDING_LEN];
+    encode_padding(&mut padding, SLOT_SIZE);
+    padding
+}
+
+#[test]
+fn padding_header_is_a_tbf_header() {
+    // Version 2, 16 bytes of header, the size of the slot, no flag, and the
+    // XOR of the other words as checksum.
+    assert_eq!(
+        padding(),
+        [
+            0x02, 0x00, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01,
+            0x10, 0x00
+        ]
+    );
+}
+
+#[test]
+fn failed_copy_is_retried() {
+    let fixture = setup();
+    let image = image(200);
+    stage(&fixture, &image, 0);
+
+    // The first write to app flash, of the padding header, fails.
+    assert_eq!(fixture.app_staging.install(), Ok(()));
+    while !fixture.flash.is_pending() {
+        assert!(fixture.staging.complete());
+    }
+    fixture.flash.fail_next(ErrorCode::FAIL);
+    while fixture.staging.complete() || fixture.flash.complete() {}
+    assert_eq!(fixture.installed.results.take(), vec![Err(ErrorCode::FAIL)]);
+
+    // The staged image is kept, and installed on the next boot.
+    assert_ne!(header(&fixture), vec![0; HEADER_LEN]);
+    assert_eq!(install(&fixture), vec![Ok(Some(0))]);
+    assert_eq!(fixture.flash.contents(0, 200), image);
+}
+
+#[test]
+fn slot_is_padding_until_the_copy_completes() {
+    let fixture = setup();
+    let old = image(SLOT_SIZE)
+        .iter()
+        .map(|byte| !byte)
+        .collect::<Vec<_>>();
+    fixture.flash.set_contents(SLOT_SIZE, &old);
+    let image = image(200);
+    stage(&fixture, &image, 1);
+
+    // The padding header and the first chunk are written, the next chunk
+    // fails.
+    assert_eq!(fixture.app_staging.install(), Ok(()));
+    let mut writes = 0;
+    while writes < 3 {
+        if fixture.flash.is_pending() {
+            writes += 1;
+            if writes == 3 {
+                fixture.flash.fail_next(ErrorCode::FAIL);
+            }
+            assert!(fixture.flash.complete());
+        } else {
+            assert!(fixture.staging.complete());
+        }
+    }
+    while fixture.staging.complete() || fixture.flash.complete() {}
+    assert_eq!(fixture.installed.results.take(), vec![Err(ErrorCode::FAIL)]);
+
+    // The slot is skipped as padding, not loaded as half the old app.
+    assert_eq!(fixture.flash.contents(SLOT_SIZE, PADDING_LEN), padding());
+    assert_eq!(
+        fixture.flash.contents(SLOT_SIZE + PADDING_LEN, 64),
+        image[PADDING_LEN..PADDING_LEN + 64]
+    );
+    assert_eq!(
+        fixture.flash.contents(SLOT_SIZE + 200, SLOT_SIZE - 200),
+        old[200..]
+    );
+
+    // The retry copies the TBF header of the image last.
+    assert_eq!(install(&fixture), vec![Ok(Some(1))]);
+    assert_eq!(fixture.flash.contents(SLOT_SIZE, 200), image);
+    assert_eq!(header(&fixture), vec![0; HEADER_LEN]);
+}
+
+#[test]
+fn unreadable_header_is_kept() {
+    let fixture = setup();
+    stage(&fixture, &image(200), 0);
+
+    fixture.staging.fail_next(ErrorCode::FAIL);
+    assert_eq!(install(&fixture), vec![Err(ErrorCode::FAIL)]);
+    assert_ne!(header(&fixture), vec![0; HEADER_LEN]);
+    assert_eq!(
+        fixture.flash.contents(0, SLOT_COUNT * SLOT_SIZE),
+        erased_flash()
+    );
+}
+
+#[test]
+fn only_the_updater_may_stage() {
+    let fixture = setup();
+    let apps = fixture.board.load_apps(1);
+
+    // The app has no fixed ShortId, so it is not the updater.
+    apps.command(0, DRIVER_NUM, 0, 0, 0);
+    apps.command(0, DRIVER_NUM, 3, 0, 0);
+    apps.run(&[(DRIVER_NUM, fixture.app_staging as &dyn SyscallDriver)]);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ]
+    ));
+    assert!(!fixture.staging.is_pending());
+}
@@ -0,0 +1,325 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Process console board commands, alone and typed into a console.
+
+use std::cell::{Cell, RefCell};
+
+use capsules_core::process_console::{
+    Command, ConsoleCommand, ConsoleCommandClient, KernelAddresses, ProcessConsole,
+    COMMAND_BUF_LEN, QUEUE_BUF_LEN, READ_BUF_LEN, WRITE_BUF_LEN,
+};
+use capsules_extra::console_commands::{
+    AdcCommand, SettingsCommand, StorageCommand, STORAGE_BUF_LEN,
+};
+use capsules_extra::settings::{self, Setting, SettingClient, Settings};
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::hil::adc::AdcChannel;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::hil::uart::{Receive, Transmit};
+use kernel::process::{ProcessPrinterText, ShortId};
+use kernel::ErrorCode;
+
+use crate::fixtures::{
+    leak, leak_buffer, Board, ConsoleUart, FakeAdcChannel, FakeAlarm, RamStorage,
+};
+
+/// Captures what a command prints.
+#[derive(Default)]
+struct Output {
+    text: RefCell<String>,
+    done: Cell<usize>,
+}
+
+impl ConsoleCommandClient for Output {
+    fn print(&self, bytes: &[u8]) {
+        self.text
+            .borrow_mut()
+            .push_str(std::str::from_utf8(bytes).unwrap());
+    }
+
+    fn command_done(&self) {
+        self.done.set(self.done.get() + 1);
+    }
+}
+
+fn adc_command() -> (
+    &'static AdcCommand<'static>,
+    [&'static FakeAdcChannel<'static>; 2],
+    &'static Output,
+) {
+    let channel0 = leak(FakeAdcChannel::new(Some(3300)));
+    let channel1 = leak(FakeAdcChannel::new(None));
+    let channels: &'static [&'static dyn AdcChannel<'static>] = leak([
+        channel0 as &dyn AdcChannel<'static>,
+        channel1 as &dyn AdcChannel<'static>,
+    ]);
+    let command = leak(AdcCommand::new(channels));
+    channel0.set_client(command);
+    channel1.set_client(command);
+    let output = leak(Output::default());
+    command.set_client(output);
+    (command, [channel0, channel1], output)
+}
+
+fn storage_command() -> (
+    &'static StorageCommand<'static>,
+    &'static RamStorage<'static>,
+    &'static Output,
+) {
+    let ram = leak(RamStorage::new(0x100));
+    let buffer = Box::leak(Box::new([0; STORAGE_BUF_LEN]));
+    let command = leak(StorageCommand::new(ram, buffer));
+    ram.set_client(command);
+    let output = leak(Output::default());
+    command.set_client(output);
+    (command, ram, output)
+}
+
+#[test]
+fn adc_read_prints_millivolts() {
+    let (command, [channel0, channel1], output) = adc_command();
+
+    assert_eq!(command.execute("read 0"), Ok(()));
+    assert_eq!(command.execute("read 1"), Err(ErrorCode::BUSY));
+    assert!(channel0.deliver(0x8000));
+    assert_eq!(output.text.take(), "adc 0: 1650 mV (raw 0x8000)\r\n");
+    assert_eq!(output.done.take(), 1);
+
+    // Without a known reference only the raw value is printed.
+    assert_eq!(command.execute("read 0x1"), Ok(()));
+    assert!(channel1.deliver(0x1234));
+    assert_eq!(output.text.take(), "adc 1: raw 0x1234\r\n");
+    assert_eq!(output.done.take(), 1);
+}
+
+#[test]
+fn adc_rejects_bad_arguments() {
+    let (command, [channel0, _], output) = adc_command();
+
+    for arguments in ["", "read", "read 2", "write 0", "read zero"] {
+        assert_eq!(command.execute(arguments), Err(ErrorCode::INVAL));
+    }
+    assert!(!channel0.is_requested());
+    assert_eq!(output.text.take(), "");
+    assert_eq!(output.done.get(), 0);
+}
+
+#[test]
+fn storage_hex_dump() {
+    let (command, ram, output) = storage_command();
+    let data = leak_buffer(STORAGE_BUF_LEN);
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert_eq!(ram.write(data, 0x10, STORAGE_BUF_LEN), Ok(()));
+    assert!(ram.complete());
+
+    assert_eq!(command.execute("0x12 20"), Ok(()));
+    assert_eq!(command.execute("0"), Err(ErrorCode::BUSY));
+    assert!(ram.complete());
+    assert_eq!(
+        output.text.take(),
+        "00000012: 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 10 11\r\n\
+         00000022: 12 13 14 15\r\n"
+    );
+    assert_eq!(output.done.take(), 1);
+
+    // The length is optional and capped to the buffer.
+    assert_eq!(command.execute("0"), Ok(()));
+    assert!(ram.complete());
+    assert_eq!(output.text.take().lines().count(), 1);
+    assert_eq!(command.execute("0 1000"), Ok(()));
+    assert!(ram.complete());
+    assert_eq!(output.text.take().lines().count(), STORAGE_BUF_LEN / 16);
+
+    for arguments in ["", "0 0", "0x 4"] {
+        assert_eq!(command.execute(arguments), Err(ErrorCode::INVAL));
+    }
+}
+
+#[test]
+fn storage_read_failure() {
+    let (command, ram, output) = storage_command();
+
+    assert_eq!(command.execute("0 4"), Ok(()));
+    ram.fail_next(ErrorCode::FAIL);
+    assert!(ram.complete());
+    assert_eq!(output.text.take(), "storage read failed: FAIL\r\n");
+    assert_eq!(output.done.take(), 1);
+    // The buffer is back.
+    assert_eq!(command.execute("0 4"), Ok(()));
+}
+
+/// Owns the settings but does nothing with them.
+struct Ignore;
+
+impl SettingClient for Ignore {
+    fn apply_setting(&self, _key: u32, _value: i32) {}
+}
+
+#[test]
+fn settings_list_and_change() {
+    let board = Board::new();
+    let ram = leak(RamStorage::new(0x40));
+    let settings = leak(Settings::new(
+        ram,
+        0,
+        0x40,
+        ShortId::LocallyUnique,
+        board.create_grant(settings::DRIVER_NUM),
+        leak_buffer(0x40),
+    ));
+    ram.set_client(settings);
+    for setting in [
+        Setting::new(0x10, 1000, 100, 60_000, &Ignore),
+        Setting::new(0x20, -10, -40, 85, &Ignore),
+    ] {
+        assert_eq!(settings.register(leak(setting)), Ok(()));
+    }
+    // A persisted threshold above the maximum.
+    ram.set_contents(0, &[0x20, 0, 0, 0, 90, 0, 0, 0]);
+    assert_eq!(settings.load(), Ok(()));
+    assert!(ram.complete());
+
+    let command = leak(SettingsCommand::new(settings));
+    settings.set_client(command);
+    let output = leak(Output::default());
+    command.set_client(output);
+
+    assert_eq!(command.execute(""), Ok(()));
+    assert_eq!(
+        output.text.take(),
+        "0x10: 1000 [100, 60000]\r\n0x20: 85 [-40, 85] clamped\r\n"
+    );
+    assert_eq!(output.done.take(), 1);
+
+    assert_eq!(command.execute("0x20 -0x20"), Ok(()));
+    assert!(ram.complete());
+    assert_eq!(output.text.take(), "0x20: -32\r\n");
+    assert_eq!(output.done.take(), 1);
+    assert_eq!(ram.contents(0, 8), [0x20, 0, 0, 0, 0xe0, 0xff, 0xff, 0xff]);
+
+    assert_eq!(command.execute("16 250"), Ok(()));
+    ram.fail_next(ErrorCode::FAIL);
+    assert!(ram.complete());
+    assert_eq!(output.text.take(), "0x10: change failed: FAIL\r\n");
+    assert_eq!(output.done.take(), 1);
+
+    for arguments in ["0x10", "0x10 99", "0x30 1", "0x10 1 2", "key 1"] {
+        assert_eq!(command.execute(arguments), Err(ErrorCode::INVAL));
+    }
+    assert_eq!(output.done.get(), 0);
+}
+
+struct ProcessManagementCap;
+unsafe impl ProcessManagementCapability for ProcessManagementCap {}
+
+type Console = ProcessConsole<'static, 10, FakeAlarm<'static, Freq1KHz>, ProcessManagementCap>;
+
+fn console(
+    board: &Board,
+    commands: &'static [&'static dyn ConsoleCommand<'static>],
+) -> (&'static Console, &'static ConsoleUart<'static>) {
+    let uart = leak(ConsoleUart::new());
+    let alarm = leak(FakeAlarm::new());
+    let console = leak(ProcessConsole::new(
+        uart,
+        alarm,
+        leak(ProcessPrinterText::new()),
+        leak_buffer(WRITE_BUF_LEN),
+        leak_buffer(READ_BUF_LEN),
+        leak_buffer(QUEUE_BUF_LEN),
+        leak_buffer(COMMAND_BUF_LEN),
+        Box::leak(Box::new([Command::default(); 10])),
+        board.kernel,
+        KernelAddresses {
+            stack_start: core::ptr::null(),
+            stack_end: core::ptr::null(),
+            text_start: core::ptr::null(),
+            text_end: core::ptr::null(),
+            read_only_data_start: core::ptr::null(),
+            relocations_start: core::ptr::null(),
+            relocations_end: core::ptr::null(),
+            bss_start: core::ptr::null(),
+            bss_end: core::ptr::null(),
+        },
+        None,
+        ProcessManagementCap,
+    ));
+    uart.set_transmit_client(console);
+    uart.set_receive_client(console);
+    alarm.set_alarm_client(console);
+    console.set_commands(commands);
+    assert_eq!(console.start(), Ok(()));
+    assert!(alarm.fire());
+    uart.flush();
+    assert_eq!(uart.take_output(), "tock$ ");
+    (console, uart)
+}
+
+#[test]
+fn console_runs_board_commands() {
+    let board = Board::new();
+    let (adc, [channel0, _], _) = adc_command();
+    let (storage, ram, _) = storage_command();
+    let commands: &'static [&'static dyn ConsoleCommand<'static>] = leak([
+        adc as &dyn ConsoleCommand<'static>,
+        storage as &dyn ConsoleCommand<'static>,
+    ]);
+    let (_, uart) = console(&board, commands);
+
+    uart.type_text("help\r");
+    let help = uart.take_output();
+    assert!(help.contains("Board commands are:\r\n"));
+    assert!(help.contains("  adc           adc read <channel>: sample an ADC channel, in mV\r\n"));
+    assert!(help.ends_with("tock$ "));
+
+    // The prompt comes back once the sample is printed.
+    uart.type_text("adc read 0\r");
+    assert_eq!(uart.take_output(), "adc read 0\r\n");
+    assert!(channel0.deliver(0xFFFF));
+    uart.flush();
+    assert_eq!(uart.take_output(), "adc 0: 3299 mV (raw 0xffff)\r\ntock$ ");
+
+    uart.type_text("storage 0 2\r");
+    assert!(ram.complete());
+    uart.flush();
+    assert_eq!(
+        uart.take_output(),
+        "storage 0 2\r\n00000000: ff ff\r\ntock$ "
+    );
+
+    uart.type_text("adc read 5\r");
+    assert_eq!(
+        uart.take_output(),
+        "adc read 5\r\nadc failed: INVAL\r\n  adc           adc read <channel>: sample an ADC channel, in mV\r\ntock$ "
+    );
+
+    // Unknown commands list the board commands too.
+    uart.type_text("adcx\r");
+    assert!(uart
+        .take_output()
+        .ends_with("Board commands are: adc storage\r\ntock$ "));
+}
+
+#[test]
+fn console_refuses_overlapping_board_commands() {
+    let board = Board::new();
+    let (adc, [channel0, _], _) = adc_command();
+    let commands: &'static [&'static dyn ConsoleCommand<'static>] =
+        leak([adc as &dyn ConsoleCommand<'static>]);
+    let (_, uart) = console(&board, commands);
+
+    uart.type_text("adc read 0\r");
+    uart.type_text("adc read 1\r");
+    assert_eq!(
+        uart.take_output(),
+        "adc read 0\r\nadc read 1\r\nBusy: the previous command is not finished.\r\n"
+    );
+    assert!(channel0.deliver(0));
+    uart.flush();
+    assert_eq!(uart.take_output(), "adc 0: 0 mV (raw 0x0000)\r\ntock$ ");
+}
@@ -0,0 +1,303 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! The entropy converters over scripted 8 and 32 bit sources, covering the
+//! `Continue::More` and `Continue::Done` cases and error propagation.
+
+use std::cell::{Cell, RefCell};
+
+use capsules_core::rng::{Entropy32To8, Entropy32ToRandom, Entropy8To32};
+use kernel::hil::entropy::{self, Entropy32, Entropy8};
+use kernel::hil::rng::{self, Rng};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, DeterministicEntropy32, DeterministicEntropy8};
+
+/// Takes entropy until it has `wanted` items, and gives up on errors.
+struct Taker<T> {
+    wanted: Cell<usize>,
+    taken: RefCell<Vec<T>>,
+    errors: RefCell<Vec<Result<(), ErrorCode>>>,
+}
+
+impl<T> Taker<T> {
+    fn new(wanted: usize) -> Self {
+        Self {
+            wanted: Cell::new(wanted),
+            taken: RefCell::new(Vec::new()),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Takes items from `entropy`, returns whether it wants more.
+    fn take_from(
+        &self,
+        entropy: &mut dyn Iterator<Item = T>,
+        error: Result<(), ErrorCode>,
+    ) -> bool {
+        self.errors.borrow_mut().push(error);
+        let mut taken = self.taken.borrow_mut();
+        while taken.len() < self.wanted.get() {
+            match entropy.next() {
+                Some(item) => taken.push(item),
+                None => return error == Ok(()),
+            }
+        }
+        false
+    }
+
+    /// Asks for `wanted` more items.
+    fn want(&self, wanted: usize) {
+        self.wanted.set(self.taken.borrow().len() + wanted);
+    }
+}
+
+fn continue_entropy(more: bool) -> entropy::Continue {
+    if more {
+        entropy::Continue::More
+    } else {
+        entropy::Continue::Done
+    }
+}
+
+impl entropy::Client32 for Taker<u32> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        continue_entropy(self.take_from(entropy, error))
+    }
+}
+
+impl entropy::Client8 for Taker<u8> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u8>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        continue_entropy(self.take_from(entropy, error))
+    }
+}
+
+impl rng::Client for Taker<u32> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        if self.take_from(randomness, error) {
+            rng::Continue::More
+        } else {
+            rng::Continue::Done
+        }
+    }
+}
+
+type Words = Entropy8To32<'static, DeterministicEntropy8<'static>>;
+
+fn words(
+    wanted: usize,
+) -> (
+    &'static DeterministicEntropy8<'static>,
+    &'static Words,
+    &'static Taker<u32>,
+) {
+    let source = leak(DeterministicEntropy8::new());
+    let to32 = leak(Entropy8To32::new(source));
+    let taker = leak(Taker::new(wanted));
+    to32.set_client(taker);
+    (source, to32, taker)
+}
+
+type Bytes = Entropy32To8<'static, DeterministicEntropy32<'static>>;
+
+fn bytes(
+    wanted: usize,
+) -> (
+    &'static DeterministicEntropy32<'static>,
+    &'static Bytes,
+    &'static Taker<u8>,
+) {
+    let source = leak(DeterministicEntropy32::new());
+    let to8 = leak(Entropy32To8::new(source));
+    let taker = leak(Taker::new(wanted));
+    to8.set_client(taker);
+    (source, to8, taker)
+}
+
+#[test]
+fn words_across_callbacks() {
+    let (source, to32, taker) = words(2);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2], Ok(())), 2);
+    assert!(source.is_requested());
+    assert_eq!(source.deliver(&[3, 4, 5], Ok(())), 3);
+    assert!(source.is_requested());
+    assert_eq!(*taker.taken.borrow(), vec![0x0403_0201]);
+
+    // Done after the second word, the last byte stays with the source.
+    assert_eq!(source.deliver(&[6, 7, 8, 9], Ok(())), 3);
+    assert!(!source.is_requested());
+    assert_eq!(taker.taken.take(), vec![0x0403_0201, 0x0807_0605]);
+    assert_eq!(taker.errors.take(), vec![Ok(()); 2]);
+}
+
+#[test]
+fn several_words_in_one_callback() {
+    let (source, to32, taker) = words(2);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2, 3, 4, 5, 6, 7, 8], Ok(())), 8);
+    assert!(!source.is_requested());
+    assert_eq!(taker.taken.take(), vec![0x0403_0201, 0x0807_0605]);
+}
+
+#[test]
+fn word_not_taken_is_not_reused() {
+    // A client that is done without taking the word.
+    let (source, to32, taker) = words(0);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2, 3, 4], Ok(())), 4);
+    assert!(!source.is_requested());
+    assert!(taker.taken.take().is_empty());
+
+    taker.want(1);
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[5, 6], Ok(())), 2);
+    assert!(source.is_requested());
+    assert_eq!(source.deliver(&[7, 8], Ok(())), 2);
+    assert_eq!(taker.taken.take(), vec![0x0807_0605]);
+}
+
+#[test]
+fn word_not_taken_is_kept_while_client_wants_more() {
+    let (source, to32, taker) = words(0);
+
+    // The client asks for more but takes nothing: it gets the word again.
+    struct Later<'a>(&'a Taker<u32>, Cell<bool>);
+    impl entropy::Client32 for Later<'_> {
+        fn entropy_available(
+            &self,
+            entropy: &mut dyn Iterator<Item = u32>,
+            error: Result<(), ErrorCode>,
+        ) -> entropy::Continue {
+            if self.1.replace(true) {
+                entropy::Client32::entropy_available(self.0, entropy, error)
+            } else {
+                entropy::Continue::More
+            }
+        }
+    }
+    let later = leak(Later(taker, Cell::new(false)));
+    to32.set_client(later);
+    taker.want(2);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2, 3, 4, 5], Ok(())), 4);
+    assert!(source.is_requested());
+    assert_eq!(source.deliver(&[5, 6, 7, 8], Ok(())), 4);
+    assert!(!source.is_requested());
+    assert_eq!(taker.taken.take(), vec![0x0403_0201, 0x0807_0605]);
+}
+
+#[test]
+fn partial_word_dropped_when_client_is_done() {
+    let (source, to32, taker) = words(1);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2], Ok(())), 2);
+    // The client gives up on the error.
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    assert!(!source.is_requested());
+    assert!(taker.taken.take().is_empty());
+    // The client only got the error, as there was no word before it.
+    assert_eq!(taker.errors.take(), vec![Err(ErrorCode::FAIL)]);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[5, 6, 7, 8], Ok(())), 4);
+    assert_eq!(taker.taken.take(), vec![0x0807_0605]);
+}
+
+#[test]
+fn flush_drops_partial_word() {
+    let (source, to32, taker) = words(1);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2], Ok(())), 2);
+    to32.flush();
+    assert_eq!(source.deliver(&[5, 6, 7, 8], Ok(())), 4);
+    assert_eq!(taker.taken.take(), vec![0x0807_0605]);
+}
+
+#[test]
+fn errors_reach_word_client() {
+    let (source, to32, taker) = words(1);
+
+    assert_eq!(to32.get(), Ok(()));
+    assert_eq!(source.deliver(&[1, 2, 3], Err(ErrorCode::CANCEL)), 0);
+    assert!(!source.is_requested());
+    assert_eq!(taker.errors.take(), vec![Err(ErrorCode::CANCEL)]);
+    assert!(taker.taken.take().is_empty());
+}
+
+#[test]
+fn bytes_across_callbacks() {
+    let (source, to8, taker) = bytes(6);
+
+    assert_eq!(to8.get(), Ok(()));
+    source.deliver(&[0x0403_0201], Ok(()));
+    assert!(source.is_requested());
+    source.deliver(&[0x0807_0605], Ok(()));
+    assert!(!source.is_requested());
+    assert_eq!(taker.taken.take(), vec![1, 2, 3, 4, 5, 6]);
+
+    // The next request starts with a new word.
+    taker.want(2);
+    assert_eq!(to8.get(), Ok(()));
+    source.deliver(&[0x0c0b_0a09], Ok(()));
+    assert!(!source.is_requested());
+    assert_eq!(taker.taken.take(), vec![9, 10]);
+    assert_eq!(taker.errors.take(), vec![Ok(()); 3]);
+}
+
+#[test]
+fn bytes_wait_for_a_word() {
+    let (source, to8, taker) = bytes(1);
+
+    assert_eq!(to8.get(), Ok(()));
+    source.deliver(&[], Ok(()));
+    assert!(source.is_requested());
+    // The client is not called without a word.
+    assert!(taker.errors.take().is_empty());
+    source.deliver(&[0xff], Ok(()));
+    assert_eq!(taker.taken.take(), vec![0xff]);
+}
+
+#[test]
+fn errors_reach_byte_client() {
+    let (source, to8, taker) = bytes(1);
+
+    assert_eq!(to8.get(), Ok(()));
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    assert!(!source.is_requested());
+    assert_eq!(taker.errors.take(), vec![Err(ErrorCode::FAIL)]);
+}
+
+#[test]
+fn errors_reach_random_client_with_the_entropy() {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let taker = leak(Taker::new(1));
+    rng.set_client(taker);
+
+    // The words that come with an error are still random.
+    assert_eq!(rng.get(), Ok(()));
+    source.deliver(&[7], Err(ErrorCode::CANCEL));
+    assert!(!source.is_requested());
+    assert_eq!(taker.errors.take(), vec![Err(ErrorCode::CANCEL)]);
+    assert_eq!(taker.taken.take(), vec![7]);
+}
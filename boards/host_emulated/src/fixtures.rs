@@ -0,0 +1,1852 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Emulated peripherals shared by all the scenarios.
+//!
+//! None of these complete an operation on their own: the scenario decides
+//! when an alarm fires or when a bus transfer finishes, which makes every
+//! interleaving reproducible.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Mutex, MutexGuard};
+
+use kernel::capabilities;
+use kernel::deferred_call::DeferredCall;
+use kernel::hil::adc;
+use kernel::hil::entropy;
+use kernel::hil::gpio;
+use kernel::hil::i2c;
+use kernel::hil::nonvolatile_storage;
+use kernel::hil::sensors;
+use kernel::hil::spi;
+use kernel::hil::time::{self, Frequency, Ticks, Ticks32};
+use kernel::hil::uart;
+use kernel::platform::chip::Chip;
+use kernel::platform::{KernelResources, SyscallDriverLookup};
+use kernel::process::{self, FunctionCall, FunctionCallSource, Process};
+use kernel::scheduler::{Scheduler, SchedulingDecision};
+use kernel::syscall::{
+    ContextSwitchReason, Syscall, SyscallDriver, SyscallReturn, UserspaceKernelBoundary,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, Kernel, ProcessId};
+
+/// Moves `value` to the heap and returns a `'static` reference to it, the
+/// same way `static_init!` provides `'static` objects on a real board.
+pub fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+/// Returns a zeroed `'static` buffer of `len` bytes.
+pub fn leak_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// Held by the scenario using the deferred calls of the kernel.
+static DEFERRED_CALLS: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static DEFERRED_CALLS_GUARD: RefCell<Option<MutexGuard<'static, ()>>> =
+        const { RefCell::new(None) };
+}
+
+/// Gives the deferred calls of the kernel to the calling scenario until it
+/// ends, starting from none.
+///
+/// The kernel keeps deferred calls in globals with room for 32 of them, and
+/// the scenarios run in parallel threads. Scenarios creating capsules which
+/// use a deferred call run one at a time, and each resets the globals. This
+/// must be called before the first such capsule is created, which
+/// [`Board::new`] does. Each test runs in its own thread, and its turn ends
+/// with the thread.
+pub fn take_deferred_calls() {
+    DEFERRED_CALLS_GUARD.with(|guard| {
+        let mut guard = guard.borrow_mut();
+        if guard.is_none() {
+            // A scenario that failed does not keep the others from running.
+            *guard = Some(DEFERRED_CALLS.lock().unwrap_or_else(|e| e.into_inner()));
+            // SAFETY: The deferred calls of the previous scenarios are not
+            // used anymore, and the other threads wait for their turn.
+            unsafe { DeferredCall::reset() };
+        }
+    });
+}
+
+struct MemoryAllocationCap;
+unsafe impl capabilities::MemoryAllocationCapability for MemoryAllocationCap {}
+
+struct ExternalProcessCap;
+unsafe impl capabilities::ExternalProcessCapability for ExternalProcessCap {}
+
+struct ProcessManagementCap;
+unsafe impl capabilities::ProcessManagementCapability for ProcessManagementCap {}
+
+struct MainLoopCap;
+unsafe impl capabilities::MainLoopCapability for MainLoopCap {}
+
+/// The number of processes a [`Board`] can load.
+pub const MAX_APPS: usize = 4;
+
+/// A kernel used to create the grants capsules need. It has no process
+/// until the scenario loads some with [`Board::load_apps`].
+pub struct Board {
+    pub kernel: &'static Kernel,
+    processes: *mut [Option<&'static dyn Process>; MAX_APPS],
+}
+
+impl Board {
+    pub fn new() -> Self {
+        take_deferred_calls();
+        let processes: *mut [Option<&'static dyn Process>; MAX_APPS] =
+            Box::leak(Box::new([None; MAX_APPS]));
+        Self {
+            // The kernel reads the array the processes are loaded into, the
+            // same way boards share their `PROCESSES` array.
+            kernel: leak(Kernel::new(unsafe { &(*processes)[..] })),
+            processes,
+        }
+    }
+
+    pub fn create_grant<
+        T: Default,
+        Upcalls: kernel::grant::UpcallSize,
+        AllowROs: kernel::grant::AllowRoSize,
+        AllowRWs: kernel::grant::AllowRwSize,
+    >(
+        &self,
+        driver_num: usize,
+    ) -> kernel::grant::Grant<T, Upcalls, AllowROs, AllowRWs> {
+        self.kernel.create_grant(driver_num, &MemoryAllocationCap)
+    }
+
+    /// A `ProcessId` for a process that does not exist, to issue commands
+    /// to drivers. Entering a grant with it fails, so no upcall is ever
+    /// delivered.
+    pub fn process_id(&self, identifier: usize) -> ProcessId {
+        ProcessId::new_external(self.kernel, identifier, 0, &ExternalProcessCap)
+    }
+
+    /// Loads `count` processes, see [`Apps`]. The kernel allocates the
+    /// grant regions when it loads processes, so all the grants must be
+    /// created first.
+    pub fn load_apps(&self, count: usize) -> Apps {
+        assert!(count <= MAX_APPS);
+        let chip = leak(AppChip::default());
+        let mut flash = Vec::new();
+        for _ in 0..count {
+            flash.extend_from_slice(&app_binary());
+        }
+        // A header that cannot be parsed ends the list of apps.
+        flash.extend_from_slice(&[0; 8]);
+
+        // Process structures are placed in app memory, which must be
+        // aligned for them.
+        let memory: &'static mut [u64] =
+            Box::leak(vec![0; count * 2 * APP_RAM_SIZE / 8].into_boxed_slice());
+        let memory = unsafe {
+            std::slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut u8, memory.len() * 8)
+        };
+
+        process::load_processes(
+            self.kernel,
+            chip,
+            Box::leak(flash.into_boxed_slice()),
+            memory,
+            unsafe { &mut *self.processes },
+            leak(process::PanicFaultPolicy {}),
+            &ProcessManagementCap,
+        )
+        .expect("failed to load the apps");
+
+        let mut ids = Vec::new();
+        self.kernel
+            .process_each_capability(&ProcessManagementCap, |process| {
+                ids.push(process.processid())
+            });
+        assert_eq!(ids.len(), count);
+        let apps = Apps {
+            kernel: self.kernel,
+            chip,
+            ids: RefCell::new(ids),
+        };
+        // Start the apps, they yield right away.
+        apps.run(&[]);
+        apps
+    }
+}
+
+/// Size of the memory an app can access, where its allow buffers are.
+const APP_MEMORY_SIZE: usize = 4096;
+/// RAM of an app, the rest is left for its grants.
+const APP_RAM_SIZE: usize = 12 * 1024;
+
+/// A TBF app binary with no code, enough for the kernel to load a process.
+fn app_binary() -> Vec<u8> {
+    const HEADER_SIZE: u16 = 40;
+    const TOTAL_SIZE: u32 = 128;
+    let words: [u32; 10] = [
+        // version, header size
+        2 | (HEADER_SIZE as u32) << 16,
+        TOTAL_SIZE,
+        // flags: enabled
+        1,
+        // checksum, computed below
+        0,
+        // main TLV: init function offset, protected size, minimum RAM size
+        1 | 12 << 16,
+        0,
+        0,
+        APP_RAM_SIZE as u32,
+        // kernel version TLV
+        8 | 4 << 16,
+        kernel::KERNEL_MAJOR_VERSION as u32 | (kernel::KERNEL_MINOR_VERSION as u32) << 16,
+    ];
+    let mut header = words;
+    header[3] = words.iter().fold(0, |checksum, word| checksum ^ word);
+    let mut binary: Vec<u8> = header.iter().flat_map(|word| word.to_le_bytes()).collect();
+    binary.resize(TOTAL_SIZE as usize, 0);
+    binary
+}
+
+/// What one app did with the system calls the scenario queued for it.
+#[derive(Default)]
+struct AppLog {
+    syscalls: VecDeque<Syscall>,
+    returns: Vec<SyscallReturn>,
+    upcalls: Vec<FunctionCall>,
+}
+
+/// Switching to an app issues the next system call queued for it, or
+/// `yield-wait` once there is none left.
+#[derive(Default)]
+struct ScriptedUserspace {
+    apps: RefCell<Vec<AppLog>>,
+}
+
+/// The state the kernel keeps for each process: the index of its log.
+#[derive(Default)]
+struct ScriptedState {
+    app: Option<usize>,
+}
+
+impl ScriptedState {
+    fn app(&self) -> usize {
+        self.app.expect("process not initialized")
+    }
+}
+
+impl UserspaceKernelBoundary for ScriptedUserspace {
+    type StoredState = ScriptedState;
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        APP_MEMORY_SIZE
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut ScriptedState,
+    ) -> Result<(), ()> {
+        // A restarted process keeps its log, without the system calls
+        // queued for the previous instance.
+        let mut apps = self.apps.borrow_mut();
+        match state.app {
+            Some(app) => apps[app].syscalls.clear(),
+            None => {
+                state.app = Some(apps.len());
+                apps.push(AppLog::default());
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut ScriptedState,
+        return_value: SyscallReturn,
+    ) -> Result<(), ()> {
+        self.apps.borrow_mut()[state.app()]
+            .returns
+            .push(return_value);
+        Ok(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut ScriptedState,
+        upcall: FunctionCall,
+    ) -> Result<(), ()> {
+        if let FunctionCallSource::Driver(_) = upcall.source {
+            self.apps.borrow_mut()[state.app()].upcalls.push(upcall);
+        }
+        Ok(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut ScriptedState,
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        let syscall = self.apps.borrow_mut()[state.app()]
+            .syscalls
+            .pop_front()
+            .unwrap_or(Syscall::Yield {
+                which: 1,
+                address: std::ptr::null_mut(),
+            });
+        (ContextSwitchReason::SyscallFired { syscall }, None)
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &ScriptedState,
+        _writer: &mut dyn core::fmt::Write,
+    ) {
+    }
+
+    fn store_context(&self, _state: &ScriptedState, _out: &mut [u8]) -> Result<usize, ErrorCode> {
+        Ok(0)
+    }
+}
+
+/// A chip without interrupts or memory protection, running scripted apps.
+#[derive(Default)]
+struct AppChip {
+    userspace: ScriptedUserspace,
+}
+
+impl Chip for AppChip {
+    type MPU = ();
+    type UserspaceKernelBoundary = ScriptedUserspace;
+
+    fn service_pending_interrupts(&self) {}
+
+    fn has_pending_interrupts(&self) -> bool {
+        false
+    }
+
+    fn mpu(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &ScriptedUserspace {
+        &self.userspace
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
+
+    unsafe fn print_state(&self, _writer: &mut dyn core::fmt::Write) {}
+}
+
+/// Runs the first app with work to do, until it yields.
+struct FirstReady {
+    kernel: &'static Kernel,
+}
+
+impl FirstReady {
+    fn next_ready(&self) -> Option<ProcessId> {
+        let mut next = None;
+        self.kernel
+            .process_each_capability(&ProcessManagementCap, |process| {
+                if next.is_none() && process.ready() {
+                    next = Some(process.processid());
+                }
+            });
+        next
+    }
+}
+
+impl Scheduler<AppChip> for FirstReady {
+    fn next(&self) -> SchedulingDecision {
+        self.next_ready()
+            .map_or(SchedulingDecision::TrySleep, |id| {
+                SchedulingDecision::RunProcess((id, None))
+            })
+    }
+
+    fn result(&self, _result: process::StoppedExecutingReason, _execution_time_us: Option<u32>) {}
+
+    unsafe fn do_kernel_work_now(&self, _chip: &AppChip) -> bool {
+        false
+    }
+
+    unsafe fn continue_process(&self, _id: ProcessId, _chip: &AppChip) -> bool {
+        true
+    }
+}
+
+/// The drivers the apps can reach, by driver number.
+struct AppResources<'a> {
+    drivers: &'a [(usize, &'a dyn SyscallDriver)],
+    scheduler: FirstReady,
+}
+
+impl SyscallDriverLookup for AppResources<'_> {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn SyscallDriver>) -> R,
+    {
+        f(self
+            .drivers
+            .iter()
+            .find(|(num, _)| *num == driver_num)
+            .map(|(_, driver)| *driver))
+    }
+}
+
+impl KernelResources<AppChip> for AppResources<'_> {
+    type SyscallDriverLookup = Self;
+    type SyscallFilter = ();
+    type ProcessFault = ();
+    type ContextSwitchCallback = ();
+    type Scheduler = FirstReady;
+    type SchedulerTimer = ();
+    type WatchDog = ();
+
+    fn syscall_driver_lookup(&self) -> &Self {
+        self
+    }
+
+    fn syscall_filter(&self) -> &() {
+        &()
+    }
+
+    fn process_fault(&self) -> &() {
+        &()
+    }
+
+    fn context_switch_callback(&self) -> &() {
+        &()
+    }
+
+    fn scheduler(&self) -> &FirstReady {
+        &self.scheduler
+    }
+
+    fn scheduler_timer(&self) -> &() {
+        &()
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+}
+
+/// Processes run by the real kernel loop. Instead of code, each app issues
+/// the system calls the scenario queued for it, then yields until it gets
+/// an upcall. Apps are numbered from 0 in the order they were loaded.
+pub struct Apps {
+    kernel: &'static Kernel,
+    chip: &'static AppChip,
+    ids: RefCell<Vec<ProcessId>>,
+}
+
+impl Apps {
+    fn queue(&self, app: usize, syscall: Syscall) {
+        self.chip.userspace.apps.borrow_mut()[app]
+            .syscalls
+            .push_back(syscall);
+    }
+
+    fn with_process<R>(&self, app: usize, f: impl FnOnce(&dyn Process) -> R) -> R {
+        self.kernel
+            .process_map_or_external(
+                None,
+                self.ids.borrow()[app],
+                |process| Some(f(process)),
+                &ProcessManagementCap,
+            )
+            .expect("no such app")
+    }
+
+    /// Queues a subscribe to upcall `subscribe_num` of `driver_num`.
+    pub fn subscribe(&self, app: usize, driver_num: usize, subscribe_num: usize) {
+        // Upcalls must point to the app's flash.
+        let upcall_ptr = self.with_process(app, |process| {
+            process.get_addresses().flash_non_protected_start
+        });
+        self.queue(
+            app,
+            Syscall::Subscribe {
+                driver_number: driver_num,
+                subdriver_number: subscribe_num,
+                upcall_ptr: upcall_ptr as *mut (),
+                appdata: 0,
+            },
+        );
+    }
+
+    /// Queues a read-write allow of the first `len` bytes of the app's
+    /// memory to `allow_num` of `driver_num`.
+    pub fn allow_readwrite(&self, app: usize, driver_num: usize, allow_num: usize, len: usize) {
+        self.allow_readwrite_at(app, driver_num, allow_num, 0, len);
+    }
+
+    /// Queues a read-write allow of `len` bytes of the app's memory from
+    /// `offset`, to `allow_num` of `driver_num`.
+    pub fn allow_readwrite_at(
+        &self,
+        app: usize,
+        driver_num: usize,
+        allow_num: usize,
+        offset: usize,
+        len: usize,
+    ) {
+        assert!(offset + len <= APP_MEMORY_SIZE);
+        let address = self.with_process(app, |process| process.get_addresses().sram_start);
+        self.queue(
+            app,
+            Syscall::ReadWriteAllow {
+                driver_number: driver_num,
+                subdriver_number: allow_num,
+                allow_address: (address + offset) as *mut u8,
+                allow_size: len,
+            },
+        );
+    }
+
+    /// Queues a read-only allow of `len` bytes of the app's memory from
+    /// `offset`, to `allow_num` of `driver_num`.
+    pub fn allow_readonly_at(
+        &self,
+        app: usize,
+        driver_num: usize,
+        allow_num: usize,
+        offset: usize,
+        len: usize,
+    ) {
+        assert!(offset + len <= APP_MEMORY_SIZE);
+        let address = self.with_process(app, |process| process.get_addresses().sram_start);
+        self.queue(
+            app,
+            Syscall::ReadOnlyAllow {
+                driver_number: driver_num,
+                subdriver_number: allow_num,
+                allow_address: (address + offset) as *const u8,
+                allow_size: len,
+            },
+        );
+    }
+
+    /// Copies `data` into the app's memory at `offset`.
+    pub fn write_memory(&self, app: usize, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= APP_MEMORY_SIZE);
+        let address = self.with_process(app, |process| process.get_addresses().sram_start);
+        unsafe { std::slice::from_raw_parts_mut((address + offset) as *mut u8, data.len()) }
+            .copy_from_slice(data);
+    }
+
+    /// Returns the first `len` bytes of the app's memory.
+    pub fn read_memory(&self, app: usize, len: usize) -> Vec<u8> {
+        let address = self.with_process(app, |process| process.get_addresses().sram_start);
+        unsafe { std::slice::from_raw_parts(address as *const u8, len) }.to_vec()
+    }
+
+    /// Queues a command.
+    pub fn command(
+        &self,
+        app: usize,
+        driver_num: usize,
+        command_num: usize,
+        arg0: usize,
+        arg1: usize,
+    ) {
+        self.queue(
+            app,
+            Syscall::Command {
+                driver_number: driver_num,
+                subdriver_number: command_num,
+                arg0,
+                arg1,
+            },
+        );
+    }
+
+    /// Runs the apps until none of them has anything left to do.
+    pub fn run(&self, drivers: &[(usize, &dyn SyscallDriver)]) {
+        let resources = AppResources {
+            drivers,
+            scheduler: FirstReady {
+                kernel: self.kernel,
+            },
+        };
+        // Apps wait in `yield` for an upcall. A function call from the
+        // kernel wakes up those with system calls to issue.
+        for app in 0..self.ids.borrow().len() {
+            if !self.chip.userspace.apps.borrow()[app].syscalls.is_empty() {
+                self.with_process(app, |process| {
+                    let _ = process.enqueue_task(process::Task::FunctionCall(FunctionCall {
+                        source: FunctionCallSource::Kernel,
+                        argument0: 0,
+                        argument1: 0,
+                        argument2: 0,
+                        argument3: 0,
+                        pc: 0,
+                    }));
+                });
+            }
+        }
+        while resources.scheduler.next_ready().is_some() {
+            self.kernel.kernel_loop_operation::<_, _, 0>(
+                &resources,
+                self.chip,
+                None,
+                true,
+                &MainLoopCap,
+            );
+        }
+    }
+
+    /// Returns the values returned to the system calls of `app` so far, and
+    /// clears them.
+    pub fn take_returns(&self, app: usize) -> Vec<SyscallReturn> {
+        std::mem::take(&mut self.chip.userspace.apps.borrow_mut()[app].returns)
+    }
+
+    /// Returns the `(driver, subscribe number, [arguments])` of the upcalls
+    /// `app` ran so far, and clears them.
+    pub fn take_upcalls(&self, app: usize) -> Vec<(usize, usize, [usize; 3])> {
+        std::mem::take(&mut self.chip.userspace.apps.borrow_mut()[app].upcalls)
+            .into_iter()
+            .filter_map(|upcall| match upcall.source {
+                FunctionCallSource::Driver(id) => Some((
+                    id.driver_num,
+                    id.subscribe_num,
+                    [upcall.argument0, upcall.argument1, upcall.argument2],
+                )),
+                FunctionCallSource::Kernel => None,
+            })
+            .collect()
+    }
+
+    /// Terminates `app`, as if it exited.
+    pub fn terminate(&self, app: usize) {
+        self.with_process(app, |process| process.terminate(None));
+    }
+
+    /// Restarts `app`, as the fault policy does after a crash. The new
+    /// instance has the same slot, a new `ProcessId` and empty grants; the
+    /// system calls queued for the old instance are dropped.
+    pub fn restart(&self, app: usize) {
+        let processid = self.with_process(app, |process| {
+            process.try_restart(None);
+            process.processid()
+        });
+        self.ids.borrow_mut()[app] = processid;
+    }
+}
+
+/// An alarm whose time only moves forward when the scenario fires it.
+pub struct FakeAlarm<'a, F: Frequency> {
+    now: Cell<Ticks32>,
+    reference: Cell<Ticks32>,
+    dt: Cell<Ticks32>,
+    armed: Cell<bool>,
+    minimum_dt: Cell<Ticks32>,
+    refuse_alarms: Cell<bool>,
+    now_step: Cell<u32>,
+    fired: Cell<usize>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+    _frequency: PhantomData<F>,
+}
+
+impl<'a, F: Frequency> FakeAlarm<'a, F> {
+    pub fn new() -> Self {
+        Self {
+            now: Cell::new(0u32.into()),
+            reference: Cell::new(0u32.into()),
+            dt: Cell::new(0u32.into()),
+            armed: Cell::new(false),
+            minimum_dt: Cell::new(0u32.into()),
+            refuse_alarms: Cell::new(false),
+            now_step: Cell::new(0),
+            fired: Cell::new(0),
+            client: OptionalCell::empty(),
+            _frequency: PhantomData,
+        }
+    }
+
+    /// The dt of the currently armed alarm, in ticks.
+    pub fn armed_dt(&self) -> Option<u32> {
+        if self.armed.get() {
+            Some(self.dt.get().into_u32())
+        } else {
+            None
+        }
+    }
+
+    /// Makes time move forward by `ticks` every time `now()` is read, for
+    /// capsules busy-waiting on the counter.
+    pub fn set_now_step(&self, ticks: u32) {
+        self.now_step.set(ticks);
+    }
+
+    /// Makes `set_alarm()` ignore alarms shorter than `ticks`, as some
+    /// implementations do.
+    pub fn set_minimum_dt(&self, ticks: u32) {
+        self.minimum_dt.set(ticks.into());
+    }
+
+    /// Makes `set_alarm()` ignore every alarm.
+    pub fn set_refuse_alarms(&self, refuse: bool) {
+        self.refuse_alarms.set(refuse);
+    }
+
+    /// Moves time forward by `ticks`, without firing the alarm.
+    pub fn advance(&self, ticks: u32) {
+        self.now.set(self.now.get().wrapping_add(ticks.into()));
+    }
+
+    /// The number of times the alarm fired so far.
+    pub fn fired_count(&self) -> usize {
+        self.fired.get()
+    }
+
+    /// Moves time to the expiration of the armed alarm and calls the client.
+    /// Returns `false` if no alarm was armed.
+    pub fn fire(&self) -> bool {
+        if !self.armed.get() {
+            return false;
+        }
+        let dt = core::cmp::max(self.dt.get(), self.minimum_dt.get());
+        let expiration = self.reference.get().wrapping_add(dt);
+        if expiration.into_u32() > self.now.get().into_u32() {
+            self.now.set(expiration);
+        }
+        self.armed.set(false);
+        self.fired.set(self.fired.get() + 1);
+        self.client.map(|client| client.alarm());
+        true
+    }
+
+    /// Fires the alarm until nobody arms it anymore, at most `limit` times.
+    /// Returns the number of times it fired.
+    pub fn run(&self, limit: usize) -> usize {
+        let mut count = 0;
+        while count < limit && self.fire() {
+            count += 1;
+        }
+        count
+    }
+}
+
+impl<F: Frequency> time::Time for FakeAlarm<'_, F> {
+    type Frequency = F;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        let now = self.now.get();
+        self.now.set(now.wrapping_add(self.now_step.get().into()));
+        now
+    }
+}
+
+impl<'a, F: Frequency> time::Alarm<'a> for FakeAlarm<'a, F> {
+    fn set_alarm_client(&self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        if self.refuse_alarms.get() || dt < self.minimum_dt.get() {
+            return;
+        }
+        self.reference.set(reference);
+        self.dt.set(dt);
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        self.reference.get().wrapping_add(self.dt.get())
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        self.minimum_dt.get()
+    }
+}
+
+/// Every level change of a set of [`RecordingPin`]s, in order.
+#[derive(Default)]
+pub struct PinLog {
+    events: RefCell<Vec<(usize, bool)>>,
+}
+
+impl PinLog {
+    /// Returns the `(pin id, level)` events recorded so far and clears the
+    /// log.
+    pub fn take(&self) -> Vec<(usize, bool)> {
+        self.events.take()
+    }
+}
+
+/// A GPIO pin recording its level changes into a shared [`PinLog`].
+///
+/// As an input, the scenario raises its interrupt with
+/// [`RecordingPin::trigger`].
+pub struct RecordingPin<'a> {
+    id: usize,
+    level: Cell<bool>,
+    output: Cell<bool>,
+    log: &'a PinLog,
+    interrupt: Cell<Option<gpio::InterruptEdge>>,
+    client: OptionalCell<&'a dyn gpio::Client>,
+}
+
+impl<'a> RecordingPin<'a> {
+    pub fn new(id: usize, log: &'a PinLog) -> Self {
+        Self {
+            id,
+            level: Cell::new(false),
+            output: Cell::new(false),
+            log,
+            interrupt: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Calls the interrupt client if interrupts are enabled. Returns `false`
+    /// if they are not.
+    pub fn trigger(&self) -> bool {
+        if self.interrupt.get().is_none() {
+            return false;
+        }
+        self.client.map(|client| client.fired());
+        true
+    }
+
+    fn write(&self, level: bool) {
+        self.level.set(level);
+        self.log.events.borrow_mut().push((self.id, level));
+    }
+}
+
+impl gpio::Configure for RecordingPin<'_> {
+    fn configuration(&self) -> gpio::Configuration {
+        if self.output.get() {
+            gpio::Configuration::Output
+        } else {
+            gpio::Configuration::Input
+        }
+    }
+
+    fn make_output(&self) -> gpio::Configuration {
+        self.output.set(true);
+        gpio::Configuration::Output
+    }
+
+    fn disable_output(&self) -> gpio::Configuration {
+        self.output.set(false);
+        gpio::Configuration::Input
+    }
+
+    fn make_input(&self) -> gpio::Configuration {
+        self.output.set(false);
+        gpio::Configuration::Input
+    }
+
+    fn disable_input(&self) -> gpio::Configuration {
+        self.configuration()
+    }
+
+    fn deactivate_to_low_power(&self) {}
+
+    fn set_floating_state(&self, _state: gpio::FloatingState) {}
+
+    fn floating_state(&self) -> gpio::FloatingState {
+        gpio::FloatingState::PullNone
+    }
+}
+
+impl gpio::Output for RecordingPin<'_> {
+    fn set(&self) {
+        self.write(true);
+    }
+
+    fn clear(&self) {
+        self.write(false);
+    }
+
+    fn toggle(&self) -> bool {
+        self.write(!self.level.get());
+        self.level.get()
+    }
+}
+
+impl gpio::Input for RecordingPin<'_> {
+    fn read(&self) -> bool {
+        self.level.get()
+    }
+}
+
+impl<'a> gpio::Interrupt<'a> for RecordingPin<'a> {
+    fn set_client(&self, client: &'a dyn gpio::Client) {
+        self.client.set(client);
+    }
+
+    fn enable_interrupts(&self, mode: gpio::InterruptEdge) {
+        self.interrupt.set(Some(mode));
+    }
+
+    fn disable_interrupts(&self) {
+        self.interrupt.set(None);
+    }
+
+    fn is_pending(&self) -> bool {
+        false
+    }
+}
+
+/// An I2C device answering with scripted responses.
+///
+/// Every transfer stays pending until [`ScriptedI2CDevice::complete`] is
+/// called, which records the written bytes and fills the buffer with the
+/// next scripted response. A transfer can be refused with
+/// [`ScriptedI2CDevice::fail_next_start`].
+pub struct ScriptedI2CDevice<'a> {
+    client: OptionalCell<&'a dyn i2c::I2CClient>,
+    pending: TakeCell<'static, [u8]>,
+    pending_lengths: Cell<(usize, usize)>,
+    responses: RefCell<VecDeque<Result<Vec<u8>, i2c::Error>>>,
+    written: RefCell<Vec<Vec<u8>>>,
+    start_failure: Cell<Option<i2c::Error>>,
+}
+
+impl<'a> ScriptedI2CDevice<'a> {
+    pub fn new() -> Self {
+        Self {
+            client: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+            pending_lengths: Cell::new((0, 0)),
+            responses: RefCell::new(VecDeque::new()),
+            written: RefCell::new(Vec::new()),
+            start_failure: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn i2c::I2CClient) {
+        self.client.set(client);
+    }
+
+    /// Queues the result of a future transfer. Transfers without a scripted
+    /// response succeed and read zeros.
+    pub fn push_response(&self, response: Result<Vec<u8>, i2c::Error>) {
+        self.responses.borrow_mut().push_back(response);
+    }
+
+    /// Makes the next transfer fail with `error` when started, returning
+    /// the buffer.
+    pub fn fail_next_start(&self, error: i2c::Error) {
+        self.start_failure.set(Some(error));
+    }
+
+    /// Returns the bytes written by each completed transfer and clears them.
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        self.written.take()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Completes the pending transfer. Returns `false` if there was none.
+    pub fn complete(&self) -> bool {
+        let (write_len, read_len) = self.pending_lengths.get();
+        self.pending.take().map_or(false, |buffer| {
+            self.written.borrow_mut().push(buffer[..write_len].to_vec());
+            let status = match self.responses.borrow_mut().pop_front() {
+                Some(Ok(data)) => {
+                    let len = core::cmp::min(read_len, data.len());
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    Ok(())
+                }
+                Some(Err(error)) => Err(error),
+                None => {
+                    buffer[..read_len].fill(0);
+                    Ok(())
+                }
+            };
+            self.client
+                .map(move |client| client.command_complete(buffer, status));
+            true
+        })
+    }
+
+    fn start(
+        &self,
+        buffer: &'static mut [u8],
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        if self.pending.is_some() {
+            return Err((i2c::Error::Busy, buffer));
+        }
+        if let Some(error) = self.start_failure.take() {
+            return Err((error, buffer));
+        }
+        self.pending_lengths.set((write_len, read_len));
+        self.pending.replace(buffer);
+        Ok(())
+    }
+}
+
+impl i2c::I2CDevice for ScriptedI2CDevice<'_> {
+    fn enable(&self) {}
+
+    fn disable(&self) {}
+
+    fn write_read(
+        &self,
+        data: &'static mut [u8],
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.start(data, write_len, read_len)
+    }
+
+    fn write(
+        &self,
+        data: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.start(data, len, 0)
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.start(buffer, 0, len)
+    }
+}
+
+/// An SPI device replying with scripted data, in the order the transfers
+/// are started.
+///
+/// A transfer can be refused with [`ScriptedSpiDevice::fail_next_start`], or
+/// completed with an error with [`ScriptedSpiDevice::complete_with_error`].
+pub struct ScriptedSpiDevice<'a> {
+    client: OptionalCell<&'a dyn spi::SpiMasterClient>,
+    pending_write: TakeCell<'static, [u8]>,
+    pending_read: TakeCell<'static, [u8]>,
+    pending_len: Cell<usize>,
+    responses: RefCell<VecDeque<Vec<u8>>>,
+    written: RefCell<Vec<Vec<u8>>>,
+    start_failure: Cell<Option<ErrorCode>>,
+}
+
+impl<'a> ScriptedSpiDevice<'a> {
+    pub fn new() -> Self {
+        Self {
+            client: OptionalCell::empty(),
+            pending_write: TakeCell::empty(),
+            pending_read: TakeCell::empty(),
+            pending_len: Cell::new(0),
+            responses: RefCell::new(VecDeque::new()),
+            written: RefCell::new(Vec::new()),
+            start_failure: Cell::new(None),
+        }
+    }
+
+    /// Makes the next `read_write_bytes()` fail with `error`, returning the
+    /// buffers.
+    pub fn fail_next_start(&self, error: ErrorCode) {
+        self.start_failure.set(Some(error));
+    }
+
+    /// Queues the bytes read by a future transfer with a read buffer,
+    /// starting with the byte clocked in with the first byte written.
+    /// Transfers without a scripted response read zeros.
+    pub fn push_response(&self, response: Vec<u8>) {
+        self.responses.borrow_mut().push_back(response);
+    }
+
+    /// Returns the bytes written by each completed transfer and clears them.
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        self.written.take()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending_write.is_some()
+    }
+
+    /// Completes the pending transfer. Returns `false` if there was none.
+    pub fn complete(&self) -> bool {
+        self.finish(Ok(()))
+    }
+
+    /// Completes the pending transfer with `error`, without reading any
+    /// scripted response. Returns `false` if there was no transfer.
+    pub fn complete_with_error(&self, error: ErrorCode) -> bool {
+        self.finish(Err(error))
+    }
+
+    fn finish(&self, result: Result<(), ErrorCode>) -> bool {
+        let len = self.pending_len.get();
+        self.pending_write.take().map_or(false, |write_buffer| {
+            self.written.borrow_mut().push(write_buffer[..len].to_vec());
+            let read_buffer = self.pending_read.take().map(|buffer| {
+                if result.is_ok() {
+                    let response = self.responses.borrow_mut().pop_front().unwrap_or_default();
+                    buffer[..len].fill(0);
+                    let copied = core::cmp::min(len, response.len());
+                    buffer[..copied].copy_from_slice(&response[..copied]);
+                }
+                buffer
+            });
+            self.client
+                .map(move |client| client.read_write_done(write_buffer, read_buffer, len, result));
+            true
+        })
+    }
+}
+
+impl<'a> spi::SpiMasterDevice<'a> for ScriptedSpiDevice<'a> {
+    fn set_client(&self, client: &'a dyn spi::SpiMasterClient) {
+        self.client.set(client);
+    }
+
+    fn configure(
+        &self,
+        _cpol: spi::ClockPolarity,
+        _cpal: spi::ClockPhase,
+        _rate: u32,
+    ) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8], Option<&'static mut [u8]>)> {
+        if self.pending_write.is_some() {
+            return Err((ErrorCode::BUSY, write_buffer, read_buffer));
+        }
+        if let Some(error) = self.start_failure.take() {
+            return Err((error, write_buffer, read_buffer));
+        }
+        let len = read_buffer
+            .as_ref()
+            .map_or(len, |buffer| core::cmp::min(len, buffer.len()));
+        self.pending_len
+            .set(core::cmp::min(len, write_buffer.len()));
+        self.pending_write.replace(write_buffer);
+        if let Some(buffer) = read_buffer {
+            self.pending_read.replace(buffer);
+        }
+        Ok(())
+    }
+
+    fn set_rate(&self, _rate: u32) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn get_rate(&self) -> u32 {
+        1_000_000
+    }
+
+    fn set_polarity(&self, _polarity: spi::ClockPolarity) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn get_polarity(&self) -> spi::ClockPolarity {
+        spi::ClockPolarity::IdleLow
+    }
+
+    fn set_phase(&self, _phase: spi::ClockPhase) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn get_phase(&self) -> spi::ClockPhase {
+        spi::ClockPhase::SampleLeading
+    }
+}
+
+/// An ADC channel whose samples are delivered by the scenario.
+///
+/// Samples queued with [`FakeAdcChannel::complete_synchronously`] are
+/// instead delivered from within `sample()`, like a fast driver would.
+pub struct FakeAdcChannel<'a> {
+    reference_mv: Option<usize>,
+    requested: Cell<bool>,
+    starts: Cell<usize>,
+    synchronous: RefCell<VecDeque<u16>>,
+    client: OptionalCell<&'a dyn adc::Client>,
+}
+
+impl<'a> FakeAdcChannel<'a> {
+    pub fn new(reference_mv: Option<usize>) -> Self {
+        Self {
+            reference_mv,
+            requested: Cell::new(false),
+            starts: Cell::new(0),
+            synchronous: RefCell::new(VecDeque::new()),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.get()
+    }
+
+    /// The number of samples requested so far.
+    pub fn starts(&self) -> usize {
+        self.starts.get()
+    }
+
+    /// Queues samples delivered from within the next `sample()` calls.
+    pub fn complete_synchronously(&self, samples: &[u16]) {
+        self.synchronous.borrow_mut().extend(samples);
+    }
+
+    /// Completes the requested sample. Returns `false` if none was
+    /// requested.
+    pub fn deliver(&self, sample: u16) -> bool {
+        if !self.requested.replace(false) {
+            return false;
+        }
+        self.client.map(|client| client.sample_ready(sample));
+        true
+    }
+}
+
+impl<'a> adc::AdcChannel<'a> for FakeAdcChannel<'a> {
+    fn sample(&self) -> Result<(), ErrorCode> {
+        if self.requested.replace(true) {
+            return Err(ErrorCode::BUSY);
+        }
+        self.starts.set(self.starts.get() + 1);
+        let sample = self.synchronous.borrow_mut().pop_front();
+        if let Some(sample) = sample {
+            self.deliver(sample);
+        }
+        Ok(())
+    }
+
+    fn sample_continuous(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn stop_sampling(&self) -> Result<(), ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn get_resolution_bits(&self) -> usize {
+        16
+    }
+
+    fn get_voltage_reference_mv(&self) -> Option<usize> {
+        self.reference_mv
+    }
+
+    fn set_client(&self, client: &'a dyn adc::Client) {
+        self.client.set(client);
+    }
+}
+
+/// A temperature sensor whose readings are delivered by the scenario.
+pub struct FakeTemperature<'a> {
+    requested: Cell<bool>,
+    reads: Cell<usize>,
+    client: OptionalCell<&'a dyn sensors::TemperatureClient>,
+}
+
+impl<'a> FakeTemperature<'a> {
+    pub fn new() -> Self {
+        Self {
+            requested: Cell::new(false),
+            reads: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.get()
+    }
+
+    /// The number of readings started so far.
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    /// Completes the requested reading, in hundredths of a degree. Returns
+    /// `false` if none was requested.
+    pub fn deliver(&self, value: Result<i32, ErrorCode>) -> bool {
+        if !self.requested.replace(false) {
+            return false;
+        }
+        self.client.map(|client| client.callback(value));
+        true
+    }
+}
+
+impl<'a> sensors::TemperatureDriver<'a> for FakeTemperature<'a> {
+    fn set_client(&self, client: &'a dyn sensors::TemperatureClient) {
+        self.client.set(client);
+    }
+
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        if self.requested.replace(true) {
+            return Err(ErrorCode::BUSY);
+        }
+        self.reads.set(self.reads.get() + 1);
+        Ok(())
+    }
+}
+
+/// A high speed ADC whose buffers are filled by the scenario. Channels are
+/// plain indices.
+pub struct FakeHighSpeedAdc<'a> {
+    /// Buffers given to the ADC, with the number of samples to collect in
+    /// each.
+    buffers: RefCell<VecDeque<(&'static mut [u16], usize)>>,
+    sampling: Cell<bool>,
+    next_sample: Cell<u16>,
+    client: OptionalCell<&'a dyn adc::Client>,
+    highspeed_client: OptionalCell<&'a dyn adc::HighSpeedClient>,
+    /// Channels configured, with their configuration words, in order.
+    configured: RefCell<Vec<(usize, u32)>>,
+}
+
+impl<'a> FakeHighSpeedAdc<'a> {
+    pub fn new() -> Self {
+        Self {
+            buffers: RefCell::new(VecDeque::new()),
+            sampling: Cell::new(false),
+            next_sample: Cell::new(1),
+            client: OptionalCell::empty(),
+            highspeed_client: OptionalCell::empty(),
+            configured: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn is_sampling(&self) -> bool {
+        self.sampling.get()
+    }
+
+    /// Takes the channels configured so far, with their configuration words.
+    pub fn take_configured(&self) -> Vec<(usize, u32)> {
+        self.configured.take()
+    }
+
+    /// Fills the next buffer with consecutive samples, starting at 1 and
+    /// continuing across buffers, and hands it to the client. Returns the
+    /// number of samples, or `None` if the ADC holds no buffer.
+    pub fn fill_buffer(&self) -> Option<usize> {
+        if !self.sampling.get() {
+            return None;
+        }
+        let (buffer, length) = self.buffers.borrow_mut().pop_front()?;
+        for sample in buffer[..length].iter_mut() {
+            *sample = self.next_sample.get();
+            self.next_sample.set(self.next_sample.get() + 1);
+        }
+        self.highspeed_client
+            .map(|client| client.samples_ready(buffer, length, Ok(())));
+        Some(length)
+    }
+
+    /// Hands the next buffer back to the client without samples, as an ADC
+    /// failing with `error`. Returns `false` if the ADC holds no buffer.
+    pub fn fail_buffer(&self, error: ErrorCode) -> bool {
+        if !self.sampling.get() {
+            return false;
+        }
+        let Some((buffer, _)) = self.buffers.borrow_mut().pop_front() else {
+            return false;
+        };
+        self.highspeed_client
+            .map(|client| client.samples_ready(buffer, 0, Err(error)));
+        true
+    }
+}
+
+impl<'a> adc::Adc<'a> for FakeHighSpeedAdc<'a> {
+    type Channel = usize;
+
+    fn sample(&self, _channel: &usize) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn sample_continuous(&self, _channel: &usize, _frequency: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn stop_sampling(&self) -> Result<(), ErrorCode> {
+        self.sampling.set(false);
+        Ok(())
+    }
+
+    fn get_resolution_bits(&self) -> usize {
+        16
+    }
+
+    fn get_voltage_reference_mv(&self) -> Option<usize> {
+        None
+    }
+
+    fn set_client(&self, client: &'a dyn adc::Client) {
+        self.client.set(client);
+    }
+}
+
+/// Configuration words with the top bit set are not supported.
+impl adc::AdcChannelConfig for FakeHighSpeedAdc<'_> {
+    type Channel = usize;
+
+    fn configure_channel(&self, channel: &usize, config: u32) -> Result<(), ErrorCode> {
+        if config & 0x8000_0000 != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.sampling.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.configured.borrow_mut().push((*channel, config));
+        Ok(())
+    }
+}
+
+impl<'a> adc::AdcHighSpeed<'a> for FakeHighSpeedAdc<'a> {
+    fn sample_highspeed(
+        &self,
+        _channel: &usize,
+        _frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        if self.sampling.get() {
+            return Err((ErrorCode::BUSY, buffer1, buffer2));
+        }
+        self.sampling.set(true);
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.push_back((buffer1, length1));
+        buffers.push_back((buffer2, length2));
+        Ok(())
+    }
+
+    fn provide_buffer(
+        &self,
+        buf: &'static mut [u16],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16])> {
+        if !self.sampling.get() {
+            return Err((ErrorCode::OFF, buf));
+        }
+        self.buffers.borrow_mut().push_back((buf, length));
+        Ok(())
+    }
+
+    fn retrieve_buffers(
+        &self,
+    ) -> Result<(Option<&'static mut [u16]>, Option<&'static mut [u16]>), ErrorCode> {
+        if self.sampling.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let mut buffers = self.buffers.borrow_mut();
+        let first = buffers.pop_front().map(|(buffer, _)| buffer);
+        let second = buffers.pop_front().map(|(buffer, _)| buffer);
+        Ok((first, second))
+    }
+
+    fn set_highspeed_client(&self, client: &'a dyn adc::HighSpeedClient) {
+        self.highspeed_client.set(client);
+    }
+}
+
+/// A UART for consoles: the scenario types into it and reads what was
+/// transmitted.
+pub struct ConsoleUart<'a> {
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    tx_pending: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    rx_pending: TakeCell<'static, [u8]>,
+    output: RefCell<Vec<u8>>,
+}
+
+impl<'a> ConsoleUart<'a> {
+    pub fn new() -> Self {
+        Self {
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_pending: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            rx_pending: TakeCell::empty(),
+            output: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Completes transmissions until there are none left.
+    pub fn flush(&self) {
+        while let Some(buffer) = self.tx_pending.take() {
+            let len = self.tx_len.get();
+            self.output.borrow_mut().extend_from_slice(&buffer[..len]);
+            self.tx_client
+                .map(move |client| client.transmitted_buffer(buffer, len, Ok(())));
+        }
+    }
+
+    /// Types `text` one byte at a time, flushing the output after each.
+    pub fn type_text(&self, text: &str) {
+        for &byte in text.as_bytes() {
+            let buffer = self
+                .rx_pending
+                .take()
+                .expect("nobody is receiving from the UART");
+            buffer[0] = byte;
+            self.rx_client
+                .map(move |client| client.received_buffer(buffer, 1, Ok(()), uart::Error::None));
+            self.flush();
+        }
+    }
+
+    /// Returns what was transmitted so far as text, and clears it.
+    pub fn take_output(&self) -> String {
+        String::from_utf8_lossy(&self.output.take()).into_owned()
+    }
+}
+
+impl<'a> uart::Transmit<'a> for ConsoleUart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_pending.is_some() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        self.tx_len.set(tx_len);
+        self.tx_pending.replace(tx_buffer);
+        Ok(())
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a> uart::Receive<'a> for ConsoleUart<'a> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        _rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_pending.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        self.rx_pending.replace(rx_buffer);
+        Ok(())
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum StorageOperation {
+    Read { address: usize, length: usize },
+    Write { address: usize, length: usize },
+    Erase { address: usize, length: usize },
+}
+
+/// A RAM backed physical storage driver. Erasing sets bytes to zero.
+///
+/// Operations stay pending until [`RamStorage::complete`] is called. A
+/// failure can be injected with [`RamStorage::fail_next`].
+pub struct RamStorage<'a> {
+    memory: RefCell<Vec<u8>>,
+    client: OptionalCell<&'a dyn nonvolatile_storage::NonvolatileStorageClient>,
+    pending: TakeCell<'static, [u8]>,
+    operation: Cell<Option<StorageOperation>>,
+    failure: Cell<Option<ErrorCode>>,
+}
+
+impl<'a> RamStorage<'a> {
+    pub fn new(size: usize) -> Self {
+        Self {
+            memory: RefCell::new(vec![0xff; size]),
+            client: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+            operation: Cell::new(None),
+            failure: Cell::new(None),
+        }
+    }
+
+    /// Makes the next completed operation fail with `error` without
+    /// transferring any bytes.
+    pub fn fail_next(&self, error: ErrorCode) {
+        self.failure.set(Some(error));
+    }
+
+    /// Returns a copy of `length` bytes of the storage at `address`.
+    pub fn contents(&self, address: usize, length: usize) -> Vec<u8> {
+        self.memory.borrow()[address..address + length].to_vec()
+    }
+
+    /// Sets the storage at `address` to `data`, as if it was written before
+    /// the scenario started.
+    pub fn set_contents(&self, address: usize, data: &[u8]) {
+        self.memory.borrow_mut()[address..address + data.len()].copy_from_slice(data);
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.operation.get().is_some()
+    }
+
+    /// Completes the pending operation. Returns `false` if there was none.
+    pub fn complete(&self) -> bool {
+        let operation = self.operation.take();
+        let failure = self.failure.take();
+        if let Some(StorageOperation::Erase { address, length }) = operation {
+            let length = match failure {
+                Some(_) => 0,
+                None => {
+                    self.memory.borrow_mut()[address..address + length].fill(0);
+                    length
+                }
+            };
+            let result = failure.map_or(Ok(()), Err);
+            self.client.map(|client| client.erase_done(length, result));
+            return true;
+        }
+        match (self.pending.take(), operation, failure) {
+            (Some(buffer), Some(StorageOperation::Read { .. }), Some(error)) => {
+                self.client
+                    .map(move |client| client.read_done(buffer, 0, Err(error)));
+                true
+            }
+            (Some(buffer), Some(StorageOperation::Write { .. }), Some(error)) => {
+                self.client
+                    .map(move |client| client.write_done(buffer, 0, Err(error)));
+                true
+            }
+            (Some(buffer), Some(StorageOperation::Read { address, length }), None) => {
+                buffer[..length].copy_from_slice(&self.memory.borrow()[address..address + length]);
+                self.client
+                    .map(move |client| client.read_done(buffer, length, Ok(())));
+                true
+            }
+            (Some(buffer), Some(StorageOperation::Write { address, length }), None) => {
+                self.memory.borrow_mut()[address..address + length]
+                    .copy_from_slice(&buffer[..length]);
+                self.client
+                    .map(move |client| client.write_done(buffer, length, Ok(())));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn start(
+        &self,
+        buffer: &'static mut [u8],
+        operation: StorageOperation,
+    ) -> Result<(), ErrorCode> {
+        let (address, length) = match operation {
+            StorageOperation::Read { address, length } => (address, length),
+            StorageOperation::Write { address, length } => (address, length),
+            StorageOperation::Erase { .. } => return Err(ErrorCode::INVAL),
+        };
+        if self.is_pending() {
+            return Err(ErrorCode::BUSY);
+        }
+        if length > buffer.len() || address + length > self.memory.borrow().len() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.operation.set(Some(operation));
+        self.pending.replace(buffer);
+        Ok(())
+    }
+}
+
+impl<'a> nonvolatile_storage::NonvolatileStorage<'a> for RamStorage<'a> {
+    fn set_client(&self, client: &'a dyn nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.start(buffer, StorageOperation::Read { address, length })
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.start(buffer, StorageOperation::Write { address, length })
+    }
+
+    fn erase(&self, address: usize, length: usize) -> Result<(), ErrorCode> {
+        if self.is_pending() {
+            return Err(ErrorCode::BUSY);
+        }
+        if address + length > self.memory.borrow().len() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.operation
+            .set(Some(StorageOperation::Erase { address, length }));
+        Ok(())
+    }
+}
+
+/// An entropy source delivering the bytes the scenario hands to it.
+pub struct DeterministicEntropy8<'a> {
+    client: OptionalCell<&'a dyn entropy::Client8>,
+    requested: Cell<bool>,
+}
+
+impl<'a> DeterministicEntropy8<'a> {
+    pub fn new() -> Self {
+        Self {
+            client: OptionalCell::empty(),
+            requested: Cell::new(false),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.get()
+    }
+
+    /// Delivers `bytes` to the client if entropy was requested, keeps the
+    /// request active if the client asks for more, and returns how many
+    /// bytes the client took.
+    pub fn deliver(&self, bytes: &[u8], error: Result<(), ErrorCode>) -> usize {
+        if !self.requested.get() {
+            return 0;
+        }
+        let mut iter = bytes.iter().copied();
+        let more = self.client.map_or(false, |client| {
+            client.entropy_available(&mut iter, error) == entropy::Continue::More
+        });
+        self.requested.set(more);
+        bytes.len() - iter.len()
+    }
+}
+
+impl<'a> entropy::Entropy8<'a> for DeterministicEntropy8<'a> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        self.requested.set(true);
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn entropy::Client8) {
+        self.client.set(client);
+    }
+}
+
+/// An entropy source delivering the words the scenario hands to it.
+pub struct DeterministicEntropy32<'a> {
+    client: OptionalCell<&'a dyn entropy::Client32>,
+    requested: Cell<bool>,
+    get_error: OptionalCell<ErrorCode>,
+}
+
+impl<'a> DeterministicEntropy32<'a> {
+    pub fn new() -> Self {
+        Self {
+            client: OptionalCell::empty(),
+            requested: Cell::new(false),
+            get_error: OptionalCell::empty(),
+        }
+    }
+
+    /// Makes the next `get()` fail with `error`.
+    pub fn fail_next_get(&self, error: ErrorCode) {
+        self.get_error.set(error);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.get()
+    }
+
+    /// Delivers `words` to the client if entropy was requested, and keeps
+    /// the request active if the client asks for more.
+    pub fn deliver(&self, words: &[u32], error: Result<(), ErrorCode>) {
+        if !self.requested.get() {
+            return;
+        }
+        let mut iter = words.iter().copied();
+        let more = self.client.map_or(false, |client| {
+            client.entropy_available(&mut iter, error) == entropy::Continue::More
+        });
+        self.requested.set(more);
+    }
+}
+
+impl<'a> entropy::Entropy32<'a> for DeterministicEntropy32<'a> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        if let Some(error) = self.get_error.take() {
+            return Err(error);
+        }
+        self.requested.set(true);
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn entropy::Client32) {
+        self.client.set(client);
+    }
+}
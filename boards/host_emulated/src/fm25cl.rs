@@ -0,0 +1,320 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! FM25CL FRAM over a scripted SPI device.
+
+use std::cell::RefCell;
+
+use capsules_extra::fm25cl::{FM25CLClient, FM25CLCustom, ProtectRange, FM25CL};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::spi::SpiMasterDevice;
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, ScriptedSpiDevice};
+
+type Fram = FM25CL<'static, ScriptedSpiDevice<'static>>;
+
+const WREN: u8 = 0x06;
+const WRSR: u8 = 0x01;
+const RDSR: u8 = 0x05;
+const WRITE: u8 = 0x02;
+const READ: u8 = 0x03;
+
+#[derive(Default)]
+struct Client {
+    status: RefCell<Vec<u8>>,
+    protection_set: RefCell<Vec<Result<(), ErrorCode>>>,
+    protection: RefCell<Vec<Result<ProtectRange, ErrorCode>>>,
+    erased: RefCell<Vec<(usize, Result<(), ErrorCode>)>>,
+    /// The bytes read, or the length written, with the result.
+    read: RefCell<Vec<(Vec<u8>, Result<(), ErrorCode>)>>,
+    written: RefCell<Vec<(usize, Result<(), ErrorCode>)>>,
+}
+
+impl FM25CLClient for Client {
+    fn status(&self, status: u8) {
+        self.status.borrow_mut().push(status);
+    }
+    fn read(&self, _data: &'static mut [u8], _len: usize) {}
+    fn done(&self, _buffer: &'static mut [u8]) {}
+
+    fn protection_set(&self, result: Result<(), ErrorCode>) {
+        self.protection_set.borrow_mut().push(result);
+    }
+
+    fn protection(&self, range: Result<ProtectRange, ErrorCode>) {
+        self.protection.borrow_mut().push(range);
+    }
+}
+
+impl NonvolatileStorageClient for Client {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.read
+            .borrow_mut()
+            .push((buffer[..length].to_vec(), result));
+    }
+
+    fn write_done(&self, _buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.written.borrow_mut().push((length, result));
+    }
+
+    fn erase_done(&self, length: usize, result: Result<(), ErrorCode>) {
+        self.erased.borrow_mut().push((length, result));
+    }
+}
+
+/// Sets up the driver with 16 byte buffers, so transfers carry 13 bytes of
+/// data at a time.
+fn setup() -> (
+    &'static ScriptedSpiDevice<'static>,
+    &'static Fram,
+    &'static Client,
+) {
+    let spi = leak(ScriptedSpiDevice::new());
+    let fram = leak(FM25CL::new(spi, leak_buffer(16), leak_buffer(16)));
+    spi.set_client(fram);
+    let client = leak(Client::default());
+    fram.set_client(client);
+    NonvolatileStorage::set_client(fram, client);
+    (spi, fram, client)
+}
+
+#[test]
+fn write_protection_enables_writes_first() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(fram.set_write_protection(ProtectRange::UpperHalf), Ok(()));
+    assert!(spi.complete());
+    assert!(spi.complete());
+    assert!(!spi.is_pending());
+    assert_eq!(spi.take_written(), vec![vec![WREN], vec![WRSR, 0b1000]]);
+    assert_eq!(client.protection_set.take(), vec![Ok(())]);
+}
+
+#[test]
+fn protection_is_read_from_the_status_register() {
+    let (spi, fram, client) = setup();
+
+    spi.push_response(vec![0, 0b1000_0100]);
+    assert_eq!(fram.get_protection(), Ok(()));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![RDSR, 0]]);
+    assert_eq!(
+        client.protection.take(),
+        vec![Ok(ProtectRange::UpperQuarter)]
+    );
+
+    assert_eq!(fram.get_protection(), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert_eq!(client.protection.take(), vec![Err(ErrorCode::FAIL)]);
+}
+
+#[test]
+fn status_register_is_read() {
+    let (spi, fram, client) = setup();
+
+    // WEL and BP1 set.
+    spi.push_response(vec![0, 0b1000_1010, 0, 0]);
+    assert_eq!(fram.read_status(), Ok(()));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written()[0][0], RDSR);
+    assert_eq!(client.status.take(), vec![0b1000_1010]);
+
+    // A failed transfer delivers nothing, and one that cannot start is
+    // reported right away.
+    assert_eq!(fram.read_status(), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    spi.fail_next_start(ErrorCode::OFF);
+    assert_eq!(fram.read_status(), Err(ErrorCode::OFF));
+    assert!(client.status.take().is_empty());
+
+    assert_eq!(fram.read_status(), Ok(()));
+    assert!(spi.is_pending());
+}
+
+#[test]
+fn erase_writes_zeros_in_chunks() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(NonvolatileStorage::erase(fram, 0x100, 20), Ok(()));
+    while spi.complete() {}
+    let mut first = vec![WRITE, 0x01, 0x00];
+    first.extend_from_slice(&[0; 13]);
+    let mut second = vec![WRITE, 0x01, 0x0d];
+    second.extend_from_slice(&[0; 7]);
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![WREN], first, vec![WREN], second]
+    );
+    assert_eq!(client.erased.take(), vec![(20, Ok(()))]);
+}
+
+#[test]
+fn erase_failure_reports_the_bytes_erased() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(NonvolatileStorage::erase(fram, 0, 20), Ok(()));
+    assert!(spi.complete());
+    assert!(spi.complete());
+    assert!(spi.complete());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert!(!spi.is_pending());
+    assert_eq!(client.erased.take(), vec![(13, Err(ErrorCode::FAIL))]);
+
+    // The driver is idle again.
+    assert_eq!(fram.get_protection(), Ok(()));
+}
+
+fn data(len: usize) -> &'static mut [u8] {
+    let buffer = leak_buffer(len);
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = (i as u8).wrapping_add(1);
+    }
+    buffer
+}
+
+#[test]
+fn write_is_split_into_chunks() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(NonvolatileStorage::write(fram, data(24), 0x200, 20), Ok(()));
+    while spi.complete() {}
+    let mut first = vec![WRITE, 0x02, 0x00];
+    first.extend(1..=13);
+    let mut second = vec![WRITE, 0x02, 0x0d];
+    second.extend(14..=20);
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![WREN], first, vec![WREN], second]
+    );
+    assert_eq!(client.written.take(), vec![(20, Ok(()))]);
+}
+
+#[test]
+fn read_is_split_into_chunks() {
+    let (spi, fram, client) = setup();
+
+    let mut first = vec![0; 3];
+    first.extend(1..=13);
+    let mut second = vec![0; 3];
+    second.extend(14..=20);
+    spi.push_response(first);
+    spi.push_response(second);
+    assert_eq!(
+        NonvolatileStorage::read(fram, leak_buffer(20), 0x1fe0, 20),
+        Ok(())
+    );
+    assert_eq!(
+        NonvolatileStorage::write(fram, data(4), 0, 4),
+        Err(ErrorCode::BUSY)
+    );
+    while spi.complete() {}
+    // Only the opcode and the address matter in a read.
+    assert_eq!(
+        spi.take_written()
+            .iter()
+            .map(|transfer| (transfer[..3].to_vec(), transfer.len()))
+            .collect::<Vec<_>>(),
+        vec![(vec![READ, 0x1f, 0xe0], 16), (vec![READ, 0x1f, 0xed], 10)]
+    );
+    assert_eq!(client.read.take(), vec![((1..=20).collect(), Ok(()))]);
+}
+
+#[test]
+fn failures_report_the_bytes_transferred() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(NonvolatileStorage::write(fram, data(20), 0, 20), Ok(()));
+    assert!(spi.complete());
+    assert!(spi.complete());
+    assert!(spi.complete());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert!(!spi.is_pending());
+    assert_eq!(client.written.take(), vec![(13, Err(ErrorCode::FAIL))]);
+
+    spi.push_response(vec![0, 0, 0, 0xaa, 0xbb]);
+    assert_eq!(
+        NonvolatileStorage::read(fram, leak_buffer(20), 0, 20),
+        Ok(())
+    );
+    assert!(spi.complete());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert!(!spi.is_pending());
+    let mut read = vec![0xaa, 0xbb];
+    read.resize(13, 0);
+    assert_eq!(client.read.take(), vec![(read, Err(ErrorCode::FAIL))]);
+
+    // The driver is idle again.
+    assert_eq!(fram.get_protection(), Ok(()));
+}
+
+#[test]
+fn transfers_are_bounded() {
+    let (spi, fram, _client) = setup();
+
+    fram.set_capacity(0x800);
+    assert_eq!(
+        NonvolatileStorage::read(fram, leak_buffer(4), 0x7fe, 4),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(
+        NonvolatileStorage::write(fram, data(4), 0x1_0000, 4),
+        Err(ErrorCode::INVAL)
+    );
+    // The buffer must hold the data.
+    assert_eq!(
+        NonvolatileStorage::write(fram, data(4), 0, 5),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(
+        NonvolatileStorage::read(fram, leak_buffer(4), 0, 0),
+        Err(ErrorCode::INVAL)
+    );
+    assert!(!spi.is_pending());
+
+    assert_eq!(
+        NonvolatileStorage::write(fram, data(0x800), 0, 0x800),
+        Ok(())
+    );
+    let mut transfers = 0;
+    while spi.complete() {
+        transfers += 1;
+    }
+    // 0x800 bytes are 158 chunks of 13 bytes, each after a WREN.
+    assert_eq!(transfers, 2 * 158);
+}
+
+#[test]
+fn erase_is_bounded_by_the_capacity() {
+    let (spi, fram, _client) = setup();
+
+    fram.set_capacity(0x800);
+    assert_eq!(
+        NonvolatileStorage::erase(fram, 0x7ff, 2),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(NonvolatileStorage::erase(fram, 0, 0), Err(ErrorCode::INVAL));
+    assert!(!spi.is_pending());
+
+    assert_eq!(fram.erase_all(), Ok(()));
+    assert!(spi.is_pending());
+}
+
+#[test]
+fn requests_are_refused_while_busy() {
+    let (spi, fram, client) = setup();
+
+    assert_eq!(fram.set_write_protection(ProtectRange::All), Ok(()));
+    assert_eq!(fram.get_protection(), Err(ErrorCode::BUSY));
+    assert_eq!(fram.erase_all(), Err(ErrorCode::BUSY));
+    assert_eq!(fram.read_status(), Err(ErrorCode::BUSY));
+    assert_eq!(
+        NonvolatileStorage::read(fram, leak_buffer(4), 0, 4),
+        Err(ErrorCode::BUSY)
+    );
+
+    while spi.complete() {}
+    assert_eq!(client.protection_set.take(), vec![Ok(())]);
+    assert_eq!(fram.get_protection(), Ok(()));
+}
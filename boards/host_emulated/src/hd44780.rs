@@ -0,0 +1,599 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! HD44780 LCD over recording GPIO pins.
+
+use std::cell::RefCell;
+
+use capsules_extra::hd44780::{row_offsets, EntryDirection, PulseMode, HD44780};
+use kernel::deferred_call::DeferredCallClient;
+use kernel::hil::text_screen::{TextScreen, TextScreenClient};
+use kernel::hil::time::{Alarm, Freq1KHz, Freq1MHz, Freq32KHz, Frequency, Ticks, Time};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, take_deferred_calls, FakeAlarm, PinLog, RecordingPin};
+
+const RS: usize = 0;
+const EN: usize = 1;
+const D4: usize = 2;
+
+type Lcd<F> = HD44780<'static, FakeAlarm<'static, F>>;
+
+#[derive(Default)]
+struct Client {
+    events: RefCell<Vec<Result<(), ErrorCode>>>,
+    written: RefCell<Vec<usize>>,
+}
+
+impl TextScreenClient for Client {
+    fn command_complete(&self, r: Result<(), ErrorCode>) {
+        self.events.borrow_mut().push(r);
+    }
+
+    fn write_complete(&self, _buffer: &'static mut [u8], len: usize, r: Result<(), ErrorCode>) {
+        self.events.borrow_mut().push(r);
+        self.written.borrow_mut().push(len);
+    }
+}
+
+struct Fixture<F: Frequency + 'static> {
+    lcd: &'static Lcd<F>,
+    alarm: &'static FakeAlarm<'static, F>,
+    pins: &'static PinLog,
+    client: &'static Client,
+}
+
+fn setup<F: Frequency>() -> Fixture<F> {
+    setup_with_entry::<F>(EntryDirection::LeftToRight, false)
+}
+
+fn setup_with_entry<F: Frequency>(direction: EntryDirection, shift: bool) -> Fixture<F> {
+    setup_with_geometry::<F>(16, 2, direction, shift)
+}
+
+fn setup_with_geometry<F: Frequency>(
+    width: u8,
+    height: u8,
+    direction: EntryDirection,
+    shift: bool,
+) -> Fixture<F> {
+    take_deferred_calls();
+    let pins = leak(PinLog::default());
+    let pin = |id| leak(RecordingPin::new(id, pins));
+    let alarm = leak(FakeAlarm::new());
+    let lcd = leak(HD44780::new(
+        pin(RS),
+        pin(EN),
+        pin(D4),
+        pin(D4 + 1),
+        pin(D4 + 2),
+        pin(D4 + 3),
+        leak_buffer(4),
+        alarm,
+        width,
+        height,
+        direction,
+        shift,
+    ));
+    alarm.set_alarm_client(lcd);
+    // Let time pass while the LCD busy-waits on the counter.
+    alarm.set_now_step(1);
+    let client = leak(Client::default());
+    lcd.set_client(Some(client));
+    Fixture {
+        lcd,
+        alarm,
+        pins,
+        client,
+    }
+}
+
+/// Decodes the `(rs, nibble)` pairs latched by the LCD on each falling edge
+/// of the enable pin.
+fn latched_nibbles(events: &[(usize, bool)]) -> Vec<(bool, u8)> {
+    let mut levels = [false; 6];
+    let mut nibbles = Vec::new();
+    for &(pin, level) in events {
+        if pin == EN && levels[EN] && !level {
+            let nibble = (0..4).fold(0, |n, bit| n | ((levels[D4 + bit] as u8) << bit));
+            nibbles.push((levels[RS], nibble));
+        }
+        levels[pin] = level;
+    }
+    nibbles
+}
+
+/// Pairs nibbles into the bytes sent in 4-bit mode.
+fn bytes(nibbles: &[(bool, u8)]) -> Vec<(bool, u8)> {
+    nibbles
+        .chunks(2)
+        .map(|pair| (pair[0].0, (pair[0].1 << 4) | pair[1].1))
+        .collect()
+}
+
+fn initialize<F: Frequency>(fixture: &Fixture<F>) {
+    fixture.lcd.display_on().unwrap();
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    fixture.client.events.take();
+}
+
+#[test]
+fn initialization_sequence() {
+    let fixture = setup::<Freq1MHz>();
+    assert_eq!(fixture.lcd.display_on(), Ok(()));
+    fixture.alarm.run(1000);
+
+    assert_eq!(fixture.client.events.take(), vec![Ok(())]);
+    let nibbles = latched_nibbles(&fixture.pins.take());
+    // Switch to 4-bit mode, one nibble at a time.
+    assert_eq!(
+        &nibbles[..4],
+        &[(false, 0x3), (false, 0x3), (false, 0x3), (false, 0x2)]
+    );
+    // Function set, display control, clear and entry mode.
+    assert_eq!(
+        bytes(&nibbles[4..]),
+        vec![(false, 0x28), (false, 0x0e), (false, 0x01), (false, 0x06)]
+    );
+}
+
+#[test]
+fn print_sequence() {
+    let fixture = setup::<Freq1MHz>();
+    initialize(&fixture);
+
+    let buffer = leak_buffer(16);
+    buffer[..5].copy_from_slice(b"Hello");
+    assert!(fixture.lcd.print(buffer, 5).is_ok());
+    // The LCD is busy until the whole string is printed.
+    assert_eq!(fixture.lcd.clear(), Err(ErrorCode::BUSY));
+    fixture.alarm.run(1000);
+
+    assert_eq!(fixture.client.written.take(), vec![5]);
+    assert_eq!(fixture.client.events.take(), vec![Ok(())]);
+    let data: Vec<u8> = bytes(&latched_nibbles(&fixture.pins.take()))
+        .into_iter()
+        .map(|(rs, byte)| {
+            assert!(rs, "characters are sent as data");
+            byte
+        })
+        .collect();
+    assert_eq!(data, b"Hello");
+}
+
+#[test]
+fn cursor_and_shift_commands() {
+    let fixture = setup::<Freq1MHz>();
+    initialize(&fixture);
+
+    assert_eq!(fixture.lcd.set_cursor(3, 1), Ok(()));
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.home(), Ok(()));
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.scroll_display_left(), Ok(()));
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.scroll_display_right(), Ok(()));
+    fixture.alarm.run(1000);
+
+    assert_eq!(fixture.client.events.take(), vec![Ok(()); 4]);
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![
+            (false, 0x80 | 0x43),
+            (false, 0x02),
+            (false, 0x18),
+            (false, 0x1c)
+        ]
+    );
+}
+
+/// Fires the alarm until the LCD stops arming it, and returns the delays it
+/// was armed with.
+fn armed_delays<F: Frequency>(fixture: &Fixture<F>) -> Vec<u32> {
+    let mut delays = Vec::new();
+    while let Some(dt) = fixture.alarm.armed_dt() {
+        delays.push(dt);
+        fixture.alarm.fire();
+    }
+    delays
+}
+
+/// Runs the initialization sequence and returns the dt of every alarm the
+/// LCD armed.
+fn initialization_delays<F: Frequency>(fixture: &Fixture<F>) -> Vec<u32> {
+    fixture.lcd.display_on().unwrap();
+    armed_delays(fixture)
+}
+
+#[test]
+fn delays_follow_alarm_frequency() {
+    let fast_fixture = setup::<Freq1MHz>();
+    // 32 kHz alarms time the enable pulses too.
+    fast_fixture.lcd.set_pulse_mode(PulseMode::Alarm);
+    let fast = initialization_delays(&fast_fixture);
+    let slow = initialization_delays(&setup::<Freq32KHz>());
+    assert_eq!(fast.len(), slow.len());
+    // The power on delay is 50 ms.
+    assert_eq!(fast[0], 50_000);
+    assert_eq!(slow[0], 1_639);
+    for (fast, slow) in fast.iter().zip(slow.iter()) {
+        // No delay is shorter than requested, even at 32 kHz.
+        assert!(*slow as u64 * 1_000_000 >= *fast as u64 * 32_768);
+    }
+}
+
+#[test]
+fn initialization_delays_match_datasheet() {
+    let fixture = setup::<Freq1MHz>();
+    fixture.lcd.set_pulse_mode(PulseMode::Alarm);
+    let delays = initialization_delays(&fixture);
+    // Each nibble is latched by a 1 us enable pulse followed by 37 us of
+    // execution time.
+    let pulse = [1, 1, 37];
+    let mut expected = vec![50_000];
+    expected.extend(pulse);
+    expected.push(4_500);
+    expected.extend(pulse);
+    expected.push(150);
+    expected.extend(pulse);
+    expected.push(150);
+    expected.extend(pulse);
+    assert_eq!(&delays[..expected.len()], &expected[..]);
+    // The clear command needs the long delay.
+    assert!(delays.contains(&2_000));
+}
+
+#[test]
+fn slow_alarm_initializes_and_prints() {
+    let fixture = setup::<Freq1KHz>();
+    assert_eq!(initialization_delays(&fixture).iter().min(), Some(&1));
+    assert_eq!(fixture.client.events.take(), vec![Ok(())]);
+    fixture.pins.take();
+
+    let buffer = leak_buffer(16);
+    buffer[..2].copy_from_slice(b"ok");
+    assert!(fixture.lcd.print(buffer, 2).is_ok());
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.client.written.take(), vec![2]);
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![(true, b'o'), (true, b'k')]
+    );
+}
+
+#[test]
+fn busy_wait_initialization_delays() {
+    let delays = initialization_delays(&setup::<Freq1MHz>());
+    // The enable pulses are busy-waited by default at 1 MHz, only the
+    // execution delays are left.
+    assert_eq!(&delays[..8], &[50_000, 37, 4_500, 37, 150, 37, 150, 37]);
+    assert!(!delays.contains(&1));
+    assert!(delays.contains(&2_000));
+}
+
+/// The nibbles latched by the initialization sequence with an alarm that
+/// accepts any delay.
+fn reference_initialization<F: Frequency + 'static>() -> Vec<(bool, u8)> {
+    let fixture = setup::<F>();
+    fixture.lcd.display_on().unwrap();
+    fixture.alarm.run(1000);
+    latched_nibbles(&fixture.pins.take())
+}
+
+#[test]
+fn delays_respect_minimum_dt() {
+    let fixture = setup::<Freq1MHz>();
+    // Alarms shorter than 5 ms are dropped by this alarm.
+    fixture.alarm.set_minimum_dt(5_000);
+    let delays = initialization_delays(&fixture);
+
+    assert!(delays.iter().all(|&dt| dt >= 5_000));
+    assert_eq!(delays[0], 50_000);
+    assert_eq!(fixture.client.events.take(), vec![Ok(())]);
+    assert_eq!(
+        latched_nibbles(&fixture.pins.take()),
+        reference_initialization::<Freq1MHz>()
+    );
+}
+
+#[test]
+fn refused_alarms_are_polled_from_deferred_calls() {
+    let fixture = setup::<Freq1KHz>();
+    fixture.alarm.set_refuse_alarms(true);
+    assert_eq!(fixture.lcd.display_on(), Ok(()));
+    assert_eq!(fixture.lcd.clear(), Err(ErrorCode::BUSY));
+
+    // Nothing is sent before the power on delay is over.
+    let start = fixture.alarm.now();
+    fixture.lcd.handle_deferred_call();
+    assert!(fixture.pins.take().is_empty());
+
+    let mut calls = 0;
+    while fixture.client.events.borrow().is_empty() && calls < 10_000 {
+        fixture.lcd.handle_deferred_call();
+        calls += 1;
+    }
+    assert_eq!(fixture.client.events.take(), vec![Ok(())]);
+    assert!(fixture.alarm.now().wrapping_sub(start).into_u32() >= 50);
+    assert_eq!(
+        latched_nibbles(&fixture.pins.take()),
+        reference_initialization::<Freq1KHz>()
+    );
+
+    // The LCD is idle again, spurious deferred calls are ignored.
+    fixture.lcd.handle_deferred_call();
+    assert!(fixture.pins.take().is_empty());
+    assert_eq!(fixture.lcd.clear(), Ok(()));
+}
+
+/// Prints `len` characters and returns the bytes latched by the LCD, the
+/// number of alarm events and the time it took, in ticks.
+fn print_stats<F: Frequency>(fixture: &Fixture<F>, len: usize) -> (Vec<(bool, u8)>, usize, u32) {
+    let buffer = leak_buffer(len);
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = b'a' + (i % 26) as u8;
+    }
+    let fired = fixture.alarm.fired_count();
+    let start = fixture.alarm.now();
+    assert!(fixture.lcd.print(buffer, len).is_ok());
+    fixture.alarm.run(1000);
+    let elapsed = fixture.alarm.now().wrapping_sub(start).into_u32();
+    assert_eq!(fixture.client.written.take(), vec![len]);
+    (
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        fixture.alarm.fired_count() - fired,
+        elapsed,
+    )
+}
+
+#[test]
+fn busy_wait_pulses_save_alarm_events() {
+    let alarm_fixture = setup::<Freq1MHz>();
+    alarm_fixture.lcd.set_pulse_mode(PulseMode::Alarm);
+    initialize(&alarm_fixture);
+    let (alarm_bytes, alarm_events, alarm_latency) = print_stats(&alarm_fixture, 16);
+
+    let busy_fixture = setup::<Freq1MHz>();
+    initialize(&busy_fixture);
+    let (busy_bytes, busy_events, busy_latency) = print_stats(&busy_fixture, 16);
+
+    // Same output, with one alarm event per character instead of six.
+    assert_eq!(busy_bytes, alarm_bytes);
+    assert_eq!(alarm_events, 16 * 6);
+    assert_eq!(busy_events, 16);
+    assert!(busy_latency < alarm_latency);
+}
+
+#[test]
+fn character_delay_paces_printing() {
+    let fixture = setup::<Freq1MHz>();
+    initialize(&fixture);
+    fixture.lcd.set_character_delay_us(500);
+
+    let buffer = leak_buffer(16);
+    buffer[..3].copy_from_slice(b"abc");
+    assert!(fixture.lcd.print(buffer, 3).is_ok());
+    assert_eq!(armed_delays(&fixture), vec![500; 3]);
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![(true, b'a'), (true, b'b'), (true, b'c')]
+    );
+
+    // Commands are not paced.
+    assert_eq!(fixture.lcd.set_cursor(0, 0), Ok(()));
+    assert_eq!(armed_delays(&fixture), vec![37]);
+}
+
+#[test]
+fn character_delay_paces_alarm_pulse_printing() {
+    let fixture = setup::<Freq1MHz>();
+    fixture.lcd.set_pulse_mode(PulseMode::Alarm);
+    initialize(&fixture);
+    fixture.lcd.set_character_delay_us(500);
+
+    let buffer = leak_buffer(16);
+    buffer[..3].copy_from_slice(b"abc");
+    assert!(fixture.lcd.print(buffer, 3).is_ok());
+    // The delay replaces the execution delay after each character.
+    assert_eq!(armed_delays(&fixture), [1, 1, 37, 1, 1, 500].repeat(3));
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![(true, b'a'), (true, b'b'), (true, b'c')]
+    );
+
+    assert_eq!(fixture.lcd.set_cursor(0, 0), Ok(()));
+    assert_eq!(armed_delays(&fixture), [1, 1, 37, 1, 1, 37]);
+}
+
+/// Prints `text` and returns the bytes latched by the LCD.
+fn print<F: Frequency>(fixture: &Fixture<F>, text: &[u8]) -> Vec<(bool, u8)> {
+    let buffer = leak_buffer(text.len());
+    buffer.copy_from_slice(text);
+    assert!(fixture.lcd.print(buffer, text.len()).is_ok());
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.client.written.take(), vec![text.len()]);
+    bytes(&latched_nibbles(&fixture.pins.take()))
+}
+
+#[test]
+fn entry_mode_commands() {
+    let fixture = setup_with_entry::<Freq1MHz>(EntryDirection::RightToLeft, true);
+    fixture.lcd.display_on().unwrap();
+    fixture.alarm.run(1000);
+    // The initial entry mode is the one given to the constructor.
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())).last(),
+        Some(&(false, 0x04 | 0x01))
+    );
+
+    assert_eq!(fixture.lcd.set_entry_shift(false), Ok(()));
+    assert_eq!(fixture.lcd.set_entry_shift(true), Err(ErrorCode::BUSY));
+    fixture.alarm.run(1000);
+    assert_eq!(
+        fixture.lcd.set_entry_direction(EntryDirection::LeftToRight),
+        Ok(())
+    );
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.set_entry_shift(true), Ok(()));
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.screen_command(6, 2, 0), Err(ErrorCode::INVAL));
+    assert_eq!(fixture.lcd.screen_command(7, 2, 0), Err(ErrorCode::INVAL));
+
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![(false, 0x04), (false, 0x06), (false, 0x07)]
+    );
+    assert_eq!(fixture.client.events.take(), vec![Ok(()); 4]);
+}
+
+#[test]
+fn clear_keeps_right_to_left_entry() {
+    let fixture = setup_with_entry::<Freq1MHz>(EntryDirection::RightToLeft, false);
+    initialize(&fixture);
+
+    assert_eq!(fixture.lcd.set_cursor(5, 1), Ok(()));
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    assert_eq!(fixture.lcd.clear(), Ok(()));
+    fixture.alarm.run(1000);
+    // The clear command sets the entry direction back to left to right.
+    assert_eq!(
+        bytes(&latched_nibbles(&fixture.pins.take())),
+        vec![(false, 0x01), (false, 0x04)]
+    );
+    assert_eq!(fixture.lcd.cursor(), (0, 0));
+}
+
+#[test]
+fn left_to_right_text_wraps_to_the_next_line() {
+    let fixture = setup::<Freq1MHz>();
+    initialize(&fixture);
+    assert_eq!(fixture.lcd.cursor(), (0, 0));
+
+    assert_eq!(fixture.lcd.set_cursor(14, 0), Ok(()));
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    assert_eq!(
+        print(&fixture, b"abcd"),
+        vec![
+            (true, b'a'),
+            (true, b'b'),
+            (false, 0x80 | 0x40),
+            (true, b'c'),
+            (true, b'd')
+        ]
+    );
+    assert_eq!(fixture.lcd.cursor(), (2, 1));
+
+    // From the last line back to the first one, once there is something
+    // left to print.
+    assert_eq!(fixture.lcd.set_cursor(15, 1), Ok(()));
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    assert_eq!(print(&fixture, b"e"), vec![(true, b'e')]);
+    assert_eq!(fixture.lcd.cursor(), (0, 0));
+    assert_eq!(print(&fixture, b"f"), vec![(false, 0x80), (true, b'f')]);
+    assert_eq!(fixture.lcd.cursor(), (1, 0));
+}
+
+#[test]
+fn right_to_left_text_wraps_to_the_next_line() {
+    let fixture = setup::<Freq1MHz>();
+    initialize(&fixture);
+
+    assert_eq!(
+        fixture.lcd.set_entry_direction(EntryDirection::RightToLeft),
+        Ok(())
+    );
+    fixture.alarm.run(1000);
+    assert_eq!(fixture.lcd.set_cursor(1, 0), Ok(()));
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    assert_eq!(
+        print(&fixture, b"abc"),
+        vec![
+            (true, b'a'),
+            (true, b'b'),
+            (false, 0x80 | 0x40 | 15),
+            (true, b'c')
+        ]
+    );
+    assert_eq!(fixture.lcd.cursor(), (14, 1));
+}
+
+#[test]
+fn entry_shift_does_not_wrap() {
+    let fixture = setup_with_entry::<Freq1MHz>(EntryDirection::RightToLeft, true);
+    initialize(&fixture);
+
+    // The display scrolls instead.
+    assert_eq!(print(&fixture, b"ab"), vec![(true, b'a'), (true, b'b')]);
+    assert_eq!(fixture.lcd.cursor(), (38, 0));
+}
+
+#[test]
+fn row_offsets_follow_the_width() {
+    assert_eq!(row_offsets(16), [0x00, 0x40, 0x10, 0x50]);
+    assert_eq!(row_offsets(20), [0x00, 0x40, 0x14, 0x54]);
+    assert_eq!(row_offsets(8), [0x00, 0x40, 0x08, 0x48]);
+}
+
+#[test]
+fn cursor_follows_the_geometry() {
+    for (width, height) in [(16, 2), (20, 4), (8, 1)] {
+        let fixture =
+            setup_with_geometry::<Freq1MHz>(width, height, EntryDirection::LeftToRight, false);
+        initialize(&fixture);
+        assert_eq!(fixture.lcd.get_size(), (width as usize, height as usize));
+
+        // The last character of every line.
+        let offsets = row_offsets(width);
+        for row in 0..height {
+            assert_eq!(
+                fixture.lcd.set_cursor(width as usize - 1, row as usize),
+                Ok(())
+            );
+            fixture.alarm.run(1000);
+            assert_eq!(
+                bytes(&latched_nibbles(&fixture.pins.take())),
+                vec![(false, 0x80 | (offsets[row as usize] + width - 1))],
+                "{}x{}, line {}",
+                width,
+                height,
+                row
+            );
+        }
+
+        // Nothing outside of the display.
+        assert_eq!(
+            fixture.lcd.set_cursor(width as usize, 0),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            fixture.lcd.set_cursor(0, height as usize),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(fixture.alarm.armed_dt(), None);
+        assert!(fixture.pins.take().is_empty());
+        assert_eq!(fixture.client.events.take(), vec![Ok(()); height as usize]);
+    }
+}
+
+#[test]
+fn text_wraps_through_the_four_lines() {
+    let fixture = setup_with_geometry::<Freq1MHz>(20, 4, EntryDirection::LeftToRight, false);
+    initialize(&fixture);
+
+    assert_eq!(fixture.lcd.set_cursor(19, 1), Ok(()));
+    fixture.alarm.run(1000);
+    fixture.pins.take();
+    // From the end of line 1 to the start of line 2, which continues line 0
+    // in the display data RAM.
+    assert_eq!(
+        print(&fixture, b"ab"),
+        vec![(true, b'a'), (false, 0x80 | 0x14), (true, b'b')]
+    );
+    assert_eq!(fixture.lcd.cursor(), (1, 2));
+}
@@ -0,0 +1,226 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Interrupt and alarm latency over a scripted loopback and time reference.
+
+use capsules_extra::interrupt_latency::{
+    InterruptLatency, Mode, Statistics, DRIVER_NUM, MAX_TRIALS,
+};
+use kernel::hil::gpio::Interrupt;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, Apps, Board, FakeAlarm, PinLog, RecordingPin};
+
+type Latency = InterruptLatency<
+    'static,
+    FakeAlarm<'static, Freq1KHz>,
+    FakeAlarm<'static, Freq1KHz>,
+    RecordingPin<'static>,
+    RecordingPin<'static>,
+>;
+
+struct Fixture {
+    latency: &'static Latency,
+    alarm: &'static FakeAlarm<'static, Freq1KHz>,
+    output: &'static PinLog,
+    input: &'static RecordingPin<'static>,
+    apps: Apps,
+}
+
+/// The alarm is also the time reference. The loopback is scripted: the
+/// scenario triggers the input after the output toggled.
+fn setup() -> Fixture {
+    let board = Board::new();
+    let alarm = leak(FakeAlarm::new());
+    let output = leak(PinLog::default());
+    let input = leak(RecordingPin::new(1, leak(PinLog::default())));
+    let latency = leak(InterruptLatency::new(
+        alarm,
+        alarm,
+        leak(RecordingPin::new(0, output)),
+        input,
+        board.create_grant(DRIVER_NUM),
+    ));
+    alarm.set_alarm_client(latency);
+    input.set_client(latency);
+    let apps = board.load_apps(1);
+    Fixture {
+        latency,
+        alarm,
+        output,
+        input,
+        apps,
+    }
+}
+
+impl Fixture {
+    fn run(&self) {
+        self.apps
+            .run(&[(DRIVER_NUM, self.latency as &dyn SyscallDriver)]);
+    }
+
+    /// Reads the results of the last run, with commands 3 to 5.
+    fn results(&self) -> Vec<SyscallReturn> {
+        for command in 3..=5 {
+            self.apps.command(0, DRIVER_NUM, command, 0, 0);
+        }
+        self.run();
+        self.apps.take_returns(0)
+    }
+}
+
+#[test]
+fn statistics_math() {
+    let mut statistics = Statistics::default();
+    assert_eq!(statistics.min(), None);
+    assert_eq!(statistics.mean(), None);
+
+    for ticks in [7, 3, 12, 3, 9] {
+        statistics.record(ticks);
+    }
+    statistics.record_timeout();
+    assert_eq!(statistics.measured(), 5);
+    assert_eq!(statistics.timed_out(), 1);
+    assert_eq!(statistics.min(), Some(3));
+    assert_eq!(statistics.max(), Some(12));
+    // 34 / 5, rounded down.
+    assert_eq!(statistics.mean(), Some(6));
+
+    // The total does not overflow with the largest latencies.
+    let mut statistics = Statistics::default();
+    statistics.record(u32::MAX);
+    statistics.record(u32::MAX - 2);
+    assert_eq!(statistics.mean(), Some(u32::MAX - 1));
+}
+
+#[test]
+fn gpio_trials_measure_toggle_to_interrupt() {
+    let fixture = setup();
+    fixture.apps.subscribe(0, DRIVER_NUM, 0);
+    fixture.apps.command(0, DRIVER_NUM, 1, 3, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::SubscribeSuccess(..), SyscallReturn::Success]
+    ));
+
+    for ticks in [5, 9, 4] {
+        assert_eq!(fixture.output.take().len(), 1);
+        fixture.alarm.advance(ticks);
+        assert!(fixture.input.trigger());
+    }
+    // Interrupts are off between runs, and the timeout was disarmed.
+    assert!(!fixture.input.trigger());
+    assert_eq!(fixture.alarm.armed_dt(), None);
+    assert!(fixture.output.take().is_empty());
+
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [3, 0, 0])]
+    );
+    assert!(matches!(
+        fixture.results()[..],
+        [
+            SyscallReturn::SuccessU32U32(3, 0),
+            SyscallReturn::SuccessU32U32(4, 9),
+            SyscallReturn::SuccessU32(6),
+        ]
+    ));
+}
+
+#[test]
+fn gpio_trials_time_out_without_loopback() {
+    let fixture = setup();
+    fixture.apps.subscribe(0, DRIVER_NUM, 0);
+    fixture.apps.command(0, DRIVER_NUM, 1, 3, 0);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    // The first trial is measured, the input never fires again.
+    fixture.alarm.advance(6);
+    assert!(fixture.input.trigger());
+    assert_eq!(fixture.alarm.run(10), 2);
+    assert_eq!(fixture.output.take().len(), 3);
+
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [1, 2, 0])]
+    );
+    assert!(matches!(
+        fixture.results()[..],
+        [
+            SyscallReturn::SuccessU32U32(1, 2),
+            SyscallReturn::SuccessU32U32(6, 6),
+            SyscallReturn::SuccessU32(6),
+        ]
+    ));
+}
+
+#[test]
+fn timer_trials_measure_request_to_callback() {
+    let fixture = setup();
+    fixture.apps.subscribe(0, DRIVER_NUM, 0);
+    fixture.apps.command(0, DRIVER_NUM, 2, 2, 0);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    // The alarm is requested as soon as possible, and its callback comes
+    // late.
+    for ticks in [2, 8] {
+        assert_eq!(fixture.alarm.armed_dt(), Some(0));
+        fixture.alarm.advance(ticks);
+        assert!(fixture.alarm.fire());
+    }
+    assert_eq!(fixture.alarm.armed_dt(), None);
+    assert!(fixture.output.take().is_empty());
+
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [2, 0, 0])]
+    );
+    assert!(matches!(
+        fixture.results()[..],
+        [
+            SyscallReturn::SuccessU32U32(2, 0),
+            SyscallReturn::SuccessU32U32(2, 8),
+            SyscallReturn::SuccessU32(5),
+        ]
+    ));
+}
+
+#[test]
+fn runs_are_checked() {
+    let fixture = setup();
+    fixture.apps.command(0, DRIVER_NUM, 1, 0, 0);
+    fixture.apps.command(0, DRIVER_NUM, 2, MAX_TRIALS + 1, 0);
+    fixture.apps.command(0, DRIVER_NUM, 2, 1, 0);
+    // One run at a time, from the apps or the kernel.
+    fixture.apps.command(0, DRIVER_NUM, 1, 1, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::INVAL),
+            SyscallReturn::Failure(ErrorCode::INVAL),
+            SyscallReturn::Success,
+            SyscallReturn::Failure(ErrorCode::BUSY),
+        ]
+    ));
+    assert_eq!(fixture.latency.start(Mode::Gpio, 1), Err(ErrorCode::BUSY));
+
+    // Nothing was measured yet.
+    assert!(matches!(
+        fixture.results()[..],
+        [
+            SyscallReturn::SuccessU32U32(0, 0),
+            SyscallReturn::Failure(ErrorCode::FAIL),
+            SyscallReturn::Failure(ErrorCode::FAIL),
+        ]
+    ));
+}
@@ -0,0 +1,920 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! L3GD20 gyroscope over a scripted SPI device.
+
+use std::cell::RefCell;
+
+use capsules_extra::l3gd20::{
+    FifoMode, L3gd20DataRate, L3gd20Spi, DRIVER_NUM, L3GD20_AXIS_DISABLED, L3GD20_RX_SIZE,
+    L3GD20_TX_SIZE,
+};
+use capsules_extra::sample_history::{HistorySample, SampleHistory, SensorKind};
+use kernel::hil::gpio::Interrupt;
+use kernel::hil::sensors::{NineDof, NineDofClient, TemperatureClient, TemperatureDriver};
+use kernel::hil::spi::SpiMasterDevice;
+use kernel::hil::time::Freq1KHz;
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, Apps, Board, FakeAlarm, PinLog, RecordingPin, ScriptedSpiDevice};
+
+type Gyroscope = L3gd20Spi<'static, ScriptedSpiDevice<'static>>;
+
+#[derive(Default)]
+struct Samples {
+    samples: RefCell<Vec<(usize, usize, usize)>>,
+    temperatures: RefCell<Vec<Result<i32, ErrorCode>>>,
+}
+
+impl TemperatureClient for Samples {
+    fn callback(&self, value: Result<i32, ErrorCode>) {
+        self.temperatures.borrow_mut().push(value);
+    }
+}
+
+impl NineDofClient for Samples {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        self.samples.borrow_mut().push((x, y, z));
+    }
+}
+
+fn setup() -> (
+    &'static ScriptedSpiDevice<'static>,
+    &'static Gyroscope,
+    &'static Samples,
+) {
+    setup_on(&Board::new())
+}
+
+fn setup_on(
+    board: &Board,
+) -> (
+    &'static ScriptedSpiDevice<'static>,
+    &'static Gyroscope,
+    &'static Samples,
+) {
+    setup_with_pin(board, None)
+}
+
+fn setup_with_pin(
+    board: &Board,
+    interrupt_pin: Option<&'static RecordingPin<'static>>,
+) -> (
+    &'static ScriptedSpiDevice<'static>,
+    &'static Gyroscope,
+    &'static Samples,
+) {
+    let spi = leak(ScriptedSpiDevice::new());
+    let l3gd20 = leak(L3gd20Spi::new(
+        spi,
+        interrupt_pin.map(|pin| pin as _),
+        Box::leak(Box::new([0; L3GD20_TX_SIZE])),
+        Box::leak(Box::new([0; L3GD20_RX_SIZE])),
+        board.create_grant(DRIVER_NUM),
+    ));
+    spi.set_client(l3gd20);
+    assert_eq!(l3gd20.configure(), Ok(()));
+    if let Some(pin) = interrupt_pin {
+        pin.set_client(l3gd20);
+    }
+    let samples = leak(Samples::default());
+    NineDof::set_client(l3gd20, samples);
+    TemperatureDriver::set_client(l3gd20, samples);
+    (spi, l3gd20, samples)
+}
+
+/// Encodes `(x, y, z)` samples as the sensor sends them.
+fn encode(samples: &[(i16, i16, i16)]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|&(x, y, z)| [x, y, z])
+        .flat_map(i16::to_le_bytes)
+        .collect()
+}
+
+#[test]
+fn gyroscope_reads_without_process() {
+    let (spi, l3gd20, samples) = setup();
+
+    // The sensor is free again after each read, even though no process
+    // ever used the driver.
+    for raw in [1_000, -1_000] {
+        assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+        spi.push_response([vec![0], encode(&[(raw, 0, 2 * raw)])].concat());
+        assert!(spi.complete());
+    }
+
+    assert_eq!(spi.take_written(), vec![vec![0xE8, 0, 0, 0, 0, 0, 0]; 2]);
+    // 8.75 mdps/digit
+    assert_eq!(
+        samples.samples.take(),
+        vec![(8, 0, 17), ((-8isize) as usize, 0, (-17isize) as usize)]
+    );
+}
+
+#[test]
+fn fifo_burst_read() {
+    let (spi, l3gd20, samples) = setup();
+    assert_eq!(l3gd20.read_fifo(), Err(ErrorCode::OFF));
+    assert_eq!(l3gd20.enable_fifo(32), Err(ErrorCode::INVAL));
+
+    assert_eq!(l3gd20.enable_fifo(16), Ok(()));
+    assert_eq!(l3gd20.read_fifo(), Err(ErrorCode::BUSY));
+    while spi.complete() {}
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![0x24, 0x40], vec![0x2E, 0x40 | 16]]
+    );
+
+    // Three samples are waiting, they are read in a single transfer.
+    let fifo = [(100, 200, 300), (400, 500, 600), (1_000, 2_000, -4_000)];
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    spi.push_response(vec![0, 0x03]);
+    assert!(spi.complete());
+    spi.push_response([vec![0], encode(&fifo)].concat());
+    assert!(spi.complete());
+    assert!(!spi.is_pending());
+
+    let written = spi.take_written();
+    assert_eq!(written[0], vec![0xAF, 0]);
+    assert_eq!(written[1].len(), 1 + 3 * 6);
+    assert_eq!(written[1][0], 0xE8);
+    // Every sample goes to the NineDof client, oldest first.
+    assert_eq!(
+        samples.samples.take(),
+        vec![(0, 1, 2), (3, 4, 5), (8, 17, (-35isize) as usize)]
+    );
+}
+
+#[test]
+fn fifo_full_and_empty() {
+    let (spi, l3gd20, samples) = setup();
+    assert_eq!(l3gd20.enable_fifo(0), Ok(()));
+    while spi.complete() {}
+    spi.take_written();
+
+    // An empty FIFO ends the read without a second transfer.
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    spi.push_response(vec![0, 0x20]);
+    assert!(spi.complete());
+    assert!(!spi.is_pending());
+    assert!(samples.samples.take().is_empty());
+
+    // An overrun means the FIFO holds all 32 samples.
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    spi.push_response(vec![0, 0x40 | 0x1F]);
+    assert!(spi.complete());
+    assert!(spi.complete());
+    let written = spi.take_written();
+    assert_eq!(written[2].len(), 1 + 32 * 6);
+    assert_eq!(samples.samples.take().len(), 32);
+
+    assert_eq!(l3gd20.disable_fifo(), Ok(()));
+    while spi.complete() {}
+    assert_eq!(spi.take_written(), vec![vec![0x24, 0x00], vec![0x2E, 0x00]]);
+    assert_eq!(l3gd20.read_fifo(), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn high_pass_filter_commands() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let process = board.process_id(1);
+
+    // 4 enables the filter, 5 sets its mode and divider. `CommandReturn`
+    // cannot be inspected here, the transfers show what each command did.
+    for (command, data1, data2) in [(4, 1, 0), (5, 2, 9), (4, 0, 0)] {
+        let _ = l3gd20.command(command, data1, data2, process);
+        assert!(spi.complete());
+    }
+
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![0x24, 0x10], vec![0x21, 0x29], vec![0x24, 0x00]]
+    );
+}
+
+#[test]
+fn data_ready_needs_interrupt_pin() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+
+    let _ = l3gd20.command(11, 1, 0, board.process_id(1));
+    assert!(!spi.is_pending());
+    assert!(spi.take_written().is_empty());
+}
+
+#[test]
+fn data_ready_stops_without_owner() {
+    let board = Board::new();
+    let pin = leak(RecordingPin::new(0, leak(PinLog::default())));
+    let (spi, l3gd20, samples) = setup_with_pin(&board, Some(pin));
+
+    // Command 11 routes data ready to INT2 and arms the pin.
+    let _ = l3gd20.command(11, 1, 0, board.process_id(1));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![0x22, 0x08]]);
+
+    // The owner cannot be entered any more, so the first interrupt turns
+    // data ready events off instead of reading a sample nobody receives.
+    assert!(pin.trigger());
+    assert!(!spi.is_pending());
+    assert!(!pin.trigger());
+    assert!(samples.samples.take().is_empty());
+
+    // Disabling writes the register back without arming the pin again.
+    let _ = l3gd20.command(11, 0, 0, board.process_id(1));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![0x22, 0x00]]);
+    assert!(!pin.trigger());
+}
+
+#[test]
+fn temperature_uses_offset() {
+    let board = Board::new();
+    let (spi, l3gd20, samples) = setup_on(&board);
+
+    // Raw 0 reads as the offset, 25 deg C by default.
+    let read = |raw: u8| {
+        assert_eq!(TemperatureDriver::read_temperature(l3gd20), Ok(()));
+        spi.push_response(vec![0, raw]);
+        assert!(spi.complete());
+    };
+    read(0);
+    // The raw value is signed and decreases as the temperature rises.
+    read(0xFB);
+    read(10);
+    l3gd20.set_temperature_offset(40);
+    read(0);
+    let _ = l3gd20.command(12, (-10i32) as usize, 0, board.process_id(1));
+    read(2);
+
+    assert_eq!(spi.take_written(), vec![vec![0xA6, 0]; 5]);
+    assert_eq!(
+        samples.temperatures.take(),
+        vec![Ok(2500), Ok(3000), Ok(1500), Ok(4000), Ok(-1200)]
+    );
+}
+
+#[test]
+fn fifo_modes_and_watermark() {
+    let (spi, l3gd20, samples) = setup();
+
+    assert_eq!(l3gd20.configure_fifo(FifoMode::Fifo, 4), Ok(()));
+    while spi.complete() {}
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![0x24, 0x40], vec![0x2E, 0x20 | 4]]
+    );
+
+    // Ten samples are waiting, a read drains the watermark level of them.
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    spi.push_response(vec![0, 10]);
+    assert!(spi.complete());
+    spi.push_response([vec![0], encode(&[(0, 0, 0); 4])].concat());
+    assert!(spi.complete());
+    assert_eq!(spi.take_written()[1].len(), 1 + 4 * 6);
+    assert_eq!(samples.samples.take().len(), 4);
+
+    assert_eq!(l3gd20.configure_fifo(FifoMode::BypassToStream, 31), Ok(()));
+    while spi.complete() {}
+    assert_eq!(
+        spi.take_written(),
+        vec![vec![0x24, 0x40], vec![0x2E, 0x80 | 31]]
+    );
+
+    // Bypass mode disables the FIFO.
+    assert_eq!(l3gd20.configure_fifo(FifoMode::Bypass, 0), Ok(()));
+    while spi.complete() {}
+    assert_eq!(spi.take_written(), vec![vec![0x24, 0x00], vec![0x2E, 0x00]]);
+    assert_eq!(l3gd20.read_fifo(), Err(ErrorCode::OFF));
+}
+
+fn run(apps: &Apps, l3gd20: &'static Gyroscope) {
+    apps.run(&[(DRIVER_NUM, l3gd20 as &dyn SyscallDriver)]);
+}
+
+#[test]
+fn fifo_overrun_upcall() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.subscribe(0, DRIVER_NUM, 2);
+    apps.command(0, DRIVER_NUM, 8, 0, 0);
+    run(&apps, l3gd20);
+    while spi.complete() {}
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 0, 0])]);
+
+    apps.command(0, DRIVER_NUM, 9, 0, 0);
+    run(&apps, l3gd20);
+    spi.push_response(vec![0, 0x40 | 0x1F]);
+    assert!(spi.complete());
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+
+    // The overrun is reported before the samples, none of which is copied
+    // as the app allowed no buffer.
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 2, [32, 0, 0]), (DRIVER_NUM, 0, [0, 32, 0])]
+    );
+    assert!(apps
+        .take_returns(0)
+        .iter()
+        .all(|r| !matches!(r, SyscallReturn::Failure(_))));
+}
+
+#[test]
+fn fifo_watermark_interrupt() {
+    let board = Board::new();
+    let pin = leak(RecordingPin::new(0, leak(PinLog::default())));
+    let (spi, l3gd20, samples) = setup_with_pin(&board, Some(pin));
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 1);
+    apps.command(0, DRIVER_NUM, 11, 1, 0);
+    run(&apps, l3gd20);
+    assert!(spi.complete());
+
+    // Enabling the FIFO moves INT2 from data ready to the watermark.
+    assert_eq!(l3gd20.enable_fifo(2), Ok(()));
+    while spi.complete() {}
+    assert_eq!(
+        spi.take_written(),
+        vec![
+            vec![0x22, 0x08],
+            vec![0x24, 0x40],
+            vec![0x2E, 0x40 | 2],
+            vec![0x22, 0x06]
+        ]
+    );
+
+    // An interrupt during a transfer reads the FIFO once it is done.
+    assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+    assert!(pin.trigger());
+    spi.push_response([vec![0], encode(&[(0, 0, 0)])].concat());
+    assert!(spi.complete());
+    spi.push_response(vec![0, 2]);
+    assert!(spi.complete());
+    spi.push_response([vec![0], encode(&[(0, 0, 0); 2])].concat());
+    assert!(spi.complete());
+    assert!(!spi.is_pending());
+    run(&apps, l3gd20);
+
+    let written = spi.take_written();
+    assert_eq!(written[1], vec![0xAF, 0]);
+    assert_eq!(written[2].len(), 1 + 2 * 6);
+    assert_eq!(samples.samples.take().len(), 3);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 1, [0, 2, 0])]);
+}
+
+#[test]
+fn start_failure_returns_buffers() {
+    let (spi, l3gd20, samples) = setup();
+
+    // Each operation reports the error of the SPI bus and leaves the driver
+    // idle, with its buffers, so that the same operation can be retried.
+    let operations: [&dyn Fn() -> Result<(), ErrorCode>; 5] = [
+        &|| l3gd20.is_present(),
+        &|| l3gd20.power_on(),
+        &|| l3gd20.read_gyroscope(),
+        &|| TemperatureDriver::read_temperature(l3gd20),
+        &|| l3gd20.enable_fifo(1),
+    ];
+    for operation in operations {
+        spi.fail_next_start(ErrorCode::FAIL);
+        assert_eq!(operation(), Err(ErrorCode::FAIL));
+        assert!(!spi.is_pending());
+        assert_eq!(operation(), Ok(()));
+        while spi.complete() {}
+    }
+    assert_eq!(spi.take_written().len(), 6);
+    assert_eq!(samples.samples.take(), vec![(0, 0, 0)]);
+    assert_eq!(samples.temperatures.take(), vec![Ok(2500)]);
+
+    // A FIFO configuration that did not reach the sensor is forgotten.
+    assert_eq!(l3gd20.disable_fifo(), Ok(()));
+    while spi.complete() {}
+    spi.fail_next_start(ErrorCode::OFF);
+    assert_eq!(l3gd20.enable_fifo(4), Err(ErrorCode::OFF));
+    assert_eq!(l3gd20.read_fifo(), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn transfer_error_reports_failure() {
+    let (spi, l3gd20, samples) = setup();
+
+    assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+    spi.push_response([vec![0], encode(&[(1_000, 1_000, 1_000)])].concat());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert_eq!(TemperatureDriver::read_temperature(l3gd20), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+
+    // Whatever the read buffer holds is not a sample.
+    assert_eq!(samples.samples.take(), vec![(0, 0, 0)]);
+    assert_eq!(samples.temperatures.take(), vec![Err(ErrorCode::FAIL)]);
+
+    // A failed FIFO level read ends the FIFO read.
+    assert_eq!(l3gd20.enable_fifo(0), Ok(()));
+    while spi.complete() {}
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert!(!spi.is_pending());
+    assert!(samples.samples.take().is_empty());
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+}
+
+#[test]
+fn command_returns_spi_error() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+
+    spi.fail_next_start(ErrorCode::FAIL);
+    apps.command(0, DRIVER_NUM, 6, 0, 0);
+    apps.command(0, DRIVER_NUM, 3, 1, 0);
+    run(&apps, l3gd20);
+    // The first return is the subscribe.
+    assert!(matches!(
+        apps.take_returns(0)[1..],
+        [
+            SyscallReturn::Failure(ErrorCode::FAIL),
+            SyscallReturn::Success
+        ]
+    ));
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 0, 0])]);
+
+    // The FIFO samples cannot be read after the FIFO level, the read ends
+    // with no samples.
+    apps.command(0, DRIVER_NUM, 8, 0, 0);
+    run(&apps, l3gd20);
+    while spi.complete() {}
+    apps.command(0, DRIVER_NUM, 9, 0, 0);
+    run(&apps, l3gd20);
+    spi.push_response(vec![0, 0x03]);
+    spi.fail_next_start(ErrorCode::FAIL);
+    assert!(spi.complete());
+    assert!(!spi.is_pending());
+    run(&apps, l3gd20);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [0, 0, 0]), (DRIVER_NUM, 0, [0, 0, 0])]
+    );
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+}
+
+#[test]
+fn axis_combinations() {
+    let (spi, l3gd20, samples) = setup();
+    const OFF: usize = L3GD20_AXIS_DISABLED;
+
+    for mask in 0..8u8 {
+        let (x, y, z) = (mask & 1 != 0, mask & 2 != 0, mask & 4 != 0);
+        assert_eq!(l3gd20.set_axes(x, y, z), Ok(()));
+        // The data rate, bandwidth and power bits are kept.
+        spi.push_response(vec![0, 0xBF]);
+        assert!(spi.complete());
+        // Nothing changes until the write is acknowledged.
+        if mask != 7 {
+            assert_ne!(l3gd20.enabled_axes(), (x, y, z));
+        }
+        assert!(spi.complete());
+        assert!(!spi.is_pending());
+        assert_eq!(l3gd20.enabled_axes(), (x, y, z));
+        // CTRL_REG1 is Zen, Xen, Yen from bit 2 to 0.
+        let bits = u8::from(z) << 2 | u8::from(x) << 1 | u8::from(y);
+        assert_eq!(
+            spi.take_written(),
+            vec![vec![0xA0, 0], vec![0x20, 0xB8 | bits]]
+        );
+
+        assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+        spi.push_response([vec![0], encode(&[(1_000, 2_000, -4_000)])].concat());
+        assert!(spi.complete());
+        spi.take_written();
+        assert_eq!(
+            samples.samples.take(),
+            vec![(
+                if x { 8 } else { OFF },
+                if y { 17 } else { OFF },
+                if z { (-35isize) as usize } else { OFF },
+            )]
+        );
+
+        // Back to all axes for the next combination.
+        assert_eq!(l3gd20.set_axes(true, true, true), Ok(()));
+        while spi.complete() {}
+        spi.take_written();
+    }
+}
+
+#[test]
+fn axes_unchanged_when_write_fails() {
+    let (spi, l3gd20, _) = setup();
+
+    assert_eq!(l3gd20.set_axes(false, false, true), Ok(()));
+    spi.push_response(vec![0, 0x0F]);
+    assert!(spi.complete());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert_eq!(l3gd20.enabled_axes(), (true, true, true));
+
+    // A failed read does not write CTRL_REG1.
+    assert_eq!(l3gd20.set_axes(false, false, true), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert!(!spi.is_pending());
+    assert_eq!(l3gd20.enabled_axes(), (true, true, true));
+
+    // Power on keeps the axes that are disabled.
+    assert_eq!(l3gd20.set_axes(false, false, true), Ok(()));
+    while spi.complete() {}
+    spi.take_written();
+    assert_eq!(l3gd20.power_on(), Ok(()));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![0x20, 0x0C]]);
+}
+
+#[test]
+fn data_rates_write_ctrl_reg1() {
+    let (spi, l3gd20, _) = setup();
+    let rates = [
+        (L3gd20DataRate::Hz95, 0x0F),
+        (L3gd20DataRate::Hz190, 0x4F),
+        (L3gd20DataRate::Hz380, 0x8F),
+        (L3gd20DataRate::Hz760, 0xCF),
+    ];
+    for (odr, ctrl_reg1) in rates {
+        // Read-modify-write of CTRL_REG1, from a sensor powered on at the
+        // default rate.
+        assert_eq!(l3gd20.set_data_rate(odr, 0), Ok(()));
+        spi.push_response(vec![0, 0x0F]);
+        while spi.complete() {}
+        assert_eq!(
+            spi.take_written(),
+            vec![vec![0xA0, 0], vec![0x20, ctrl_reg1]]
+        );
+        assert_eq!(l3gd20.data_rate(), (odr, 0));
+
+        // A power cycle keeps the rate.
+        assert_eq!(l3gd20.power_on(), Ok(()));
+        assert!(spi.complete());
+        assert_eq!(spi.take_written(), vec![vec![0x20, ctrl_reg1]]);
+    }
+
+    // The bandwidth is in bits 5:4, the power and axis bits are kept.
+    assert_eq!(l3gd20.set_data_rate(L3gd20DataRate::Hz380, 3), Ok(()));
+    spi.push_response(vec![0, 0xC4]);
+    while spi.complete() {}
+    assert_eq!(spi.take_written()[1], vec![0x20, 0xB4]);
+    assert_eq!(
+        l3gd20.set_data_rate(L3gd20DataRate::Hz380, 4),
+        Err(ErrorCode::INVAL)
+    );
+}
+
+#[test]
+fn data_rate_unchanged_when_write_fails() {
+    let (spi, l3gd20, _) = setup();
+
+    assert_eq!(l3gd20.set_data_rate(L3gd20DataRate::Hz760, 2), Ok(()));
+    spi.push_response(vec![0, 0x0F]);
+    assert!(spi.complete());
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert_eq!(l3gd20.data_rate(), (L3gd20DataRate::Hz95, 0));
+
+    spi.take_written();
+    assert_eq!(l3gd20.power_on(), Ok(()));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![0x20, 0x0F]]);
+}
+
+#[test]
+fn power_on_needs_spi_configuration() {
+    let board = Board::new();
+    let spi = leak(ScriptedSpiDevice::new());
+    let l3gd20: &'static Gyroscope = leak(L3gd20Spi::new(
+        spi,
+        None,
+        Box::leak(Box::new([0; L3GD20_TX_SIZE])),
+        Box::leak(Box::new([0; L3GD20_RX_SIZE])),
+        board.create_grant(DRIVER_NUM),
+    ));
+    spi.set_client(l3gd20);
+
+    assert_eq!(l3gd20.power_on(), Err(ErrorCode::OFF));
+    assert!(!spi.is_pending());
+    assert_eq!(l3gd20.configure(), Ok(()));
+    assert_eq!(l3gd20.power_on(), Ok(()));
+    assert!(spi.complete());
+    assert_eq!(spi.take_written(), vec![vec![0x20, 0x0F]]);
+}
+
+#[test]
+fn data_rate_command() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    run(&apps, l3gd20);
+    apps.take_returns(0);
+
+    apps.command(0, DRIVER_NUM, 14, 4, 0);
+    apps.command(0, DRIVER_NUM, 14, 0, 4);
+    run(&apps, l3gd20);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::INVAL),
+            SyscallReturn::Failure(ErrorCode::INVAL)
+        ]
+    ));
+    assert!(!spi.is_pending());
+
+    // 380 Hz, bandwidth 1.
+    apps.command(0, DRIVER_NUM, 14, 2, 1);
+    run(&apps, l3gd20);
+    spi.push_response(vec![0, 0x0F]);
+    while spi.complete() {}
+    run(&apps, l3gd20);
+    assert_eq!(spi.take_written()[1], vec![0x20, 0x9F]);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [2, 1, 0])]);
+}
+
+#[test]
+fn axes_command() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+
+    // Z only.
+    apps.command(0, DRIVER_NUM, 13, 0b100, 0);
+    run(&apps, l3gd20);
+    while spi.complete() {}
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0b100, 0, 0])]);
+    spi.take_written();
+
+    apps.command(0, DRIVER_NUM, 6, 0, 0);
+    run(&apps, l3gd20);
+    spi.push_response([vec![0], encode(&[(1, 2, -3)])].concat());
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            0,
+            [
+                L3GD20_AXIS_DISABLED,
+                L3GD20_AXIS_DISABLED,
+                (-3isize) as usize
+            ]
+        )]
+    );
+}
+
+#[test]
+fn driver_is_owned_by_the_first_process() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(2);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.subscribe(1, DRIVER_NUM, 0);
+    run(&apps, l3gd20);
+    apps.take_returns(0);
+    apps.take_returns(1);
+
+    // Is present, from the first process.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+
+    // The second process can only check that the driver exists.
+    apps.command(1, DRIVER_NUM, 0, 0, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(
+        apps.take_returns(1)[..],
+        [
+            SyscallReturn::Success,
+            SyscallReturn::Failure(ErrorCode::RESERVE)
+        ]
+    ));
+
+    // The result goes to the owner only.
+    spi.push_response(vec![0, 0xD4]);
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [1, 1, 0])]);
+    assert!(apps.take_upcalls(1).is_empty());
+
+    // Once the owner exits, the next process to issue a command owns the
+    // driver.
+    apps.terminate(0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(apps.take_returns(1)[..], [SyscallReturn::Success]));
+    spi.push_response(vec![0, 0x00]);
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(1), vec![(DRIVER_NUM, 0, [1, 0, 0])]);
+}
+
+/// Attaches a history of `capacity` samples timed by the returned clock.
+fn attach_history(
+    l3gd20: &'static Gyroscope,
+    capacity: usize,
+) -> (
+    &'static SampleHistory<'static>,
+    &'static FakeAlarm<'static, Freq1KHz>,
+) {
+    let clock = leak(FakeAlarm::new());
+    let history = leak(SampleHistory::new(
+        clock,
+        Box::leak(vec![HistorySample::default(); capacity].into_boxed_slice()),
+    ));
+    l3gd20.set_history(history);
+    (history, clock)
+}
+
+fn history_contents(history: &SampleHistory) -> Vec<(u32, [i16; 3])> {
+    let mut samples = Vec::new();
+    history.for_each(|sample| {
+        assert_eq!(sample.sensor, SensorKind::Gyroscope);
+        samples.push((sample.timestamp_ms, sample.xyz));
+    });
+    samples
+}
+
+#[test]
+fn history_keeps_the_latest_samples() {
+    let (spi, l3gd20, _) = setup();
+    let (history, clock) = attach_history(l3gd20, 3);
+
+    // Nothing is recorded until the history is enabled.
+    assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+    spi.push_response([vec![0], encode(&[(100, 0, 0)])].concat());
+    assert!(spi.complete());
+    assert!(history.is_empty());
+
+    assert_eq!(l3gd20.set_history_enabled(true), Ok(()));
+    for x in 1..=5 {
+        clock.advance(10);
+        assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+        spi.push_response([vec![0], encode(&[(x, -x, 2 * x)])].concat());
+        assert!(spi.complete());
+    }
+    assert_eq!(
+        history_contents(history),
+        vec![(30, [3, -3, 6]), (40, [4, -4, 8]), (50, [5, -5, 10])]
+    );
+
+    // Failed reads are not recorded, and disabling keeps the samples.
+    assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+    assert!(spi.complete_with_error(ErrorCode::FAIL));
+    assert_eq!(l3gd20.set_history_enabled(false), Ok(()));
+    assert_eq!(l3gd20.read_gyroscope(), Ok(()));
+    spi.push_response([vec![0], encode(&[(9, 9, 9)])].concat());
+    assert!(spi.complete());
+    assert_eq!(history_contents(history).len(), 3);
+    assert_eq!(history_contents(history)[2], (50, [5, -5, 10]));
+}
+
+/// Records the number of samples in the history at each `NineDof` callback.
+struct HistoryWatcher {
+    history: &'static SampleHistory<'static>,
+    lengths: RefCell<Vec<usize>>,
+}
+
+impl NineDofClient for HistoryWatcher {
+    fn callback(&self, _x: usize, _y: usize, _z: usize) {
+        self.lengths.borrow_mut().push(self.history.len());
+    }
+}
+
+#[test]
+fn history_records_fifo_samples_before_nine_dof_callbacks() {
+    let (spi, l3gd20, _) = setup();
+    let (history, _) = attach_history(l3gd20, 8);
+    let watcher = leak(HistoryWatcher {
+        history,
+        lengths: RefCell::new(Vec::new()),
+    });
+    NineDof::set_client(l3gd20, watcher);
+    assert_eq!(l3gd20.set_history_enabled(true), Ok(()));
+
+    assert_eq!(l3gd20.enable_fifo(0), Ok(()));
+    while spi.complete() {}
+    assert_eq!(l3gd20.read_fifo(), Ok(()));
+    spi.push_response(vec![0, 3]);
+    assert!(spi.complete());
+    spi.push_response([vec![0], encode(&[(1, 1, 1), (2, 2, 2), (3, 3, 3)])].concat());
+    assert!(spi.complete());
+
+    // Each sample is in the history when the client gets it.
+    assert_eq!(watcher.lengths.take(), vec![1, 2, 3]);
+    assert_eq!(
+        history_contents(history)
+            .iter()
+            .map(|(_, xyz)| xyz[0])
+            .collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+}
+
+#[test]
+fn history_commands() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    run(&apps, l3gd20);
+    apps.take_returns(0);
+
+    // Without a history.
+    apps.command(0, DRIVER_NUM, 15, 1, 0);
+    apps.command(0, DRIVER_NUM, 16, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ]
+    ));
+
+    let (_, clock) = attach_history(l3gd20, 4);
+    apps.command(0, DRIVER_NUM, 15, 1, 0);
+    run(&apps, l3gd20);
+    for x in 1..=3 {
+        clock.advance(0x100);
+        apps.command(0, DRIVER_NUM, 6, 0, 0);
+        run(&apps, l3gd20);
+        spi.push_response([vec![0], encode(&[(x, 0, -1)])].concat());
+        assert!(spi.complete());
+        run(&apps, l3gd20);
+    }
+    apps.take_upcalls(0);
+    apps.take_returns(0);
+
+    // The buffer holds the two newest samples, oldest first.
+    apps.allow_readwrite(0, DRIVER_NUM, 1, 2 * 12 + 5);
+    apps.command(0, DRIVER_NUM, 16, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::AllowReadWriteSuccess(..),
+            SyscallReturn::SuccessU32(2)
+        ]
+    ));
+    assert!(apps.take_upcalls(0).is_empty());
+    assert_eq!(
+        apps.read_memory(0, 24),
+        vec![
+            0x00, 0x02, 0x00, 0x00, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, //
+            0x00, 0x03, 0x00, 0x00, 0, 0, 3, 0, 0, 0, 0xFF, 0xFF,
+        ]
+    );
+}
+
+#[test]
+fn restarted_app_does_not_get_the_upcall_of_its_previous_instance() {
+    let board = Board::new();
+    let (spi, l3gd20, _) = setup_on(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    apps.take_returns(0);
+
+    // The app crashes during the transfer, and its new instance waits for
+    // the transfer to finish.
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [_, SyscallReturn::Failure(ErrorCode::RESERVE)]
+    ));
+    spi.push_response(vec![0, 0xD4]);
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // Then it owns the sensor.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, l3gd20);
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+    spi.push_response(vec![0, 0xD4]);
+    assert!(spi.complete());
+    run(&apps, l3gd20);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [1, 1, 0])]);
+}
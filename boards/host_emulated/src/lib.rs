@@ -0,0 +1,57 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Host emulated board.
+//!
+//! This crate instantiates the in-tree capsules on top of emulated
+//! peripherals and runs scenarios against them with the standard test
+//! harness, so the capsule stack can be tested without hardware.
+//!
+//! Each capsule has its own module with its scenarios, and all modules share
+//! the emulated peripherals from `fixtures`. See the README for the
+//! limitations of the emulation.
+
+#[cfg(test)]
+mod fixtures;
+
+#[cfg(test)]
+mod adc;
+#[cfg(test)]
+mod adc_threshold;
+#[cfg(test)]
+mod ads1115;
+#[cfg(test)]
+mod app_staging;
+#[cfg(test)]
+mod console_commands;
+#[cfg(test)]
+mod entropy;
+#[cfg(test)]
+mod fm25cl;
+#[cfg(test)]
+mod hd44780;
+#[cfg(test)]
+mod interrupt_latency;
+#[cfg(test)]
+mod l3gd20;
+#[cfg(test)]
+mod lsm303dlhc;
+#[cfg(test)]
+mod ltc294x;
+#[cfg(test)]
+mod nonvolatile_storage;
+#[cfg(test)]
+mod rng;
+#[cfg(test)]
+mod settings;
+#[cfg(test)]
+mod si7021;
+#[cfg(test)]
+mod software_pwm;
+#[cfg(test)]
+mod syscall_accounting;
+#[cfg(test)]
+mod temperature_compensation;
+#[cfg(test)]
+mod watchdog;
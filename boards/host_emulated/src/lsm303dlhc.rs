@@ -0,0 +1,737 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! LSM303DLHC over scripted I2C devices.
+
+use std::cell::RefCell;
+
+use capsules_extra::lsm303dlhc::{
+    Lsm303dlhcI2C, SelfTestClient, SelfTestResult, DRIVER_NUM, SELF_TEST_ACCELEROMETER,
+    SELF_TEST_MAGNETOMETER,
+};
+use capsules_extra::lsm303xx::{
+    Lsm303AccelDataRate, Lsm303MagnetoDataRate, Lsm303Range, Lsm303Scale,
+};
+use capsules_extra::sample_history::{HistorySample, SampleHistory, SensorKind};
+use kernel::hil::gpio::{Interrupt, Output};
+use kernel::hil::sensors::{NineDof, NineDofClient, TemperatureClient, TemperatureDriver};
+use kernel::hil::time::Freq1KHz;
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{
+    leak, leak_buffer, Board, FakeAlarm, PinLog, RecordingPin, ScriptedI2CDevice,
+};
+
+#[derive(Default)]
+struct Client {
+    temperatures: RefCell<Vec<Result<i32, ErrorCode>>>,
+    samples: RefCell<Vec<(usize, usize, usize)>>,
+    self_tests: RefCell<Vec<Result<SelfTestResult, ErrorCode>>>,
+}
+
+impl TemperatureClient for Client {
+    fn callback(&self, value: Result<i32, ErrorCode>) {
+        self.temperatures.borrow_mut().push(value);
+    }
+}
+
+impl NineDofClient for Client {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        self.samples.borrow_mut().push((x, y, z));
+    }
+}
+
+impl SelfTestClient for Client {
+    fn self_test_done(&self, result: Result<SelfTestResult, ErrorCode>) {
+        self.self_tests.borrow_mut().push(result);
+    }
+}
+
+struct Fixture {
+    sensor: &'static Lsm303dlhcI2C<'static, ScriptedI2CDevice<'static>>,
+    accelerometer: &'static ScriptedI2CDevice<'static>,
+    magnetometer: &'static ScriptedI2CDevice<'static>,
+    client: &'static Client,
+}
+
+fn setup() -> Fixture {
+    setup_on(&Board::new(), None)
+}
+
+/// Creates the sensor, expecting `identity` as `(register, value)` if set.
+fn setup_on(board: &Board, identity: Option<(u8, u8)>) -> Fixture {
+    setup_with_pin(board, identity, None)
+}
+
+fn setup_with_pin(
+    board: &Board,
+    identity: Option<(u8, u8)>,
+    interrupt_pin: Option<&'static RecordingPin<'static>>,
+) -> Fixture {
+    let accelerometer = leak(ScriptedI2CDevice::new());
+    let magnetometer = leak(ScriptedI2CDevice::new());
+    let sensor = leak(match identity {
+        Some((register, value)) => Lsm303dlhcI2C::new(
+            accelerometer,
+            magnetometer,
+            interrupt_pin.map(|pin| pin as _),
+            register,
+            value,
+            leak_buffer(8),
+            board.create_grant(DRIVER_NUM),
+        ),
+        None => Lsm303dlhcI2C::new_default_identity(
+            accelerometer,
+            magnetometer,
+            interrupt_pin.map(|pin| pin as _),
+            leak_buffer(8),
+            board.create_grant(DRIVER_NUM),
+        ),
+    });
+    accelerometer.set_client(sensor);
+    magnetometer.set_client(sensor);
+    if let Some(pin) = interrupt_pin {
+        pin.set_client(sensor);
+    }
+    let client = leak(Client::default());
+    TemperatureDriver::set_client(sensor, client);
+    NineDof::set_client(sensor, client);
+    sensor.set_self_test_client(client);
+    Fixture {
+        sensor,
+        accelerometer,
+        magnetometer,
+        client,
+    }
+}
+
+fn configure(fixture: &Fixture, temperature: bool) -> Result<(), ErrorCode> {
+    fixture.sensor.configure(
+        Lsm303AccelDataRate::DataRate25Hz,
+        false,
+        Lsm303Scale::Scale2G,
+        false,
+        temperature,
+        Lsm303MagnetoDataRate::DataRate3_0Hz,
+        Lsm303Range::Range4_7G,
+    )
+}
+
+/// Completes the configuration sequence: power mode and scale on the
+/// accelerometer, then temperature/data rate and range on the magnetometer.
+fn finish_configuration(fixture: &Fixture) {
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.magnetometer.complete());
+    assert!(fixture.magnetometer.complete());
+    assert!(!fixture.accelerometer.is_pending());
+    assert!(!fixture.magnetometer.is_pending());
+}
+
+#[test]
+fn temperature_read_requires_enabled_sensor() {
+    let fixture = setup();
+    assert_eq!(fixture.sensor.read_temperature(), Err(ErrorCode::OFF));
+
+    configure(&fixture, false).unwrap();
+    finish_configuration(&fixture);
+    assert_eq!(fixture.sensor.read_temperature(), Err(ErrorCode::OFF));
+    assert!(!fixture.magnetometer.is_pending());
+}
+
+#[test]
+fn temperature_read_during_configuration() {
+    let fixture = setup();
+    configure(&fixture, true).unwrap();
+
+    // The temperature sensor is being enabled.
+    assert!(fixture.accelerometer.complete());
+    assert_eq!(fixture.sensor.read_temperature(), Err(ErrorCode::BUSY));
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.magnetometer.complete());
+    fixture.magnetometer.take_written();
+    // The range is still being written.
+    assert_eq!(fixture.sensor.read_temperature(), Err(ErrorCode::BUSY));
+    assert!(fixture.magnetometer.complete());
+
+    fixture.magnetometer.push_response(Ok(vec![0x01, 0x00]));
+    assert_eq!(fixture.sensor.read_temperature(), Ok(()));
+    assert!(fixture.magnetometer.complete());
+
+    let written = fixture.magnetometer.take_written();
+    assert_eq!(written[written.len() - 1], vec![0x31]);
+    assert_eq!(fixture.client.temperatures.take().len(), 1);
+}
+
+#[test]
+fn temperature_read_after_failed_enable() {
+    let fixture = setup();
+    configure(&fixture, true).unwrap();
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.accelerometer.complete());
+    fixture
+        .magnetometer
+        .push_response(Err(kernel::hil::i2c::Error::DataNak));
+    assert!(fixture.magnetometer.complete());
+    assert!(fixture.magnetometer.complete());
+    assert!(!fixture.magnetometer.is_pending());
+    // The chip may not have enabled the temperature sensor.
+    assert_eq!(fixture.sensor.read_temperature(), Err(ErrorCode::OFF));
+
+    configure(&fixture, true).unwrap();
+    finish_configuration(&fixture);
+    fixture.magnetometer.push_response(Ok(vec![0x01, 0x00]));
+    assert_eq!(fixture.sensor.read_temperature(), Ok(()));
+    assert!(fixture.magnetometer.complete());
+    assert_eq!(fixture.client.temperatures.take(), vec![Ok(1_900)]);
+}
+
+#[test]
+fn temperature_read_failure() {
+    let fixture = setup();
+    configure(&fixture, true).unwrap();
+    finish_configuration(&fixture);
+
+    fixture
+        .magnetometer
+        .push_response(Err(kernel::hil::i2c::Error::DataNak));
+    assert_eq!(fixture.sensor.read_temperature(), Ok(()));
+    assert!(fixture.magnetometer.complete());
+
+    assert_eq!(
+        fixture.client.temperatures.take(),
+        vec![Err(ErrorCode::NOACK)]
+    );
+}
+
+/// Reads the temperature with the magnetometer answering `response`.
+fn read_temperature(fixture: &Fixture, response: [u8; 2]) -> Vec<Result<i32, ErrorCode>> {
+    fixture.magnetometer.push_response(Ok(response.to_vec()));
+    assert_eq!(fixture.sensor.read_temperature(), Ok(()));
+    assert!(fixture.magnetometer.complete());
+    fixture.client.temperatures.take()
+}
+
+#[test]
+fn temperature_in_hundredths_with_offset() {
+    let fixture = setup();
+    configure(&fixture, true).unwrap();
+    finish_configuration(&fixture);
+
+    // 16 LSB above the default offset of 17 deg C, at 8 LSB per deg C.
+    assert_eq!(read_temperature(&fixture, [0x01, 0x00]), vec![Ok(1_900)]);
+    // The output is signed, the low 4 bits are not part of it.
+    assert_eq!(read_temperature(&fixture, [0xFF, 0x8F]), vec![Ok(1_600)]);
+    assert_eq!(read_temperature(&fixture, [0x00, 0x10]), vec![Ok(1_712)]);
+
+    fixture.sensor.set_temperature_offset(-250);
+    assert_eq!(read_temperature(&fixture, [0x01, 0x00]), vec![Ok(-50)]);
+}
+
+#[test]
+fn temperature_offset_command() {
+    let board = Board::new();
+    let fixture = setup_on(&board, None);
+    configure(&fixture, true).unwrap();
+    finish_configuration(&fixture);
+
+    let apps = board.load_apps(1);
+    apps.command(0, DRIVER_NUM, 9, (-1_000isize) as usize, 0);
+    apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+    assert_eq!(read_temperature(&fixture, [0x00, 0x00]), vec![Ok(-1_000)]);
+}
+
+/// Runs the presence check for one app, with the magnetometer answering
+/// `response`, and returns the upcall the app got.
+fn check_presence(
+    board: &Board,
+    fixture: &Fixture,
+    response: u8,
+) -> Vec<(usize, usize, [usize; 3])> {
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+    fixture.magnetometer.push_response(Ok(vec![response]));
+    assert!(fixture.magnetometer.complete());
+    apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+    apps.take_upcalls(0)
+}
+
+#[test]
+fn present_with_default_identity() {
+    let board = Board::new();
+    let fixture = setup_on(&board, None);
+    assert_eq!(
+        check_presence(&board, &fixture, 60),
+        vec![(DRIVER_NUM, 0, [1, 0, 0])]
+    );
+    assert_eq!(fixture.magnetometer.take_written(), vec![vec![0x0F]]);
+}
+
+#[test]
+fn present_with_configured_identity() {
+    let board = Board::new();
+    let fixture = setup_on(&board, Some((0x4F, 0x40)));
+    assert_eq!(
+        check_presence(&board, &fixture, 0x40),
+        vec![(DRIVER_NUM, 0, [1, 0, 0])]
+    );
+    assert_eq!(fixture.magnetometer.take_written(), vec![vec![0x4F]]);
+}
+
+#[test]
+fn absent_with_default_identity_on_configured_sensor() {
+    let board = Board::new();
+    let fixture = setup_on(&board, Some((0x4F, 0x40)));
+    assert_eq!(
+        check_presence(&board, &fixture, 60),
+        vec![(DRIVER_NUM, 0, [0, 0, 0])]
+    );
+}
+
+fn setup_data_ready() -> (Fixture, &'static RecordingPin<'static>) {
+    let pin = leak(RecordingPin::new(0, leak(PinLog::default())));
+    (setup_with_pin(&Board::new(), None, Some(pin)), pin)
+}
+
+/// Completes a magnetometer read returning X = 400, Z = -400, Y = 710,
+/// which the 4.7 gauss range scales to 100, -100 and 200.
+fn complete_magnetometer_read(fixture: &Fixture) {
+    fixture
+        .magnetometer
+        .push_response(Ok(vec![0x01, 0x90, 0xFE, 0x70, 0x02, 0xC6]));
+    assert!(fixture.magnetometer.complete());
+    assert_eq!(fixture.magnetometer.take_written(), vec![vec![0x03]]);
+}
+
+#[test]
+fn data_ready_reads_magnetometer() {
+    let (fixture, pin) = setup_data_ready();
+    assert!(!pin.trigger());
+
+    configure(&fixture, false).unwrap();
+    finish_configuration(&fixture);
+    fixture.magnetometer.take_written();
+    assert!(fixture.client.samples.take().is_empty());
+
+    for _ in 0..2 {
+        assert!(pin.trigger());
+        complete_magnetometer_read(&fixture);
+    }
+    assert!(!fixture.magnetometer.is_pending());
+    assert_eq!(
+        fixture.client.samples.take(),
+        vec![(100, 200, (-100isize) as usize); 2]
+    );
+}
+
+#[test]
+fn data_ready_while_busy_is_coalesced() {
+    let (fixture, pin) = setup_data_ready();
+    configure(&fixture, false).unwrap();
+
+    // Interrupts during the configuration result in one read at its end.
+    assert!(pin.trigger());
+    assert!(fixture.accelerometer.complete());
+    assert!(pin.trigger());
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.magnetometer.complete());
+    assert!(fixture.magnetometer.complete());
+    fixture.magnetometer.take_written();
+    assert!(fixture.magnetometer.complete());
+    assert!(!fixture.magnetometer.is_pending());
+    assert_eq!(fixture.client.samples.take().len(), 1);
+}
+
+#[test]
+fn data_ready_already_high_after_configuration() {
+    let (fixture, pin) = setup_data_ready();
+    pin.set();
+    configure(&fixture, false).unwrap();
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.accelerometer.complete());
+    assert!(fixture.magnetometer.complete());
+    assert!(fixture.magnetometer.complete());
+    fixture.magnetometer.take_written();
+
+    assert!(fixture.magnetometer.is_pending());
+    assert!(fixture.magnetometer.complete());
+    assert_eq!(fixture.client.samples.take().len(), 1);
+}
+
+/// An accelerometer status and sample, new if `ready`.
+fn accelerometer_sample(ready: bool, sample: [i16; 3]) -> Vec<u8> {
+    let mut response = vec![if ready { 0x08 } else { 0x00 }];
+    for value in sample {
+        response.extend_from_slice(&value.to_le_bytes());
+    }
+    response
+}
+
+/// Scripts a self-test: the first sample of each phase is discarded, the
+/// magnetometer answers `field` as X, Y and Z.
+fn script_self_test(fixture: &Fixture, baseline: [i16; 3], self_test: [i16; 3], field: [i16; 3]) {
+    for sample in [baseline, self_test] {
+        fixture
+            .accelerometer
+            .push_response(Ok(accelerometer_sample(true, [0x7FFF; 3])));
+        for _ in 0..4 {
+            fixture
+                .accelerometer
+                .push_response(Ok(accelerometer_sample(true, sample)));
+        }
+        // CTRL_REG4_A write
+        fixture.accelerometer.push_response(Ok(vec![]));
+    }
+    let [x, y, z] = field.map(i16::to_be_bytes);
+    fixture.magnetometer.push_response(Ok([x, z, y].concat()));
+}
+
+/// Completes the transfers of both devices until none is left.
+fn run_transfers(fixture: &Fixture) {
+    while fixture.accelerometer.complete() || fixture.magnetometer.complete() {}
+}
+
+fn configured_self_test_fixture(board: &Board) -> Fixture {
+    let fixture = setup_on(board, None);
+    configure(&fixture, false).unwrap();
+    finish_configuration(&fixture);
+    fixture.accelerometer.take_written();
+    fixture.magnetometer.take_written();
+    fixture
+}
+
+/// At +/-2 g, 8192 LSB are 500 mg. At 4.7 gauss, 200 LSB on X are 500 mG.
+const BASELINE: [i16; 3] = [0, 0, 16384];
+const SELF_TEST: [i16; 3] = [8192, 8192, 24576];
+const FIELD: [i16; 3] = [200, 0, 0];
+
+#[test]
+fn self_test_passes() {
+    let board = Board::new();
+    let fixture = configured_self_test_fixture(&board);
+    // a status read without new data is repeated
+    fixture
+        .accelerometer
+        .push_response(Ok(accelerometer_sample(false, [0; 3])));
+    script_self_test(&fixture, BASELINE, SELF_TEST, FIELD);
+
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 10, 0, 0);
+    apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+    run_transfers(&fixture);
+    apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+
+    let passed = SELF_TEST_ACCELEROMETER | SELF_TEST_MAGNETOMETER;
+    assert_eq!(
+        fixture.client.self_tests.take(),
+        vec![Ok(SelfTestResult {
+            passed,
+            accel_delta: [500; 3],
+            magnetic_field: 500,
+        })]
+    );
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            0,
+            [passed as usize, 500 | (500 << 16), 500 | (500 << 16)]
+        )]
+    );
+
+    let status = vec![0xA7];
+    let mut expected = vec![status.clone(); 6];
+    expected.push(vec![0x23, 0x02]);
+    expected.extend(vec![status; 5]);
+    expected.push(vec![0x23, 0x00]);
+    assert_eq!(fixture.accelerometer.take_written(), expected);
+    assert_eq!(fixture.magnetometer.take_written(), vec![vec![0x03]]);
+}
+
+#[test]
+fn self_test_accelerometer_fails() {
+    let fixture = configured_self_test_fixture(&Board::new());
+    // 16 LSB are under 1 mg
+    script_self_test(&fixture, BASELINE, [16, 0, 16400], FIELD);
+    assert_eq!(fixture.sensor.run_self_test(), Ok(()));
+    run_transfers(&fixture);
+
+    assert_eq!(
+        fixture.client.self_tests.take(),
+        vec![Ok(SelfTestResult {
+            passed: SELF_TEST_MAGNETOMETER,
+            accel_delta: [0; 3],
+            magnetic_field: 500,
+        })]
+    );
+}
+
+#[test]
+fn self_test_magnetometer_fails() {
+    let fixture = configured_self_test_fixture(&Board::new());
+    // 40 LSB on X and 71 on Z are 100 and 200 mG
+    script_self_test(&fixture, BASELINE, SELF_TEST, [40, 0, 71]);
+    assert_eq!(fixture.sensor.run_self_test(), Ok(()));
+    run_transfers(&fixture);
+    assert_eq!(
+        fixture.client.self_tests.take(),
+        vec![Ok(SelfTestResult {
+            passed: SELF_TEST_ACCELEROMETER,
+            accel_delta: [500; 3],
+            magnetic_field: 223,
+        })]
+    );
+
+    // the bounds are configurable
+    assert_eq!(
+        fixture.sensor.set_magnetic_field_bounds(300, 200),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(fixture.sensor.set_magnetic_field_bounds(200, 300), Ok(()));
+    script_self_test(&fixture, BASELINE, SELF_TEST, [40, 0, 71]);
+    assert_eq!(fixture.sensor.run_self_test(), Ok(()));
+    run_transfers(&fixture);
+    assert_eq!(
+        fixture.client.self_tests.take()[0].map(|result| result.passed),
+        Ok(SELF_TEST_ACCELEROMETER | SELF_TEST_MAGNETOMETER)
+    );
+}
+
+#[test]
+fn self_test_refused_while_busy() {
+    let fixture = setup();
+    configure(&fixture, false).unwrap();
+    assert_eq!(fixture.sensor.run_self_test(), Err(ErrorCode::BUSY));
+    finish_configuration(&fixture);
+
+    assert_eq!(fixture.sensor.run_self_test(), Ok(()));
+    assert_eq!(fixture.sensor.run_self_test(), Err(ErrorCode::BUSY));
+    assert_eq!(fixture.sensor.read_accelerometer(), Err(ErrorCode::BUSY));
+    assert_eq!(fixture.sensor.read_magnetometer(), Err(ErrorCode::BUSY));
+}
+
+#[test]
+fn self_test_error_restores_control_register() {
+    let fixture = configured_self_test_fixture(&Board::new());
+    for _ in 0..5 {
+        fixture
+            .accelerometer
+            .push_response(Ok(accelerometer_sample(true, BASELINE)));
+    }
+    fixture.accelerometer.push_response(Ok(vec![]));
+    fixture
+        .accelerometer
+        .push_response(Err(kernel::hil::i2c::Error::DataNak));
+    assert_eq!(fixture.sensor.run_self_test(), Ok(()));
+    run_transfers(&fixture);
+
+    assert_eq!(
+        fixture.client.self_tests.take(),
+        vec![Err(ErrorCode::NOACK)]
+    );
+    let written = fixture.accelerometer.take_written();
+    assert_eq!(written.len(), 8);
+    assert_eq!(written[7], vec![0x23, 0x00]);
+    assert!(fixture.magnetometer.take_written().is_empty());
+}
+
+/// Attaches a history of `capacity` samples timed by the returned clock.
+fn attach_history(
+    fixture: &Fixture,
+    capacity: usize,
+) -> (
+    &'static SampleHistory<'static>,
+    &'static FakeAlarm<'static, Freq1KHz>,
+) {
+    let clock = leak(FakeAlarm::new());
+    let history = leak(SampleHistory::new(
+        clock,
+        Box::leak(vec![HistorySample::default(); capacity].into_boxed_slice()),
+    ));
+    fixture.sensor.set_history(history);
+    (history, clock)
+}
+
+fn history_contents(history: &SampleHistory) -> Vec<(u32, SensorKind, [i16; 3])> {
+    let mut samples = Vec::new();
+    history.for_each(|sample| samples.push((sample.timestamp_ms, sample.sensor, sample.xyz)));
+    samples
+}
+
+/// Reads the accelerometer, answering the raw `(x, y, z)`.
+fn read_accelerometer(fixture: &Fixture, (x, y, z): (i16, i16, i16)) {
+    let response = [x, y, z].iter().flat_map(|v| v.to_le_bytes()).collect();
+    fixture.accelerometer.push_response(Ok(response));
+    assert_eq!(fixture.sensor.read_accelerometer(), Ok(()));
+    assert!(fixture.accelerometer.complete());
+}
+
+/// Reads the magnetometer, answering the raw `(x, y, z)` in the order of its
+/// registers.
+fn read_magnetometer(fixture: &Fixture, (x, y, z): (i16, i16, i16)) {
+    let response = [x, z, y].iter().flat_map(|v| v.to_be_bytes()).collect();
+    fixture.magnetometer.push_response(Ok(response));
+    assert_eq!(fixture.sensor.read_magnetometer(), Ok(()));
+    assert!(fixture.magnetometer.complete());
+}
+
+#[test]
+fn history_records_both_sensors_in_order() {
+    let fixture = setup();
+    assert_eq!(
+        fixture.sensor.set_history_enabled(true),
+        Err(ErrorCode::NOSUPPORT)
+    );
+    let (history, clock) = attach_history(&fixture, 3);
+
+    read_accelerometer(&fixture, (1, 2, 3));
+    assert!(history.is_empty());
+
+    assert_eq!(fixture.sensor.set_history_enabled(true), Ok(()));
+    clock.advance(5);
+    read_accelerometer(&fixture, (-1, -2, -3));
+    clock.advance(5);
+    read_magnetometer(&fixture, (0x0102, -0x0304, 0x0506));
+    assert_eq!(
+        history_contents(history),
+        vec![
+            (5, SensorKind::Accelerometer, [-1, -2, -3]),
+            (10, SensorKind::Magnetometer, [0x0102, -0x0304, 0x0506]),
+        ]
+    );
+
+    // The oldest samples make room for the new ones.
+    for x in 1..=3 {
+        clock.advance(5);
+        read_accelerometer(&fixture, (x, 0, 0));
+    }
+    assert_eq!(
+        history_contents(history),
+        vec![
+            (15, SensorKind::Accelerometer, [1, 0, 0]),
+            (20, SensorKind::Accelerometer, [2, 0, 0]),
+            (25, SensorKind::Accelerometer, [3, 0, 0]),
+        ]
+    );
+
+    // Failed reads are not recorded, the client still gets its callback.
+    fixture
+        .accelerometer
+        .push_response(Err(kernel::hil::i2c::Error::DataNak));
+    assert_eq!(fixture.sensor.read_accelerometer(), Ok(()));
+    assert!(fixture.accelerometer.complete());
+    assert_eq!(history_contents(history)[2].0, 25);
+    assert_eq!(fixture.client.samples.take().len(), 7);
+}
+
+/// Records the newest sample of the history at each `NineDof` callback.
+struct HistoryWatcher {
+    history: &'static SampleHistory<'static>,
+    newest: RefCell<Vec<Option<SensorKind>>>,
+}
+
+impl NineDofClient for HistoryWatcher {
+    fn callback(&self, _x: usize, _y: usize, _z: usize) {
+        let mut newest = None;
+        self.history.for_each(|sample| newest = Some(sample.sensor));
+        self.newest.borrow_mut().push(newest);
+    }
+}
+
+#[test]
+fn history_sample_precedes_nine_dof_callback() {
+    let fixture = setup();
+    let (history, _) = attach_history(&fixture, 4);
+    let watcher = leak(HistoryWatcher {
+        history,
+        newest: RefCell::new(Vec::new()),
+    });
+    NineDof::set_client(fixture.sensor, watcher);
+    assert_eq!(fixture.sensor.set_history_enabled(true), Ok(()));
+
+    read_magnetometer(&fixture, (1, 1, 1));
+    read_accelerometer(&fixture, (1, 1, 1));
+    assert_eq!(
+        watcher.newest.take(),
+        vec![
+            Some(SensorKind::Magnetometer),
+            Some(SensorKind::Accelerometer)
+        ]
+    );
+}
+
+#[test]
+fn history_commands() {
+    let board = Board::new();
+    let fixture = setup_on(&board, None);
+    let (_, clock) = attach_history(&fixture, 4);
+    let apps = board.load_apps(1);
+    let run = || apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+
+    apps.command(0, DRIVER_NUM, 12, 1, 0);
+    run();
+    clock.advance(0x0102);
+    read_accelerometer(&fixture, (1, -1, 0x100));
+    clock.advance(1);
+    read_magnetometer(&fixture, (-2, 2, 0));
+
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 64);
+    apps.command(0, DRIVER_NUM, 13, 0, 0);
+    run();
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Success,
+            SyscallReturn::AllowReadWriteSuccess(..),
+            SyscallReturn::SuccessU32(2)
+        ]
+    ));
+    assert_eq!(
+        apps.read_memory(0, 24),
+        vec![
+            0x02, 0x01, 0x00, 0x00, 1, 0, 1, 0, 0xFF, 0xFF, 0x00, 0x01, //
+            0x03, 0x01, 0x00, 0x00, 2, 0, 0xFE, 0xFF, 2, 0, 0, 0,
+        ]
+    );
+}
+
+#[test]
+fn restarted_app_does_not_get_the_upcall_of_its_previous_instance() {
+    let board = Board::new();
+    let fixture = setup_on(&board, None);
+    let apps = board.load_apps(1);
+    let run = || apps.run(&[(DRIVER_NUM, fixture.sensor as &dyn SyscallDriver)]);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run();
+    apps.take_returns(0);
+
+    // The app crashes while the sensor is busy, and its new instance waits
+    // for the operation to finish.
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run();
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [_, SyscallReturn::Failure(ErrorCode::NOMEM)]
+    ));
+    fixture.magnetometer.push_response(Ok(vec![60]));
+    assert!(fixture.magnetometer.complete());
+    run();
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // Then it owns the sensor.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run();
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+    fixture.magnetometer.push_response(Ok(vec![60]));
+    assert!(fixture.magnetometer.complete());
+    run();
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [1, 0, 0])]);
+}
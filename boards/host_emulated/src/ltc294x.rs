@@ -0,0 +1,481 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! LTC294X over a scripted I2C device.
+
+use std::cell::RefCell;
+
+use capsules_extra::ltc294x::{
+    ChipModel, InterruptPinConf, LTC294XClient, LTC294XDriver, VBatAlert, BUF_LEN, DRIVER_NUM,
+    LTC294X,
+};
+use kernel::errorcode::into_statuscode;
+use kernel::hil::gpio;
+use kernel::hil::i2c;
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, Apps, Board, ScriptedI2CDevice};
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Interrupt(u8),
+    Status(u8),
+    Temperature(u16),
+    Error(ErrorCode),
+}
+
+#[derive(Default)]
+struct Client {
+    events: RefCell<Vec<Event>>,
+    temperatures: RefCell<Vec<Result<i32, ErrorCode>>>,
+}
+
+fn status_byte(
+    undervolt_lockout: bool,
+    vbat_alert: bool,
+    charge_alert_low: bool,
+    charge_alert_high: bool,
+    accumulated_charge_overflow: bool,
+) -> u8 {
+    (undervolt_lockout as u8)
+        | (vbat_alert as u8) << 1
+        | (charge_alert_low as u8) << 2
+        | (charge_alert_high as u8) << 3
+        | (accumulated_charge_overflow as u8) << 5
+}
+
+impl TemperatureClient for Client {
+    fn callback(&self, value: Result<i32, ErrorCode>) {
+        self.temperatures.borrow_mut().push(value);
+    }
+}
+
+impl LTC294XClient for Client {
+    fn interrupt(&self, uvlock: bool, vbata: bool, ca_low: bool, ca_high: bool, accover: bool) {
+        self.events.borrow_mut().push(Event::Interrupt(status_byte(
+            uvlock, vbata, ca_low, ca_high, accover,
+        )));
+    }
+
+    fn status(&self, uvlock: bool, vbata: bool, ca_low: bool, ca_high: bool, accover: bool) {
+        self.events.borrow_mut().push(Event::Status(status_byte(
+            uvlock, vbata, ca_low, ca_high, accover,
+        )));
+    }
+
+    fn charge(&self, _charge: u16) {}
+    fn charge_uah(&self, _uah: Result<i32, ErrorCode>) {}
+    fn voltage(&self, _voltage: u16) {}
+    fn current(&self, _current: u16) {}
+    fn temperature(&self, raw: u16) {
+        self.events.borrow_mut().push(Event::Temperature(raw));
+    }
+    fn done(&self) {}
+
+    fn error(&self, error: ErrorCode) {
+        self.events.borrow_mut().push(Event::Error(error));
+    }
+}
+
+fn setup() -> (
+    &'static LTC294X<'static, ScriptedI2CDevice<'static>>,
+    &'static ScriptedI2CDevice<'static>,
+    &'static Client,
+) {
+    let i2c = leak(ScriptedI2CDevice::new());
+    let ltc294x = leak(LTC294X::new(i2c, None, leak_buffer(BUF_LEN)));
+    i2c.set_client(ltc294x);
+    let client = leak(Client::default());
+    ltc294x.set_client(client);
+    TemperatureDriver::set_client(ltc294x, client);
+    (ltc294x, i2c, client)
+}
+
+#[test]
+fn interrupt_reads_status() {
+    let (ltc294x, i2c, client) = setup();
+
+    gpio::Client::fired(ltc294x);
+    i2c.push_response(Ok(vec![0x08]));
+    assert!(i2c.complete());
+
+    assert_eq!(client.events.take(), vec![Event::Interrupt(0x08)]);
+    assert!(!i2c.is_pending());
+}
+
+#[test]
+fn interrupt_while_busy_is_queued() {
+    let (ltc294x, i2c, client) = setup();
+
+    assert_eq!(ltc294x.read_status(), Ok(()));
+    gpio::Client::fired(ltc294x);
+    // The requested status read is not mistaken for the interrupt one.
+    i2c.push_response(Ok(vec![0x01]));
+    assert!(i2c.complete());
+    assert_eq!(client.events.take(), vec![Event::Status(0x01)]);
+
+    assert!(i2c.is_pending());
+    i2c.push_response(Ok(vec![0x24]));
+    assert!(i2c.complete());
+    assert_eq!(client.events.take(), vec![Event::Interrupt(0x24)]);
+    assert!(!i2c.is_pending());
+}
+
+#[test]
+fn refused_transfer_is_returned() {
+    let (ltc294x, i2c, client) = setup();
+
+    i2c.fail_next_start(i2c::Error::AddressNak);
+    assert_eq!(ltc294x.read_status(), Err(ErrorCode::NOACK));
+    i2c.fail_next_start(i2c::Error::Busy);
+    assert_eq!(ltc294x.shutdown(), Err(ErrorCode::BUSY));
+    assert!(!i2c.is_pending());
+    assert!(client.events.take().is_empty());
+
+    // The driver is idle again, with its buffer.
+    assert_eq!(ltc294x.read_status(), Ok(()));
+    i2c.push_response(Ok(vec![0x01]));
+    assert!(i2c.complete());
+    assert_eq!(client.events.take(), vec![Event::Status(0x01)]);
+}
+
+#[test]
+fn failed_transfer_is_not_decoded() {
+    let (ltc294x, i2c, client) = setup();
+
+    assert_eq!(ltc294x.read_status(), Ok(()));
+    gpio::Client::fired(ltc294x);
+    i2c.push_response(Err(i2c::Error::AddressNak));
+    assert!(i2c.complete());
+    assert_eq!(client.events.take(), vec![Event::Error(ErrorCode::NOACK)]);
+
+    // The interrupt status is still read afterwards, and can fail too.
+    assert!(i2c.is_pending());
+    i2c.push_response(Err(i2c::Error::Timeout));
+    assert!(i2c.complete());
+    assert_eq!(client.events.take(), vec![Event::Error(ErrorCode::FAIL)]);
+    assert!(!i2c.is_pending());
+}
+
+#[test]
+fn temperature_sensor() {
+    let (ltc294x, i2c, client) = setup();
+    assert_eq!(ltc294x.read_temperature(), Err(ErrorCode::NOSUPPORT));
+
+    ltc294x.set_chip_model(ChipModel::LTC2942);
+    assert_eq!(ltc294x.read_temperature(), Ok(()));
+    // The read starts at the status register, the temperature is at 0x0C.
+    let mut registers = vec![0; 14];
+    registers[12..14].copy_from_slice(&[0x7F, 0x40]);
+    i2c.push_response(Ok(registers));
+    assert!(i2c.complete());
+
+    ltc294x.set_chip_model(ChipModel::LTC2943);
+    assert_eq!(ltc294x.read_temperature(), Ok(()));
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+
+    // Readings and errors only go to the temperature client.
+    assert_eq!(
+        client.temperatures.take(),
+        vec![Ok(2_509), Err(ErrorCode::NOACK)]
+    );
+    assert!(client.events.take().is_empty());
+}
+
+#[test]
+fn shutdown_and_wake_keep_the_configuration() {
+    let (ltc294x, i2c, _) = setup();
+
+    // Before any configuration, the power-on value is kept.
+    assert_eq!(ltc294x.shutdown(), Ok(()));
+    assert!(i2c.complete());
+    assert_eq!(i2c.take_written(), vec![vec![0x01, 0x3D]]);
+
+    // Charge complete interrupt, prescaler 2^5, 2.9 V alert.
+    assert_eq!(
+        ltc294x.configure(
+            InterruptPinConf::ChargeCompleteMode,
+            5,
+            VBatAlert::Threshold2V9,
+            50,
+        ),
+        Ok(())
+    );
+    assert!(i2c.complete());
+    assert_eq!(ltc294x.shutdown(), Ok(()));
+    assert!(i2c.complete());
+    assert_eq!(ltc294x.wake(), Ok(()));
+    assert!(i2c.complete());
+    assert_eq!(
+        i2c.take_written(),
+        vec![vec![0x01, 0xAA], vec![0x01, 0xAB], vec![0x01, 0xAA]]
+    );
+
+    // A failed write does not change the value the bit is set in.
+    assert_eq!(
+        ltc294x.configure(InterruptPinConf::Disabled, 0, VBatAlert::Off, 50),
+        Ok(())
+    );
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    assert_eq!(ltc294x.shutdown(), Ok(()));
+    assert!(i2c.complete());
+    assert_eq!(i2c.take_written(), vec![vec![0x01, 0x00], vec![0x01, 0xAB]]);
+}
+
+type Driver = LTC294XDriver<'static, ScriptedI2CDevice<'static>>;
+
+fn setup_driver(board: &Board) -> (&'static ScriptedI2CDevice<'static>, &'static Driver) {
+    let (i2c, _, driver) = setup_chip_and_driver(board);
+    (i2c, driver)
+}
+
+fn setup_chip_and_driver(
+    board: &Board,
+) -> (
+    &'static ScriptedI2CDevice<'static>,
+    &'static LTC294X<'static, ScriptedI2CDevice<'static>>,
+    &'static Driver,
+) {
+    let i2c = leak(ScriptedI2CDevice::new());
+    let ltc294x = leak(LTC294X::new(i2c, None, leak_buffer(BUF_LEN)));
+    i2c.set_client(ltc294x);
+    let driver = leak(LTC294XDriver::new(ltc294x, board.create_grant(DRIVER_NUM)));
+    ltc294x.set_client(driver);
+    (i2c, ltc294x, driver)
+}
+
+fn run(apps: &Apps, driver: &'static Driver) {
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+}
+
+#[test]
+fn driver_is_owned_by_the_first_process() {
+    let board = Board::new();
+    let (i2c, driver) = setup_driver(&board);
+    let apps = board.load_apps(2);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.subscribe(1, DRIVER_NUM, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+    apps.take_returns(1);
+
+    // Get status from the first process.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+
+    // The second process can only check that the driver exists.
+    apps.command(1, DRIVER_NUM, 0, 0, 0);
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(
+        apps.take_returns(1)[..],
+        [
+            SyscallReturn::Success,
+            SyscallReturn::Failure(ErrorCode::NOMEM)
+        ]
+    ));
+
+    // The status goes to the owner only.
+    i2c.push_response(Ok(vec![0x01]));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [1, 1, ChipModel::LTC2941 as usize])]
+    );
+    assert!(apps.take_upcalls(1).is_empty());
+}
+
+#[test]
+fn ownership_passes_on_when_the_owner_exits() {
+    let board = Board::new();
+    let (i2c, driver) = setup_driver(&board);
+    let apps = board.load_apps(2);
+    apps.subscribe(1, DRIVER_NUM, 0);
+    run(&apps, driver);
+    apps.take_returns(1);
+
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    i2c.push_response(Ok(vec![0x00]));
+    assert!(i2c.complete());
+    apps.terminate(0);
+
+    apps.command(1, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(apps.take_returns(1)[..], [SyscallReturn::Success]));
+    i2c.push_response(Ok(vec![0x08]));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 0, [1, 0x08, ChipModel::LTC2941 as usize])]
+    );
+}
+
+#[test]
+fn driver_reports_bus_errors() {
+    let board = Board::new();
+    let (i2c, driver) = setup_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+
+    // A detached chip does not acknowledge its address.
+    i2c.fail_next_start(i2c::Error::AddressNak);
+    apps.command(0, DRIVER_NUM, 6, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::NOACK)]
+    ));
+    assert!(apps.take_upcalls(0).is_empty());
+
+    apps.command(0, DRIVER_NUM, 6, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            0,
+            [8, into_statuscode(Err(ErrorCode::NOACK)), 0]
+        )]
+    );
+}
+
+#[test]
+fn restarted_owner_does_not_get_the_upcall_of_its_previous_instance() {
+    let board = Board::new();
+    let (i2c, driver) = setup_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+
+    // The owner crashes while the status is read, and its new instance
+    // waits for the read to finish.
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [_, SyscallReturn::Failure(ErrorCode::NOMEM)]
+    ));
+    i2c.push_response(Ok(vec![0x01]));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // Then it owns the driver.
+    apps.command(0, DRIVER_NUM, 1, 0, 0);
+    run(&apps, driver);
+    assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+    i2c.push_response(Ok(vec![0x08]));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [1, 8, ChipModel::LTC2941 as usize])]
+    );
+}
+
+#[test]
+fn charge_too_large_for_microampere_hours_is_an_error() {
+    let board = Board::new();
+    let (i2c, ltc294x, driver) = setup_chip_and_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+
+    // With a 1 uOhm sense resistor a full register is far beyond i32::MAX
+    // microampere-hours.
+    ltc294x.set_sense_resistor_uohms(1);
+    for command in [11, 6] {
+        apps.command(0, DRIVER_NUM, command, 0, 0);
+        run(&apps, driver);
+        assert!(matches!(apps.take_returns(0)[..], [SyscallReturn::Success]));
+        i2c.push_response(Ok(vec![0x00, 0x3C, 0xFF, 0xFF]));
+        assert!(i2c.complete());
+    }
+    run(&apps, driver);
+
+    // The raw value is still available.
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![
+            (DRIVER_NUM, 0, [8, into_statuscode(Err(ErrorCode::SIZE)), 0]),
+            (DRIVER_NUM, 0, [2, 0xFFFF, 0]),
+        ]
+    );
+}
+
+#[test]
+fn temperature_goes_to_whoever_asked() {
+    let board = Board::new();
+    let (i2c, ltc294x, driver) = setup_chip_and_driver(&board);
+    let sensor = leak(Client::default());
+    TemperatureDriver::set_client(ltc294x, sensor);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.command(0, DRIVER_NUM, 10, 2, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+    let mut registers = vec![0; 14];
+    registers[12..14].copy_from_slice(&[0x7F, 0x40]);
+
+    // A reading for the app.
+    apps.command(0, DRIVER_NUM, 12, 0, 0);
+    run(&apps, driver);
+    i2c.push_response(Ok(registers.clone()));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 0, [7, 0x7F40, 2_509])]
+    );
+    assert!(sensor.temperatures.take().is_empty());
+
+    // A reading and a failure for the kernel user.
+    assert_eq!(ltc294x.read_temperature(), Ok(()));
+    i2c.push_response(Ok(registers));
+    assert!(i2c.complete());
+    assert_eq!(ltc294x.read_temperature(), Ok(()));
+    i2c.push_response(Err(i2c::Error::Timeout));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        sensor.temperatures.take(),
+        vec![Ok(2_509), Err(ErrorCode::FAIL)]
+    );
+    assert!(apps.take_upcalls(0).is_empty());
+
+    // A failure for the app.
+    apps.command(0, DRIVER_NUM, 12, 0, 0);
+    run(&apps, driver);
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    run(&apps, driver);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            0,
+            [8, into_statuscode(Err(ErrorCode::NOACK)), 0]
+        )]
+    );
+    assert!(sensor.temperatures.take().is_empty());
+}
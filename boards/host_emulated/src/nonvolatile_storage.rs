@@ -0,0 +1,1135 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! NonvolatileStorage over a RAM backed storage driver.
+
+use std::cell::{Cell, RefCell};
+
+use capsules_extra::nonvolatile_storage_driver::{
+    BatchClient, BatchEntry, LogFullPolicy, NonvolatileLog, NonvolatileLogClient,
+    NonvolatileStorage, NonvolatileStorageUser, Priority, StorageGeometry, BATCH_DESCRIPTOR_LEN,
+    DRIVER_NUM, LOG_HEADER_LEN, MAX_BATCH_ENTRIES, MAX_HIGH_PRIORITY_STREAK, MAX_KERNEL_STREAK,
+};
+use kernel::deferred_call::DeferredCallClient;
+use kernel::errorcode::into_statuscode;
+use kernel::hil::nonvolatile_storage::{
+    NonvolatileStorage as NonvolatileStorageHil, NonvolatileStorageClient,
+};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, Apps, Board, RamStorage};
+
+const USERSPACE_START: usize = 0x100;
+const USERSPACE_LENGTH: usize = 0x100;
+const KERNEL_START: usize = 0x000;
+const KERNEL_LENGTH: usize = 0x100;
+const GEOMETRY: StorageGeometry = StorageGeometry {
+    page_size: 0x40,
+    erase_size: 0x100,
+    total_size: 0x200,
+};
+
+#[derive(Debug, PartialEq)]
+enum Done {
+    Read(Vec<u8>, Result<(), ErrorCode>),
+    Write(usize, Result<(), ErrorCode>),
+}
+
+#[derive(Default)]
+struct KernelClient {
+    done: RefCell<Vec<Done>>,
+}
+
+impl NonvolatileStorageClient for KernelClient {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.done
+            .borrow_mut()
+            .push(Done::Read(buffer[..length].to_vec(), result));
+    }
+
+    fn write_done(&self, _buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.done.borrow_mut().push(Done::Write(length, result));
+    }
+}
+
+/// A kernel client writing one byte, its name, at its own address, which
+/// logs the writes it completed and writes again `repeat` times.
+struct NamedClient {
+    name: u8,
+    order: &'static RefCell<Vec<u8>>,
+    storage: OptionalCell<&'static NonvolatileStorageUser<'static>>,
+    repeat: Cell<usize>,
+}
+
+impl NamedClient {
+    fn write(&self) -> Result<(), ErrorCode> {
+        let storage = self.storage.get().unwrap();
+        storage.write(buffer_with(&[self.name]), self.name as usize, 1)
+    }
+}
+
+impl NonvolatileStorageClient for NamedClient {
+    fn read_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        _result: Result<(), ErrorCode>,
+    ) {
+    }
+
+    fn write_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        assert_eq!(result, Ok(()));
+        self.order.borrow_mut().push(self.name);
+        if self.repeat.get() > 0 {
+            self.repeat.set(self.repeat.get() - 1);
+            assert_eq!(self.write(), Ok(()));
+        }
+    }
+}
+
+/// A kernel client writing `chunks` chunks of `CHUNK` bytes from address 0
+/// with two buffers, queueing the next chunk from each completion.
+struct StreamingClient {
+    storage: &'static NonvolatileStorage<'static>,
+    ram: &'static RamStorage<'static>,
+    chunks: usize,
+    submitted: Cell<usize>,
+    completed: Cell<usize>,
+    /// Completions reported while the storage was idle although a chunk was
+    /// still queued.
+    idle_gaps: Cell<usize>,
+}
+
+const CHUNK: usize = 16;
+
+impl StreamingClient {
+    fn submit(&self, buffer: &'static mut [u8]) {
+        let chunk = self.submitted.get();
+        buffer[..CHUNK].fill(chunk as u8);
+        assert_eq!(self.storage.write(buffer, chunk * CHUNK, CHUNK), Ok(()));
+        self.submitted.set(chunk + 1);
+    }
+
+    fn start(&self) {
+        self.submit(leak_buffer(CHUNK));
+        self.submit(leak_buffer(CHUNK));
+    }
+}
+
+impl NonvolatileStorageClient for StreamingClient {
+    fn read_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        _result: Result<(), ErrorCode>,
+    ) {
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        assert_eq!((length, result), (CHUNK, Ok(())));
+        self.completed.set(self.completed.get() + 1);
+        if self.submitted.get() > self.completed.get() && !self.ram.is_pending() {
+            self.idle_gaps.set(self.idle_gaps.get() + 1);
+        }
+        if self.submitted.get() < self.chunks {
+            self.submit(buffer);
+        }
+    }
+}
+
+fn streaming_client(fixture: &Fixture, chunks: usize) -> &'static StreamingClient {
+    let client = leak(StreamingClient {
+        storage: fixture.storage,
+        ram: fixture.ram,
+        chunks,
+        submitted: Cell::new(0),
+        completed: Cell::new(0),
+        idle_gaps: Cell::new(0),
+    });
+    fixture.storage.set_client(client);
+    client
+}
+
+/// Sets up a user of `storage` with `priority`, whose client writes `name`.
+fn named_user(
+    storage: &'static NonvolatileStorage<'static>,
+    priority: Priority,
+    name: u8,
+    order: &'static RefCell<Vec<u8>>,
+) -> &'static NamedClient {
+    let user = leak(NonvolatileStorageUser::new(storage, priority));
+    user.setup();
+    let client = leak(NamedClient {
+        name,
+        order,
+        storage: OptionalCell::new(user),
+        repeat: Cell::new(0),
+    });
+    user.set_client(client);
+    client
+}
+
+struct Fixture {
+    storage: &'static NonvolatileStorage<'static>,
+    ram: &'static RamStorage<'static>,
+    client: &'static KernelClient,
+    apps: Apps,
+}
+
+fn setup() -> Fixture {
+    setup_apps(1)
+}
+
+/// A storage shared by `app_count` apps.
+fn setup_apps(app_count: usize) -> Fixture {
+    let board = Board::new();
+    let ram = leak(RamStorage::new(0x200));
+    let storage = leak(NonvolatileStorage::new(
+        ram,
+        board.create_grant(DRIVER_NUM),
+        USERSPACE_START,
+        USERSPACE_LENGTH,
+        KERNEL_START,
+        KERNEL_LENGTH,
+        GEOMETRY,
+        leak_buffer(capsules_extra::nonvolatile_storage_driver::BUF_LEN),
+    ));
+    ram.set_client(storage);
+    storage.register();
+    let client = leak(KernelClient::default());
+    storage.set_client(client);
+    let apps = board.load_apps(app_count);
+    Fixture {
+        storage,
+        ram,
+        client,
+        apps,
+    }
+}
+
+impl Fixture {
+    fn run(&self) {
+        self.apps
+            .run(&[(DRIVER_NUM, self.storage as &dyn SyscallDriver)]);
+    }
+}
+
+fn buffer_with(data: &[u8]) -> &'static mut [u8] {
+    let buffer = leak_buffer(32);
+    buffer[..data.len()].copy_from_slice(data);
+    buffer
+}
+
+#[test]
+fn kernel_write_then_read() {
+    let fixture = setup();
+
+    assert_eq!(fixture.storage.write(buffer_with(b"tock"), 0x10, 4), Ok(()));
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.ram.contents(0x10, 4), b"tock");
+
+    assert_eq!(fixture.storage.read(leak_buffer(32), 0x10, 4), Ok(()));
+    assert!(fixture.ram.complete());
+
+    assert_eq!(
+        fixture.client.done.take(),
+        vec![Done::Write(4, Ok(())), Done::Read(b"tock".to_vec(), Ok(()))]
+    );
+}
+
+#[test]
+fn kernel_request_queued_behind_kernel_request() {
+    let fixture = setup();
+
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    assert_eq!(fixture.storage.write(buffer_with(b"two"), 0x20, 3), Ok(()));
+    // Only one operation is given to the physical storage at a time.
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(3, Ok(()))]);
+    assert!(fixture.ram.is_pending());
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(3, Ok(()))]);
+    assert!(!fixture.ram.is_pending());
+
+    assert_eq!(fixture.ram.contents(0x00, 3), b"one");
+    assert_eq!(fixture.ram.contents(0x20, 3), b"two");
+}
+
+#[test]
+fn kernel_request_outside_kernel_region() {
+    let fixture = setup();
+
+    assert_eq!(
+        fixture.storage.write(buffer_with(b"x"), USERSPACE_START, 1),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(
+        fixture.storage.read(leak_buffer(32), KERNEL_LENGTH - 2, 4),
+        Err(ErrorCode::INVAL)
+    );
+    assert!(!fixture.ram.is_pending());
+}
+
+#[test]
+fn kernel_read_failure_is_reported() {
+    let fixture = setup();
+
+    assert_eq!(fixture.storage.read(leak_buffer(32), 0x10, 4), Ok(()));
+    fixture.ram.fail_next(ErrorCode::FAIL);
+    assert!(fixture.ram.complete());
+
+    assert_eq!(
+        fixture.client.done.take(),
+        vec![Done::Read(Vec::new(), Err(ErrorCode::FAIL))]
+    );
+}
+
+#[test]
+fn failed_write_continues_queue() {
+    let fixture = setup();
+
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    assert_eq!(fixture.storage.write(buffer_with(b"two"), 0x20, 3), Ok(()));
+
+    fixture.ram.fail_next(ErrorCode::NOACK);
+    assert!(fixture.ram.complete());
+    assert_eq!(
+        fixture.client.done.take(),
+        vec![Done::Write(0, Err(ErrorCode::NOACK))]
+    );
+
+    // The failure released the storage and the queued write was started.
+    assert!(fixture.ram.is_pending());
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(3, Ok(()))]);
+
+    assert_eq!(fixture.ram.contents(0x00, 3), [0xff; 3]);
+    assert_eq!(fixture.ram.contents(0x20, 3), b"two");
+}
+
+#[test]
+fn userspace_erase() {
+    let fixture = setup();
+    fixture.apps.subscribe(0, DRIVER_NUM, 2);
+    fixture.apps.command(0, DRIVER_NUM, 4, 0x10, 8);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::SubscribeSuccess(..), SyscallReturn::Success]
+    ));
+
+    // No buffer is needed, the erase is passed on at the physical address.
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 2, [8, 0, 0])]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 8), [0; 8]);
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x18, 1), [0xff]);
+
+    // An erase past the end of the userspace region is refused.
+    fixture
+        .apps
+        .command(0, DRIVER_NUM, 4, USERSPACE_LENGTH - 4, 8);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::INVAL)]
+    ));
+    assert!(!fixture.ram.is_pending());
+}
+
+/// Has app 0 allow `data` as its write buffer and ask to write `length`
+/// bytes at `offset`.
+fn userspace_write(fixture: &Fixture, data: &[u8], offset: usize, length: usize) {
+    fixture.apps.write_memory(0, 0, data);
+    fixture.apps.subscribe(0, DRIVER_NUM, 1);
+    fixture
+        .apps
+        .allow_readonly_at(0, DRIVER_NUM, 0, 0, data.len());
+    fixture.apps.command(0, DRIVER_NUM, 3, offset, length);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [
+            SyscallReturn::SubscribeSuccess(..),
+            SyscallReturn::AllowReadOnlySuccess(..),
+            SyscallReturn::Success
+        ]
+    ));
+}
+
+#[test]
+fn userspace_write_copies_the_buffer_when_it_starts() {
+    let fixture = setup();
+    userspace_write(&fixture, b"original", 0x10, 8);
+
+    // The command only recorded the write.
+    assert!(!fixture.ram.is_pending());
+    fixture.apps.write_memory(0, 0, b"modified");
+    fixture.storage.handle_deferred_call();
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 1, [8, 0, 0])]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 8), b"modified");
+
+    // A spurious deferred call finds nothing to do.
+    fixture.storage.handle_deferred_call();
+    assert!(!fixture.ram.is_pending());
+}
+
+#[test]
+fn queued_userspace_write_copies_the_buffer_when_it_starts() {
+    let fixture = setup();
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    userspace_write(&fixture, b"original", 0x10, 8);
+    fixture.storage.handle_deferred_call();
+
+    // The app allows a shorter buffer with other data while it waits.
+    fixture.apps.write_memory(0, 0x40, b"new");
+    fixture.apps.allow_readonly_at(0, DRIVER_NUM, 0, 0x40, 3);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    assert!(fixture.ram.complete());
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 1, [3, 0, 0])]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 4), b"new\xff");
+}
+
+#[test]
+fn queued_write_of_a_dead_app_is_dropped() {
+    let fixture = setup_apps(2);
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    for (app, data) in [(0, b"app zero"), (1, b"app one!")] {
+        fixture.apps.write_memory(app, 0, data);
+        fixture.apps.subscribe(app, DRIVER_NUM, 1);
+        fixture.apps.allow_readonly_at(app, DRIVER_NUM, 0, 0, 8);
+        fixture
+            .apps
+            .command(app, DRIVER_NUM, 3, 0x10 * (app + 1), 8);
+    }
+    fixture.run();
+    for app in 0..2 {
+        fixture.apps.take_returns(app);
+    }
+    fixture.storage.handle_deferred_call();
+
+    // App 0 dies while both writes wait for the kernel write.
+    fixture.apps.terminate(0);
+    assert!(fixture.ram.complete());
+
+    // Only the write of app 1 runs, and the storage is then idle.
+    assert!(fixture.ram.complete());
+    assert!(!fixture.ram.is_pending());
+    fixture.run();
+    assert!(fixture.apps.take_upcalls(0).is_empty());
+    assert_eq!(
+        fixture.apps.take_upcalls(1),
+        vec![(DRIVER_NUM, 1, [8, 0, 0])]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 8), [0xff; 8]);
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x20, 8), b"app one!");
+}
+
+#[test]
+fn userspace_write_unallowed_before_it_starts() {
+    let fixture = setup();
+    userspace_write(&fixture, b"original", 0x10, 8);
+    fixture.apps.allow_readonly_at(0, DRIVER_NUM, 0, 0, 0);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    fixture.storage.handle_deferred_call();
+    assert!(!fixture.ram.is_pending());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(
+            DRIVER_NUM,
+            1,
+            [0, into_statuscode(Err(ErrorCode::RESERVE)), 0]
+        )]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 8), [0xff; 8]);
+
+    // The storage is free for the next request.
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    assert!(fixture.ram.complete());
+}
+
+#[test]
+fn high_priority_requests_run_first() {
+    let fixture = setup();
+    let order = leak(RefCell::new(Vec::new()));
+    let first = named_user(fixture.storage, Priority::Normal, b'a', order);
+    let normal = named_user(fixture.storage, Priority::Normal, b'n', order);
+    let high = named_user(fixture.storage, Priority::High, b'h', order);
+
+    assert_eq!(first.write(), Ok(()));
+    fixture.apps.subscribe(0, DRIVER_NUM, 2);
+    fixture.apps.command(0, DRIVER_NUM, 4, 0x10, 8);
+    fixture.run();
+    assert_eq!(normal.write(), Ok(()));
+    assert_eq!(fixture.storage.write(buffer_with(b"k"), 0x40, 1), Ok(()));
+    assert_eq!(high.write(), Ok(()));
+
+    // The write in progress is not disturbed.
+    assert!(fixture.ram.complete());
+    assert_eq!(order.take(), b"a");
+    assert_eq!(fixture.ram.contents(b'a' as usize, 1), b"a");
+
+    // Then the high priority write runs, the kernel requests in the order
+    // of the clients, and the app last.
+    assert!(fixture.ram.complete());
+    assert_eq!(order.take(), b"h");
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(1, Ok(()))]);
+    assert!(fixture.ram.complete());
+    assert_eq!(order.take(), b"n");
+    fixture.run();
+    assert!(fixture.apps.take_upcalls(0).is_empty());
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 2, [8, 0, 0])]
+    );
+    assert!(!fixture.ram.is_pending());
+}
+
+#[test]
+fn high_priority_streak_is_bounded() {
+    let fixture = setup();
+    let order = leak(RefCell::new(Vec::new()));
+    let first = named_user(fixture.storage, Priority::Normal, b'a', order);
+    let normal = named_user(fixture.storage, Priority::Normal, b'n', order);
+    let high = named_user(fixture.storage, Priority::High, b'h', order);
+
+    assert_eq!(first.write(), Ok(()));
+    high.repeat.set(2 * MAX_HIGH_PRIORITY_STREAK);
+    assert_eq!(high.write(), Ok(()));
+    assert_eq!(normal.write(), Ok(()));
+    while fixture.ram.complete() {}
+
+    let mut expected = b"a".to_vec();
+    expected.extend([b'h'; MAX_HIGH_PRIORITY_STREAK]);
+    expected.push(b'n');
+    expected.extend([b'h'; MAX_HIGH_PRIORITY_STREAK + 1]);
+    assert_eq!(order.take(), expected);
+}
+
+#[test]
+fn high_priority_requests_run_alone_without_bound() {
+    let fixture = setup();
+    let order = leak(RefCell::new(Vec::new()));
+    let high = named_user(fixture.storage, Priority::High, b'h', order);
+
+    high.repeat.set(2 * MAX_HIGH_PRIORITY_STREAK);
+    assert_eq!(high.write(), Ok(()));
+    while fixture.ram.complete() {}
+    assert_eq!(order.take(), vec![b'h'; 2 * MAX_HIGH_PRIORITY_STREAK + 1]);
+}
+
+#[test]
+fn streamed_kernel_writes_keep_the_storage_busy() {
+    let fixture = setup();
+    let chunks = KERNEL_LENGTH / CHUNK;
+    let client = streaming_client(&fixture, chunks);
+
+    client.start();
+    let mut operations = 0;
+    while fixture.ram.complete() {
+        operations += 1;
+    }
+
+    // Every chunk was queued while the previous one was written, without
+    // being refused, and the next one always started before the client
+    // heard of a completion.
+    assert_eq!(operations, chunks);
+    assert_eq!(client.completed.get(), chunks);
+    assert_eq!(client.idle_gaps.get(), 0);
+    for chunk in 0..chunks {
+        assert_eq!(
+            fixture.ram.contents(chunk * CHUNK, CHUNK),
+            [chunk as u8; CHUNK]
+        );
+    }
+}
+
+#[test]
+fn apps_run_between_kernel_bursts() {
+    let fixture = setup();
+    let chunks = KERNEL_LENGTH / CHUNK;
+    let client = streaming_client(&fixture, chunks);
+
+    client.start();
+    fixture.apps.subscribe(0, DRIVER_NUM, 2);
+    fixture.apps.command(0, DRIVER_NUM, 4, 0x10, 8);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    // The app waits for a streak of kernel writes, not for the whole stream.
+    for _ in 0..MAX_KERNEL_STREAK {
+        assert!(fixture.ram.complete());
+    }
+    assert_eq!(client.completed.get(), MAX_KERNEL_STREAK);
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 2, [8, 0, 0])]
+    );
+    assert_eq!(client.completed.get(), MAX_KERNEL_STREAK);
+
+    // Then the stream goes on.
+    while fixture.ram.complete() {}
+    assert_eq!(client.completed.get(), chunks);
+    assert_eq!(client.idle_gaps.get(), 0);
+}
+
+/// The completed batches of a kernel client: the status of each entry, the
+/// number of entries written and the result.
+#[derive(Default)]
+struct BatchLog {
+    done: RefCell<Vec<(Vec<Result<(), ErrorCode>>, usize, Result<(), ErrorCode>)>>,
+}
+
+impl BatchClient for BatchLog {
+    fn batch_done(
+        &self,
+        _data: &'static mut [u8],
+        entries: &'static mut [BatchEntry],
+        completed: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        let statuses = entries.iter().map(|entry| entry.status).collect();
+        self.done.borrow_mut().push((statuses, completed, result));
+    }
+}
+
+// Where the app of the batch tests keeps its data, after the descriptors.
+const BATCH_DATA: usize = MAX_BATCH_ENTRIES * BATCH_DESCRIPTOR_LEN;
+
+/// Has the app describe the `(offset, length)` writes of a batch, with the
+/// status words set to a value the capsule never writes, and allow them
+/// with `data`.
+fn allow_batch(fixture: &Fixture, entries: &[(usize, usize)], data: &[u8]) {
+    let descriptors: Vec<u8> = entries
+        .iter()
+        .flat_map(|&(offset, length)| {
+            [offset as u32, length as u32, 0xffff_ffff]
+                .into_iter()
+                .flat_map(u32::to_le_bytes)
+        })
+        .collect();
+    fixture.apps.write_memory(0, 0, &descriptors);
+    fixture.apps.write_memory(0, BATCH_DATA, data);
+    fixture.apps.subscribe(0, DRIVER_NUM, 3);
+    fixture
+        .apps
+        .allow_readwrite_at(0, DRIVER_NUM, 1, 0, descriptors.len());
+    fixture
+        .apps
+        .allow_readonly_at(0, DRIVER_NUM, 0, BATCH_DATA, data.len());
+    fixture.run();
+    fixture.apps.take_returns(0);
+}
+
+/// The status words of the first `count` descriptors of the app.
+fn batch_statuses(fixture: &Fixture, count: usize) -> Vec<usize> {
+    fixture
+        .apps
+        .read_memory(0, count * BATCH_DESCRIPTOR_LEN)
+        .chunks(BATCH_DESCRIPTOR_LEN)
+        .map(|descriptor| u32::from_le_bytes(descriptor[8..12].try_into().unwrap()) as usize)
+        .collect()
+}
+
+#[test]
+fn userspace_batch_writes_all_entries() {
+    let fixture = setup();
+    allow_batch(&fixture, &[(0x10, 4), (0x40, 3), (0x80, 2)], b"tockxyzhi");
+    fixture.apps.command(0, DRIVER_NUM, 5, 3, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::Success]
+    ));
+
+    // The entries are written one after the other, without the app.
+    for _ in 0..3 {
+        assert!(fixture.ram.complete());
+    }
+    assert!(!fixture.ram.is_pending());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 3, [3, 0, 0])]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 4), b"tock");
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x40, 3), b"xyz");
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x80, 2), b"hi");
+    assert_eq!(batch_statuses(&fixture, 3), vec![0, 0, 0]);
+}
+
+#[test]
+fn userspace_batch_is_checked_before_writing() {
+    let fixture = setup();
+    allow_batch(&fixture, &[(0x10, 4), (0x20, 4)], b"tockxyzw");
+    // More entries than the descriptors allowed, no entry at all, or too
+    // many.
+    fixture.apps.command(0, DRIVER_NUM, 5, 3, 0);
+    fixture.apps.command(0, DRIVER_NUM, 5, 0, 0);
+    fixture
+        .apps
+        .command(0, DRIVER_NUM, 5, MAX_BATCH_ENTRIES + 1, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::SIZE),
+            SyscallReturn::Failure(ErrorCode::INVAL),
+            SyscallReturn::Failure(ErrorCode::INVAL),
+        ]
+    ));
+
+    // The last entry runs past the end of the userspace region.
+    allow_batch(
+        &fixture,
+        &[(0x10, 4), (USERSPACE_LENGTH - 2, 4)],
+        b"tockxyzw",
+    );
+    fixture.apps.command(0, DRIVER_NUM, 5, 2, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::INVAL)]
+    ));
+
+    // More data described than allowed.
+    allow_batch(&fixture, &[(0x10, 4), (0x20, 8)], b"tockxyz");
+    fixture.apps.command(0, DRIVER_NUM, 5, 2, 0);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::Failure(ErrorCode::SIZE)]
+    ));
+
+    // Nothing was written.
+    assert!(!fixture.ram.is_pending());
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 4), [0xff; 4]);
+}
+
+#[test]
+fn userspace_batch_stops_at_a_failure() {
+    let fixture = setup();
+    allow_batch(&fixture, &[(0x10, 4), (0x40, 3), (0x80, 2)], b"tockxyzhi");
+    fixture.apps.command(0, DRIVER_NUM, 5, 3, 0);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    assert!(fixture.ram.complete());
+    fixture.ram.fail_next(ErrorCode::FAIL);
+    assert!(fixture.ram.complete());
+    assert!(!fixture.ram.is_pending());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 3, [1, into_statuscode(Err(ErrorCode::FAIL)), 0])]
+    );
+    assert_eq!(
+        batch_statuses(&fixture, 3),
+        vec![
+            0,
+            into_statuscode(Err(ErrorCode::FAIL)),
+            into_statuscode(Err(ErrorCode::CANCEL)),
+        ]
+    );
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 4), b"tock");
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x80, 2), [0xff; 2]);
+
+    // The storage is free again.
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    assert!(fixture.ram.complete());
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(3, Ok(()))]);
+}
+
+fn kernel_batch(entries: &[(usize, usize)]) -> &'static mut [BatchEntry] {
+    Box::leak(
+        entries
+            .iter()
+            .map(|&(address, length)| BatchEntry::new(address, length))
+            .collect(),
+    )
+}
+
+#[test]
+fn kernel_batch_runs_after_the_current_request() {
+    let fixture = setup();
+    let log = leak(BatchLog::default());
+    fixture.storage.set_batch_client(log);
+
+    assert_eq!(fixture.storage.write(buffer_with(b"one"), 0x00, 3), Ok(()));
+    assert!(fixture
+        .storage
+        .write_batch(
+            buffer_with(b"tockhi"),
+            kernel_batch(&[(0x10, 4), (0x20, 2)])
+        )
+        .is_ok());
+    // A second batch waits for the first to be handed back.
+    assert!(matches!(
+        fixture
+            .storage
+            .write_batch(buffer_with(b"x"), kernel_batch(&[(0x30, 1)])),
+        Err((ErrorCode::NOMEM, ..))
+    ));
+
+    while fixture.ram.complete() {}
+    assert_eq!(fixture.client.done.take(), vec![Done::Write(3, Ok(()))]);
+    assert_eq!(log.done.take(), vec![(vec![Ok(()), Ok(())], 2, Ok(()))]);
+    assert_eq!(fixture.ram.contents(0x10, 4), b"tock");
+    assert_eq!(fixture.ram.contents(0x20, 2), b"hi");
+}
+
+#[test]
+fn kernel_batch_is_checked_and_stops_at_a_failure() {
+    let fixture = setup();
+    let log = leak(BatchLog::default());
+    fixture.storage.set_batch_client(log);
+
+    // The second entry is in the userspace region.
+    assert!(matches!(
+        fixture.storage.write_batch(
+            buffer_with(b"tockhi"),
+            kernel_batch(&[(0x10, 4), (USERSPACE_START, 2)])
+        ),
+        Err((ErrorCode::INVAL, ..))
+    ));
+    assert!(!fixture.ram.is_pending());
+
+    assert!(fixture
+        .storage
+        .write_batch(
+            buffer_with(b"tockxyzhi"),
+            kernel_batch(&[(0x10, 4), (0x40, 3), (0x80, 2)])
+        )
+        .is_ok());
+    fixture.ram.fail_next(ErrorCode::NOACK);
+    assert!(fixture.ram.complete());
+    assert!(!fixture.ram.is_pending());
+    assert_eq!(
+        log.done.take(),
+        vec![(
+            vec![
+                Err(ErrorCode::NOACK),
+                Err(ErrorCode::CANCEL),
+                Err(ErrorCode::CANCEL)
+            ],
+            0,
+            Err(ErrorCode::NOACK)
+        )]
+    );
+    assert_eq!(fixture.ram.contents(0x40, 3), [0xff; 3]);
+}
+
+#[test]
+fn restarted_app_does_not_get_the_batch_of_its_previous_instance() {
+    let fixture = setup();
+    allow_batch(&fixture, &[(0x10, 4), (0x40, 3)], b"tockxyz");
+    fixture.apps.command(0, DRIVER_NUM, 5, 2, 0);
+    fixture.run();
+    fixture.apps.take_returns(0);
+
+    // The app crashes while its first entry is written. The rest of the
+    // batch is dropped, its new instance gets no upcall.
+    fixture.apps.restart(0);
+    fixture.apps.subscribe(0, DRIVER_NUM, 2);
+    fixture.apps.subscribe(0, DRIVER_NUM, 3);
+    fixture.run();
+    fixture.apps.take_returns(0);
+    assert!(fixture.ram.complete());
+    assert!(!fixture.ram.is_pending());
+    fixture.run();
+    assert!(fixture.apps.take_upcalls(0).is_empty());
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x10, 4), b"tock");
+    assert_eq!(fixture.ram.contents(USERSPACE_START + 0x40, 3), [0xff; 3]);
+
+    // The storage is free for the new instance.
+    fixture.apps.command(0, DRIVER_NUM, 4, 0x80, 8);
+    fixture.run();
+    assert!(matches!(
+        fixture.apps.take_returns(0)[..],
+        [SyscallReturn::Success]
+    ));
+    assert!(fixture.ram.complete());
+    fixture.run();
+    assert_eq!(
+        fixture.apps.take_upcalls(0),
+        vec![(DRIVER_NUM, 2, [8, 0, 0])]
+    );
+}
+
+#[derive(Debug, PartialEq)]
+enum LogEvent {
+    Mounted(Result<(), ErrorCode>),
+    Appended(usize, Result<(), ErrorCode>),
+    Read(Vec<u8>, usize, Result<(), ErrorCode>),
+}
+
+#[derive(Default)]
+struct LogClient {
+    events: RefCell<Vec<LogEvent>>,
+}
+
+impl NonvolatileLogClient for LogClient {
+    fn mounted(&self, result: Result<(), ErrorCode>) {
+        self.events.borrow_mut().push(LogEvent::Mounted(result));
+    }
+
+    fn append_done(&self, offset: usize, result: Result<(), ErrorCode>) {
+        self.events
+            .borrow_mut()
+            .push(LogEvent::Appended(offset, result));
+    }
+
+    fn record_read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        next: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.events
+            .borrow_mut()
+            .push(LogEvent::Read(buffer[..length].to_vec(), next, result));
+    }
+}
+
+const LOG_START: usize = 0x80;
+/// Room for 24 bytes of records.
+const LOG_LENGTH: usize = 0x20;
+
+/// Mounts a log of the kernel region of `fixture`, with its own storage
+/// user.
+fn mounted_log(
+    fixture: &Fixture,
+    policy: LogFullPolicy,
+) -> (&'static NonvolatileLog<'static>, &'static LogClient) {
+    let user = leak(NonvolatileStorageUser::new(
+        fixture.storage,
+        Priority::Normal,
+    ));
+    user.setup();
+    let log = leak(NonvolatileLog::new(
+        user,
+        LOG_START,
+        LOG_LENGTH,
+        policy,
+        leak_buffer(32),
+    ));
+    user.set_client(log);
+    let client = leak(LogClient::default());
+    log.set_client(client);
+    assert_eq!(log.mount(), Ok(()));
+    complete_all(fixture);
+    assert_eq!(client.events.take(), vec![LogEvent::Mounted(Ok(()))]);
+    (log, client)
+}
+
+fn complete_all(fixture: &Fixture) {
+    while fixture.ram.complete() {}
+}
+
+fn append(fixture: &Fixture, log: &NonvolatileLog, client: &LogClient, record: &[u8]) -> usize {
+    assert_eq!(log.append(record), Ok(()));
+    complete_all(fixture);
+    match client.events.take()[..] {
+        [LogEvent::Appended(offset, Ok(()))] => offset,
+        ref events => panic!("unexpected events {:?}", events),
+    }
+}
+
+/// Reads the records of `log` from offset 0 to its end.
+fn replay(fixture: &Fixture, log: &NonvolatileLog, client: &LogClient) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < log.end() {
+        assert_eq!(log.read_record(leak_buffer(16), offset), Ok(()));
+        complete_all(fixture);
+        match client.events.take().pop() {
+            Some(LogEvent::Read(record, next, Ok(()))) => {
+                records.push(record);
+                offset = next;
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+    records
+}
+
+#[test]
+fn log_appends_and_replays_records() {
+    let fixture = setup();
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Error);
+    assert_eq!(log.end(), 0);
+
+    assert_eq!(append(&fixture, log, client, b"abc"), 0);
+    assert_eq!(append(&fixture, log, client, b""), 5);
+    assert_eq!(append(&fixture, log, client, b"de"), 7);
+    assert_eq!(log.end(), 11);
+
+    // The cursor in the header, then each record after its length.
+    assert_eq!(
+        fixture.ram.contents(LOG_START, LOG_HEADER_LEN + 11),
+        [0x4e, 0x56, 0x4c, 0x47, 11, 0, 0, 0, 3, 0, b'a', b'b', b'c', 0, 0, 2, 0, b'd', b'e']
+    );
+    assert_eq!(
+        replay(&fixture, log, client),
+        vec![b"abc".to_vec(), vec![], b"de".to_vec()]
+    );
+    assert_eq!(log.read_record(leak_buffer(16), 11), Err(ErrorCode::INVAL));
+}
+
+#[test]
+fn log_cursor_survives_a_reset() {
+    let fixture = setup();
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Error);
+    append(&fixture, log, client, b"first");
+
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Error);
+    assert_eq!(log.end(), 7);
+    assert_eq!(append(&fixture, log, client, b"second"), 7);
+    assert_eq!(
+        replay(&fixture, log, client),
+        vec![b"first".to_vec(), b"second".to_vec()]
+    );
+}
+
+#[test]
+fn full_log_refuses_or_wraps() {
+    let fixture = setup();
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Error);
+    append(&fixture, log, client, &[1; 10]);
+    append(&fixture, log, client, &[2; 10]);
+    assert_eq!(log.append(&[3; 1]), Err(ErrorCode::NOMEM));
+    assert_eq!(log.append(&[3; 23]), Err(ErrorCode::SIZE));
+    assert!(!fixture.ram.is_pending());
+
+    // Once cleared, it starts over.
+    assert_eq!(log.clear(), Ok(()));
+    complete_all(&fixture);
+    assert_eq!(client.events.take(), vec![LogEvent::Appended(0, Ok(()))]);
+    assert_eq!(log.end(), 0);
+
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Wrap);
+    append(&fixture, log, client, &[1; 10]);
+    append(&fixture, log, client, &[2; 10]);
+    assert_eq!(append(&fixture, log, client, &[3; 4]), 0);
+    assert_eq!(log.end(), 6);
+    assert_eq!(replay(&fixture, log, client), vec![vec![3; 4]]);
+}
+
+#[test]
+fn failed_append_keeps_the_cursor() {
+    let fixture = setup();
+    let (log, client) = mounted_log(&fixture, LogFullPolicy::Error);
+    append(&fixture, log, client, b"ok");
+
+    fixture.ram.fail_next(ErrorCode::FAIL);
+    assert_eq!(log.append(b"lost"), Ok(()));
+    assert_eq!(log.append(b"busy"), Err(ErrorCode::BUSY));
+    complete_all(&fixture);
+    assert_eq!(
+        client.events.take(),
+        vec![LogEvent::Appended(4, Err(ErrorCode::FAIL))]
+    );
+    assert_eq!(log.end(), 4);
+
+    assert_eq!(append(&fixture, log, client, b"next"), 4);
+    assert_eq!(
+        replay(&fixture, log, client),
+        vec![b"ok".to_vec(), b"next".to_vec()]
+    );
+}
+
+/// Runs command `command_num` of app 0 and returns its result.
+fn size_command(fixture: &Fixture, command_num: usize) -> SyscallReturn {
+    fixture.apps.command(0, DRIVER_NUM, command_num, 0, 0);
+    fixture.run();
+    fixture.apps.take_returns(0).pop().unwrap()
+}
+
+#[test]
+fn geometry_is_reported() {
+    let fixture = setup();
+    assert_eq!(fixture.storage.geometry(), GEOMETRY);
+    assert!(matches!(
+        size_command(&fixture, 1),
+        SyscallReturn::SuccessU32(0x100)
+    ));
+    assert!(matches!(
+        size_command(&fixture, 6),
+        SyscallReturn::SuccessU32(0x40)
+    ));
+    assert!(matches!(
+        size_command(&fixture, 7),
+        SyscallReturn::SuccessU32(0x100)
+    ));
+}
+
+#[test]
+fn sizes_over_32_bits_are_refused() {
+    let board = Board::new();
+    let ram = leak(RamStorage::new(0x200));
+    let huge = 1 << 32;
+    let storage = leak(NonvolatileStorage::new(
+        ram,
+        board.create_grant(DRIVER_NUM),
+        0,
+        huge,
+        0,
+        0x100,
+        StorageGeometry {
+            page_size: 0x40,
+            erase_size: huge,
+            total_size: 2 * huge,
+        },
+        leak_buffer(capsules_extra::nonvolatile_storage_driver::BUF_LEN),
+    ));
+    let fixture = Fixture {
+        storage,
+        ram,
+        client: leak(KernelClient::default()),
+        apps: board.load_apps(1),
+    };
+    assert!(matches!(
+        size_command(&fixture, 1),
+        SyscallReturn::Failure(ErrorCode::SIZE)
+    ));
+    assert!(matches!(
+        size_command(&fixture, 6),
+        SyscallReturn::SuccessU32(0x40)
+    ));
+    assert!(matches!(
+        size_command(&fixture, 7),
+        SyscallReturn::Failure(ErrorCode::SIZE)
+    ));
+}
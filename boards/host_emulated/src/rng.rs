@@ -0,0 +1,825 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! The entropy to randomness conversion stack, the ChaCha generator, and the
+//! buffer filling for kernel capsules, over a deterministic source.
+
+use std::cell::{Cell, RefCell};
+
+use capsules_core::rng::{
+    ChaChaRng, ConditionedEntropy32, Entropy32To8, Entropy32ToRandom, Entropy8To32,
+    PeriodicRefresh, RngBufferFill, RngBufferFillClient, RngBufferFillUser, RngDriver,
+    SynchronousRandom, CACHE_WORDS, CHACHA_RESEED_INTERVAL, DRIVER_NUM,
+};
+use kernel::deferred_call::DeferredCallClient;
+use kernel::hil::rng::{self, Random, Rng};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{
+    leak, leak_buffer, take_deferred_calls, Apps, Board, DeterministicEntropy32, FakeAlarm,
+};
+
+type Source = Entropy32ToRandom<'static, DeterministicEntropy32<'static>>;
+
+fn synchronous_random() -> (
+    &'static DeterministicEntropy32<'static>,
+    &'static SynchronousRandom<'static, Source>,
+) {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let random = leak(SynchronousRandom::new(rng));
+    (source, random)
+}
+
+fn rng_driver(
+    board: &Board,
+) -> (
+    &'static DeterministicEntropy32<'static>,
+    &'static RngDriver<'static, Source>,
+) {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let driver = leak(RngDriver::new(rng, board.create_grant(DRIVER_NUM)));
+    rng.set_client(driver);
+    (source, driver)
+}
+
+/// Takes random words until it has `wanted` of them.
+struct Collector {
+    wanted: Cell<usize>,
+    words: RefCell<Vec<u32>>,
+    errors: RefCell<Vec<Result<(), ErrorCode>>>,
+}
+
+impl Collector {
+    fn new(wanted: usize) -> Self {
+        Self {
+            wanted: Cell::new(wanted),
+            words: RefCell::new(Vec::new()),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl rng::Client for Collector {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        self.errors.borrow_mut().push(error);
+        let mut words = self.words.borrow_mut();
+        while words.len() < self.wanted.get() {
+            match randomness.next() {
+                Some(word) => words.push(word),
+                None => return rng::Continue::More,
+            }
+        }
+        rng::Continue::Done
+    }
+}
+
+#[test]
+fn entropy32_fills_across_batches() {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let collector = leak(Collector::new(5));
+    rng.set_client(collector);
+
+    assert_eq!(rng.get(), Ok(()));
+    assert!(source.is_requested());
+    source.deliver(&[1, 2], Ok(()));
+    assert!(source.is_requested());
+    source.deliver(&[3], Ok(()));
+    source.deliver(&[4, 5, 6, 7], Ok(()));
+
+    assert!(!source.is_requested());
+    assert_eq!(collector.words.take(), vec![1, 2, 3, 4, 5]);
+    assert_eq!(collector.errors.take(), vec![Ok(()); 3]);
+}
+
+#[test]
+fn entropy32_errors_reach_the_client() {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let collector = leak(Collector::new(1));
+    rng.set_client(collector);
+
+    assert_eq!(rng.get(), Ok(()));
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    source.deliver(&[9], Ok(()));
+
+    assert_eq!(collector.errors.take(), vec![Err(ErrorCode::FAIL), Ok(())]);
+    assert_eq!(collector.words.take(), vec![9]);
+}
+
+#[test]
+fn byte_conversion_round_trip() {
+    // 32 -> 8 -> 32 bit conversion must not change the words.
+    let source = leak(DeterministicEntropy32::new());
+    let to8 = leak(Entropy32To8::new(source));
+    let to32 = leak(Entropy8To32::new(to8));
+    let rng = leak(Entropy32ToRandom::new(to32));
+    let collector = leak(Collector::new(3));
+    rng.set_client(collector);
+
+    assert_eq!(rng.get(), Ok(()));
+    for word in [0x0403_0201, 0x0807_0605, 0x0c0b_0a09] {
+        source.deliver(&[word], Ok(()));
+    }
+
+    assert!(!source.is_requested());
+    assert_eq!(
+        collector.words.take(),
+        vec![0x0403_0201, 0x0807_0605, 0x0c0b_0a09]
+    );
+}
+
+/// A collector of `wanted` words conditioned from `ratio` raw words each.
+fn conditioned(
+    ratio: usize,
+    wanted: usize,
+) -> (&'static DeterministicEntropy32<'static>, &'static Collector) {
+    let source = leak(DeterministicEntropy32::new());
+    let conditioner = leak(ConditionedEntropy32::new(source, ratio));
+    let rng = leak(Entropy32ToRandom::new(conditioner));
+    let collector = leak(Collector::new(wanted));
+    rng.set_client(collector);
+    assert_eq!(rng.get(), Ok(()));
+    (source, collector)
+}
+
+#[test]
+fn conditioned_entropy_waits_for_enough_input() {
+    let (source, collector) = conditioned(4, 2);
+
+    // The client is not called before 4 raw words arrived.
+    source.deliver(&[1, 2, 3], Ok(()));
+    assert!(collector.errors.take().is_empty());
+    assert!(source.is_requested());
+
+    source.deliver(&[4, 5, 6, 7], Ok(()));
+    assert_eq!(collector.errors.take(), vec![Ok(())]);
+    assert_eq!(collector.words.borrow().len(), 1);
+    assert!(source.is_requested());
+
+    // The second word needs one more raw word.
+    source.deliver(&[8, 9], Ok(()));
+    assert!(!source.is_requested());
+    let words = collector.words.take();
+    assert_eq!(words.len(), 2);
+    assert_ne!(words[0], words[1]);
+    assert!(words.iter().all(|word| !(1..=9).contains(word)));
+}
+
+#[test]
+fn conditioned_entropy_mixes_its_input() {
+    let words = |raw: &[u32]| {
+        let (source, collector) = conditioned(2, raw.len() / 2);
+        source.deliver(raw, Ok(()));
+        collector.words.take()
+    };
+
+    // The same input gives the same words.
+    let zeros = words(&[0; 8]);
+    assert_eq!(zeros, words(&[0; 8]));
+    // Constant input still gives different words.
+    assert_eq!(zeros.len(), 4);
+    for (i, word) in zeros.iter().enumerate() {
+        assert!(!zeros[..i].contains(word));
+    }
+    // A single bit of input changes the word it is absorbed in and the
+    // following ones.
+    let flipped = words(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(flipped[0], zeros[0]);
+    for (flipped, zero) in flipped[1..].iter().zip(&zeros[1..]) {
+        assert_ne!(flipped, zero);
+        assert!((flipped ^ zero).count_ones() > 4);
+    }
+}
+
+#[test]
+fn conditioned_entropy_passes_errors() {
+    let (source, collector) = conditioned(2, 1);
+    source.deliver(&[1], Ok(()));
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    assert_eq!(collector.errors.take(), vec![Err(ErrorCode::FAIL)]);
+    assert!(collector.words.take().is_empty());
+
+    // The raw word before the error still counts.
+    source.deliver(&[2], Ok(()));
+    assert_eq!(collector.errors.take(), vec![Ok(())]);
+    assert_eq!(collector.words.take().len(), 1);
+    assert!(!source.is_requested());
+}
+
+#[test]
+fn synchronous_random_refresh_changes_output() {
+    let (source, random) = synchronous_random();
+    random.initialize();
+    assert!(!random.is_seeded());
+    source.deliver(&[7], Ok(()));
+    assert!(random.is_seeded());
+
+    let first: Vec<u32> = (0..3).map(|_| random.random()).collect();
+    assert_eq!(random.outputs_since_reseed(), 3);
+
+    // Until the new seed arrives the old sequence continues.
+    assert_eq!(random.refresh(), Ok(()));
+    assert_eq!(random.refresh(), Err(ErrorCode::ALREADY));
+    let _ = random.random();
+    assert_eq!(random.outputs_since_reseed(), 4);
+    source.deliver(&[7], Ok(()));
+    assert_eq!(random.outputs_since_reseed(), 0);
+    let again: Vec<u32> = (0..3).map(|_| random.random()).collect();
+    assert_eq!(again, first);
+
+    assert_eq!(random.refresh(), Ok(()));
+    source.deliver(&[8], Ok(()));
+    let reseeded: Vec<u32> = (0..3).map(|_| random.random()).collect();
+    assert_ne!(reseeded, first);
+}
+
+#[test]
+fn synchronous_random_errors_keep_seed() {
+    let (source, random) = synchronous_random();
+
+    // A failing source leaves the generator unseeded, and a later refresh
+    // can still seed it.
+    source.fail_next_get(ErrorCode::OFF);
+    random.initialize();
+    assert!(!random.is_seeded());
+    assert_eq!(random.seed_error(), Some(ErrorCode::OFF));
+    assert!(!source.is_requested());
+    assert_eq!(random.refresh(), Ok(()));
+    source.deliver(&[3], Ok(()));
+    assert!(random.is_seeded());
+    assert_eq!(random.seed_error(), None);
+
+    let before = random.random();
+    random.reseed(3);
+    assert_eq!(random.refresh(), Ok(()));
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    assert_eq!(random.seed_error(), Some(ErrorCode::FAIL));
+    assert_eq!(random.random(), before);
+    assert_eq!(random.outputs_since_reseed(), 1);
+    // The failed request is over, so another one can be made.
+    assert_eq!(random.refresh(), Ok(()));
+}
+
+#[test]
+fn periodic_refresh() {
+    let (source, random) = synchronous_random();
+    let alarm = leak(FakeAlarm::<Freq1KHz>::new());
+    let refresh = leak(PeriodicRefresh::new(alarm, random, 500));
+    alarm.set_alarm_client(refresh);
+    random.initialize();
+    source.deliver(&[1], Ok(()));
+    refresh.start();
+    assert_eq!(alarm.armed_dt(), Some(500));
+    assert!(!source.is_requested());
+
+    let _ = random.random();
+    assert!(alarm.fire());
+    assert!(source.is_requested());
+    assert_eq!(alarm.armed_dt(), Some(500));
+    source.deliver(&[2], Ok(()));
+    assert_eq!(random.outputs_since_reseed(), 0);
+
+    // A period in which the source does not answer does not stack requests.
+    assert!(alarm.fire());
+    assert!(alarm.fire());
+    assert!(source.is_requested());
+    assert_eq!(alarm.armed_dt(), Some(500));
+}
+
+fn run(apps: &Apps, driver: &'static RngDriver<'static, Source>) {
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+}
+
+/// Has app 0 issue command 2 and returns its result.
+fn cached_word(apps: &Apps, driver: &'static RngDriver<'static, Source>) -> Result<u32, ErrorCode> {
+    apps.command(0, DRIVER_NUM, 2, 0, 0);
+    run(apps, driver);
+    match apps.take_returns(0).pop() {
+        Some(SyscallReturn::SuccessU32(word)) => Ok(word),
+        Some(SyscallReturn::Failure(error)) => Err(error),
+        other => panic!("unexpected return {:?}", other),
+    }
+}
+
+#[test]
+fn cached_word_command() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+
+    // An empty cache starts a fill, which stops once the cache is full.
+    assert_eq!(cached_word(&apps, driver), Err(ErrorCode::BUSY));
+    assert!(source.is_requested());
+    source.deliver(&[1, 2, 3], Ok(()));
+    assert!(source.is_requested());
+    let words: Vec<u32> = (4..=20).collect();
+    source.deliver(&words, Ok(()));
+    assert!(!source.is_requested());
+    assert_eq!(driver.cached_words(), CACHE_WORDS);
+
+    // Every word is returned once, oldest first.
+    for word in 1..=CACHE_WORDS as u32 {
+        assert_eq!(cached_word(&apps, driver), Ok(word));
+    }
+    assert_eq!(cached_word(&apps, driver), Err(ErrorCode::BUSY));
+    assert!(source.is_requested());
+}
+
+#[test]
+fn cached_word_start_failure() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+
+    source.fail_next_get(ErrorCode::OFF);
+    assert_eq!(cached_word(&apps, driver), Err(ErrorCode::OFF));
+    assert!(!source.is_requested());
+    assert_eq!(cached_word(&apps, driver), Err(ErrorCode::BUSY));
+    assert!(source.is_requested());
+}
+
+#[test]
+fn cache_fills_after_buffer_requests() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 8);
+    run(&apps, driver);
+    assert_eq!(cached_word(&apps, driver), Err(ErrorCode::BUSY));
+    apps.command(0, DRIVER_NUM, 1, 8, 0);
+    run(&apps, driver);
+    assert!(source.is_requested());
+
+    // The app buffer is served first, the cache gets what is left.
+    source.deliver(&[1], Ok(()));
+    assert_eq!(driver.cached_words(), 0);
+    source.deliver(&[2, 3, 4], Ok(()));
+    assert_eq!(driver.cached_words(), 2);
+    assert!(source.is_requested());
+    let words: Vec<u32> = (5..=20).collect();
+    source.deliver(&words, Ok(()));
+    assert!(!source.is_requested());
+    assert_eq!(driver.cached_words(), CACHE_WORDS);
+
+    run(&apps, driver);
+    let bytes: Vec<u8> = [1u32, 2]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    assert_eq!(apps.read_memory(0, 8), bytes);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 8, 0])]);
+    assert_eq!(cached_word(&apps, driver), Ok(3));
+    assert_eq!(cached_word(&apps, driver), Ok(4));
+}
+
+#[test]
+fn leftover_words_are_cached() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 4);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+
+    // Without a pending command 2 the request ends with the app's.
+    source.deliver(&[1, 2, 3], Ok(()));
+    assert!(!source.is_requested());
+    assert_eq!(driver.cached_words(), 2);
+    assert_eq!(cached_word(&apps, driver), Ok(2));
+    assert_eq!(cached_word(&apps, driver), Ok(3));
+    assert_eq!(driver.cached_words(), 0);
+}
+
+#[test]
+fn cancel_while_another_app_waits() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(2);
+    for app in 0..2 {
+        apps.subscribe(app, DRIVER_NUM, 0);
+        apps.allow_readwrite(app, DRIVER_NUM, 0, 4);
+        apps.command(app, DRIVER_NUM, 1, 4, 0);
+    }
+    run(&apps, driver);
+    apps.command(0, DRIVER_NUM, 3, 0, 0);
+    run(&apps, driver);
+    assert!(source.is_requested());
+
+    source.deliver(&[0x0403_0201], Ok(()));
+    run(&apps, driver);
+    assert!(!source.is_requested());
+    assert!(apps.take_upcalls(0).is_empty());
+    assert_eq!(apps.read_memory(0, 4), vec![0; 4]);
+    assert_eq!(apps.take_upcalls(1), vec![(DRIVER_NUM, 0, [0, 4, 0])]);
+    assert_eq!(apps.read_memory(1, 4), vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn cancel_when_last_stops_the_rng() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 4);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+    assert!(source.is_requested());
+
+    // The source never answers.
+    apps.command(0, DRIVER_NUM, 3, 0, 0);
+    run(&apps, driver);
+    assert!(!source.is_requested());
+    assert!(apps
+        .take_returns(0)
+        .iter()
+        .all(|r| !matches!(r, SyscallReturn::Failure(_))));
+
+    // A new request starts the RNG again.
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+    assert!(source.is_requested());
+    source.deliver(&[7], Ok(()));
+    run(&apps, driver);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 4, 0])]);
+}
+
+#[test]
+fn cancel_ignores_terminated_apps() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(2);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 4);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    apps.allow_readwrite(1, DRIVER_NUM, 0, 4);
+    apps.command(1, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+
+    // The request of an app that exited does not keep the RNG running.
+    apps.terminate(0);
+    apps.command(1, DRIVER_NUM, 3, 0, 0);
+    run(&apps, driver);
+    assert!(!source.is_requested());
+}
+
+#[test]
+fn request_start_failure() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 4);
+    run(&apps, driver);
+    apps.take_returns(0);
+
+    source.fail_next_get(ErrorCode::OFF);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+    // The failure does not leave the driver thinking the RNG runs.
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::OFF),
+            SyscallReturn::Success
+        ]
+    ));
+    assert!(source.is_requested());
+}
+
+type Filler = RngBufferFillUser<'static, Source>;
+
+/// Keeps the buffers it gets back, and fills the next one of `refills`.
+#[derive(Default)]
+struct FillClient {
+    user: Cell<Option<&'static Filler>>,
+    filled: RefCell<Vec<(Vec<u8>, Result<(), ErrorCode>)>>,
+    refills: RefCell<Vec<(&'static mut [u8], usize)>>,
+}
+
+impl RngBufferFillClient for FillClient {
+    fn fill_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.filled.borrow_mut().push((buffer.to_vec(), result));
+        if let Some((buffer, length)) = self.refills.borrow_mut().pop() {
+            assert!(self.user.get().unwrap().fill(buffer, length).is_ok());
+        }
+    }
+}
+
+/// `users` capsules filling buffers from one deterministic source.
+fn buffer_fill(
+    users: usize,
+) -> (
+    &'static DeterministicEntropy32<'static>,
+    Vec<(&'static Filler, &'static FillClient)>,
+) {
+    let source = leak(DeterministicEntropy32::new());
+    let rng = leak(Entropy32ToRandom::new(source));
+    let fill = leak(RngBufferFill::new(rng));
+    rng.set_client(fill);
+    let users = (0..users)
+        .map(|_| {
+            let user = leak(RngBufferFillUser::new(fill));
+            user.setup();
+            let client = leak(FillClient::default());
+            client.user.set(Some(user));
+            user.set_client(client);
+            (&*user, &*client)
+        })
+        .collect();
+    (source, users)
+}
+
+#[test]
+fn buffer_fill_one_word_at_a_time() {
+    let (source, users) = buffer_fill(1);
+    let (user, client) = users[0];
+
+    assert!(user.fill(leak_buffer(12), 10).is_ok());
+    for word in [0x0403_0201, 0x0807_0605] {
+        source.deliver(&[word], Ok(()));
+        assert!(source.is_requested());
+        assert!(client.filled.borrow().is_empty());
+    }
+    // Only two bytes of the last word are needed.
+    source.deliver(&[0x0c0b_0a09], Ok(()));
+
+    assert!(!source.is_requested());
+    assert_eq!(
+        client.filled.take(),
+        vec![(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0, 0], Ok(()))]
+    );
+}
+
+#[test]
+fn buffer_fills_are_served_in_turn_from_one_batch() {
+    let (source, users) = buffer_fill(2);
+    let (first, first_client) = users[0];
+    let (second, second_client) = users[1];
+
+    assert!(first.fill(leak_buffer(4), 4).is_ok());
+    assert_eq!(
+        first.fill(leak_buffer(4), 4).map_err(|(error, _)| error),
+        Err(ErrorCode::BUSY)
+    );
+    assert!(second.fill(leak_buffer(6), 6).is_ok());
+    // The first client fills another buffer as soon as it gets its own,
+    // which waits for the second one.
+    first_client.refills.borrow_mut().push((leak_buffer(3), 3));
+
+    let words: Vec<u32> = (1..=100).collect();
+    source.deliver(&words, Ok(()));
+
+    assert!(!source.is_requested());
+    assert_eq!(
+        first_client.filled.take(),
+        vec![(vec![1, 0, 0, 0], Ok(())), (vec![4, 0, 0], Ok(()))]
+    );
+    assert_eq!(
+        second_client.filled.take(),
+        vec![(vec![2, 0, 0, 0, 3, 0], Ok(()))]
+    );
+}
+
+#[test]
+fn buffer_fill_error_ends_the_fill_in_progress() {
+    let (source, users) = buffer_fill(2);
+    let (first, first_client) = users[0];
+    let (second, second_client) = users[1];
+
+    assert!(first.fill(leak_buffer(8), 8).is_ok());
+    assert!(second.fill(leak_buffer(4), 4).is_ok());
+    source.deliver(&[0x0403_0201], Ok(()));
+    source.deliver(&[0xffff_ffff], Err(ErrorCode::FAIL));
+
+    assert_eq!(
+        first_client.filled.take(),
+        vec![(vec![1, 2, 3, 4, 0, 0, 0, 0], Err(ErrorCode::FAIL))]
+    );
+    // The next fill starts over with new randomness.
+    assert!(source.is_requested());
+    assert!(second_client.filled.borrow().is_empty());
+    source.deliver(&[0x0807_0605], Ok(()));
+
+    assert!(!source.is_requested());
+    assert_eq!(
+        second_client.filled.take(),
+        vec![(vec![5, 6, 7, 8], Ok(()))]
+    );
+}
+
+#[test]
+fn buffer_fill_rejected() {
+    let (source, users) = buffer_fill(1);
+    let (user, client) = users[0];
+
+    let error = |result: Result<(), (ErrorCode, &'static mut [u8])>| result.map_err(|e| e.0);
+    assert_eq!(error(user.fill(leak_buffer(4), 0)), Err(ErrorCode::INVAL));
+    assert_eq!(error(user.fill(leak_buffer(4), 5)), Err(ErrorCode::INVAL));
+    source.fail_next_get(ErrorCode::OFF);
+    assert_eq!(error(user.fill(leak_buffer(4), 4)), Err(ErrorCode::OFF));
+    assert!(!source.is_requested());
+
+    // The user is not left busy.
+    assert!(user.fill(leak_buffer(4), 4).is_ok());
+    source.deliver(&[7], Ok(()));
+    assert_eq!(client.filled.take(), vec![(vec![7, 0, 0, 0], Ok(()))]);
+}
+
+#[test]
+fn restarted_app_does_not_get_the_request_of_its_previous_instance() {
+    let board = Board::new();
+    let (source, driver) = rng_driver(&board);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 4);
+    apps.command(0, DRIVER_NUM, 1, 4, 0);
+    run(&apps, driver);
+
+    // The app crashes while waiting, and its new instance asks for more.
+    apps.restart(0);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 8);
+    apps.command(0, DRIVER_NUM, 1, 8, 0);
+    run(&apps, driver);
+    apps.take_returns(0);
+
+    // The first word would have completed the old request.
+    source.deliver(&[1], Ok(()));
+    run(&apps, driver);
+    assert!(apps.take_upcalls(0).is_empty());
+    source.deliver(&[2], Ok(()));
+    run(&apps, driver);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 8, 0])]);
+    assert_eq!(apps.read_memory(0, 8), [1, 0, 0, 0, 2, 0, 0, 0]);
+}
+
+type ChaCha = ChaChaRng<'static, DeterministicEntropy32<'static>>;
+
+const SEED: [u32; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+fn chacha(reseed_interval: usize) -> (&'static DeterministicEntropy32<'static>, &'static ChaCha) {
+    take_deferred_calls();
+    let source = leak(DeterministicEntropy32::new());
+    let chacha = leak(ChaChaRng::new(source, reseed_interval));
+    chacha.initialize();
+    (source, chacha)
+}
+
+/// A generator seeded with `SEED`.
+fn seeded_chacha(
+    reseed_interval: usize,
+) -> (&'static DeterministicEntropy32<'static>, &'static ChaCha) {
+    let (source, chacha) = chacha(reseed_interval);
+    assert!(source.is_requested());
+    source.deliver(&SEED[..3], Ok(()));
+    assert!(!chacha.is_seeded());
+    source.deliver(&SEED[3..], Ok(()));
+    assert!(chacha.is_seeded());
+    assert!(!source.is_requested());
+    (source, chacha)
+}
+
+#[test]
+fn chacha_rng_reseeding_changes_the_stream() {
+    let (_, a) = seeded_chacha(CHACHA_RESEED_INTERVAL);
+    let (source, b) = seeded_chacha(CHACHA_RESEED_INTERVAL);
+    let first: Vec<u32> = (0..20).map(|_| a.random()).collect();
+    assert_eq!(first, (0..20).map(|_| b.random()).collect::<Vec<_>>());
+    // No word repeats within a few blocks.
+    let mut sorted = first.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), first.len());
+
+    // Until the new seed arrives, the stream goes on unchanged.
+    assert_eq!(b.refresh(), Ok(()));
+    assert_eq!(b.refresh(), Err(ErrorCode::ALREADY));
+    assert_eq!(a.random(), b.random());
+    source.deliver(&SEED, Ok(()));
+    assert_eq!(b.outputs_since_reseed(), 0);
+    let after: Vec<u32> = (0..8).map(|_| b.random()).collect();
+    assert_ne!(after, (0..8).map(|_| a.random()).collect::<Vec<_>>());
+
+    // `reseed()` sets the key, whatever came before.
+    a.reseed(7);
+    b.reseed(7);
+    assert_eq!(a.random(), b.random());
+}
+
+#[test]
+fn chacha_rng_reseeds_every_interval() {
+    let (source, chacha) = seeded_chacha(16);
+    for _ in 0..15 {
+        chacha.random();
+    }
+    assert!(!source.is_requested());
+    chacha.random();
+    assert!(source.is_requested());
+
+    // The generator keeps going while the seed is on its way.
+    chacha.random();
+    assert_eq!(chacha.outputs_since_reseed(), 17);
+    source.deliver(&SEED, Ok(()));
+    assert_eq!(chacha.outputs_since_reseed(), 0);
+    assert!(!source.is_requested());
+}
+
+#[test]
+fn chacha_rng_waits_for_its_seed() {
+    let (source, chacha) = chacha(CHACHA_RESEED_INTERVAL);
+    let client = leak(Collector::new(20));
+    chacha.set_client(client);
+    assert_eq!(chacha.get(), Ok(()));
+    chacha.handle_deferred_call();
+    assert!(client.words.borrow().is_empty());
+
+    source.deliver(&SEED, Ok(()));
+    chacha.handle_deferred_call();
+    chacha.handle_deferred_call();
+    assert_eq!(client.errors.take(), vec![Ok(()), Ok(())]);
+    let (_, reference) = seeded_chacha(CHACHA_RESEED_INTERVAL);
+    assert_eq!(
+        client.words.take(),
+        (0..20).map(|_| reference.random()).collect::<Vec<_>>()
+    );
+    // The client is done.
+    chacha.handle_deferred_call();
+    assert!(client.errors.borrow().is_empty());
+}
+
+#[test]
+fn chacha_rng_keeps_the_initialize_error() {
+    take_deferred_calls();
+    let source = leak(DeterministicEntropy32::new());
+    let chacha = leak(ChaChaRng::new(source, CHACHA_RESEED_INTERVAL));
+    source.fail_next_get(ErrorCode::OFF);
+    chacha.initialize();
+    assert!(!chacha.is_seeded());
+    assert_eq!(chacha.seed_error(), Some(ErrorCode::OFF));
+    assert!(!source.is_requested());
+
+    // The first request asks the source again, and the seed clears the
+    // error.
+    let client = leak(Collector::new(1));
+    chacha.set_client(client);
+    assert_eq!(chacha.get(), Ok(()));
+    source.deliver(&SEED, Ok(()));
+    assert!(chacha.is_seeded());
+    assert_eq!(chacha.seed_error(), None);
+    chacha.handle_deferred_call();
+    assert_eq!(client.errors.take(), vec![Ok(())]);
+}
+
+#[test]
+fn chacha_rng_source_errors_end_unseeded_requests() {
+    let (source, chacha) = chacha(CHACHA_RESEED_INTERVAL);
+    let client = leak(Collector::new(1));
+    chacha.set_client(client);
+    assert_eq!(chacha.get(), Ok(()));
+    source.deliver(&[], Err(ErrorCode::FAIL));
+    assert_eq!(client.errors.take(), vec![Err(ErrorCode::FAIL)]);
+    assert!(!chacha.is_seeded());
+    assert_eq!(chacha.seed_error(), Some(ErrorCode::FAIL));
+
+    // A new request asks the source again, and its error is passed on.
+    source.fail_next_get(ErrorCode::OFF);
+    assert_eq!(chacha.get(), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn chacha_rng_backs_the_driver() {
+    let board = Board::new();
+    let (source, chacha) = chacha(CHACHA_RESEED_INTERVAL);
+    let driver = leak(RngDriver::new(chacha, board.create_grant(DRIVER_NUM)));
+    chacha.set_client(driver);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.allow_readwrite(0, DRIVER_NUM, 0, 8);
+    apps.command(0, DRIVER_NUM, 1, 8, 0);
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+    apps.take_returns(0);
+
+    source.deliver(&SEED, Ok(()));
+    chacha.handle_deferred_call();
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+    assert_eq!(apps.take_upcalls(0), vec![(DRIVER_NUM, 0, [0, 8, 0])]);
+    let (_, reference) = seeded_chacha(CHACHA_RESEED_INTERVAL);
+    let expected: Vec<u8> = (0..2)
+        .flat_map(|_| reference.random().to_le_bytes())
+        .collect();
+    assert_eq!(apps.read_memory(0, 8), expected);
+
+    // The words left over went to the cache, the source is not asked.
+    assert!(driver.cached_words() > 0);
+    assert!(!source.is_requested());
+}
@@ -0,0 +1,361 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Settings persisted in a RAM backed storage.
+
+use std::cell::RefCell;
+
+use capsules_extra::settings::{
+    LoadClient, SetClient, Setting, SettingClient, Settings, DRIVER_NUM, RECORD_LEN,
+};
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::process::ShortId;
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, Board, RamStorage};
+
+const START: usize = 0x40;
+const RECORDS: usize = 8;
+
+const PERIOD: u32 = 0x10;
+const THRESHOLD: u32 = 0x20;
+const HEARTBEAT: u32 = 0x30;
+
+/// Everything the settings reported, in order.
+#[derive(Default)]
+struct Log {
+    applied: RefCell<Vec<(&'static str, u32, i32)>>,
+    loaded: RefCell<Vec<Result<(), ErrorCode>>>,
+    set: RefCell<Vec<(u32, Result<(), ErrorCode>)>>,
+}
+
+impl LoadClient for Log {
+    fn load_done(&self, result: Result<(), ErrorCode>) {
+        self.loaded.borrow_mut().push(result);
+    }
+}
+
+impl SetClient for Log {
+    fn set_done(&self, key: u32, result: Result<(), ErrorCode>) {
+        self.set.borrow_mut().push((key, result));
+    }
+}
+
+/// A capsule owning settings.
+struct Owner {
+    name: &'static str,
+    log: &'static Log,
+}
+
+impl SettingClient for Owner {
+    fn apply_setting(&self, key: u32, value: i32) {
+        self.log.applied.borrow_mut().push((self.name, key, value));
+    }
+}
+
+struct Fixture {
+    board: Board,
+    storage: &'static RamStorage<'static>,
+    settings: &'static Settings<'static>,
+    log: &'static Log,
+}
+
+/// Registers the period of a logger, a threshold and a heartbeat interval,
+/// owned by different capsules.
+fn setup() -> Fixture {
+    let board = Board::new();
+    let storage = leak(RamStorage::new(START + RECORDS * RECORD_LEN));
+    let settings = leak(Settings::new(
+        storage,
+        START,
+        RECORDS * RECORD_LEN,
+        ShortId::Fixed(1.try_into().unwrap()),
+        board.create_grant(DRIVER_NUM),
+        leak_buffer(RECORDS * RECORD_LEN),
+    ));
+    storage.set_client(settings);
+    let log = leak(Log::default());
+    settings.set_load_client(log);
+    settings.set_client(log);
+    let logger = leak(Owner {
+        name: "logger",
+        log,
+    });
+    let thermal = leak(Owner {
+        name: "thermal",
+        log,
+    });
+    for setting in [
+        Setting::new(PERIOD, 1000, 100, 60_000, logger),
+        Setting::new(THRESHOLD, -10, -40, 85, thermal),
+        Setting::new(HEARTBEAT, 500, 10, 10_000, logger),
+    ] {
+        assert_eq!(settings.register(leak(setting)), Ok(()));
+    }
+    Fixture {
+        board,
+        storage,
+        settings,
+        log,
+    }
+}
+
+fn record(key: u32, value: i32) -> Vec<u8> {
+    [key.to_le_bytes(), value.to_le_bytes()].concat()
+}
+
+fn persist(fixture: &Fixture, slot: usize, key: u32, value: i32) {
+    fixture
+        .storage
+        .set_contents(START + slot * RECORD_LEN, &record(key, value));
+}
+
+fn load(fixture: &Fixture) {
+    assert_eq!(fixture.settings.load(), Ok(()));
+    while fixture.storage.complete() {}
+}
+
+#[test]
+fn missing_keys_use_defaults() {
+    let fixture = setup();
+    // A record of a setting that is not registered any more.
+    persist(&fixture, 1, 0x99, 7);
+    load(&fixture);
+
+    assert_eq!(fixture.log.loaded.take(), vec![Ok(())]);
+    assert_eq!(
+        fixture.log.applied.take(),
+        vec![
+            ("logger", PERIOD, 1000),
+            ("thermal", THRESHOLD, -10),
+            ("logger", HEARTBEAT, 500),
+        ]
+    );
+    assert!(fixture
+        .settings
+        .settings()
+        .all(|setting| !setting.is_clamped()));
+    // Nothing is written at boot.
+    assert_eq!(
+        fixture.storage.contents(START + RECORD_LEN, RECORD_LEN),
+        record(0x99, 7)
+    );
+    assert_eq!(fixture.storage.contents(START, RECORD_LEN), vec![0xff; 8]);
+}
+
+#[test]
+fn out_of_range_values_are_clamped_and_flagged() {
+    let fixture = setup();
+    persist(&fixture, 0, THRESHOLD, 120);
+    persist(&fixture, 3, PERIOD, 5);
+    persist(&fixture, 5, HEARTBEAT, 2000);
+    load(&fixture);
+
+    assert_eq!(
+        fixture.log.applied.take(),
+        vec![
+            ("logger", PERIOD, 100),
+            ("thermal", THRESHOLD, 85),
+            ("logger", HEARTBEAT, 2000),
+        ]
+    );
+    assert_eq!(
+        fixture
+            .settings
+            .settings()
+            .map(|setting| (setting.key(), setting.value(), setting.is_clamped()))
+            .collect::<Vec<_>>(),
+        vec![
+            (PERIOD, 100, true),
+            (THRESHOLD, 85, true),
+            (HEARTBEAT, 2000, false)
+        ]
+    );
+
+    // A change clears the flag.
+    assert_eq!(fixture.settings.set(PERIOD, 250), Ok(()));
+    while fixture.storage.complete() {}
+    assert_eq!(fixture.log.set.take(), vec![(PERIOD, Ok(()))]);
+    assert_eq!(
+        fixture.storage.contents(START + 3 * RECORD_LEN, RECORD_LEN),
+        record(PERIOD, 250)
+    );
+    assert!(fixture
+        .settings
+        .settings()
+        .all(|setting| setting.key() == THRESHOLD || !setting.is_clamped()));
+}
+
+#[test]
+fn apply_callbacks_follow_registration_order() {
+    let fixture = setup();
+    // The records are in another order than the registrations.
+    persist(&fixture, 0, HEARTBEAT, 20);
+    persist(&fixture, 1, THRESHOLD, 30);
+    persist(&fixture, 2, PERIOD, 200);
+    load(&fixture);
+    assert_eq!(
+        fixture.log.applied.take(),
+        vec![
+            ("logger", PERIOD, 200),
+            ("thermal", THRESHOLD, 30),
+            ("logger", HEARTBEAT, 20),
+        ]
+    );
+
+    // A change only applies the changed setting, once it was persisted.
+    assert_eq!(fixture.settings.set(THRESHOLD, -5), Ok(()));
+    assert!(fixture.log.applied.borrow().is_empty());
+    assert_eq!(fixture.settings.set(PERIOD, 300), Err(ErrorCode::BUSY));
+    while fixture.storage.complete() {}
+    assert_eq!(fixture.log.applied.take(), vec![("thermal", THRESHOLD, -5)]);
+    assert_eq!(
+        fixture.storage.contents(START + RECORD_LEN, RECORD_LEN),
+        record(THRESHOLD, -5)
+    );
+}
+
+#[test]
+fn new_settings_take_free_records() {
+    let fixture = setup();
+    persist(&fixture, 0, 0x99, 7);
+    persist(&fixture, 2, THRESHOLD, 30);
+    load(&fixture);
+    fixture.log.applied.take();
+
+    assert_eq!(fixture.settings.set(HEARTBEAT, 20), Ok(()));
+    while fixture.storage.complete() {}
+    assert_eq!(fixture.settings.set(PERIOD, 200), Ok(()));
+    while fixture.storage.complete() {}
+    assert_eq!(
+        fixture.storage.contents(START, 4 * RECORD_LEN),
+        [
+            record(0x99, 7),
+            record(PERIOD, 200),
+            record(THRESHOLD, 30),
+            record(HEARTBEAT, 20),
+        ]
+        .concat()
+    );
+
+    // The values are found again at the next boot.
+    let rebooted = setup();
+    rebooted
+        .storage
+        .set_contents(START, &fixture.storage.contents(START, 4 * RECORD_LEN));
+    load(&rebooted);
+    assert_eq!(
+        rebooted.log.applied.take(),
+        vec![
+            ("logger", PERIOD, 200),
+            ("thermal", THRESHOLD, 30),
+            ("logger", HEARTBEAT, 20),
+        ]
+    );
+}
+
+#[test]
+fn changes_are_validated() {
+    let fixture = setup();
+    assert_eq!(fixture.settings.set(PERIOD, 200), Err(ErrorCode::OFF));
+
+    load(&fixture);
+    for (key, value) in [(PERIOD, 99), (PERIOD, 60_001), (THRESHOLD, -41), (0x99, 0)] {
+        assert_eq!(fixture.settings.set(key, value), Err(ErrorCode::INVAL));
+    }
+    assert!(!fixture.storage.is_pending());
+
+    assert_eq!(fixture.settings.set(PERIOD, 60_000), Ok(()));
+    while fixture.storage.complete() {}
+    assert_eq!(fixture.log.set.take(), vec![(PERIOD, Ok(()))]);
+}
+
+#[test]
+fn only_the_manager_may_use_the_driver() {
+    let fixture = setup();
+    load(&fixture);
+
+    // The app has no fixed ShortId, so it is not the manager.
+    let apps = fixture.board.load_apps(1);
+    apps.command(0, DRIVER_NUM, 0, 0, 0);
+    apps.command(0, DRIVER_NUM, 3, PERIOD as usize, 200);
+    apps.run(&[(DRIVER_NUM, fixture.settings as &dyn SyscallDriver)]);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ]
+    ));
+    assert!(!fixture.storage.is_pending());
+}
+
+#[test]
+fn registration_is_checked() {
+    let fixture = setup();
+    let owner = leak(Owner {
+        name: "other",
+        log: fixture.log,
+    });
+    for (setting, error) in [
+        (Setting::new(0, 1, 0, 2, owner), ErrorCode::INVAL),
+        (Setting::new(0xFFFF_FFFF, 1, 0, 2, owner), ErrorCode::INVAL),
+        (Setting::new(0x40, 3, 0, 2, owner), ErrorCode::INVAL),
+        (Setting::new(PERIOD, 1, 0, 2, owner), ErrorCode::ALREADY),
+    ] {
+        assert_eq!(fixture.settings.register(leak(setting)), Err(error));
+    }
+
+    load(&fixture);
+    assert_eq!(
+        fixture
+            .settings
+            .register(leak(Setting::new(0x40, 1, 0, 2, owner))),
+        Err(ErrorCode::BUSY)
+    );
+    assert_eq!(fixture.settings.settings().count(), 3);
+}
+
+#[test]
+fn failed_load_applies_defaults() {
+    let fixture = setup();
+    persist(&fixture, 0, PERIOD, 200);
+    assert_eq!(fixture.settings.load(), Ok(()));
+    fixture.storage.fail_next(ErrorCode::FAIL);
+    assert!(fixture.storage.complete());
+
+    assert_eq!(fixture.log.loaded.take(), vec![Err(ErrorCode::FAIL)]);
+    assert_eq!(
+        fixture.log.applied.take(),
+        vec![
+            ("logger", PERIOD, 1000),
+            ("thermal", THRESHOLD, -10),
+            ("logger", HEARTBEAT, 500),
+        ]
+    );
+    // The records are unknown, nothing may be overwritten.
+    assert_eq!(fixture.settings.set(HEARTBEAT, 20), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn failed_write_is_not_applied() {
+    let fixture = setup();
+    load(&fixture);
+    fixture.log.applied.take();
+
+    assert_eq!(fixture.settings.set(PERIOD, 200), Ok(()));
+    fixture.storage.fail_next(ErrorCode::FAIL);
+    assert!(fixture.storage.complete());
+    assert_eq!(fixture.log.set.take(), vec![(PERIOD, Err(ErrorCode::FAIL))]);
+    assert!(fixture.log.applied.take().is_empty());
+    assert_eq!(
+        fixture
+            .settings
+            .settings()
+            .next()
+            .map(|setting| setting.value()),
+        Some(1000)
+    );
+}
@@ -0,0 +1,784 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! SI7021 identity read, resolution and heater settings, measurements
+//! queued while the chip is busy, temperatures read from humidity
+//! conversions, CRC checking, and the settings from processes, over a
+//! scripted I2C device.
+
+use std::cell::RefCell;
+
+use capsules_extra::si7021::{
+    crc8, Si7021Driver, Si7021Id, Si7021IdClient, Si7021Resolution, DRIVER_NUM,
+    PREVIOUS_RH_MAX_AGE_MS, SI7021,
+};
+use kernel::errorcode::into_statuscode;
+use kernel::hil::i2c;
+use kernel::hil::sensors::{HumidityClient, HumidityDriver, TemperatureClient, TemperatureDriver};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::syscall::{SyscallDriver, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, leak_buffer, Apps, Board, FakeAlarm, ScriptedI2CDevice};
+
+type Sensor = SI7021<'static, FakeAlarm<'static, Freq1KHz>, ScriptedI2CDevice<'static>>;
+
+#[derive(Default)]
+struct Client {
+    ids: RefCell<Vec<Result<Si7021Id, ErrorCode>>>,
+    humidities: RefCell<Vec<usize>>,
+    temperatures: RefCell<Vec<Result<i32, ErrorCode>>>,
+}
+
+impl TemperatureClient for Client {
+    fn callback(&self, temperature: Result<i32, ErrorCode>) {
+        self.temperatures.borrow_mut().push(temperature);
+    }
+}
+
+impl HumidityClient for Client {
+    fn callback(&self, humidity: usize) {
+        self.humidities.borrow_mut().push(humidity);
+    }
+}
+
+impl Si7021IdClient for Client {
+    fn id_read(&self, id: Result<Si7021Id, ErrorCode>) {
+        self.ids.borrow_mut().push(id);
+    }
+}
+
+fn setup() -> (
+    &'static Sensor,
+    &'static ScriptedI2CDevice<'static>,
+    &'static Client,
+    &'static FakeAlarm<'static, Freq1KHz>,
+) {
+    let i2c = leak(ScriptedI2CDevice::new());
+    let alarm = leak(FakeAlarm::new());
+    let sensor = leak(SI7021::new(i2c, alarm, leak_buffer(14)));
+    i2c.set_client(sensor);
+    alarm.set_alarm_client(sensor);
+    let client = leak(Client::default());
+    sensor.set_id_client(client);
+    HumidityDriver::set_client(sensor, client);
+    TemperatureDriver::set_client(sensor, client);
+    (sensor, i2c, client, alarm)
+}
+
+/// `raw` followed by its CRC, as the chip sends a measurement.
+fn with_crc(raw: [u8; 2]) -> Vec<u8> {
+    vec![raw[0], raw[1], crc8(&raw)]
+}
+
+#[test]
+fn crc8_of_the_datasheet_examples() {
+    assert_eq!(crc8(&[]), 0x00);
+    assert_eq!(crc8(&[0xDC]), 0x79);
+    assert_eq!(crc8(&[0x68, 0x3A]), 0x7C);
+    assert_eq!(crc8(&[0x4E, 0x85]), 0x6B);
+    assert_eq!(crc8(&[0x66, 0x7C]), 0x8A);
+    assert_eq!(crc8(&[0x5F, 0x8C]), 0x79);
+}
+
+#[test]
+fn read_id_of_an_si7021() {
+    let (sensor, i2c, client, _) = setup();
+
+    assert_eq!(sensor.read_id(), Ok(()));
+    assert_eq!(sensor.read_id(), Err(ErrorCode::BUSY));
+    assert!(i2c.complete());
+    // SNA_3 to SNA_0, each followed by the CRC of SNA so far.
+    let sna = [0x12, 0x34, 0x56, 0x78];
+    i2c.push_response(Ok(vec![
+        sna[0],
+        crc8(&sna[..1]),
+        sna[1],
+        crc8(&sna[..2]),
+        sna[2],
+        crc8(&sna[..3]),
+        sna[3],
+        crc8(&sna),
+    ]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    // SNB_3 (the part number) and SNB_2, CRC, SNB_1 and SNB_0, CRC.
+    let snb = [0x15, 0xFF, 0xB2, 0x00];
+    i2c.push_response(Ok(vec![
+        snb[0],
+        snb[1],
+        crc8(&snb[..2]),
+        snb[2],
+        snb[3],
+        crc8(&snb),
+    ]));
+    assert!(i2c.complete());
+    i2c.push_response(Ok(vec![0x20]));
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0xFA, 0x0F],
+            vec![],
+            vec![0xFC, 0xC9],
+            vec![],
+            vec![0x84, 0xB8],
+        ]
+    );
+    assert_eq!(
+        client.ids.take(),
+        vec![Ok(Si7021Id {
+            serial_number: 0x1234_5678_15FF_B200,
+            part_number: 0x15,
+            firmware_version: 0x20,
+        })]
+    );
+
+    // The sensor is idle again.
+    assert_eq!(sensor.read_id(), Ok(()));
+}
+
+#[test]
+fn read_id_of_an_si7020() {
+    let (sensor, i2c, client, _) = setup();
+
+    assert_eq!(sensor.read_id(), Ok(()));
+    i2c.push_response(Ok(vec![]));
+    i2c.push_response(Ok(vec![0; 8]));
+    i2c.push_response(Ok(vec![]));
+    let snb = [0x14, 0, 0, 0];
+    i2c.push_response(Ok(vec![0x14, 0, crc8(&snb[..2]), 0, 0, crc8(&snb)]));
+    i2c.push_response(Ok(vec![0xFF]));
+    while i2c.complete() {}
+
+    let ids = client.ids.take();
+    assert_eq!(ids.len(), 1);
+    let id = ids[0].unwrap();
+    assert_eq!(id.part_number, 0x14);
+    assert_eq!(id.firmware_version, 0xFF);
+}
+
+#[test]
+fn read_id_failure() {
+    let (sensor, i2c, client, _) = setup();
+
+    assert_eq!(sensor.read_id(), Ok(()));
+    assert!(i2c.complete());
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+    assert_eq!(client.ids.take(), vec![Err(ErrorCode::NOACK)]);
+
+    assert_eq!(sensor.read_id(), Ok(()));
+}
+
+#[test]
+fn read_id_with_a_bad_crc() {
+    let (sensor, i2c, client, _) = setup();
+
+    assert_eq!(sensor.read_id(), Ok(()));
+    assert!(i2c.complete());
+    // The CRC after SNA_1 does not cover SNA_3 to SNA_1.
+    let sna = [0x12, 0x34, 0x56, 0x78];
+    i2c.push_response(Ok(vec![
+        sna[0],
+        crc8(&sna[..1]),
+        sna[1],
+        crc8(&sna[..2]),
+        sna[2],
+        crc8(&sna[..2]),
+        sna[3],
+        crc8(&sna),
+    ]));
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+    assert_eq!(client.ids.take(), vec![Err(ErrorCode::FAIL)]);
+
+    assert_eq!(sensor.read_id(), Ok(()));
+}
+
+#[test]
+fn set_resolution_writes_the_resolution_bits() {
+    let (sensor, i2c, _, alarm) = setup();
+
+    for (resolution, bits, delay_ms) in [
+        (Si7021Resolution::Rh8Temp12, 0x01, 7),
+        (Si7021Resolution::Rh10Temp13, 0x80, 11),
+        (Si7021Resolution::Rh11Temp11, 0x81, 12),
+        (Si7021Resolution::Rh12Temp14, 0x00, 20),
+    ] {
+        // The other bits of the register are kept as they are.
+        for register in [0x3A, 0xFF] {
+            assert_eq!(sensor.set_resolution(resolution), Ok(()));
+            assert_eq!(sensor.read_id(), Err(ErrorCode::BUSY));
+            i2c.push_response(Ok(vec![register]));
+            assert!(i2c.complete());
+            assert!(i2c.complete());
+            assert!(!i2c.is_pending());
+            assert_eq!(
+                i2c.take_written(),
+                vec![vec![0xE7], vec![0xE6, (register & 0x7E) | bits]]
+            );
+            assert_eq!(sensor.resolution(), resolution);
+        }
+
+        // The measurement waits for the conversion at this resolution.
+        assert_eq!(sensor.read_temperature(), Ok(()));
+        assert!(i2c.complete());
+        assert_eq!(alarm.armed_dt(), Some(delay_ms));
+        assert!(alarm.fire());
+        i2c.push_response(Ok(with_crc([0x66, 0x00])));
+        i2c.push_response(Ok(with_crc([0x66, 0x00])));
+        while i2c.complete() {}
+        i2c.take_written();
+    }
+}
+
+#[test]
+fn measurement_requested_while_setting_the_resolution() {
+    let (sensor, i2c, _, _) = setup();
+
+    assert_eq!(sensor.set_resolution(Si7021Resolution::Rh11Temp11), Ok(()));
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    // The temperature measurement starts once the register is written.
+    assert!(i2c.complete());
+    assert_eq!(
+        i2c.take_written(),
+        vec![vec![0xE7], vec![0xE6, 0x81], vec![0xF3]]
+    );
+}
+
+#[test]
+fn set_resolution_failure() {
+    let (sensor, i2c, _, _) = setup();
+
+    assert_eq!(sensor.set_resolution(Si7021Resolution::Rh8Temp12), Ok(()));
+    assert!(i2c.complete());
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+    assert_eq!(sensor.resolution(), Si7021Resolution::Rh12Temp14);
+    assert_eq!(sensor.set_resolution(Si7021Resolution::Rh8Temp12), Ok(()));
+}
+
+#[test]
+fn set_heater_writes_level_and_enable_bit() {
+    let (sensor, i2c, _, _) = setup();
+
+    assert_eq!(sensor.set_heater(true, 9), Ok(()));
+    assert_eq!(sensor.set_heater(false, 0), Err(ErrorCode::BUSY));
+    assert!(i2c.complete());
+    i2c.push_response(Ok(vec![0x3A]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+
+    // The level is clamped.
+    assert_eq!(sensor.set_heater(false, 200), Ok(()));
+    assert!(i2c.complete());
+    i2c.push_response(Ok(vec![0xFF]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0x51, 9],
+            vec![0xE7],
+            vec![0xE6, 0x3E],
+            vec![0x51, 15],
+            vec![0xE7],
+            vec![0xE6, 0xFB],
+        ]
+    );
+    // The resolution bits were read back along the way.
+    assert_eq!(sensor.resolution(), Si7021Resolution::Rh11Temp11);
+}
+
+#[test]
+fn set_heater_is_refused_during_a_measurement() {
+    let (sensor, i2c, _, _) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert_eq!(sensor.set_heater(true, 1), Err(ErrorCode::BUSY));
+    assert!(i2c.complete());
+    assert_eq!(sensor.set_heater(true, 1), Err(ErrorCode::BUSY));
+}
+
+#[test]
+fn humidity_after_a_heater_pulse() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity_after_heating(4, 500), Ok(()));
+    assert!(i2c.complete());
+    i2c.push_response(Ok(vec![0x3A]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+    assert_eq!(alarm.armed_dt(), Some(500));
+
+    // The heater is turned off, then the humidity is measured.
+    assert!(alarm.fire());
+    i2c.push_response(Ok(vec![0x3E]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    assert_eq!(alarm.armed_dt(), Some(20));
+    assert!(alarm.fire());
+    i2c.push_response(Ok(with_crc([0x80, 0x00])));
+    i2c.push_response(Ok(with_crc([0x80, 0x00])));
+    while i2c.complete() {}
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0x51, 4],
+            vec![0xE7],
+            vec![0xE6, 0x3E],
+            vec![0xE7],
+            vec![0xE6, 0x3A],
+            vec![0xF5],
+            vec![],
+            vec![],
+        ]
+    );
+    assert_eq!(client.humidities.take(), vec![5650]);
+}
+
+/// Run the measurement started last, the chip answers `raw`.
+fn measure(i2c: &ScriptedI2CDevice, alarm: &FakeAlarm<Freq1KHz>, raw: [u8; 2]) {
+    assert!(i2c.complete());
+    assert!(alarm.fire());
+    i2c.push_response(Ok(with_crc(raw)));
+    i2c.push_response(Ok(with_crc(raw)));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+}
+
+/// Complete the read of the temperature of the last humidity conversion,
+/// the chip answers `raw` without a checksum.
+fn read_previous(i2c: &ScriptedI2CDevice, raw: [u8; 2]) {
+    i2c.push_response(Ok(raw.to_vec()));
+    assert!(i2c.complete());
+}
+
+#[test]
+fn corrupted_temperature_is_measured_again() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert!(i2c.complete());
+    assert!(alarm.fire());
+    i2c.push_response(Ok(vec![0x66, 0x00, 0x00]));
+    i2c.push_response(Ok(vec![0x66, 0x01, crc8(&[0x66, 0x00])]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    assert!(client.temperatures.take().is_empty());
+
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![vec![0xF3], vec![], vec![], vec![0xF3], vec![], vec![],]
+    );
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+}
+
+#[test]
+fn measurements_corrupted_twice_are_reported() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    for _ in 0..2 {
+        assert!(i2c.complete());
+        assert!(alarm.fire());
+        i2c.push_response(Ok(vec![0x66, 0x00, 0x00]));
+        i2c.push_response(Ok(vec![0x66, 0x00, 0x00]));
+        assert!(i2c.complete());
+        assert!(i2c.complete());
+    }
+    assert_eq!(client.temperatures.take(), vec![Err(ErrorCode::FAIL)]);
+
+    // The humidity on deck gets a measurement of its own to retry.
+    for _ in 0..2 {
+        assert!(i2c.complete());
+        assert!(alarm.fire());
+        i2c.push_response(Err(i2c::Error::DataNak));
+        i2c.push_response(Err(i2c::Error::DataNak));
+        assert!(i2c.complete());
+        assert!(i2c.complete());
+    }
+    assert!(!i2c.is_pending());
+    assert_eq!(client.humidities.take(), vec![usize::MAX]);
+
+    // The next measurement is intact and reported.
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+}
+
+#[test]
+fn temperature_and_humidity_queued_during_a_measurement() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    // Both measurements wait on deck, one of each.
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert_eq!(sensor.read_humidity(), Err(ErrorCode::BUSY));
+    assert_eq!(sensor.read_temperature(), Err(ErrorCode::BUSY));
+
+    measure(i2c, alarm, [0x80, 0x00]);
+    // The queued humidity is measured first, the temperature is read from
+    // its conversion.
+    measure(i2c, alarm, [0x40, 0x00]);
+    read_previous(i2c, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0xF5],
+            vec![],
+            vec![],
+            vec![0xF5],
+            vec![],
+            vec![],
+            vec![0xE0],
+        ]
+    );
+    assert_eq!(client.humidities.take(), vec![5650, 2525]);
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+
+    // The sensor is idle again.
+    assert_eq!(sensor.read_id(), Ok(()));
+}
+
+#[test]
+fn humidity_queued_during_a_temperature_measurement() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    measure(i2c, alarm, [0x66, 0x00]);
+    // Another temperature is requested while the humidity is measured, and
+    // read from its conversion.
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    measure(i2c, alarm, [0x80, 0x00]);
+    read_previous(i2c, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0xF3],
+            vec![],
+            vec![],
+            vec![0xF5],
+            vec![],
+            vec![],
+            vec![0xE0],
+        ]
+    );
+    assert_eq!(client.temperatures.take(), vec![Ok(2316), Ok(2316)]);
+    assert_eq!(client.humidities.take(), vec![5650]);
+}
+
+#[test]
+fn measurements_queued_during_an_id_read() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_id(), Ok(()));
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert!(i2c.complete());
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    assert_eq!(client.ids.take(), vec![Err(ErrorCode::NOACK)]);
+
+    measure(i2c, alarm, [0x80, 0x00]);
+    read_previous(i2c, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+    assert_eq!(client.humidities.take(), vec![5650]);
+}
+
+#[test]
+fn queued_measurement_that_cannot_start_is_reported() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert!(i2c.complete());
+    assert!(alarm.fire());
+    i2c.push_response(Ok(with_crc([0x66, 0x00])));
+    i2c.push_response(Ok(with_crc([0x66, 0x00])));
+    assert!(i2c.complete());
+    // The humidity measurement is refused by the bus.
+    i2c.fail_next_start(i2c::Error::Busy);
+    assert!(i2c.complete());
+    assert!(!i2c.is_pending());
+
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+    assert_eq!(client.humidities.take(), vec![usize::MAX]);
+    // The sensor is idle again.
+    assert_eq!(sensor.read_id(), Ok(()));
+}
+
+#[test]
+fn measurement_that_cannot_start_fails_right_away() {
+    let (sensor, i2c, client, _) = setup();
+
+    i2c.fail_next_start(i2c::Error::Busy);
+    assert_eq!(sensor.read_temperature(), Err(ErrorCode::BUSY));
+    assert!(client.temperatures.borrow().is_empty());
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+}
+
+#[test]
+fn measurements_queued_while_setting_the_resolution() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.set_resolution(Si7021Resolution::Rh11Temp11), Ok(()));
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    measure(i2c, alarm, [0x80, 0x00]);
+    read_previous(i2c, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+    assert_eq!(client.humidities.take(), vec![5650]);
+}
+
+#[test]
+fn temperature_is_read_from_a_recent_humidity_conversion() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    measure(i2c, alarm, [0x80, 0x00]);
+    i2c.take_written();
+
+    // Up to the maximum age, no conversion is waited for.
+    alarm.advance(PREVIOUS_RH_MAX_AGE_MS);
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    assert_eq!(alarm.armed_dt(), None);
+    read_previous(i2c, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(i2c.take_written(), vec![vec![0xE0]]);
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+    assert_eq!(client.humidities.take(), vec![5650]);
+}
+
+#[test]
+fn temperature_is_measured_after_an_old_humidity_conversion() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    measure(i2c, alarm, [0x80, 0x00]);
+    i2c.take_written();
+
+    alarm.advance(PREVIOUS_RH_MAX_AGE_MS + 1);
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(i2c.take_written(), vec![vec![0xF3], vec![], vec![]]);
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+}
+
+#[test]
+fn temperature_is_measured_after_a_corrupted_humidity() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    for _ in 0..2 {
+        assert!(i2c.complete());
+        assert!(alarm.fire());
+        i2c.push_response(Ok(vec![0x80, 0x00, 0x00]));
+        i2c.push_response(Ok(vec![0x80, 0x00, 0x00]));
+        assert!(i2c.complete());
+        assert!(i2c.complete());
+    }
+    assert_eq!(client.humidities.take(), vec![usize::MAX]);
+    i2c.take_written();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert_eq!(i2c.take_written(), vec![vec![0xF3], vec![], vec![]]);
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+}
+
+#[test]
+fn failed_previous_temperature_read_is_measured() {
+    let (sensor, i2c, client, alarm) = setup();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    measure(i2c, alarm, [0x80, 0x00]);
+    i2c.take_written();
+
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    assert!(client.temperatures.borrow().is_empty());
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert!(!i2c.is_pending());
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![vec![0xE0], vec![0xF3], vec![], vec![]]
+    );
+    assert_eq!(client.temperatures.take(), vec![Ok(2316)]);
+
+    // The next temperature is measured as well.
+    assert_eq!(sensor.read_temperature(), Ok(()));
+    measure(i2c, alarm, [0x66, 0x00]);
+    assert_eq!(i2c.take_written(), vec![vec![0xF3], vec![], vec![]]);
+}
+
+type Driver = Si7021Driver<'static, FakeAlarm<'static, Freq1KHz>, ScriptedI2CDevice<'static>>;
+
+/// The sensor of `setup()` used by one process through an `Si7021Driver`.
+fn setup_driver() -> (
+    &'static Sensor,
+    &'static ScriptedI2CDevice<'static>,
+    &'static Client,
+    &'static FakeAlarm<'static, Freq1KHz>,
+    &'static Driver,
+    Apps,
+) {
+    let (sensor, i2c, client, alarm) = setup();
+    let board = Board::new();
+    let driver = leak(Si7021Driver::new(sensor, board.create_grant(DRIVER_NUM)));
+    sensor.set_config_client(driver);
+    let apps = board.load_apps(1);
+    apps.subscribe(0, DRIVER_NUM, 0);
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+    apps.take_returns(0);
+    (sensor, i2c, client, alarm, driver, apps)
+}
+
+/// Issues `command` with `data1` and `data2`, returns what it returned.
+fn command(
+    apps: &Apps,
+    driver: &'static Driver,
+    command: usize,
+    data1: usize,
+    data2: usize,
+) -> Vec<SyscallReturn> {
+    apps.command(0, DRIVER_NUM, command, data1, data2);
+    apps.run(&[(DRIVER_NUM, driver as &dyn SyscallDriver)]);
+    apps.take_returns(0)
+}
+
+/// The upcall of a change done with `result`.
+fn config_done(result: Result<(), ErrorCode>) -> (usize, usize, [usize; 3]) {
+    (DRIVER_NUM, 0, [into_statuscode(result), 0, 0])
+}
+
+#[test]
+fn driver_sets_the_heater_and_the_resolution() {
+    let (sensor, i2c, _, _, driver, apps) = setup_driver();
+
+    assert!(matches!(
+        command(&apps, driver, 0, 0, 0)[..],
+        [SyscallReturn::Success]
+    ));
+
+    assert!(matches!(
+        command(&apps, driver, 1, 1, 9)[..],
+        [SyscallReturn::Success]
+    ));
+    assert!(i2c.complete());
+    i2c.push_response(Ok(vec![0x3A]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    command(&apps, driver, 0, 0, 0);
+    assert_eq!(apps.take_upcalls(0), vec![config_done(Ok(()))]);
+
+    assert!(matches!(
+        command(&apps, driver, 2, 3, 0)[..],
+        [SyscallReturn::Success]
+    ));
+    i2c.push_response(Ok(vec![0x3E]));
+    assert!(i2c.complete());
+    assert!(i2c.complete());
+    command(&apps, driver, 0, 0, 0);
+    assert_eq!(apps.take_upcalls(0), vec![config_done(Ok(()))]);
+    assert_eq!(sensor.resolution(), Si7021Resolution::Rh11Temp11);
+
+    assert_eq!(
+        i2c.take_written(),
+        vec![
+            vec![0x51, 9],
+            vec![0xE7],
+            vec![0xE6, 0x3E],
+            vec![0xE7],
+            vec![0xE6, 0xBF],
+        ]
+    );
+}
+
+#[test]
+fn driver_rejects_invalid_settings() {
+    let (_, i2c, _, _, driver, apps) = setup_driver();
+
+    for (command_num, data1, data2) in [(1, 2, 0), (1, 1, 16), (2, 4, 0)] {
+        assert!(matches!(
+            command(&apps, driver, command_num, data1, data2)[..],
+            [SyscallReturn::Failure(ErrorCode::INVAL)]
+        ));
+    }
+    assert!(matches!(
+        command(&apps, driver, 3, 0, 0)[..],
+        [SyscallReturn::Failure(ErrorCode::NOSUPPORT)]
+    ));
+    assert!(!i2c.is_pending());
+}
+
+#[test]
+fn driver_reports_a_failed_change() {
+    let (sensor, i2c, _, _, driver, apps) = setup_driver();
+
+    command(&apps, driver, 2, 1, 0);
+    assert!(i2c.complete());
+    i2c.push_response(Err(i2c::Error::DataNak));
+    assert!(i2c.complete());
+    command(&apps, driver, 0, 0, 0);
+    assert_eq!(
+        apps.take_upcalls(0),
+        vec![config_done(Err(i2c::Error::DataNak.into()))]
+    );
+    assert_eq!(sensor.resolution(), Si7021Resolution::Rh12Temp14);
+}
+
+#[test]
+fn driver_changes_are_refused_during_a_measurement() {
+    let (sensor, i2c, client, alarm, driver, apps) = setup_driver();
+
+    assert_eq!(sensor.read_humidity(), Ok(()));
+    assert!(matches!(
+        command(&apps, driver, 1, 1, 5)[..],
+        [SyscallReturn::Failure(ErrorCode::BUSY)]
+    ));
+    assert!(matches!(
+        command(&apps, driver, 2, 1, 0)[..],
+        [SyscallReturn::Failure(ErrorCode::BUSY)]
+    ));
+
+    // The measurement goes on as if nothing happened.
+    measure(i2c, alarm, [0x80, 0x00]);
+    assert_eq!(client.humidities.take(), vec![5650]);
+    assert_eq!(i2c.take_written(), vec![vec![0xF5], vec![], vec![]]);
+    assert!(apps.take_upcalls(0).is_empty());
+}
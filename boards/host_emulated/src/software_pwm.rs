@@ -0,0 +1,213 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Software PWM over recording GPIO pins and a fake alarm.
+
+use capsules_extra::software_pwm::{SoftwarePwm, SoftwarePwmPin, MAX_DUTY_CYCLE};
+use kernel::hil::gpio::ActivationMode;
+use kernel::hil::pwm::PwmPin;
+use kernel::hil::time::{Alarm, Freq1MHz, Ticks, Time};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, FakeAlarm, PinLog, RecordingPin};
+
+type Pwm = SoftwarePwm<'static, FakeAlarm<'static, Freq1MHz>>;
+type Channel = SoftwarePwmPin<'static, FakeAlarm<'static, Freq1MHz>>;
+
+const MAX_FREQUENCY_HZ: usize = 20_000;
+
+struct Fixture {
+    alarm: &'static FakeAlarm<'static, Freq1MHz>,
+    pins: &'static PinLog,
+    pwm: &'static Pwm,
+}
+
+fn setup() -> Fixture {
+    let pins = leak(PinLog::default());
+    let alarm = leak(FakeAlarm::new());
+    let pwm = leak(SoftwarePwm::new(alarm, MAX_FREQUENCY_HZ));
+    alarm.set_alarm_client(pwm);
+    Fixture { alarm, pins, pwm }
+}
+
+impl Fixture {
+    fn channel(&self, id: usize, mode: ActivationMode) -> &'static Channel {
+        let channel = leak(SoftwarePwmPin::new(
+            self.pwm,
+            leak(RecordingPin::new(id, self.pins)),
+            mode,
+        ));
+        channel.add_to_mux();
+        channel
+    }
+
+    /// Fires the alarm until time reaches `until`, and returns every edge as
+    /// `(time, pin id, level)`, ordered by time then pin id.
+    fn edges_until(&self, until: u32) -> Vec<(u32, usize, bool)> {
+        let mut edges = Vec::new();
+        while let Some(dt) = self.alarm.armed_dt() {
+            let time = self.alarm.get_alarm().into_u32();
+            if time > until {
+                assert!(dt > 0);
+                break;
+            }
+            assert!(self.alarm.fire());
+            let mut fired: Vec<_> = self
+                .pins
+                .take()
+                .into_iter()
+                .map(|(id, level)| (time, id, level))
+                .collect();
+            fired.sort();
+            edges.extend(fired);
+        }
+        edges
+    }
+}
+
+/// The edges of a single active-high pin started at 0, up to `until`.
+fn expected_edges(id: usize, high: u32, low: u32, until: u32) -> Vec<(u32, usize, bool)> {
+    let mut edges = Vec::new();
+    let mut time = 0;
+    loop {
+        time += high;
+        if time > until {
+            break;
+        }
+        edges.push((time, id, false));
+        time += low;
+        if time > until {
+            break;
+        }
+        edges.push((time, id, true));
+    }
+    edges
+}
+
+#[test]
+fn edges_follow_frequency_and_duty_cycle() {
+    // (frequency, duty cycle, high ticks, low ticks) at 1 MHz.
+    let cases = [
+        (100, MAX_DUTY_CYCLE / 2, 5000, 5000),
+        (1000, MAX_DUTY_CYCLE / 4, 250, 750),
+        (3000, MAX_DUTY_CYCLE / 10, 33, 300),
+        (10_000, MAX_DUTY_CYCLE * 999 / 1000, 99, 1),
+    ];
+    for (frequency, duty, high, low) in cases {
+        let f = setup();
+        let channel = f.channel(0, ActivationMode::ActiveHigh);
+        assert_eq!(f.pins.take(), vec![(0, false)]);
+
+        assert_eq!(channel.start(frequency, duty), Ok(()));
+        assert_eq!(f.pins.take(), vec![(0, true)]);
+        let until = 3 * (high + low);
+        assert_eq!(
+            f.edges_until(until),
+            expected_edges(0, high, low, until),
+            "{} Hz, duty cycle {}",
+            frequency,
+            duty
+        );
+    }
+}
+
+#[test]
+fn channels_share_the_alarm() {
+    let f = setup();
+    let a = f.channel(0, ActivationMode::ActiveHigh);
+    let b = f.channel(1, ActivationMode::ActiveHigh);
+    f.pins.take();
+
+    // 500/500 and 625/1875 ticks.
+    assert_eq!(a.start(1000, MAX_DUTY_CYCLE / 2), Ok(()));
+    assert_eq!(b.start(400, MAX_DUTY_CYCLE / 4), Ok(()));
+    assert_eq!(f.pins.take(), vec![(0, true), (1, true)]);
+
+    let mut expected = expected_edges(0, 500, 500, 5000);
+    expected.extend(expected_edges(1, 625, 1875, 5000));
+    expected.sort();
+    let edges = f.edges_until(5000);
+    assert_eq!(edges, expected);
+    // Both edges at 2500 are serviced by the same firing.
+    assert!(edges.contains(&(2500, 0, false)) && edges.contains(&(2500, 1, true)));
+    assert_eq!(f.alarm.fired_count(), 12);
+
+    // Stopping one channel leaves the other one on schedule.
+    assert_eq!(b.stop(), Ok(()));
+    assert_eq!(f.pins.take(), vec![(1, false)]);
+    assert_eq!(f.alarm.armed_dt(), Some(500));
+    assert_eq!(f.edges_until(6000), vec![(5500, 0, false), (6000, 0, true)]);
+}
+
+#[test]
+fn constant_duty_cycles_do_not_use_the_alarm() {
+    let f = setup();
+    let channel = f.channel(0, ActivationMode::ActiveLow);
+    assert_eq!(f.pins.take(), vec![(0, true)]);
+
+    assert_eq!(channel.start(1000, 0), Ok(()));
+    assert_eq!(f.pins.take(), vec![(0, true)]);
+    assert_eq!(f.alarm.armed_dt(), None);
+
+    assert_eq!(channel.start(1000, MAX_DUTY_CYCLE), Ok(()));
+    assert_eq!(f.pins.take(), vec![(0, false)]);
+    assert_eq!(f.alarm.armed_dt(), None);
+}
+
+#[test]
+fn stop_leaves_the_pin_inactive() {
+    let f = setup();
+    let channel = f.channel(0, ActivationMode::ActiveLow);
+    f.pins.take();
+
+    assert_eq!(channel.start(1000, MAX_DUTY_CYCLE / 2), Ok(()));
+    assert!(f.alarm.fire());
+    assert!(f.alarm.fire());
+    assert_eq!(f.pins.take(), vec![(0, false), (0, true), (0, false)]);
+
+    // Stopped while active: back to inactive, which is high.
+    assert_eq!(channel.stop(), Ok(()));
+    assert_eq!(f.pins.take(), vec![(0, true)]);
+    assert_eq!(f.alarm.armed_dt(), None);
+}
+
+#[test]
+fn late_edges_restart_the_phase() {
+    let f = setup();
+    let channel = f.channel(0, ActivationMode::ActiveHigh);
+    f.pins.take();
+
+    assert_eq!(channel.start(1000, MAX_DUTY_CYCLE / 2), Ok(()));
+    // The alarm is serviced 1500 ticks after the first edge was due.
+    f.alarm.advance(2000);
+    assert!(f.alarm.fire());
+    assert_eq!(f.pins.take(), vec![(0, true), (0, false)]);
+    assert_eq!(f.alarm.armed_dt(), Some(500));
+    assert_eq!(f.alarm.now().into_u32(), 2000);
+    assert_eq!(
+        f.edges_until(3500),
+        vec![(2500, 0, true), (3000, 0, false), (3500, 0, true)]
+    );
+}
+
+#[test]
+fn out_of_range_settings_are_refused() {
+    let f = setup();
+    let channel = f.channel(0, ActivationMode::ActiveHigh);
+    f.pins.take();
+
+    assert_eq!(channel.get_maximum_frequency_hz(), MAX_FREQUENCY_HZ);
+    assert_eq!(channel.get_maximum_duty_cycle(), MAX_DUTY_CYCLE);
+    assert_eq!(channel.start(0, 0), Err(ErrorCode::INVAL));
+    assert_eq!(
+        channel.start(MAX_FREQUENCY_HZ + 1, 0),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(
+        channel.start(1000, MAX_DUTY_CYCLE + 1),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(f.pins.take(), vec![]);
+    assert_eq!(f.alarm.armed_dt(), None);
+}
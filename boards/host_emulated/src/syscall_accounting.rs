@@ -0,0 +1,145 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Syscall accounting: the counters table, its slot overflow policy, and
+//! commands counted on their way from apps to a driver.
+
+use capsules_extra::syscall_accounting::{AccountedDriver, SyscallCounters, DRIVER_NUM};
+use kernel::process::ShortId;
+use kernel::syscall::{CommandReturn, SyscallDriver, SyscallReturn};
+use kernel::{ErrorCode, ProcessId};
+
+use crate::fixtures::{leak, Board};
+
+const RNG: usize = 0x40001;
+const KV: usize = 0x50003;
+const CONSOLE: usize = 0x1;
+
+fn id(id: u32) -> ShortId {
+    ShortId::Fixed(id.try_into().unwrap())
+}
+
+#[test]
+fn counts_per_app_and_driver() {
+    let counters = SyscallCounters::<2, 2>::new([RNG, KV], id(100));
+
+    counters.count(RNG, id(1));
+    counters.count(RNG, id(1));
+    counters.count(KV, id(1));
+    counters.count(RNG, id(2));
+    // Drivers outside the set are not counted.
+    counters.count(CONSOLE, id(3));
+    // Apps without a fixed ShortId cannot be told apart.
+    counters.count(KV, ShortId::LocallyUnique);
+
+    assert_eq!(counters.commands(id(1), RNG), Some(2));
+    assert_eq!(counters.commands(id(1), KV), Some(1));
+    assert_eq!(counters.commands(id(2), RNG), Some(1));
+    assert_eq!(counters.commands(id(2), KV), Some(0));
+    assert_eq!(counters.commands(id(1), CONSOLE), None);
+    assert_eq!(counters.commands(id(3), RNG), None);
+    assert_eq!(counters.other_commands(RNG), Some(0));
+    assert_eq!(counters.other_commands(KV), Some(1));
+    assert_eq!(counters.other_commands(CONSOLE), None);
+}
+
+#[test]
+fn apps_past_the_slots_share_the_other_bucket() {
+    let counters = SyscallCounters::<2, 1>::new([RNG], id(100));
+
+    counters.count(RNG, id(1));
+    counters.count(RNG, id(2));
+    counters.count(RNG, id(3));
+    counters.count(RNG, id(4));
+    counters.count(RNG, id(3));
+    // The apps seen first keep their slots.
+    counters.count(RNG, id(1));
+
+    assert_eq!(counters.commands(id(1), RNG), Some(2));
+    assert_eq!(counters.commands(id(2), RNG), Some(1));
+    assert_eq!(counters.commands(id(3), RNG), None);
+    assert_eq!(counters.commands(id(4), RNG), None);
+    assert_eq!(counters.other_commands(RNG), Some(3));
+
+    // Clearing frees the slots for the next apps.
+    counters.clear();
+    assert_eq!(counters.commands(id(1), RNG), None);
+    assert_eq!(counters.other_commands(RNG), Some(0));
+    counters.count(RNG, id(3));
+    assert_eq!(counters.commands(id(3), RNG), Some(1));
+}
+
+#[test]
+fn counters_saturate() {
+    let counters = SyscallCounters::<1, 1>::new([RNG], id(100));
+
+    for _ in 0..70_000 {
+        counters.count(RNG, id(1));
+        counters.count(RNG, id(2));
+    }
+    assert_eq!(counters.commands(id(1), RNG), Some(u16::MAX));
+    assert_eq!(counters.other_commands(RNG), Some(u16::MAX));
+}
+
+#[test]
+fn only_the_monitor_reads_the_table() {
+    let board = Board::new();
+    let counters = leak(SyscallCounters::<2, 1>::new([RNG], id(100)));
+    let apps = board.load_apps(1);
+
+    // The app has no fixed ShortId, so it is not the monitor.
+    apps.command(0, DRIVER_NUM, 0, 0, 0);
+    apps.command(0, DRIVER_NUM, 5, 0, 0);
+    apps.run(&[(DRIVER_NUM, counters as &dyn SyscallDriver)]);
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ]
+    ));
+}
+
+/// Returns its first argument.
+struct Echo;
+
+impl SyscallDriver for Echo {
+    fn command(&self, _: usize, data1: usize, _: usize, _: ProcessId) -> CommandReturn {
+        CommandReturn::success_u32(data1 as u32)
+    }
+
+    fn allocate_grant(&self, _: ProcessId) -> Result<(), kernel::process::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn commands_from_apps_are_counted_before_the_driver() {
+    let board = Board::new();
+    let counters = leak(SyscallCounters::<2, 1>::new([RNG], id(100)));
+    let apps = board.load_apps(2);
+    let echo = leak(Echo);
+    let rng = AccountedDriver::new(echo, RNG, counters);
+    let console = AccountedDriver::new(echo, CONSOLE, counters);
+
+    apps.command(0, RNG, 1, 7, 0);
+    apps.command(1, RNG, 2, 8, 0);
+    apps.command(1, RNG, 2, 9, 0);
+    apps.command(1, CONSOLE, 1, 10, 0);
+    apps.run(&[(RNG, &rng), (CONSOLE, &console)]);
+
+    assert!(matches!(
+        apps.take_returns(0)[..],
+        [SyscallReturn::SuccessU32(7)]
+    ));
+    assert!(matches!(
+        apps.take_returns(1)[..],
+        [
+            SyscallReturn::SuccessU32(8),
+            SyscallReturn::SuccessU32(9),
+            SyscallReturn::SuccessU32(10)
+        ]
+    ));
+    assert_eq!(counters.other_commands(RNG), Some(3));
+}
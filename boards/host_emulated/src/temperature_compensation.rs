@@ -0,0 +1,174 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Temperature compensation of ADC voltages, alone and through the `adc`
+//! console command.
+
+use capsules_core::process_console::{ConsoleCommand, ConsoleCommandClient};
+use capsules_extra::console_commands::AdcCommand;
+use capsules_extra::temperature_compensation::{
+    Coefficients, Compensation, TemperatureCompensation, VoltageCompensation,
+};
+use kernel::hil::adc::AdcChannel;
+use kernel::hil::sensors::TemperatureDriver;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::ErrorCode;
+
+use crate::fixtures::{leak, FakeAdcChannel, FakeAlarm, FakeTemperature};
+
+type Compensator = TemperatureCompensation<'static, FakeAlarm<'static, Freq1KHz>>;
+
+/// 0.3% per 10 degrees on channel 0, no error at 25 degrees. Channel 1 is
+/// not corrected.
+const COEFFICIENTS: [Coefficients; 1] = [Coefficients {
+    slope: 300,
+    intercept: -7_500,
+}];
+
+fn setup() -> (
+    &'static Compensator,
+    &'static FakeTemperature<'static>,
+    &'static FakeAlarm<'static, Freq1KHz>,
+) {
+    let temperature = leak(FakeTemperature::new());
+    let alarm = leak(FakeAlarm::new());
+    let compensation = leak(TemperatureCompensation::new(
+        temperature,
+        alarm,
+        &COEFFICIENTS,
+        1_000,
+        2_500,
+    ));
+    temperature.set_client(compensation);
+    alarm.set_alarm_client(compensation);
+    (compensation, temperature, alarm)
+}
+
+fn compensated(mv: usize, age_ms: u32) -> Compensation {
+    Compensation {
+        mv: mv,
+        applied: true,
+        temperature_age_ms: Some(age_ms),
+    }
+}
+
+fn uncompensated(mv: usize, age_ms: Option<u32>) -> Compensation {
+    Compensation {
+        mv: mv,
+        applied: false,
+        temperature_age_ms: age_ms,
+    }
+}
+
+#[test]
+fn corrects_with_the_latest_temperature() {
+    let (compensation, temperature, alarm) = setup();
+
+    // Nothing to correct with before the first reading.
+    assert_eq!(compensation.compensate(0, 1000), uncompensated(1000, None));
+
+    assert_eq!(compensation.start(), Ok(()));
+    assert_eq!(alarm.armed_dt(), Some(1_000));
+    assert!(temperature.is_requested());
+    assert!(temperature.deliver(Ok(3_500)));
+    assert_eq!(compensation.temperature(), Some((3_500, 0)));
+    assert_eq!(compensation.compensate(0, 1000), compensated(997, 0));
+    // Channels without coefficients are left as they are.
+    assert_eq!(
+        compensation.compensate(1, 1000),
+        uncompensated(1000, Some(0))
+    );
+
+    assert!(alarm.fire());
+    assert_eq!(temperature.reads(), 2);
+    assert!(temperature.deliver(Ok(-1_000)));
+    assert_eq!(compensation.compensate(0, 1000), compensated(1010, 0));
+
+    assert!(alarm.fire());
+    assert!(temperature.deliver(Ok(2_500)));
+    assert_eq!(compensation.compensate(0, 1000), compensated(1000, 0));
+}
+
+#[test]
+fn stale_temperature_is_not_used() {
+    let (compensation, temperature, alarm) = setup();
+
+    assert_eq!(compensation.start(), Ok(()));
+    assert!(temperature.deliver(Ok(3_500)));
+
+    // A failed reading keeps the previous one, which ages.
+    assert!(alarm.fire());
+    assert!(temperature.deliver(Err(ErrorCode::FAIL)));
+    assert_eq!(compensation.compensate(0, 1000), compensated(997, 1_000));
+
+    // The sensor stops answering.
+    assert!(alarm.fire());
+    assert_eq!(temperature.reads(), 3);
+    assert_eq!(compensation.compensate(0, 1000), compensated(997, 2_000));
+    assert!(alarm.fire());
+    assert_eq!(
+        compensation.compensate(0, 1000),
+        uncompensated(1000, Some(3_000))
+    );
+
+    // And comes back.
+    assert!(temperature.deliver(Ok(3_500)));
+    assert_eq!(compensation.compensate(0, 1000), compensated(997, 0));
+}
+
+#[derive(Default)]
+struct Output {
+    text: std::cell::RefCell<String>,
+}
+
+impl ConsoleCommandClient for Output {
+    fn print(&self, bytes: &[u8]) {
+        self.text
+            .borrow_mut()
+            .push_str(std::str::from_utf8(bytes).unwrap());
+    }
+
+    fn command_done(&self) {}
+}
+
+#[test]
+fn adc_command_prints_compensated_millivolts() {
+    let (compensation, temperature, alarm) = setup();
+    let channel0 = leak(FakeAdcChannel::new(Some(3300)));
+    let channel1 = leak(FakeAdcChannel::new(Some(3300)));
+    let channels: &'static [&'static dyn AdcChannel<'static>] = leak([
+        channel0 as &dyn AdcChannel<'static>,
+        channel1 as &dyn AdcChannel<'static>,
+    ]);
+    let command = leak(AdcCommand::new(channels));
+    channel0.set_client(command);
+    channel1.set_client(command);
+    let output = leak(Output::default());
+    command.set_client(output);
+    command.set_compensation(compensation);
+
+    assert_eq!(command.execute("read 0"), Ok(()));
+    assert!(channel0.deliver(0x8000));
+    assert_eq!(
+        output.text.take(),
+        "adc 0: 1650 mV (raw 0x8000, uncompensated)\r\n"
+    );
+
+    assert_eq!(compensation.start(), Ok(()));
+    assert!(temperature.deliver(Ok(3_500)));
+    assert!(alarm.fire());
+    assert_eq!(command.execute("read 0"), Ok(()));
+    assert!(channel0.deliver(0x8000));
+    assert_eq!(
+        output.text.take(),
+        "adc 0: 1646 mV (raw 0x8000, compensated, temp 1000 ms old)\r\n"
+    );
+
+    assert_eq!(command.execute("read 1"), Ok(()));
+    assert!(channel1.deliver(0x8000));
+    assert_eq!(
+        output.text.take(),
+        "adc 1: 1650 mV (raw 0x8000, uncompensated, temp 1000 ms old)\r\n"
+    );
+}
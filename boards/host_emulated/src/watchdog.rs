@@ -0,0 +1,218 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! The progress watchdog, alone and reporting the operations of the
+//! nonvolatile storage driver.
+
+use std::cell::Cell;
+
+use capsules_extra::nonvolatile_storage_driver::{
+    NonvolatileStorage, StorageGeometry, BUF_LEN, DRIVER_NUM,
+};
+use kernel::hil::nonvolatile_storage::NonvolatileStorage as NonvolatileStorageHil;
+use kernel::platform::watchdog::{ProgressWatchDog, WatchDog};
+
+use crate::fixtures::{leak, leak_buffer, Board, RamStorage};
+
+/// Counts what the kernel asks of the hardware watchdog.
+#[derive(Default)]
+struct MockWatchDog {
+    tickles: Cell<usize>,
+    suspended: Cell<bool>,
+}
+
+impl MockWatchDog {
+    fn take_tickles(&self) -> usize {
+        self.tickles.take()
+    }
+}
+
+impl WatchDog for MockWatchDog {
+    fn tickle(&self) {
+        self.tickles.set(self.tickles.get() + 1);
+        self.suspended.set(false);
+    }
+
+    fn suspend(&self) {
+        self.suspended.set(true);
+    }
+}
+
+fn setup<const N: usize>() -> (
+    &'static ProgressWatchDog<'static, MockWatchDog, N>,
+    &'static MockWatchDog,
+) {
+    let mock = leak(MockWatchDog::default());
+    (leak(ProgressWatchDog::new(mock)), mock)
+}
+
+#[test]
+fn tickles_pass_through_without_operations() {
+    let (watchdog, mock) = setup::<2>();
+    let _progress = watchdog.work_progress().unwrap();
+
+    watchdog.tickle();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 2);
+}
+
+#[test]
+fn handles_are_limited() {
+    let (watchdog, _) = setup::<2>();
+
+    assert!(watchdog.work_progress().is_some());
+    assert!(watchdog.work_progress().is_some());
+    assert!(watchdog.work_progress().is_none());
+}
+
+#[test]
+fn stalled_operation_misses_tickles() {
+    let (watchdog, mock) = setup::<2>();
+    let progress = watchdog.work_progress().unwrap();
+
+    progress.start();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+    // No progress since the last tickle.
+    watchdog.tickle();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 0);
+
+    progress.touch();
+    assert_eq!(mock.take_tickles(), 1);
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 0);
+
+    progress.finish();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+}
+
+#[test]
+fn every_operation_must_make_progress() {
+    let (watchdog, mock) = setup::<2>();
+    let active = watchdog.work_progress().unwrap();
+    let stalled = watchdog.work_progress().unwrap();
+
+    active.start();
+    stalled.start();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+
+    // One stuck operation is enough to let the watchdog fire.
+    for _ in 0..3 {
+        active.touch();
+        watchdog.tickle();
+    }
+    assert_eq!(mock.take_tickles(), 0);
+
+    stalled.touch();
+    assert_eq!(mock.take_tickles(), 1);
+
+    // Once the stalled one is done, the active one keeps the board alive.
+    stalled.finish();
+    for _ in 0..3 {
+        active.touch();
+        watchdog.tickle();
+    }
+    assert_eq!(mock.take_tickles(), 3);
+}
+
+#[test]
+fn touching_an_idle_handle_does_nothing() {
+    let (watchdog, mock) = setup::<1>();
+    let progress = watchdog.work_progress().unwrap();
+
+    progress.touch();
+    assert_eq!(mock.take_tickles(), 0);
+    progress.start();
+    progress.finish();
+    progress.touch();
+    assert_eq!(mock.take_tickles(), 0);
+    assert!(!watchdog.in_progress());
+}
+
+#[test]
+fn keeps_running_through_sleep_while_an_operation_is_in_progress() {
+    let (watchdog, mock) = setup::<1>();
+    let progress = watchdog.work_progress().unwrap();
+
+    watchdog.suspend();
+    assert!(mock.suspended.get());
+    watchdog.resume();
+    assert!(!mock.suspended.get());
+    assert_eq!(mock.take_tickles(), 1);
+
+    progress.start();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+    watchdog.suspend();
+    assert!(!mock.suspended.get());
+    // Waking up is not progress.
+    watchdog.resume();
+    assert_eq!(mock.take_tickles(), 0);
+}
+
+fn storage_setup() -> (
+    &'static NonvolatileStorage<'static>,
+    &'static RamStorage<'static>,
+    &'static ProgressWatchDog<'static, MockWatchDog, 1>,
+    &'static MockWatchDog,
+) {
+    let board = Board::new();
+    let ram = leak(RamStorage::new(0x200));
+    let storage = leak(NonvolatileStorage::new(
+        ram,
+        board.create_grant(DRIVER_NUM),
+        0x100,
+        0x100,
+        0x000,
+        0x100,
+        StorageGeometry {
+            page_size: 1,
+            erase_size: 1,
+            total_size: 0x200,
+        },
+        leak_buffer(BUF_LEN),
+    ));
+    ram.set_client(storage);
+    let (watchdog, mock) = setup::<1>();
+    storage.set_work_progress(watchdog.work_progress().unwrap());
+    (storage, ram, watchdog, mock)
+}
+
+#[test]
+fn storage_operations_that_complete_keep_the_board_alive() {
+    let (storage, ram, watchdog, mock) = storage_setup();
+
+    assert_eq!(storage.write(leak_buffer(16), 0x00, 16), Ok(()));
+    assert_eq!(storage.write(leak_buffer(16), 0x10, 16), Ok(()));
+    assert!(watchdog.in_progress());
+    watchdog.tickle();
+    // Each completed operation is progress, including when the queued one
+    // starts right away.
+    for _ in 0..2 {
+        assert!(ram.complete());
+        watchdog.tickle();
+    }
+    assert_eq!(mock.take_tickles(), 3);
+    assert!(!watchdog.in_progress());
+}
+
+#[test]
+fn stuck_storage_operation_misses_tickles() {
+    let (storage, ram, watchdog, mock) = storage_setup();
+
+    assert_eq!(storage.read(leak_buffer(16), 0x00, 16), Ok(()));
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+    // The storage never answers.
+    watchdog.tickle();
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 0);
+
+    assert!(ram.complete());
+    watchdog.tickle();
+    assert_eq!(mock.take_tickles(), 1);
+}
@@ -525,6 +525,7 @@ pub unsafe fn main() {
         capsules_core::adc::DRIVER_NUM,
     )
     .finalize(components::adc_dedicated_component_static!(sam4l::adc::Adc));
+    adc.set_channel_config(&peripherals.adc);
 
     let gpio = GpioComponent::new(
         board_kernel,
@@ -665,8 +666,9 @@ pub unsafe fn main() {
         board_kernel,
         capsules_extra::nonvolatile_storage_driver::DRIVER_NUM,
         &peripherals.flash_controller,
-        0x60000, // Start address for userspace accessible region
-        0x20000, // Length of userspace accessible region
+        0x80000,                                 // Size of the flash of the SAM4LC8C
+        0x60000,                                 // Start address for userspace accessible region
+        0x20000,                                 // Length of userspace accessible region
         core::ptr::addr_of!(_sstorage) as usize, //start address of kernel region
         core::ptr::addr_of!(_estorage) as usize - core::ptr::addr_of!(_sstorage) as usize, // length of kernel region
     )
@@ -19,6 +19,7 @@ use components::gpio::GpioComponent;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::hil::led::LedHigh;
+use kernel::hil::time::Alarm;
 use kernel::platform::{KernelResources, SyscallDriverLookup};
 use kernel::scheduler::round_robin::RoundRobinSched;
 use kernel::{create_capability, debug, static_init};
@@ -57,6 +58,10 @@ type TemperatureSTMSensor = components::temperature_stm::TemperatureSTMComponent
 >;
 type TemperatureDriver = components::temperature::TemperatureComponentType<TemperatureSTMSensor>;
 type RngDriver = components::rng::RngComponentType<stm32f429zi::trng::Trng<'static>>;
+type I2CMasterSlaveDriver = capsules_core::i2c_master_slave_driver::I2CMasterSlaveDriver<
+    'static,
+    stm32f429zi::i2c::I2C<'static>,
+>;
 
 /// Nucleo F429ZI HSE frequency in MHz
 pub const NUCLEO_F429ZI_HSE_FREQUENCY_MHZ: usize = 8;
@@ -81,6 +86,7 @@ struct NucleoF429ZI {
     temperature: &'static TemperatureDriver,
     gpio: &'static capsules_core::gpio::GPIO<'static, stm32f429zi::gpio::Pin<'static>>,
     rng: &'static RngDriver,
+    i2c_master_slave: &'static I2CMasterSlaveDriver,
 
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm4::systick::SysTick,
@@ -110,6 +116,7 @@ impl SyscallDriverLookup for NucleoF429ZI {
             capsules_extra::can::DRIVER_NUM => f(Some(self.can)),
             capsules_extra::dac::DRIVER_NUM => f(Some(self.dac)),
             capsules_extra::date_time::DRIVER_NUM => f(Some(self.date_time)),
+            capsules_core::i2c_master_slave_driver::DRIVER_NUM => f(Some(self.i2c_master_slave)),
             _ => f(None),
         }
     }
@@ -186,6 +193,7 @@ unsafe fn setup_dma(
 /// Helper function called during bring-up that configures multiplexed I/O.
 unsafe fn set_pin_primary_functions(
     syscfg: &stm32f429zi::syscfg::Syscfg,
+    i2c2: &stm32f429zi::i2c::I2C,
     gpio_ports: &'static stm32f429zi::gpio::GpioPorts<'static>,
 ) {
     use kernel::hil::gpio::Configure;
@@ -278,6 +286,25 @@ unsafe fn set_pin_primary_functions(
     gpio_ports.get_pin(PinId::PA04).map(|pin| {
         pin.set_mode(stm32f429zi::gpio::Mode::AnalogMode);
     });
+
+    // I2C2 SDA on pf0 (D68) and SCL on pf1 (D69)
+    gpio_ports.get_pin(PinId::PF00).map(|pin| {
+        pin.set_mode_output_opendrain();
+        pin.set_floating_state(kernel::hil::gpio::FloatingState::PullNone);
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF4 is I2C
+        pin.set_alternate_function(AlternateFunction::AF4);
+    });
+    gpio_ports.get_pin(PinId::PF01).map(|pin| {
+        pin.set_mode_output_opendrain();
+        pin.set_floating_state(kernel::hil::gpio::FloatingState::PullNone);
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF4 is I2C
+        pin.set_alternate_function(AlternateFunction::AF4);
+    });
+
+    i2c2.enable_clock();
+    i2c2.set_speed(stm32f429zi::i2c::I2CSpeed::Speed100k, 16);
 }
 
 /// Helper function for miscellaneous peripheral functions
@@ -344,7 +371,7 @@ unsafe fn start() -> (
         &peripherals.rtc,
     );
 
-    set_pin_primary_functions(syscfg, &base_peripherals.gpio_ports);
+    set_pin_primary_functions(syscfg, &base_peripherals.i2c2, &base_peripherals.gpio_ports);
 
     setup_dma(
         dma1,
@@ -509,8 +536,9 @@ unsafe fn start() -> (
             65 => gpio_ports.pins[6][0].as_ref().unwrap(), //D65
             66 => gpio_ports.pins[3][1].as_ref().unwrap(), //D66
             67 => gpio_ports.pins[3][0].as_ref().unwrap(), //D67
-            68 => gpio_ports.pins[5][0].as_ref().unwrap(), //D68
-            69 => gpio_ports.pins[5][1].as_ref().unwrap(), //D69
+            // I2C2 Pins
+            // 68 => gpio_ports.pins[5][0].as_ref().unwrap(), //D68
+            // 69 => gpio_ports.pins[5][1].as_ref().unwrap(), //D69
             70 => gpio_ports.pins[5][2].as_ref().unwrap(), //D70
             71 => gpio_ports.pins[0][7].as_ref().unwrap(),  //D71
 
@@ -623,6 +651,24 @@ unsafe fn start() -> (
         stm32f429zi::rtc::Rtc<'static>
     ));
 
+    // I2C MASTER/SLAVE
+    let i2c_timeout_alarm = static_init!(
+        VirtualMuxAlarm<'static, stm32f429zi::tim2::Tim2>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    i2c_timeout_alarm.setup();
+    base_peripherals.i2c2.set_timeout_alarm(i2c_timeout_alarm);
+    i2c_timeout_alarm.set_alarm_client(&base_peripherals.i2c2);
+
+    let i2c_master_slave = components::i2c::I2CMasterSlaveDriverComponent::new(
+        board_kernel,
+        capsules_core::i2c_master_slave_driver::DRIVER_NUM,
+        &base_peripherals.i2c2,
+    )
+    .finalize(components::i2c_master_slave_component_static!(
+        stm32f429zi::i2c::I2C
+    ));
+
     // PROCESS CONSOLE
     let process_console = components::process_console::ProcessConsoleComponent::new(
         board_kernel,
@@ -654,6 +700,7 @@ unsafe fn start() -> (
         alarm: alarm,
         gpio: gpio,
         rng: rng,
+        i2c_master_slave: i2c_master_slave,
 
         scheduler,
         systick: cortexm4::systick::SysTick::new(),
@@ -18,6 +18,7 @@ use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
 use components::gpio::GpioComponent;
 use kernel::capabilities;
 use kernel::component::Component;
+use kernel::hil::adc::AdcChannel;
 use kernel::hil::gpio::Configure;
 use kernel::hil::led::LedHigh;
 use kernel::platform::{KernelResources, SyscallDriverLookup};
@@ -54,10 +55,11 @@ const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::Panic
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
 
-type TemperatureSTMSensor = components::temperature_stm::TemperatureSTMComponentType<
+type TemperatureSensor = stm32f446re::adc::TemperatureSensor<
+    'static,
     capsules_core::virtualizers::virtual_adc::AdcDevice<'static, stm32f446re::adc::Adc<'static>>,
 >;
-type TemperatureDriver = components::temperature::TemperatureComponentType<TemperatureSTMSensor>;
+type TemperatureDriver = components::temperature::TemperatureComponentType<TemperatureSensor>;
 
 /// A structure representing this platform that holds references to all
 /// capsules for this platform.
@@ -373,24 +375,31 @@ unsafe fn start() -> (
     let adc_mux = components::adc::AdcMuxComponent::new(&base_peripherals.adc1)
         .finalize(components::adc_mux_component_static!(stm32f446re::adc::Adc));
 
-    let temp_sensor = components::temperature_stm::TemperatureSTMComponent::new(
-        adc_mux,
-        stm32f446re::adc::Channel::Channel18,
-        2.5,
-        0.76,
-    )
-    .finalize(components::temperature_stm_adc_component_static!(
-        stm32f446re::adc::Adc
-    ));
+    // The die temperature, with the factory calibration and VDDA measured
+    // through VREFINT.
+    let vrefint_channel =
+        components::adc::AdcComponent::new(adc_mux, stm32f446re::adc::Channel::VREFINT)
+            .finalize(components::adc_component_static!(stm32f446re::adc::Adc));
+    let temp_channel =
+        components::adc::AdcComponent::new(adc_mux, stm32f446re::adc::Channel::TEMPERATURE_SENSOR)
+            .finalize(components::adc_component_static!(stm32f446re::adc::Adc));
+    let temp_sensor = static_init!(
+        TemperatureSensor,
+        stm32f446re::adc::TemperatureSensor::new(
+            vrefint_channel,
+            temp_channel,
+            stm32f446re::adc::Calibration::read(),
+        )
+    );
+    vrefint_channel.set_client(temp_sensor);
+    temp_channel.set_client(temp_sensor);
 
     let temp = components::temperature::TemperatureComponent::new(
         board_kernel,
         capsules_extra::temperature::DRIVER_NUM,
         temp_sensor,
     )
-    .finalize(components::temperature_component_static!(
-        TemperatureSTMSensor
-    ));
+    .finalize(components::temperature_component_static!(TemperatureSensor));
 
     let adc_channel_0 =
         components::adc::AdcComponent::new(adc_mux, stm32f446re::adc::Channel::Channel0)
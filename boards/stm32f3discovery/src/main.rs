@@ -651,6 +651,7 @@ pub unsafe fn main() {
     let l3gd20 = components::l3gd20::L3gd20Component::new(
         spi_mux,
         gpio_ports.get_pin(stm32f303xc::gpio::PinId::PE03).unwrap(),
+        None,
         board_kernel,
         capsules_extra::l3gd20::DRIVER_NUM,
     )
@@ -659,7 +660,7 @@ pub unsafe fn main() {
         stm32f303xc::spi::Spi
     ));
 
-    l3gd20.power_on();
+    let _ = l3gd20.power_on();
 
     // Comment this if you want to use the ADC MCU temp sensor
     let temp = components::temperature::TemperatureComponent::new(
@@ -678,6 +679,7 @@ pub unsafe fn main() {
         mux_i2c,
         None,
         None,
+        None,
         board_kernel,
         capsules_extra::lsm303dlhc::DRIVER_NUM,
     )
@@ -767,6 +769,7 @@ pub unsafe fn main() {
         board_kernel,
         capsules_extra::nonvolatile_storage_driver::DRIVER_NUM,
         &peripherals.flash,
+        0x40000,    // Size of the flash of the STM32F303VCT6
         0x08038000, // Start address for userspace accesible region
         0x8000,     // Length of userspace accesible region (16 pages)
         core::ptr::addr_of!(_sstorage) as usize,
@@ -6,6 +6,13 @@
 //!
 //! - <https://github.com/chipsalliance/Cores-SweRVolf>
 //!
+//! The platform has no source of randomness. The random numbers given to
+//! apps come from a pseudo-random generator seeded with the machine timer at
+//! boot, so they are predictable and must not be used for cryptography.
+//!
+//! The kernel uses the round-robin scheduler, unless the `sched_cooperative`
+//! or `sched_priority` feature selects another one. It runs up to 4
+//! processes, or 8 or 16 with the `procs8` or `procs16` feature.
 
 #![no_std]
 // Disable this attribute when documenting, as a workaround for
@@ -14,19 +21,52 @@
 
 use core::ptr::{addr_of, addr_of_mut};
 
+use capsules_core::rng::{NoRng, RandomRng, RngDriver, SynchronousRandom};
 use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::syscall_accounting::{AccountedDriver, SyscallCounters};
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::hil;
+use kernel::hil::led::LedHigh;
+use kernel::hil::rng::{Random, Rng};
+use kernel::hil::time::{Ticks, Time};
 use kernel::platform::{KernelResources, SyscallDriverLookup};
+use kernel::process::{ProcessLoadError, ProcessLoadingAsync, ProcessLoadingAsyncClient, ShortId};
+#[cfg(feature = "sched_cooperative")]
 use kernel::scheduler::cooperative::CooperativeSched;
+#[cfg(feature = "sched_priority")]
+use kernel::scheduler::priority::PrioritySched;
+#[cfg(not(any(feature = "sched_cooperative", feature = "sched_priority")))]
+use kernel::scheduler::round_robin::RoundRobinSched;
 use kernel::utilities::registers::interfaces::ReadWriteable;
 use kernel::{create_capability, debug, static_init};
 use rv32i::csr;
 use swervolf_eh1::chip::SweRVolfDefaultPeripherals;
+use swervolf_eh1::gpio::GpioPin;
 
 pub mod io;
 
+#[cfg(all(feature = "sched_cooperative", feature = "sched_priority"))]
+compile_error!("select at most one of the `sched_cooperative` and `sched_priority` features");
+
+/// The scheduler selected by the features of the board.
+#[cfg(feature = "sched_cooperative")]
+type SchedulerInUse = CooperativeSched<'static>;
+#[cfg(feature = "sched_priority")]
+type SchedulerInUse = PrioritySched;
+#[cfg(not(any(feature = "sched_cooperative", feature = "sched_priority")))]
+type SchedulerInUse = RoundRobinSched<'static>;
+
+#[cfg(all(feature = "procs8", feature = "procs16"))]
+compile_error!("select at most one of the `procs8` and `procs16` features");
+
+/// Number of process slots, 4 unless the `procs8` or `procs16` feature
+/// selects more.
+#[cfg(feature = "procs8")]
+pub const NUM_PROCS: usize = 8;
+#[cfg(feature = "procs16")]
+pub const NUM_PROCS: usize = 16;
+#[cfg(not(any(feature = "procs8", feature = "procs16")))]
 pub const NUM_PROCS: usize = 4;
 //
 // Actual memory for holding the active process structures. Need an empty list
@@ -47,15 +87,27 @@ const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::Panic
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x900] = [0; 0x900];
 
+/// Commands to the console and the alarm are counted for up to `NUM_PROCS`
+/// apps.
+type SyscallCountersTable = SyscallCounters<NUM_PROCS, 2>;
+
+/// Pseudo-random numbers, seeded once at boot.
+type PseudoRandom = SynchronousRandom<'static, NoRng>;
+
 /// A structure representing this platform that holds references to all
-/// capsules for this platform. We've included an alarm and console.
+/// capsules for this platform. We've included an alarm, console, LEDs,
+/// GPIO and random numbers.
 struct SweRVolf {
     console: &'static capsules_core::console::Console<'static>,
+    led: &'static capsules_core::led::LedDriver<'static, LedHigh<'static, GpioPin<'static>>, 16>,
+    gpio: &'static capsules_core::gpio::GPIO<'static, GpioPin<'static>>,
     alarm: &'static capsules_core::alarm::AlarmDriver<
         'static,
         VirtualMuxAlarm<'static, swervolf_eh1::syscon::SysCon<'static>>,
     >,
-    scheduler: &'static CooperativeSched<'static>,
+    rng: &'static RngDriver<'static, RandomRng<'static, PseudoRandom>>,
+    syscall_counters: &'static SyscallCountersTable,
+    scheduler: &'static SchedulerInUse,
     scheduler_timer: &'static swerv::eh1_timer::Timer<'static>,
 }
 
@@ -65,10 +117,23 @@ impl SyscallDriverLookup for SweRVolf {
     where
         F: FnOnce(Option<&dyn kernel::syscall::SyscallDriver>) -> R,
     {
-        match driver_num {
-            capsules_core::console::DRIVER_NUM => f(Some(self.console)),
-            capsules_core::alarm::DRIVER_NUM => f(Some(self.alarm)),
-            _ => f(None),
+        let driver: Option<&dyn kernel::syscall::SyscallDriver> = match driver_num {
+            capsules_core::console::DRIVER_NUM => Some(self.console),
+            capsules_core::alarm::DRIVER_NUM => Some(self.alarm),
+            capsules_core::led::DRIVER_NUM => Some(self.led),
+            capsules_core::gpio::DRIVER_NUM => Some(self.gpio),
+            capsules_core::rng::DRIVER_NUM => Some(self.rng),
+            capsules_extra::syscall_accounting::DRIVER_NUM => Some(self.syscall_counters),
+            _ => None,
+        };
+        // Commands are counted before they reach the driver.
+        match driver {
+            Some(driver) => f(Some(&AccountedDriver::new(
+                driver,
+                driver_num,
+                self.syscall_counters,
+            ))),
+            None => f(None),
         }
     }
 }
@@ -79,7 +144,7 @@ impl KernelResources<swervolf_eh1::chip::SweRVolf<'static, SweRVolfDefaultPeriph
     type SyscallDriverLookup = Self;
     type SyscallFilter = ();
     type ProcessFault = ();
-    type Scheduler = CooperativeSched<'static>;
+    type Scheduler = SchedulerInUse;
     type SchedulerTimer = swerv::eh1_timer::Timer<'static>;
     type WatchDog = ();
     type ContextSwitchCallback = ();
@@ -107,6 +172,31 @@ impl KernelResources<swervolf_eh1::chip::SweRVolf<'static, SweRVolfDefaultPeriph
     }
 }
 
+/// Reports the outcome of process loading on the debug console.
+struct LoadingReport;
+
+impl ProcessLoadingAsyncClient for LoadingReport {
+    fn process_loaded(&self, result: Result<(), ProcessLoadError>) {
+        match result {
+            Ok(()) | Err(ProcessLoadError::NoProcessSlot) => {}
+            Err(err) => {
+                debug!("Error loading processes!");
+                debug!("{:?}", err);
+            }
+        }
+    }
+
+    fn process_loading_finished(&self) {
+        // Loading stops once every slot is used, apps after them are skipped.
+        if unsafe { (*addr_of!(PROCESSES)).iter().all(Option::is_some) } {
+            debug!(
+                "All {} process slots are used, further apps are not loaded.",
+                NUM_PROCS
+            );
+        }
+    }
+}
+
 /// Main function called after RAM initialized.
 #[no_mangle]
 pub unsafe fn main() {
@@ -117,6 +207,7 @@ pub unsafe fn main() {
         SweRVolfDefaultPeripherals,
         SweRVolfDefaultPeripherals::new()
     );
+    peripherals.init();
 
     // initialize capabilities
     let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
@@ -202,6 +293,95 @@ pub unsafe fn main() {
     components::debug_writer::DebugWriterComponent::new(uart_mux)
         .finalize(components::debug_writer_component_static!());
 
+    // Process control over the same UART.
+    let process_console = components::process_console::ProcessConsoleComponent::new(
+        board_kernel,
+        uart_mux,
+        mux_alarm,
+        process_printer,
+        None,
+    )
+    .finalize(components::process_console_component_static!(
+        swervolf_eh1::syscon::SysCon
+    ));
+    let _ = process_console.start();
+
+    // LEDs of the Nexys A7, on GPIO bits 0 to 15.
+    let led = components::led::LedsComponent::new().finalize(components::led_component_static!(
+        LedHigh<'static, GpioPin>,
+        LedHigh::new(&peripherals.gpio_port[0]),
+        LedHigh::new(&peripherals.gpio_port[1]),
+        LedHigh::new(&peripherals.gpio_port[2]),
+        LedHigh::new(&peripherals.gpio_port[3]),
+        LedHigh::new(&peripherals.gpio_port[4]),
+        LedHigh::new(&peripherals.gpio_port[5]),
+        LedHigh::new(&peripherals.gpio_port[6]),
+        LedHigh::new(&peripherals.gpio_port[7]),
+        LedHigh::new(&peripherals.gpio_port[8]),
+        LedHigh::new(&peripherals.gpio_port[9]),
+        LedHigh::new(&peripherals.gpio_port[10]),
+        LedHigh::new(&peripherals.gpio_port[11]),
+        LedHigh::new(&peripherals.gpio_port[12]),
+        LedHigh::new(&peripherals.gpio_port[13]),
+        LedHigh::new(&peripherals.gpio_port[14]),
+        LedHigh::new(&peripherals.gpio_port[15]),
+    ));
+
+    // Switches of the Nexys A7, on GPIO bits 16 to 31.
+    let gpio = components::gpio::GpioComponent::new(
+        board_kernel,
+        capsules_core::gpio::DRIVER_NUM,
+        components::gpio_component_helper!(
+            GpioPin,
+            0 => &peripherals.gpio_port[16],
+            1 => &peripherals.gpio_port[17],
+            2 => &peripherals.gpio_port[18],
+            3 => &peripherals.gpio_port[19],
+            4 => &peripherals.gpio_port[20],
+            5 => &peripherals.gpio_port[21],
+            6 => &peripherals.gpio_port[22],
+            7 => &peripherals.gpio_port[23],
+            8 => &peripherals.gpio_port[24],
+            9 => &peripherals.gpio_port[25],
+            10 => &peripherals.gpio_port[26],
+            11 => &peripherals.gpio_port[27],
+            12 => &peripherals.gpio_port[28],
+            13 => &peripherals.gpio_port[29],
+            14 => &peripherals.gpio_port[30],
+            15 => &peripherals.gpio_port[31]
+        ),
+    )
+    .finalize(components::gpio_component_static!(GpioPin));
+
+    // Random numbers for apps. Without hardware randomness, the generator
+    // is seeded with the time the board took to get here.
+    let random = static_init!(PseudoRandom, SynchronousRandom::new(&NoRng));
+    random.reseed(mtimer.now().into_u32());
+    let random_rng = static_init!(RandomRng<'static, PseudoRandom>, RandomRng::new(random));
+    kernel::deferred_call::DeferredCallClient::register(random_rng);
+    let rng = static_init!(
+        RngDriver<'static, RandomRng<'static, PseudoRandom>>,
+        RngDriver::new(
+            random_rng,
+            board_kernel.create_grant(capsules_core::rng::DRIVER_NUM, &memory_allocation_cap)
+        )
+    );
+    random_rng.set_client(rng);
+
+    // The monitor is the app named "syscall_monitor", with the ShortId the
+    // `AppIdAssignerNames` used to load the processes below gives it.
+    let syscall_counters = static_init!(
+        SyscallCountersTable,
+        SyscallCounters::new(
+            [
+                capsules_core::console::DRIVER_NUM,
+                capsules_core::alarm::DRIVER_NUM,
+            ],
+            core::num::NonZeroU32::new(kernel::utilities::helpers::crc32_posix(b"syscall_monitor"))
+                .map_or(ShortId::LocallyUnique, ShortId::Fixed),
+        )
+    );
+
     debug!("SweRVolf initialisation complete.");
     debug!("Entering main loop.");
 
@@ -217,36 +397,76 @@ pub unsafe fn main() {
         static _eappmem: u8;
     }
 
+    #[cfg(feature = "sched_cooperative")]
     let scheduler =
         components::sched::cooperative::CooperativeComponent::new(&*addr_of!(PROCESSES))
             .finalize(components::cooperative_component_static!(NUM_PROCS));
+    #[cfg(feature = "sched_priority")]
+    let scheduler = components::sched::priority::PriorityComponent::new(board_kernel)
+        .finalize(components::priority_component_static!());
+    #[cfg(not(any(feature = "sched_cooperative", feature = "sched_priority")))]
+    let scheduler = components::sched::round_robin::RoundRobinComponent::new(&*addr_of!(PROCESSES))
+        .finalize(components::round_robin_component_static!(NUM_PROCS));
 
     let swervolf = SweRVolf {
         console,
+        led,
+        gpio,
         alarm,
+        rng,
+        syscall_counters,
         scheduler,
         scheduler_timer: chip.get_scheduler_timer(),
     };
 
-    kernel::process::load_processes(
-        board_kernel,
-        chip,
-        core::slice::from_raw_parts(
-            core::ptr::addr_of!(_sapps),
-            core::ptr::addr_of!(_eapps) as usize - core::ptr::addr_of!(_sapps) as usize,
-        ),
-        core::slice::from_raw_parts_mut(
-            core::ptr::addr_of_mut!(_sappmem),
-            core::ptr::addr_of!(_eappmem) as usize - core::ptr::addr_of!(_sappmem) as usize,
-        ),
-        &mut *addr_of_mut!(PROCESSES),
-        &FAULT_RESPONSE,
-        &process_mgmt_cap,
-    )
-    .unwrap_or_else(|err| {
-        debug!("Error loading processes!");
-        debug!("{:?}", err);
-    });
+    // Apps need no credentials, and get a fixed ShortId from the CRC32 of
+    // their name.
+    let checking_policy = static_init!(
+        kernel::process_checker::basic::AppCheckerSimulated<'static>,
+        kernel::process_checker::basic::AppCheckerSimulated::new()
+    );
+    kernel::deferred_call::DeferredCallClient::register(checking_policy);
+
+    let assigner = components::appid::assigner_name::AppIdAssignerNamesComponent::new()
+        .finalize(components::appid_assigner_names_component_static!());
+
+    let checker = components::appid::checker::ProcessCheckerMachineComponent::new(checking_policy)
+        .finalize(components::process_checker_machine_component_static!());
+
+    let process_binary_array = static_init!(
+        [Option<kernel::process::ProcessBinary>; NUM_PROCS],
+        core::array::from_fn(|_| None)
+    );
+
+    let loader = static_init!(
+        kernel::process::SequentialProcessLoaderMachine<
+            swervolf_eh1::chip::SweRVolf<SweRVolfDefaultPeripherals>,
+        >,
+        kernel::process::SequentialProcessLoaderMachine::new(
+            checker,
+            &mut *addr_of_mut!(PROCESSES),
+            process_binary_array,
+            board_kernel,
+            chip,
+            core::slice::from_raw_parts(
+                core::ptr::addr_of!(_sapps),
+                core::ptr::addr_of!(_eapps) as usize - core::ptr::addr_of!(_sapps) as usize,
+            ),
+            core::slice::from_raw_parts_mut(
+                core::ptr::addr_of_mut!(_sappmem),
+                core::ptr::addr_of!(_eappmem) as usize - core::ptr::addr_of!(_sappmem) as usize,
+            ),
+            &FAULT_RESPONSE,
+            assigner,
+            &process_mgmt_cap
+        )
+    );
+
+    checker.set_client(loader);
+    let loading_report = static_init!(LoadingReport, LoadingReport);
+    loader.set_client(loading_report);
+    kernel::deferred_call::DeferredCallClient::register(loader);
+    loader.start();
 
     board_kernel.kernel_loop(
         &swervolf,
@@ -440,6 +440,7 @@ pub unsafe fn start() -> (
         board_kernel,
         capsules_extra::nonvolatile_storage_driver::DRIVER_NUM,
         &base_peripherals.nvmc,
+        0x100000, // Size of the flash of the nRF52840
         0xFC000,  // Start address for userspace accessible region
         4096 * 4, // Length of userspace accessible region (16 pages)
         0,        // No kernel access
@@ -51,13 +51,85 @@
 //! );
 //! sam4l::adc::ADC0.set_client(adc);
 //! ```
+//!
+//! A sample of the virtualized ADC whose channel never calls back would keep
+//! every later request waiting. Boards can bound how long a sample takes
+//! with an [`AdcSampleTimeout`]:
+//!
+//! ```rust
+//! let adc_timeout = static_init!(
+//!     capsules_core::adc::AdcSampleTimeout<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules_core::adc::AdcSampleTimeout::new(adc_timeout_alarm, 100)
+//! );
+//! adc_timeout_alarm.set_alarm_client(adc_timeout);
+//! adc_syscall.set_sample_timeout(adc_timeout);
+//! ```
+//!
+//! The app whose sample timed out gets a [`SAMPLING_FAILED`] upcall with
+//! `FAIL`, and the next request starts. A channel
+//! whose samples time out twice in a row is marked unhealthy until one of
+//! its samples succeeds: its resolution and reference voltage queries
+//! return `FAIL`. Only the first 32 channels are marked.
+//!
+//! Kernel samples
+//! --------------
+//!
+//! Capsules can take single samples through `AdcVirtualized` too, queued
+//! with the samples of the apps. The capsule registers itself with
+//! `set_kernel_client()` and requests a sample with `sample_once()`. The
+//! sample goes to its `sample_ready()`, once the samples in progress are
+//! done. The kernel has at most one sample requested at a time, and it is
+//! served before the queued samples of the apps. A kernel sample that times
+//! out or fails to start is dropped without a callback.
+//!
+//! Errors
+//! ------
+//!
+//! A sampling that fails gets an upcall with [`SAMPLING_FAILED`] instead of
+//! the mode as the first argument, the channel as the second one and the
+//! `ErrorCode` as the third one. The sampling is over, the app can start
+//! another one. `AdcDedicated` reports the buffered samplings the ADC failed
+//! to fill. Samplings stopped by the app end without an upcall.
+//!
+//! Packed words
+//! ------------
+//!
+//! `AdcDedicated` packs a channel and a number of samples into one word, in
+//! the first argument of the buffered sampling command and in the second
+//! argument of the upcalls of buffered samples:
+//!
+//! ```text
+//!  31                      8 7        0
+//! +-------------------------+----------+
+//! |    number of samples    | channel  |
+//! +-------------------------+----------+
+//! ```
+//!
+//! The layout is the same whatever the size of `usize`, so a buffered
+//! sampling holds at most [`MAX_PACKED_SAMPLES`] samples: longer requests,
+//! and buffers longer than that, are refused with `SIZE`. Only the first 256
+//! channels can sample into a buffer. [`pack_samples_channel`] and
+//! [`unpack_samples_channel`] convert between the word and its fields.
+//!
+//! Channel configuration
+//! ---------------------
+//!
+//! On chips whose ADC implements [`hil::adc::AdcChannelConfig`], the board
+//! gives it to `AdcDedicated` with `set_channel_config()`. An app can then
+//! store a configuration word for each of the first
+//! [`MAX_CONFIGURED_CHANNELS`] channels, such as a gain or a reference, with
+//! command 105, and remove it with command 106. The word is specific to the
+//! chip, and is applied before every sampling of its channel. Channels
+//! without a configuration are sampled with the default configuration of
+//! the chip.
 
 use core::cell::Cell;
 use core::cmp;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil;
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer, WriteableProcessSlice};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -67,6 +139,12 @@ use crate::driver;
 use crate::virtualizers::virtual_adc::Operation;
 pub const DRIVER_NUM: usize = driver::NUM::Adc as usize;
 
+/// First argument of the upcall of a failed sampling, instead of the mode.
+pub const SAMPLING_FAILED: usize = usize::MAX;
+
+/// Channels of an `AdcDedicated` which can have a configuration word.
+pub const MAX_CONFIGURED_CHANNELS: usize = 16;
+
 /// Multiplexed ADC syscall driver, used by applications and capsules.
 /// Virtualized, and can be use by multiple applications at the same time;
 /// requests are queued. Does not support continuous or high-speed sampling.
@@ -74,6 +152,130 @@ pub struct AdcVirtualized<'a> {
     drivers: &'a [&'a dyn hil::adc::AdcChannel<'a>],
     apps: Grant<AppSys, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
     current_process: OptionalCell<ProcessId>,
+    /// Channel of the sample in progress.
+    current_channel: Cell<usize>,
+    sample_timeout: OptionalCell<&'a dyn SampleTimeout<'a>>,
+    /// Channels whose last sample timed out, one bit per channel.
+    timed_out: Cell<u32>,
+    /// Channels whose samples timed out twice in a row.
+    unhealthy: Cell<u32>,
+    /// Samples that timed out so far.
+    timeouts: Cell<usize>,
+    kernel_client: OptionalCell<&'a dyn hil::adc::Client>,
+    /// Channel of the kernel sample waiting for the ADC.
+    kernel_channel: OptionalCell<usize>,
+    /// The sample in progress is the kernel's.
+    kernel_active: Cell<bool>,
+}
+
+/// Bounds how long the samples of an [`AdcVirtualized`] may take, see
+/// [`AdcVirtualized::set_sample_timeout`].
+pub trait SampleTimeout<'a> {
+    /// `adc` is told when a started timeout expires.
+    fn set_client(&self, adc: &'a AdcVirtualized<'a>);
+
+    /// Start the timeout of a sample.
+    fn start(&self);
+
+    /// The sample is done, cancel its timeout.
+    fn cancel(&self);
+}
+
+/// A [`SampleTimeout`] of `timeout_ms` milliseconds timed with `alarm`. It
+/// must be set as the client of `alarm`.
+pub struct AdcSampleTimeout<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    timeout_ms: u32,
+    adc: OptionalCell<&'a AdcVirtualized<'a>>,
+}
+
+impl<'a, A: Alarm<'a>> AdcSampleTimeout<'a, A> {
+    pub fn new(alarm: &'a A, timeout_ms: u32) -> AdcSampleTimeout<'a, A> {
+        AdcSampleTimeout {
+            alarm,
+            timeout_ms,
+            adc: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> SampleTimeout<'a> for AdcSampleTimeout<'a, A> {
+    fn set_client(&self, adc: &'a AdcVirtualized<'a>) {
+        self.adc.set(adc);
+    }
+
+    fn start(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(self.timeout_ms));
+    }
+
+    fn cancel(&self) {
+        let _ = self.alarm.disarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for AdcSampleTimeout<'a, A> {
+    fn alarm(&self) {
+        self.adc.map(|adc| adc.sample_timed_out());
+    }
+}
+
+/// The health bit of `channel`, none past the 32nd channel.
+fn channel_bit(channel: usize) -> u32 {
+    u32::try_from(channel)
+        .ok()
+        .and_then(|channel| 1u32.checked_shl(channel))
+        .unwrap_or(0)
+}
+
+/// Bits of a packed word holding the channel.
+const PACKED_CHANNEL_BITS: usize = 8;
+
+/// The largest number of samples of a packed word, so that it fits in 32
+/// bits.
+pub const MAX_PACKED_SAMPLES: usize = (u32::MAX >> PACKED_CHANNEL_BITS) as usize;
+
+/// Pack `samples` and `channel` into one word, see the module documentation.
+/// The number of samples saturates at [`MAX_PACKED_SAMPLES`] rather than
+/// wrap, and only the low 8 bits of the channel are kept: callers check
+/// both beforehand.
+pub fn pack_samples_channel(samples: usize, channel: usize) -> usize {
+    debug_assert!(samples <= MAX_PACKED_SAMPLES);
+    debug_assert!(channel < 1 << PACKED_CHANNEL_BITS);
+    (cmp::min(samples, MAX_PACKED_SAMPLES) << PACKED_CHANNEL_BITS)
+        | (channel & ((1 << PACKED_CHANNEL_BITS) - 1))
+}
+
+/// Split a packed word into its number of samples and its channel. Bits
+/// above the 32nd are kept in the number of samples, for the caller to
+/// refuse it.
+pub fn unpack_samples_channel(word: usize) -> (usize, usize) {
+    (
+        word >> PACKED_CHANNEL_BITS,
+        word & ((1 << PACKED_CHANNEL_BITS) - 1),
+    )
+}
+
+/// Samples converted on the stack before each copy into an app buffer.
+const COPY_CHUNK_SAMPLES: usize = 32;
+
+/// Copy `samples` into `dest` as little-endian 16-bit values, as many bytes
+/// as fit: the low byte of a last sample that does not fully fit is copied.
+/// Chunks of samples are converted on the stack, then each is written to the
+/// app buffer with one `copy_from_slice`.
+fn copy_samples(samples: &[u16], dest: &WriteableProcessSlice) {
+    let len = cmp::min(samples.len() * 2, dest.len());
+    let mut bytes = [0u8; 2 * COPY_CHUNK_SAMPLES];
+    for (start, chunk) in (0..len)
+        .step_by(bytes.len())
+        .zip(samples.chunks(COPY_CHUNK_SAMPLES))
+    {
+        for (pair, sample) in bytes.chunks_exact_mut(2).zip(chunk) {
+            pair.copy_from_slice(&sample.to_le_bytes());
+        }
+        let end = cmp::min(start + 2 * chunk.len(), len);
+        dest[start..end].copy_from_slice(&bytes[..(end - start)]);
+    }
 }
 
 /// ADC syscall driver, used by applications to interact with ADC.
@@ -84,6 +286,11 @@ pub struct AdcDedicated<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> {
     // ADC driver
     adc: &'a A,
     channels: &'a [<A as hil::adc::Adc<'a>>::Channel],
+    channel_config: OptionalCell<
+        &'a dyn hil::adc::AdcChannelConfig<Channel = <A as hil::adc::Adc<'a>>::Channel>,
+    >,
+    /// Configuration words of the channels, applied before their samplings.
+    channel_configs: [Cell<Option<u32>>; MAX_CONFIGURED_CHANNELS],
 
     // ADC state
     active: Cell<bool>,
@@ -124,6 +331,8 @@ pub struct App {
     samples_remaining: Cell<usize>,
     samples_outstanding: Cell<usize>,
     next_samples_outstanding: Cell<usize>,
+    /// Number of samples of a single buffer sampling.
+    samples_requested: Cell<usize>,
     using_app_buf0: Cell<bool>,
 }
 
@@ -134,6 +343,7 @@ impl Default for App {
             samples_remaining: Cell::new(0),
             samples_outstanding: Cell::new(0),
             next_samples_outstanding: Cell::new(0),
+            samples_requested: Cell::new(0),
             using_app_buf0: Cell::new(true),
         }
     }
@@ -173,6 +383,8 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
             // ADC driver
             adc: adc,
             channels: channels,
+            channel_config: OptionalCell::empty(),
+            channel_configs: Default::default(),
 
             // ADC state
             active: Cell::new(false),
@@ -190,6 +402,49 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
         }
     }
 
+    /// Set the analog configuration of the channels, for chips which
+    /// support it.
+    pub fn set_channel_config(
+        &self,
+        channel_config: &'a dyn hil::adc::AdcChannelConfig<
+            Channel = <A as hil::adc::Adc<'a>>::Channel,
+        >,
+    ) {
+        self.channel_config.set(channel_config);
+    }
+
+    /// Store the configuration word of a channel, or remove it with `None`.
+    ///
+    /// - `channel` - index into `channels` array
+    fn store_channel_config(&self, channel: usize, config: Option<u32>) -> Result<(), ErrorCode> {
+        if self.channel_config.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if channel >= self.channels.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        let slot = self.channel_configs.get(channel).ok_or(ErrorCode::SIZE)?;
+        slot.set(config);
+        Ok(())
+    }
+
+    /// Apply the configuration word of a channel, if it has one, before
+    /// sampling it.
+    ///
+    /// - `channel` - index into `channels` array
+    fn apply_channel_config(&self, channel: usize) -> Result<(), ErrorCode> {
+        let config = self
+            .channel_configs
+            .get(channel)
+            .and_then(|config| config.get());
+        match config {
+            Some(config) => self.channel_config.map_or(Ok(()), |channel_config| {
+                channel_config.configure_channel(&self.channels[channel], config)
+            }),
+            None => Ok(()),
+        }
+    }
+
     /// Store a buffer we've regained ownership of and return a handle to it.
     /// The handle can have `map()` called on it in order to process the data in
     /// the buffer.
@@ -260,6 +515,8 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
         }
         let chan = &self.channels[channel];
 
+        self.apply_channel_config(channel)?;
+
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleSample);
@@ -294,6 +551,8 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
         }
         let chan = &self.channels[channel];
 
+        self.apply_channel_config(channel)?;
+
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::ContinuousSample);
@@ -315,11 +574,18 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
     /// Collect a buffer-full of analog samples.
     ///
     /// Samples are collected into the first app buffer provided. The number of
-    /// samples collected is equal to the size of the buffer "allowed".
+    /// samples collected is `samples`, or the size of the buffer "allowed" if
+    /// `samples` is 0. The rest of the buffer is left untouched.
     ///
     /// - `channel` - index into `channels` array, which channel to sample
     /// - `frequency` - number of samples per second to collect
-    fn sample_buffer(&self, channel: usize, frequency: u32) -> Result<(), ErrorCode> {
+    /// - `samples` - number of samples to collect, 0 to fill the buffer
+    fn sample_buffer(
+        &self,
+        channel: usize,
+        frequency: u32,
+        samples: usize,
+    ) -> Result<(), ErrorCode> {
         // only one sample at a time
         if self.active.get() {
             return Err(ErrorCode::BUSY);
@@ -355,6 +621,19 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
             return Err(ErrorCode::NOMEM);
         }
 
+        // determine request length
+        let request_len = match samples {
+            0 => app_buf_length / 2,
+            samples if samples <= app_buf_length / 2 => samples,
+            _ => return Err(ErrorCode::SIZE),
+        };
+        // the upcall could not report the number of samples
+        if request_len > MAX_PACKED_SAMPLES {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.apply_channel_config(channel)?;
+
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleBuffer);
@@ -368,12 +647,10 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
                         self.adc_buf2
                             .take()
                             .map_or(Err(ErrorCode::BUSY), move |buf2| {
-                                // determine request length
-                                let request_len = app_buf_length / 2;
                                 let len1;
                                 let len2;
                                 if request_len <= buf1.len() {
-                                    len1 = app_buf_length / 2;
+                                    len1 = request_len;
                                     len2 = 0;
                                 } else if request_len <= (buf1.len() + buf2.len()) {
                                     len1 = buf1.len();
@@ -385,6 +662,7 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
 
                                 // begin sampling
                                 app.using_app_buf0.set(true);
+                                app.samples_requested.set(request_len);
                                 app.samples_remaining.set(request_len - len1 - len2);
                                 app.samples_outstanding.set(len1 + len2);
                                 self.adc
@@ -447,8 +725,9 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
             return Err(ErrorCode::BUSY);
         }
 
-        // convert channel index
-        if channel >= self.channels.len() {
+        // convert channel index, the upcalls only report 8 bits of it
+        let (_, packed_channel) = unpack_samples_channel(channel);
+        if channel >= self.channels.len() || channel != packed_channel {
             return Err(ErrorCode::INVAL);
         }
         let chan = &self.channels[channel];
@@ -481,6 +760,12 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
         if !exists {
             return Err(ErrorCode::NOMEM);
         }
+        // the upcalls could not report the number of samples
+        if cmp::max(app_buf_length, next_app_buf_length) / 2 > MAX_PACKED_SAMPLES {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.apply_channel_config(channel)?;
 
         // save state for callback
         self.active.set(true);
@@ -623,6 +908,35 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> AdcDedicated<'a, A>
         })
     }
 
+    /// Stops the operation in progress and takes the buffers back from the
+    /// ADC, without telling the app.
+    fn abandon_operation(&self) {
+        self.active.set(false);
+        self.mode.set(AdcMode::NoMode);
+        let _ = self.adc.stop_sampling();
+        if let Ok((buf1, buf2)) = self.adc.retrieve_buffers() {
+            buf1.map(|buf| {
+                self.replace_buffer(buf);
+            });
+            buf2.map(|buf| {
+                self.replace_buffer(buf);
+            });
+        }
+    }
+
+    /// Ends a buffered sampling that failed with `error`, and tells the app.
+    fn sampling_failed(&self, error: ErrorCode) {
+        self.abandon_operation();
+        self.processid.map(|id| {
+            let _ = self.apps.enter(id, |app, upcalls| {
+                app.app_buf_offset.set(0);
+                upcalls
+                    .schedule_upcall(0, (SAMPLING_FAILED, self.channel.get(), usize::from(error)))
+                    .ok();
+            });
+        });
+    }
+
     fn get_resolution_bits(&self) -> usize {
         self.adc.get_resolution_bits()
     }
@@ -645,10 +959,106 @@ impl<'a> AdcVirtualized<'a> {
             drivers: drivers,
             apps: grant,
             current_process: OptionalCell::empty(),
+            current_channel: Cell::new(0),
+            sample_timeout: OptionalCell::empty(),
+            timed_out: Cell::new(0),
+            unhealthy: Cell::new(0),
+            timeouts: Cell::new(0),
+            kernel_client: OptionalCell::empty(),
+            kernel_channel: OptionalCell::empty(),
+            kernel_active: Cell::new(false),
+        }
+    }
+
+    /// Set the client of the samples requested with `sample_once()`.
+    pub fn set_kernel_client(&self, client: &'a dyn hil::adc::Client) {
+        self.kernel_client.set(client);
+    }
+
+    /// Request a single sample of `channel` for the kernel client.
+    ///
+    /// The sample starts right away if the ADC is free, and is queued
+    /// otherwise. Returns `BUSY` if the kernel already has a sample
+    /// requested, and `FAIL` without a kernel client.
+    pub fn sample_once(&self, channel: usize) -> Result<(), ErrorCode> {
+        if channel >= self.drivers.len() {
+            return Err(ErrorCode::NODEVICE);
+        }
+        if self.kernel_client.is_none() {
+            return Err(ErrorCode::FAIL);
+        }
+        if self.kernel_active.get() || self.kernel_channel.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.is_busy() {
+            self.kernel_channel.set(channel);
+            return Ok(());
+        }
+        self.kernel_active.set(true);
+        let r = self.start_sample(Operation::OneSample, channel);
+        if r.is_err() {
+            self.kernel_active.set(false);
+            self.run_next_command();
+        }
+        r
+    }
+
+    /// Whether a sample is in progress, for a process or the kernel.
+    fn is_busy(&self) -> bool {
+        self.current_process.is_some() || self.kernel_active.get()
+    }
+
+    /// Give up samples that take longer than `timeout`, so a channel that
+    /// never calls back does not hold the ADC forever.
+    pub fn set_sample_timeout(&'a self, timeout: &'a dyn SampleTimeout<'a>) {
+        timeout.set_client(self);
+        self.sample_timeout.set(timeout);
+    }
+
+    /// The number of samples that timed out so far.
+    pub fn sample_timeouts(&self) -> usize {
+        self.timeouts.get()
+    }
+
+    /// Whether the samples of `channel` timed out twice in a row, since its
+    /// last successful sample.
+    pub fn is_unhealthy(&self, channel: usize) -> bool {
+        self.unhealthy.get() & channel_bit(channel) != 0
+    }
+
+    /// Ends the sample in progress, which timed out, and starts the next
+    /// one.
+    fn sample_timed_out(&self) {
+        let processid = self.current_process.take();
+        if self.kernel_active.replace(false) || processid.is_some() {
+            let channel = self.current_channel.get();
+            // A late sample would go to the next process.
+            let _ = self.drivers[channel].stop_sampling();
+            self.timeouts.set(self.timeouts.get() + 1);
+            let bit = channel_bit(channel);
+            if self.timed_out.get() & bit != 0 {
+                self.unhealthy.set(self.unhealthy.get() | bit);
+            }
+            self.timed_out.set(self.timed_out.get() | bit);
+            processid.map(|processid| {
+                let _ = self.apps.enter(processid, |_, upcalls| {
+                    upcalls
+                        .schedule_upcall(
+                            0,
+                            (SAMPLING_FAILED, channel, usize::from(ErrorCode::FAIL)),
+                        )
+                        .ok();
+                });
+            });
         }
+        self.run_next_command();
     }
 
     /// Enqueue the command to be executed when the ADC is available.
+    ///
+    /// If the ADC is free the command starts right away. The next queued
+    /// command is only started once this one is done, from `sample_ready`,
+    /// or if it fails to start.
     fn enqueue_command(
         &self,
         command: Operation,
@@ -656,14 +1066,23 @@ impl<'a> AdcVirtualized<'a> {
         processid: ProcessId,
     ) -> Result<(), ErrorCode> {
         if channel < self.drivers.len() {
-            if self.current_process.is_none() {
+            if self.current_process.contains(&processid) {
+                // The process already has a sample in progress.
+                Err(ErrorCode::BUSY)
+            } else if !self.is_busy() {
+                // The channel is reported with the sample.
+                self.apps
+                    .enter(processid, |app, _| {
+                        app.channel = channel;
+                    })
+                    .map_err(ErrorCode::from)?;
                 self.current_process.set(processid);
-                let r = self.call_driver(command, channel);
-                if r != Ok(()) {
+                let r = self.call_driver(command, channel, processid);
+                if r.is_err() {
                     self.current_process.clear();
+                    self.run_next_command();
                 }
-                self.run_next_command();
-                Ok(())
+                r
             } else {
                 match self
                     .apps
@@ -688,8 +1107,16 @@ impl<'a> AdcVirtualized<'a> {
         }
     }
 
-    /// Run next command in queue, when available
+    /// Run next command in queue, when available. A kernel sample goes
+    /// first.
     fn run_next_command(&self) {
+        if let Some(channel) = self.kernel_channel.take() {
+            self.kernel_active.set(true);
+            if self.start_sample(Operation::OneSample, channel).is_ok() {
+                return;
+            }
+            self.kernel_active.set(false);
+        }
         let mut command = Operation::OneSample;
         let mut channel = 0;
         for app in self.apps.iter() {
@@ -708,7 +1135,7 @@ impl<'a> AdcVirtualized<'a> {
                 }
             });
             if start_command {
-                match self.call_driver(command, channel) {
+                match self.call_driver(command, channel, processid) {
                     Err(_) => {
                         self.current_process.clear();
                     }
@@ -720,11 +1147,32 @@ impl<'a> AdcVirtualized<'a> {
         }
     }
 
-    /// Request the sample from the specified channel
-    fn call_driver(&self, command: Operation, channel: usize) -> Result<(), ErrorCode> {
-        match command {
+    /// Request the sample from the specified channel for `processid`, which
+    /// must be the process the ADC is serving.
+    fn call_driver(
+        &self,
+        command: Operation,
+        channel: usize,
+        processid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        if !self.current_process.contains(&processid) {
+            return Err(ErrorCode::FAIL);
+        }
+        self.start_sample(command, channel)
+    }
+
+    /// Start a sample of `channel` for the current process or the kernel.
+    fn start_sample(&self, command: Operation, channel: usize) -> Result<(), ErrorCode> {
+        self.current_channel.set(channel);
+        // Started first, the sample may complete before `sample()` returns.
+        self.sample_timeout.map(|timeout| timeout.start());
+        let r = match command {
             Operation::OneSample => self.drivers[channel].sample(),
+        };
+        if r.is_err() {
+            self.sample_timeout.map(|timeout| timeout.cancel());
         }
+        r
     }
 }
 
@@ -824,8 +1272,11 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
     /// - `buf` - internal buffer filled with analog samples
     /// - `length` - number of valid samples in the buffer, guaranteed to be
     ///   less than or equal to buffer length
-    fn samples_ready(&self, buf: &'static mut [u16], length: usize) {
+    /// - `result` - whether the ADC filled the buffer, a failure ends the
+    ///   sampling with an error upcall
+    fn samples_ready(&self, buf: &'static mut [u16], length: usize, result: Result<(), ErrorCode>) {
         let mut unexpected_state = false;
+        let mut failure = result.err();
 
         // Make sure in all cases we regain ownership of the buffer. However,
         // we also get a reference back to it so we can copy the sampled values
@@ -833,10 +1284,12 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
         let buffer_with_samples = self.replace_buffer(buf);
 
         // do we expect a buffer?
-        if self.active.get()
+        let expected = self.active.get()
             && (self.mode.get() == AdcMode::SingleBuffer
-                || self.mode.get() == AdcMode::ContinuousBuffer)
-        {
+                || self.mode.get() == AdcMode::ContinuousBuffer);
+        if expected && failure.is_some() {
+            // The samples of a failed sampling are not delivered.
+        } else if expected {
             // we did expect a buffer. Determine the current application state
             self.processid.map(|id| {
                 self.apps
@@ -845,11 +1298,17 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                         // with enough space. The buffer still may be empty though
                         let app_buf0 = match kernel_data.get_readwrite_processbuffer(0) {
                             Ok(buf) => buf,
-                            Err(_) => return,
+                            Err(_) => {
+                                failure = Some(ErrorCode::FAIL);
+                                return;
+                            }
                         };
                         let app_buf1 = match kernel_data.get_readwrite_processbuffer(1) {
                             Ok(buf) => buf,
-                            Err(_) => return,
+                            Err(_) => {
+                                failure = Some(ErrorCode::FAIL);
+                                return;
+                            }
                         };
                         // determine which app buffer to copy data into and which is
                         // next up if we're in continuous mode
@@ -863,6 +1322,9 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                             app_buf_ref = &app_buf1;
                             next_app_buf = &app_buf0;
                         }
+                        // samples each app buffer holds, looked up once
+                        let app_buf_samples = app_buf_ref.len() / 2;
+                        let next_app_buf_samples = next_app_buf.len() / 2;
 
                         // update count of outstanding sample requests
                         app.samples_outstanding
@@ -890,10 +1352,9 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                                     // there's already an outstanding request to the ADC
                                     // for the next app_buffer that was placed last
                                     // time, so we need to account for that
-                                    let samples_needed =
-                                        next_app_buf.enter(|buf| buf.len() / 2).unwrap_or(0);
-                                    app.samples_remaining
-                                        .set(samples_needed - app.next_samples_outstanding.get());
+                                    app.samples_remaining.set(
+                                        next_app_buf_samples - app.next_samples_outstanding.get(),
+                                    );
                                     app.samples_outstanding
                                         .set(app.next_samples_outstanding.get());
                                     app.using_app_buf0.set(!app.using_app_buf0.get());
@@ -912,7 +1373,6 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                                         // are smaller than the length of the adc
                                         // buffers, which is unsustainable at high
                                         // sampling frequencies
-                                        let next_next_app_buf = &app_buf_ref;
 
                                         // provide a new buffer. However, we cannot
                                         // currently update state since the next
@@ -920,11 +1380,8 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                                         // We'll just make a request and handle the
                                         // state updating on next callback
                                         self.take_and_map_buffer(|adc_buf| {
-                                            let samples_needed = next_next_app_buf
-                                                .enter(|buf| buf.len() / 2)
-                                                .unwrap_or(0);
                                             let request_len =
-                                                cmp::min(samples_needed, adc_buf.len());
+                                                cmp::min(app_buf_samples, adc_buf.len());
                                             app.next_samples_outstanding.set(request_len);
                                             let _ = self
                                                 .adc
@@ -972,9 +1429,8 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                                     // just make a request and handle the state updating
                                     // on next callback
                                     self.take_and_map_buffer(|adc_buf| {
-                                        let samples_needed =
-                                            next_app_buf.enter(|buf| buf.len() / 2).unwrap_or(0);
-                                        let request_len = cmp::min(samples_needed, adc_buf.len());
+                                        let request_len =
+                                            cmp::min(next_app_buf_samples, adc_buf.len());
                                         app.next_samples_outstanding.set(request_len);
                                         let _ = self
                                             .adc
@@ -1005,58 +1461,31 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
                             });
                         }
 
-                        let skip_amt = app.app_buf_offset.get() / 2;
-
-                        {
-                            let app_buf = if use0 { &app_buf0 } else { &app_buf1 };
-
-                            // next we should copy bytes to the app buffer
-                            let _ = app_buf.mut_enter(|app_buf| {
-                                // Copy bytes to app buffer by iterating over the
-                                // data.
-                                buffer_with_samples.map(|adc_buf| {
-                                    // The `for` commands:
-                                    //  * `chunks_mut`: get sets of two bytes from the app
-                                    //                  buffer
-                                    //  * `skip`: skips the already written bytes from the
-                                    //            app buffer
-                                    //  * `zip`: ties that iterator to an iterator on the
-                                    //           adc buffer, limiting iteration length to
-                                    //           the minimum of each of their lengths
-                                    //  * `take`: limits us to the minimum of buffer lengths
-                                    //            or sample length
-                                    // We then split each sample into its two bytes and copy
-                                    // them to the app buffer
-                                    for (chunk, &sample) in app_buf
-                                        .chunks(2)
-                                        .skip(skip_amt)
-                                        .zip(adc_buf.iter())
-                                        .take(length)
-                                    {
-                                        let mut val = sample;
-                                        for byte in chunk.iter() {
-                                            byte.set((val & 0xFF) as u8);
-                                            val >>= 8;
-                                        }
-                                    }
-                                });
+                        // next we should copy the samples to the app buffer,
+                        // after the ones already there
+                        let offset = app.app_buf_offset.get();
+                        let _ = app_buf_ref.mut_enter(|app_buf| {
+                            buffer_with_samples.map(|adc_buf| {
+                                if let Some(dest) = app_buf.get_from(offset..) {
+                                    copy_samples(&adc_buf[..cmp::min(length, adc_buf.len())], dest);
+                                }
                             });
-                        }
+                        });
                         // update our byte offset based on how many samples we
                         // copied
                         app.app_buf_offset
                             .set(app.app_buf_offset.get() + length * 2);
 
-                        // let in_use_buf;
-                        let (buf_ptr, buf_len) = if use0 {
-                            (app_buf0.ptr(), app_buf0.len())
-                        } else {
-                            (app_buf1.ptr(), app_buf1.len())
-                        };
+                        let buf_ptr = app_buf_ref.ptr();
                         // if the app_buffer is filled, perform callback
                         if perform_callback {
                             // actually schedule the callback
-                            let len_chan = ((buf_len / 2) << 8) | (self.channel.get() & 0xFF);
+                            let samples = if self.mode.get() == AdcMode::SingleBuffer {
+                                app.samples_requested.get()
+                            } else {
+                                app_buf_samples
+                            };
+                            let len_chan = pack_samples_channel(samples, self.channel.get());
                             kernel_data
                                 .schedule_upcall(
                                     0,
@@ -1103,7 +1532,9 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> hil::adc::HighSpeedC
             unexpected_state = true;
         }
 
-        if unexpected_state {
+        if let Some(error) = failure.filter(|_| expected && !unexpected_state) {
+            self.sampling_failed(error);
+        } else if unexpected_state {
             // Operation was likely canceled, or the app crashed. Make sure
             // state is consistent. No callback.
             self.active.set(false);
@@ -1158,26 +1589,25 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> SyscallDriver for Ad
         // capsule no longer exists.
         let match_or_empty_or_nonexistant = self.processid.map_or(true, |owning_app| {
             // We have recorded that an app has ownership of the ADC.
-
-            // If the ADC is still active, then we need to wait for the operation
-            // to finish and the app, whether it exists or not (it may have crashed),
-            // still owns this capsule. If the ADC is not active, then
-            // we need to verify that that application still exists, and remove
-            // it as owner if not.
-            if self.active.get() {
-                owning_app == processid
-            } else {
-                // Check the app still exists.
-                //
-                // If the `.enter()` succeeds, then the app is still valid, and
-                // we can check if the owning app matches the one that called
-                // the command. If the `.enter()` fails, then the owning app no
-                // longer exists and we return `true` to signify the
-                // "or_nonexistant" case.
-                self.apps
-                    .enter(owning_app, |_, _| owning_app == processid)
-                    .unwrap_or(true)
-            }
+            //
+            // Check the app still exists. If the `.enter()` succeeds, then the
+            // app is still valid, and we can check if the owning app matches
+            // the one that called the command. If the `.enter()` fails, then
+            // the owning app no longer exists and we return `true` to signify
+            // the "or_nonexistant" case. A restarted app is a new process with
+            // a new `ProcessId`, so it does not match its previous instance.
+            self.apps
+                .enter(owning_app, |_, _| owning_app == processid)
+                .unwrap_or_else(|_| {
+                    // The operation of an app that crashed has nobody to
+                    // report to, and a continuous one would hold the ADC
+                    // forever. Stop it so that its samples never reach the
+                    // new owner.
+                    if self.active.get() {
+                        self.abandon_operation();
+                    }
+                    true
+                })
         });
         if match_or_empty_or_nonexistant {
             self.processid.set(processid);
@@ -1211,15 +1641,19 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> SyscallDriver for Ad
                 }),
             },
 
-            // Multiple sample on a channel
-            3 => match self.sample_buffer(channel, frequency as u32) {
-                Ok(()) => CommandReturn::success(),
-                e => CommandReturn::failure(if let Ok(err) = ErrorCode::try_from(e) {
-                    err
-                } else {
-                    panic!("ADC: invalid return code")
-                }),
-            },
+            // Multiple sample on a channel, the channel argument is packed
+            // with the number of samples
+            3 => {
+                let (samples, channel) = unpack_samples_channel(channel);
+                match self.sample_buffer(channel, frequency as u32, samples) {
+                    Ok(()) => CommandReturn::success(),
+                    e => CommandReturn::failure(if let Ok(err) = ErrorCode::try_from(e) {
+                        err
+                    } else {
+                        panic!("ADC: invalid return code")
+                    }),
+                }
+            }
 
             // Continuous buffered sampling on a channel
             4 => match self.sample_buffer_continuous(channel, frequency as u32) {
@@ -1252,6 +1686,14 @@ impl<'a, A: hil::adc::Adc<'a> + hil::adc::AdcHighSpeed<'a>> SyscallDriver for Ad
                 }
             }
 
+            // Store the configuration word of a channel
+            105 => self
+                .store_channel_config(channel, Some(frequency as u32))
+                .into(),
+
+            // Remove the configuration word of a channel
+            106 => self.store_channel_config(channel, None).into(),
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -1296,17 +1738,21 @@ impl SyscallDriver for AdcVirtualized<'_> {
 
             // Get resolution bits
             101 => {
-                if channel < self.drivers.len() {
-                    CommandReturn::success_u32(self.drivers[channel].get_resolution_bits() as u32)
-                } else {
+                if channel >= self.drivers.len() {
                     CommandReturn::failure(ErrorCode::NODEVICE)
+                } else if self.is_unhealthy(channel) {
+                    CommandReturn::failure(ErrorCode::FAIL)
+                } else {
+                    CommandReturn::success_u32(self.drivers[channel].get_resolution_bits() as u32)
                 }
             }
 
             // Get voltage reference mV
             102 => {
                 if channel < self.drivers.len() {
-                    if let Some(voltage) = self.drivers[channel].get_voltage_reference_mv() {
+                    if self.is_unhealthy(channel) {
+                        CommandReturn::failure(ErrorCode::FAIL)
+                    } else if let Some(voltage) = self.drivers[channel].get_voltage_reference_mv() {
                         CommandReturn::success_u32(voltage as u32)
                     } else {
                         CommandReturn::failure(ErrorCode::NOSUPPORT)
@@ -1316,6 +1762,9 @@ impl SyscallDriver for AdcVirtualized<'_> {
                 }
             }
 
+            // Number of samples that timed out
+            103 => CommandReturn::success_u32(self.timeouts.get() as u32),
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -1327,9 +1776,16 @@ impl SyscallDriver for AdcVirtualized<'_> {
 
 impl<'a> hil::adc::Client for AdcVirtualized<'a> {
     fn sample_ready(&self, sample: u16) {
-        self.current_process.take().map(|processid| {
+        let processid = self.current_process.take();
+        let kernel = self.kernel_active.replace(false);
+        if kernel || processid.is_some() {
+            self.sample_timeout.map(|timeout| timeout.cancel());
+            let bit = channel_bit(self.current_channel.get());
+            self.timed_out.set(self.timed_out.get() & !bit);
+            self.unhealthy.set(self.unhealthy.get() & !bit);
+        }
+        processid.map(|processid| {
             let _ = self.apps.enter(processid, |app, upcalls| {
-                app.pending_command = false;
                 let channel = app.channel;
                 upcalls
                     .schedule_upcall(
@@ -1340,5 +1796,10 @@ impl<'a> hil::adc::Client for AdcVirtualized<'a> {
             });
         });
         self.run_next_command();
+        // After the next sample started, so that another kernel sample
+        // requested from the callback waits for its turn.
+        if kernel {
+            self.kernel_client.map(|client| client.sample_ready(sample));
+        }
     }
 }
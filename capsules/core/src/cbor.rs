@@ -0,0 +1,325 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Minimal CBOR (RFC 8949) encoder for records sent to apps or hosts.
+//!
+//! The encoder writes into a slice given by the caller and supports the
+//! types records need: integers, byte and text strings, arrays, maps,
+//! booleans and null. Floats, tags and indefinite lengths are not
+//! supported. Arrays and maps are written as a header with the number of
+//! items (for maps, of key/value pairs), followed by the items encoded one
+//! after the other.
+//!
+//! Each item is either written completely or not at all: an item which does
+//! not fit in the rest of the slice returns `SIZE` and leaves the slice as
+//! it was, so `len()` is always the length of a valid prefix of the
+//! encoding.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use capsules_core::cbor::Encoder;
+//! let mut buffer = [0; 16];
+//! let mut encoder = Encoder::new(&mut buffer);
+//! // {1: 3300, 2: "ok"}
+//! encoder.map(2)?;
+//! encoder.unsigned(1)?;
+//! encoder.unsigned(3300)?;
+//! encoder.unsigned(2)?;
+//! encoder.text("ok")?;
+//! let len = encoder.len();
+//! # Ok::<(), kernel::ErrorCode>(())
+//! ```
+
+use kernel::ErrorCode;
+
+/// Major types of RFC 8949, section 3.1.
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+/// Simple values of RFC 8949, section 3.3.
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+const NULL: u8 = 22;
+
+pub struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Encoder<'a> {
+        Encoder { buffer, len: 0 }
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of bytes left in the slice.
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.len
+    }
+
+    pub fn unsigned(&mut self, value: u64) -> Result<(), ErrorCode> {
+        self.item(UNSIGNED, value, &[])
+    }
+
+    pub fn signed(&mut self, value: i64) -> Result<(), ErrorCode> {
+        if value < 0 {
+            // -1 - value, without overflowing on i64::MIN
+            self.item(NEGATIVE, !value as u64, &[])
+        } else {
+            self.unsigned(value as u64)
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<(), ErrorCode> {
+        self.item(BYTES, value.len() as u64, value)
+    }
+
+    pub fn text(&mut self, value: &str) -> Result<(), ErrorCode> {
+        self.item(TEXT, value.len() as u64, value.as_bytes())
+    }
+
+    /// The header of an array of `len` items.
+    pub fn array(&mut self, len: usize) -> Result<(), ErrorCode> {
+        self.item(ARRAY, len as u64, &[])
+    }
+
+    /// The header of a map of `len` key/value pairs.
+    pub fn map(&mut self, len: usize) -> Result<(), ErrorCode> {
+        self.item(MAP, len as u64, &[])
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<(), ErrorCode> {
+        self.item(SIMPLE, if value { TRUE } else { FALSE } as u64, &[])
+    }
+
+    pub fn null(&mut self) -> Result<(), ErrorCode> {
+        self.item(SIMPLE, NULL as u64, &[])
+    }
+
+    /// Writes the head of an item, with the shortest encoding of `argument`,
+    /// followed by `payload`.
+    fn item(&mut self, major: u8, argument: u64, payload: &[u8]) -> Result<(), ErrorCode> {
+        let argument_len = match argument {
+            0..=23 => 0,
+            24..=0xFF => 1,
+            0x100..=0xFFFF => 2,
+            0x1_0000..=0xFFFF_FFFF => 4,
+            _ => 8,
+        };
+        let len = 1 + argument_len + payload.len();
+        if len > self.remaining() {
+            return Err(ErrorCode::SIZE);
+        }
+        let item = &mut self.buffer[self.len..self.len + len];
+        item[0] = (major << 5)
+            | match argument_len {
+                0 => argument as u8,
+                1 => 24,
+                2 => 25,
+                4 => 26,
+                _ => 27,
+            };
+        item[1..1 + argument_len].copy_from_slice(&argument.to_be_bytes()[8 - argument_len..]);
+        item[1 + argument_len..].copy_from_slice(payload);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Encoder;
+    use kernel::ErrorCode;
+
+    /// Checks the encoding against an example of RFC 8949, appendix A.
+    fn check<F>(expected: &[u8], encode: F)
+    where
+        F: FnOnce(&mut Encoder) -> Result<(), ErrorCode>,
+    {
+        let mut buffer = [0; 64];
+        let mut encoder = Encoder::new(&mut buffer);
+        assert_eq!(encode(&mut encoder), Ok(()));
+        let len = encoder.len();
+        assert_eq!(&buffer[..len], expected);
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        let examples: [(u64, &[u8]); 11] = [
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (10, &[0x0a]),
+            (23, &[0x17]),
+            (24, &[0x18, 0x18]),
+            (25, &[0x18, 0x19]),
+            (100, &[0x18, 0x64]),
+            (1000, &[0x19, 0x03, 0xe8]),
+            (1000000, &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+            (
+                1000000000000,
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+            ),
+            (
+                18446744073709551615,
+                &[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (value, expected) in examples {
+            check(expected, |e| e.unsigned(value));
+        }
+    }
+
+    #[test]
+    fn signed_integers() {
+        let examples: [(i64, &[u8]); 7] = [
+            (0, &[0x00]),
+            (1000, &[0x19, 0x03, 0xe8]),
+            (-1, &[0x20]),
+            (-10, &[0x29]),
+            (-100, &[0x38, 0x63]),
+            (-1000, &[0x39, 0x03, 0xe7]),
+            (
+                i64::MIN,
+                &[0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (value, expected) in examples {
+            check(expected, |e| e.signed(value));
+        }
+    }
+
+    #[test]
+    fn simple_values() {
+        check(&[0xf4], |e| e.bool(false));
+        check(&[0xf5], |e| e.bool(true));
+        check(&[0xf6], |e| e.null());
+    }
+
+    #[test]
+    fn strings() {
+        check(&[0x40], |e| e.bytes(&[]));
+        check(&[0x44, 0x01, 0x02, 0x03, 0x04], |e| e.bytes(&[1, 2, 3, 4]));
+        check(&[0x60], |e| e.text(""));
+        check(&[0x61, 0x61], |e| e.text("a"));
+        check(&[0x64, 0x49, 0x45, 0x54, 0x46], |e| e.text("IETF"));
+        check(&[0x62, 0x22, 0x5c], |e| e.text("\"\\"));
+        check(&[0x62, 0xc3, 0xbc], |e| e.text("\u{00fc}"));
+        check(&[0x63, 0xe6, 0xb0, 0xb4], |e| e.text("\u{6c34}"));
+        check(&[0x64, 0xf0, 0x90, 0x85, 0x91], |e| e.text("\u{10151}"));
+    }
+
+    #[test]
+    fn arrays() {
+        check(&[0x80], |e| e.array(0));
+        check(&[0x83, 0x01, 0x02, 0x03], |e| {
+            e.array(3)?;
+            (1..=3).try_for_each(|i| e.unsigned(i))
+        });
+        // [1, [2, 3], [4, 5]]
+        check(&[0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05], |e| {
+            e.array(3)?;
+            e.unsigned(1)?;
+            e.array(2)?;
+            e.unsigned(2)?;
+            e.unsigned(3)?;
+            e.array(2)?;
+            e.unsigned(4)?;
+            e.unsigned(5)
+        });
+        // [1, 2, ..., 25]
+        let mut expected = [0; 29];
+        expected[..2].copy_from_slice(&[0x98, 0x19]);
+        for (i, byte) in expected[2..25].iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        expected[25..].copy_from_slice(&[0x18, 0x18, 0x18, 0x19]);
+        check(&expected, |e| {
+            e.array(25)?;
+            (1..=25).try_for_each(|i| e.unsigned(i))
+        });
+    }
+
+    #[test]
+    fn maps() {
+        check(&[0xa0], |e| e.map(0));
+        // {1: 2, 3: 4}
+        check(&[0xa2, 0x01, 0x02, 0x03, 0x04], |e| {
+            e.map(2)?;
+            (1..=4).try_for_each(|i| e.unsigned(i))
+        });
+        // {"a": 1, "b": [2, 3]}
+        check(
+            &[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x82, 0x02, 0x03],
+            |e| {
+                e.map(2)?;
+                e.text("a")?;
+                e.unsigned(1)?;
+                e.text("b")?;
+                e.array(2)?;
+                e.unsigned(2)?;
+                e.unsigned(3)
+            },
+        );
+        // ["a", {"b": "c"}]
+        check(&[0x82, 0x61, 0x61, 0xa1, 0x61, 0x62, 0x61, 0x63], |e| {
+            e.array(2)?;
+            e.text("a")?;
+            e.map(1)?;
+            e.text("b")?;
+            e.text("c")
+        });
+        // {"a": "A", "b": "B", "c": "C", "d": "D", "e": "E"}
+        check(
+            &[
+                0xa5, 0x61, 0x61, 0x61, 0x41, 0x61, 0x62, 0x61, 0x42, 0x61, 0x63, 0x61, 0x43, 0x61,
+                0x64, 0x61, 0x44, 0x61, 0x65, 0x61, 0x45,
+            ],
+            |e| {
+                e.map(5)?;
+                [("a", "A"), ("b", "B"), ("c", "C"), ("d", "D"), ("e", "E")]
+                    .iter()
+                    .try_for_each(|(key, value)| {
+                        e.text(key)?;
+                        e.text(value)
+                    })
+            },
+        );
+    }
+
+    #[test]
+    fn items_that_do_not_fit_are_not_written() {
+        let mut buffer = [0xaa; 4];
+        let mut encoder = Encoder::new(&mut buffer);
+        assert_eq!(encoder.unsigned(1000), Ok(()));
+        assert_eq!(encoder.remaining(), 1);
+        assert_eq!(encoder.unsigned(24), Err(ErrorCode::SIZE));
+        assert_eq!(encoder.text("a"), Err(ErrorCode::SIZE));
+        assert_eq!(encoder.len(), 3);
+        assert_eq!(encoder.unsigned(23), Ok(()));
+        assert_eq!(encoder.null(), Err(ErrorCode::SIZE));
+        assert_eq!(encoder.len(), 4);
+        assert_eq!(buffer, [0x19, 0x03, 0xe8, 0x17]);
+
+        let mut buffer = [0xaa; 4];
+        let mut encoder = Encoder::new(&mut buffer);
+        assert_eq!(encoder.bytes(&[1, 2, 3, 4]), Err(ErrorCode::SIZE));
+        assert!(encoder.is_empty());
+        assert_eq!(buffer, [0xaa; 4]);
+    }
+}
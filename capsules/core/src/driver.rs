@@ -23,6 +23,7 @@ pub enum NUM {
     LowLevelDebug         = 0x00008,
     ReadOnlyState         = 0x00009,
     Pwm                   = 0x00010,
+    AdcThreshold          = 0x00011,
 
     // Kernel
     Ipc                   = 0x10000,
@@ -57,6 +58,8 @@ pub enum NUM {
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
     Kv                    = 0x50003,
+    AppStaging            = 0x50004,
+    Settings              = 0x50005,
 
     // Sensors
     Temperature           = 0x60000,
@@ -76,6 +79,7 @@ pub enum NUM {
     Lsm303dlch            = 0x70006,
     Mlx90614              = 0x70007,
     Lsm6dsoxtr            = 0x70008,
+    Si7021                = 0x70009,
 
     // Other ICs
     Ltc294x               = 0x80000,
@@ -93,5 +97,7 @@ pub enum NUM {
     KeyboardHid           = 0x90005,
     DateTime              = 0x90007,
     CycleCount            = 0x90008,
+    SyscallAccounting     = 0x90009,
+    InterruptLatency      = 0x9000A,
 }
 }
@@ -13,6 +13,7 @@ pub mod stream;
 pub mod adc;
 pub mod alarm;
 pub mod button;
+pub mod cbor;
 pub mod console;
 pub mod console_ordered;
 pub mod driver;
@@ -24,6 +25,7 @@ pub mod led;
 pub mod low_level_debug;
 pub mod process_console;
 pub mod rng;
+pub mod single_owner;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod virtualizers;
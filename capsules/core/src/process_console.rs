@@ -6,6 +6,17 @@
 //! a terminal to inspect and control userspace processes.
 //!
 //! For a more in-depth documentation check /doc/Process_Console.md
+//!
+//! Board commands
+//! --------------
+//!
+//! Boards can add their own commands with [`ProcessConsole::set_commands`].
+//! Each command is a [`ConsoleCommand`] provider: the console runs it when
+//! the first word of the command line is its name, and lists it in the help
+//! output. A provider prints through the [`ConsoleCommandClient`] and calls
+//! `command_done()` when it is finished, possibly from a later callback;
+//! the prompt is displayed again only then. The built-in commands take
+//! precedence over board commands with the same name.
 use core::cell::Cell;
 use core::cmp;
 use core::fmt;
@@ -45,6 +56,45 @@ pub const DEFAULT_COMMAND_HISTORY_LEN: usize = 10;
 const VALID_COMMANDS_STR: &[u8] =
     b"help status list stop start fault boot terminate process kernel reset panic console-start console-stop\r\n";
 
+/// A command provided by the board to the process console.
+pub trait ConsoleCommand<'a> {
+    /// The name of the command, typed as the first word of the command line.
+    fn name(&self) -> &'static str;
+
+    /// One line describing the usage of the command, displayed by `help`.
+    fn help(&self) -> &'static str;
+
+    /// Set the console the command prints to. Called by
+    /// [`ProcessConsole::set_commands`].
+    fn set_client(&self, client: &'a dyn ConsoleCommandClient);
+
+    /// Run the command with the rest of the command line as arguments.
+    ///
+    /// On `Ok(())`, the command must call `command_done()` on its client
+    /// when it is finished, from this call or from a later callback. On
+    /// `Err`, the console prints the error and the usage of the command.
+    fn execute(&self, arguments: &str) -> Result<(), ErrorCode>;
+}
+
+/// Interface of the process console for the board commands.
+pub trait ConsoleCommandClient {
+    /// Print `bytes` on the console.
+    fn print(&self, bytes: &[u8]);
+
+    /// The running command is finished.
+    fn command_done(&self);
+}
+
+/// Whether a board command is running.
+#[derive(Clone, Copy, PartialEq)]
+enum CommandState {
+    Idle,
+    /// `execute()` has not returned yet.
+    Executing,
+    /// The command will call `command_done()` later.
+    Pending,
+}
+
 /// Escape character for ANSI escape sequences.
 const ESC: u8 = b'\x1B';
 
@@ -260,6 +310,12 @@ pub struct ProcessConsole<
     /// received after finishing echoing the last newline character.
     execute: Cell<bool>,
 
+    /// Commands added by the board.
+    commands: Cell<&'a [&'a dyn ConsoleCommand<'a>]>,
+
+    /// Whether a board command is running.
+    command_state: Cell<CommandState>,
+
     /// Reference to the kernel object so we can access process state.
     kernel: &'static Kernel,
 
@@ -468,6 +524,8 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
             cursor: Cell::new(0),
             previous_byte: Cell::new(EOL),
             execute: Cell::new(false),
+            commands: Cell::new(&[]),
+            command_state: Cell::new(CommandState::Idle),
             kernel: kernel,
             kernel_addresses: kernel_addresses,
             reset_function: reset_function,
@@ -475,6 +533,14 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
         }
     }
 
+    /// Add the commands provided by the board to the console.
+    pub fn set_commands(&'a self, commands: &'a [&'a dyn ConsoleCommand<'a>]) {
+        for command in commands {
+            command.set_client(self);
+        }
+        self.commands.set(commands);
+    }
+
     /// Start the process console listening for user commands.
     pub fn start(&self) -> Result<(), ErrorCode> {
         if self.mode.get() == ProcessConsoleState::Off {
@@ -523,9 +589,68 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
         let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
 
         let _ = self.write_bytes(b"Welcome to the process console.\r\n");
+        self.print_valid_commands();
+        self.prompt();
+    }
+
+    /// Print the names of the built-in and board commands.
+    fn print_valid_commands(&self) {
         let _ = self.write_bytes(b"Valid commands are: ");
         let _ = self.write_bytes(VALID_COMMANDS_STR);
-        self.prompt();
+        if !self.commands.get().is_empty() {
+            let _ = self.write_bytes(b"Board commands are:");
+            for command in self.commands.get() {
+                let _ = self.write_bytes(b" ");
+                let _ = self.write_bytes(command.name().as_bytes());
+            }
+            let _ = self.write_bytes(b"\r\n");
+        }
+    }
+
+    /// Print the usage of a board command.
+    fn print_usage(&self, command: &dyn ConsoleCommand<'a>) {
+        let mut console_writer = ConsoleWriter::new();
+        let _ = write(
+            &mut console_writer,
+            format_args!("  {:14}{}\r\n", command.name(), command.help()),
+        );
+        let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+    }
+
+    /// Find the board command the command line starts with.
+    fn find_command(&self, command_line: &str) -> Option<&'a dyn ConsoleCommand<'a>> {
+        let name = command_line.split_whitespace().next()?;
+        self.commands
+            .get()
+            .iter()
+            .find(|command| command.name() == name)
+            .copied()
+    }
+
+    /// Run a board command. `arguments` is the rest of the command line.
+    fn run_command(&self, command: &dyn ConsoleCommand<'a>, arguments: &str) {
+        if self.command_state.get() != CommandState::Idle {
+            let _ = self.write_bytes(b"Busy: the previous command is not finished.\r\n");
+            return;
+        }
+        self.command_state.set(CommandState::Executing);
+        match command.execute(arguments) {
+            Ok(()) => {
+                if self.command_state.get() == CommandState::Executing {
+                    self.command_state.set(CommandState::Pending);
+                }
+            }
+            Err(e) => {
+                self.command_state.set(CommandState::Idle);
+                let mut console_writer = ConsoleWriter::new();
+                let _ = write(
+                    &mut console_writer,
+                    format_args!("{} failed: {:?}\r\n", command.name(), e),
+                );
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                self.print_usage(command);
+            }
+        }
     }
 
     /// Simple state machine helper function that identifies the next state for
@@ -790,6 +915,12 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                             let _ = self.write_bytes(b"Welcome to the process console.\r\n");
                             let _ = self.write_bytes(b"Valid commands are: ");
                             let _ = self.write_bytes(VALID_COMMANDS_STR);
+                            if !self.commands.get().is_empty() {
+                                let _ = self.write_bytes(b"Board commands are:\r\n");
+                                for command in self.commands.get() {
+                                    self.print_usage(*command);
+                                }
+                            }
                         } else if clean_str.starts_with("console-stop") {
                             let _ = self.write_bytes(b"Disabling the process console.\r\n");
                             let _ = self.write_bytes(b"Run console-start to reactivate.\r\n");
@@ -1003,9 +1134,11 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                             );
                         } else if clean_str.starts_with("panic") {
                             panic!("Process Console forced a kernel panic.");
+                        } else if let Some(board_command) = self.find_command(clean_str) {
+                            let arguments = clean_str[board_command.name().len()..].trim();
+                            self.run_command(board_command, arguments);
                         } else {
-                            let _ = self.write_bytes(b"Valid commands are: ");
-                            let _ = self.write_bytes(VALID_COMMANDS_STR);
+                            self.print_valid_commands();
                         }
                     }
                     Err(_e) => {
@@ -1023,7 +1156,9 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
             command[0] = 0;
         });
         self.command_index.set(0);
-        if self.writer_state.get() == WriterState::Empty {
+        if self.writer_state.get() == WriterState::Empty
+            && self.command_state.get() == CommandState::Idle
+        {
             self.prompt();
         }
     }
@@ -1129,6 +1264,26 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
     }
 }
 
+impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCapability>
+    ConsoleCommandClient for ProcessConsole<'a, COMMAND_HISTORY_LEN, A, C>
+{
+    fn print(&self, bytes: &[u8]) {
+        let _ = self.write_bytes(bytes);
+    }
+
+    fn command_done(&self) {
+        match self.command_state.get() {
+            // `read_command()` displays the prompt.
+            CommandState::Executing => self.command_state.set(CommandState::Idle),
+            CommandState::Pending => {
+                self.command_state.set(CommandState::Idle);
+                self.prompt();
+            }
+            CommandState::Idle => {}
+        }
+    }
+}
+
 impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCapability> AlarmClient
     for ProcessConsole<'a, COMMAND_HISTORY_LEN, A, C>
 {
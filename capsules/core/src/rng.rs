@@ -6,11 +6,41 @@
 //! userspace applications to request randomness, entropy conversion, entropy
 //! to randomness conversion, and synchronous random number generation.
 //!
+//! The synchronous generator can be reseeded from its `Rng` at any time with
+//! `SynchronousRandom::refresh()`, or periodically with `PeriodicRefresh`.
+//! On boards without a source of randomness, a generator seeded with
+//! `reseed()` over `NoRng` can stand in for one through `RandomRng`.
+//!
+//! `ChaChaRng` turns an `Entropy32` into a ChaCha20 based generator, which
+//! serves as both an `Rng` and a `Random` and only goes back to its source
+//! to reseed.
+//!
+//! Sources whose words may be biased or correlated can be wrapped in
+//! `ConditionedEntropy32`, which compresses several raw words into each word
+//! it outputs.
+//!
+//! Kernel capsules which need a buffer of random bytes, such as a seed or a
+//! key, get it from `RngBufferFill` instead of taking words from the
+//! iterator themselves. Each of them fills its buffer through its own
+//! `RngBufferFillUser`, one buffer at a time.
+//!
 //!
 //! The RNG accepts a user-defined callback and buffer to hold received
 //! randomness. A single command starts the RNG, the callback is called when the
 //! requested amount of randomness is received, or the buffer is filled.
 //!
+//! The driver also keeps a small cache of random words, filled with the words
+//! left over once all apps got what they asked for. Command 2 returns one of
+//! them right away, so an app which needs a single `u32` does not have to go
+//! through the allow, subscribe and yield sequence. If the cache is empty,
+//! the command starts filling it and fails, and the app can retry later or
+//! use command 1 instead. Each cached word is returned only once.
+//!
+//! Command 3 cancels the request of the calling app, which gets no callback
+//! for it. If no other app is waiting for randomness, the RNG is stopped, so
+//! an app can give up on a source that never answers. Apps that no longer
+//! exist are not waiting for randomness.
+//!
 //! Usage
 //! -----
 //!
@@ -26,14 +56,17 @@
 
 use core::cell::Cell;
 
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::entropy;
 use kernel::hil::entropy::{Entropy32, Entropy8};
 use kernel::hil::rng;
 use kernel::hil::rng::{Client, Continue, Random, Rng};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
 use kernel::processbuffer::WriteableProcessBuffer;
 use kernel::syscall::{CommandReturn, SyscallDriver};
-use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
 /// Syscall driver number.
@@ -53,10 +86,19 @@ pub struct App {
     idx: usize,
 }
 
+/// Number of random words the driver caches for command 2.
+pub const CACHE_WORDS: usize = 16;
+
 pub struct RngDriver<'a, R: Rng<'a>> {
     rng: &'a R,
     apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     getting_randomness: Cell<bool>,
+    /// Ring buffer of cached words, starting at `cache_head`.
+    cache: [Cell<u32>; CACHE_WORDS],
+    cache_head: Cell<usize>,
+    cache_len: Cell<usize>,
+    /// Whether the RNG should keep running until the cache is full.
+    filling_cache: Cell<bool>,
 }
 
 impl<'a, R: Rng<'a>> RngDriver<'a, R> {
@@ -68,7 +110,64 @@ impl<'a, R: Rng<'a>> RngDriver<'a, R> {
             rng: rng,
             apps: grant,
             getting_randomness: Cell::new(false),
+            cache: Default::default(),
+            cache_head: Cell::new(0),
+            cache_len: Cell::new(0),
+            filling_cache: Cell::new(false),
+        }
+    }
+
+    /// Number of words in the cache.
+    pub fn cached_words(&self) -> usize {
+        self.cache_len.get()
+    }
+
+    /// Removes the oldest word from the cache.
+    fn pop_cached(&self) -> Option<u32> {
+        let len = self.cache_len.get();
+        if len == 0 {
+            return None;
+        }
+        let head = self.cache_head.get();
+        self.cache_head.set((head + 1) % CACHE_WORDS);
+        self.cache_len.set(len - 1);
+        Some(self.cache[head].get())
+    }
+
+    /// Moves words from `randomness` into the cache until either is
+    /// exhausted. Returns whether the cache is full.
+    fn fill_cache(&self, randomness: &mut dyn Iterator<Item = u32>) -> bool {
+        while self.cache_len.get() < CACHE_WORDS {
+            match randomness.next() {
+                Some(word) => {
+                    let len = self.cache_len.get();
+                    self.cache[(self.cache_head.get() + len) % CACHE_WORDS].set(word);
+                    self.cache_len.set(len + 1);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether an existing app is waiting for random bytes.
+    fn has_pending_requests(&self) -> bool {
+        self.apps
+            .iter()
+            .any(|cntr| cntr.enter(|app, _| app.remaining > 0))
+    }
+
+    /// Starts the RNG unless it is already running.
+    fn start_rng(&self) -> Result<(), ErrorCode> {
+        if self.getting_randomness.get() {
+            return Ok(());
         }
+        self.getting_randomness.set(true);
+        let result = self.rng.get();
+        if result.is_err() {
+            self.getting_randomness.set(false);
+        }
+        result
     }
 }
 
@@ -162,11 +261,18 @@ impl<'a, R: Rng<'a>> rng::Client for RngDriver<'a, R> {
             }
         }
 
-        if done {
+        if !done {
+            return rng::Continue::More;
+        }
+
+        // All apps are served, keep what is left for command 2.
+        let full = self.fill_cache(randomness);
+        if self.filling_cache.get() && !full {
+            rng::Continue::More
+        } else {
+            self.filling_cache.set(false);
             self.getting_randomness.set(false);
             rng::Continue::Done
-        } else {
-            rng::Continue::More
         }
     }
 }
@@ -185,29 +291,58 @@ impl<'a, R: Rng<'a>> SyscallDriver for RngDriver<'a, R> {
 
             // Ask for a given number of random bytes
             1 => {
-                let mut needs_get = false;
                 let result = self
                     .apps
                     .enter(processid, |app, _| {
                         app.remaining = data;
                         app.idx = 0;
+                    })
+                    .map_err(ErrorCode::from);
+
+                // Assume that the process has a callback & slice
+                // set. It might die or revoke them before the
+                // result arrives anyways
+                match result.and_then(|()| self.start_rng()) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(error) => {
+                        let _ = self.apps.enter(processid, |app, _| {
+                            app.remaining = 0;
+                        });
+                        CommandReturn::failure(error)
+                    }
+                }
+            }
 
-                        // Assume that the process has a callback & slice
-                        // set. It might die or revoke them before the
-                        // result arrives anyways
-                        if !self.getting_randomness.get() {
-                            self.getting_randomness.set(true);
-                            needs_get = true;
+            // Return a cached random word
+            2 => match self.pop_cached() {
+                Some(word) => CommandReturn::success_u32(word),
+                None => {
+                    self.filling_cache.set(true);
+                    match self.start_rng() {
+                        Ok(()) => CommandReturn::failure(ErrorCode::BUSY),
+                        Err(error) => {
+                            self.filling_cache.set(false);
+                            CommandReturn::failure(error)
                         }
+                    }
+                }
+            },
 
-                        CommandReturn::success()
-                    })
-                    .unwrap_or_else(|err| CommandReturn::failure(err.into()));
-                if needs_get {
-                    let _ = self.rng.get();
+            // Cancel the request for random bytes
+            3 => match self.apps.enter(processid, |app, _| {
+                app.remaining = 0;
+                app.idx = 0;
+            }) {
+                Ok(()) => {
+                    if self.getting_randomness.get() && !self.has_pending_requests() {
+                        let _ = self.rng.cancel();
+                        self.getting_randomness.set(false);
+                        self.filling_cache.set(false);
+                    }
+                    CommandReturn::success()
                 }
-                result
-            }
+                Err(err) => CommandReturn::failure(err.into()),
+            },
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -252,17 +387,12 @@ impl<'a, E: Entropy32<'a>> entropy::Client32 for Entropy32ToRandom<'a, E> {
         entropy: &mut dyn Iterator<Item = u32>,
         error: Result<(), ErrorCode>,
     ) -> entropy::Continue {
+        // Errors are passed on with the entropy, which may still hold
+        // random words.
         self.client.map_or(entropy::Continue::Done, |client| {
-            if error != Ok(()) {
-                match client.randomness_available(&mut Entropy32ToRandomIter(entropy), error) {
-                    rng::Continue::More => entropy::Continue::More,
-                    rng::Continue::Done => entropy::Continue::Done,
-                }
-            } else {
-                match client.randomness_available(&mut Entropy32ToRandomIter(entropy), Ok(())) {
-                    rng::Continue::More => entropy::Continue::More,
-                    rng::Continue::Done => entropy::Continue::Done,
-                }
+            match client.randomness_available(&mut Entropy32ToRandomIter(entropy), error) {
+                rng::Continue::More => entropy::Continue::More,
+                rng::Continue::Done => entropy::Continue::Done,
             }
         })
     }
@@ -278,9 +408,16 @@ impl Iterator for Entropy32ToRandomIter<'_> {
     }
 }
 
+/// Assembles words of entropy from bytes, least significant byte first.
+///
+/// A word is passed to the client once its four bytes have arrived, and is
+/// kept until the client takes it. When the client returns
+/// `Continue::Done`, the word being assembled is discarded, so the next
+/// request starts from fresh bytes.
 pub struct Entropy8To32<'a, E: Entropy8<'a>> {
     egen: &'a E,
     client: OptionalCell<&'a dyn entropy::Client32>,
+    /// Number of bytes in `bytes`, 4 for a word the client has not taken.
     count: Cell<usize>,
     bytes: Cell<u32>,
 }
@@ -294,6 +431,13 @@ impl<'a, E: Entropy8<'a>> Entropy8To32<'a, E> {
             bytes: Cell::new(0),
         }
     }
+
+    /// Discard the word being assembled, so the next word is made of bytes
+    /// received after this call only.
+    pub fn flush(&self) {
+        self.count.set(0);
+        self.bytes.set(0);
+    }
 }
 
 impl<'a, E: Entropy8<'a>> Entropy32<'a> for Entropy8To32<'a, E> {
@@ -327,13 +471,18 @@ impl<'a, E: Entropy8<'a>> entropy::Client8 for Entropy8To32<'a, E> {
     ) -> entropy::Continue {
         self.client.map_or(entropy::Continue::Done, |client| {
             if error != Ok(()) {
-                client.entropy_available(&mut Entropy8To32Iter(self), error)
-            } else {
+                let rval = client.entropy_available(&mut Entropy8To32Iter(self), error);
+                if rval == entropy::Continue::Done {
+                    self.flush();
+                }
+                return rval;
+            }
+            loop {
                 let mut count = self.count.get();
                 // Read in one byte at a time until we have 4;
-                // return More if we need more, else return the value
-                // of the upper randomness_available, as if it needs more
-                // we'll need more from the underlying Rng8.
+                // return More if we need more, else pass the word to
+                // the client, and keep going for as long as it asks
+                // for more.
                 while count < 4 {
                     let byte = entropy.next();
                     match byte {
@@ -350,9 +499,19 @@ impl<'a, E: Entropy8<'a>> entropy::Client8 for Entropy8To32<'a, E> {
                         }
                     }
                 }
-                let rval = client.entropy_available(&mut Entropy8To32Iter(self), Ok(()));
-                self.bytes.set(0);
-                rval
+                match client.entropy_available(&mut Entropy8To32Iter(self), Ok(())) {
+                    entropy::Continue::Done => {
+                        self.flush();
+                        return entropy::Continue::Done;
+                    }
+                    entropy::Continue::More => {
+                        if self.count.get() == 4 {
+                            // The client did not take the word, it gets
+                            // it again with the next bytes.
+                            return entropy::Continue::More;
+                        }
+                    }
+                }
             }
         })
     }
@@ -367,7 +526,7 @@ impl<'a, 'b: 'a, E: Entropy8<'b>> Iterator for Entropy8To32Iter<'a, 'b, E> {
         let count = self.0.count.get();
         if count == 4 {
             self.0.count.set(0);
-            Some(self.0.bytes.get())
+            Some(self.0.bytes.replace(0))
         } else {
             None
         }
@@ -460,29 +619,262 @@ impl<'a, 'b: 'a, E: Entropy32<'b>> Iterator for Entropy32To8Iter<'a, 'b, E> {
     }
 }
 
+/// An `Entropy32` whose words each condense several words of another
+/// `Entropy32`, for sources that may output biased or correlated words.
+///
+/// The raw words are absorbed, one at a time, into a 128 bit state mixed
+/// with the rounds of HalfSipHash-2-4 under a fixed key. Once `ratio` raw
+/// words were absorbed since the previous output, the state is finalized
+/// into a conditioned word for the client; the state carries over to the
+/// next word. Until then, the adaptor asks its source for more words
+/// without calling the client. With a source providing at least `32 /
+/// ratio` bits of entropy per word, each output word gets 32 bits of
+/// input entropy. This is a compression function, not a vetted
+/// conditioning component, and the ratio has to be chosen from the
+/// measured entropy of the source.
+///
+/// Like `Entropy8To32`, a word the client does not take is kept for it, and
+/// when the client returns `Continue::Done` the words absorbed towards the
+/// next output are not counted anymore.
+pub struct ConditionedEntropy32<'a, E: Entropy32<'a>> {
+    egen: &'a E,
+    client: OptionalCell<&'a dyn entropy::Client32>,
+    ratio: usize,
+    state: Cell<[u32; 4]>,
+    /// Raw words absorbed since the previous output.
+    absorbed: Cell<usize>,
+    /// Conditioned word the client has not taken.
+    word: OptionalCell<u32>,
+}
+
+impl<'a, E: Entropy32<'a>> ConditionedEntropy32<'a, E> {
+    /// `ratio` raw words are absorbed for each conditioned word, at least
+    /// one.
+    pub fn new(egen: &'a E, ratio: usize) -> Self {
+        Self {
+            egen,
+            client: OptionalCell::empty(),
+            ratio: ratio.max(1),
+            // HalfSipHash initialization, with a zero key.
+            state: Cell::new([0, 0, 0x6c796765, 0x74656462]),
+            absorbed: Cell::new(0),
+            word: OptionalCell::empty(),
+        }
+    }
+
+    /// Raw words absorbed for each conditioned word.
+    pub fn ratio(&self) -> usize {
+        self.ratio
+    }
+
+    /// Discard the conditioned word the client has not taken and the count
+    /// of words absorbed towards the next one, so the next word needs
+    /// `ratio` raw words received after this call.
+    pub fn flush(&self) {
+        self.word.clear();
+        self.absorbed.set(0);
+    }
+
+    fn absorb(&self, raw: u32) {
+        let mut v = self.state.get();
+        v[3] ^= raw;
+        half_sip_rounds(&mut v, 2);
+        v[0] ^= raw;
+        self.state.set(v);
+        self.absorbed.set(self.absorbed.get() + 1);
+    }
+
+    fn squeeze(&self) -> u32 {
+        let mut v = self.state.get();
+        v[2] ^= 0xff;
+        half_sip_rounds(&mut v, 4);
+        self.state.set(v);
+        self.absorbed.set(0);
+        v[1] ^ v[3]
+    }
+}
+
+/// `rounds` SipRounds of HalfSipHash over `v`.
+fn half_sip_rounds(v: &mut [u32; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(5) ^ v[0];
+        v[0] = v[0].rotate_left(16);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(8) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(7) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[2];
+        v[2] = v[2].rotate_left(16);
+    }
+}
+
+impl<'a, E: Entropy32<'a>> Entropy32<'a> for ConditionedEntropy32<'a, E> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        self.egen.get()
+    }
+
+    /// Cancel acquisition of random numbers.
+    ///
+    /// There are two valid return values:
+    ///   - Ok(()): an outstanding request from `get` has been cancelled,
+    ///     or there was no outstanding request. No `randomness_available`
+    ///     callback will be issued.
+    ///   - FAIL: There will be a randomness_available callback, which
+    ///     may or may not return an error code.
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.egen.cancel()
+    }
+
+    fn set_client(&'a self, client: &'a dyn entropy::Client32) {
+        self.egen.set_client(self);
+        self.client.set(client);
+    }
+}
+
+impl<'a, E: Entropy32<'a>> entropy::Client32 for ConditionedEntropy32<'a, E> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        self.client.map_or(entropy::Continue::Done, |client| {
+            if error != Ok(()) {
+                let rval = client.entropy_available(&mut ConditionedEntropy32Iter(self), error);
+                if rval == entropy::Continue::Done {
+                    self.flush();
+                }
+                return rval;
+            }
+            loop {
+                // Absorb raw words until the next conditioned word is
+                // ready, the client is only called once it is.
+                while self.word.is_none() {
+                    match entropy.next() {
+                        None => return entropy::Continue::More,
+                        Some(raw) => {
+                            self.absorb(raw);
+                            if self.absorbed.get() >= self.ratio {
+                                self.word.set(self.squeeze());
+                            }
+                        }
+                    }
+                }
+                match client.entropy_available(&mut ConditionedEntropy32Iter(self), Ok(())) {
+                    entropy::Continue::Done => {
+                        self.flush();
+                        return entropy::Continue::Done;
+                    }
+                    entropy::Continue::More => {
+                        if self.word.is_some() {
+                            // The client did not take the word, it gets
+                            // it again with the next raw words.
+                            return entropy::Continue::More;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct ConditionedEntropy32Iter<'a, 'b: 'a, E: Entropy32<'b>>(&'a ConditionedEntropy32<'b, E>);
+
+impl<'a, 'b: 'a, E: Entropy32<'b>> Iterator for ConditionedEntropy32Iter<'a, 'b, E> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.0.word.take()
+    }
+}
+
+/// A synchronous random number generator seeded from an asynchronous `Rng`.
+///
+/// `initialize()` requests a seed from the `Rng`, and `refresh()` requests a
+/// new one later on. The new seed replaces the state of the generator in
+/// the `randomness_available` callback, all at once: callers of `random()`
+/// keep getting numbers from the previous seed until the callback, and from
+/// the new seed afterwards. A failed or cancelled request leaves the current
+/// seed in place, and is kept for `seed_error()`. `PeriodicRefresh` calls
+/// `refresh()` on an alarm.
 pub struct SynchronousRandom<'a, R: Rng<'a>> {
     rgen: &'a R,
     seed: Cell<u32>,
+    /// Whether a seed was received from `rgen` or set with `reseed()`.
+    seeded: Cell<bool>,
+    /// Whether a seed was requested from `rgen` and not received yet.
+    refreshing: Cell<bool>,
+    /// Error of the last failed seed request, if no seed came since.
+    seed_error: OptionalCell<ErrorCode>,
+    /// Number of `random()` outputs since the last reseed.
+    outputs_since_reseed: Cell<usize>,
 }
 
-#[allow(dead_code)]
 impl<'a, R: Rng<'a>> SynchronousRandom<'a, R> {
-    fn new(rgen: &'a R) -> Self {
+    pub fn new(rgen: &'a R) -> Self {
         Self {
             rgen: rgen,
             seed: Cell::new(0),
+            seeded: Cell::new(false),
+            refreshing: Cell::new(false),
+            seed_error: OptionalCell::empty(),
+            outputs_since_reseed: Cell::new(0),
         }
     }
+
+    /// Request a new seed from the underlying `Rng`. Returns `ALREADY` if a
+    /// request is outstanding, or the error of the `Rng`.
+    pub fn refresh(&self) -> Result<(), ErrorCode> {
+        if self.refreshing.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.rgen.get().map_err(|error| {
+            self.seed_error.set(error);
+            error
+        })?;
+        self.refreshing.set(true);
+        Ok(())
+    }
+
+    /// Whether the generator has been seeded. If `initialize()` could not
+    /// get a seed from the `Rng`, this stays `false` until a later
+    /// `refresh()` or `reseed()` succeeds, and `seed_error()` tells why.
+    pub fn is_seeded(&self) -> bool {
+        self.seeded.get()
+    }
+
+    /// The error of the last seed request that failed, either when it was
+    /// made or in the callback of the `Rng`, or `None` if a seed was set
+    /// since.
+    pub fn seed_error(&self) -> Option<ErrorCode> {
+        self.seed_error.get()
+    }
+
+    /// Number of values returned by `random()` since the last reseed.
+    pub fn outputs_since_reseed(&self) -> usize {
+        self.outputs_since_reseed.get()
+    }
+
+    fn set_seed(&self, seed: u32) {
+        self.seed.set(seed);
+        self.seeded.set(true);
+        self.seed_error.clear();
+        self.outputs_since_reseed.set(0);
+    }
 }
 
 impl<'a, R: Rng<'a>> Random<'a> for SynchronousRandom<'a, R> {
     fn initialize(&'a self) {
         self.rgen.set_client(self);
-        let _ = self.rgen.get();
+        // `initialize()` cannot return the error. Without a seed the
+        // generator still works, deterministically, `is_seeded()` reports
+        // it and `refresh()` kept the error for `seed_error()`.
+        let _ = self.refresh();
     }
 
     fn reseed(&self, seed: u32) {
-        self.seed.set(seed);
+        self.set_seed(seed);
     }
 
     // This implementation uses a linear congruential generator due to
@@ -497,6 +889,8 @@ impl<'a, R: Rng<'a>> Random<'a> for SynchronousRandom<'a, R> {
         let val = val.wrapping_mul(LCG_MULTIPLIER);
         let val = val.wrapping_add(LCG_INCREMENT);
         self.seed.set(val);
+        self.outputs_since_reseed
+            .set(self.outputs_since_reseed.get().saturating_add(1));
         val
     }
 }
@@ -505,14 +899,686 @@ impl<'a, R: Rng<'a>> Client for SynchronousRandom<'a, R> {
     fn randomness_available(
         &self,
         randomness: &mut dyn Iterator<Item = u32>,
-        _error: Result<(), ErrorCode>,
+        error: Result<(), ErrorCode>,
     ) -> Continue {
+        if let Err(error) = error {
+            self.refreshing.set(false);
+            self.seed_error.set(error);
+            return Continue::Done;
+        }
         match randomness.next() {
             None => Continue::More,
             Some(val) => {
-                self.seed.set(val);
+                self.refreshing.set(false);
+                self.set_seed(val);
                 Continue::Done
             }
         }
     }
 }
+
+/// Periodically requests a new seed for a `SynchronousRandom`.
+pub struct PeriodicRefresh<'a, A: Alarm<'a>, R: Rng<'a>> {
+    alarm: &'a A,
+    random: &'a SynchronousRandom<'a, R>,
+    interval_ms: u32,
+}
+
+impl<'a, A: Alarm<'a>, R: Rng<'a>> PeriodicRefresh<'a, A, R> {
+    pub fn new(
+        alarm: &'a A,
+        random: &'a SynchronousRandom<'a, R>,
+        interval_ms: u32,
+    ) -> PeriodicRefresh<'a, A, R> {
+        PeriodicRefresh {
+            alarm: alarm,
+            random: random,
+            interval_ms: interval_ms,
+        }
+    }
+
+    /// Start refreshing the seed every `interval_ms`.
+    pub fn start(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(self.interval_ms));
+    }
+}
+
+impl<'a, A: Alarm<'a>, R: Rng<'a>> AlarmClient for PeriodicRefresh<'a, A, R> {
+    fn alarm(&self) {
+        // If the previous request is still outstanding there is nothing to
+        // do until the next period.
+        let _ = self.random.refresh();
+        self.start();
+    }
+}
+
+/// An `Rng` without a source, for a `SynchronousRandom` which is only
+/// seeded with `reseed()`. Every request fails with `NODEVICE`.
+pub struct NoRng;
+
+impl<'a> Rng<'a> for NoRng {
+    fn get(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NODEVICE)
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn set_client(&'a self, _client: &'a dyn rng::Client) {}
+}
+
+/// Random words handed to the client of a `RandomRng` in each callback.
+const RANDOM_RNG_WORDS: usize = 8;
+
+/// An `Rng` over a synchronous `Random` generator, for boards without a
+/// hardware source of randomness. The numbers are only as unpredictable as
+/// the seed of the generator, so they must not be used for cryptography.
+///
+/// Requests are answered from a deferred call, with up to
+/// `RANDOM_RNG_WORDS` words each time.
+pub struct RandomRng<'a, R: Random<'a>> {
+    random: &'a R,
+    client: OptionalCell<&'a dyn rng::Client>,
+    requested: Cell<bool>,
+    deferred_call: DeferredCall,
+}
+
+impl<'a, R: Random<'a>> RandomRng<'a, R> {
+    pub fn new(random: &'a R) -> Self {
+        Self {
+            random: random,
+            client: OptionalCell::empty(),
+            requested: Cell::new(false),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+}
+
+impl<'a, R: Random<'a>> Rng<'a> for RandomRng<'a, R> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        if !self.requested.replace(true) {
+            self.deferred_call.set();
+        }
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        // The deferred call finds nothing to do.
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.client.set(client);
+    }
+}
+
+impl<'a, R: Random<'a>> DeferredCallClient for RandomRng<'a, R> {
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    fn handle_deferred_call(&self) {
+        if !self.requested.replace(false) {
+            return;
+        }
+        let mut words = (0..RANDOM_RNG_WORDS).map(|_| self.random.random());
+        let more = self.client.map_or(false, |client| {
+            client.randomness_available(&mut words, Ok(())) == rng::Continue::More
+        });
+        // A `get()` from the callback already set the deferred call again.
+        if more && !self.requested.replace(true) {
+            self.deferred_call.set();
+        }
+    }
+}
+
+/// "expand 32-byte k", the first four words of every ChaCha state.
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// Words of a ChaCha key, and of the seed a `ChaChaRng` takes from its
+/// source.
+const CHACHA_KEY_WORDS: usize = 8;
+
+/// Random words handed to the client of a `ChaChaRng` in each callback.
+const CHACHA_RNG_WORDS: usize = 16;
+
+/// Default number of outputs of a `ChaChaRng` between two automatic
+/// reseeds.
+pub const CHACHA_RESEED_INTERVAL: usize = 1 << 16;
+
+fn chacha_quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function of RFC 8439, section 2.3, returning the
+/// sixteen words of the block for `key`, `counter` and `nonce`.
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        chacha_quarter_round(&mut state, 0, 4, 8, 12);
+        chacha_quarter_round(&mut state, 1, 5, 9, 13);
+        chacha_quarter_round(&mut state, 2, 6, 10, 14);
+        chacha_quarter_round(&mut state, 3, 7, 11, 15);
+        chacha_quarter_round(&mut state, 0, 5, 10, 15);
+        chacha_quarter_round(&mut state, 1, 6, 11, 12);
+        chacha_quarter_round(&mut state, 2, 7, 8, 13);
+        chacha_quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*input);
+    }
+    state
+}
+
+/// A deterministic random bit generator built on the ChaCha20 block
+/// function and seeded from an `Entropy32`, for kernel capsules that need
+/// many random numbers without waiting on the hardware source for each of
+/// them.
+///
+/// Each block is computed under the current key, with a zero counter and
+/// nonce. Its first eight words replace the key and the other eight are
+/// output, so the numbers already output cannot be recovered from the state
+/// of the generator.
+///
+/// A seed is eight words of the source, mixed into the key. `refresh()`
+/// requests one, and the generator requests one by itself every
+/// `reseed_interval` outputs; the previous key stays in use until the seed
+/// arrives. A failed request is kept for `seed_error()`. `Random::reseed()`
+/// replaces the key with a fixed one derived from its argument instead, for
+/// deterministic tests.
+///
+/// As an `Rng`, requests wait for the first seed, then are answered from a
+/// deferred call with up to `CHACHA_RNG_WORDS` words each time. A source
+/// error before the first seed ends the request with that error. It can
+/// back an `RngDriver`, which then spares the source.
+pub struct ChaChaRng<'a, E: Entropy32<'a>> {
+    egen: &'a E,
+    client: OptionalCell<&'a dyn rng::Client>,
+    key: Cell<[u32; CHACHA_KEY_WORDS]>,
+    /// Output words of the current block.
+    block: Cell<[u32; CHACHA_KEY_WORDS]>,
+    /// Words of `block` already output.
+    used: Cell<usize>,
+    /// Seed words received from `egen` so far.
+    seed: Cell<[u32; CHACHA_KEY_WORDS]>,
+    seed_words: Cell<usize>,
+    /// Whether a seed was received from `egen` or set with `reseed()`.
+    seeded: Cell<bool>,
+    /// Whether a seed was requested from `egen` and not received yet.
+    refreshing: Cell<bool>,
+    /// Error of the last failed seed request, if no seed came since.
+    seed_error: OptionalCell<ErrorCode>,
+    reseed_interval: usize,
+    /// Number of words output since the last reseed.
+    outputs_since_reseed: Cell<usize>,
+    /// Whether the client asked for randomness.
+    requested: Cell<bool>,
+    deferred_call: DeferredCall,
+}
+
+impl<'a, E: Entropy32<'a>> ChaChaRng<'a, E> {
+    /// The generator reseeds itself every `reseed_interval` outputs, at
+    /// least one.
+    pub fn new(egen: &'a E, reseed_interval: usize) -> Self {
+        Self {
+            egen: egen,
+            client: OptionalCell::empty(),
+            key: Cell::new([0; CHACHA_KEY_WORDS]),
+            block: Cell::new([0; CHACHA_KEY_WORDS]),
+            used: Cell::new(CHACHA_KEY_WORDS),
+            seed: Cell::new([0; CHACHA_KEY_WORDS]),
+            seed_words: Cell::new(0),
+            seeded: Cell::new(false),
+            refreshing: Cell::new(false),
+            seed_error: OptionalCell::empty(),
+            reseed_interval: reseed_interval.max(1),
+            outputs_since_reseed: Cell::new(0),
+            requested: Cell::new(false),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+
+    /// Request a new seed from the `Entropy32`. Returns `ALREADY` if a
+    /// request is outstanding, or the error of the `Entropy32`.
+    pub fn refresh(&self) -> Result<(), ErrorCode> {
+        if self.refreshing.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.egen.get().map_err(|error| {
+            self.seed_error.set(error);
+            error
+        })?;
+        self.refreshing.set(true);
+        Ok(())
+    }
+
+    /// Whether the generator has been seeded. Until then, its output only
+    /// depends on the number of words output, and `seed_error()` tells why
+    /// the seed did not come if its request failed.
+    pub fn is_seeded(&self) -> bool {
+        self.seeded.get()
+    }
+
+    /// The error of the last seed request that failed, either when it was
+    /// made or in the callback of the `Entropy32`, or `None` if a seed was
+    /// set since.
+    pub fn seed_error(&self) -> Option<ErrorCode> {
+        self.seed_error.get()
+    }
+
+    /// Number of words output since the last reseed.
+    pub fn outputs_since_reseed(&self) -> usize {
+        self.outputs_since_reseed.get()
+    }
+
+    fn set_key(&self, key: [u32; CHACHA_KEY_WORDS]) {
+        self.key.set(key);
+        // The words left in the block came from the previous key.
+        self.used.set(CHACHA_KEY_WORDS);
+        self.seeded.set(true);
+        self.seed_error.clear();
+        self.outputs_since_reseed.set(0);
+    }
+
+    fn next_word(&self) -> u32 {
+        if self.used.get() == CHACHA_KEY_WORDS {
+            let block = chacha20_block(&self.key.get(), 0, &[0; 3]);
+            let mut key = [0; CHACHA_KEY_WORDS];
+            let mut output = [0; CHACHA_KEY_WORDS];
+            key.copy_from_slice(&block[..CHACHA_KEY_WORDS]);
+            output.copy_from_slice(&block[CHACHA_KEY_WORDS..]);
+            self.key.set(key);
+            self.block.set(output);
+            self.used.set(0);
+        }
+        let word = self.block.get()[self.used.get()];
+        self.used.set(self.used.get() + 1);
+
+        let outputs = self.outputs_since_reseed.get().saturating_add(1);
+        self.outputs_since_reseed.set(outputs);
+        if outputs >= self.reseed_interval {
+            // Keep going with the current key if the source cannot answer,
+            // the next output asks again.
+            let _ = self.refresh();
+        }
+        word
+    }
+}
+
+impl<'a, E: Entropy32<'a>> Random<'a> for ChaChaRng<'a, E> {
+    fn initialize(&'a self) {
+        self.egen.set_client(self);
+        // `initialize()` cannot return the error, `refresh()` kept it for
+        // `seed_error()`. The first `get()` asks again.
+        let _ = self.refresh();
+    }
+
+    fn reseed(&self, seed: u32) {
+        let mut key = [0; CHACHA_KEY_WORDS];
+        key[0] = seed;
+        self.set_key(key);
+    }
+
+    fn random(&self) -> u32 {
+        self.next_word()
+    }
+}
+
+impl<'a, E: Entropy32<'a>> Rng<'a> for ChaChaRng<'a, E> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        if !self.seeded.get() {
+            match self.refresh() {
+                Ok(()) | Err(ErrorCode::ALREADY) => {}
+                Err(error) => return Err(error),
+            }
+            self.requested.set(true);
+        } else if !self.requested.replace(true) {
+            self.deferred_call.set();
+        }
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        // The deferred call finds nothing to do.
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.egen.set_client(self);
+        self.client.set(client);
+    }
+}
+
+impl<'a, E: Entropy32<'a>> entropy::Client32 for ChaChaRng<'a, E> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        if let Err(seed_error) = error {
+            self.refreshing.set(false);
+            self.seed_error.set(seed_error);
+            self.seed_words.set(0);
+            if !self.seeded.get() && self.requested.replace(false) {
+                self.client
+                    .map(|client| client.randomness_available(&mut core::iter::empty(), error));
+            }
+            return entropy::Continue::Done;
+        }
+
+        let mut seed = self.seed.get();
+        let mut count = self.seed_words.get();
+        while count < CHACHA_KEY_WORDS {
+            match entropy.next() {
+                Some(word) => seed[count] = word,
+                None => break,
+            }
+            count += 1;
+        }
+        if count < CHACHA_KEY_WORDS {
+            self.seed.set(seed);
+            self.seed_words.set(count);
+            return entropy::Continue::More;
+        }
+
+        let mut key = self.key.get();
+        for (word, seed) in key.iter_mut().zip(seed.iter()) {
+            *word ^= *seed;
+        }
+        self.set_key(key);
+        self.seed.set([0; CHACHA_KEY_WORDS]);
+        self.seed_words.set(0);
+        self.refreshing.set(false);
+        if self.requested.get() {
+            self.deferred_call.set();
+        }
+        entropy::Continue::Done
+    }
+}
+
+impl<'a, E: Entropy32<'a>> DeferredCallClient for ChaChaRng<'a, E> {
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    fn handle_deferred_call(&self) {
+        if !self.seeded.get() || !self.requested.replace(false) {
+            return;
+        }
+        let mut words = (0..CHACHA_RNG_WORDS).map(|_| self.next_word());
+        let more = self.client.map_or(false, |client| {
+            client.randomness_available(&mut words, Ok(())) == rng::Continue::More
+        });
+        // A `get()` from the callback already set the deferred call again.
+        if more && !self.requested.replace(true) {
+            self.deferred_call.set();
+        }
+    }
+}
+
+/// Client of an `RngBufferFillUser`.
+pub trait RngBufferFillClient {
+    /// The random bytes asked for with `fill()` are in `buffer`, if `result`
+    /// is `Ok(())`. Otherwise it holds the error of the `Rng`.
+    fn fill_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+}
+
+/// Fills the buffers of kernel capsules with random bytes from an `Rng`.
+///
+/// Capsules share it through one `RngBufferFillUser` each. Buffers are
+/// filled one after the other, in turn between the users, and each with the
+/// random words of the `Rng` least significant byte first; the bytes of the
+/// last word beyond the length asked for are dropped. An error of the `Rng`
+/// ends the fill in progress, and the next one starts over.
+pub struct RngBufferFill<'a, R: Rng<'a>> {
+    rng: &'a R,
+    users: List<'a, RngBufferFillUser<'a, R>>,
+    /// The user whose buffer is being filled.
+    inflight: OptionalCell<&'a RngBufferFillUser<'a, R>>,
+    /// Whether randomness is being handed out. The `Rng` is kept going for
+    /// the buffers waiting then, rather than asked again.
+    delivering: Cell<bool>,
+}
+
+impl<'a, R: Rng<'a>> RngBufferFill<'a, R> {
+    pub fn new(rng: &'a R) -> Self {
+        Self {
+            rng: rng,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+            delivering: Cell::new(false),
+        }
+    }
+
+    /// Starts filling the buffer of `user`, unless another one is being
+    /// filled, in which case the buffer of `user` waits for its turn.
+    fn start(&self, user: &'a RngBufferFillUser<'a, R>) -> Result<(), ErrorCode> {
+        if self.inflight.is_some() || self.delivering.get() {
+            return Ok(());
+        }
+        self.rng.get()?;
+        self.inflight.set(user);
+        Ok(())
+    }
+
+    /// The next user with a buffer waiting, starting after `previous`.
+    fn next_waiting(
+        &self,
+        previous: &'a RngBufferFillUser<'a, R>,
+    ) -> Option<&'a RngBufferFillUser<'a, R>> {
+        self.users
+            .iter()
+            .skip_while(|user| !core::ptr::eq(*user, previous))
+            .skip(1)
+            .find(|user| user.is_waiting())
+            .or_else(|| self.users.iter().find(|user| user.is_waiting()))
+    }
+}
+
+impl<'a, R: Rng<'a>> Client for RngBufferFill<'a, R> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> Continue {
+        self.delivering.set(true);
+        let next = loop {
+            let Some(user) = self.inflight.get() else {
+                break Continue::Done;
+            };
+            if error.is_ok() && !user.take_randomness(randomness) {
+                break Continue::More;
+            }
+            self.inflight.clear();
+            // Fills queued by the client of `user` wait for the others.
+            user.done(error);
+            self.inflight.insert(self.next_waiting(user));
+            if error.is_err() {
+                // The words left, if any, are not used after an error.
+                break if self.inflight.is_some() {
+                    Continue::More
+                } else {
+                    Continue::Done
+                };
+            }
+        };
+        self.delivering.set(false);
+        next
+    }
+}
+
+/// The buffer filling of one kernel capsule through an `RngBufferFill`.
+pub struct RngBufferFillUser<'a, R: Rng<'a>> {
+    fill: &'a RngBufferFill<'a, R>,
+    next: ListLink<'a, RngBufferFillUser<'a, R>>,
+    client: OptionalCell<&'a dyn RngBufferFillClient>,
+    buffer: TakeCell<'static, [u8]>,
+    /// Number of bytes of `buffer` to fill.
+    length: Cell<usize>,
+    /// Number of bytes of `buffer` filled so far.
+    filled: Cell<usize>,
+}
+
+impl<'a, R: Rng<'a>> ListNode<'a, RngBufferFillUser<'a, R>> for RngBufferFillUser<'a, R> {
+    fn next(&'a self) -> &'a ListLink<'a, RngBufferFillUser<'a, R>> {
+        &self.next
+    }
+}
+
+impl<'a, R: Rng<'a>> RngBufferFillUser<'a, R> {
+    pub fn new(fill: &'a RngBufferFill<'a, R>) -> Self {
+        Self {
+            fill: fill,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            filled: Cell::new(0),
+        }
+    }
+
+    /// Adds this user to its `RngBufferFill`.
+    pub fn setup(&'a self) {
+        self.fill.users.push_tail(self);
+    }
+
+    pub fn set_client(&self, client: &'a dyn RngBufferFillClient) {
+        self.client.set(client);
+    }
+
+    /// Fills the first `length` bytes of `buffer` with random bytes and
+    /// passes it back to the client with `fill_done()`. Returns `BUSY` while
+    /// a buffer of this user is being filled, `INVAL` if `length` is zero or
+    /// longer than `buffer`, or the error of the `Rng`.
+    pub fn fill(
+        &'a self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if length == 0 || length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        self.length.set(length);
+        self.filled.set(0);
+        if let Err(error) = self.fill.start(self) {
+            return Err((error, buffer));
+        }
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Copies words from `randomness` into the buffer, and tells whether it
+    /// is full.
+    fn take_randomness(&self, randomness: &mut dyn Iterator<Item = u32>) -> bool {
+        let length = self.length.get();
+        self.buffer.map(|buffer| {
+            let mut filled = self.filled.get();
+            while filled < length {
+                let Some(word) = randomness.next() else {
+                    break;
+                };
+                let count = (length - filled).min(4);
+                buffer[filled..filled + count].copy_from_slice(&word.to_le_bytes()[..count]);
+                filled += count;
+            }
+            self.filled.set(filled);
+        });
+        self.filled.get() >= length
+    }
+
+    fn done(&self, result: Result<(), ErrorCode>) {
+        self.buffer.take().map(|buffer| {
+            self.client.map(|client| client.fill_done(buffer, result));
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chacha20_block, chacha_quarter_round};
+
+    /// Serializes a block as RFC 8439 prints it.
+    fn bytes(block: &[u32; 16]) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        for (chunk, word) in bytes.chunks_mut(4).zip(block.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// RFC 8439, section 2.1.1.
+    #[test]
+    fn quarter_round() {
+        let mut state = [0; 16];
+        state[..4].copy_from_slice(&[0x11111111, 0x01020304, 0x9b8d6f43, 0x01234567]);
+        chacha_quarter_round(&mut state, 0, 1, 2, 3);
+        assert_eq!(state[..4], [0xea2a92f4, 0xcb1cf8ce, 0x4581472e, 0x5881c4bb]);
+    }
+
+    /// RFC 8439, section 2.3.2.
+    #[test]
+    fn block_function() {
+        let mut key = [0; 8];
+        for (i, word) in key.iter_mut().enumerate() {
+            let i = 4 * i as u8;
+            *word = u32::from_le_bytes([i, i + 1, i + 2, i + 3]);
+        }
+        let nonce = [0x09000000, 0x4a000000, 0x00000000];
+        assert_eq!(
+            chacha20_block(&key, 1, &nonce),
+            [
+                0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3, 0xc7f4d1c7, 0x0368c033, 0x9aaa2204,
+                0x4e6cd4c3, 0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9, 0xd19c12b5, 0xb94e16de,
+                0xe883d0cb, 0x4e3c50a2,
+            ]
+        );
+    }
+
+    /// RFC 8439, appendix A.1, test vectors #1 and #2.
+    #[test]
+    fn block_function_keystream() {
+        let block = chacha20_block(&[0; 8], 0, &[0; 3]);
+        assert_eq!(
+            bytes(&block),
+            [
+                0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86,
+                0xbd, 0x28, 0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a, 0xa8, 0x36, 0xef, 0xcc,
+                0x8b, 0x77, 0x0d, 0xc7, 0xda, 0x41, 0x59, 0x7c, 0x51, 0x57, 0x48, 0x8d, 0x77, 0x24,
+                0xe0, 0x3f, 0xb8, 0xd8, 0x4a, 0x37, 0x6a, 0x43, 0xb8, 0xf4, 0x15, 0x18, 0xa1, 0x1c,
+                0xc3, 0x87, 0xb6, 0x69, 0xb2, 0xee, 0x65, 0x86,
+            ]
+        );
+        let block = chacha20_block(&[0; 8], 1, &[0; 3]);
+        assert_eq!(
+            bytes(&block)[..16],
+            [
+                0x9f, 0x07, 0xe7, 0xbe, 0x55, 0x51, 0x38, 0x7a, 0x98, 0xba, 0x97, 0x7c, 0x73, 0x2d,
+                0x08, 0x0d,
+            ]
+        );
+    }
+}
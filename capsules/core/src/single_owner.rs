@@ -0,0 +1,192 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Scaffolding for system call drivers used by one process at a time.
+//!
+//! Many sensor drivers are not virtualized: the first process to issue a
+//! command owns the driver until it exits, and the driver sends the results
+//! of its operations to that process. `SingleOwner` holds the grant and the
+//! owner of such a driver, and implements the parts every one of them
+//! needs:
+//!
+//! - command `0` succeeds for every process,
+//! - any other command is refused to processes other than the owner while
+//!   the owner exists, with `SingleOwnerCommands::NOT_OWNER`,
+//! - the first command of a process makes it the owner if there is none, or
+//!   if the previous owner no longer exists and its operation is done,
+//! - `allocate_grant()`,
+//! - scheduling upcalls to the owner.
+//!
+//! Usage
+//! -----
+//!
+//! The driver keeps a `SingleOwner` instead of a `Grant`, implements
+//! `SingleOwnerCommands` for its commands, and forwards `SyscallDriver` to
+//! the `SingleOwner`:
+//!
+//! ```rust,ignore
+//! use capsules_core::single_owner::{SingleOwner, SingleOwnerCommands};
+//!
+//! pub struct Sensor<'a> {
+//!     owner: SingleOwner<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+//!     // ...
+//! }
+//!
+//! impl SingleOwnerCommands for Sensor<'_> {
+//!     fn owner_command(
+//!         &self,
+//!         command_num: usize,
+//!         data1: usize,
+//!         data2: usize,
+//!         process_id: ProcessId,
+//!     ) -> CommandReturn {
+//!         match command_num {
+//!             1 => self.start_reading().into(),
+//!             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+//!         }
+//!     }
+//! }
+//!
+//! impl SyscallDriver for Sensor<'_> {
+//!     fn command(
+//!         &self,
+//!         command_num: usize,
+//!         data1: usize,
+//!         data2: usize,
+//!         process_id: ProcessId,
+//!     ) -> CommandReturn {
+//!         self.owner.command(self, command_num, data1, data2, process_id)
+//!     }
+//!
+//!     fn allocate_grant(&self, process_id: ProcessId) -> Result<(), kernel::process::Error> {
+//!         self.owner.allocate_grant(process_id)
+//!     }
+//! }
+//! ```
+//!
+//! Once the operation is done, the driver calls `schedule_upcall()`, or
+//! `enter_owner()` if it also needs the buffers allowed by the owner.
+
+use kernel::grant::{AllowRoSize, AllowRwSize, Grant, GrantKernelData, UpcallSize};
+use kernel::syscall::CommandReturn;
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+/// The commands of a driver used through a `SingleOwner`.
+pub trait SingleOwnerCommands {
+    /// Error returned to a process that is not the owner.
+    const NOT_OWNER: ErrorCode = ErrorCode::RESERVE;
+
+    /// Run command `command_num`, never `0`, for `process_id`, which owns
+    /// the driver.
+    fn owner_command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn;
+
+    /// Whether an operation is in progress. The result of an operation goes
+    /// to the owner, so a process does not take over from an owner that no
+    /// longer exists, such as the previous instance of a restarted process,
+    /// until it is done.
+    fn is_busy(&self) -> bool {
+        false
+    }
+}
+
+/// The grant of a driver and the process that owns it.
+pub struct SingleOwner<
+    T: Default,
+    Upcalls: UpcallSize,
+    AllowROs: AllowRoSize,
+    AllowRWs: AllowRwSize,
+> {
+    grant: Grant<T, Upcalls, AllowROs, AllowRWs>,
+    owner: OptionalCell<ProcessId>,
+}
+
+impl<T: Default, Upcalls: UpcallSize, AllowROs: AllowRoSize, AllowRWs: AllowRwSize>
+    SingleOwner<T, Upcalls, AllowROs, AllowRWs>
+{
+    pub fn new(grant: Grant<T, Upcalls, AllowROs, AllowRWs>) -> Self {
+        Self {
+            grant: grant,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// The grant, for operations on processes other than the owner.
+    pub fn grant(&self) -> &Grant<T, Upcalls, AllowROs, AllowRWs> {
+        &self.grant
+    }
+
+    /// The process owning the driver, which may no longer exist.
+    pub fn owner(&self) -> Option<ProcessId> {
+        self.owner.get()
+    }
+
+    /// Make `process_id` the owner, unless another process that still exists
+    /// owns the driver. An owner that no longer exists keeps the driver while
+    /// it is `busy` with its operation.
+    pub fn claim(&self, process_id: ProcessId, busy: bool) -> bool {
+        let match_or_empty_or_nonexistent = self.owner.map_or(true, |owner| {
+            self.grant
+                .enter(owner, |_, _| owner == process_id)
+                .unwrap_or(!busy)
+        });
+        if match_or_empty_or_nonexistent {
+            self.owner.set(process_id);
+        }
+        match_or_empty_or_nonexistent
+    }
+
+    /// Handle a command: `0` succeeds, others go to `driver` if `process_id`
+    /// is or can become the owner.
+    pub fn command<D: SingleOwnerCommands>(
+        &self,
+        driver: &D,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            // Handle this first as it should be returned
+            // unconditionally
+            return CommandReturn::success();
+        }
+
+        if self.claim(process_id, driver.is_busy()) {
+            driver.owner_command(command_num, data1, data2, process_id)
+        } else {
+            CommandReturn::failure(D::NOT_OWNER)
+        }
+    }
+
+    pub fn allocate_grant(&self, process_id: ProcessId) -> Result<(), kernel::process::Error> {
+        self.grant.enter(process_id, |_, _| {})
+    }
+
+    /// Run `f` with the grant of the owner. Returns `None` if there is no
+    /// owner or it no longer exists.
+    pub fn enter_owner<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut T, &GrantKernelData) -> R,
+    {
+        self.owner.get().and_then(|owner| {
+            self.grant
+                .enter(owner, |app, kernel_data| f(app, kernel_data))
+                .ok()
+        })
+    }
+
+    /// Schedule upcall `upcall_num` of the owner, if there is one.
+    pub fn schedule_upcall(&self, upcall_num: usize, args: (usize, usize, usize)) {
+        self.enter_owner(|_, kernel_data| {
+            kernel_data.schedule_upcall(upcall_num, args).ok();
+        });
+    }
+}
@@ -0,0 +1,345 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Tells apps when a slowly changing analog input crosses a threshold,
+//! without them polling the ADC.
+//!
+//! Each app configures a channel, a sampling period and a low and a high
+//! threshold. Once started, the channel is sampled every period, and the app
+//! gets an upcall only when the input crosses a threshold. The two
+//! thresholds form a hysteresis band: the input is above once a sample
+//! reaches the high threshold, and only below again once a sample falls to
+//! the low threshold, so noise around one threshold does not produce a burst
+//! of upcalls. The first sample after starting sets the initial side, above
+//! if it reaches the high threshold and below otherwise, without an upcall.
+//!
+//! The channels are shared between the apps: samples due at the same time
+//! are taken one after the other, like the samples of `AdcVirtualized`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let adc_threshold = static_init!(
+//!     AdcThreshold<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     AdcThreshold::new(
+//!         adc_channels,
+//!         virtual_alarm,
+//!         board_kernel.create_grant(capsules_extra::adc_threshold::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! virtual_alarm.set_alarm_client(adc_threshold);
+//! for channel in adc_channels {
+//!     channel.set_client(adc_threshold);
+//! }
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: The input crossed a threshold.
+//!   - `data1`: `CROSSED_HIGH` or `CROSSED_LOW`.
+//!   - `data2`: the channel.
+//!   - `data3`: the sample which crossed the threshold.
+//!
+//! ### Command
+//!
+//! - `0`: Driver existence check, returns the number of channels.
+//! - `1`: Configure the app, while it is stopped.
+//!   - `data1`: the channel in bits 0-7, the period in milliseconds above,
+//!     see [`pack_channel_period`].
+//!   - `data2`: the low threshold in bits 0-15, the high threshold in bits
+//!     16-31, see [`pack_thresholds`]. The low threshold must be below the
+//!     high one.
+//! - `2`: Start sampling, `INVAL` if the app is not configured.
+//! - `3`: Stop sampling.
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil;
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::AdcThreshold as usize;
+
+/// Upcall argument of a sample reaching the high threshold.
+pub const CROSSED_HIGH: usize = 1;
+/// Upcall argument of a sample falling to the low threshold.
+pub const CROSSED_LOW: usize = 0;
+
+/// Longest sampling period, so that it fits with the channel in a word.
+pub const MAX_PERIOD_MS: u32 = 0xFF_FFFF;
+
+/// Bits of a packed word holding the channel.
+const PACKED_CHANNEL_BITS: usize = 8;
+
+/// Pack `channel` and `period_ms` into the first argument of command `1`.
+pub fn pack_channel_period(channel: usize, period_ms: u32) -> usize {
+    ((period_ms as usize) << PACKED_CHANNEL_BITS) | (channel & ((1 << PACKED_CHANNEL_BITS) - 1))
+}
+
+/// Pack the thresholds into the second argument of command `1`.
+pub fn pack_thresholds(low: u16, high: u16) -> usize {
+    ((high as usize) << 16) | low as usize
+}
+
+/// Which side of the hysteresis band the input is on.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Side {
+    /// No sample yet since the app started.
+    #[default]
+    Unknown,
+    Below,
+    Above,
+}
+
+#[derive(Clone, Copy)]
+struct Config {
+    channel: usize,
+    period_ms: u32,
+    low: u16,
+    high: u16,
+}
+
+#[derive(Default)]
+pub struct App {
+    config: Option<Config>,
+    running: bool,
+    /// Time the current period started at.
+    reference: u32,
+    /// A sample is due and waits for the ADC.
+    sample_pending: bool,
+    side: Side,
+}
+
+pub struct AdcThreshold<'a, A: Alarm<'a>> {
+    channels: &'a [&'a dyn hil::adc::AdcChannel<'a>],
+    alarm: &'a A,
+    apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// The app whose sample is in progress.
+    current_process: OptionalCell<ProcessId>,
+}
+
+impl<'a, A: Alarm<'a>> AdcThreshold<'a, A> {
+    pub fn new(
+        channels: &'a [&'a dyn hil::adc::AdcChannel<'a>],
+        alarm: &'a A,
+        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> AdcThreshold<'a, A> {
+        AdcThreshold {
+            channels,
+            alarm,
+            apps: grant,
+            current_process: OptionalCell::empty(),
+        }
+    }
+
+    fn configure(&self, processid: ProcessId, config: Config) -> Result<(), ErrorCode> {
+        if config.channel >= self.channels.len() {
+            return Err(ErrorCode::NODEVICE);
+        }
+        if config.period_ms == 0 || config.low >= config.high {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(processid, |app, _| {
+            if app.running {
+                return Err(ErrorCode::BUSY);
+            }
+            app.config = Some(config);
+            Ok(())
+        })?
+    }
+
+    fn start(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        self.apps.enter(processid, |app, _| {
+            if app.config.is_none() {
+                return Err(ErrorCode::INVAL);
+            }
+            if app.running {
+                return Err(ErrorCode::ALREADY);
+            }
+            // The first sample is taken right away.
+            app.running = true;
+            app.side = Side::Unknown;
+            app.reference = self.alarm.now().into_u32();
+            app.sample_pending = true;
+            Ok(())
+        })??;
+        self.sample_next();
+        self.rearm();
+        Ok(())
+    }
+
+    fn stop(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        self.apps.enter(processid, |app, _| {
+            if !app.running {
+                return Err(ErrorCode::ALREADY);
+            }
+            // A sample in progress is dropped when it arrives.
+            app.running = false;
+            app.sample_pending = false;
+            Ok(())
+        })??;
+        self.rearm();
+        Ok(())
+    }
+
+    /// Start the next sample due, unless one is in progress.
+    fn sample_next(&self) {
+        if self.current_process.is_some() {
+            return;
+        }
+        for app in self.apps.iter() {
+            let processid = app.processid();
+            let channel = app.enter(|app, _| {
+                if !app.sample_pending {
+                    return None;
+                }
+                app.sample_pending = false;
+                app.config.map(|config| config.channel)
+            });
+            if let Some(channel) = channel {
+                self.current_process.set(processid);
+                if self.channels[channel].sample().is_ok() {
+                    break;
+                }
+                // The sample of this period is skipped.
+                self.current_process.clear();
+            }
+        }
+    }
+
+    /// Arm the alarm for the end of the earliest period, or disarm it if no
+    /// app is sampling.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let mut earliest: Option<A::Ticks> = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let (true, Some(config)) = (app.running, app.config) {
+                    let period = self.alarm.ticks_from_ms(config.period_ms);
+                    let elapsed = now.wrapping_sub(A::Ticks::from(app.reference));
+                    let remaining = if elapsed < period {
+                        period.wrapping_sub(elapsed)
+                    } else {
+                        A::Ticks::from(0)
+                    };
+                    earliest = Some(earliest.map_or(remaining, |earliest| earliest.min(remaining)));
+                }
+            });
+        }
+        match earliest {
+            Some(dt) => self.alarm.set_alarm(now, dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for AdcThreshold<'a, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let (true, Some(config)) = (app.running, app.config) {
+                    let period = self.alarm.ticks_from_ms(config.period_ms);
+                    let elapsed = now.wrapping_sub(A::Ticks::from(app.reference));
+                    if elapsed >= period {
+                        // The next period starts now, a sample still waiting
+                        // for the ADC is not taken twice.
+                        app.reference = now.into_u32();
+                        app.sample_pending = true;
+                    }
+                }
+            });
+        }
+        self.sample_next();
+        self.rearm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> hil::adc::Client for AdcThreshold<'a, A> {
+    fn sample_ready(&self, sample: u16) {
+        self.current_process.take().map(|processid| {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                let Some(config) = app.config.filter(|_| app.running) else {
+                    return;
+                };
+                let side = if sample >= config.high {
+                    Side::Above
+                } else if sample <= config.low {
+                    Side::Below
+                } else {
+                    app.side
+                };
+                let crossing = match (app.side, side) {
+                    (Side::Unknown, _) => None,
+                    (Side::Below, Side::Above) => Some(CROSSED_HIGH),
+                    (Side::Above, Side::Below) => Some(CROSSED_LOW),
+                    _ => None,
+                };
+                // Inside the band, the first sample counts as below.
+                app.side = match side {
+                    Side::Unknown => Side::Below,
+                    side => side,
+                };
+                if let Some(crossing) = crossing {
+                    kernel_data
+                        .schedule_upcall(0, (crossing, config.channel, sample as usize))
+                        .ok();
+                }
+            });
+        });
+        self.sample_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> SyscallDriver for AdcThreshold<'a, A> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success_u32(self.channels.len() as u32),
+
+            // Configure
+            1 => {
+                let Ok(period_ms) = u32::try_from(data1 >> PACKED_CHANNEL_BITS) else {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                };
+                if period_ms > MAX_PERIOD_MS || data2 > u32::MAX as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let config = Config {
+                    channel: data1 & ((1 << PACKED_CHANNEL_BITS) - 1),
+                    period_ms,
+                    low: data2 as u16,
+                    high: (data2 >> 16) as u16,
+                };
+                self.configure(processid, config).into()
+            }
+
+            // Start
+            2 => self.start(processid).into(),
+
+            // Stop
+            3 => self.stop(processid).into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
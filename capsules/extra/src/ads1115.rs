@@ -0,0 +1,491 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Driver for the TI ADS1115 16-bit I2C analog to digital converter.
+//!
+//! - <https://www.ti.com/product/ADS1115>
+//!
+//! The ADS1115 has a single converter behind an input multiplexer and a
+//! programmable gain amplifier (PGA). Each `Ads1115Channel` selects one input
+//! mux setting and one PGA setting and implements `hil::adc::AdcChannel`, so
+//! the channels can be passed to `capsules_core::adc::AdcVirtualized` like
+//! the channels of an MCU ADC. All channels share the chip through the
+//! `Ads1115` object, which runs one conversion at a time and queues the
+//! others. Queued channels are served in turn.
+//!
+//! Conversions are done in single-shot mode. The end of a conversion is
+//! detected with the ALERT/RDY pin if one is given to `Ads1115::new()`.
+//! Otherwise the driver polls the OS bit of the config register with the
+//! alarm.
+//!
+//! Samples are reported left-justified as required by the ADC HIL: the
+//! positive 15-bit range of the converter is shifted into a `u16` and
+//! negative differential readings are reported as 0. The reference voltage
+//! of a channel is the full-scale range of its PGA setting.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let buffer = static_init!([u8; capsules_extra::ads1115::BUF_LEN], [0; capsules_extra::ads1115::BUF_LEN]);
+//! let ads1115_i2c = static_init!(
+//!     capsules_core::virtualizers::virtual_i2c::I2CDevice,
+//!     capsules_core::virtualizers::virtual_i2c::I2CDevice::new(i2c_mux, 0x48));
+//! let ads1115 = static_init!(
+//!     capsules_extra::ads1115::Ads1115<'static, VirtualMuxAlarm<'static, Rtc>, I2CDevice>,
+//!     capsules_extra::ads1115::Ads1115::new(ads1115_i2c, alarm, None, buffer));
+//! ads1115_i2c.set_client(ads1115);
+//! alarm.set_alarm_client(ads1115);
+//!
+//! let ain0 = static_init!(
+//!     capsules_extra::ads1115::Ads1115Channel<'static, VirtualMuxAlarm<'static, Rtc>, I2CDevice>,
+//!     capsules_extra::ads1115::Ads1115Channel::new(ads1115,
+//!         capsules_extra::ads1115::InputMux::Ain0,
+//!         capsules_extra::ads1115::Gain::Fsr4096));
+//! ain0.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::hil::adc;
+use kernel::hil::gpio;
+use kernel::hil::i2c;
+use kernel::hil::time::{self, ConvertTicks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+pub const BUF_LEN: usize = 3;
+
+/// Time a conversion takes at the default data rate of 128 SPS.
+const CONVERSION_TIME_US: u32 = 7_900;
+/// How long to wait before polling again if a conversion is not done.
+const POLL_INTERVAL_US: u32 = 1_000;
+
+#[allow(dead_code)]
+enum Registers {
+    Conversion = 0x00,
+    Config = 0x01,
+    LowThreshold = 0x02,
+    HighThreshold = 0x03,
+}
+
+/// Config register fields.
+const CONFIG_OS: u16 = 1 << 15;
+const CONFIG_MODE_SINGLE_SHOT: u16 = 1 << 8;
+const CONFIG_DATA_RATE_128SPS: u16 = 0b100 << 5;
+/// Assert ALERT/RDY after one conversion.
+const CONFIG_COMP_QUEUE_ONE: u16 = 0b00;
+/// Disable the comparator and put ALERT/RDY in high impedance.
+const CONFIG_COMP_QUEUE_DISABLE: u16 = 0b11;
+
+/// Input multiplexer settings.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InputMux {
+    /// AIN0 - AIN1
+    Ain0Ain1 = 0b000,
+    /// AIN0 - AIN3
+    Ain0Ain3 = 0b001,
+    /// AIN1 - AIN3
+    Ain1Ain3 = 0b010,
+    /// AIN2 - AIN3
+    Ain2Ain3 = 0b011,
+    /// AIN0 - GND
+    Ain0 = 0b100,
+    /// AIN1 - GND
+    Ain1 = 0b101,
+    /// AIN2 - GND
+    Ain2 = 0b110,
+    /// AIN3 - GND
+    Ain3 = 0b111,
+}
+
+/// Full-scale range of the programmable gain amplifier.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gain {
+    Fsr6144 = 0b000,
+    Fsr4096 = 0b001,
+    Fsr2048 = 0b010,
+    Fsr1024 = 0b011,
+    Fsr512 = 0b100,
+    Fsr256 = 0b101,
+}
+
+impl Gain {
+    /// Full-scale range in millivolts.
+    fn full_scale_mv(&self) -> usize {
+        match self {
+            Gain::Fsr6144 => 6144,
+            Gain::Fsr4096 => 4096,
+            Gain::Fsr2048 => 2048,
+            Gain::Fsr1024 => 1024,
+            Gain::Fsr512 => 512,
+            Gain::Fsr256 => 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Setting up the thresholds so ALERT/RDY signals conversion ready.
+    SetHighThreshold,
+    SetLowThreshold,
+    StartConversion,
+    /// Waiting for ALERT/RDY or the alarm.
+    WaitConversion,
+    ReadStatus,
+    ReadConversion,
+}
+
+/// The ADS1115 chip shared by all of its channels.
+pub struct Ads1115<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> {
+    i2c: &'a I,
+    alarm: &'a A,
+    ready_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+    ready_pin_configured: Cell<bool>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    channels: List<'a, Ads1115Channel<'a, A, I>>,
+    inflight: OptionalCell<&'a Ads1115Channel<'a, A, I>>,
+    /// The channel sampled last, the search for the next one starts after
+    /// it.
+    last: OptionalCell<&'a Ads1115Channel<'a, A, I>>,
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> Ads1115<'a, A, I> {
+    pub fn new(
+        i2c: &'a I,
+        alarm: &'a A,
+        ready_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+        buffer: &'static mut [u8],
+    ) -> Ads1115<'a, A, I> {
+        Ads1115 {
+            i2c: i2c,
+            alarm: alarm,
+            ready_pin: ready_pin,
+            ready_pin_configured: Cell::new(false),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            channels: List::new(),
+            inflight: OptionalCell::empty(),
+            last: OptionalCell::empty(),
+        }
+    }
+
+    /// Start a conversion for the next channel waiting for a sample, if the
+    /// chip is not busy. Channels are served in turn, starting after the
+    /// channel sampled last, so one channel can not starve the others.
+    ///
+    /// A channel whose conversion can not be started has its request
+    /// dropped. Returns the error of the last such channel.
+    fn do_next_op(&self) -> Result<(), ErrorCode> {
+        let mut result = Ok(());
+        while self.inflight.is_none() {
+            match self.next_pending() {
+                Some(channel) => {
+                    if let Err(error) = self.start(channel) {
+                        channel.pending.set(false);
+                        result = Err(error);
+                    }
+                }
+                None => break,
+            }
+        }
+        result
+    }
+
+    /// The first channel waiting for a sample after the channel sampled
+    /// last, wrapping around the list of channels.
+    fn next_pending(&self) -> Option<&'a Ads1115Channel<'a, A, I>> {
+        self.last
+            .get()
+            .and_then(|last| {
+                self.channels
+                    .iter()
+                    .skip_while(|channel| !core::ptr::eq(*channel, last))
+                    .skip(1)
+                    .find(|channel| channel.pending.get())
+            })
+            .or_else(|| self.channels.iter().find(|channel| channel.pending.get()))
+    }
+
+    fn start(&self, channel: &'a Ads1115Channel<'a, A, I>) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        self.inflight.set(channel);
+        self.last.set(channel);
+        self.i2c.enable();
+
+        let result = match self.ready_pin {
+            Some(pin) if !self.ready_pin_configured.get() => {
+                pin.make_input();
+                pin.enable_interrupts(gpio::InterruptEdge::FallingEdge);
+
+                // A high threshold with the MSB set and a low threshold
+                // with the MSB cleared turn ALERT/RDY into a conversion
+                // ready signal.
+                self.state.set(State::SetHighThreshold);
+                self.write_register(buffer, Registers::HighThreshold, 0x8000)
+            }
+            _ => {
+                self.state.set(State::StartConversion);
+                self.write_register(buffer, Registers::Config, channel.config())
+            }
+        };
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+
+    /// Write `value` to `register`. If the transfer can not be started the
+    /// buffer is kept and the error returned.
+    fn write_register(
+        &self,
+        buffer: &'static mut [u8],
+        register: Registers,
+        value: u16,
+    ) -> Result<(), ErrorCode> {
+        buffer[0] = register as u8;
+        buffer[1..3].copy_from_slice(&value.to_be_bytes());
+        self.i2c.write(buffer, 3).map_err(|(error, buffer)| {
+            self.buffer.replace(buffer);
+            error.into()
+        })
+    }
+
+    fn read_register(
+        &self,
+        buffer: &'static mut [u8],
+        register: Registers,
+    ) -> Result<(), ErrorCode> {
+        buffer[0] = register as u8;
+        self.i2c
+            .write_read(buffer, 1, 2)
+            .map_err(|(error, buffer)| {
+                self.buffer.replace(buffer);
+                error.into()
+            })
+    }
+
+    fn set_poll_alarm(&self, us: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_us(us));
+    }
+
+    /// Get back to idle, returning the channel that was being sampled.
+    fn stop(&self) -> Option<&'a Ads1115Channel<'a, A, I>> {
+        self.i2c.disable();
+        self.state.set(State::Idle);
+        self.inflight.take()
+    }
+
+    /// Stop the current conversion without a sample and move on to the next
+    /// channel. The ADC HIL has no way to report errors to the client.
+    fn abort(&self) {
+        self.stop().map(|channel| channel.pending.set(false));
+        let _ = self.do_next_op();
+    }
+
+    /// Abort the conversion if a transfer it needs could not be started.
+    fn check(&self, result: Result<(), ErrorCode>) {
+        if result.is_err() {
+            self.abort();
+        }
+    }
+
+    fn read_conversion(&self) {
+        self.buffer.take().map(|buffer| {
+            self.state.set(State::ReadConversion);
+            self.check(self.read_register(buffer, Registers::Conversion));
+        });
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> i2c::I2CClient for Ads1115<'a, A, I> {
+    fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
+        if status.is_err() {
+            self.buffer.replace(buffer);
+            self.abort();
+            return;
+        }
+
+        match self.state.get() {
+            State::SetHighThreshold => {
+                self.state.set(State::SetLowThreshold);
+                self.check(self.write_register(buffer, Registers::LowThreshold, 0x0000));
+            }
+            State::SetLowThreshold => {
+                self.ready_pin_configured.set(true);
+                match self.inflight.get() {
+                    Some(channel) => {
+                        self.state.set(State::StartConversion);
+                        self.check(self.write_register(
+                            buffer,
+                            Registers::Config,
+                            channel.config(),
+                        ));
+                    }
+                    None => {
+                        self.buffer.replace(buffer);
+                        self.abort();
+                    }
+                }
+            }
+            State::StartConversion => {
+                self.buffer.replace(buffer);
+                self.state.set(State::WaitConversion);
+                if self.ready_pin.is_none() {
+                    self.set_poll_alarm(CONVERSION_TIME_US);
+                }
+            }
+            State::ReadStatus => {
+                if u16::from_be_bytes([buffer[0], buffer[1]]) & CONFIG_OS == 0 {
+                    // Still converting.
+                    self.buffer.replace(buffer);
+                    self.state.set(State::WaitConversion);
+                    self.set_poll_alarm(POLL_INTERVAL_US);
+                } else {
+                    self.state.set(State::ReadConversion);
+                    self.check(self.read_register(buffer, Registers::Conversion));
+                }
+            }
+            State::ReadConversion => {
+                let raw = i16::from_be_bytes([buffer[0], buffer[1]]);
+                // Left-justify the positive range, negative readings are
+                // reported as 0.
+                let sample = (raw.max(0) as u16) << 1;
+
+                self.buffer.replace(buffer);
+                self.stop().map(|channel| {
+                    if channel.pending.replace(false) {
+                        channel.client.map(|client| client.sample_ready(sample));
+                    }
+                });
+                let _ = self.do_next_op();
+            }
+            State::Idle | State::WaitConversion => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> time::AlarmClient for Ads1115<'a, A, I> {
+    fn alarm(&self) {
+        if self.state.get() == State::WaitConversion {
+            self.buffer.take().map(|buffer| {
+                self.state.set(State::ReadStatus);
+                self.check(self.read_register(buffer, Registers::Config));
+            });
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> gpio::Client for Ads1115<'a, A, I> {
+    fn fired(&self) {
+        if self.state.get() == State::WaitConversion {
+            self.read_conversion();
+        }
+    }
+}
+
+/// One input mux and PGA setting of an ADS1115.
+pub struct Ads1115Channel<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> {
+    ads1115: &'a Ads1115<'a, A, I>,
+    mux: InputMux,
+    gain: Gain,
+    /// Whether a sample was requested and not delivered yet.
+    pending: Cell<bool>,
+    next: ListLink<'a, Ads1115Channel<'a, A, I>>,
+    client: OptionalCell<&'a dyn adc::Client>,
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> Ads1115Channel<'a, A, I> {
+    pub fn new(
+        ads1115: &'a Ads1115<'a, A, I>,
+        mux: InputMux,
+        gain: Gain,
+    ) -> Ads1115Channel<'a, A, I> {
+        Ads1115Channel {
+            ads1115: ads1115,
+            mux: mux,
+            gain: gain,
+            pending: Cell::new(false),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Register this channel with the chip. Must be called before sampling.
+    pub fn setup(&'a self) {
+        self.ads1115.channels.push_head(self);
+    }
+
+    /// Config register value starting a single-shot conversion on this
+    /// channel.
+    fn config(&self) -> u16 {
+        let comp_queue = if self.ads1115.ready_pin.is_some() {
+            CONFIG_COMP_QUEUE_ONE
+        } else {
+            CONFIG_COMP_QUEUE_DISABLE
+        };
+        CONFIG_OS
+            | ((self.mux as u16) << 12)
+            | ((self.gain as u16) << 9)
+            | CONFIG_MODE_SINGLE_SHOT
+            | CONFIG_DATA_RATE_128SPS
+            | comp_queue
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> ListNode<'a, Ads1115Channel<'a, A, I>>
+    for Ads1115Channel<'a, A, I>
+{
+    fn next(&'a self) -> &'a ListLink<'a, Ads1115Channel<'a, A, I>> {
+        &self.next
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> adc::AdcChannel<'a> for Ads1115Channel<'a, A, I> {
+    fn sample(&self) -> Result<(), ErrorCode> {
+        if self.pending.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.pending.set(true);
+        // When the chip is idle no other channel waits, so this channel's
+        // conversion starts now, and a failure to start it is returned.
+        let result = self.ads1115.do_next_op();
+        if self.pending.get() {
+            Ok(())
+        } else {
+            result
+        }
+    }
+
+    fn sample_continuous(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn stop_sampling(&self) -> Result<(), ErrorCode> {
+        // A conversion that already started finishes, but its sample is
+        // dropped.
+        self.pending.set(false);
+        Ok(())
+    }
+
+    fn get_resolution_bits(&self) -> usize {
+        16
+    }
+
+    fn get_voltage_reference_mv(&self) -> Option<usize> {
+        Some(self.gain.full_scale_mv())
+    }
+
+    fn set_client(&self, client: &'a dyn adc::Client) {
+        self.client.set(client);
+    }
+}
@@ -28,6 +28,7 @@
 
 use core::cmp;
 
+use kernel::errorcode::into_statuscode;
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil;
 use kernel::processbuffer::ReadableProcessBuffer;
@@ -148,16 +149,24 @@ impl<'a> AppFlash<'a> {
 }
 
 impl hil::nonvolatile_storage::NonvolatileStorageClient for AppFlash<'_> {
-    fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
+    fn read_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        _result: Result<(), ErrorCode>,
+    ) {
+    }
 
-    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize, result: Result<(), ErrorCode>) {
         // Put our write buffer back.
         self.buffer.replace(buffer);
 
         // Notify the current application that the command finished.
         self.current_app.take().map(|processid| {
             let _ = self.apps.enter(processid, |_app, upcalls| {
-                upcalls.schedule_upcall(upcall::WRITE_DONE, (0, 0, 0)).ok();
+                upcalls
+                    .schedule_upcall(upcall::WRITE_DONE, (into_statuscode(result), 0, 0))
+                    .ok();
             });
         });
 
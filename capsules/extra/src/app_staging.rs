@@ -0,0 +1,740 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Staging of app binaries for over-the-air updates.
+//!
+//! An updater process writes a new TBF app binary to a dedicated region of
+//! nonvolatile storage and commits it to one of the app slots of the board.
+//! On the next boot, before the processes are loaded, the kernel checks the
+//! staged image and copies it over the app flash slot.
+//!
+//! The staging region starts with a header, followed by the image:
+//!
+//! ```text
+//! +-------+--------+-------+------+----------------------------+
+//! | magic | length | crc32 | slot | image ...                  |
+//! +-------+--------+-------+------+----------------------------+
+//!     4       4        4      4
+//! ```
+//!
+//! All the fields are little endian `u32`s, the CRC is
+//! `kernel::utilities::helpers::crc32_posix()` of the `length` bytes of the
+//! image. The header is written last when an image is committed, and is
+//! cleared once the image was installed, or rejected because it is invalid.
+//!
+//! Slot `n` of the app flash starts at `slots_start + n * slot_size` in the
+//! address space of the app flash storage. The kernel stops looking for apps
+//! at the first gap in app flash, so the staged image should fill its slot,
+//! padded as `tockloader` pads apps.
+//!
+//! Boot
+//! ----
+//!
+//! `install()` starts the installation of the staged image. Nothing is
+//! written to app flash unless the header is valid and the CRC of the staged
+//! image matches, so a corrupted or partially staged image leaves the
+//! existing apps untouched. An invalid image is rejected and its header
+//! cleared.
+//!
+//! The copy first writes the TBF header of a padding binary as large as the
+//! slot over the start of the slot, then the image after its first
+//! `PADDING_LEN` bytes, and the first bytes of the image, which hold its TBF
+//! header, last. Until the copy completes, the process loader skips the
+//! slot as padding and goes on with the next ones, instead of loading a half
+//! overwritten app. If the copy fails the header is kept, so that the copy
+//! is tried again on the next boot. The `InstallClient` is called once done,
+//! and must load the processes then:
+//!
+//! ```rust,ignore
+//! let app_staging = static_init!(
+//!     capsules_extra::app_staging::AppStaging<'static>,
+//!     capsules_extra::app_staging::AppStaging::new(
+//!         staging_storage,              // Storage holding the staging region.
+//!         0x0,                          // Start of the staging region.
+//!         0x10000,                      // Length of the staging region.
+//!         app_flash_storage,            // Storage over the app flash.
+//!         core::ptr::addr_of!(_sapps) as usize, // Start of the first slot.
+//!         0x10000,                      // Size of a slot.
+//!         4,                            // Number of slots.
+//!         ShortId::Fixed(updater_id),   // ShortId of the updater.
+//!         board_kernel.create_grant(capsules_extra::app_staging::DRIVER_NUM, &grant_cap),
+//!         static_init!([u8; 512], [0; 512]),
+//!     )
+//! );
+//! NonvolatileStorage::set_client(staging_storage, app_staging);
+//! NonvolatileStorage::set_client(app_flash_storage, app_staging);
+//!
+//! // Processes are loaded once the staged image is installed, as the copy
+//! // completes from the kernel loop.
+//! app_staging.set_install_client(process_loader);
+//! let _ = app_staging.install();
+//! board_kernel.kernel_loop(...);
+//! ```
+//!
+//! The `nrf52840dk-app-staging` board configuration is set up this way.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Only the process whose `ShortId` is the updater's may use the driver,
+//! every command returns `NOSUPPORT` for the other processes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver existence check.
+//! - `1`: Write the buffer of read-only allow `0` to the staged image.
+//!   - `data1`: offset in the image.
+//!   - Return: `Ok(())` if the write started, `BUSY` if another operation is
+//!     in progress, `INVAL` if the buffer is empty or does not fit in the
+//!     staging region. At most `BUF_LEN` bytes are written.
+//! - `2`: Commit the staged image.
+//!   - `data1`: length of the image.
+//!   - `data2`: app slot the image is installed to.
+//!   - Return: `Ok(())` if the commit started, `BUSY` if another operation is
+//!     in progress, `INVAL` if the image does not fit in the staging region
+//!     or in a slot, or the slot does not exist.
+//! - `3`: Cancel the committed image, by clearing the header.
+//!   - Return: `Ok(())` if the cancel started, `BUSY` if another operation is
+//!     in progress.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Write done.
+//!   - `data1`: number of bytes written.
+//!   - `data2`: statuscode.
+//! - `1`: Commit or cancel done.
+//!   - `data1`: statuscode.
+//!   - `data2`: CRC of the committed image, as read back from storage. The
+//!     updater should cancel the commit if it is not the CRC it expects.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::process::ShortId;
+use kernel::processbuffer::ReadableProcessBuffer;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::helpers::{crc32_posix, crc32_posix_continue};
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::AppStaging as usize;
+
+/// IDs for subscribed upcalls.
+mod upcall {
+    /// Write done callback.
+    pub const WRITE_DONE: usize = 0;
+    /// Commit or cancel done callback.
+    pub const COMMIT_DONE: usize = 1;
+    /// Number of upcalls.
+    pub const COUNT: u8 = 2;
+}
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// Bytes to write to the staged image.
+    pub const WRITE: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+pub const BUF_LEN: usize = 512;
+
+/// Marks a committed image, "STAG".
+const STAGING_MAGIC: u32 = 0x5354_4147;
+/// Length of the header at the start of the staging region.
+pub const HEADER_LEN: usize = 16;
+/// Length of the TBF header of a padding binary, the header of a version 2
+/// TBF without any TLV.
+pub const PADDING_LEN: usize = 16;
+
+/// Encode the TBF header of a padding binary of `total_size` bytes, which
+/// the process loader skips.
+pub fn encode_padding(buffer: &mut [u8], total_size: usize) {
+    let version = 2;
+    let words = [version | (PADDING_LEN as u32) << 16, total_size as u32, 0];
+    let checksum = words.iter().fold(0, |checksum, word| checksum ^ word);
+    for (bytes, word) in buffer
+        .chunks_exact_mut(4)
+        .zip(words.iter().chain(core::iter::once(&checksum)))
+    {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// Client for the installation of the staged image at boot.
+pub trait InstallClient {
+    /// Called once the staged image was handled. `result` is
+    /// `Ok(Some(slot))` if an image was copied to `slot`, `Ok(None)` if no
+    /// image was staged, and the error otherwise.
+    fn install_done(&self, result: Result<Option<usize>, ErrorCode>);
+}
+
+/// Header of a committed image.
+#[derive(Clone, Copy, PartialEq)]
+struct Header {
+    length: usize,
+    crc: u32,
+    slot: usize,
+}
+
+impl Header {
+    fn encode(&self, buffer: &mut [u8]) {
+        let fields = [
+            STAGING_MAGIC,
+            self.length as u32,
+            self.crc,
+            self.slot as u32,
+        ];
+        for (bytes, field) in buffer.chunks_exact_mut(4).zip(fields) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    /// Returns `None` if there is no committed image.
+    fn decode(buffer: &[u8]) -> Option<Header> {
+        let field = |i: usize| {
+            u32::from_le_bytes([
+                buffer[4 * i],
+                buffer[4 * i + 1],
+                buffer[4 * i + 2],
+                buffer[4 * i + 3],
+            ])
+        };
+        if field(0) != STAGING_MAGIC {
+            return None;
+        }
+        Some(Header {
+            length: field(1) as usize,
+            crc: field(2),
+            slot: field(3) as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Writing the updater's bytes to the image.
+    Write,
+    /// Reading back the image to commit, `offset` bytes were checksummed.
+    CommitChecksum {
+        length: usize,
+        slot: usize,
+        offset: usize,
+        crc: u32,
+    },
+    /// Writing the header of the committed image.
+    CommitHeader {
+        crc: u32,
+    },
+    /// Clearing the header for the updater.
+    Cancel,
+    /// Reading the header at boot.
+    InstallHeader,
+    /// Checking the CRC of the staged image, `offset` bytes were checksummed.
+    InstallChecksum {
+        header: Header,
+        offset: usize,
+        crc: u32,
+    },
+    /// Writing a padding header over the slot, before the image is copied.
+    InstallInvalidate {
+        header: Header,
+    },
+    /// Reading the chunk of the image at `offset` to copy it.
+    InstallRead {
+        header: Header,
+        offset: usize,
+    },
+    /// Writing the chunk of the image at `offset` to the slot.
+    InstallWrite {
+        header: Header,
+        offset: usize,
+    },
+    /// Clearing the header of an installed or rejected image.
+    InstallClear {
+        result: Result<Option<usize>, ErrorCode>,
+    },
+}
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct AppStaging<'a> {
+    /// Storage holding the staging region.
+    staging: &'a dyn NonvolatileStorage<'a>,
+    /// Address of the staging region in `staging`.
+    staging_start: usize,
+    /// Length of the staging region, including the header.
+    staging_length: usize,
+    /// Storage over the app flash.
+    app_flash: &'a dyn NonvolatileStorage<'a>,
+    /// Address of the first slot in `app_flash`.
+    slots_start: usize,
+    slot_size: usize,
+    slot_count: usize,
+    /// The only process allowed to use the driver.
+    updater: ShortId,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<0>,
+    >,
+    buffer: TakeCell<'static, [u8]>,
+    buffer_len: usize,
+    state: Cell<State>,
+    current_process: OptionalCell<ProcessId>,
+    install_client: OptionalCell<&'a dyn InstallClient>,
+}
+
+impl<'a> AppStaging<'a> {
+    pub fn new(
+        staging: &'a dyn NonvolatileStorage<'a>,
+        staging_start: usize,
+        staging_length: usize,
+        app_flash: &'a dyn NonvolatileStorage<'a>,
+        slots_start: usize,
+        slot_size: usize,
+        slot_count: usize,
+        updater: ShortId,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<0>,
+        >,
+        buffer: &'static mut [u8],
+    ) -> AppStaging<'a> {
+        AppStaging {
+            staging,
+            staging_start,
+            staging_length,
+            app_flash,
+            slots_start,
+            slot_size,
+            slot_count,
+            updater,
+            apps: grant,
+            buffer_len: buffer.len(),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            current_process: OptionalCell::empty(),
+            install_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_install_client(&self, client: &'a dyn InstallClient) {
+        self.install_client.set(client);
+    }
+
+    /// Maximum length of a staged image.
+    fn capacity(&self) -> usize {
+        self.staging_length.saturating_sub(HEADER_LEN)
+    }
+
+    /// Check and install the staged image, see the module documentation.
+    /// The `InstallClient` is only called if this returns `Ok(())`.
+    pub fn install(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.read(State::InstallHeader, 0, HEADER_LEN)
+    }
+
+    /// Read `length` bytes of the staging region at `offset` into the
+    /// buffer.
+    fn read(&self, state: State, offset: usize, length: usize) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        self.state.set(state);
+        self.staging
+            .read(buffer, self.staging_start + offset, length)
+            .map_err(|error| {
+                self.state.set(State::Idle);
+                error
+            })
+    }
+
+    /// Write the buffer, with the first `length` bytes set by `fill`, to
+    /// `storage` at `address`.
+    fn write<F: FnOnce(&mut [u8])>(
+        &self,
+        state: State,
+        storage: &'a dyn NonvolatileStorage<'a>,
+        address: usize,
+        length: usize,
+        fill: F,
+    ) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        fill(&mut buffer[..length]);
+        self.state.set(state);
+        storage.write(buffer, address, length).map_err(|error| {
+            self.state.set(State::Idle);
+            error
+        })
+    }
+
+    /// Clear the header, so that no image is committed.
+    fn clear_header(&self, state: State) -> Result<(), ErrorCode> {
+        self.write(
+            state,
+            self.staging,
+            self.staging_start,
+            HEADER_LEN,
+            |buffer| buffer.fill(0),
+        )
+    }
+
+    /// Read the chunk of the image at `offset`.
+    fn read_image(&self, state: State, offset: usize, length: usize) -> Result<(), ErrorCode> {
+        let chunk = cmp::min(self.buffer_len, length - offset);
+        self.read(state, HEADER_LEN + offset, chunk)
+    }
+
+    /// Address of `slot` in the app flash.
+    fn slot_address(&self, slot: usize) -> usize {
+        self.slots_start + slot * self.slot_size
+    }
+
+    /// Read the chunk of the image at `offset` to copy it to the slot. The
+    /// first `PADDING_LEN` bytes are copied last, so a chunk never crosses
+    /// them.
+    fn copy_chunk(&self, header: Header, offset: usize) -> Result<(), ErrorCode> {
+        let end = if offset < PADDING_LEN {
+            cmp::min(PADDING_LEN, header.length)
+        } else {
+            header.length
+        };
+        self.read_image(State::InstallRead { header, offset }, offset, end)
+    }
+
+    /// The offset of the chunk to copy once `length` bytes were copied at
+    /// `offset`, or `None` if the image is copied entirely.
+    fn next_chunk(header: &Header, offset: usize, length: usize) -> Option<usize> {
+        let next = offset + length;
+        if offset >= PADDING_LEN {
+            Some(if next < header.length { next } else { 0 })
+        } else if next < cmp::min(PADDING_LEN, header.length) {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `header` describes an image that fits in the staging region
+    /// and in its slot.
+    fn header_is_valid(&self, header: &Header) -> bool {
+        header.length > 0
+            && header.length <= self.capacity()
+            && header.length <= self.slot_size
+            && header.slot < self.slot_count
+    }
+
+    fn install_done(&self, result: Result<Option<usize>, ErrorCode>) {
+        self.state.set(State::Idle);
+        self.install_client
+            .map(|client| client.install_done(result));
+    }
+
+    fn write_image(&self, offset: usize, processid: ProcessId) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(processid, |_app, kernel_data| {
+                let data = kernel_data
+                    .get_readonly_processbuffer(ro_allow::WRITE)
+                    .map_err(ErrorCode::from)?;
+                let length = cmp::min(data.len(), self.buffer_len);
+                if length == 0 || offset > self.capacity() || length > self.capacity() - offset {
+                    return Err(ErrorCode::INVAL);
+                }
+                self.write(
+                    State::Write,
+                    self.staging,
+                    self.staging_start + HEADER_LEN + offset,
+                    length,
+                    |buffer| {
+                        let _ = data.enter(|data| data[..length].copy_to_slice(buffer));
+                    },
+                )
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn commit(&self, length: usize, slot: usize) -> Result<(), ErrorCode> {
+        let header = Header {
+            length,
+            crc: 0,
+            slot,
+        };
+        if !self.header_is_valid(&header) {
+            return Err(ErrorCode::INVAL);
+        }
+        let state = State::CommitChecksum {
+            length,
+            slot,
+            offset: 0,
+            crc: crc32_posix(&[]),
+        };
+        self.read_image(state, 0, length)
+    }
+
+    fn commit_done(&self, result: Result<(), ErrorCode>, crc: u32) {
+        self.state.set(State::Idle);
+        self.current_process.take().map(|processid| {
+            let _ = self.apps.enter(processid, |_app, kernel_data| {
+                kernel_data
+                    .schedule_upcall(
+                        upcall::COMMIT_DONE,
+                        (into_statuscode(result), crc as usize, 0),
+                    )
+                    .ok();
+            });
+        });
+    }
+
+    /// End the operation that was in `state` with `error`.
+    fn failed(&self, state: State, error: ErrorCode) {
+        match state {
+            State::CommitChecksum { .. } | State::CommitHeader { .. } | State::Cancel => {
+                self.commit_done(Err(error), 0)
+            }
+            State::InstallHeader
+            | State::InstallChecksum { .. }
+            | State::InstallInvalidate { .. }
+            | State::InstallRead { .. }
+            | State::InstallWrite { .. }
+            | State::InstallClear { .. } => self.install_done(Err(error)),
+            State::Write | State::Idle => self.state.set(State::Idle),
+        }
+    }
+}
+
+impl NonvolatileStorageClient for AppStaging<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        let state = self.state.get();
+        // A read without any byte would never finish the image.
+        let result = result.and(if length > 0 {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        });
+        let checksum = |crc: u32| {
+            self.buffer
+                .map_or(crc, |buffer| crc32_posix_continue(crc, &buffer[..length]))
+        };
+
+        let started = match (state, result) {
+            (_, Err(error)) => Err(error),
+
+            (
+                State::CommitChecksum {
+                    length: image_length,
+                    slot,
+                    offset,
+                    crc,
+                },
+                Ok(()),
+            ) => {
+                let crc = checksum(crc);
+                let offset = offset + length;
+                if offset < image_length {
+                    let state = State::CommitChecksum {
+                        length: image_length,
+                        slot,
+                        offset,
+                        crc,
+                    };
+                    self.read_image(state, offset, image_length)
+                } else {
+                    let header = Header {
+                        length: image_length,
+                        crc,
+                        slot,
+                    };
+                    self.write(
+                        State::CommitHeader { crc },
+                        self.staging,
+                        self.staging_start,
+                        HEADER_LEN,
+                        |buffer| header.encode(buffer),
+                    )
+                }
+            }
+
+            (State::InstallHeader, Ok(())) => {
+                match self
+                    .buffer
+                    .map_or(None, |buffer| Header::decode(&buffer[..HEADER_LEN]))
+                {
+                    None => {
+                        self.install_done(Ok(None));
+                        Ok(())
+                    }
+                    Some(header) if !self.header_is_valid(&header) => {
+                        self.clear_header(State::InstallClear {
+                            result: Err(ErrorCode::INVAL),
+                        })
+                    }
+                    Some(header) => {
+                        let state = State::InstallChecksum {
+                            header,
+                            offset: 0,
+                            crc: crc32_posix(&[]),
+                        };
+                        self.read_image(state, 0, header.length)
+                    }
+                }
+            }
+
+            (
+                State::InstallChecksum {
+                    header,
+                    offset,
+                    crc,
+                },
+                Ok(()),
+            ) => {
+                let crc = checksum(crc);
+                let offset = offset + length;
+                if offset < header.length {
+                    let state = State::InstallChecksum {
+                        header,
+                        offset,
+                        crc,
+                    };
+                    self.read_image(state, offset, header.length)
+                } else if crc != header.crc {
+                    self.clear_header(State::InstallClear {
+                        result: Err(ErrorCode::FAIL),
+                    })
+                } else {
+                    // Nothing was written to app flash until now.
+                    self.write(
+                        State::InstallInvalidate { header },
+                        self.app_flash,
+                        self.slot_address(header.slot),
+                        PADDING_LEN,
+                        |buffer| encode_padding(buffer, self.slot_size),
+                    )
+                }
+            }
+
+            (State::InstallRead { header, offset }, Ok(())) => {
+                let address = self.slot_address(header.slot) + offset;
+                let state = State::InstallWrite { header, offset };
+                self.write(state, self.app_flash, address, length, |_| {})
+            }
+
+            _ => Ok(()),
+        };
+
+        if let Err(error) = started {
+            self.failed(state, error);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        let state = self.state.get();
+
+        let started = match (state, result) {
+            (State::Write, _) => {
+                self.state.set(State::Idle);
+                self.current_process.take().map(|processid| {
+                    let _ = self.apps.enter(processid, |_app, kernel_data| {
+                        kernel_data
+                            .schedule_upcall(
+                                upcall::WRITE_DONE,
+                                (length, into_statuscode(result), 0),
+                            )
+                            .ok();
+                    });
+                });
+                Ok(())
+            }
+            (State::CommitHeader { crc }, _) => {
+                self.commit_done(result, crc);
+                Ok(())
+            }
+            (State::InstallClear { result }, _) => {
+                self.install_done(result);
+                Ok(())
+            }
+            // The staged image is kept if the copy fails, to try again on
+            // the next boot.
+            (_, Err(error)) => Err(error),
+            (State::InstallInvalidate { header }, Ok(())) => {
+                let offset = if header.length > PADDING_LEN {
+                    PADDING_LEN
+                } else {
+                    0
+                };
+                self.copy_chunk(header, offset)
+            }
+            (State::InstallWrite { header, offset }, Ok(())) => {
+                match Self::next_chunk(&header, offset, length) {
+                    Some(offset) => self.copy_chunk(header, offset),
+                    None => self.clear_header(State::InstallClear {
+                        result: Ok(Some(header.slot)),
+                    }),
+                }
+            }
+            (State::Cancel, Ok(())) => {
+                self.commit_done(Ok(()), 0);
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+
+        if let Err(error) = started {
+            self.failed(state, error);
+        }
+    }
+}
+
+impl SyscallDriver for AppStaging<'_> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        if processid.short_app_id() != self.updater {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+        if self.state.get() != State::Idle {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        // Set before the operation starts, in case it completes at once.
+        self.current_process.set(processid);
+        let result = match command_num {
+            // Write
+            1 => self.write_image(data1, processid),
+            // Commit
+            2 => self.commit(data1, data2),
+            // Cancel
+            3 => self.clear_header(State::Cancel),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        if result.is_err() {
+            self.current_process.clear();
+        }
+        result.into()
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,401 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Process console commands for board bring-up.
+//!
+//! - `adc read <channel>` samples an ADC channel and prints the value in
+//!   millivolts, corrected for temperature if the board sets a
+//!   [`VoltageCompensation`] with `AdcCommand::set_compensation()`.
+//! - `storage <offset> [length]` prints a hex dump of up to
+//!   [`STORAGE_BUF_LEN`] bytes of nonvolatile storage.
+//! - `settings` lists the [`Settings`] and `settings <key> <value>` changes
+//!   one, persisting it.
+//!
+//! Numbers are decimal, or hexadecimal with a `0x` prefix.
+//!
+//! Usage
+//! -----
+//!
+//! The ADC channels need their own clients, so the board gives the command
+//! dedicated virtual channels (`capsules_core::virtualizers::virtual_adc::AdcDevice`)
+//! rather than the ones of the ADC syscall driver. The storage command
+//! uses the kernel region of the nonvolatile storage driver.
+//!
+//! ```rust
+//! let adc_command = static_init!(
+//!     capsules_extra::console_commands::AdcCommand<'static>,
+//!     capsules_extra::console_commands::AdcCommand::new(console_adc_channels)
+//! );
+//! for channel in console_adc_channels {
+//!     channel.set_client(adc_command);
+//! }
+//! let storage_command = static_init!(
+//!     capsules_extra::console_commands::StorageCommand<'static>,
+//!     capsules_extra::console_commands::StorageCommand::new(
+//!         nonvolatile_storage,
+//!         static_init!([u8; capsules_extra::console_commands::STORAGE_BUF_LEN], [0; 64]),
+//!     )
+//! );
+//! nonvolatile_storage.set_client(storage_command);
+//! let settings_command = static_init!(
+//!     capsules_extra::console_commands::SettingsCommand<'static>,
+//!     capsules_extra::console_commands::SettingsCommand::new(settings)
+//! );
+//! settings.set_client(settings_command);
+//! let commands = static_init!(
+//!     [&'static dyn ConsoleCommand<'static>; 3],
+//!     [adc_command, storage_command, settings_command]
+//! );
+//! process_console.set_commands(commands);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::{self, Write};
+
+use capsules_core::process_console::{ConsoleCommand, ConsoleCommandClient};
+use kernel::hil::adc;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::settings::{SetClient, Settings};
+use crate::temperature_compensation::VoltageCompensation;
+
+/// Largest range `storage` prints.
+pub const STORAGE_BUF_LEN: usize = 64;
+
+/// Bytes printed on each line of a hex dump.
+const DUMP_LINE_LEN: usize = 16;
+
+/// Parse a decimal number, or an hexadecimal one with a `0x` prefix.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse a number as `parse_number()`, possibly negative.
+fn parse_signed(s: &str) -> Option<i32> {
+    match s.strip_prefix('-') {
+        Some(magnitude) => i32::try_from(parse_number(magnitude)?)
+            .ok()
+            .map(|magnitude| -magnitude),
+        None => i32::try_from(parse_number(s)?).ok(),
+    }
+}
+
+/// Formats a line of output in a fixed size buffer.
+struct LineWriter {
+    buf: [u8; 80],
+    len: usize,
+}
+
+impl LineWriter {
+    fn new() -> Self {
+        LineWriter {
+            buf: [0; 80],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for LineWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Convert a left-justified sample to millivolts.
+fn sample_to_mv(sample: u16, reference_mv: usize) -> usize {
+    (sample as usize * reference_mv) >> 16
+}
+
+/// `adc read <channel>`: sample a channel and print the voltage.
+pub struct AdcCommand<'a> {
+    channels: &'a [&'a dyn adc::AdcChannel<'a>],
+    /// Index of the channel being sampled.
+    active: OptionalCell<usize>,
+    compensation: OptionalCell<&'a dyn VoltageCompensation>,
+    client: OptionalCell<&'a dyn ConsoleCommandClient>,
+}
+
+impl<'a> AdcCommand<'a> {
+    pub fn new(channels: &'a [&'a dyn adc::AdcChannel<'a>]) -> AdcCommand<'a> {
+        AdcCommand {
+            channels: channels,
+            active: OptionalCell::empty(),
+            compensation: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Correct the voltages printed, channels are numbered as in `channels`.
+    pub fn set_compensation(&self, compensation: &'a dyn VoltageCompensation) {
+        self.compensation.set(compensation);
+    }
+
+    fn write_voltage(
+        &self,
+        line: &mut LineWriter,
+        index: usize,
+        sample: u16,
+        reference_mv: usize,
+    ) -> fmt::Result {
+        let mv = sample_to_mv(sample, reference_mv);
+        match self.compensation.get() {
+            None => write!(line, "adc {}: {} mV (raw {:#06x})\r\n", index, mv, sample),
+            Some(compensation) => {
+                let compensated = compensation.compensate(index, mv);
+                write!(
+                    line,
+                    "adc {}: {} mV (raw {:#06x}, {}",
+                    index,
+                    compensated.mv,
+                    sample,
+                    if compensated.applied {
+                        "compensated"
+                    } else {
+                        "uncompensated"
+                    }
+                )?;
+                if let Some(age) = compensated.temperature_age_ms {
+                    write!(line, ", temp {} ms old", age)?;
+                }
+                write!(line, ")\r\n")
+            }
+        }
+    }
+}
+
+impl<'a> ConsoleCommand<'a> for AdcCommand<'a> {
+    fn name(&self) -> &'static str {
+        "adc"
+    }
+
+    fn help(&self) -> &'static str {
+        "adc read <channel>: sample an ADC channel, in mV"
+    }
+
+    fn set_client(&self, client: &'a dyn ConsoleCommandClient) {
+        self.client.set(client);
+    }
+
+    fn execute(&self, arguments: &str) -> Result<(), ErrorCode> {
+        let mut words = arguments.split_whitespace();
+        let index = match (words.next(), words.next().and_then(parse_number)) {
+            (Some("read"), Some(index)) if index < self.channels.len() => index,
+            _ => return Err(ErrorCode::INVAL),
+        };
+        if self.active.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.channels[index].sample()?;
+        self.active.set(index);
+        Ok(())
+    }
+}
+
+impl adc::Client for AdcCommand<'_> {
+    fn sample_ready(&self, sample: u16) {
+        self.active.take().map(|index| {
+            let mut line = LineWriter::new();
+            let _ = match self.channels[index].get_voltage_reference_mv() {
+                Some(reference_mv) => self.write_voltage(&mut line, index, sample, reference_mv),
+                None => write!(line, "adc {}: raw {:#06x}\r\n", index, sample),
+            };
+            self.client.map(|client| {
+                client.print(line.as_bytes());
+                client.command_done();
+            });
+        });
+    }
+}
+
+/// `storage <offset> [length]`: hex dump of nonvolatile storage.
+pub struct StorageCommand<'a> {
+    storage: &'a dyn NonvolatileStorage<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    offset: Cell<usize>,
+    client: OptionalCell<&'a dyn ConsoleCommandClient>,
+}
+
+impl<'a> StorageCommand<'a> {
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'a>,
+        buffer: &'static mut [u8; STORAGE_BUF_LEN],
+    ) -> StorageCommand<'a> {
+        StorageCommand {
+            storage: storage,
+            buffer: TakeCell::new(buffer),
+            offset: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a> ConsoleCommand<'a> for StorageCommand<'a> {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    fn help(&self) -> &'static str {
+        "storage <offset> [length]: dump up to 64 bytes of storage"
+    }
+
+    fn set_client(&self, client: &'a dyn ConsoleCommandClient) {
+        self.client.set(client);
+    }
+
+    fn execute(&self, arguments: &str) -> Result<(), ErrorCode> {
+        let mut words = arguments.split_whitespace();
+        let offset = words
+            .next()
+            .and_then(parse_number)
+            .ok_or(ErrorCode::INVAL)?;
+        let length = match words.next() {
+            Some(word) => parse_number(word).ok_or(ErrorCode::INVAL)?,
+            None => DUMP_LINE_LEN,
+        };
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        let length = cmp::min(length, buffer.len());
+        if length == 0 {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::INVAL);
+        }
+        self.offset.set(offset);
+        self.storage.read(buffer, offset, length)
+    }
+}
+
+impl NonvolatileStorageClient for StorageCommand<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.client.map(|client| {
+            let offset = self.offset.get();
+            for (i, chunk) in buffer[..length].chunks(DUMP_LINE_LEN).enumerate() {
+                let mut line = LineWriter::new();
+                let _ = write!(line, "{:08x}:", offset + i * DUMP_LINE_LEN);
+                for byte in chunk {
+                    let _ = write!(line, " {:02x}", byte);
+                }
+                let _ = line.write_str("\r\n");
+                client.print(line.as_bytes());
+            }
+            if let Err(e) = result {
+                let mut line = LineWriter::new();
+                let _ = write!(line, "storage read failed: {:?}\r\n", e);
+                client.print(line.as_bytes());
+            }
+            client.command_done();
+        });
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(
+        &self,
+        buffer: &'static mut [u8],
+        _length: usize,
+        _result: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+    }
+}
+
+/// `settings [<key> <value>]`: list the settings, or change one.
+pub struct SettingsCommand<'a> {
+    settings: &'a Settings<'a>,
+    client: OptionalCell<&'a dyn ConsoleCommandClient>,
+}
+
+impl<'a> SettingsCommand<'a> {
+    pub fn new(settings: &'a Settings<'a>) -> SettingsCommand<'a> {
+        SettingsCommand {
+            settings: settings,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn list(&self, client: &dyn ConsoleCommandClient) {
+        for setting in self.settings.settings() {
+            let mut line = LineWriter::new();
+            let (min, max) = setting.range();
+            let _ = write!(
+                line,
+                "{:#x}: {} [{}, {}]{}\r\n",
+                setting.key(),
+                setting.value(),
+                min,
+                max,
+                if setting.is_clamped() { " clamped" } else { "" }
+            );
+            client.print(line.as_bytes());
+        }
+        client.command_done();
+    }
+}
+
+impl<'a> ConsoleCommand<'a> for SettingsCommand<'a> {
+    fn name(&self) -> &'static str {
+        "settings"
+    }
+
+    fn help(&self) -> &'static str {
+        "settings [<key> <value>]: list the settings, or change one"
+    }
+
+    fn set_client(&self, client: &'a dyn ConsoleCommandClient) {
+        self.client.set(client);
+    }
+
+    fn execute(&self, arguments: &str) -> Result<(), ErrorCode> {
+        let mut words = arguments.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => {
+                self.client.map(|client| self.list(client));
+                Ok(())
+            }
+            (Some(key), Some(value), None) => {
+                let key = parse_number(key)
+                    .and_then(|key| u32::try_from(key).ok())
+                    .ok_or(ErrorCode::INVAL)?;
+                let value = parse_signed(value).ok_or(ErrorCode::INVAL)?;
+                self.settings.set(key, value)
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+}
+
+impl SetClient for SettingsCommand<'_> {
+    fn set_done(&self, key: u32, result: Result<(), ErrorCode>) {
+        self.client.map(|client| {
+            let mut line = LineWriter::new();
+            let _ = match result {
+                Ok(()) => write!(
+                    line,
+                    "{:#x}: {}\r\n",
+                    key,
+                    self.settings
+                        .settings()
+                        .find(|setting| setting.key() == key)
+                        .map_or(0, |setting| setting.value())
+                ),
+                Err(e) => write!(line, "{:#x}: change failed: {:?}\r\n", key, e),
+            };
+            client.print(line.as_bytes());
+            client.command_done();
+        });
+    }
+}
@@ -43,6 +43,18 @@
 //! this driver to work with capsules like the `nonvolatile_storage_driver`
 //! that provide virtualization and a userspace interface. The second is a
 //! custom interface that exposes other chip-specific functions.
+//!
+//! Reads, writes and erases are split into SPI transfers of at most
+//! `BUF_LEN - 3` bytes of data, after the opcode and the address, so they
+//! may be of any length within the memory. Each write transfer is preceded
+//! by `WREN`, as the chip clears its write enable latch after every write.
+//! Erasing writes zeros over the memory. `erase_all()` clears the whole
+//! chip, of `CAPACITY` bytes unless set otherwise with `set_capacity()`.
+//! `set_write_protection()` protects an upper part of the memory from writes
+//! with the block protect bits of the status register, and
+//! `get_protection()` reads them back; `read_status()` reads the whole
+//! register. Requests made while another one is in
+//! progress return `BUSY`.
 
 use core::cell::Cell;
 use core::cmp;
@@ -52,6 +64,9 @@ use kernel::ErrorCode;
 
 pub const BUF_LEN: usize = 512;
 
+/// Size of the FM25CL64B, in bytes.
+pub const CAPACITY: usize = 8192;
+
 const SPI_SPEED: u32 = 4000000;
 
 #[allow(dead_code)]
@@ -77,16 +92,71 @@ enum State {
 
     /// Read from the FRAM
     ReadMemory,
+
+    /// Set the block protect bits
+    WriteStatusEnable,
+    WriteStatus,
+
+    /// Read the block protect bits
+    ReadProtection,
+
+    /// Write zeros over the FRAM
+    EraseEnable,
+    EraseMemory,
+}
+
+/// The upper part of the memory protected from writes, selected by the
+/// BP1 and BP0 bits of the status register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtectRange {
+    None = 0b00,
+    UpperQuarter = 0b01,
+    UpperHalf = 0b10,
+    All = 0b11,
+}
+
+impl ProtectRange {
+    /// Position of BP0 in the status register.
+    const SHIFT: u8 = 2;
+
+    fn status_bits(self) -> u8 {
+        (self as u8) << Self::SHIFT
+    }
+
+    fn from_status(status: u8) -> ProtectRange {
+        match (status >> Self::SHIFT) & 0b11 {
+            0b00 => ProtectRange::None,
+            0b01 => ProtectRange::UpperQuarter,
+            0b10 => ProtectRange::UpperHalf,
+            _ => ProtectRange::All,
+        }
+    }
 }
 
 pub trait FM25CLCustom {
+    /// Read the whole status register, delivered with
+    /// `FM25CLClient::status()`. Nothing is delivered if the SPI transfer
+    /// fails.
     fn read_status(&self) -> Result<(), ErrorCode>;
+
+    /// Protect `range` from writes. WPEN is cleared, so the status register
+    /// itself stays writable whatever the level of the /W pin.
+    fn set_write_protection(&self, range: ProtectRange) -> Result<(), ErrorCode>;
+
+    /// Read which part of the memory is protected from writes.
+    fn get_protection(&self) -> Result<(), ErrorCode>;
+
+    /// Write zeros over the whole chip. Completes with
+    /// `NonvolatileStorageClient::erase_done()`.
+    fn erase_all(&self) -> Result<(), ErrorCode>;
 }
 
 pub trait FM25CLClient {
     fn status(&self, status: u8);
     fn read(&self, data: &'static mut [u8], len: usize);
     fn done(&self, buffer: &'static mut [u8]);
+    fn protection_set(&self, result: Result<(), ErrorCode>);
+    fn protection(&self, range: Result<ProtectRange, ErrorCode>);
 }
 
 pub struct FM25CL<'a, S: hil::spi::SpiMasterDevice<'a>> {
@@ -97,8 +167,15 @@ pub struct FM25CL<'a, S: hil::spi::SpiMasterDevice<'a>> {
     client: OptionalCell<&'a dyn hil::nonvolatile_storage::NonvolatileStorageClient>,
     client_custom: OptionalCell<&'a dyn FM25CLClient>,
     client_buffer: TakeCell<'static, [u8]>, // Store buffer and state for passing back to client
-    client_write_address: Cell<u16>,
-    client_write_len: Cell<u16>,
+    capacity: Cell<usize>,
+    /// The block protect bits being written to the status register.
+    protect_range: Cell<ProtectRange>,
+    /// Next address to read, write or erase, and the number of bytes left.
+    address: Cell<usize>,
+    remaining: Cell<usize>,
+    /// Bytes moved by the current SPI transfer, and by the ones before it.
+    chunk: Cell<usize>,
+    transferred: Cell<usize>,
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CL<'a, S> {
@@ -116,8 +193,12 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CL<'a, S> {
             client: OptionalCell::empty(),
             client_custom: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
-            client_write_address: Cell::new(0),
-            client_write_len: Cell::new(0),
+            capacity: Cell::new(CAPACITY),
+            protect_range: Cell::new(ProtectRange::None),
+            address: Cell::new(0),
+            remaining: Cell::new(0),
+            chunk: Cell::new(0),
+            transferred: Cell::new(0),
         }
     }
 
@@ -125,6 +206,146 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CL<'a, S> {
         self.client_custom.set(client);
     }
 
+    /// Set the size of the chip, in bytes, for another member of the family
+    /// than the FM25CL64B.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.set(capacity);
+    }
+
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        if self.state.get() == State::Idle {
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    /// Send the first `len` bytes of `txbuffer`, in `state`. The buffer is
+    /// put back if the transfer could not start.
+    fn send(&self, txbuffer: &'static mut [u8], len: usize, state: State) -> Result<(), ErrorCode> {
+        self.transfer(txbuffer, None, len, state)
+    }
+
+    /// Send the first `len` bytes of `txbuffer` while receiving into
+    /// `rxbuffer`, in `state`. The buffers are put back if the transfer
+    /// could not start.
+    fn transfer(
+        &self,
+        txbuffer: &'static mut [u8],
+        rxbuffer: Option<&'static mut [u8]>,
+        len: usize,
+        state: State,
+    ) -> Result<(), ErrorCode> {
+        self.state.set(state);
+        self.spi
+            .read_write_bytes(txbuffer, rxbuffer, len)
+            .map_err(|(err, txbuffer, rxbuffer)| {
+                self.state.set(State::Idle);
+                self.txbuffer.replace(txbuffer);
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                err
+            })
+    }
+
+    /// Check that `length` bytes from `address` are in the memory, and start
+    /// counting the bytes moved.
+    fn start(&self, address: usize, length: usize) -> Result<(), ErrorCode> {
+        if length == 0 || address + length > self.capacity.get() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.address.set(address);
+        self.remaining.set(length);
+        self.transferred.set(0);
+        Ok(())
+    }
+
+    /// Put the opcode and the address of the next chunk at the start of
+    /// `txbuffer`, and return the length of the chunk.
+    fn next_chunk(&self, txbuffer: &mut [u8], max_len: usize, opcode: Opcodes) -> usize {
+        let address = self.address.get();
+        let chunk = cmp::min(max_len - 3, self.remaining.get());
+        txbuffer[0] = opcode as u8;
+        txbuffer[1] = ((address >> 8) & 0xFF) as u8;
+        txbuffer[2] = (address & 0xFF) as u8;
+        self.chunk.set(chunk);
+        chunk
+    }
+
+    /// Count the chunk just transferred, and return whether it was the last.
+    fn chunk_done(&self) -> bool {
+        let chunk = self.chunk.get();
+        self.transferred.set(self.transferred.get() + chunk);
+        self.address.set(self.address.get() + chunk);
+        self.remaining.set(self.remaining.get() - chunk);
+        self.remaining.get() == 0
+    }
+
+    /// Erase `length` bytes from `address`.
+    pub fn erase(&self, address: u16, length: usize) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.start(address as usize, length)?;
+        self.configure_spi()?;
+
+        self.txbuffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |txbuffer| {
+                txbuffer[0] = Opcodes::WriteEnable as u8;
+                self.send(txbuffer, 1, State::EraseEnable)
+            })
+    }
+
+    /// Write zeros over the next part of the memory to erase.
+    fn erase_next(&self, txbuffer: &'static mut [u8]) {
+        let chunk = self.next_chunk(txbuffer, txbuffer.len(), Opcodes::WriteMemory);
+        txbuffer[3..(chunk + 3)].fill(0);
+
+        if let Err(err) = self.send(txbuffer, chunk + 3, State::EraseMemory) {
+            self.erase_done(Err(err));
+        }
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        self.client
+            .map(|client| client.erase_done(self.transferred.get(), result));
+    }
+
+    /// Write the next chunk of the client buffer.
+    fn write_next(&self, txbuffer: &'static mut [u8]) {
+        let chunk = self.next_chunk(txbuffer, txbuffer.len(), Opcodes::WriteMemory);
+        let offset = self.transferred.get();
+        self.client_buffer.map(|buffer| {
+            txbuffer[3..(chunk + 3)].copy_from_slice(&buffer[offset..(offset + chunk)]);
+        });
+
+        if let Err(err) = self.send(txbuffer, chunk + 3, State::WriteMemory) {
+            self.write_done(Err(err));
+        }
+    }
+
+    fn write_done(&self, result: Result<(), ErrorCode>) {
+        self.client_buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.write_done(buffer, self.transferred.get(), result));
+        });
+    }
+
+    /// Read the next chunk into the client buffer.
+    fn read_next(&self, txbuffer: &'static mut [u8], rxbuffer: &'static mut [u8]) {
+        let max_len = cmp::min(txbuffer.len(), rxbuffer.len());
+        let chunk = self.next_chunk(txbuffer, max_len, Opcodes::ReadMemory);
+
+        if let Err(err) = self.transfer(txbuffer, Some(rxbuffer), chunk + 3, State::ReadMemory) {
+            self.read_done(Err(err));
+        }
+    }
+
+    fn read_done(&self, result: Result<(), ErrorCode>) {
+        self.client_buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.read_done(buffer, self.transferred.get(), result));
+        });
+    }
+
     /// Setup SPI for this chip
     fn configure_spi(&self) -> Result<(), ErrorCode> {
         self.spi.configure(
@@ -134,40 +355,42 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CL<'a, S> {
         )
     }
 
+    /// Write `len` bytes of `buffer` from `address`.
     pub fn write(
         &self,
         address: u16,
         buffer: &'static mut [u8],
-        len: u16,
+        len: usize,
     ) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        if len > buffer.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.start(address as usize, len)?;
         self.configure_spi()?;
 
         self.txbuffer
             .take()
             .map_or(Err(ErrorCode::RESERVE), move |txbuffer| {
-                txbuffer[0] = Opcodes::WriteEnable as u8;
-
-                let write_len = cmp::min(txbuffer.len(), len as usize);
-
                 // Need to save the buffer passed to us so we can give it back.
                 self.client_buffer.replace(buffer);
-                // Also save address and len for the actual write.
-                self.client_write_address.set(address);
-                self.client_write_len.set(write_len as u16);
-
-                self.state.set(State::WriteEnable);
-                let res = self.spi.read_write_bytes(txbuffer, None, 1);
-                match res {
-                    Ok(()) => Ok(()),
-                    Err((err, txbuffer, _)) => {
-                        self.txbuffer.replace(txbuffer);
-                        Err(err)
-                    }
-                }
+                txbuffer[0] = Opcodes::WriteEnable as u8;
+                self.send(txbuffer, 1, State::WriteEnable)
             })
     }
 
-    pub fn read(&self, address: u16, buffer: &'static mut [u8], len: u16) -> Result<(), ErrorCode> {
+    /// Read `len` bytes from `address` into `buffer`.
+    pub fn read(
+        &self,
+        address: u16,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        if len > buffer.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.start(address as usize, len)?;
         self.configure_spi()?;
 
         self.txbuffer
@@ -176,27 +399,11 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CL<'a, S> {
                 self.rxbuffer
                     .take()
                     .map_or(Err(ErrorCode::RESERVE), move |rxbuffer| {
-                        txbuffer[0] = Opcodes::ReadMemory as u8;
-                        txbuffer[1] = ((address >> 8) & 0xFF) as u8;
-                        txbuffer[2] = (address & 0xFF) as u8;
-
                         // Save the user buffer for later
                         self.client_buffer.replace(buffer);
-
-                        let read_len = cmp::min(rxbuffer.len() - 3, len as usize);
-
-                        self.state.set(State::ReadMemory);
-                        let res = self
-                            .spi
-                            .read_write_bytes(txbuffer, Some(rxbuffer), read_len + 3);
-                        match res {
-                            Ok(()) => Ok(()),
-                            Err((err, txbuffer, rxbuffer)) => {
-                                self.txbuffer.replace(txbuffer);
-                                self.rxbuffer.replace(rxbuffer.unwrap());
-                                Err(err)
-                            }
-                        }
+                        let max_len = cmp::min(txbuffer.len(), rxbuffer.len());
+                        let chunk = self.next_chunk(txbuffer, max_len, Opcodes::ReadMemory);
+                        self.transfer(txbuffer, Some(rxbuffer), chunk + 3, State::ReadMemory)
                     })
             })
     }
@@ -207,8 +414,8 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> hil::spi::SpiMasterClient for FM25CL<
         &self,
         write_buffer: &'static mut [u8],
         read_buffer: Option<&'static mut [u8]>,
-        len: usize,
-        _status: Result<(), ErrorCode>,
+        _len: usize,
+        status: Result<(), ErrorCode>,
     ) {
         match self.state.get() {
             State::ReadStatus => {
@@ -218,69 +425,123 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> hil::spi::SpiMasterClient for FM25CL<
                 self.txbuffer.replace(write_buffer);
 
                 read_buffer.map(|read_buffer| {
-                    let status = read_buffer[1];
+                    let status_register = read_buffer[1];
 
                     // Also replace this buffer
                     self.rxbuffer.replace(read_buffer);
 
-                    self.client_custom.map(|client| client.status(status));
+                    // The byte clocked in is meaningless if the transfer
+                    // failed.
+                    if status.is_ok() {
+                        self.client_custom
+                            .map(|client| client.status(status_register));
+                    }
                 });
             }
             State::WriteEnable => {
-                self.state.set(State::WriteMemory);
-
-                self.client_buffer.map(move |buffer| {
-                    write_buffer[0] = Opcodes::WriteMemory as u8;
-                    write_buffer[1] = ((self.client_write_address.get() >> 8) & 0xFF) as u8;
-                    write_buffer[2] = (self.client_write_address.get() & 0xFF) as u8;
-
-                    let write_len =
-                        cmp::min(write_buffer.len(), self.client_write_len.get() as usize);
-
-                    write_buffer[3..(write_len + 3)].copy_from_slice(&buffer[..write_len]);
-
-                    let _ = self
-                        .spi
-                        .read_write_bytes(write_buffer, read_buffer, write_len + 3);
-                });
+                if status.is_err() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.write_done(status);
+                    return;
+                }
+                self.write_next(write_buffer);
             }
             State::WriteMemory => {
-                self.state.set(State::Idle);
-
-                let write_len = cmp::min(write_buffer.len(), self.client_write_len.get() as usize);
-
-                // Replace these buffers
-                self.txbuffer.replace(write_buffer);
-                read_buffer.map(|read_buffer| {
-                    self.rxbuffer.replace(read_buffer);
-                });
+                if status.is_err() || self.chunk_done() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.write_done(status);
+                    return;
+                }
 
-                // Call done with the write() buffer
-                self.client_buffer.take().map(move |buffer| {
-                    self.client
-                        .map(move |client| client.write_done(buffer, write_len));
-                });
+                // The write enable latch is cleared after each write.
+                write_buffer[0] = Opcodes::WriteEnable as u8;
+                if let Err(err) = self.send(write_buffer, 1, State::WriteEnable) {
+                    self.write_done(Err(err));
+                }
             }
             State::ReadMemory => {
+                let Some(read_buffer) = read_buffer else {
+                    return;
+                };
+                if status.is_ok() {
+                    let offset = self.transferred.get();
+                    let chunk = self.chunk.get();
+                    self.client_buffer.map(|buffer| {
+                        buffer[offset..(offset + chunk)]
+                            .copy_from_slice(&read_buffer[3..(chunk + 3)]);
+                    });
+                }
+                if status.is_err() || self.chunk_done() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.read_done(status);
+                    return;
+                }
+                self.read_next(write_buffer, read_buffer);
+            }
+            State::WriteStatusEnable => {
+                if status.is_err() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.client_custom
+                        .map(|client| client.protection_set(status));
+                    return;
+                }
+                write_buffer[0] = Opcodes::WriteStatusRegister as u8;
+                write_buffer[1] = self.protect_range.get().status_bits();
+                if let Err(err) = self.send(write_buffer, 2, State::WriteStatus) {
+                    self.client_custom
+                        .map(|client| client.protection_set(Err(err)));
+                }
+            }
+            State::WriteStatus => {
+                self.state.set(State::Idle);
+                self.txbuffer.replace(write_buffer);
+                self.client_custom
+                    .map(|client| client.protection_set(status));
+            }
+            State::ReadProtection => {
                 self.state.set(State::Idle);
-
-                // Replace the TX buffer
                 self.txbuffer.replace(write_buffer);
 
                 read_buffer.map(|read_buffer| {
-                    self.client_buffer.take().map(move |buffer| {
-                        let read_len = cmp::min(buffer.len(), len);
-
-                        buffer[..(read_len - 3)]
-                            .copy_from_slice(&read_buffer[3..((read_len - 3) + 3)]);
-
-                        self.rxbuffer.replace(read_buffer);
-
-                        self.client
-                            .map(move |client| client.read_done(buffer, read_len - 3));
-                    });
+                    let range = status.map(|()| ProtectRange::from_status(read_buffer[1]));
+                    self.rxbuffer.replace(read_buffer);
+                    self.client_custom.map(|client| client.protection(range));
                 });
             }
+            State::EraseEnable => {
+                if status.is_err() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.erase_done(status);
+                    return;
+                }
+                self.erase_next(write_buffer);
+            }
+            State::EraseMemory => {
+                if status.is_err() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.erase_done(status);
+                    return;
+                }
+                if self.chunk_done() {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.erase_done(Ok(()));
+                    return;
+                }
+
+                // The write enable latch is cleared after each write.
+                write_buffer[0] = Opcodes::WriteEnable as u8;
+                if let Err(err) = self.send(write_buffer, 1, State::EraseEnable) {
+                    self.erase_done(Err(err));
+                }
+            }
             _ => {}
         }
     }
@@ -289,6 +550,7 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> hil::spi::SpiMasterClient for FM25CL<
 // Implement the custom interface that exposes chip-specific commands.
 impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CLCustom for FM25CL<'a, S> {
     fn read_status(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
         self.configure_spi()?;
 
         self.txbuffer
@@ -301,13 +563,45 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> FM25CLCustom for FM25CL<'a, S> {
 
                         // Use 4 bytes instead of the required 2 because that works better
                         // with DMA for some reason.
-                        // TODO verify SPI return value
-                        let _ = self.spi.read_write_bytes(txbuffer, Some(rxbuffer), 4);
-                        self.state.set(State::ReadStatus);
-                        Ok(())
+                        self.transfer(txbuffer, Some(rxbuffer), 4, State::ReadStatus)
+                    })
+            })
+    }
+
+    fn set_write_protection(&self, range: ProtectRange) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.configure_spi()?;
+
+        self.txbuffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |txbuffer| {
+                // The status register can only be written once writes are
+                // enabled.
+                self.protect_range.set(range);
+                txbuffer[0] = Opcodes::WriteEnable as u8;
+                self.send(txbuffer, 1, State::WriteStatusEnable)
+            })
+    }
+
+    fn get_protection(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.configure_spi()?;
+
+        self.txbuffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |txbuffer| {
+                self.rxbuffer
+                    .take()
+                    .map_or(Err(ErrorCode::RESERVE), move |rxbuffer| {
+                        txbuffer[0] = Opcodes::ReadStatusRegister as u8;
+                        self.transfer(txbuffer, Some(rxbuffer), 2, State::ReadProtection)
                     })
             })
     }
+
+    fn erase_all(&self) -> Result<(), ErrorCode> {
+        self.erase(0, self.capacity.get())
+    }
 }
 
 /// Implement the generic `NonvolatileStorage` interface common to chips that
@@ -325,7 +619,10 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> hil::nonvolatile_storage::Nonvolatile
         address: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        self.read(address as u16, buffer, length as u16)
+        if address > u16::MAX as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        self.read(address as u16, buffer, length)
     }
 
     fn write(
@@ -334,6 +631,16 @@ impl<'a, S: hil::spi::SpiMasterDevice<'a>> hil::nonvolatile_storage::Nonvolatile
         address: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        self.write(address as u16, buffer, length as u16)
+        if address > u16::MAX as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        self.write(address as u16, buffer, length)
+    }
+
+    fn erase(&self, address: usize, length: usize) -> Result<(), ErrorCode> {
+        if address > u16::MAX as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        self.erase(address as u16, length)
     }
 }
@@ -24,6 +24,62 @@
 //! to the text_screen capsule, in order for this capsule to be able to receive new
 //! commands. If a command is sent while this capsule is busy, it will return a
 //! "BUSY" code.
+//!
+//! Pulse timing
+//! ------------
+//!
+//! The enable pulse that latches each nibble only needs to be 450 ns wide.
+//! With an alarm of at least 1 MHz the capsule defaults to
+//! [`PulseMode::BusyWait`]: the pulses are generated by busy-waiting on the
+//! alarm counter, and both nibbles of a byte are sent back to back, so a
+//! character costs a single alarm event (the execution delay after it). A
+//! busy-wait lasts up to two ticks of the alarm, about 2 us at 1 MHz, with
+//! interrupts left waiting.
+//!
+//! With slower alarms, or when selected with `set_pulse_mode()`,
+//! [`PulseMode::Alarm`] times both edges of every pulse with the alarm, so a
+//! character costs six alarm events (two pulses and the execution delay
+//! after each nibble), and the kernel never spins.
+//!
+//! `set_character_delay_us()` sets a minimum delay between two printed
+//! characters, to leave room to the other clients of a shared alarm while
+//! long strings are printed. It is 0 by default, so characters are only
+//! separated by the execution delay the LCD needs.
+//!
+//! Entry mode
+//! ----------
+//!
+//! Characters are entered left to right or right to left, see
+//! [`EntryDirection`], and with entry shift the display scrolls as
+//! characters are entered instead of the cursor moving on the display. Both
+//! are set when the capsule is created and can be changed with
+//! `set_entry_direction()` and `set_entry_shift()`.
+//!
+//! The capsule keeps track of the cursor. Without entry shift, a character
+//! printed on the last column of a line in the entry direction (the right
+//! one left to right, the left one right to left) moves the cursor to the
+//! first column of the next line, and the next character is printed there.
+//!
+//! Geometry
+//! --------
+//!
+//! The width and height of the display are given when the capsule is
+//! created, and are the `width` and `height` parameters of the component.
+//! They are reported by `get_size()`, the lines start at the addresses
+//! given by [`row_offsets()`], and cursor positions outside of the display
+//! are refused with `INVAL`. The controller addresses at most four lines of
+//! at most 40 characters.
+//!
+//! Delays
+//! ------
+//!
+//! Every delay is rounded up to whole alarm ticks, and is at least the
+//! minimum dt of the alarm, so alarms that cannot be that short are not
+//! missed. If the alarm still refuses a delay, the capsule polls the counter
+//! from deferred calls until it is over instead of waiting forever. This
+//! keeps the kernel busy for the length of the delay, so it is a last
+//! resort. The component registers the deferred call, boards creating the
+//! capsule themselves have to call `DeferredCallClient::register()`.
 
 //! Usage
 //! -----
@@ -53,21 +109,27 @@
 //! Author: Teona Severin <teona.severin9@gmail.com>
 
 use core::cell::Cell;
+use core::cmp;
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil::gpio;
 use kernel::hil::text_screen::{TextScreen, TextScreenClient};
-use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::hil::time::{self, Alarm, ConvertTicks, Ticks};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::ErrorCode;
 
 /// commands
 static LCD_CLEARDISPLAY: u8 = 0x01;
+static LCD_RETURNHOME: u8 = 0x02;
 static LCD_ENTRYMODESET: u8 = 0x04;
 static LCD_DISPLAYCONTROL: u8 = 0x08;
+static LCD_CURSORSHIFT: u8 = 0x10;
 static LCD_FUNCTIONSET: u8 = 0x20;
 static LCD_SETDDRAMADDR: u8 = 0x80;
 
 /// flags for display entry mode
+static LCD_ENTRYRIGHT: u8 = 0x00;
 static LCD_ENTRYLEFT: u8 = 0x02;
+static LCD_ENTRYSHIFTINCREMENT: u8 = 0x01;
 static LCD_ENTRYSHIFTDECREMENT: u8 = 0x00;
 
 /// flags for display on/off control
@@ -76,6 +138,11 @@ static LCD_CURSORON: u8 = 0x02;
 static LCD_BLINKON: u8 = 0x01;
 static LCD_BLINKOFF: u8 = 0x00;
 
+/// flags for display/cursor shift
+static LCD_DISPLAYMOVE: u8 = 0x08;
+static LCD_MOVERIGHT: u8 = 0x04;
+static LCD_MOVELEFT: u8 = 0x00;
+
 /// flags for function set
 static LCD_8BITMODE: u8 = 0x10;
 static LCD_4BITMODE: u8 = 0x00;
@@ -83,8 +150,58 @@ static LCD_2LINE: u8 = 0x08;
 static LCD_1LINE: u8 = 0x00;
 static LCD_5X8DOTS: u8 = 0x00;
 
+/// delays from the datasheet, in microseconds
+const POWER_ON_DELAY_US: u32 = 50_000;
+const FUNCTION_SET_DELAY_US: u32 = 4_500;
+const FUNCTION_SET_REPEAT_DELAY_US: u32 = 150;
+const CLEAR_DELAY_US: u32 = 2_000;
+const COMMAND_DELAY_US: u32 = 37;
+const ENABLE_PULSE_DELAY_US: u32 = 1;
+
+/// length of a line of the display data RAM, whatever the width of the
+/// display
+const DDRAM_LINE_LEN: u8 = 40;
+
+/// number of lines the controller can address
+const MAX_LINES: u8 = 4;
+
 pub const BUF_LEN: usize = 4;
 
+/// The slowest alarm frequency, in Hz, enable pulses are busy-waited with
+/// by default.
+pub const BUSY_WAIT_MIN_FREQUENCY: u32 = 1_000_000;
+
+/// `row_offsets()` returns the display data RAM address of the first
+/// character of each line, for a display `width` characters wide.
+///
+/// Lines 0 and 1 start each line of the display data RAM, and lines 2 and 3
+/// continue them right after the last visible column: a 20x4 display uses
+/// 0x00, 0x40, 0x14 and 0x54.
+pub fn row_offsets(width: u8) -> [u8; BUF_LEN] {
+    [0x00, 0x40, width, 0x40 + width]
+}
+
+/// The direction characters are entered in.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EntryDirection {
+    /// The cursor moves right after each character.
+    LeftToRight,
+    /// The cursor moves left after each character.
+    RightToLeft,
+}
+
+/// How the enable pulses latching the data are timed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PulseMode {
+    /// Busy-wait for the width of the pulse, and send both nibbles of a
+    /// byte at once. This is the default with alarms of at least
+    /// `BUSY_WAIT_MIN_FREQUENCY`.
+    BusyWait,
+    /// Use an alarm for every edge of the enable pin. This is the default
+    /// with slower alarms.
+    Alarm,
+}
+
 /// The states the program can be in.
 #[derive(Copy, Clone, PartialEq)]
 enum LCDStatus {
@@ -110,6 +227,7 @@ enum LCDStatus {
     PulseHigh,
     Command,
     Clear,
+    EntryMode,
 }
 
 pub struct HD44780<'a, A: Alarm<'a>> {
@@ -126,13 +244,23 @@ pub struct HD44780<'a, A: Alarm<'a>> {
     display_function: Cell<u8>,
     display_control: Cell<u8>,
     display_mode: Cell<u8>,
-    num_lines: Cell<u8>,
     row_offsets: TakeCell<'static, [u8]>,
 
+    /// column and row the next character is printed at
+    cursor: Cell<(u8, u8)>,
+    /// the cursor wrapped to the next line, where the LCD has to be moved
+    /// before the next character
+    wrap_pending: Cell<bool>,
+
     alarm: &'a A,
+    deferred_call: DeferredCall,
+    /// start and length of a delay the alarm refused, polled from deferred
+    /// calls
+    deferred_delay: OptionalCell<(A::Ticks, A::Ticks)>,
 
     lcd_status: Cell<LCDStatus>,
     lcd_after_pulse_status: Cell<LCDStatus>,
+    lcd_after_pulse_delay: Cell<u32>,
     lcd_after_command_status: Cell<LCDStatus>,
     lcd_after_delay_status: Cell<LCDStatus>,
     command_to_finish: Cell<u8>,
@@ -140,6 +268,9 @@ pub struct HD44780<'a, A: Alarm<'a>> {
     begin_done: Cell<bool>,
     initialized: Cell<bool>,
 
+    pulse_mode: Cell<PulseMode>,
+    character_delay_us: Cell<u32>,
+
     text_screen_client: OptionalCell<&'a dyn TextScreenClient>,
 
     done_printing: Cell<bool>,
@@ -162,6 +293,8 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
         alarm: &'a A,
         width: u8,
         height: u8,
+        entry_direction: EntryDirection,
+        entry_shift: bool,
     ) -> HD44780<'a, A> {
         rs_pin.make_output();
         en_pin.make_output();
@@ -180,17 +313,29 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             height: Cell::new(height),
             display_function: Cell::new(LCD_4BITMODE | LCD_1LINE | LCD_5X8DOTS),
             display_control: Cell::new(0),
-            display_mode: Cell::new(0),
-            num_lines: Cell::new(0),
+            display_mode: Cell::new(Self::entry_mode(entry_direction, entry_shift)),
             row_offsets: TakeCell::new(row_offsets),
+            cursor: Cell::new((0, 0)),
+            wrap_pending: Cell::new(false),
             alarm: alarm,
+            deferred_call: DeferredCall::new(),
+            deferred_delay: OptionalCell::empty(),
             lcd_status: Cell::new(LCDStatus::Idle),
             lcd_after_pulse_status: Cell::new(LCDStatus::Idle),
+            lcd_after_pulse_delay: Cell::new(COMMAND_DELAY_US),
             lcd_after_command_status: Cell::new(LCDStatus::Idle),
             lcd_after_delay_status: Cell::new(LCDStatus::Idle),
             command_to_finish: Cell::new(0),
             begin_done: Cell::new(false),
             initialized: Cell::new(false),
+            pulse_mode: Cell::new(
+                if <A::Frequency as time::Frequency>::frequency() >= BUSY_WAIT_MIN_FREQUENCY {
+                    PulseMode::BusyWait
+                } else {
+                    PulseMode::Alarm
+                },
+            ),
+            character_delay_us: Cell::new(0),
             text_screen_client: OptionalCell::empty(),
             done_printing: Cell::new(false),
             write_buffer: TakeCell::empty(),
@@ -212,7 +357,11 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     /// `init()` is called after the capsule is instantiated:
     /// - hd44780.init(16,2);
     ///
+    /// The geometry is limited to what the controller can address: at most
+    /// four lines of at most `DDRAM_LINE_LEN` characters.
     fn init(&self, col: u8, row: u8) {
+        let col = cmp::min(cmp::max(col, 1), DDRAM_LINE_LEN);
+        let row = cmp::min(cmp::max(row, 1), MAX_LINES);
         self.begin_done.set(false);
         self.width.set(col);
         self.height.set(row);
@@ -222,12 +371,74 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
                 .replace(self.display_function.get() | LCD_2LINE);
         }
 
-        self.num_lines.replace(row);
-        let _ = self.set_rows(0x00, 0x40, 0x00 + col, 0x40 + col);
+        let [row0, row1, row2, row3] = row_offsets(col);
+        let _ = self.set_rows(row0, row1, row2, row3);
+    }
+
+    /// `is_idle()` tells whether a new command can be started. The status is
+    /// already `Idle` while the last command of a sequence executes, so the
+    /// pending delay is checked too.
+    fn is_idle(&self) -> bool {
+        self.lcd_status.get() == LCDStatus::Idle
+            && !self.alarm.is_armed()
+            && self.deferred_delay.is_none()
+    }
+
+    /// `set_pulse_mode()` selects how the enable pulses are timed. See
+    /// [`PulseMode`].
+    pub fn set_pulse_mode(&self, mode: PulseMode) {
+        self.pulse_mode.set(mode);
+    }
+
+    /// `entry_mode()` is the value of the entry mode flags.
+    fn entry_mode(direction: EntryDirection, shift: bool) -> u8 {
+        let direction = match direction {
+            EntryDirection::LeftToRight => LCD_ENTRYLEFT,
+            EntryDirection::RightToLeft => LCD_ENTRYRIGHT,
+        };
+        let shift = if shift {
+            LCD_ENTRYSHIFTINCREMENT
+        } else {
+            LCD_ENTRYSHIFTDECREMENT
+        };
+        direction | shift
+    }
+
+    /// `entry_direction()` is the direction characters are entered in.
+    pub fn entry_direction(&self) -> EntryDirection {
+        if self.display_mode.get() & LCD_ENTRYLEFT != 0 {
+            EntryDirection::LeftToRight
+        } else {
+            EntryDirection::RightToLeft
+        }
+    }
+
+    /// `cursor()` is the column and row the next character is printed at.
+    pub fn cursor(&self) -> (u8, u8) {
+        self.cursor.get()
+    }
+
+    /// `set_character_delay_us()` sets the minimum delay between two
+    /// printed characters, in microseconds. With 0, the default, characters
+    /// are only separated by the execution delay of the LCD.
+    pub fn set_character_delay_us(&self, us: u32) {
+        self.character_delay_us.set(us);
     }
 
+    /// `screen_command()` runs one of the display commands that do not need
+    /// any data to be written to the device.
+    ///
+    /// The supported commands are:
+    /// - 1: display control, `op` 0 sets and `op` 1 clears the `value` flags
+    /// - 2: clear the display
+    /// - 3: return home (cursor at (0,0) and undo any display shift)
+    /// - 4: shift the display one position to the left
+    /// - 5: shift the display one position to the right
+    /// - 6: entry direction, `op` 0 left to right and `op` 1 right to left
+    /// - 7: entry shift, `op` 0 disables and `op` 1 enables it
+    ///
     pub fn screen_command(&self, command: usize, op: usize, value: u8) -> Result<(), ErrorCode> {
-        if self.lcd_status.get() == LCDStatus::Idle {
+        if self.is_idle() {
             match command {
                 1 => {
                     if op == 0 {
@@ -243,7 +454,56 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
                 }
 
                 2 => {
-                    self.lcd_clear(LCDStatus::Idle);
+                    // Clearing the display sets the entry direction back to
+                    // left to right.
+                    if self.entry_direction() == EntryDirection::RightToLeft {
+                        self.lcd_clear(LCDStatus::EntryMode);
+                    } else {
+                        self.lcd_clear(LCDStatus::Idle);
+                    }
+                    Ok(())
+                }
+
+                3 => {
+                    self.lcd_home(LCDStatus::Idle);
+                    Ok(())
+                }
+
+                4 => {
+                    self.command_to_finish
+                        .replace(LCD_CURSORSHIFT | LCD_DISPLAYMOVE | LCD_MOVELEFT);
+                    self.lcd_command(self.command_to_finish.get(), LCDStatus::Idle);
+                    Ok(())
+                }
+
+                5 => {
+                    self.command_to_finish
+                        .replace(LCD_CURSORSHIFT | LCD_DISPLAYMOVE | LCD_MOVERIGHT);
+                    self.lcd_command(self.command_to_finish.get(), LCDStatus::Idle);
+                    Ok(())
+                }
+
+                6 => {
+                    let direction = match op {
+                        0 => EntryDirection::LeftToRight,
+                        1 => EntryDirection::RightToLeft,
+                        _ => return Err(ErrorCode::INVAL),
+                    };
+                    let shift = self.display_mode.get() & LCD_ENTRYSHIFTINCREMENT != 0;
+                    self.display_mode.set(Self::entry_mode(direction, shift));
+                    self.lcd_entry_mode(LCDStatus::Idle);
+                    Ok(())
+                }
+
+                7 => {
+                    let shift = match op {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(ErrorCode::INVAL),
+                    };
+                    self.display_mode
+                        .set(Self::entry_mode(self.entry_direction(), shift));
+                    self.lcd_entry_mode(LCDStatus::Idle);
                     Ok(())
                 }
 
@@ -257,7 +517,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     /// `set_rows()` sets initializing parameters for the communication.
     ///
     /// Example:
-    ///  self.set_rows(0x00, 0x40, 0x00+col, 0x40+col);
+    ///  self.set_rows(0x00, 0x40, 0x14, 0x54);
     ///
     fn set_rows(&self, row0: u8, row1: u8, row2: u8, row3: u8) -> Result<(), ErrorCode> {
         self.row_offsets.map(|buffer| {
@@ -273,16 +533,48 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     /// each write operation, according to the HD44780 datasheet, figure 26,
     /// toggle that will be continued in the fired() function.
     ///
-    /// As argument, there is:
+    /// With `PulseMode::BusyWait` the whole pulse is generated here, and
+    /// only the execution delay that follows it uses the alarm.
+    ///
+    /// As arguments, there are:
     ///  - the status of the program after the process of pulse is done
+    ///  - the execution delay after the pulse, in microseconds
     ///
     /// Example:
-    ///  self.pulse(LCDStatus::Idle);
+    ///  self.pulse(LCDStatus::Idle, COMMAND_DELAY_US);
     ///
-    fn pulse(&self, after_pulse_status: LCDStatus) {
-        self.lcd_after_pulse_status.set(after_pulse_status);
+    fn pulse(&self, after_pulse_status: LCDStatus, delay_us: u32) {
+        match self.pulse_mode.get() {
+            PulseMode::BusyWait => {
+                self.busy_pulse();
+                self.set_delay(delay_us, after_pulse_status);
+            }
+            PulseMode::Alarm => {
+                self.lcd_after_pulse_status.set(after_pulse_status);
+                self.lcd_after_pulse_delay.set(delay_us);
+                self.en_pin.clear();
+                self.set_delay(ENABLE_PULSE_DELAY_US, LCDStatus::PulseLow);
+            }
+        }
+    }
+
+    /// `busy_pulse()` toggles the enable pin, busy-waiting for the width of
+    /// the pulse. The enable pin is expected to be low already.
+    fn busy_pulse(&self) {
+        self.en_pin.set();
+        self.busy_wait(ENABLE_PULSE_DELAY_US);
         self.en_pin.clear();
-        self.set_delay(500, LCDStatus::PulseLow);
+    }
+
+    /// `busy_wait()` spins for at least `us` microseconds.
+    fn busy_wait(&self, us: u32) {
+        let start = self.alarm.now();
+        // `start` may have been read at the very end of a tick, so that tick
+        // does not count.
+        let ticks = self.delay_ticks(us).wrapping_add(A::Ticks::from(1));
+        while self.alarm.now().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
     }
 
     /// `write_4_bits()` will either set or clear each data_pin according to the
@@ -296,6 +588,48 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     ///  self.write_4_bits(27, LCDStatus::Idle);
     ///
     fn write_4_bits(&self, value: u8, next_status: LCDStatus) {
+        self.set_data_pins(value);
+        self.pulse(next_status, COMMAND_DELAY_US);
+    }
+
+    /// `write_8_bits()` sends a whole byte, high nibble first, and waits
+    /// `delay_us` for it to be executed.
+    ///
+    /// As arguments, there are:
+    ///  - the value to be written
+    ///  - the next status of the program after writing the value
+    ///  - the status in which the low nibble is written, when the pulses
+    ///    are timed by the alarm
+    ///  - the execution delay after the byte, in microseconds
+    ///
+    /// Example:
+    ///  self.write_8_bits(value, LCDStatus::Idle, LCDStatus::Command, COMMAND_DELAY_US);
+    ///
+    fn write_8_bits(
+        &self,
+        value: u8,
+        next_status: LCDStatus,
+        low_nibble_status: LCDStatus,
+        delay_us: u32,
+    ) {
+        match self.pulse_mode.get() {
+            PulseMode::BusyWait => {
+                self.set_data_pins(value >> 4);
+                self.busy_pulse();
+                // enable cycle time between the two nibbles
+                self.busy_wait(ENABLE_PULSE_DELAY_US);
+                self.set_data_pins(value);
+                self.pulse(next_status, delay_us);
+            }
+            PulseMode::Alarm => {
+                self.set_data_pins(value >> 4);
+                self.pulse(low_nibble_status, COMMAND_DELAY_US);
+            }
+        }
+    }
+
+    /// `set_data_pins()` sets the data pins to the low 4 bits of `value`.
+    fn set_data_pins(&self, value: u8) {
         if (value >> 0) & 0x01 != 0 {
             self.data_4_pin.set();
         } else {
@@ -319,8 +653,6 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
         } else {
             self.data_7_pin.clear();
         }
-
-        self.pulse(next_status);
     }
 
     /// `continue_ops()` is called after an alarm is fired and continues to
@@ -372,7 +704,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             }
 
             LCDStatus::Begin0_1 => {
-                self.set_delay(200, LCDStatus::Begin1);
+                self.set_delay(FUNCTION_SET_DELAY_US, LCDStatus::Begin1);
             }
 
             LCDStatus::Begin1 => {
@@ -380,7 +712,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             }
 
             LCDStatus::Begin1_2 => {
-                self.set_delay(200, LCDStatus::Begin2);
+                self.set_delay(FUNCTION_SET_REPEAT_DELAY_US, LCDStatus::Begin2);
             }
 
             LCDStatus::Begin2 => {
@@ -388,7 +720,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             }
 
             LCDStatus::Begin2_3 => {
-                self.set_delay(500, LCDStatus::Begin3);
+                self.set_delay(FUNCTION_SET_REPEAT_DELAY_US, LCDStatus::Begin3);
             }
 
             LCDStatus::Begin3 => {
@@ -404,7 +736,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
                 );
             }
 
-            LCDStatus::Begin5 => self.set_delay(200, LCDStatus::Begin6),
+            LCDStatus::Begin5 => self.set_delay(FUNCTION_SET_DELAY_US, LCDStatus::Begin6),
 
             LCDStatus::Begin6 => {
                 self.lcd_command(
@@ -414,7 +746,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             }
 
             LCDStatus::Begin7 => {
-                self.set_delay(500, LCDStatus::Begin8);
+                self.set_delay(FUNCTION_SET_REPEAT_DELAY_US, LCDStatus::Begin8);
             }
 
             LCDStatus::Begin8 => {
@@ -444,25 +776,26 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
             }
 
             LCDStatus::Begin12 => {
-                self.display_mode
-                    .set(LCD_ENTRYLEFT | LCD_ENTRYSHIFTDECREMENT);
-                self.command_to_finish
-                    .set(LCD_ENTRYMODESET | self.display_mode.get());
                 self.begin_done.set(true);
-                self.lcd_command(self.command_to_finish.get(), LCDStatus::Idle);
+                self.lcd_entry_mode(LCDStatus::Idle);
+            }
+
+            LCDStatus::EntryMode => {
+                self.lcd_entry_mode(LCDStatus::Idle);
             }
 
             LCDStatus::Clear => {
-                self.set_delay(500, self.lcd_after_delay_status.get());
+                self.set_delay(CLEAR_DELAY_US, self.lcd_after_delay_status.get());
             }
 
             LCDStatus::Printing => {
-                self.write_4_bits(self.command_to_finish.get(), LCDStatus::Idle);
+                self.set_data_pins(self.command_to_finish.get());
+                self.pulse(LCDStatus::Idle, self.character_delay());
             }
 
             LCDStatus::PulseLow => {
                 self.en_pin.set();
-                self.set_delay(500, LCDStatus::PulseHigh);
+                self.set_delay(ENABLE_PULSE_DELAY_US, LCDStatus::PulseHigh);
             }
 
             LCDStatus::Command => {
@@ -474,7 +807,10 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
 
             LCDStatus::PulseHigh => {
                 self.en_pin.clear();
-                self.set_delay(500, self.lcd_after_pulse_status.get());
+                self.set_delay(
+                    self.lcd_after_pulse_delay.get(),
+                    self.lcd_after_pulse_status.get(),
+                );
             }
         }
     }
@@ -510,7 +846,7 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
         self.lcd_after_command_status.set(next_state);
         self.command_to_finish.set(value);
         self.rs_pin.clear();
-        self.write_4_bits(value >> 4, LCDStatus::Command);
+        self.write_8_bits(value, next_state, LCDStatus::Command, COMMAND_DELAY_US);
     }
 
     /// `lcd_clear()` clears the lcd and brings the cursor at position (0,0).
@@ -522,28 +858,109 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     ///  self.clear(LCDStatus::Idle);
     ///
     fn lcd_clear(&self, next_state: LCDStatus) {
+        self.cursor.set((0, 0));
+        self.wrap_pending.set(false);
         self.lcd_after_delay_status.set(next_state);
         self.lcd_command(LCD_CLEARDISPLAY, LCDStatus::Clear);
     }
 
+    /// `lcd_home()` brings the cursor at position (0,0) and returns the display
+    /// to its original position if it was shifted. Just like clearing the
+    /// display, this needs the longer delay before the next operation.
+    ///
+    /// As argument, there is:
+    ///  - the status of the program after returning home
+    ///
+    /// Example:
+    ///  self.lcd_home(LCDStatus::Idle);
+    ///
+    fn lcd_home(&self, next_state: LCDStatus) {
+        self.cursor.set((0, 0));
+        self.wrap_pending.set(false);
+        self.lcd_after_delay_status.set(next_state);
+        self.lcd_command(LCD_RETURNHOME, LCDStatus::Clear);
+    }
+
+    /// `lcd_entry_mode()` sends the entry mode flags to the LCD.
+    ///
+    /// As argument, there is:
+    ///  - the status of the program after setting the entry mode
+    ///
+    /// Example:
+    ///  self.lcd_entry_mode(LCDStatus::Idle);
+    ///
+    fn lcd_entry_mode(&self, next_state: LCDStatus) {
+        self.lcd_command(LCD_ENTRYMODESET | self.display_mode.get(), next_state);
+    }
+
+    /// `home()` moves the cursor at position (0,0) and undoes any display
+    /// shift, without clearing the display.
+    pub fn home(&self) -> Result<(), ErrorCode> {
+        self.screen_command(3, 0, 0)
+    }
+
+    /// `scroll_display_left()` shifts the whole display one position to the
+    /// left, without changing the content of the display memory.
+    pub fn scroll_display_left(&self) -> Result<(), ErrorCode> {
+        self.screen_command(4, 0, 0)
+    }
+
+    /// `scroll_display_right()` shifts the whole display one position to the
+    /// right, without changing the content of the display memory.
+    pub fn scroll_display_right(&self) -> Result<(), ErrorCode> {
+        self.screen_command(5, 0, 0)
+    }
+
+    /// `set_entry_direction()` sets the direction the next characters are
+    /// entered in.
+    pub fn set_entry_direction(&self, direction: EntryDirection) -> Result<(), ErrorCode> {
+        match direction {
+            EntryDirection::LeftToRight => self.screen_command(6, 0, 0),
+            EntryDirection::RightToLeft => self.screen_command(6, 1, 0),
+        }
+    }
+
+    /// `set_entry_shift()` enables or disables the shift of the whole
+    /// display as characters are entered.
+    pub fn set_entry_shift(&self, shift: bool) -> Result<(), ErrorCode> {
+        self.screen_command(7, shift as usize, 0)
+    }
+
     /// `set_delay()` sets an alarm and saved the next state after that.
     ///
     /// As argument, there are:
-    ///  - the duration of the alarm:
-    ///      - 10 means 100 ms
-    ///      - 100 means 10 ms
-    ///      - 500 means 2 ms
+    ///  - the duration of the alarm in microseconds. The duration is rounded
+    ///    up to the next alarm tick, and is at least one tick long, so the
+    ///    delay is never shorter than requested, whatever the frequency of
+    ///    the alarm is. It is also at least the minimum dt of the alarm.
     ///  - the status of the program after the alarm fires
     ///
+    /// If the alarm is not armed afterwards, the delay is polled from
+    /// deferred calls instead.
+    ///
     /// Example:
-    ///  self.set_delay(10, LCDStatus::Idle);
+    ///  self.set_delay(CLEAR_DELAY_US, LCDStatus::Idle);
     ///
-    fn set_delay(&self, timer: u32, next_status: LCDStatus) {
+    fn set_delay(&self, us: u32, next_status: LCDStatus) {
         self.lcd_status.set(next_status);
-        self.alarm.set_alarm(
-            self.alarm.now(),
-            A::Ticks::from(<A::Frequency>::frequency() / timer),
-        );
+        let now = self.alarm.now();
+        let ticks = cmp::max(self.delay_ticks(us), self.alarm.minimum_dt());
+        self.alarm.set_alarm(now, ticks);
+        if !self.alarm.is_armed() {
+            self.deferred_delay.set((now, ticks));
+            self.deferred_call.set();
+        }
+    }
+
+    /// `delay_ticks()` converts a delay to alarm ticks, rounding up, with a
+    /// minimum of one tick.
+    fn delay_ticks(&self, us: u32) -> A::Ticks {
+        let mut ticks = self.alarm.ticks_from_us(us);
+        // ticks_from_us() truncates, so round up to the next tick
+        if self.alarm.ticks_to_us(ticks) < us {
+            ticks = ticks.wrapping_add(A::Ticks::from(1));
+        }
+        cmp::max(ticks, A::Ticks::from(1))
     }
 
     /// `write_character()` will send the next character to be written on the
@@ -553,6 +970,14 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     /// - self.write_character();
     ///
     fn write_character(&self) {
+        // The LCD has to be moved to the next line first, and the character
+        // is written once it is.
+        if self.wrap_pending.get() {
+            let (col, row) = self.cursor.get();
+            self.set_cursor(col, row);
+            return;
+        }
+
         let offset = self.write_offset.get() as usize;
         let mut value = 0;
         self.write_buffer.map(|buffer| {
@@ -564,9 +989,52 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
         if self.write_len.get() == 0 {
             self.done_printing.set(true);
         }
+        self.advance_cursor();
         self.rs_pin.set();
         self.command_to_finish.set(value);
-        self.write_4_bits(value >> 4, LCDStatus::Printing);
+        self.write_8_bits(
+            value,
+            LCDStatus::Idle,
+            LCDStatus::Printing,
+            self.character_delay(),
+        );
+    }
+
+    /// `advance_cursor()` moves the tracked cursor past a printed character,
+    /// in the entry direction. Without entry shift, the cursor leaving the
+    /// display wraps to the first column of the next line, from the last
+    /// line to the first.
+    fn advance_cursor(&self) {
+        let (col, row) = self.cursor.get();
+        let width = self.width.get();
+        let right_to_left = self.entry_direction() == EntryDirection::RightToLeft;
+        let cursor = if self.display_mode.get() & LCD_ENTRYSHIFTINCREMENT != 0 {
+            // The display follows the cursor along the whole line of the
+            // display data RAM.
+            let col = col % DDRAM_LINE_LEN;
+            if right_to_left {
+                ((col + DDRAM_LINE_LEN - 1) % DDRAM_LINE_LEN, row)
+            } else {
+                ((col + 1) % DDRAM_LINE_LEN, row)
+            }
+        } else if right_to_left && col == 0 {
+            self.wrap_pending.set(true);
+            (width - 1, (row + 1) % self.height.get())
+        } else if right_to_left {
+            (col - 1, row)
+        } else if col >= width - 1 {
+            self.wrap_pending.set(true);
+            (0, (row + 1) % self.height.get())
+        } else {
+            (col + 1, row)
+        };
+        self.cursor.set(cursor);
+    }
+
+    /// `character_delay()` is the delay after a printed character, in
+    /// microseconds.
+    fn character_delay(&self) -> u32 {
+        cmp::max(COMMAND_DELAY_US, self.character_delay_us.get())
     }
 
     /// `set_cursor()` sends a command to the LCD display about the position for
@@ -580,6 +1048,8 @@ impl<'a, A: Alarm<'a>> HD44780<'a, A> {
     /// - self.set_cursor(16,2);
     ///
     fn set_cursor(&self, col: u8, row: u8) {
+        self.cursor.set((col, row));
+        self.wrap_pending.set(false);
         let mut value: u8 = 0;
         self.row_offsets.map(|buffer| {
             value = buffer[row as usize];
@@ -598,9 +1068,28 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for HD44780<'a, A> {
     }
 }
 
+impl<'a, A: Alarm<'a>> DeferredCallClient for HD44780<'a, A> {
+    /// `handle_deferred_call()` continues once a delay the alarm refused is
+    /// over, and polls it again otherwise.
+    fn handle_deferred_call(&self) {
+        self.deferred_delay.take().map(|(start, ticks)| {
+            if self.alarm.now().wrapping_sub(start) < ticks {
+                self.deferred_delay.set((start, ticks));
+                self.deferred_call.set();
+            } else {
+                self.continue_ops();
+            }
+        });
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
 impl<'a, A: Alarm<'a>> TextScreen<'a> for HD44780<'a, A> {
     fn get_size(&self) -> (usize, usize) {
-        (16, 2)
+        (self.width.get() as usize, self.height.get() as usize)
     }
 
     fn print(
@@ -608,7 +1097,7 @@ impl<'a, A: Alarm<'a>> TextScreen<'a> for HD44780<'a, A> {
         buffer: &'static mut [u8],
         len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.lcd_status.get() == LCDStatus::Idle {
+        if self.is_idle() {
             self.write_buffer.replace(buffer);
             self.write_len.replace(len as u8);
             self.write_buffer_len.replace(len as u8);
@@ -621,17 +1110,10 @@ impl<'a, A: Alarm<'a>> TextScreen<'a> for HD44780<'a, A> {
     }
 
     fn set_cursor(&self, x_position: usize, y_position: usize) -> Result<(), ErrorCode> {
-        if self.lcd_status.get() == LCDStatus::Idle {
-            let mut line_number: u8 = y_position as u8;
-            if line_number >= 4 {
-                line_number = 3;
-            }
-
-            if line_number >= self.num_lines.get() {
-                line_number = self.num_lines.get() - 1;
-            }
-
-            self.set_cursor(x_position as u8, line_number);
+        if x_position >= self.width.get() as usize || y_position >= self.height.get() as usize {
+            Err(ErrorCode::INVAL)
+        } else if self.is_idle() {
+            self.set_cursor(x_position as u8, y_position as u8);
             Ok(())
         } else {
             Err(ErrorCode::BUSY)
@@ -656,8 +1138,8 @@ impl<'a, A: Alarm<'a>> TextScreen<'a> for HD44780<'a, A> {
 
     fn display_on(&self) -> Result<(), ErrorCode> {
         if !self.initialized.get() {
-            if self.lcd_status.get() == LCDStatus::Idle {
-                self.set_delay(10, LCDStatus::Begin0);
+            if self.is_idle() {
+                self.set_delay(POWER_ON_DELAY_US, LCDStatus::Begin0);
                 Ok(())
             } else {
                 Err(ErrorCode::BUSY)
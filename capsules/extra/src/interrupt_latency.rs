@@ -0,0 +1,372 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Measures interrupt and alarm latency, to track kernel performance
+//! regressions on a test fixture.
+//!
+//! In GPIO mode, an output pin is wired to an interrupt input. Each trial
+//! reads the time reference, toggles the output, and reads the time
+//! reference again when the input interrupt reaches the capsule. A trial
+//! whose interrupt does not arrive within `TRIAL_TIMEOUT_MS`, for instance
+//! because the loopback is not wired, is counted as timed out and the next
+//! trial starts.
+//!
+//! In timer mode, each trial requests an alarm as soon as possible and
+//! measures the time from the request to the alarm callback, which includes
+//! the minimum dt of the alarm.
+//!
+//! The latencies are kept in ticks of the time reference: the number of
+//! trials measured and timed out, and the minimum, maximum and mean. Runs
+//! started by the kernel print them with `debug!` when they complete.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let latency = static_init!(
+//!     InterruptLatency<'static, SysCon, VirtualMuxAlarm<'static, SysCon>, GpioPin, GpioPin>,
+//!     InterruptLatency::new(
+//!         mtimer,
+//!         virtual_alarm,
+//!         &peripherals.gpio_port[0],
+//!         &peripherals.gpio_port[16],
+//!         board_kernel.create_grant(capsules_extra::interrupt_latency::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! virtual_alarm.set_alarm_client(latency);
+//! peripherals.gpio_port[16].set_client(latency);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: A run completed, with the number of trials measured and timed out.
+//!
+//! ### Command
+//!
+//! - `0`: Driver existence check.
+//! - `1`: Start a run of GPIO trials.
+//!   - `data1`: number of trials, from 1 to `MAX_TRIALS`.
+//! - `2`: Start a run of timer trials.
+//!   - `data1`: number of trials, from 1 to `MAX_TRIALS`.
+//! - `3`: Trials of the last run.
+//!   - Return: the number of trials measured and timed out.
+//! - `4`: Extremes of the last run.
+//!   - Return: the minimum and maximum latency, `FAIL` if no trial was
+//!     measured.
+//! - `5`: Mean of the last run.
+//!   - Return: the mean latency, `FAIL` if no trial was measured.
+
+use core::cell::Cell;
+
+use kernel::debug;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::gpio;
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks, Time};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::InterruptLatency as usize;
+
+/// Most trials of a run.
+pub const MAX_TRIALS: usize = 10_000;
+
+/// Time a GPIO trial waits for the input interrupt.
+pub const TRIAL_TIMEOUT_MS: u32 = 100;
+
+/// The kind of latency a run measures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// From toggling the output to the interrupt of the input.
+    Gpio,
+    /// From requesting an alarm to its callback.
+    Timer,
+}
+
+/// Latencies of the trials of a run, in ticks of the time reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    measured: u32,
+    timed_out: u32,
+    min: u32,
+    max: u32,
+    total: u64,
+}
+
+impl Statistics {
+    /// Adds the latency of a trial.
+    pub fn record(&mut self, ticks: u32) {
+        if self.measured == 0 {
+            self.min = ticks;
+            self.max = ticks;
+        } else {
+            self.min = self.min.min(ticks);
+            self.max = self.max.max(ticks);
+        }
+        self.measured += 1;
+        self.total += ticks as u64;
+    }
+
+    /// Counts a trial whose interrupt never came.
+    pub fn record_timeout(&mut self) {
+        self.timed_out += 1;
+    }
+
+    /// Number of trials measured.
+    pub fn measured(&self) -> u32 {
+        self.measured
+    }
+
+    /// Number of trials timed out.
+    pub fn timed_out(&self) -> u32 {
+        self.timed_out
+    }
+
+    pub fn min(&self) -> Option<u32> {
+        (self.measured > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<u32> {
+        (self.measured > 0).then_some(self.max)
+    }
+
+    /// Mean latency, rounded down.
+    pub fn mean(&self) -> Option<u32> {
+        (self.measured > 0).then(|| (self.total / self.measured as u64) as u32)
+    }
+}
+
+#[derive(Default)]
+pub struct App;
+
+pub struct InterruptLatency<'a, T: Time, A: Alarm<'a>, P: gpio::Pin, IP: gpio::InterruptPin<'a>> {
+    reference: &'a T,
+    alarm: &'a A,
+    output: &'a P,
+    input: &'a IP,
+    apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// The mode of the run in progress.
+    mode: OptionalCell<Mode>,
+    /// The app which started the run in progress, none for the kernel.
+    owner: OptionalCell<ProcessId>,
+    remaining: Cell<usize>,
+    /// The time reference when the trial in progress started.
+    start: Cell<T::Ticks>,
+    statistics: Cell<Statistics>,
+}
+
+impl<'a, T: Time, A: Alarm<'a>, P: gpio::Pin, IP: gpio::InterruptPin<'a>>
+    InterruptLatency<'a, T, A, P, IP>
+{
+    pub fn new(
+        reference: &'a T,
+        alarm: &'a A,
+        output: &'a P,
+        input: &'a IP,
+        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> Self {
+        output.make_output();
+        input.make_input();
+        Self {
+            reference,
+            alarm,
+            output,
+            input,
+            apps: grant,
+            mode: OptionalCell::empty(),
+            owner: OptionalCell::empty(),
+            remaining: Cell::new(0),
+            start: Cell::new(T::Ticks::from(0)),
+            statistics: Cell::new(Statistics::default()),
+        }
+    }
+
+    /// Starts a run of `trials` trials from the kernel, whose results are
+    /// printed when it completes.
+    pub fn start(&self, mode: Mode, trials: usize) -> Result<(), ErrorCode> {
+        self.start_run(mode, trials, None)
+    }
+
+    /// The latencies of the last run, or of the run in progress so far.
+    pub fn statistics(&self) -> Statistics {
+        self.statistics.get()
+    }
+
+    pub fn print_statistics(&self) {
+        let statistics = self.statistics.get();
+        debug!(
+            "latency: {} measured, {} timed out, min {:?} max {:?} mean {:?} ticks",
+            statistics.measured(),
+            statistics.timed_out(),
+            statistics.min(),
+            statistics.max(),
+            statistics.mean(),
+        );
+    }
+
+    fn start_run(
+        &self,
+        mode: Mode,
+        trials: usize,
+        owner: Option<ProcessId>,
+    ) -> Result<(), ErrorCode> {
+        if self.mode.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if trials == 0 || trials > MAX_TRIALS {
+            return Err(ErrorCode::INVAL);
+        }
+        self.mode.set(mode);
+        self.owner.insert(owner);
+        self.remaining.set(trials);
+        self.statistics.set(Statistics::default());
+        self.start_trial(mode);
+        Ok(())
+    }
+
+    fn start_trial(&self, mode: Mode) {
+        match mode {
+            Mode::Gpio => {
+                // Interrupts are only enabled during a trial, so a late
+                // edge of a trial which timed out is not taken for the
+                // next one.
+                self.input
+                    .enable_interrupts(gpio::InterruptEdge::EitherEdge);
+                self.alarm
+                    .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(TRIAL_TIMEOUT_MS));
+                self.start.set(self.reference.now());
+                self.output.toggle();
+            }
+            Mode::Timer => {
+                self.start.set(self.reference.now());
+                self.alarm
+                    .set_alarm(self.alarm.now(), self.alarm.minimum_dt());
+            }
+        }
+    }
+
+    // The trial in progress completed, with its latency if it did not time
+    // out. Starts the next trial, or completes the run.
+    fn trial_done(&self, mode: Mode, latency: Option<u32>) {
+        let mut statistics = self.statistics.get();
+        match latency {
+            Some(ticks) => statistics.record(ticks),
+            None => statistics.record_timeout(),
+        }
+        self.statistics.set(statistics);
+
+        let remaining = self.remaining.get() - 1;
+        self.remaining.set(remaining);
+        if remaining > 0 {
+            self.start_trial(mode);
+            return;
+        }
+
+        self.mode.clear();
+        match self.owner.take() {
+            Some(processid) => {
+                let _ = self.apps.enter(processid, |_, kernel_data| {
+                    kernel_data
+                        .schedule_upcall(
+                            0,
+                            (
+                                statistics.measured() as usize,
+                                statistics.timed_out() as usize,
+                                0,
+                            ),
+                        )
+                        .ok();
+                });
+            }
+            None => self.print_statistics(),
+        }
+    }
+
+    fn elapsed(&self) -> u32 {
+        self.reference
+            .now()
+            .wrapping_sub(self.start.get())
+            .into_u32()
+    }
+}
+
+impl<'a, T: Time, A: Alarm<'a>, P: gpio::Pin, IP: gpio::InterruptPin<'a>> gpio::Client
+    for InterruptLatency<'a, T, A, P, IP>
+{
+    fn fired(&self) {
+        // The time is read before anything else.
+        let latency = self.elapsed();
+        if self.mode.get() != Some(Mode::Gpio) {
+            return;
+        }
+        self.input.disable_interrupts();
+        let _ = self.alarm.disarm();
+        self.trial_done(Mode::Gpio, Some(latency));
+    }
+}
+
+impl<'a, T: Time, A: Alarm<'a>, P: gpio::Pin, IP: gpio::InterruptPin<'a>> AlarmClient
+    for InterruptLatency<'a, T, A, P, IP>
+{
+    fn alarm(&self) {
+        let latency = self.elapsed();
+        match self.mode.get() {
+            Some(Mode::Timer) => self.trial_done(Mode::Timer, Some(latency)),
+            Some(Mode::Gpio) => {
+                self.input.disable_interrupts();
+                self.trial_done(Mode::Gpio, None);
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a, T: Time, A: Alarm<'a>, P: gpio::Pin, IP: gpio::InterruptPin<'a>> SyscallDriver
+    for InterruptLatency<'a, T, A, P, IP>
+{
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let statistics = self.statistics.get();
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 | 2 => {
+                let mode = if command_num == 1 {
+                    Mode::Gpio
+                } else {
+                    Mode::Timer
+                };
+                self.start_run(mode, data1, Some(processid)).into()
+            }
+
+            3 => CommandReturn::success_u32_u32(statistics.measured(), statistics.timed_out()),
+
+            4 => match statistics.min().zip(statistics.max()) {
+                Some((min, max)) => CommandReturn::success_u32_u32(min, max),
+                None => CommandReturn::failure(ErrorCode::FAIL),
+            },
+
+            5 => match statistics.mean() {
+                Some(mean) => CommandReturn::success_u32(mean),
+                None => CommandReturn::failure(ErrorCode::FAIL),
+            },
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -18,7 +18,8 @@
 //! ### Command
 //!
 //! All commands are asynchronous, they return a one shot callback when done
-//! Only one command can be issued at a time.
+//! Only one command can be issued at a time. A command whose SPI transfer
+//! cannot be started returns the error of the SPI bus and has no callback.
 //!
 //! #### command num
 //! - `0`: Returns Ok(())
@@ -29,7 +30,8 @@
 //!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
 //! - `2`: Power On
 //!   - `data`: unused
-//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise,
+//!     `OFF` if the SPI bus is not configured yet.
 //! - `3`: Set Scale
 //!   - `data1`: 0, 1 or 2
 //!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
@@ -46,6 +48,50 @@
 //! - `7`: Read Temperature
 //!   - `data`: unused
 //!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
+//! - `8`: Enable the FIFO in stream mode
+//!   - `data1`: watermark level, 0 to 31
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise,
+//!     `INVAL` if the watermark is out of range.
+//! - `9`: Read FIFO
+//!   - `data`: unused
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise,
+//!     `OFF` if the FIFO is not enabled.
+//! - `10`: Disable the FIFO
+//!   - `data`: unused
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
+//! - `11`: Enable data ready events
+//!   - `data1`: 1 for enable, 0 for disable
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise,
+//!     `NOSUPPORT` if the board does not connect the INT2 pin.
+//! - `12`: Set the temperature offset
+//!   - `data1`: temperature in deg C that the sensor reads as 0, as an `i32`
+//!   - Return: `Ok(())`, no callback.
+//! - `13`: Enable or disable axes
+//!   - `data1`: bit 0 enables X, bit 1 enables Y, bit 2 enables Z
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise.
+//! - `14`: Set the output data rate and bandwidth
+//!   - `data1`: output data rate, 0 (95 Hz), 1 (190 Hz), 2 (380 Hz) or 3
+//!     (760 Hz)
+//!   - `data2`: bandwidth selection, 0 to 3 (manual page 29)
+//!   - Return: `Ok(())` if no other command is in progress, `BUSY` otherwise,
+//!     `INVAL` if a value is out of range.
+//! - `15`: Enable or disable recording samples in the history
+//!   - `data1`: 1 for enable, 0 for disable
+//!   - Return: `Ok(())`, no callback, `NOSUPPORT` if the board attached no
+//!     history.
+//! - `16`: Copy the history to the buffer allowed with allow `1`
+//!   - `data`: unused
+//!   - Return: the number of samples copied, no callback, `NOSUPPORT` if the
+//!     board attached no history.
+//!
+//! ### Allow ReadWrite
+//!
+//! - `0`: Buffer receiving the samples read from the FIFO by command `9`.
+//!   Each sample is 6 bytes, the X, Y and Z rotations as little endian
+//!   `i16`, the same raw values command `6` returns. Samples that do not fit
+//!   in the buffer are dropped.
+//! - `1`: Buffer receiving the history copied by command `16`, in the format
+//!   of `sample_history`.
 //!
 //! ### Subscribe
 //!
@@ -58,10 +104,97 @@
 //!     - `1` - 1 for is present, 0 for not present
 //!     - `6` - X rotation
 //!     - `7` - temperature in deg C
+//!     - `9` - number of samples copied to the allowed buffer
+//!     - `13` - enabled axes, in the format of the command
 //!   - 'data2`: depends on command
 //!     - `6` - Y rotation
+//!     - `9` - number of samples read from the FIFO
 //!   - 'data3`: depends on command
 //!     - `6` - Z rotation
+//! - `1`: Data ready callback, called for every new sample once command `11`
+//!   enabled data ready events
+//!   - 'data1`: X rotation
+//!   - 'data2`: Y rotation
+//!   - 'data3`: Z rotation
+//!
+//!   While the FIFO is enabled, it is called for every FIFO read started by
+//!   the watermark interrupt instead, with the same arguments as the done
+//!   callback of command `9`.
+//! - `2`: FIFO overrun callback, called before the done callback of a FIFO
+//!   read when the FIFO was full and samples were lost
+//!   - 'data1`: number of samples in the FIFO
+//!
+//! FIFO
+//! ----
+//!
+//! The 32 samples FIFO of the sensor can be used, so that a single SPI
+//! transfer reads all the samples collected since the last read.
+//! `configure_fifo()` selects any of the FIFO modes of the sensor, command `8`
+//! uses stream mode. A FIFO read drains up to the watermark level of
+//! samples, or the whole FIFO with a watermark of 0. The `NineDofClient`
+//! receives every sample read, oldest first. While the FIFO is enabled,
+//! command `6` and `read_gyroscope()` return the oldest sample of the FIFO.
+//!
+//! If the FIFO is enabled when data ready events are enabled with command
+//! `11`, the FIFO watermark and overrun flags are routed to INT2 instead of
+//! data ready, and every interrupt reads the FIFO for the process.
+//!
+//! Axes
+//! ----
+//!
+//! All three axes are enabled by default. Axes that are not needed can be
+//! disabled with `set_axes()` or command `13`, which saves power. The rate
+//! and power bits of CTRL_REG1 are kept as they are. The enabled axes only
+//! change once the sensor acknowledged the write.
+//!
+//! The rotation of a disabled axis is reported as `L3GD20_AXIS_DISABLED`, to
+//! the process and to the `NineDofClient`, for samples read with command `6`,
+//! for data ready events and for the FIFO. The samples copied to the buffer
+//! allowed by the process are the raw output of the sensor, in which the
+//! disabled axes hold meaningless values.
+//!
+//! History
+//! -------
+//!
+//! The board can attach a `SampleHistory` with `set_history()`. While
+//! recording is enabled, with `set_history_enabled()` or command `15`, every
+//! sample the driver reads, whether for command `6`, a data ready event, the
+//! FIFO or the `NineDof` interface, is also appended to the history. The
+//! sample is in the history before the `NineDofClient` gets it. Recording is
+//! disabled by default and costs nothing then.
+//!
+//! Data Rate
+//! ---------
+//!
+//! The sensor outputs samples at 95 Hz by default. `set_data_rate()` or
+//! command `14` selects one of the four output data rates of the sensor and
+//! the cut-off of its low pass filter. CTRL_REG1 is read first, so that the
+//! power and axis enable bits are kept. The rate is remembered once the
+//! sensor acknowledged the write, and `power_on()` writes it again, so it
+//! survives a power cycle of the sensor. The callback of command `14` holds
+//! the output data rate and the bandwidth in use.
+//!
+//! `configure()` must configure the SPI bus before `power_on()`, which
+//! returns `OFF` otherwise.
+//!
+//! Temperature
+//! -----------
+//!
+//! The temperature output of the sensor is a signed 8 bit value relative to
+//! an uncalibrated reference, decreasing by about 1 per deg C. The driver
+//! reports `offset - raw` deg C, where the offset (25 by default) is the
+//! temperature at which the sensor outputs 0. It is set with
+//! `set_temperature_offset()` or command `12`.
+//!
+//! Data Ready
+//! ----------
+//!
+//! If the board connects the INT2 pin of the sensor, command `11` routes the
+//! data ready signal to it and the driver reads every new sample as soon as
+//! it is available, instead of the process polling with command `6`. A data
+//! ready interrupt that arrives while another transfer is in progress is
+//! handled once that transfer is done. The interrupt is disabled when the
+//! process that enabled it no longer exists.
 //!
 //! Usage
 //! -----
@@ -107,15 +240,20 @@
 //!
 
 use core::cell::Cell;
+use core::cmp;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::gpio;
 use kernel::hil::sensors;
 use kernel::hil::spi;
+use kernel::processbuffer::WriteableProcessBuffer;
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
+use crate::sample_history::{SampleHistory, SensorKind};
 use capsules_core::driver;
+use capsules_core::single_owner::{SingleOwner, SingleOwnerCommands};
 pub const DRIVER_NUM: usize = driver::NUM::L3gd20 as usize;
 
 /* Identification number */
@@ -124,10 +262,24 @@ const L3GD20_WHO_AM_I: u8 = 0xD4;
 /* Registers addresses */
 const L3GD20_REG_WHO_AM_I: u8 = 0x0F;
 const L3GD20_REG_CTRL_REG1: u8 = 0x20;
+const L3GD20_CTRL_REG1_DR_SHIFT: u8 = 6;
+const L3GD20_CTRL_REG1_BW_SHIFT: u8 = 4;
+const L3GD20_CTRL_REG1_DR_BW: u8 = 0xF0;
+const L3GD20_CTRL_REG1_PD: u8 = 0x08;
+const L3GD20_CTRL_REG1_ZEN: u8 = 0x04;
+const L3GD20_CTRL_REG1_XEN: u8 = 0x02;
+const L3GD20_CTRL_REG1_YEN: u8 = 0x01;
+const L3GD20_CTRL_REG1_AXES: u8 =
+    L3GD20_CTRL_REG1_XEN | L3GD20_CTRL_REG1_YEN | L3GD20_CTRL_REG1_ZEN;
 const L3GD20_REG_CTRL_REG2: u8 = 0x21;
-// const L3GD20_REG_CTRL_REG3: u8 = 0x22;
+const L3GD20_REG_CTRL_REG3: u8 = 0x22;
+const L3GD20_CTRL_REG3_I2_DRDY: u8 = 0x08;
+const L3GD20_CTRL_REG3_I2_WTM: u8 = 0x04;
+const L3GD20_CTRL_REG3_I2_ORUN: u8 = 0x02;
 const L3GD20_REG_CTRL_REG4: u8 = 0x23;
 const L3GD20_REG_CTRL_REG5: u8 = 0x24;
+const L3GD20_CTRL_REG5_FIFO_EN: u8 = 0x40;
+const L3GD20_CTRL_REG5_HPEN: u8 = 0x10;
 // const L3GD20_REG_REFERENCE: u8 = 0x25;
 const L3GD20_REG_OUT_TEMP: u8 = 0x26;
 // const L3GD20_REG_STATUS_REG: u8 = 0x27;
@@ -139,9 +291,9 @@ const L3GD20_REG_OUT_Y_H: u8 = 0x2B;
 const L3GD20_REG_OUT_Z_L: u8 = 0x2C;
 const L3GD20_REG_OUT_Z_H: u8 = 0x2D;
 */
-/*
 const L3GD20_REG_FIFO_CTRL_REG: u8 = 0x2E;
 const L3GD20_REG_FIFO_SRC_REG: u8 = 0x2F;
+/*
 const L3GD20_REG_INT1_CFG: u8 = 0x30;
 const L3GD20_REG_INT1_SRC: u8 = 0x31;
 const L3GD20_REG_INT1_TSH_XH: u8 = 0x32;
@@ -153,8 +305,19 @@ const L3GD20_REG_INT1_TSH_ZL: u8 = 0x37;
 const L3GD20_REG_INT1_DURATION: u8 = 0x38;
 */
 
-pub const L3GD20_TX_SIZE: usize = 10;
-pub const L3GD20_RX_SIZE: usize = 10;
+/* FIFO_SRC_REG flags */
+const L3GD20_FIFO_SRC_OVRN: u8 = 0x40;
+const L3GD20_FIFO_SRC_EMPTY: u8 = 0x20;
+const L3GD20_FIFO_SRC_FSS: u8 = 0x1F;
+
+/// Number of samples the FIFO holds.
+pub const L3GD20_FIFO_SIZE: usize = 32;
+/// Size of an X, Y, Z sample.
+const L3GD20_SAMPLE_SIZE: usize = 6;
+
+/// Large enough to read the whole FIFO in one transfer.
+pub const L3GD20_TX_SIZE: usize = 1 + L3GD20_FIFO_SIZE * L3GD20_SAMPLE_SIZE;
+pub const L3GD20_RX_SIZE: usize = 1 + L3GD20_FIFO_SIZE * L3GD20_SAMPLE_SIZE;
 
 pub const TX_BUF_LEN: usize = L3GD20_TX_SIZE;
 pub const RX_BUF_LEN: usize = L3GD20_RX_SIZE;
@@ -164,6 +327,54 @@ const L3GD20_SCALE_250: isize = 875; /* 8.75 mdps/digit */
 const L3GD20_SCALE_500: isize = 1750; /* 17.5 mdps/digit */
 const L3GD20_SCALE_2000: isize = 7000; /* 70 mdps/digit */
 
+/// Rotation reported for a disabled axis. No reading scales to it.
+pub const L3GD20_AXIS_DISABLED: usize = isize::MIN as usize;
+
+/// Default temperature, in deg C, at which OUT_TEMP reads 0.
+const L3GD20_TEMPERATURE_OFFSET: i32 = 25;
+
+/// FIFO modes, the values of FIFO_CTRL_REG bits 7:5 (datasheet pg. 35).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FifoMode {
+    /// The FIFO is not used, only the latest sample is available.
+    Bypass = 0,
+    /// Samples are collected until the FIFO is full.
+    Fifo = 1,
+    /// Samples are collected, the oldest ones are overwritten once the FIFO
+    /// is full.
+    Stream = 2,
+    /// Stream mode until an interrupt event, then FIFO mode.
+    StreamToFifo = 3,
+    /// Bypass mode until an interrupt event, then stream mode.
+    BypassToStream = 4,
+}
+
+/// Output data rates, bits DR of CTRL_REG1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum L3gd20DataRate {
+    Hz95 = 0,
+    Hz190 = 1,
+    Hz380 = 2,
+    Hz760 = 3,
+}
+
+impl TryFrom<usize> for L3gd20DataRate {
+    type Error = ErrorCode;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(L3gd20DataRate::Hz95),
+            1 => Ok(L3gd20DataRate::Hz190),
+            2 => Ok(L3gd20DataRate::Hz380),
+            3 => Ok(L3gd20DataRate::Hz760),
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+}
+
+/// Highest bandwidth selection, bits BW of CTRL_REG1.
+pub const L3GD20_MAX_BANDWIDTH: u8 = 3;
+
 #[derive(Copy, Clone, PartialEq)]
 enum L3gd20Status {
     Idle,
@@ -174,6 +385,26 @@ enum L3gd20Status {
     SetScale,
     ReadXYZ,
     ReadTemperature,
+    SetFifoEnable,
+    SetFifoMode,
+    ReadFifoLevel,
+    ReadFifo,
+    SetDataReady,
+    ReadDataReady,
+    ReadCtrlReg1,
+    SetAxes,
+    ReadCtrlReg1Rate,
+    SetDataRate,
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Samples read from the FIFO
+    pub const FIFO: usize = 0;
+    /// Samples copied from the history
+    pub const HISTORY: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 2;
 }
 
 // #[derive(Clone, Copy, PartialEq)]
@@ -193,8 +424,26 @@ pub struct L3gd20Spi<'a, S: spi::SpiMasterDevice<'a>> {
     hpf_mode: Cell<u8>,
     hpf_divider: Cell<u8>,
     scale: Cell<u8>,
-    current_process: OptionalCell<ProcessId>,
-    grants: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// Axis enable bits of CTRL_REG1 acknowledged by the sensor.
+    axes: Cell<u8>,
+    /// Axis enable bits being written.
+    pending_axes: Cell<u8>,
+    /// Data rate and bandwidth bits of CTRL_REG1 acknowledged by the sensor.
+    data_rate: Cell<u8>,
+    /// Data rate and bandwidth bits being written.
+    pending_data_rate: Cell<u8>,
+    spi_configured: Cell<bool>,
+    history: OptionalCell<&'a SampleHistory<'a>>,
+    history_enabled: Cell<bool>,
+    fifo_mode: Cell<FifoMode>,
+    fifo_watermark: Cell<u8>,
+    fifo_overrun: Cell<bool>,
+    fifo_data_ready: Cell<bool>,
+    temperature_offset: Cell<i32>,
+    interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+    data_ready_process: OptionalCell<ProcessId>,
+    data_ready_pending: Cell<bool>,
+    owner: SingleOwner<App, UpcallCount<3>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     nine_dof_client: OptionalCell<&'a dyn sensors::NineDofClient>,
     temperature_client: OptionalCell<&'a dyn sensors::TemperatureClient>,
 }
@@ -202,9 +451,10 @@ pub struct L3gd20Spi<'a, S: spi::SpiMasterDevice<'a>> {
 impl<'a, S: spi::SpiMasterDevice<'a>> L3gd20Spi<'a, S> {
     pub fn new(
         spi: &'a S,
+        interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
         txbuffer: &'static mut [u8; L3GD20_TX_SIZE],
         rxbuffer: &'static mut [u8; L3GD20_RX_SIZE],
-        grants: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+        grants: Grant<App, UpcallCount<3>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     ) -> L3gd20Spi<'a, S> {
         // setup and return struct
         L3gd20Spi {
@@ -216,90 +466,416 @@ impl<'a, S: spi::SpiMasterDevice<'a>> L3gd20Spi<'a, S> {
             hpf_mode: Cell::new(0),
             hpf_divider: Cell::new(0),
             scale: Cell::new(0),
-            current_process: OptionalCell::empty(),
-            grants: grants,
+            axes: Cell::new(L3GD20_CTRL_REG1_AXES),
+            pending_axes: Cell::new(L3GD20_CTRL_REG1_AXES),
+            data_rate: Cell::new(0),
+            pending_data_rate: Cell::new(0),
+            spi_configured: Cell::new(false),
+            history: OptionalCell::empty(),
+            history_enabled: Cell::new(false),
+            fifo_mode: Cell::new(FifoMode::Bypass),
+            fifo_watermark: Cell::new(0),
+            fifo_overrun: Cell::new(false),
+            fifo_data_ready: Cell::new(false),
+            temperature_offset: Cell::new(L3GD20_TEMPERATURE_OFFSET),
+            interrupt_pin: interrupt_pin,
+            data_ready_process: OptionalCell::empty(),
+            data_ready_pending: Cell::new(false),
+            owner: SingleOwner::new(grants),
             nine_dof_client: OptionalCell::empty(),
             temperature_client: OptionalCell::empty(),
         }
     }
 
-    pub fn is_present(&self) -> bool {
-        self.status.set(L3gd20Status::IsPresent);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_WHO_AM_I | 0x80;
-            buf[1] = 0x00;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, self.rxbuffer.take(), 2);
-        });
-        false
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        if self.status.get() == L3gd20Status::Idle {
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
     }
 
-    pub fn power_on(&self) {
-        self.status.set(L3gd20Status::PowerOn);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_CTRL_REG1;
-            buf[1] = 0x0F;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, None, 2);
-        });
+    /// Start a transfer of `len` bytes for `status`, with the first bytes of
+    /// the tx buffer set by `fill`. If the transfer cannot be started, the
+    /// buffers are put back and the driver is idle again.
+    fn transfer<F: FnOnce(&mut [u8])>(
+        &self,
+        status: L3gd20Status,
+        len: usize,
+        read: bool,
+        fill: F,
+    ) -> Result<(), ErrorCode> {
+        let buf = self.txbuffer.take().ok_or(ErrorCode::BUSY)?;
+        fill(&mut buf[..len]);
+        let rxbuf = if read { self.rxbuffer.take() } else { None };
+        self.status.set(status);
+        self.spi
+            .read_write_bytes(buf, rxbuf, len)
+            .map_err(|(error, txbuf, rxbuf)| {
+                self.txbuffer.replace(txbuf);
+                if let Some(buf) = rxbuf {
+                    self.rxbuffer.replace(buf);
+                }
+                self.status.set(L3gd20Status::Idle);
+                error
+            })
     }
 
-    fn enable_hpf(&self, enabled: bool) {
-        self.status.set(L3gd20Status::EnableHpf);
-        self.hpf_enabled.set(enabled);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_CTRL_REG5;
-            buf[1] = u8::from(enabled) << 4;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, None, 2);
-        });
+    fn write_register(&self, status: L3gd20Status, reg: u8, value: u8) -> Result<(), ErrorCode> {
+        self.transfer(status, 2, false, |buf| {
+            buf[0] = reg;
+            buf[1] = value;
+        })
+    }
+
+    /// Read `len` bytes starting at register `reg`, auto-incrementing the
+    /// address for more than one byte.
+    fn read_registers(&self, status: L3gd20Status, reg: u8, len: usize) -> Result<(), ErrorCode> {
+        self.transfer(status, 1 + len, true, |buf| {
+            buf[0] = reg | 0x80 | if len > 1 { 0x40 } else { 0 };
+            buf[1..].fill(0);
+        })
+    }
+
+    pub fn is_present(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.read_registers(L3gd20Status::IsPresent, L3GD20_REG_WHO_AM_I, 1)
     }
 
-    fn set_hpf_parameters(&self, mode: u8, divider: u8) {
-        self.status.set(L3gd20Status::SetHpfParameters);
+    /// Power on the sensor with the data rate and axes set before. The SPI
+    /// bus must be configured with `configure()` first.
+    pub fn power_on(&self) -> Result<(), ErrorCode> {
+        if !self.spi_configured.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.check_idle()?;
+        self.write_register(
+            L3gd20Status::PowerOn,
+            L3GD20_REG_CTRL_REG1,
+            self.data_rate.get() | L3GD20_CTRL_REG1_PD | self.axes.get(),
+        )
+    }
+
+    /// Set the output data rate and the bandwidth selection. CTRL_REG1 is
+    /// read first, so that only the data rate and bandwidth bits change.
+    pub fn set_data_rate(&self, odr: L3gd20DataRate, bandwidth: u8) -> Result<(), ErrorCode> {
+        if bandwidth > L3GD20_MAX_BANDWIDTH {
+            return Err(ErrorCode::INVAL);
+        }
+        self.check_idle()?;
+        self.pending_data_rate
+            .set((odr as u8) << L3GD20_CTRL_REG1_DR_SHIFT | bandwidth << L3GD20_CTRL_REG1_BW_SHIFT);
+        self.read_registers(L3gd20Status::ReadCtrlReg1Rate, L3GD20_REG_CTRL_REG1, 1)
+    }
+
+    /// The output data rate and bandwidth selection in use.
+    pub fn data_rate(&self) -> (L3gd20DataRate, u8) {
+        let value = self.data_rate.get();
+        let odr = L3gd20DataRate::try_from((value >> L3GD20_CTRL_REG1_DR_SHIFT) as usize)
+            .unwrap_or(L3gd20DataRate::Hz95);
+        (
+            odr,
+            (value >> L3GD20_CTRL_REG1_BW_SHIFT) & L3GD20_MAX_BANDWIDTH,
+        )
+    }
+
+    /// Enable or disable each axis. CTRL_REG1 is read first, so that only
+    /// the axis enable bits change.
+    pub fn set_axes(&self, x: bool, y: bool, z: bool) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        let mut axes = 0;
+        if x {
+            axes |= L3GD20_CTRL_REG1_XEN;
+        }
+        if y {
+            axes |= L3GD20_CTRL_REG1_YEN;
+        }
+        if z {
+            axes |= L3GD20_CTRL_REG1_ZEN;
+        }
+        self.pending_axes.set(axes);
+        self.read_registers(L3gd20Status::ReadCtrlReg1, L3GD20_REG_CTRL_REG1, 1)
+    }
+
+    /// Whether the X, Y and Z axes are enabled.
+    pub fn enabled_axes(&self) -> (bool, bool, bool) {
+        let axes = self.axes.get();
+        (
+            axes & L3GD20_CTRL_REG1_XEN != 0,
+            axes & L3GD20_CTRL_REG1_YEN != 0,
+            axes & L3GD20_CTRL_REG1_ZEN != 0,
+        )
+    }
+
+    /// Attach the history that samples are recorded in once enabled.
+    pub fn set_history(&self, history: &'a SampleHistory<'a>) {
+        self.history.set(history);
+    }
+
+    pub fn history(&self) -> Option<&'a SampleHistory<'a>> {
+        self.history.get()
+    }
+
+    /// Start or stop recording the samples in the history.
+    pub fn set_history_enabled(&self, enabled: bool) -> Result<(), ErrorCode> {
+        if self.history.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.history_enabled.set(enabled);
+        Ok(())
+    }
+
+    fn record_sample(&self, sample: &[u8]) {
+        if self.history_enabled.get() {
+            self.history.map(|history| {
+                history.push(SensorKind::Gyroscope, raw_sample(sample));
+            });
+        }
+    }
+
+    /// Copy the history to the buffer allowed by `process_id`. Returns the
+    /// number of samples copied.
+    fn copy_history(&self, process_id: ProcessId) -> Result<usize, ErrorCode> {
+        let history = self.history.get().ok_or(ErrorCode::NOSUPPORT)?;
+        self.owner
+            .grant()
+            .enter(process_id, |_app, kernel_data| {
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::HISTORY)
+                    .and_then(|buffer| buffer.mut_enter(|dest| history.copy_to_process(dest)))
+                    .unwrap_or(0)
+            })
+            .map_err(ErrorCode::from)
+    }
+
+    /// Enabled axes in the format of command 13.
+    fn enabled_axes_mask(&self) -> usize {
+        let (x, y, z) = self.enabled_axes();
+        usize::from(x) | usize::from(y) << 1 | usize::from(z) << 2
+    }
+
+    /// Raw X, Y and Z rotations of the sample at the start of `sample`,
+    /// `None` for the disabled axes.
+    fn sample_axes(&self, sample: &[u8]) -> [Option<i16>; 3] {
+        let (x, y, z) = self.enabled_axes();
+        let [raw_x, raw_y, raw_z] = raw_sample(sample);
+        [
+            Some(raw_x).filter(|_| x),
+            Some(raw_y).filter(|_| y),
+            Some(raw_z).filter(|_| z),
+        ]
+    }
+
+    /// Rotations of the sample at the start of `sample` for the process.
+    fn sample_upcall(&self, sample: &[u8]) -> (usize, usize, usize) {
+        let [x, y, z] = self
+            .sample_axes(sample)
+            .map(|axis| axis.map_or(L3GD20_AXIS_DISABLED, |raw| raw as usize));
+        (x, y, z)
+    }
+
+    fn enable_hpf(&self, enabled: bool) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        let previous = self.hpf_enabled.replace(enabled);
+        self.write_register(
+            L3gd20Status::EnableHpf,
+            L3GD20_REG_CTRL_REG5,
+            self.ctrl_reg5(),
+        )
+        .map_err(|error| {
+            self.hpf_enabled.set(previous);
+            error
+        })
+    }
+
+    fn set_hpf_parameters(&self, mode: u8, divider: u8) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.write_register(
+            L3gd20Status::SetHpfParameters,
+            L3GD20_REG_CTRL_REG2,
+            (mode & 0x03) << 4 | (divider & 0x0F),
+        )?;
         self.hpf_mode.set(mode);
         self.hpf_divider.set(divider);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_CTRL_REG2;
-            buf[1] = (mode & 0x03) << 4 | (divider & 0x0F);
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, None, 2);
-        });
+        Ok(())
     }
 
-    fn set_scale(&self, scale: u8) {
-        self.status.set(L3gd20Status::SetScale);
+    fn set_scale(&self, scale: u8) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.write_register(
+            L3gd20Status::SetScale,
+            L3GD20_REG_CTRL_REG4,
+            (scale & 0x03) << 4,
+        )?;
         self.scale.set(scale);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_CTRL_REG4;
-            buf[1] = (scale & 0x03) << 4;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, None, 2);
-        });
+        Ok(())
     }
 
-    fn read_xyz(&self) {
-        self.status.set(L3gd20Status::ReadXYZ);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_OUT_X_L | 0x80 | 0x40;
-            buf[1] = 0x00;
-            buf[2] = 0x00;
-            buf[3] = 0x00;
-            buf[4] = 0x00;
-            buf[5] = 0x00;
-            buf[6] = 0x00;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, self.rxbuffer.take(), 7);
-        });
+    fn read_xyz(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.read_registers(
+            L3gd20Status::ReadXYZ,
+            L3GD20_REG_OUT_X_L,
+            L3GD20_SAMPLE_SIZE,
+        )
+    }
+
+    fn read_data_ready(&self) -> Result<(), ErrorCode> {
+        if self.fifo_enabled() {
+            // The interrupt is the FIFO watermark.
+            self.start_fifo_read(true)
+        } else {
+            self.read_registers(
+                L3gd20Status::ReadDataReady,
+                L3GD20_REG_OUT_X_L,
+                L3GD20_SAMPLE_SIZE,
+            )
+        }
+    }
+
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.read_registers(L3gd20Status::ReadTemperature, L3GD20_REG_OUT_TEMP, 1)
+    }
+
+    /// Set the temperature, in deg C, at which the sensor's temperature
+    /// output reads 0.
+    pub fn set_temperature_offset(&self, offset: i32) {
+        self.temperature_offset.set(offset);
+    }
+
+    /// Value of CTRL_REG5 for the current high pass filter and FIFO
+    /// settings.
+    fn ctrl_reg5(&self) -> u8 {
+        let mut value = 0;
+        if self.hpf_enabled.get() {
+            value |= L3GD20_CTRL_REG5_HPEN;
+        }
+        if self.fifo_enabled() {
+            value |= L3GD20_CTRL_REG5_FIFO_EN;
+        }
+        value
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fifo_mode.get() != FifoMode::Bypass
+    }
+
+    /// Set the FIFO mode and its watermark level (0 to 31), the number of
+    /// samples that sets the watermark flag of the FIFO. The FIFO is enabled
+    /// in any mode other than `Bypass`.
+    pub fn configure_fifo(&self, mode: FifoMode, watermark: u8) -> Result<(), ErrorCode> {
+        if watermark as usize >= L3GD20_FIFO_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        self.check_idle()?;
+        let previous_mode = self.fifo_mode.replace(mode);
+        let previous_watermark = self.fifo_watermark.replace(watermark);
+        self.write_register(
+            L3gd20Status::SetFifoEnable,
+            L3GD20_REG_CTRL_REG5,
+            self.ctrl_reg5(),
+        )
+        .map_err(|error| {
+            self.fifo_mode.set(previous_mode);
+            self.fifo_watermark.set(previous_watermark);
+            error
+        })
+    }
+
+    /// Enable the FIFO in stream mode.
+    pub fn enable_fifo(&self, watermark: u8) -> Result<(), ErrorCode> {
+        self.configure_fifo(FifoMode::Stream, watermark)
+    }
+
+    /// Disable the FIFO, going back to reading one sample at a time.
+    pub fn disable_fifo(&self) -> Result<(), ErrorCode> {
+        self.configure_fifo(FifoMode::Bypass, 0)
+    }
+
+    fn set_fifo_mode(&self) -> Result<(), ErrorCode> {
+        self.write_register(
+            L3gd20Status::SetFifoMode,
+            L3GD20_REG_FIFO_CTRL_REG,
+            (self.fifo_mode.get() as u8) << 5 | self.fifo_watermark.get(),
+        )
+    }
+
+    /// Read up to the watermark level of samples from the FIFO, or all of
+    /// them with a watermark of 0. The number of samples is read first, then
+    /// the samples in a single transfer.
+    pub fn read_fifo(&self) -> Result<(), ErrorCode> {
+        if !self.fifo_enabled() {
+            return Err(ErrorCode::OFF);
+        }
+        self.check_idle()?;
+        self.start_fifo_read(false)
+    }
+
+    /// Start a FIFO read, for the data ready process if `data_ready`.
+    fn start_fifo_read(&self, data_ready: bool) -> Result<(), ErrorCode> {
+        self.fifo_data_ready.set(data_ready);
+        self.fifo_overrun.set(false);
+        self.read_registers(L3gd20Status::ReadFifoLevel, L3GD20_REG_FIFO_SRC_REG, 1)
     }
 
-    fn read_temperature(&self) {
-        self.status.set(L3gd20Status::ReadTemperature);
-        self.txbuffer.take().map(|buf| {
-            buf[0] = L3GD20_REG_OUT_TEMP | 0x80;
-            buf[1] = 0x00;
-            // TODO verify SPI return value
-            let _ = self.spi.read_write_bytes(buf, self.rxbuffer.take(), 2);
+    fn read_fifo_samples(&self, samples: usize) -> Result<(), ErrorCode> {
+        // auto-increment wraps from OUT_Z_H to OUT_X_L in FIFO mode
+        self.read_registers(
+            L3gd20Status::ReadFifo,
+            L3GD20_REG_OUT_X_L,
+            samples * L3GD20_SAMPLE_SIZE,
+        )
+    }
+
+    /// Route the data ready signal to the INT2 pin and read every new sample
+    /// for `process_id`, or stop doing so.
+    fn set_data_ready(&self, enabled: bool, process_id: ProcessId) -> Result<(), ErrorCode> {
+        let pin = self.interrupt_pin.ok_or(ErrorCode::NOSUPPORT)?;
+        self.check_idle()?;
+        self.set_interrupt_routing(enabled)?;
+        if enabled {
+            self.data_ready_process.set(process_id);
+            pin.make_input();
+            pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        } else {
+            self.data_ready_process.clear();
+            pin.disable_interrupts();
+        }
+        Ok(())
+    }
+
+    /// Write CTRL_REG3 to route data ready, or the FIFO watermark and
+    /// overrun flags if the FIFO is enabled, to INT2 if `enabled`.
+    fn set_interrupt_routing(&self, enabled: bool) -> Result<(), ErrorCode> {
+        let value = if !enabled {
+            0
+        } else if self.fifo_enabled() {
+            L3GD20_CTRL_REG3_I2_WTM | L3GD20_CTRL_REG3_I2_ORUN
+        } else {
+            L3GD20_CTRL_REG3_I2_DRDY
+        };
+        self.write_register(L3gd20Status::SetDataReady, L3GD20_REG_CTRL_REG3, value)
+    }
+
+    /// Scale a raw rotation to the unit of the `NineDofClient`.
+    fn scale_rotation(&self, raw: i16) -> usize {
+        // compute using only integers
+        let scale = match self.scale.get() {
+            0 => L3GD20_SCALE_250,
+            1 => L3GD20_SCALE_500,
+            _ => L3GD20_SCALE_2000,
+        };
+        (raw as isize * scale / 100000) as usize
+    }
+
+    /// Send the sample at the start of `sample` to the `NineDofClient`.
+    fn nine_dof_callback(&self, sample: &[u8]) {
+        self.nine_dof_client.map(|client| {
+            let [x, y, z] = self
+                .sample_axes(sample)
+                .map(|axis| axis.map_or(L3GD20_AXIS_DISABLED, |raw| self.scale_rotation(raw)));
+            client.callback(x, y, z);
         });
     }
 
@@ -308,224 +884,342 @@ impl<'a, S: spi::SpiMasterDevice<'a>> L3gd20Spi<'a, S> {
             spi::ClockPolarity::IdleHigh,
             spi::ClockPhase::SampleTrailing,
             1_000_000,
-        )
+        )?;
+        self.spi_configured.set(true);
+        Ok(())
     }
 }
 
-impl<'a, S: spi::SpiMasterDevice<'a>> SyscallDriver for L3gd20Spi<'a, S> {
-    fn command(
+impl<'a, S: spi::SpiMasterDevice<'a>> SingleOwnerCommands for L3gd20Spi<'a, S> {
+    fn owner_command(
         &self,
         command_num: usize,
         data1: usize,
         data2: usize,
         process_id: ProcessId,
     ) -> CommandReturn {
-        if command_num == 0 {
-            return CommandReturn::success();
-        }
-
-        let match_or_empty_or_nonexistent = self.current_process.map_or(true, |current_process| {
-            self.grants
-                .enter(current_process, |_, _| current_process == process_id)
-                .unwrap_or(true)
-        });
-
-        if match_or_empty_or_nonexistent {
-            self.current_process.set(process_id);
-        } else {
-            return CommandReturn::failure(ErrorCode::RESERVE);
-        }
-
         match command_num {
             // Check is sensor is correctly connected
-            1 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    self.is_present();
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            1 => self.is_present().into(),
             // Power On
-            2 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    self.power_on();
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            2 => self.power_on().into(),
             // Set Scale
-            3 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    let scale = data1 as u8;
-                    self.set_scale(scale);
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            3 => self.set_scale(data1 as u8).into(),
             // Enable High Pass Filter
-            4 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    let mode = data1 as u8;
-                    let divider = data2 as u8;
-                    self.set_hpf_parameters(mode, divider);
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            4 => self.enable_hpf(data1 == 1).into(),
             // Set High Pass Filter Mode and Divider
-            5 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    let enabled = data1 == 1;
-                    self.enable_hpf(enabled);
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            5 => self.set_hpf_parameters(data1 as u8, data2 as u8).into(),
             // Read XYZ
-            6 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    self.read_xyz();
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
-            }
+            6 => self.read_xyz().into(),
             // Read Temperature
-            7 => {
-                if self.status.get() == L3gd20Status::Idle {
-                    self.read_temperature();
-                    CommandReturn::success()
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
-                }
+            7 => self.read_temperature().into(),
+            // Enable FIFO
+            8 => match u8::try_from(data1) {
+                Ok(watermark) => self.enable_fifo(watermark).into(),
+                Err(_) => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // Read FIFO
+            9 => self.read_fifo().into(),
+            // Disable FIFO
+            10 => self.disable_fifo().into(),
+            // Enable data ready events
+            11 => self.set_data_ready(data1 == 1, process_id).into(),
+            // Set Temperature Offset
+            12 => {
+                self.set_temperature_offset(data1 as i32);
+                CommandReturn::success()
             }
+            // Enable Axes
+            13 => self
+                .set_axes(data1 & 0x01 != 0, data1 & 0x02 != 0, data1 & 0x04 != 0)
+                .into(),
+            // Set Data Rate
+            14 => match (L3gd20DataRate::try_from(data1), u8::try_from(data2)) {
+                (Ok(odr), Ok(bandwidth)) => self.set_data_rate(odr, bandwidth).into(),
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // Enable History
+            15 => self.set_history_enabled(data1 == 1).into(),
+            // Copy History
+            16 => match self.copy_history(process_id) {
+                Ok(count) => CommandReturn::success_u32(count as u32),
+                Err(error) => CommandReturn::failure(error),
+            },
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 
+    fn is_busy(&self) -> bool {
+        self.status.get() != L3gd20Status::Idle
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice<'a>> SyscallDriver for L3gd20Spi<'a, S> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn {
+        self.owner
+            .command(self, command_num, data1, data2, process_id)
+    }
+
     fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
-        self.grants.enter(processid, |_, _| {})
+        self.owner.allocate_grant(processid)
+    }
+}
+
+/// Number of samples stored in the FIFO, from the value of FIFO_SRC_REG.
+fn fifo_level(src: u8) -> usize {
+    if src & L3GD20_FIFO_SRC_EMPTY != 0 {
+        0
+    } else if src & L3GD20_FIFO_SRC_OVRN != 0 {
+        L3GD20_FIFO_SIZE
+    } else {
+        (src & L3GD20_FIFO_SRC_FSS) as usize
     }
 }
 
+/// Raw X, Y and Z rotations of the sample at the start of `sample`.
+fn raw_sample(sample: &[u8]) -> [i16; 3] {
+    [
+        i16::from_le_bytes([sample[0], sample[1]]),
+        i16::from_le_bytes([sample[2], sample[3]]),
+        i16::from_le_bytes([sample[4], sample[5]]),
+    ]
+}
+
 impl<'a, S: spi::SpiMasterDevice<'a>> spi::SpiMasterClient for L3gd20Spi<'a, S> {
     fn read_write_done(
         &self,
         write_buffer: &'static mut [u8],
         read_buffer: Option<&'static mut [u8]>,
         len: usize,
-        _status: Result<(), ErrorCode>,
+        result: Result<(), ErrorCode>,
     ) {
-        self.current_process.map(|proc_id| {
-            let _result = self.grants.enter(proc_id, |_app, upcalls| {
-                self.status.set(match self.status.get() {
-                    L3gd20Status::IsPresent => {
-                        let present = if let Some(ref buf) = read_buffer {
-                            buf[1] == L3GD20_WHO_AM_I
-                        } else {
-                            false
-                        };
-                        upcalls
-                            .schedule_upcall(0, (1, usize::from(present), 0))
-                            .ok();
-                        L3gd20Status::Idle
-                    }
-
-                    L3gd20Status::ReadXYZ => {
-                        let mut x: usize = 0;
-                        let mut y: usize = 0;
-                        let mut z: usize = 0;
-                        let values = if let Some(ref buf) = read_buffer {
-                            if len >= 7 {
-                                self.nine_dof_client.map(|client| {
-                                    // compute using only integers
-                                    let scale = match self.scale.get() {
-                                        0 => L3GD20_SCALE_250,
-                                        1 => L3GD20_SCALE_500,
-                                        _ => L3GD20_SCALE_2000,
-                                    };
-                                    let x: usize =
-                                        ((buf[1] as i16 | ((buf[2] as i16) << 8)) as isize * scale
-                                            / 100000)
-                                            as usize;
-                                    let y: usize =
-                                        ((buf[3] as i16 | ((buf[4] as i16) << 8)) as isize * scale
-                                            / 100000)
-                                            as usize;
-                                    let z: usize =
-                                        ((buf[5] as i16 | ((buf[6] as i16) << 8)) as isize * scale
-                                            / 100000)
-                                            as usize;
-                                    client.callback(x, y, z);
-                                });
-                                // actual computation is this one
-
-                                x = (buf[1] as i16 | ((buf[2] as i16) << 8)) as usize;
-                                y = (buf[3] as i16 | ((buf[4] as i16) << 8)) as usize;
-                                z = (buf[5] as i16 | ((buf[6] as i16) << 8)) as usize;
-                                true
-                            } else {
-                                self.nine_dof_client.map(|client| {
-                                    client.callback(0, 0, 0);
-                                });
-                                false
-                            }
-                        } else {
-                            false
-                        };
-                        if values {
-                            upcalls.schedule_upcall(0, (x, y, z)).ok();
-                        } else {
-                            upcalls.schedule_upcall(0, (0, 0, 0)).ok();
-                        }
-                        L3gd20Status::Idle
-                    }
-
-                    L3gd20Status::ReadTemperature => {
-                        let mut temperature = 0;
-                        let value = if let Some(ref buf) = read_buffer {
-                            if len >= 2 {
-                                temperature = buf[1] as i32;
-                                self.temperature_client.map(|client| {
-                                    client.callback(Ok(temperature * 100));
-                                });
-                                true
-                            } else {
-                                self.temperature_client.map(|client| {
-                                    client.callback(Err(ErrorCode::FAIL));
-                                });
-                                false
-                            }
-                        } else {
-                            false
-                        };
-                        if value {
-                            upcalls
-                                .schedule_upcall(0, (temperature as usize, 0, 0))
-                                .ok();
-                        } else {
-                            upcalls.schedule_upcall(0, (0, 0, 0)).ok();
-                        }
-                        L3gd20Status::Idle
-                    }
-
-                    _ => {
-                        upcalls.schedule_upcall(0, (0, 0, 0)).ok();
-                        L3gd20Status::Idle
-                    }
-                });
-            });
-        });
+        let status = self.status.get();
+        // Only a successful transfer returns data from the sensor.
+        let data: &[u8] = match (result, read_buffer.as_deref()) {
+            (Ok(()), Some(buf)) => &buf[..cmp::min(len, buf.len())],
+            _ => &[],
+        };
+
+        let fifo_samples = if status == L3gd20Status::ReadFifoLevel {
+            let src = data.get(1).copied().unwrap_or(L3GD20_FIFO_SRC_EMPTY);
+            self.fifo_overrun.set(src & L3GD20_FIFO_SRC_OVRN != 0);
+            match self.fifo_watermark.get() {
+                0 => fifo_level(src),
+                watermark => cmp::min(fifo_level(src), watermark as usize),
+            }
+        } else {
+            0
+        };
+
+        let ctrl_reg1 = if matches!(
+            status,
+            L3gd20Status::ReadCtrlReg1 | L3gd20Status::ReadCtrlReg1Rate
+        ) {
+            data.get(1).copied()
+        } else {
+            None
+        };
+
+        let data_ready = match status {
+            L3gd20Status::ReadDataReady => true,
+            L3gd20Status::ReadFifoLevel | L3gd20Status::ReadFifo => self.fifo_data_ready.get(),
+            _ => false,
+        };
+        let (upcall_num, process) = if data_ready {
+            (1, self.data_ready_process.get())
+        } else {
+            (0, self.owner.owner())
+        };
+
+        // Decode the result for the kernel clients and for the process.
+        let upcall = match status {
+            L3gd20Status::IsPresent => {
+                let present = data.get(1) == Some(&L3GD20_WHO_AM_I);
+                (1, usize::from(present), 0)
+            }
+
+            L3gd20Status::ReadXYZ => {
+                if data.len() > L3GD20_SAMPLE_SIZE {
+                    self.record_sample(&data[1..]);
+                    self.nine_dof_callback(&data[1..]);
+                    // actual computation is this one
+                    self.sample_upcall(&data[1..])
+                } else {
+                    self.nine_dof_client.map(|client| {
+                        client.callback(0, 0, 0);
+                    });
+                    (0, 0, 0)
+                }
+            }
+
+            L3gd20Status::ReadDataReady => {
+                if data.len() > L3GD20_SAMPLE_SIZE {
+                    self.record_sample(&data[1..]);
+                    self.sample_upcall(&data[1..])
+                } else {
+                    (0, 0, 0)
+                }
+            }
+
+            L3gd20Status::SetDataReady => {
+                // The signal may already be high, which gives no edge.
+                let high = self.interrupt_pin.map_or(false, |pin| pin.read());
+                if high && self.data_ready_process.is_some() {
+                    self.data_ready_pending.set(true);
+                }
+                (0, 0, 0)
+            }
+
+            L3gd20Status::ReadTemperature => match data.get(1) {
+                Some(&raw) => {
+                    // The output decreases as the temperature rises.
+                    let temperature = self.temperature_offset.get() - (raw as i8) as i32;
+                    self.temperature_client.map(|client| {
+                        client.callback(Ok(temperature * 100));
+                    });
+                    (temperature as usize, 0, 0)
+                }
+                None => {
+                    self.temperature_client.map(|client| {
+                        client.callback(Err(ErrorCode::FAIL));
+                    });
+                    (0, 0, 0)
+                }
+            },
+
+            L3gd20Status::ReadFifo => {
+                let samples = data.len().saturating_sub(1) / L3GD20_SAMPLE_SIZE;
+                let samples_data = data.get(1..1 + samples * L3GD20_SAMPLE_SIZE).unwrap_or(&[]);
+                for sample in samples_data.chunks_exact(L3GD20_SAMPLE_SIZE) {
+                    self.record_sample(sample);
+                    self.nine_dof_callback(sample);
+                }
+                (self.copy_to_process(process, samples_data), samples, 0)
+            }
+
+            L3gd20Status::ReadCtrlReg1 | L3gd20Status::SetAxes => {
+                if status == L3gd20Status::SetAxes && result.is_ok() {
+                    self.axes.set(self.pending_axes.get());
+                }
+                (self.enabled_axes_mask(), 0, 0)
+            }
+
+            L3gd20Status::ReadCtrlReg1Rate | L3gd20Status::SetDataRate => {
+                if status == L3gd20Status::SetDataRate && result.is_ok() {
+                    self.data_rate.set(self.pending_data_rate.get());
+                }
+                let (odr, bandwidth) = self.data_rate();
+                (odr as usize, bandwidth as usize, 0)
+            }
+
+            _ => (0, 0, 0),
+        };
+
         self.txbuffer.replace(write_buffer);
         if let Some(buf) = read_buffer {
             self.rxbuffer.replace(buf);
         }
+
+        // Operations made of two transfers continue here. If the second
+        // transfer cannot start, the operation ends with the first one.
+        let next = match status {
+            L3gd20Status::SetFifoEnable if result.is_ok() => Some(self.set_fifo_mode()),
+            L3gd20Status::SetFifoMode if result.is_ok() && self.data_ready_process.is_some() => {
+                // The FIFO changes what INT2 signals.
+                Some(self.set_interrupt_routing(true))
+            }
+            L3gd20Status::ReadFifoLevel if fifo_samples > 0 => {
+                Some(self.read_fifo_samples(fifo_samples))
+            }
+            L3gd20Status::ReadCtrlReg1 => ctrl_reg1.map(|value| {
+                self.write_register(
+                    L3gd20Status::SetAxes,
+                    L3GD20_REG_CTRL_REG1,
+                    value & !L3GD20_CTRL_REG1_AXES | self.pending_axes.get(),
+                )
+            }),
+            L3gd20Status::ReadCtrlReg1Rate => ctrl_reg1.map(|value| {
+                self.write_register(
+                    L3gd20Status::SetDataRate,
+                    L3GD20_REG_CTRL_REG1,
+                    value & !L3GD20_CTRL_REG1_DR_BW | self.pending_data_rate.get(),
+                )
+            }),
+            _ => None,
+        };
+        if let Some(Ok(())) = next {
+            return;
+        }
+
+        let overrun = matches!(status, L3gd20Status::ReadFifoLevel | L3gd20Status::ReadFifo)
+            && self.fifo_overrun.take();
+        self.status.set(L3gd20Status::Idle);
+        process.map(|proc_id| {
+            let _result = self.owner.grant().enter(proc_id, |_app, upcalls| {
+                if overrun {
+                    upcalls.schedule_upcall(2, (L3GD20_FIFO_SIZE, 0, 0)).ok();
+                }
+                upcalls.schedule_upcall(upcall_num, upcall).ok();
+            });
+        });
+
+        // A data ready interrupt arrived during the transfer.
+        if self.data_ready_pending.take() && self.data_ready_process.is_some() {
+            let _ = self.read_data_ready();
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice<'a>> L3gd20Spi<'a, S> {
+    /// Copy the FIFO samples to the buffer allowed by `process`. Returns
+    /// the number of samples copied.
+    fn copy_to_process(&self, process: Option<ProcessId>, samples: &[u8]) -> usize {
+        process.map_or(0, |proc_id| {
+            self.owner
+                .grant()
+                .enter(proc_id, |_app, kernel_data| {
+                    kernel_data
+                        .get_readwrite_processbuffer(rw_allow::FIFO)
+                        .map_or(0, |fifo| {
+                            fifo.mut_enter(|app_buffer| {
+                                let count = cmp::min(
+                                    samples.len() / L3GD20_SAMPLE_SIZE,
+                                    app_buffer.len() / L3GD20_SAMPLE_SIZE,
+                                );
+                                let len = count * L3GD20_SAMPLE_SIZE;
+                                app_buffer[..len].copy_from_slice(&samples[..len]);
+                                count
+                            })
+                            .unwrap_or(0)
+                        })
+                })
+                .unwrap_or(0)
+        })
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice<'a>> gpio::Client for L3gd20Spi<'a, S> {
+    fn fired(&self) {
+        let owner_alive = self.data_ready_process.map_or(false, |proc_id| {
+            self.owner.grant().enter(proc_id, |_, _| {}).is_ok()
+        });
+        if !owner_alive {
+            // Nobody is left to receive the samples.
+            self.data_ready_process.clear();
+            self.data_ready_pending.set(false);
+            self.interrupt_pin.map(|pin| pin.disable_interrupts());
+        } else if self.status.get() == L3gd20Status::Idle {
+            let _ = self.read_data_ready();
+        } else {
+            self.data_ready_pending.set(true);
+        }
     }
 }
 
@@ -535,12 +1229,7 @@ impl<'a, S: spi::SpiMasterDevice<'a>> sensors::NineDof<'a> for L3gd20Spi<'a, S>
     }
 
     fn read_gyroscope(&self) -> Result<(), ErrorCode> {
-        if self.status.get() == L3gd20Status::Idle {
-            self.read_xyz();
-            Ok(())
-        } else {
-            Err(ErrorCode::BUSY)
-        }
+        self.read_xyz()
     }
 }
 
@@ -550,11 +1239,6 @@ impl<'a, S: spi::SpiMasterDevice<'a>> sensors::TemperatureDriver<'a> for L3gd20S
     }
 
     fn read_temperature(&self) -> Result<(), ErrorCode> {
-        if self.status.get() == L3gd20Status::Idle {
-            self.read_temperature();
-            Ok(())
-        } else {
-            Err(ErrorCode::BUSY)
-        }
+        self.read_temperature()
     }
 }
@@ -12,12 +12,15 @@ pub mod tutorials;
 pub mod net;
 
 pub mod adc_microphone;
+pub mod adc_threshold;
+pub mod ads1115;
 pub mod air_quality;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod app_staging;
 pub mod at24c_eeprom;
 pub mod ble_advertising_driver;
 pub mod bme280;
@@ -28,6 +31,7 @@ pub mod buzzer_driver;
 pub mod buzzer_pwm;
 pub mod can;
 pub mod ccs811;
+pub mod console_commands;
 pub mod crc;
 pub mod cycle_count;
 pub mod dac;
@@ -45,6 +49,7 @@ pub mod hs3003;
 pub mod hts221;
 pub mod humidity;
 pub mod ieee802154;
+pub mod interrupt_latency;
 pub mod isl29035;
 pub mod kv_driver;
 pub mod kv_store_permissions;
@@ -76,10 +81,12 @@ pub mod pwm;
 pub mod read_only_state;
 pub mod rf233;
 pub mod rf233_const;
+pub mod sample_history;
 pub mod screen;
 pub mod screen_shared;
 pub mod sdcard;
 pub mod segger_rtt;
+pub mod settings;
 pub mod seven_segment;
 pub mod sh1106;
 pub mod sha;
@@ -88,11 +95,14 @@ pub mod sht3x;
 pub mod sht4x;
 pub mod si7021;
 pub mod sip_hash;
+pub mod software_pwm;
 pub mod sound_pressure;
 pub mod ssd1306;
 pub mod st77xx;
 pub mod symmetric_encryption;
+pub mod syscall_accounting;
 pub mod temperature;
+pub mod temperature_compensation;
 pub mod temperature_rp2040;
 pub mod temperature_stm;
 pub mod text_screen;
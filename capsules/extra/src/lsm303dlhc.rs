@@ -77,6 +77,56 @@
 //! kernel::hil::sensors::TemperatureDriver::set_client(lsm303dlhc, temp);
 //! ```
 //!
+//! Data Ready
+//! ----------
+//!
+//! If the board connects the DRDY pin of the magnetometer, `configure`
+//! enables its interrupt and the driver reads every new magnetometer sample
+//! as soon as it is available, delivering it to the `NineDofClient` as if it
+//! had called `read_magnetometer`. An interrupt that arrives while another
+//! transfer is in progress is handled once that transfer is done, several
+//! such interrupts result in a single read.
+//!
+//! The temperature sensor has to be enabled (either through `configure` or
+//! through the syscall interface) before reading the temperature. Reads fail
+//! with `ErrorCode::OFF` while it is disabled, and with `ErrorCode::BUSY`
+//! while the configuration enabling it is written. If that write fails the
+//! sensor is considered disabled, as the temperature registers would only
+//! hold stale data.
+//!
+//! The sensor measures the temperature relative to an unspecified point, with
+//! 8 LSB per deg C in the top 12 bits of its output. The driver reports
+//! `raw * 100 / 8 + offset` hundredths of a deg C, where the offset (17 deg C
+//! by default) is the temperature at which the sensor outputs 0 and differs
+//! from chip to chip. It is set, in hundredths of a deg C, with
+//! `set_temperature_offset()` or command `9`.
+//!
+//! Self-Test
+//! ---------
+//!
+//! `run_self_test()` (command `10`) checks both sensors of a configured chip.
+//! It averages `SELF_TEST_SAMPLES` accelerometer samples, enables the
+//! self-test of the accelerometer in `CTRL_REG4_A` and averages as many
+//! samples again, the first sample after each change being discarded. The
+//! change of each axis has to be within the range given by the manual for
+//! the configured scale. `CTRL_REG4_A` is then restored and the driver reads
+//! the magnetometer, whose field magnitude has to be within the bounds set
+//! with `set_magnetic_field_bounds()` or command `11` (by default those of
+//! the earth's field). The result goes to the `SelfTestClient` and to the
+//! app that started the self-test.
+//!
+//! History
+//! -------
+//!
+//! The board can attach a `SampleHistory` with `set_history()`. While
+//! recording is enabled, with `set_history_enabled()` or command `12`, every
+//! accelerometer and magnetometer sample the driver reads, for an app, for
+//! the `NineDof` interface or for a data ready interrupt, is also appended to
+//! the history, with its raw values. The samples of the self-test are not.
+//! The sample is in the history before the `NineDofClient` gets it.
+//! Recording is disabled by default and costs nothing then. Command `13`
+//! copies the history to the buffer allowed with allow `0`.
+//!
 //! Author: Alexandru Radovici <msg4alex@gmail.com>
 //!
 
@@ -88,12 +138,16 @@ use enum_primitive::cast::FromPrimitive;
 use enum_primitive::enum_from_primitive;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::gpio;
 use kernel::hil::i2c;
 use kernel::hil::sensors;
+use kernel::processbuffer::WriteableProcessBuffer;
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
+use crate::sample_history::{SampleHistory, SensorKind};
+
 use crate::lsm303xx::{
     AccelerometerRegisters, Lsm303AccelDataRate, Lsm303MagnetoDataRate, Lsm303Range, Lsm303Scale,
     CTRL_REG1, CTRL_REG4, RANGE_FACTOR_X_Y, RANGE_FACTOR_Z, SCALE_FACTOR,
@@ -104,9 +158,22 @@ use capsules_core::driver;
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = driver::NUM::Lsm303dlch as usize;
 
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Samples copied from the history
+    pub const HISTORY: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
 /// Register values
 const REGISTER_AUTO_INCREMENT: u8 = 0x80;
 
+/// Register of the magnetometer read by the presence check, by default.
+pub const DEFAULT_IDENTITY_REGISTER: u8 = 0x0F;
+/// Value of the identity register when the sensor is present, by default.
+pub const DEFAULT_IDENTITY: u8 = 60;
+
 enum_from_primitive! {
     enum MagnetometerRegisters {
         CRA_REG_M = 0x00,
@@ -122,8 +189,49 @@ enum_from_primitive! {
     }
 }
 
-// Experimental
-const TEMP_OFFSET: i32 = 17;
+/// Temperature, in hundredths of a deg C, at which the temperature sensor
+/// outputs 0, by default.
+pub const DEFAULT_TEMPERATURE_OFFSET: i32 = 1_700;
+
+/// Bit of `SelfTestResult::passed` set if the accelerometer passed.
+pub const SELF_TEST_ACCELEROMETER: u8 = 1 << 0;
+/// Bit of `SelfTestResult::passed` set if the magnetometer passed.
+pub const SELF_TEST_MAGNETOMETER: u8 = 1 << 1;
+
+/// Accelerometer samples averaged with and without the self-test enabled.
+pub const SELF_TEST_SAMPLES: u8 = 4;
+/// Status reads waiting for a new accelerometer sample before giving up.
+const SELF_TEST_MAX_POLLS: u16 = 1_000;
+/// New X, Y and Z data available, in `STATUS_REG_A`.
+const STATUS_ZYXDA: u8 = 1 << 3;
+
+/// Accepted change of each accelerometer axis with the self-test enabled, in
+/// mg, for each scale. The manual gives 17 to 360 LSB at 4 mg/LSB for
+/// +/-2 g, the lower bound is raised to 5 LSB at the coarser scales.
+const SELF_TEST_ACCEL_RANGE: [(u32, u32); 4] = [(68, 1440), (68, 1440), (80, 1440), (240, 1440)];
+
+/// Magnitude of the magnetic field accepted by the self-test, in
+/// milligauss, by default: the earth's field is between 250 and 650 mG.
+pub const DEFAULT_MAGNETIC_FIELD_BOUNDS: (u32, u32) = (250, 650);
+
+/// Outcome of a self-test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfTestResult {
+    /// `SELF_TEST_ACCELEROMETER` and `SELF_TEST_MAGNETOMETER` bits of the
+    /// sensors that passed.
+    pub passed: u8,
+    /// Change of the X, Y and Z acceleration with the self-test enabled, in
+    /// mg.
+    pub accel_delta: [u32; 3],
+    /// Magnitude of the magnetic field, in milligauss.
+    pub magnetic_field: u32,
+}
+
+pub trait SelfTestClient {
+    /// Called when the self-test is done. Errors mean that the self-test
+    /// could not be run, not that a sensor failed it.
+    fn self_test_done(&self, result: Result<SelfTestResult, ErrorCode>);
+}
 
 #[derive(Clone, Copy, PartialEq)]
 enum State {
@@ -136,12 +244,20 @@ enum State {
     SetRange,
     ReadTemperature,
     ReadMagnetometerXYZ,
+    SelfTestBaseline,
+    SelfTestEnable,
+    SelfTestSample,
+    SelfTestRestore,
+    SelfTestMagnetometer,
 }
 
 pub struct Lsm303dlhcI2C<'a, I: i2c::I2CDevice> {
     config_in_progress: Cell<bool>,
     i2c_accelerometer: &'a I,
     i2c_magnetometer: &'a I,
+    interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+    /// A data ready interrupt arrived while the driver was busy.
+    data_ready_pending: Cell<bool>,
     state: Cell<State>,
     accel_scale: Cell<Lsm303Scale>,
     mag_range: Cell<Lsm303Range>,
@@ -149,29 +265,55 @@ pub struct Lsm303dlhcI2C<'a, I: i2c::I2CDevice> {
     mag_data_rate: Cell<Lsm303MagnetoDataRate>,
     accel_data_rate: Cell<Lsm303AccelDataRate>,
     low_power: Cell<bool>,
+    /// Whether the temperature sensor is enabled, or being enabled. Cleared
+    /// if enabling it fails.
     temperature: Cell<bool>,
+    temperature_offset: Cell<i32>,
+    identity_register: u8,
+    identity: u8,
     buffer: TakeCell<'static, [u8]>,
     nine_dof_client: OptionalCell<&'a dyn sensors::NineDofClient>,
     temperature_client: OptionalCell<&'a dyn sensors::TemperatureClient>,
+    self_test_client: OptionalCell<&'a dyn SelfTestClient>,
+    /// Samples received in the current self-test phase, including the
+    /// discarded one.
+    self_test_samples: Cell<u8>,
+    self_test_polls: Cell<u16>,
+    self_test_sum: Cell<[i32; 3]>,
+    self_test_baseline: Cell<[i32; 3]>,
+    self_test_delta: Cell<[u32; 3]>,
+    /// Error to report once `CTRL_REG4_A` is restored.
+    self_test_error: OptionalCell<ErrorCode>,
+    magnetic_field_bounds: Cell<(u32, u32)>,
+    history: OptionalCell<&'a SampleHistory<'a>>,
+    history_enabled: Cell<bool>,
     current_process: OptionalCell<ProcessId>,
-    apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
 }
 
 #[derive(Default)]
 pub struct App {}
 
 impl<'a, I: i2c::I2CDevice> Lsm303dlhcI2C<'a, I> {
+    /// The sensor is present if reading `identity_register` from the
+    /// magnetometer returns `identity`. `interrupt_pin` is the pin connected
+    /// to DRDY, if any.
     pub fn new(
         i2c_accelerometer: &'a I,
         i2c_magnetometer: &'a I,
+        interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+        identity_register: u8,
+        identity: u8,
         buffer: &'static mut [u8],
-        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     ) -> Lsm303dlhcI2C<'a, I> {
         // setup and return struct
         Lsm303dlhcI2C {
             config_in_progress: Cell::new(false),
             i2c_accelerometer: i2c_accelerometer,
             i2c_magnetometer: i2c_magnetometer,
+            interrupt_pin: interrupt_pin,
+            data_ready_pending: Cell::new(false),
             state: Cell::new(State::Idle),
             accel_scale: Cell::new(Lsm303Scale::Scale2G),
             mag_range: Cell::new(Lsm303Range::Range1G),
@@ -180,14 +322,46 @@ impl<'a, I: i2c::I2CDevice> Lsm303dlhcI2C<'a, I> {
             accel_data_rate: Cell::new(Lsm303AccelDataRate::DataRate1Hz),
             low_power: Cell::new(false),
             temperature: Cell::new(false),
+            temperature_offset: Cell::new(DEFAULT_TEMPERATURE_OFFSET),
+            identity_register: identity_register,
+            identity: identity,
             buffer: TakeCell::new(buffer),
             nine_dof_client: OptionalCell::empty(),
             temperature_client: OptionalCell::empty(),
+            self_test_client: OptionalCell::empty(),
+            self_test_samples: Cell::new(0),
+            self_test_polls: Cell::new(0),
+            self_test_sum: Cell::new([0; 3]),
+            self_test_baseline: Cell::new([0; 3]),
+            self_test_delta: Cell::new([0; 3]),
+            self_test_error: OptionalCell::empty(),
+            magnetic_field_bounds: Cell::new(DEFAULT_MAGNETIC_FIELD_BOUNDS),
+            history: OptionalCell::empty(),
+            history_enabled: Cell::new(false),
             current_process: OptionalCell::empty(),
             apps: grant,
         }
     }
 
+    /// Create the driver with the default identity register and value.
+    pub fn new_default_identity(
+        i2c_accelerometer: &'a I,
+        i2c_magnetometer: &'a I,
+        interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+        buffer: &'static mut [u8],
+        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
+    ) -> Lsm303dlhcI2C<'a, I> {
+        Self::new(
+            i2c_accelerometer,
+            i2c_magnetometer,
+            interrupt_pin,
+            DEFAULT_IDENTITY_REGISTER,
+            DEFAULT_IDENTITY,
+            buffer,
+            grant,
+        )
+    }
+
     pub fn configure(
         &self,
         accel_data_rate: Lsm303AccelDataRate,
@@ -209,18 +383,230 @@ impl<'a, I: i2c::I2CDevice> Lsm303dlhcI2C<'a, I> {
             self.accel_data_rate.set(accel_data_rate);
             self.low_power.set(low_power);
 
-            self.set_power_mode(accel_data_rate, low_power)
+            self.interrupt_pin.map(|pin| {
+                pin.make_input();
+                pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+            });
+
+            let result = self.set_power_mode(accel_data_rate, low_power);
+            if result.is_err() {
+                self.stop_configuration();
+            }
+            result
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    /// Stop a configuration that could not go on. The temperature sensor may
+    /// not have been enabled.
+    fn stop_configuration(&self) {
+        self.config_in_progress.set(false);
+        self.temperature.set(false);
+    }
+
+    /// Set the temperature, in hundredths of a deg C, at which the
+    /// temperature sensor outputs 0.
+    pub fn set_temperature_offset(&self, offset_centi: i32) {
+        self.temperature_offset.set(offset_centi);
+    }
+
+    pub fn set_self_test_client(&self, client: &'a dyn SelfTestClient) {
+        self.self_test_client.replace(client);
+    }
+
+    /// Set the magnitude of the magnetic field, in milligauss, accepted by
+    /// the self-test.
+    pub fn set_magnetic_field_bounds(&self, min: u32, max: u32) -> Result<(), ErrorCode> {
+        if min > max {
+            return Err(ErrorCode::INVAL);
+        }
+        self.magnetic_field_bounds.set((min, max));
+        Ok(())
+    }
+
+    /// Run the self-test of the accelerometer and of the magnetometer. The
+    /// sensor has to be configured with the accelerometer running.
+    /// Attach the history that samples are recorded in once enabled.
+    pub fn set_history(&self, history: &'a SampleHistory<'a>) {
+        self.history.set(history);
+    }
+
+    pub fn history(&self) -> Option<&'a SampleHistory<'a>> {
+        self.history.get()
+    }
+
+    /// Start or stop recording the samples in the history.
+    pub fn set_history_enabled(&self, enabled: bool) -> Result<(), ErrorCode> {
+        if self.history.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.history_enabled.set(enabled);
+        Ok(())
+    }
+
+    fn record_sample(&self, sensor: SensorKind, xyz: [i16; 3]) {
+        if self.history_enabled.get() {
+            self.history.map(|history| {
+                history.push(sensor, xyz);
+            });
+        }
+    }
+
+    /// Copy the history to the buffer allowed by `process_id`. Returns the
+    /// number of samples copied.
+    fn copy_history(&self, process_id: ProcessId) -> Result<usize, ErrorCode> {
+        let history = self.history.get().ok_or(ErrorCode::NOSUPPORT)?;
+        self.apps
+            .enter(process_id, |_grant, kernel_data| {
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::HISTORY)
+                    .and_then(|buffer| buffer.mut_enter(|dest| history.copy_to_process(dest)))
+                    .unwrap_or(0)
+            })
+            .map_err(ErrorCode::from)
+    }
+
+    pub fn run_self_test(&self) -> Result<(), ErrorCode> {
+        if self.state.get() == State::Idle {
+            self.self_test_samples.set(0);
+            self.self_test_polls.set(0);
+            self.self_test_sum.set([0; 3]);
+            self.self_test_error.clear();
+            self.self_test_step(State::SelfTestBaseline)
         } else {
             Err(ErrorCode::BUSY)
         }
     }
 
+    /// Start the transfer of the self-test step `state`.
+    fn self_test_step(&self, state: State) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+            let (device, write_len, read_len) = match state {
+                State::SelfTestEnable | State::SelfTestRestore => {
+                    buf[0] = AccelerometerRegisters::CTRL_REG4 as u8;
+                    buf[1] = (CTRL_REG4::FS.val(self.accel_scale.get() as u8)
+                        + CTRL_REG4::HR.val(self.accel_high_resolution.get() as u8)
+                        + CTRL_REG4::ST.val((state == State::SelfTestEnable) as u8))
+                    .value;
+                    (self.i2c_accelerometer, 2, 0)
+                }
+                State::SelfTestMagnetometer => {
+                    buf[0] = MagnetometerRegisters::OUT_X_H_M as u8;
+                    (self.i2c_magnetometer, 1, 6)
+                }
+                _ => {
+                    // the status register, followed by the sample
+                    buf[0] = AccelerometerRegisters::STATUS_REG_A as u8 | REGISTER_AUTO_INCREMENT;
+                    (self.i2c_accelerometer, 1, 7)
+                }
+            };
+            self.state.set(state);
+            device.enable();
+            let result = if read_len > 0 {
+                device.write_read(buf, write_len, read_len)
+            } else {
+                device.write(buf, write_len)
+            };
+            if let Err((error, buf)) = result {
+                self.state.set(State::Idle);
+                self.buffer.replace(buf);
+                Err(error.into())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn self_test_continue(&self, state: State) {
+        if let Err(error) = self.self_test_step(state) {
+            self.self_test_failed(state, error);
+        }
+    }
+
+    /// The self-test step `state` failed, restore `CTRL_REG4_A` if the
+    /// self-test may be enabled and report `error`.
+    fn self_test_failed(&self, state: State, error: ErrorCode) {
+        match state {
+            State::SelfTestEnable | State::SelfTestSample => {
+                self.self_test_error.set(error);
+                self.self_test_continue(State::SelfTestRestore);
+            }
+            _ => {
+                let error = self.self_test_error.take().unwrap_or(error);
+                self.self_test_done(Err(error));
+            }
+        }
+    }
+
+    /// Add an accelerometer sample to the self-test phase `state`.
+    fn self_test_sample(&self, state: State, sample: [i32; 3]) {
+        let received = self.self_test_samples.get() + 1;
+        self.self_test_samples.set(received);
+        self.self_test_polls.set(0);
+        // the first sample after a change may not be settled
+        if received > 1 {
+            let mut sum = self.self_test_sum.get();
+            for axis in 0..3 {
+                sum[axis] += sample[axis];
+            }
+            self.self_test_sum.set(sum);
+        }
+        if received <= SELF_TEST_SAMPLES {
+            self.self_test_continue(state);
+            return;
+        }
+
+        let average = self
+            .self_test_sum
+            .get()
+            .map(|sum| sum / SELF_TEST_SAMPLES as i32);
+        self.self_test_samples.set(0);
+        self.self_test_sum.set([0; 3]);
+        if state == State::SelfTestBaseline {
+            self.self_test_baseline.set(average);
+            self.self_test_continue(State::SelfTestEnable);
+        } else {
+            let baseline = self.self_test_baseline.get();
+            let scale_factor = SCALE_FACTOR[self.accel_scale.get() as usize] as u32;
+            let mut delta = [0; 3];
+            for axis in 0..3 {
+                delta[axis] = average[axis].abs_diff(baseline[axis]) * scale_factor * 1000 / 32768;
+            }
+            self.self_test_delta.set(delta);
+            self.self_test_continue(State::SelfTestRestore);
+        }
+    }
+
+    fn self_test_done(&self, result: Result<SelfTestResult, ErrorCode>) {
+        self.self_test_client.map(|client| {
+            client.self_test_done(result);
+        });
+
+        self.current_process.map(|process_id| {
+            let _ = self.apps.enter(process_id, |_grant, upcalls| {
+                if let Ok(result) = result {
+                    let [x, y, z] = result.accel_delta.map(|delta| delta.min(0xFFFF) as usize);
+                    let field = result.magnetic_field.min(0xFFFF) as usize;
+                    upcalls
+                        .schedule_upcall(
+                            0,
+                            (result.passed as usize, x | (y << 16), z | (field << 16)),
+                        )
+                        .ok();
+                } else {
+                    upcalls.schedule_upcall(0, (0, 0, 0)).ok();
+                }
+            });
+        });
+    }
+
     fn is_present(&self) -> Result<(), ErrorCode> {
-        if self.state.get() != State::Idle {
+        if self.state.get() == State::Idle {
             self.state.set(State::IsPresent);
             self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
                 // turn on i2c to send commands
-                buf[0] = 0x0F;
+                buf[0] = self.identity_register;
                 self.i2c_magnetometer.enable();
                 if let Err((error, buf)) = self.i2c_magnetometer.write_read(buf, 1, 1) {
                     self.buffer.replace(buf);
@@ -319,12 +705,14 @@ impl<'a, I: i2c::I2CDevice> Lsm303dlhcI2C<'a, I> {
     ) -> Result<(), ErrorCode> {
         if self.state.get() == State::Idle {
             self.state.set(State::SetTemperatureDataRate);
+            self.temperature.set(temperature);
             self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
                 buf[0] = MagnetometerRegisters::CRA_REG_M as u8;
                 buf[1] = ((data_rate as u8) << 2) | if temperature { 1 << 7 } else { 0 };
                 self.i2c_magnetometer.enable();
                 if let Err((error, buf)) = self.i2c_magnetometer.write(buf, 2) {
                     self.state.set(State::Idle);
+                    self.temperature.set(false);
                     self.buffer.replace(buf);
                     Err(error.into())
                 } else {
@@ -360,6 +748,9 @@ impl<'a, I: i2c::I2CDevice> Lsm303dlhcI2C<'a, I> {
     }
 
     fn read_temperature(&self) -> Result<(), ErrorCode> {
+        if !self.temperature.get() {
+            return Err(ErrorCode::OFF);
+        }
         if self.state.get() == State::Idle {
             self.state.set(State::ReadTemperature);
             self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
@@ -402,7 +793,7 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
     fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
         match self.state.get() {
             State::IsPresent => {
-                let present = status.is_ok() && buffer[0] == 60;
+                let present = status.is_ok() && buffer[0] == self.identity;
 
                 self.current_process.map(|process_id| {
                     let _ = self.apps.enter(process_id, |_grant, upcalls| {
@@ -431,10 +822,12 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
                 self.i2c_accelerometer.disable();
                 self.state.set(State::Idle);
                 if self.config_in_progress.get() {
-                    let _ = self.set_scale_and_resolution(
+                    if let Err(_error) = self.set_scale_and_resolution(
                         self.accel_scale.get(),
                         self.accel_high_resolution.get(),
-                    );
+                    ) {
+                        self.stop_configuration();
+                    }
                 }
             }
             State::SetScaleAndResolution => {
@@ -456,7 +849,7 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
                         self.temperature.get(),
                         self.mag_data_rate.get(),
                     ) {
-                        self.config_in_progress.set(false);
+                        self.stop_configuration();
                     }
                 }
             }
@@ -465,6 +858,10 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
                 let mut y: usize = 0;
                 let mut z: usize = 0;
                 let values = if status == Ok(()) {
+                    self.record_sample(
+                        SensorKind::Accelerometer,
+                        [0, 2, 4].map(|i| i16::from_le_bytes([buffer[i], buffer[i + 1]])),
+                    );
                     self.nine_dof_client.map(|client| {
                         // compute using only integers
                         let scale_factor = self.accel_scale.get() as usize;
@@ -510,6 +907,11 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
             }
             State::SetTemperatureDataRate => {
                 let set_temperature_and_magneto_data_rate = status == Ok(());
+                if !set_temperature_and_magneto_data_rate {
+                    // the chip only reports valid temperatures after this
+                    // write succeeded
+                    self.temperature.set(false);
+                }
 
                 self.current_process.map(|process_id| {
                     let _ = self.apps.enter(process_id, |_grant, upcalls| {
@@ -544,6 +946,10 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
 
                 if self.config_in_progress.get() {
                     self.config_in_progress.set(false);
+                    // DRDY may already be high, which gives no edge.
+                    if self.interrupt_pin.map_or(false, |pin| pin.read()) {
+                        self.data_ready_pending.set(true);
+                    }
                 }
                 self.buffer.replace(buffer);
                 self.i2c_magnetometer.disable();
@@ -551,10 +957,10 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
             }
             State::ReadTemperature => {
                 let values = match status {
-                    Ok(()) => Ok(
-                        ((buffer[1] as i16 | ((buffer[0] as i16) << 8)) >> 4) as i32 / 8
-                            + TEMP_OFFSET,
-                    ),
+                    Ok(()) => {
+                        let raw = (buffer[1] as i16 | ((buffer[0] as i16) << 8)) >> 4;
+                        Ok(raw as i32 * 100 / 8 + self.temperature_offset.get())
+                    }
                     Err(i2c_error) => Err(i2c_error.into()),
                 };
                 self.temperature_client.map(|client| {
@@ -580,6 +986,11 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
                 let mut y: usize = 0;
                 let mut z: usize = 0;
                 let values = if status == Ok(()) {
+                    // The registers hold X, Z and Y.
+                    self.record_sample(
+                        SensorKind::Magnetometer,
+                        [0, 4, 2].map(|i| i16::from_be_bytes([buffer[i], buffer[i + 1]])),
+                    );
                     self.nine_dof_client.map(|client| {
                         // compute using only integers
                         let range = self.mag_range.get() as usize;
@@ -617,12 +1028,113 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for Lsm303dlhcI2C<'_, I> {
                 self.i2c_magnetometer.disable();
                 self.state.set(State::Idle);
             }
+            State::SelfTestBaseline | State::SelfTestSample => {
+                let state = self.state.get();
+                let sample = status.map(|()| {
+                    if buffer[0] & STATUS_ZYXDA != 0 {
+                        Some([1, 3, 5].map(|high| {
+                            (buffer[high] as i16 | ((buffer[high + 1] as i16) << 8)) as i32
+                        }))
+                    } else {
+                        None
+                    }
+                });
+
+                self.buffer.replace(buffer);
+                self.i2c_accelerometer.disable();
+                self.state.set(State::Idle);
+                match sample {
+                    Ok(Some(sample)) => self.self_test_sample(state, sample),
+                    Ok(None) => {
+                        let polls = self.self_test_polls.get() + 1;
+                        self.self_test_polls.set(polls);
+                        if polls < SELF_TEST_MAX_POLLS {
+                            self.self_test_continue(state);
+                        } else {
+                            self.self_test_failed(state, ErrorCode::FAIL);
+                        }
+                    }
+                    Err(error) => self.self_test_failed(state, error.into()),
+                }
+            }
+            State::SelfTestEnable | State::SelfTestRestore => {
+                let state = self.state.get();
+                self.buffer.replace(buffer);
+                self.i2c_accelerometer.disable();
+                self.state.set(State::Idle);
+                match status {
+                    Err(error) => self.self_test_failed(state, error.into()),
+                    Ok(()) if state == State::SelfTestEnable => {
+                        self.self_test_continue(State::SelfTestSample)
+                    }
+                    Ok(()) => match self.self_test_error.take() {
+                        Some(error) => self.self_test_done(Err(error)),
+                        None => self.self_test_continue(State::SelfTestMagnetometer),
+                    },
+                }
+            }
+            State::SelfTestMagnetometer => {
+                let field = status.map(|()| {
+                    let range = self.mag_range.get() as usize;
+                    // the magnetometer outputs X, Z, Y, high byte first
+                    let [x, z, y] = [0, 2, 4].map(|high| {
+                        (buffer[high + 1] as i16 | ((buffer[high] as i16) << 8)) as i32
+                    });
+                    let x = x * 1000 / RANGE_FACTOR_X_Y[range] as i32;
+                    let y = y * 1000 / RANGE_FACTOR_X_Y[range] as i32;
+                    let z = z * 1000 / RANGE_FACTOR_Z[range] as i32;
+                    isqrt((x * x + y * y + z * z) as u32)
+                });
+
+                self.buffer.replace(buffer);
+                self.i2c_magnetometer.disable();
+                self.state.set(State::Idle);
+                match field {
+                    Ok(field) => {
+                        let accel_delta = self.self_test_delta.get();
+                        let (accel_min, accel_max) =
+                            SELF_TEST_ACCEL_RANGE[self.accel_scale.get() as usize];
+                        let (field_min, field_max) = self.magnetic_field_bounds.get();
+                        let mut passed = 0;
+                        if accel_delta
+                            .iter()
+                            .all(|delta| (accel_min..=accel_max).contains(delta))
+                        {
+                            passed |= SELF_TEST_ACCELEROMETER;
+                        }
+                        if (field_min..=field_max).contains(&field) {
+                            passed |= SELF_TEST_MAGNETOMETER;
+                        }
+                        self.self_test_done(Ok(SelfTestResult {
+                            passed,
+                            accel_delta,
+                            magnetic_field: field,
+                        }));
+                    }
+                    Err(error) => self.self_test_failed(State::SelfTestMagnetometer, error.into()),
+                }
+            }
             _ => {
                 self.i2c_magnetometer.disable();
                 self.i2c_accelerometer.disable();
                 self.buffer.replace(buffer);
             }
         }
+
+        // A data ready interrupt arrived during the transfer.
+        if self.state.get() == State::Idle && self.data_ready_pending.take() {
+            let _ = self.read_magnetometer_xyz();
+        }
+    }
+}
+
+impl<I: i2c::I2CDevice> gpio::Client for Lsm303dlhcI2C<'_, I> {
+    fn fired(&self) {
+        if self.state.get() == State::Idle {
+            let _ = self.read_magnetometer_xyz();
+        } else {
+            self.data_ready_pending.set(true);
+        }
     }
 }
 
@@ -645,7 +1157,13 @@ impl<I: i2c::I2CDevice> SyscallDriver for Lsm303dlhcI2C<'_, I> {
         let match_or_empty_or_nonexistant = self.current_process.map_or(true, |current_process| {
             self.apps
                 .enter(current_process, |_, _| current_process == process_id)
-                .unwrap_or(true)
+                .unwrap_or_else(|_| {
+                    // The process is gone, maybe restarted with a new
+                    // `ProcessId`. Its operation in progress finishes first,
+                    // so that its upcall is dropped rather than delivered to
+                    // the next process.
+                    self.state.get() == State::Idle
+                })
         });
 
         if match_or_empty_or_nonexistant {
@@ -726,6 +1244,31 @@ impl<I: i2c::I2CDevice> SyscallDriver for Lsm303dlhcI2C<'_, I> {
                     CommandReturn::failure(ErrorCode::BUSY)
                 }
             }
+            // Set Temperature Offset
+            9 => {
+                self.set_temperature_offset(data1 as i32);
+                CommandReturn::success()
+            }
+            // Run Self-Test
+            10 => match self.run_self_test() {
+                Ok(()) => CommandReturn::success(),
+                Err(error) => CommandReturn::failure(error),
+            },
+            // Set Magnetic Field Bounds Of The Self-Test
+            11 => match self.set_magnetic_field_bounds(data1 as u32, data2 as u32) {
+                Ok(()) => CommandReturn::success(),
+                Err(error) => CommandReturn::failure(error),
+            },
+            // Enable History
+            12 => match self.set_history_enabled(data1 == 1) {
+                Ok(()) => CommandReturn::success(),
+                Err(error) => CommandReturn::failure(error),
+            },
+            // Copy History
+            13 => match self.copy_history(process_id) {
+                Ok(count) => CommandReturn::success_u32(count as u32),
+                Err(error) => CommandReturn::failure(error),
+            },
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -759,3 +1302,18 @@ impl<'a, I: i2c::I2CDevice> sensors::TemperatureDriver<'a> for Lsm303dlhcI2C<'a,
         self.read_temperature()
     }
 }
+
+/// Integer square root, rounded down.
+fn isqrt(value: u32) -> u32 {
+    if value < 2 {
+        return value;
+    }
+    // Newton's method, starting above the root
+    let mut root = value;
+    let mut next = value - value / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
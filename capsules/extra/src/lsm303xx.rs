@@ -109,6 +109,8 @@ register_bitfields![u8,
         FS OFFSET(4) NUMBITS(2) [],
         /// High Resolution
         HR OFFSET(3) NUMBITS(1) [],
+        /// Self-test mode
+        ST OFFSET(1) NUMBITS(2) [],
         /// SPI Serial Interface
         SIM OFFSET(0) NUMBITS(1) []
     ]
@@ -118,6 +120,7 @@ enum_from_primitive! {
     pub enum AccelerometerRegisters {
         CTRL_REG1 = 0x20,
         CTRL_REG4 = 0x23,
+        STATUS_REG_A = 0x27,
         OUT_X_L_A = 0x28,
         OUT_X_H_A = 0x29,
         OUT_Y_L_A = 0x2A,
@@ -25,6 +25,62 @@
 //! to potentially interface with the LTC294X chip rather than only provide
 //! it to userspace.
 //!
+//! Charge conversion
+//! -----------------
+//!
+//! The accumulated charge register counts in units of `qLSB`, which depends
+//! on the chip model, the prescaler `M` and the sense resistor:
+//!
+//! - LTC2941/LTC2942: `qLSB = 0.085 mAh * (50 mOhm / Rsense) * (M / 128)`
+//! - LTC2943: `qLSB = 0.340 mAh * (50 mOhm / Rsense) * (M / 4096)`
+//!
+//! The prescaler and sense resistor passed to `configure()` are remembered so
+//! that the driver can hand out the accumulated charge in microampere-hours
+//! through `LTC294XClient::charge_uah()` as well as the raw register value.
+//! A charge which does not fit in an `i32` once converted, with a very small
+//! sense resistor, is reported to `charge_uah()` as `SIZE` instead.
+//! Boards with a sense resistor that is not a whole number of milliohms can
+//! set it with `set_sense_resistor_uohms()`.
+//!
+//! Shutdown
+//! --------
+//!
+//! `shutdown()` and `wake()` set and clear the shutdown bit of the control
+//! register. Rather than reading the register back from the chip, they
+//! modify the copy the driver keeps of the last control value written, by
+//! `configure()` or by a previous shutdown or wake, and write it. A
+//! configuration can therefore not be lost between reading and writing the
+//! register. `configure()` writes a new control value with the shutdown bit
+//! cleared, so it also wakes the chip up.
+//!
+//! Temperature
+//! -----------
+//!
+//! The LTC2942 and LTC2943 have an internal temperature sensor. Its readings
+//! are reported raw through `LTC294XClient::temperature()` and in
+//! centidegrees Celsius through `hil::sensors::TemperatureDriver`, so the chip
+//! can be used with `capsules_extra::temperature::TemperatureSensor`:
+//!
+//! - LTC2942: `T = 600 K * raw / 0xFFFF`
+//! - LTC2943: `T = 510 K * raw / 0xFFFF`
+//!
+//! Each reading goes to whoever asked for it: reads started with
+//! `read_temperature()` only reach the `TemperatureClient`, and reads
+//! started by the syscall driver only reach the `LTC294XClient`.
+//!
+//! Kernel users must select the chip with `set_chip_model()` first, as the
+//! LTC2941 has no temperature sensor. There is no sensor HIL for voltages, so
+//! sense+ readings are only available through `LTC294XClient::voltage()`.
+//!
+//! Errors
+//! ------
+//!
+//! A transfer the I2C device refuses to start leaves the driver idle, and the
+//! call which started it returns the error. A transfer which fails once
+//! started is not decoded: the client gets `LTC294XClient::error()` instead
+//! of the reading or `done()`. A failed `read_temperature()` is reported to
+//! the `TemperatureClient` with the error.
+//!
 //! Usage
 //! -----
 //!
@@ -52,18 +108,27 @@
 
 use core::cell::Cell;
 
+use kernel::errorcode::into_statuscode;
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::gpio;
 use kernel::hil::i2c;
+use kernel::hil::sensors;
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
+use capsules_core::single_owner::{SingleOwner, SingleOwnerCommands};
+
 /// Syscall driver number.
 use capsules_core::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Ltc294x as usize;
 
-pub const BUF_LEN: usize = 20;
+pub const BUF_LEN: usize = 22;
+
+/// Control register value after power-on: alert mode, maximum prescaler.
+const CONTROL_POWER_ON: u8 = 0x3C;
+/// Shutdown bit of the control register.
+const CONTROL_SHUTDOWN: u8 = 0x01;
 
 #[allow(dead_code)]
 enum Registers {
@@ -79,6 +144,12 @@ enum Registers {
     VoltageLSB = 0x09,
     CurrentMSB = 0x0E,
     CurrentLSB = 0x0F,
+    /// Temperature on the LTC2942.
+    TemperatureMSB = 0x0C,
+    TemperatureLSB = 0x0D,
+    /// Temperature on the LTC2943.
+    LTC2943TemperatureMSB = 0x14,
+    LTC2943TemperatureLSB = 0x15,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -87,10 +158,16 @@ enum State {
 
     /// Simple read states
     ReadStatus,
+    /// Status read triggered by the interrupt pin
+    ReadInterruptStatus,
     ReadCharge,
     ReadVoltage,
     ReadCurrent,
-    ReadShutdown,
+    ReadTemperature,
+    /// Temperature read for the `TemperatureClient`
+    ReadSensorTemperature,
+    /// Writing the control register, whose value is kept on success
+    WriteControl,
 
     Done,
 }
@@ -123,7 +200,16 @@ pub struct App {}
 
 /// Supported events for the LTC294X.
 pub trait LTC294XClient {
-    fn interrupt(&self);
+    /// The interrupt pin fired. The status register read in response is
+    /// passed along.
+    fn interrupt(
+        &self,
+        undervolt_lockout: bool,
+        vbat_alert: bool,
+        charge_alert_low: bool,
+        charge_alert_high: bool,
+        accumulated_charge_overflow: bool,
+    );
     fn status(
         &self,
         undervolt_lockout: bool,
@@ -133,9 +219,17 @@ pub trait LTC294XClient {
         accumulated_charge_overflow: bool,
     );
     fn charge(&self, charge: u16);
+    /// Accumulated charge converted to microampere-hours, or `SIZE` if it
+    /// does not fit in an `i32`. Only called if a sense resistor value has
+    /// been configured.
+    fn charge_uah(&self, uah: Result<i32, ErrorCode>);
     fn voltage(&self, voltage: u16);
     fn current(&self, current: u16);
+    fn temperature(&self, raw: u16);
     fn done(&self);
+    /// An operation failed on the I2C bus, for instance because the chip is
+    /// not connected.
+    fn error(&self, error: ErrorCode);
 }
 
 /// Implementation of a driver for the LTC294X coulomb counters.
@@ -143,9 +237,21 @@ pub struct LTC294X<'a, I: i2c::I2CDevice> {
     i2c: &'a I,
     interrupt_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
     model: Cell<ChipModel>,
+    /// Prescaler exponent written in the control register.
+    prescaler: Cell<u8>,
+    /// Last value written to the control register.
+    control: Cell<u8>,
+    /// Sense resistor value in microohms, 0 if unknown.
+    sense_resistor: Cell<u32>,
+    /// Most recent raw accumulated charge reading.
+    charge: OptionalCell<u16>,
+    /// The interrupt fired while the chip was busy and its status still
+    /// needs to be read.
+    interrupt_pending: Cell<bool>,
     state: Cell<State>,
     buffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'static dyn LTC294XClient>,
+    temperature_client: OptionalCell<&'a dyn sensors::TemperatureClient>,
 }
 
 impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
@@ -158,9 +264,16 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             i2c: i2c,
             interrupt_pin: interrupt_pin,
             model: Cell::new(ChipModel::LTC2941),
+            // The prescaler is at its maximum after power-on.
+            prescaler: Cell::new(7),
+            control: Cell::new(CONTROL_POWER_ON),
+            sense_resistor: Cell::new(0),
+            charge: OptionalCell::empty(),
+            interrupt_pending: Cell::new(false),
             state: Cell::new(State::Idle),
             buffer: TakeCell::new(buffer),
             client: OptionalCell::empty(),
+            temperature_client: OptionalCell::empty(),
         }
     }
 
@@ -173,39 +286,90 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
         });
     }
 
+    /// Whether a transfer with the chip is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+
+    /// Read `len` registers from the status register on, and handle them
+    /// in `state` once read.
+    fn read(&self, buffer: &'static mut [u8], len: usize, state: State) -> Result<(), ErrorCode> {
+        self.state.set(state);
+        self.i2c
+            .read(buffer, len)
+            .map_err(|(error, buffer)| self.start_failed(buffer, error))
+    }
+
+    /// Write the first `len` bytes of `buffer`, a register address followed
+    /// by its new values, and handle the result in `state`.
+    fn write(&self, buffer: &'static mut [u8], len: usize, state: State) -> Result<(), ErrorCode> {
+        self.state.set(state);
+        self.i2c
+            .write(buffer, len)
+            .map_err(|(error, buffer)| self.start_failed(buffer, error))
+    }
+
+    /// A transfer could not be started, get back to idle.
+    fn start_failed(&self, buffer: &'static mut [u8], error: i2c::Error) -> ErrorCode {
+        self.buffer.replace(buffer);
+        self.i2c.disable();
+        self.state.set(State::Idle);
+        error.into()
+    }
+
     pub fn read_status(&self) -> Result<(), ErrorCode> {
         self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
             self.i2c.enable();
 
             // Address pointer automatically resets to the status register.
-            // TODO verify errors
-            let _ = self.i2c.read(buffer, 1);
-            self.state.set(State::ReadStatus);
-
-            Ok(())
+            self.read(buffer, 1, State::ReadStatus)
         })
     }
 
-    fn configure(
+    /// Read the status register in response to an interrupt, or remember to
+    /// do so once the current operation finishes.
+    fn read_interrupt_status(&self) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.i2c.enable();
+
+                if let Err(error) = self.read(buffer, 1, State::ReadInterruptStatus) {
+                    self.client.map(|client| client.error(error));
+                }
+            }
+            None => self.interrupt_pending.set(true),
+        }
+    }
+
+    pub fn configure(
         &self,
         int_pin_conf: InterruptPinConf,
         prescaler: u8,
         vbat_alert: VBatAlert,
+        sense_resistor_mohm: u32,
     ) -> Result<(), ErrorCode> {
         self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
             self.i2c.enable();
 
-            buffer[0] = Registers::Control as u8;
-            buffer[1] = ((int_pin_conf as u8) << 1) | (prescaler << 3) | ((vbat_alert as u8) << 6);
-
-            // TODO verify errors
-            let _ = self.i2c.write(buffer, 2);
-            self.state.set(State::Done);
+            self.prescaler.set(prescaler & 0x07);
+            self.sense_resistor
+                .set(sense_resistor_mohm.checked_mul(1000).unwrap_or(0));
 
-            Ok(())
+            let control =
+                ((int_pin_conf as u8) << 1) | ((prescaler & 0x07) << 3) | ((vbat_alert as u8) << 6);
+            self.write_control(buffer, control)
         })
     }
 
+    /// Write `control` to the control register. It becomes the value the
+    /// shutdown bit is changed in once the write succeeds.
+    fn write_control(&self, buffer: &'static mut [u8], control: u8) -> Result<(), ErrorCode> {
+        buffer[0] = Registers::Control as u8;
+        buffer[1] = control;
+
+        self.write(buffer, 2, State::WriteControl)
+    }
+
     /// Set the accumulated charge to 0
     fn reset_charge(&self) -> Result<(), ErrorCode> {
         self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
@@ -215,11 +379,7 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             buffer[1] = 0;
             buffer[2] = 0;
 
-            // TODO verify errors
-            let _ = self.i2c.write(buffer, 3);
-            self.state.set(State::Done);
-
-            Ok(())
+            self.write(buffer, 3, State::Done)
         })
     }
 
@@ -231,11 +391,7 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             buffer[1] = ((threshold & 0xFF00) >> 8) as u8;
             buffer[2] = (threshold & 0xFF) as u8;
 
-            // TODO verify errors
-            let _ = self.i2c.write(buffer, 3);
-            self.state.set(State::Done);
-
-            Ok(())
+            self.write(buffer, 3, State::Done)
         })
     }
 
@@ -247,11 +403,7 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             buffer[1] = ((threshold & 0xFF00) >> 8) as u8;
             buffer[2] = (threshold & 0xFF) as u8;
 
-            // TODO verify errors
-            let _ = self.i2c.write(buffer, 3);
-            self.state.set(State::Done);
-
-            Ok(())
+            self.write(buffer, 3, State::Done)
         })
     }
 
@@ -262,11 +414,7 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
 
             // Read all of the first four registers rather than wasting
             // time writing an address.
-            // TODO verify errors
-            let _ = self.i2c.read(buffer, 4);
-            self.state.set(State::ReadCharge);
-
-            Ok(())
+            self.read(buffer, 4, State::ReadCharge)
         })
     }
 
@@ -278,11 +426,7 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
                 self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
                     self.i2c.enable();
 
-                    // TODO verify errors
-                    let _ = self.i2c.read(buffer, 10);
-                    self.state.set(State::ReadVoltage);
-
-                    Ok(())
+                    self.read(buffer, 10, State::ReadVoltage)
                 })
             }
             _ => Err(ErrorCode::NOSUPPORT),
@@ -296,31 +440,79 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             ChipModel::LTC2943 => self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
                 self.i2c.enable();
 
-                // TODO verify errors
-                let _ = self.i2c.read(buffer, 16);
-                self.state.set(State::ReadCurrent);
-
-                Ok(())
+                self.read(buffer, 16, State::ReadCurrent)
             }),
             _ => Err(ErrorCode::NOSUPPORT),
         }
     }
 
+    /// Get the raw reading of the internal temperature sensor, and handle it
+    /// in `state`.
+    fn get_temperature(&self, state: State) -> Result<(), ErrorCode> {
+        // Not supported on all versions
+        let read_len = match self.model.get() {
+            ChipModel::LTC2942 => Registers::TemperatureLSB as usize + 1,
+            ChipModel::LTC2943 => Registers::LTC2943TemperatureLSB as usize + 1,
+            _ => return Err(ErrorCode::NOSUPPORT),
+        };
+
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            self.i2c.enable();
+
+            // Read from the status register up to the temperature rather
+            // than writing an address.
+            self.read(buffer, read_len, state)
+        })
+    }
+
     /// Put the LTC294X in a low power state.
-    fn shutdown(&self) -> Result<(), ErrorCode> {
+    pub fn shutdown(&self) -> Result<(), ErrorCode> {
         self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
             self.i2c.enable();
 
-            // Read both the status and control register rather than
-            // writing an address.
-            // TODO verify errors
-            let _ = self.i2c.read(buffer, 2);
-            self.state.set(State::ReadShutdown);
+            self.write_control(buffer, self.control.get() | CONTROL_SHUTDOWN)
+        })
+    }
 
-            Ok(())
+    /// Bring the LTC294X back from the low power state.
+    pub fn wake(&self) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            self.i2c.enable();
+
+            self.write_control(buffer, self.control.get() & !CONTROL_SHUTDOWN)
         })
     }
 
+    /// Set the value of the sense resistor in microohms. 0 means the value is
+    /// not known and charge is only reported as the raw register value.
+    pub fn set_sense_resistor_uohms(&self, sense_resistor: u32) {
+        self.sense_resistor.set(sense_resistor);
+    }
+
+    fn charge_to_uah(&self, charge: u16) -> Option<i32> {
+        charge_to_uah(
+            self.model.get(),
+            self.prescaler.get(),
+            self.sense_resistor.get(),
+            charge,
+        )
+    }
+
+    /// The most recent accumulated charge reading in milliampere-hours, or
+    /// `None` if there is no reading yet or no sense resistor is configured.
+    pub fn charge_mah(&self) -> Option<u32> {
+        self.charge
+            .get()
+            .and_then(|charge| self.charge_to_uah(charge))
+            .map(|uah| uah as u32 / 1000)
+    }
+
+    /// Set the LTC294X model actually on the board. Kernel users of the chip
+    /// need this to access the features of the LTC2942 and LTC2943.
+    pub fn set_chip_model(&self, model: ChipModel) {
+        self.model.set(model);
+    }
+
     /// Set the LTC294X model actually on the board.
     fn set_model(&self, model_num: usize) -> Result<(), ErrorCode> {
         match model_num {
@@ -339,31 +531,39 @@ impl<'a, I: i2c::I2CDevice> LTC294X<'a, I> {
             _ => Err(ErrorCode::NODEVICE),
         }
     }
-}
 
-impl<I: i2c::I2CDevice> i2c::I2CClient for LTC294X<'_, I> {
-    fn command_complete(&self, buffer: &'static mut [u8], _status: Result<(), i2c::Error>) {
+    /// Handle the registers of a successful transfer.
+    fn decode(&self, buffer: &'static mut [u8]) {
         match self.state.get() {
-            State::ReadStatus => {
+            State::ReadStatus | State::ReadInterruptStatus => {
                 let status = buffer[0];
                 let uvlock = (status & 0x01) > 0;
                 let vbata = (status & 0x02) > 0;
                 let ca_low = (status & 0x04) > 0;
                 let ca_high = (status & 0x08) > 0;
                 let accover = (status & 0x20) > 0;
-                self.client.map(|client| {
-                    client.status(uvlock, vbata, ca_low, ca_high, accover);
-                });
+                let interrupt = self.state.get() == State::ReadInterruptStatus;
 
                 self.buffer.replace(buffer);
                 self.i2c.disable();
                 self.state.set(State::Idle);
+
+                self.client.map(|client| {
+                    if interrupt {
+                        client.interrupt(uvlock, vbata, ca_low, ca_high, accover);
+                    } else {
+                        client.status(uvlock, vbata, ca_low, ca_high, accover);
+                    }
+                });
             }
             State::ReadCharge => {
-                // Charge is calculated in user space
                 let charge = ((buffer[2] as u16) << 8) | (buffer[3] as u16);
+                self.charge.set(charge);
                 self.client.map(|client| {
                     client.charge(charge);
+                    if self.sense_resistor.get() != 0 {
+                        client.charge_uah(self.charge_to_uah(charge).ok_or(ErrorCode::SIZE));
+                    }
                 });
 
                 self.buffer.replace(buffer);
@@ -390,18 +590,36 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for LTC294X<'_, I> {
                 self.i2c.disable();
                 self.state.set(State::Idle);
             }
-            State::ReadShutdown => {
-                // Set the shutdown pin to 1
-                buffer[1] |= 0x01;
-
-                // Write the control register back but with a 1 in the shutdown
-                // bit.
-                buffer[0] = Registers::Control as u8;
-                // TODO verify errors
-                let _ = self.i2c.write(buffer, 2);
-                self.state.set(State::Done);
+            State::ReadTemperature | State::ReadSensorTemperature => {
+                let msb = match self.model.get() {
+                    ChipModel::LTC2943 => Registers::LTC2943TemperatureMSB,
+                    _ => Registers::TemperatureMSB,
+                } as usize;
+                let temperature = ((buffer[msb] as u16) << 8) | (buffer[msb + 1] as u16);
+                let sensor = self.state.get() == State::ReadSensorTemperature;
+
+                self.buffer.replace(buffer);
+                self.i2c.disable();
+                self.state.set(State::Idle);
+
+                if sensor {
+                    self.temperature_client.map(|client| {
+                        client.callback(Ok(temperature_to_centi_celsius(
+                            self.model.get(),
+                            temperature,
+                        )));
+                    });
+                } else {
+                    self.client.map(|client| {
+                        client.temperature(temperature);
+                    });
+                }
             }
-            State::Done => {
+            State::WriteControl | State::Done => {
+                if self.state.get() == State::WriteControl {
+                    self.control.set(buffer[1]);
+                }
+
                 self.client.map(|client| {
                     client.done();
                 });
@@ -415,11 +633,84 @@ impl<I: i2c::I2CDevice> i2c::I2CClient for LTC294X<'_, I> {
     }
 }
 
+/// Convert a raw accumulated charge register value to microampere-hours for
+/// the given chip model, prescaler exponent and sense resistor in microohms.
+/// Returns `None` if the sense resistor is unknown or the result does not fit
+/// in an `i32`.
+fn charge_to_uah(model: ChipModel, prescaler: u8, sense_resistor: u32, charge: u16) -> Option<i32> {
+    if sense_resistor == 0 {
+        return None;
+    }
+
+    let prescaler = prescaler as u32 & 0x07;
+    // qLSB for a 50 mOhm sense resistor in uAh, the prescaler M and the
+    // prescaler value qLSB is specified at.
+    let (qlsb_uah, m, m_ref): (u64, u64, u64) = match model {
+        ChipModel::LTC2941 | ChipModel::LTC2942 => (85, 1 << prescaler, 128),
+        // M goes up in powers of four on the LTC2943 and saturates at 4096.
+        ChipModel::LTC2943 => (340, 1 << (2 * prescaler.min(6)), 4096),
+    };
+
+    // The numerator is at most 0xFFFF * 340 * 50_000 * 4096, which fits in
+    // a u64.
+    let uah = (charge as u64) * qlsb_uah * 50_000 * m / (m_ref * sense_resistor as u64);
+    i32::try_from(uah).ok()
+}
+
+/// Convert a raw temperature register value to centidegrees Celsius for the
+/// given chip model.
+fn temperature_to_centi_celsius(model: ChipModel, raw: u16) -> i32 {
+    // Full scale of the temperature ADC in centikelvin.
+    let full_scale: u32 = match model {
+        ChipModel::LTC2943 => 51_000,
+        _ => 60_000,
+    };
+    // 0xFFFF * 60_000 still fits in a u32.
+    (raw as u32 * full_scale / 0xFFFF) as i32 - 27_315
+}
+
+impl<I: i2c::I2CDevice> i2c::I2CClient for LTC294X<'_, I> {
+    fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
+        if let Err(error) = status {
+            // Whatever was read is not a reading.
+            let state = self.state.get();
+            self.buffer.replace(buffer);
+            self.i2c.disable();
+            self.state.set(State::Idle);
+
+            if state == State::ReadSensorTemperature {
+                self.temperature_client.map(|client| {
+                    client.callback(Err(error.into()));
+                });
+            } else {
+                self.client.map(|client| {
+                    client.error(error.into());
+                });
+            }
+        } else {
+            self.decode(buffer);
+        }
+
+        if self.state.get() == State::Idle && self.interrupt_pending.take() {
+            self.read_interrupt_status();
+        }
+    }
+}
+
+impl<'a, I: i2c::I2CDevice> sensors::TemperatureDriver<'a> for LTC294X<'a, I> {
+    fn set_client(&self, client: &'a dyn sensors::TemperatureClient) {
+        self.temperature_client.set(client);
+    }
+
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        self.get_temperature(State::ReadSensorTemperature)
+    }
+}
+
 impl<I: i2c::I2CDevice> gpio::Client for LTC294X<'_, I> {
     fn fired(&self) {
-        self.client.map(|client| {
-            client.interrupt();
-        });
+        // Report the interrupt together with the status that caused it.
+        self.read_interrupt_status();
     }
 }
 
@@ -428,12 +719,19 @@ mod upcall {
     /// The callback that that is triggered when events finish and when readings
     /// are ready. The first argument represents which callback was triggered.
     ///
-    /// - `0`: Interrupt occurred from the LTC294X.
+    /// - `0`: Interrupt occurred from the LTC294X. The status is passed
+    ///   along as with `1`.
     /// - `1`: Got the status.
     /// - `2`: Read the charge used.
     /// - `3`: `done()` was called.
     /// - `4`: Read the voltage.
     /// - `5`: Read the current.
+    /// - `6`: Read the charge used in microampere-hours.
+    /// - `7`: Read the temperature. The second argument is the raw reading
+    ///   and the third the temperature in centidegrees Celsius.
+    /// - `8`: The operation failed on the I2C bus, or the charge read in
+    ///   microampere-hours does not fit (`SIZE`). The second argument is the
+    ///   error as a status code.
     pub const EVENT_FINISHED: usize = 0;
     /// Number of upcalls.
     pub const COUNT: u8 = 1;
@@ -443,8 +741,9 @@ mod upcall {
 /// interface for providing access to applications.
 pub struct LTC294XDriver<'a, I: i2c::I2CDevice> {
     ltc294x: &'a LTC294X<'a, I>,
-    grants: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
-    owning_process: OptionalCell<ProcessId>,
+    owner: SingleOwner<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// Whether the pending charge reading was requested in microampere-hours.
+    charge_in_uah: Cell<bool>,
 }
 
 impl<'a, I: i2c::I2CDevice> LTC294XDriver<'a, I> {
@@ -454,25 +753,17 @@ impl<'a, I: i2c::I2CDevice> LTC294XDriver<'a, I> {
     ) -> LTC294XDriver<'a, I> {
         LTC294XDriver {
             ltc294x: ltc,
-            grants: grants,
-            owning_process: OptionalCell::empty(),
+            owner: SingleOwner::new(grants),
+            charge_in_uah: Cell::new(false),
         }
     }
 }
 
-impl<I: i2c::I2CDevice> LTC294XClient for LTC294XDriver<'_, I> {
-    fn interrupt(&self) {
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(upcall::EVENT_FINISHED, (0, 0, 0))
-                    .ok();
-            });
-        });
-    }
-
-    fn status(
+impl<'a, I: i2c::I2CDevice> LTC294XDriver<'a, I> {
+    /// Send the status bits to the owning process as event `event`.
+    fn status_upcall(
         &self,
+        event: usize,
         undervolt_lockout: bool,
         vbat_alert: bool,
         charge_alert_low: bool,
@@ -484,67 +775,113 @@ impl<I: i2c::I2CDevice> LTC294XClient for LTC294XDriver<'_, I> {
             | ((charge_alert_low as usize) << 2)
             | ((charge_alert_high as usize) << 3)
             | ((accumulated_charge_overflow as usize) << 4);
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(
-                        upcall::EVENT_FINISHED,
-                        (1, ret, self.ltc294x.model.get() as usize),
-                    )
-                    .ok();
-            });
-        });
+        self.owner.schedule_upcall(
+            upcall::EVENT_FINISHED,
+            (event, ret, self.ltc294x.model.get() as usize),
+        );
+    }
+}
+
+impl<I: i2c::I2CDevice> LTC294XClient for LTC294XDriver<'_, I> {
+    fn interrupt(
+        &self,
+        undervolt_lockout: bool,
+        vbat_alert: bool,
+        charge_alert_low: bool,
+        charge_alert_high: bool,
+        accumulated_charge_overflow: bool,
+    ) {
+        self.status_upcall(
+            0,
+            undervolt_lockout,
+            vbat_alert,
+            charge_alert_low,
+            charge_alert_high,
+            accumulated_charge_overflow,
+        );
+    }
+
+    fn status(
+        &self,
+        undervolt_lockout: bool,
+        vbat_alert: bool,
+        charge_alert_low: bool,
+        charge_alert_high: bool,
+        accumulated_charge_overflow: bool,
+    ) {
+        self.status_upcall(
+            1,
+            undervolt_lockout,
+            vbat_alert,
+            charge_alert_low,
+            charge_alert_high,
+            accumulated_charge_overflow,
+        );
     }
 
     fn charge(&self, charge: u16) {
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(upcall::EVENT_FINISHED, (2, charge as usize, 0))
-                    .ok();
-            });
-        });
+        if self.charge_in_uah.get() {
+            return;
+        }
+        self.owner
+            .schedule_upcall(upcall::EVENT_FINISHED, (2, charge as usize, 0));
+    }
+
+    fn charge_uah(&self, uah: Result<i32, ErrorCode>) {
+        if !self.charge_in_uah.get() {
+            return;
+        }
+        match uah {
+            Ok(uah) => self
+                .owner
+                .schedule_upcall(upcall::EVENT_FINISHED, (6, uah as usize, 0)),
+            Err(error) => self.error(error),
+        }
     }
 
     fn done(&self) {
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(upcall::EVENT_FINISHED, (3, 0, 0))
-                    .ok();
-            });
-        });
+        self.owner
+            .schedule_upcall(upcall::EVENT_FINISHED, (3, 0, 0));
     }
 
     fn voltage(&self, voltage: u16) {
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(upcall::EVENT_FINISHED, (4, voltage as usize, 0))
-                    .ok();
-            });
-        });
+        self.owner
+            .schedule_upcall(upcall::EVENT_FINISHED, (4, voltage as usize, 0));
     }
 
     fn current(&self, current: u16) {
-        self.owning_process.map(|pid| {
-            let _res = self.grants.enter(pid, |_app, upcalls| {
-                upcalls
-                    .schedule_upcall(upcall::EVENT_FINISHED, (5, current as usize, 0))
-                    .ok();
-            });
-        });
+        self.owner
+            .schedule_upcall(upcall::EVENT_FINISHED, (5, current as usize, 0));
+    }
+
+    fn error(&self, error: ErrorCode) {
+        self.owner
+            .schedule_upcall(upcall::EVENT_FINISHED, (8, into_statuscode(Err(error)), 0));
+    }
+
+    fn temperature(&self, raw: u16) {
+        self.owner.schedule_upcall(
+            upcall::EVENT_FINISHED,
+            (
+                7,
+                raw as usize,
+                temperature_to_centi_celsius(self.ltc294x.model.get(), raw) as usize,
+            ),
+        );
     }
 }
 
-impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
+impl<I: i2c::I2CDevice> SingleOwnerCommands for LTC294XDriver<'_, I> {
+    const NOT_OWNER: ErrorCode = ErrorCode::NOMEM;
+
     /// Request operations for the LTC294X chip.
     ///
     /// ### `command_num`
     ///
     /// - `0`: Driver existence check.
     /// - `1`: Get status of the chip.
-    /// - `2`: Configure settings of the chip.
+    /// - `2`: Configure settings of the chip. `data2` is the sense resistor
+    ///   value in milliohms, or 0 if it is not known.
     /// - `3`: Reset accumulated charge measurement to zero.
     /// - `4`: Set the upper threshold for charge.
     /// - `5`: Set the lower threshold for charge.
@@ -555,30 +892,17 @@ impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
     /// - `9`: Get the current reading. Only supported on the LTC2943.
     /// - `10`: Set the model of the LTC294X actually being used. `data` is the
     ///   value of the X.
-    fn command(
+    /// - `11`: Get the current charge accumulated in microampere-hours. Fails
+    ///   with `INVAL` if the sense resistor value is not known.
+    /// - `12`: Get the temperature. Only supported on the LTC2942 and LTC2943.
+    /// - `13`: Wake the chip up after a shutdown.
+    fn owner_command(
         &self,
         command_num: usize,
         data: usize,
-        _: usize,
-        process_id: ProcessId,
+        data2: usize,
+        _process_id: ProcessId,
     ) -> CommandReturn {
-        if command_num == 0 {
-            // Handle this first as it should be returned
-            // unconditionally
-            return CommandReturn::success();
-        }
-
-        let match_or_empty_or_nonexistant = self.owning_process.map_or(true, |current_process| {
-            self.grants
-                .enter(current_process, |_, _| current_process == process_id)
-                .unwrap_or(true)
-        });
-        if match_or_empty_or_nonexistant {
-            self.owning_process.set(process_id);
-        } else {
-            return CommandReturn::failure(ErrorCode::NOMEM);
-        }
-
         match command_num {
             // Get status.
             1 => self.ltc294x.read_status().into(),
@@ -603,7 +927,7 @@ impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
                 };
 
                 self.ltc294x
-                    .configure(int_pin_conf, prescaler as u8, vbat_alert)
+                    .configure(int_pin_conf, prescaler as u8, vbat_alert, data2 as u32)
                     .into()
             }
 
@@ -617,7 +941,10 @@ impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
             5 => self.ltc294x.set_low_threshold(data as u16).into(),
 
             // Get charge
-            6 => self.ltc294x.get_charge().into(),
+            6 => {
+                self.charge_in_uah.set(false);
+                self.ltc294x.get_charge().into()
+            }
 
             // Shutdown
             7 => self.ltc294x.shutdown().into(),
@@ -631,12 +958,126 @@ impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
             // Set the current chip model
             10 => self.ltc294x.set_model(data).into(),
 
+            // Get charge in uAh
+            11 => {
+                if self.ltc294x.sense_resistor.get() == 0 {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.charge_in_uah.set(true);
+                self.ltc294x.get_charge().into()
+            }
+
+            // Get temperature
+            12 => self.ltc294x.get_temperature(State::ReadTemperature).into(),
+
+            // Wake up
+            13 => self.ltc294x.wake().into(),
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 
+    fn is_busy(&self) -> bool {
+        self.ltc294x.is_busy()
+    }
+}
+
+impl<I: i2c::I2CDevice> SyscallDriver for LTC294XDriver<'_, I> {
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        data2: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn {
+        self.owner
+            .command(self, command_num, data, data2, process_id)
+    }
+
     fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
-        self.grants.enter(processid, |_, _| {})
+        self.owner.allocate_grant(processid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOHM: u32 = 1000;
+
+    #[test]
+    fn ltc2941_charge() {
+        // qLSB is 0.085 mAh with 50 mOhm and M = 128.
+        assert_eq!(charge_to_uah(ChipModel::LTC2941, 7, 50 * MOHM, 1), Some(85));
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2941, 7, 50 * MOHM, 0x7FFF),
+            Some(2_785_195)
+        );
+        // M = 1 divides qLSB by 128.
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2941, 0, 50 * MOHM, 0xFFFF),
+            Some(43_519)
+        );
+    }
+
+    #[test]
+    fn ltc2942_charge() {
+        // Doubling the sense resistor halves qLSB.
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2942, 7, 100 * MOHM, 1000),
+            Some(42_500)
+        );
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2942, 7, 50 * MOHM + 500, 0),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn ltc2943_charge() {
+        // qLSB is 0.340 mAh with 50 mOhm and M = 4096.
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2943, 6, 50 * MOHM, 1),
+            Some(340)
+        );
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2943, 7, 50 * MOHM, 0xFFFF),
+            Some(22_281_900)
+        );
+        // M = 1 divides qLSB by 4096.
+        assert_eq!(
+            charge_to_uah(ChipModel::LTC2943, 0, 50 * MOHM, 4096),
+            Some(340)
+        );
+    }
+
+    #[test]
+    fn temperature() {
+        // 0 K and full scale.
+        assert_eq!(temperature_to_centi_celsius(ChipModel::LTC2942, 0), -27_315);
+        assert_eq!(
+            temperature_to_centi_celsius(ChipModel::LTC2942, 0xFFFF),
+            32_685
+        );
+        assert_eq!(
+            temperature_to_centi_celsius(ChipModel::LTC2943, 0xFFFF),
+            23_685
+        );
+        // About 25 C.
+        assert_eq!(
+            temperature_to_centi_celsius(ChipModel::LTC2942, 0x7F40),
+            2_509
+        );
+        assert_eq!(
+            temperature_to_centi_celsius(ChipModel::LTC2943, 0x9580),
+            2_468
+        );
+    }
+
+    #[test]
+    fn charge_out_of_range() {
+        assert_eq!(charge_to_uah(ChipModel::LTC2941, 7, 0, 0x7FFF), None);
+        assert_eq!(charge_to_uah(ChipModel::LTC2943, 7, 1, 0xFFFF), None);
     }
 }
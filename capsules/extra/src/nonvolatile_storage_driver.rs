@@ -13,6 +13,37 @@
 //! as the userspace accessible address space. The kernel memory can overlap
 //! if desired, or can be a completely separate range.
 //!
+//! Read and write completions are signaled to userspace with the number of
+//! bytes transferred as the first argument and a statuscode as the second.
+//! If the physical storage reports a failure, the statuscode holds the error
+//! and the length is the number of bytes completed before the failure.
+//! Erase completions are signaled the same way, and need no allowed buffer.
+//!
+//! A write command only checks and records the request, so it takes the same
+//! time whatever its length. The data is copied from the allowed write
+//! buffer when the write is handed to the physical storage, from a deferred
+//! call if the storage is idle, or once the operations before it completed.
+//! The components register the deferred call. Boards creating the capsule
+//! themselves have to call `DeferredCallClient::register()`, which the
+//! kernel checks at boot with `DeferredCall::verify_setup()`.
+//! The bytes written are the contents of the buffer at that time, up to the
+//! length of the buffer then. If the app un-allowed the buffer, the write
+//! completes with `RESERVE` and nothing written.
+//!
+//! The page and erase block sizes of the physical storage, given to the
+//! capsule as a `StorageGeometry`, are returned to apps by commands 6 and 7,
+//! and to the kernel by `geometry()`, so that writes can be aligned to them.
+//!
+//! A batch writes several records back to back as a single request, with a
+//! single upcall. The app allows descriptors of the writes, each an offset
+//! and a length followed by a status word, as `u32` little endian, and the
+//! data of the writes one after the other in the write buffer. All entries
+//! are checked before the first is written. The upcall reports the number
+//! of entries written and a statuscode, and the status word of each entry
+//! is set: success for the entries written, the error of the entry that
+//! failed, and `CANCEL` for the entries after it. Kernel clients write
+//! batches with `write_batch()`.
+//!
 //! Here is a diagram of the expected stack with this capsule:
 //! Boxes are components and between the boxes are the traits that are the
 //! interfaces between components. This capsule provides both a kernel and
@@ -54,16 +85,63 @@
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
+//!         capsules::nonvolatile_storage_driver::StorageGeometry {
+//!             page_size: 1,            // FRAM writes and erases bytes.
+//!             erase_size: 1,
+//!             total_size: 8192,
+//!         },
 //!         &mut capsules::nonvolatile_storage_driver::BUFFER));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
+//! kernel::deferred_call::DeferredCallClient::register(nonvolatile_storage);
 //! ```
+//!
+//! Operations run one at a time, the others wait in turn. Besides the
+//! kernel client of the capsule, kernel capsules can each use the kernel
+//! region through a `NonvolatileStorageUser`. The waiting requests of
+//! `Priority::High` users, such as a crash recorder, run before the other
+//! kernel and userspace requests, but once `MAX_HIGH_PRIORITY_STREAK` of them
+//! ran in a row, one of the others runs first. High priority users are
+//! served in the order they were set up. Once `MAX_KERNEL_STREAK` kernel
+//! requests ran in a row, a waiting userspace request runs first. An
+//! operation in progress is never interrupted.
+//!
+//! Each kernel client can have two requests waiting. A client streaming
+//! large transfers keeps the storage busy by using two buffers: while one is
+//! read or written, the request for the other one waits. When the first
+//! completes, the next operation is started before the client is called
+//! back, and the client queues the following chunk from the callback. The
+//! second waiting request holds that chunk while a userspace request takes
+//! its turn.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let recorder_storage = static_init!(
+//!     capsules::nonvolatile_storage_driver::NonvolatileStorageUser<'static>,
+//!     capsules::nonvolatile_storage_driver::NonvolatileStorageUser::new(
+//!         nonvolatile_storage,
+//!         capsules::nonvolatile_storage_driver::Priority::High));
+//! recorder_storage.setup();
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(recorder_storage, recorder);
+//! ```
+//!
+//! A `NonvolatileLog` on top of the kernel interface appends records of
+//! variable length to part of the kernel region, and keeps where the next
+//! one goes in the storage, so kernel capsules can replay them after a
+//! reset.
 
 use core::cell::Cell;
 use core::cmp;
 
-use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
 use kernel::hil;
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::platform::watchdog::WorkProgress;
+use kernel::processbuffer::{
+    ReadableProcessBuffer, ReadableProcessSlice, WriteableProcessBuffer, WriteableProcessSlice,
+};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -78,8 +156,12 @@ mod upcall {
     pub const READ_DONE: usize = 0;
     /// Write done callback.
     pub const WRITE_DONE: usize = 1;
+    /// Erase done callback.
+    pub const ERASE_DONE: usize = 2;
+    /// Batch done callback.
+    pub const BATCH_DONE: usize = 3;
     /// Number of upcalls.
-    pub const COUNT: u8 = 2;
+    pub const COUNT: u8 = 4;
 }
 
 /// Ids for read-only allow buffers
@@ -94,24 +176,221 @@ mod ro_allow {
 mod rw_allow {
     /// Setup a buffer to read from the nonvolatile storage into.
     pub const READ: usize = 0;
+    /// Setup the descriptors of a batch of writes.
+    pub const BATCH: usize = 1;
     /// The number of allow buffers the kernel stores for this grant
-    pub const COUNT: u8 = 1;
+    pub const COUNT: u8 = 2;
 }
 
 pub const BUF_LEN: usize = 512;
 
+/// Most requests of high priority users run in a row while other requests
+/// wait.
+pub const MAX_HIGH_PRIORITY_STREAK: usize = 4;
+
+/// Most kernel requests run in a row while userspace requests wait.
+pub const MAX_KERNEL_STREAK: usize = 8;
+
+/// Most entries of a batch of writes.
+pub const MAX_BATCH_ENTRIES: usize = 16;
+
+/// Bytes of the descriptor of each entry of a batch allowed by an app.
+pub const BATCH_DESCRIPTOR_LEN: usize = 12;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
     UserspaceWrite,
+    UserspaceErase,
+    UserspaceBatchWrite,
     KernelRead,
     KernelWrite,
 }
 
+/// Layout of the physical storage, for users to pick writes that do not
+/// straddle pages or erase blocks. Storage that can write or erase any byte,
+/// such as FRAM, has sizes of 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StorageGeometry {
+    /// Bytes of a page, the unit the storage writes.
+    pub page_size: usize,
+    /// Bytes of an erase block.
+    pub erase_size: usize,
+    /// Bytes of the whole storage.
+    pub total_size: usize,
+}
+
 #[derive(Clone, Copy)]
-pub enum NonvolatileUser {
+pub enum NonvolatileUser<'a> {
     App { processid: ProcessId },
     Kernel,
+    KernelUser(&'a NonvolatileStorageUser<'a>),
+}
+
+/// Priority of the requests of a `NonvolatileStorageUser`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+/// One write of a batch of a kernel client: `length` bytes at `address`,
+/// from the batch data following the entries before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchEntry {
+    pub address: usize,
+    pub length: usize,
+    /// Set when the batch completes: success if the entry was written, the
+    /// error if it failed, and `CANCEL` if it was not attempted.
+    pub status: Result<(), ErrorCode>,
+}
+
+impl BatchEntry {
+    pub const fn new(address: usize, length: usize) -> BatchEntry {
+        BatchEntry {
+            address,
+            length,
+            status: Err(ErrorCode::CANCEL),
+        }
+    }
+}
+
+pub trait BatchClient {
+    /// The batch completed, with the first `completed` entries written. On
+    /// an error, the entry at index `completed` failed.
+    fn batch_done(
+        &self,
+        data: &'static mut [u8],
+        entries: &'static mut [BatchEntry],
+        completed: usize,
+        result: Result<(), ErrorCode>,
+    );
+}
+
+// The status of entry `index` of a batch that completed with `result` after
+// `completed` entries.
+fn entry_status(
+    index: usize,
+    completed: usize,
+    result: Result<(), ErrorCode>,
+) -> Result<(), ErrorCode> {
+    match index.cmp(&completed) {
+        cmp::Ordering::Less => Ok(()),
+        cmp::Ordering::Equal => result,
+        cmp::Ordering::Greater => Err(ErrorCode::CANCEL),
+    }
+}
+
+// The offset and length of entry `index` of the descriptors of an app.
+fn read_descriptor(descriptors: &ReadableProcessSlice, index: usize) -> Option<(usize, usize)> {
+    let descriptor =
+        descriptors.get(index * BATCH_DESCRIPTOR_LEN..(index + 1) * BATCH_DESCRIPTOR_LEN)?;
+    let (mut offset, mut length) = ([0; 4], [0; 4]);
+    descriptor[0..4].copy_to_slice(&mut offset);
+    descriptor[4..8].copy_to_slice(&mut length);
+    Some((
+        u32::from_le_bytes(offset) as usize,
+        u32::from_le_bytes(length) as usize,
+    ))
+}
+
+// A size returned to userspace, which only gets 32 bits.
+fn size_u32(size: usize) -> CommandReturn {
+    u32::try_from(size).map_or(
+        CommandReturn::failure(ErrorCode::SIZE),
+        CommandReturn::success_u32,
+    )
+}
+
+fn write_status(descriptors: &WriteableProcessSlice, index: usize, status: usize) {
+    if let Some(field) =
+        descriptors.get(index * BATCH_DESCRIPTOR_LEN + 8..(index + 1) * BATCH_DESCRIPTOR_LEN)
+    {
+        field.copy_from_slice(&(status as u32).to_le_bytes());
+    }
+}
+
+/// A read/write of a kernel client waiting for the storage.
+struct QueuedRequest {
+    command: Cell<Option<NonvolatileCommand>>,
+    buffer: TakeCell<'static, [u8]>,
+    address: Cell<usize>,
+    length: Cell<usize>,
+}
+
+impl QueuedRequest {
+    fn new() -> QueuedRequest {
+        QueuedRequest {
+            command: Cell::new(None),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.command.get().is_some()
+    }
+}
+
+/// The requests of a kernel client waiting for the storage, in order. The
+/// second one holds the next chunk of a client streaming with two buffers,
+/// queued while the first waits for a userspace request to complete.
+struct KernelRequest {
+    queue: [QueuedRequest; 2],
+}
+
+impl KernelRequest {
+    fn new() -> KernelRequest {
+        KernelRequest {
+            queue: [QueuedRequest::new(), QueuedRequest::new()],
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.queue[0].is_pending()
+    }
+
+    fn push(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        // No more room in the queue, nowhere to store this request.
+        let slot = self
+            .queue
+            .iter()
+            .find(|slot| !slot.is_pending())
+            .ok_or(ErrorCode::NOMEM)?;
+        slot.command.set(Some(command));
+        slot.address.set(address);
+        slot.length.set(length);
+        slot.buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<(NonvolatileCommand, &'static mut [u8], usize, usize)> {
+        let [first, second] = &self.queue;
+        let command = first.command.take()?;
+        let request = (
+            command,
+            first.buffer.take()?,
+            first.address.get(),
+            first.length.get(),
+        );
+        if let Some(command) = second.command.take() {
+            first.command.set(Some(command));
+            first.address.set(second.address.get());
+            first.length.set(second.length.get());
+            second
+                .buffer
+                .take()
+                .map(|buffer| first.buffer.replace(buffer));
+        }
+        Some(request)
+    }
 }
 
 pub struct App {
@@ -145,8 +424,9 @@ pub struct NonvolatileStorage<'a> {
 
     // Internal buffer for copying appslices into.
     buffer: TakeCell<'static, [u8]>,
+    buffer_len: usize,
     // What issued the currently executing call. This can be an app or the kernel.
-    current_user: OptionalCell<NonvolatileUser>,
+    current_user: OptionalCell<NonvolatileUser<'a>>,
 
     // The first byte that is accessible from userspace.
     userspace_start_address: usize,
@@ -156,20 +436,39 @@ pub struct NonvolatileStorage<'a> {
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
     kernel_length: usize,
+    // Layout of the physical storage.
+    geometry: StorageGeometry,
 
     // Optional client for the kernel. Only needed if the kernel intends to use
     // this nonvolatile storage.
     kernel_client: OptionalCell<&'a dyn hil::nonvolatile_storage::NonvolatileStorageClient>,
-    // Whether the kernel is waiting for a read/write.
-    kernel_pending_command: Cell<bool>,
-    // Whether the kernel wanted a read/write.
-    kernel_command: Cell<NonvolatileCommand>,
-    // Holder for the buffer passed from the kernel in case we need to wait.
-    kernel_buffer: TakeCell<'static, [u8]>,
-    // How many bytes to read/write from the kernel buffer.
-    kernel_readwrite_length: Cell<usize>,
-    // Where to read/write from the kernel request.
-    kernel_readwrite_address: Cell<usize>,
+    // The read/write of the kernel client waiting for the storage.
+    kernel_request: KernelRequest,
+    // The batch of the kernel client, waiting or in progress, and its client.
+    kernel_batch_data: TakeCell<'static, [u8]>,
+    kernel_batch_entries: TakeCell<'static, [BatchEntry]>,
+    kernel_batch_waiting: Cell<bool>,
+    batch_client: OptionalCell<&'a dyn BatchClient>,
+    // The batch the current user is writing: whether there is one, its
+    // number of entries, the entry being written, its length, and where
+    // its data starts.
+    batch_active: Cell<bool>,
+    batch_count: Cell<usize>,
+    batch_index: Cell<usize>,
+    batch_entry_length: Cell<usize>,
+    batch_data_offset: Cell<usize>,
+    // The other kernel clients, each with its own waiting request.
+    users: List<'a, NonvolatileStorageUser<'a>>,
+    // How many requests of high priority users ran in a row.
+    high_priority_streak: Cell<usize>,
+    // How many kernel requests ran in a row.
+    kernel_streak: Cell<usize>,
+
+    // Optional watchdog handle, told when an operation of the underlying
+    // storage starts and when it completes.
+    work_progress: OptionalCell<WorkProgress<'a>>,
+    // Starts the app requests recorded while the storage was idle.
+    deferred_call: DeferredCall,
 }
 
 impl<'a> NonvolatileStorage<'a> {
@@ -185,23 +484,358 @@ impl<'a> NonvolatileStorage<'a> {
         userspace_length: usize,
         kernel_start_address: usize,
         kernel_length: usize,
+        geometry: StorageGeometry,
         buffer: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
         NonvolatileStorage {
             driver: driver,
             apps: grant,
+            buffer_len: buffer.len(),
             buffer: TakeCell::new(buffer),
             current_user: OptionalCell::empty(),
             userspace_start_address: userspace_start_address,
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
+            geometry: geometry,
             kernel_client: OptionalCell::empty(),
-            kernel_pending_command: Cell::new(false),
-            kernel_command: Cell::new(NonvolatileCommand::KernelRead),
-            kernel_buffer: TakeCell::empty(),
-            kernel_readwrite_length: Cell::new(0),
-            kernel_readwrite_address: Cell::new(0),
+            kernel_request: KernelRequest::new(),
+            kernel_batch_data: TakeCell::empty(),
+            kernel_batch_entries: TakeCell::empty(),
+            kernel_batch_waiting: Cell::new(false),
+            batch_client: OptionalCell::empty(),
+            batch_active: Cell::new(false),
+            batch_count: Cell::new(0),
+            batch_index: Cell::new(0),
+            batch_entry_length: Cell::new(0),
+            batch_data_offset: Cell::new(0),
+            users: List::new(),
+            high_priority_streak: Cell::new(0),
+            kernel_streak: Cell::new(0),
+            work_progress: OptionalCell::empty(),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+
+    /// The layout of the physical storage.
+    pub fn geometry(&self) -> StorageGeometry {
+        self.geometry
+    }
+
+    /// Report the operations of the underlying storage to the watchdog, so
+    /// one that never completes lets the watchdog reset the board.
+    pub fn set_work_progress(&self, work_progress: WorkProgress<'a>) {
+        self.work_progress.set(work_progress);
+    }
+
+    // Tell the watchdog an operation of the underlying storage started if
+    // `res` is a success.
+    fn operation_started(&self, res: Result<(), ErrorCode>) -> Result<(), ErrorCode> {
+        if res.is_ok() {
+            self.work_progress
+                .map(|work_progress| work_progress.start());
+        }
+        res
+    }
+
+    fn in_userspace_region(&self, offset: usize, length: usize) -> bool {
+        offset < self.userspace_length
+            && length <= self.userspace_length
+            && offset + length <= self.userspace_length
+    }
+
+    fn in_kernel_region(&self, offset: usize, length: usize) -> bool {
+        offset >= self.kernel_start_address
+            && offset < self.kernel_start_address + self.kernel_length
+            && length <= self.kernel_length
+            && offset + length <= self.kernel_start_address + self.kernel_length
+    }
+
+    // Check the `count` entries of a batch, given by `entry`, before any is
+    // written: each must be in its region and fit the internal buffer, and
+    // their data must fit in `data_len` bytes.
+    fn check_batch(
+        &self,
+        count: usize,
+        entry: impl Fn(usize) -> Option<(usize, usize)>,
+        in_region: impl Fn(usize, usize) -> bool,
+        data_len: usize,
+    ) -> Result<(), ErrorCode> {
+        if count == 0 || count > MAX_BATCH_ENTRIES {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut total = 0;
+        for index in 0..count {
+            let (address, length) = entry(index).ok_or(ErrorCode::SIZE)?;
+            if length == 0 || !in_region(address, length) {
+                return Err(ErrorCode::INVAL);
+            }
+            if length > self.buffer_len {
+                return Err(ErrorCode::SIZE);
+            }
+            total += length;
+        }
+        if total > data_len {
+            return Err(ErrorCode::SIZE);
+        }
+        Ok(())
+    }
+
+    // Check the batch of `count` entries of an app, and run it now if the
+    // storage is idle, otherwise queue it.
+    fn enqueue_batch(&self, count: usize, processid: ProcessId) -> Result<(), ErrorCode> {
+        let start_now = self
+            .apps
+            .enter(processid, |app, kernel_data| {
+                let data_len = kernel_data
+                    .get_readonly_processbuffer(ro_allow::WRITE)
+                    .map_or(0, |data| data.len());
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::BATCH)
+                    .and_then(|descriptors| {
+                        descriptors.enter(|descriptors| {
+                            self.check_batch(
+                                count,
+                                |index| read_descriptor(descriptors, index),
+                                |offset, length| self.in_userspace_region(offset, length),
+                                data_len,
+                            )
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE))?;
+
+                if self.current_user.is_none() {
+                    Ok(true)
+                } else if app.pending_command {
+                    // No more room in the queue, nowhere to store this
+                    // request.
+                    Err(ErrorCode::NOMEM)
+                } else {
+                    app.pending_command = true;
+                    app.command = NonvolatileCommand::UserspaceBatchWrite;
+                    app.length = count;
+                    Ok(false)
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        if start_now {
+            self.start_batch(NonvolatileUser::App { processid }, count)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Hand the storage to the batch of `count` entries of `user`, and start
+    // writing its first entry.
+    fn start_batch(&self, user: NonvolatileUser<'a>, count: usize) -> Result<(), ErrorCode> {
+        self.set_current_user(user);
+        self.batch_active.set(true);
+        self.batch_count.set(count);
+        self.batch_index.set(0);
+        self.batch_data_offset.set(0);
+        let res = self.batch_write_next();
+        if res.is_err() {
+            self.batch_active.set(false);
+            self.current_user.clear();
+        }
+        res
+    }
+
+    // Copy the data of the entry `index` of the batch of the current user
+    // into `buffer`, and return its physical address and length. The
+    // entries of an app are checked again, as it may change its buffers
+    // while the batch runs.
+    fn batch_entry(
+        &self,
+        index: usize,
+        data_offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(usize, usize), ErrorCode> {
+        match self.current_user.get() {
+            Some(NonvolatileUser::App { processid }) => self
+                .apps
+                .enter(processid, |_, kernel_data| {
+                    let (offset, length) = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::BATCH)
+                        .and_then(|descriptors| {
+                            descriptors.enter(|descriptors| read_descriptor(descriptors, index))
+                        })
+                        .ok()
+                        .flatten()
+                        .ok_or(ErrorCode::SIZE)?;
+                    if length > buffer.len() || !self.in_userspace_region(offset, length) {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    kernel_data
+                        .get_readonly_processbuffer(ro_allow::WRITE)
+                        .and_then(|data| {
+                            data.enter(|data| {
+                                data.get(data_offset..(data_offset + length))
+                                    .map(|data| data.copy_to_slice(&mut buffer[..length]))
+                                    .ok_or(ErrorCode::SIZE)
+                            })
+                        })
+                        .unwrap_or(Err(ErrorCode::RESERVE))?;
+                    Ok((offset + self.userspace_start_address, length))
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            Some(NonvolatileUser::Kernel) => {
+                self.kernel_batch_entries
+                    .map_or(Err(ErrorCode::FAIL), |entries| {
+                        let entry = entries.get(index).ok_or(ErrorCode::FAIL)?;
+                        let (address, length) = (entry.address, entry.length);
+                        self.kernel_batch_data
+                            .map_or(Err(ErrorCode::RESERVE), |data| {
+                                let data = data
+                                    .get(data_offset..(data_offset + length))
+                                    .ok_or(ErrorCode::SIZE)?;
+                                buffer
+                                    .get_mut(..length)
+                                    .ok_or(ErrorCode::SIZE)?
+                                    .copy_from_slice(data);
+                                Ok((address, length))
+                            })
+                    })
+            }
+            _ => Err(ErrorCode::FAIL),
+        }
+    }
+
+    // Start writing the current entry of the batch.
+    fn batch_write_next(&self) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        match self.batch_entry(self.batch_index.get(), self.batch_data_offset.get(), buffer) {
+            Ok((address, length)) => {
+                self.batch_entry_length.set(length);
+                let res = self.driver.write(buffer, address, length);
+                self.operation_started(res)
+            }
+            Err(e) => {
+                self.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    // The write of the current entry of the batch completed: write the next
+    // entry, or complete the batch.
+    fn batch_write_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        let mut completed = self.batch_index.get();
+        let mut result = result;
+        if result.is_ok() {
+            completed += 1;
+            self.batch_index.set(completed);
+            self.batch_data_offset
+                .set(self.batch_data_offset.get() + self.batch_entry_length.get());
+            if completed < self.batch_count.get() {
+                result = self.batch_write_next();
+                if result.is_ok() {
+                    return;
+                }
+            }
+        }
+
+        self.batch_active.set(false);
+        self.current_user
+            .map(|user| self.batch_done(user, completed, result));
+        self.current_user.clear();
+        self.check_queue();
+    }
+
+    // Report the completion of the batch of `user`, with the status of each
+    // entry.
+    fn batch_done(
+        &self,
+        user: NonvolatileUser<'a>,
+        completed: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        let count = self.batch_count.get();
+        match user {
+            NonvolatileUser::App { processid } => {
+                let _ = self.apps.enter(processid, |_, kernel_data| {
+                    let _ = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::BATCH)
+                        .and_then(|descriptors| {
+                            descriptors.mut_enter(|descriptors| {
+                                for index in 0..count {
+                                    let status = entry_status(index, completed, result);
+                                    write_status(descriptors, index, into_statuscode(status));
+                                }
+                            })
+                        });
+                    kernel_data
+                        .schedule_upcall(
+                            upcall::BATCH_DONE,
+                            (completed, into_statuscode(result), 0),
+                        )
+                        .ok();
+                });
+            }
+            _ => {
+                if let Some((data, entries)) = self.take_kernel_batch() {
+                    for (index, entry) in entries.iter_mut().enumerate() {
+                        entry.status = entry_status(index, completed, result);
+                    }
+                    self.batch_client
+                        .map(move |client| client.batch_done(data, entries, completed, result));
+                }
+            }
+        }
+    }
+
+    fn take_kernel_batch(&self) -> Option<(&'static mut [u8], &'static mut [BatchEntry])> {
+        self.kernel_batch_waiting.set(false);
+        let data = self.kernel_batch_data.take();
+        let entries = self.kernel_batch_entries.take();
+        data.zip(entries)
+    }
+
+    pub fn set_batch_client(&self, client: &'a dyn BatchClient) {
+        self.batch_client.set(client);
+    }
+
+    /// Write the `entries` of a batch back to back in the kernel region, as
+    /// a single request, taking their data one after the other from `data`.
+    /// All entries are checked first. The batch completes with
+    /// `BatchClient::batch_done()`, after the last entry or the first one
+    /// that failed. One batch of the kernel client waits or runs at a time.
+    pub fn write_batch(
+        &self,
+        data: &'static mut [u8],
+        entries: &'static mut [BatchEntry],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [BatchEntry])> {
+        if self.kernel_batch_entries.is_some() {
+            return Err((ErrorCode::NOMEM, data, entries));
+        }
+        if let Err(e) = self.check_batch(
+            entries.len(),
+            |index| {
+                entries
+                    .get(index)
+                    .map(|entry| (entry.address, entry.length))
+            },
+            |address, length| self.in_kernel_region(address, length),
+            data.len(),
+        ) {
+            return Err((e, data, entries));
+        }
+
+        let count = entries.len();
+        self.kernel_batch_data.replace(data);
+        self.kernel_batch_entries.replace(entries);
+        if self.current_user.is_some() {
+            self.kernel_batch_waiting.set(true);
+            return Ok(());
+        }
+        match self.start_batch(NonvolatileUser::Kernel, count) {
+            Ok(()) => Ok(()),
+            // The batch was stored just above.
+            Err(e) => self
+                .take_kernel_batch()
+                .map_or(Ok(()), |(data, entries)| Err((e, data, entries))),
         }
     }
 
@@ -213,148 +847,239 @@ impl<'a> NonvolatileStorage<'a> {
         command: NonvolatileCommand,
         offset: usize,
         length: usize,
-        processid: Option<ProcessId>,
+        processid: ProcessId,
     ) -> Result<(), ErrorCode> {
-        // Do bounds check.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
-                    return Err(ErrorCode::INVAL);
+        // Do bounds check. Userspace sees memory that starts at address 0
+        // even if it is offset in the physical memory.
+        if !self.in_userspace_region(offset, length) {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.apps
+            .enter(processid, |app, kernel_data| {
+                // Get the length of the correct allowed buffer. An
+                // erase does not use one.
+                let allow_buf_len = match command {
+                    NonvolatileCommand::UserspaceRead => kernel_data
+                        .get_readwrite_processbuffer(rw_allow::READ)
+                        .map_or(0, |read| read.len()),
+                    NonvolatileCommand::UserspaceWrite => kernel_data
+                        .get_readonly_processbuffer(ro_allow::WRITE)
+                        .map_or(0, |read| read.len()),
+                    _ => length,
+                };
+
+                // Check that it exists.
+                if allow_buf_len == 0 || self.buffer.is_none() {
+                    return Err(ErrorCode::RESERVE);
                 }
-            }
-            NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                // Because the kernel uses the NonvolatileStorage interface,
-                // its calls are absolute addresses.
-                if offset < self.kernel_start_address
-                    || offset >= self.kernel_start_address + self.kernel_length
-                    || length > self.kernel_length
-                    || offset + length > self.kernel_start_address + self.kernel_length
-                {
-                    return Err(ErrorCode::INVAL);
+
+                // Shorten the length if the application gave us nowhere to
+                // put it.
+                let active_len = cmp::min(length, allow_buf_len);
+
+                if app.pending_command {
+                    // No more room in the queue, nowhere to store this
+                    // request.
+                    return Err(ErrorCode::NOMEM);
                 }
+
+                // A write is recorded even if the storage is idle, its data
+                // is copied when it starts, from the deferred call.
+                if self.current_user.is_none() && command != NonvolatileCommand::UserspaceWrite {
+                    // No app is currently using the underlying storage.
+                    // Mark this app as active, and then execute the command.
+                    self.set_current_user(NonvolatileUser::App {
+                        processid: processid,
+                    });
+                    let res =
+                        self.start_userspace_command(kernel_data, command, offset, active_len);
+                    if res.is_err() {
+                        self.current_user.clear();
+                    }
+                    res
+                } else {
+                    // Some app is using the storage, or this is a write, we
+                    // must wait.
+                    app.pending_command = true;
+                    app.command = command;
+                    app.offset = offset;
+                    app.length = active_len;
+                    if self.current_user.is_none() {
+                        self.deferred_call.set();
+                    }
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    // Run the read/write of a kernel client now if the storage is idle,
+    // otherwise keep it in `request` until its turn.
+    fn enqueue_kernel_command(
+        &self,
+        user: NonvolatileUser<'a>,
+        request: &KernelRequest,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        // Because the kernel uses the NonvolatileStorage interface, its calls
+        // are absolute addresses.
+        if !self.in_kernel_region(offset, length) {
+            return Err(ErrorCode::INVAL);
+        }
+        let length = cmp::min(length, buffer.len());
+        request.push(command, buffer, offset, length)?;
+        if self.current_user.is_none() {
+            // Nothing is using this, lets go!
+            self.start_kernel_command(user, request)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Start the read/write waiting in `request`.
+    fn start_kernel_command(
+        &self,
+        user: NonvolatileUser<'a>,
+        request: &KernelRequest,
+    ) -> Result<(), ErrorCode> {
+        let (command, buffer, address, length) = request.pop().ok_or(ErrorCode::NOMEM)?;
+
+        self.set_current_user(user);
+        let res = match command {
+            NonvolatileCommand::KernelRead => self.driver.read(buffer, address, length),
+            NonvolatileCommand::KernelWrite => self.driver.write(buffer, address, length),
+            _ => Err(ErrorCode::FAIL),
+        };
+        let res = self.operation_started(res);
+        if res.is_err() {
+            self.current_user.clear();
+        }
+        res
+    }
+
+    // Mark `user` as using the storage, and count the requests of high
+    // priority users and of the kernel run in a row.
+    fn set_current_user(&self, user: NonvolatileUser<'a>) {
+        match user {
+            NonvolatileUser::KernelUser(kernel_user) if kernel_user.priority == Priority::High => {
+                self.high_priority_streak
+                    .set(self.high_priority_streak.get() + 1);
             }
+            _ => self.high_priority_streak.set(0),
         }
+        match user {
+            NonvolatileUser::App { .. } => self.kernel_streak.set(0),
+            _ => self.kernel_streak.set(self.kernel_streak.get() + 1),
+        }
+        self.current_user.set(user);
+    }
 
-        // Do very different actions if this is a call from userspace
-        // or from the kernel.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                processid.map_or(Err(ErrorCode::FAIL), |processid| {
-                    self.apps
-                        .enter(processid, |app, kernel_data| {
-                            // Get the length of the correct allowed buffer.
-                            let allow_buf_len = match command {
-                                NonvolatileCommand::UserspaceRead => kernel_data
-                                    .get_readwrite_processbuffer(rw_allow::READ)
-                                    .map_or(0, |read| read.len()),
-                                NonvolatileCommand::UserspaceWrite => kernel_data
-                                    .get_readonly_processbuffer(ro_allow::WRITE)
-                                    .map_or(0, |read| read.len()),
-                                _ => 0,
-                            };
+    fn client_of(
+        &self,
+        user: NonvolatileUser<'a>,
+    ) -> Option<&'a dyn hil::nonvolatile_storage::NonvolatileStorageClient> {
+        match user {
+            NonvolatileUser::App { .. } => None,
+            NonvolatileUser::Kernel => self.kernel_client.get(),
+            NonvolatileUser::KernelUser(kernel_user) => kernel_user.client.get(),
+        }
+    }
 
-                            // Check that it exists.
-                            if allow_buf_len == 0 || self.buffer.is_none() {
-                                return Err(ErrorCode::RESERVE);
-                            }
-
-                            // Shorten the length if the application gave us nowhere to
-                            // put it.
-                            let active_len = cmp::min(length, allow_buf_len);
-
-                            // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user.set(NonvolatileUser::App {
-                                    processid: processid,
-                                });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    let _ = kernel_data
-                                        .get_readonly_processbuffer(ro_allow::WRITE)
-                                        .and_then(|write| {
-                                            write.enter(|app_buffer| {
-                                                self.buffer.map(|kernel_buffer| {
-                                                    // Check that the internal buffer and the buffer that was
-                                                    // allowed are long enough.
-                                                    let write_len =
-                                                        cmp::min(active_len, kernel_buffer.len());
-
-                                                    let d = &app_buffer[0..write_len];
-                                                    for (i, c) in kernel_buffer[0..write_len]
-                                                        .iter_mut()
-                                                        .enumerate()
-                                                    {
-                                                        *c = d[i].get();
-                                                    }
-                                                });
-                                            })
-                                        });
-                                }
+    fn request_of(&self, user: NonvolatileUser<'a>) -> Option<&KernelRequest> {
+        match user {
+            NonvolatileUser::App { .. } => None,
+            NonvolatileUser::Kernel => Some(&self.kernel_request),
+            NonvolatileUser::KernelUser(kernel_user) => Some(&kernel_user.request),
+        }
+    }
 
-                                self.userspace_call_driver(command, offset, active_len)
-                            } else {
-                                // Some app is using the storage, we must wait.
-                                if app.pending_command {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    Err(ErrorCode::NOMEM)
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    Ok(())
-                                }
-                            }
-                        })
-                        .unwrap_or_else(|err| Err(err.into()))
-                })
+    // Start the next operation before the kernel client `user` is told its
+    // operation completed, if it already queued another request. Returns
+    // whether the storage was handed on.
+    fn pipeline(&self, user: NonvolatileUser<'a>) -> bool {
+        if self
+            .request_of(user)
+            .map_or(false, |request| request.is_pending())
+        {
+            self.current_user.clear();
+            self.check_queue();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn waiting_user(&self, priority: Priority) -> Option<&'a NonvolatileStorageUser<'a>> {
+        self.users
+            .iter()
+            .find(|user| user.priority == priority && user.request.is_pending())
+    }
+
+    // Whether requests other than those of high priority users wait.
+    fn other_requests_waiting(&self) -> bool {
+        self.kernel_request.is_pending()
+            || self.waiting_user(Priority::Normal).is_some()
+            || self
+                .apps
+                .iter()
+                .any(|cntr| cntr.enter(|app, _| app.pending_command))
+    }
+
+    // The kernel client whose request runs next, if any, and its request.
+    fn next_kernel_request(&self) -> Option<(NonvolatileUser<'a>, &KernelRequest)> {
+        if let Some(user) = self.waiting_user(Priority::High) {
+            if self.high_priority_streak.get() < MAX_HIGH_PRIORITY_STREAK
+                || !self.other_requests_waiting()
+            {
+                return Some((NonvolatileUser::KernelUser(user), &user.request));
             }
-            NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                self.kernel_buffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |kernel_buffer| {
-                        let active_len = cmp::min(length, kernel_buffer.len());
-
-                        // Check if there is something going on.
-                        if self.current_user.is_none() {
-                            // Nothing is using this, lets go!
-                            self.current_user.set(NonvolatileUser::Kernel);
-
-                            match command {
-                                NonvolatileCommand::KernelRead => {
-                                    self.driver.read(kernel_buffer, offset, active_len)
-                                }
-                                NonvolatileCommand::KernelWrite => {
-                                    self.driver.write(kernel_buffer, offset, active_len)
-                                }
-                                _ => Err(ErrorCode::FAIL),
-                            }
-                        } else {
-                            if self.kernel_pending_command.get() {
-                                Err(ErrorCode::NOMEM)
-                            } else {
-                                self.kernel_pending_command.set(true);
-                                self.kernel_command.set(command);
-                                self.kernel_readwrite_length.set(active_len);
-                                self.kernel_readwrite_address.set(offset);
-                                self.kernel_buffer.replace(kernel_buffer);
-                                Ok(())
-                            }
+        }
+        if self.kernel_request.is_pending() {
+            Some((NonvolatileUser::Kernel, &self.kernel_request))
+        } else {
+            self.waiting_user(Priority::Normal)
+                .map(|user| (NonvolatileUser::KernelUser(user), &user.request))
+        }
+    }
+
+    // Start the request of an app, after copying the data of a write from
+    // the buffer the app allowed now. Returns `RESERVE` if it un-allowed it.
+    fn start_userspace_command(
+        &self,
+        kernel_data: &GrantKernelData,
+        command: NonvolatileCommand,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        let length = if command == NonvolatileCommand::UserspaceWrite {
+            kernel_data
+                .get_readonly_processbuffer(ro_allow::WRITE)
+                .and_then(|write| {
+                    write.enter(|app_buffer| {
+                        if app_buffer.len() == 0 {
+                            return Err(ErrorCode::RESERVE);
                         }
+                        self.buffer
+                            .map_or(Err(ErrorCode::RESERVE), |kernel_buffer| {
+                                let write_len = cmp::min(
+                                    cmp::min(length, app_buffer.len()),
+                                    kernel_buffer.len(),
+                                );
+                                app_buffer[..write_len]
+                                    .copy_to_slice(&mut kernel_buffer[..write_len]);
+                                Ok(write_len)
+                            })
                     })
-            }
-        }
+                })
+                .unwrap_or(Err(ErrorCode::RESERVE))?
+        } else {
+            length
+        };
+        self.userspace_call_driver(command, offset, length)
     }
 
     fn userspace_call_driver(
@@ -367,6 +1092,11 @@ impl<'a> NonvolatileStorage<'a> {
         // storage.
         let physical_address = offset + self.userspace_start_address;
 
+        if command == NonvolatileCommand::UserspaceErase {
+            let res = self.driver.erase(physical_address, length);
+            return self.operation_started(res);
+        }
+
         self.buffer
             .take()
             .map_or(Err(ErrorCode::RESERVE), |buffer| {
@@ -375,7 +1105,7 @@ impl<'a> NonvolatileStorage<'a> {
                 let active_len = cmp::min(length, buffer.len());
 
                 // self.current_app.set(Some(processid));
-                match command {
+                let res = match command {
                     NonvolatileCommand::UserspaceRead => {
                         self.driver.read(buffer, physical_address, active_len)
                     }
@@ -383,69 +1113,115 @@ impl<'a> NonvolatileStorage<'a> {
                         self.driver.write(buffer, physical_address, active_len)
                     }
                     _ => Err(ErrorCode::FAIL),
-                }
+                };
+                self.operation_started(res)
             })
     }
 
+    // Start the next kernel request. One that could not be started is
+    // dropped, and the next one is tried rather than leaving the storage
+    // idle. Returns whether one started.
+    fn start_next_kernel_request(&self) -> bool {
+        while let Some((user, request)) = self.next_kernel_request() {
+            if self.start_kernel_command(user, request).is_ok() {
+                return true;
+            }
+        }
+        if self.kernel_batch_waiting.take() {
+            let count = self.kernel_batch_entries.map_or(0, |entries| entries.len());
+            match self.start_batch(NonvolatileUser::Kernel, count) {
+                Ok(()) => return true,
+                Err(e) => self.batch_done(NonvolatileUser::Kernel, 0, Err(e)),
+            }
+        }
+        false
+    }
+
     fn check_queue(&self) {
-        // Check if there are any pending events.
-        if self.kernel_pending_command.get() {
-            self.kernel_buffer.take().map(|kernel_buffer| {
-                self.kernel_pending_command.set(false);
-                self.current_user.set(NonvolatileUser::Kernel);
-
-                match self.kernel_command.get() {
-                    NonvolatileCommand::KernelRead => self.driver.read(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    NonvolatileCommand::KernelWrite => self.driver.write(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    _ => Err(ErrorCode::FAIL),
-                }
-            });
-        } else {
-            // If the kernel is not requesting anything, check all of the apps.
-            for cntr in self.apps.iter() {
-                let processid = cntr.processid();
-                let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App {
-                            processid: processid,
-                        });
-                        if let Ok(()) =
-                            self.userspace_call_driver(app.command, app.offset, app.length)
-                        {
-                            true
-                        } else {
+        // Check if there are any pending events. Waiting apps go first after
+        // a streak of kernel requests.
+        if self.kernel_streak.get() < MAX_KERNEL_STREAK && self.start_next_kernel_request() {
+            return;
+        }
+
+        // If the kernel is not requesting anything, check all of the apps.
+        for cntr in self.apps.iter() {
+            let processid = cntr.processid();
+            // A batch is started once the grant is no longer entered.
+            let batch = Cell::new(None);
+            let started_command = cntr.enter(|app, kernel_data| {
+                if app.pending_command && app.command == NonvolatileCommand::UserspaceBatchWrite {
+                    app.pending_command = false;
+                    batch.set(Some(app.length));
+                    false
+                } else if app.pending_command {
+                    app.pending_command = false;
+                    self.set_current_user(NonvolatileUser::App {
+                        processid: processid,
+                    });
+                    match self.start_userspace_command(
+                        kernel_data,
+                        app.command,
+                        app.offset,
+                        app.length,
+                    ) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            // Tell the app its request failed so it is not
+                            // left waiting, and move on to the next one.
+                            self.current_user.clear();
+                            let upcall_num = match app.command {
+                                NonvolatileCommand::UserspaceRead => upcall::READ_DONE,
+                                NonvolatileCommand::UserspaceErase => upcall::ERASE_DONE,
+                                _ => upcall::WRITE_DONE,
+                            };
+                            kernel_data
+                                .schedule_upcall(upcall_num, (0, into_statuscode(Err(e)), 0))
+                                .ok();
                             false
                         }
-                    } else {
-                        false
                     }
-                });
-                if started_command {
-                    break;
+                } else {
+                    false
+                }
+            });
+            if started_command {
+                return;
+            }
+            if let Some(count) = batch.get() {
+                let user = NonvolatileUser::App { processid };
+                match self.start_batch(user, count) {
+                    Ok(()) => return,
+                    // Tell the app its batch failed, and move on.
+                    Err(e) => self.batch_done(user, 0, Err(e)),
                 }
             }
         }
+
+        // Kernel requests held back for apps, or for apps whose requests
+        // failed, can run now.
+        self.start_next_kernel_request();
     }
 }
 
 /// This is the callback client for the underlying physical storage driver.
 impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'_> {
-    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
-        // Switch on which user of this capsule generated this callback.
-        self.current_user.take().map(|user| {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        // The next queued operation, if any, starts the watchdog wait again.
+        self.work_progress
+            .map(|work_progress| work_progress.finish());
+
+        // Switch on which user of this capsule generated this callback. It
+        // is still the current user, so that a kernel client asking for the
+        // storage again waits for its turn, unless the storage was already
+        // handed on.
+        let mut handed_on = false;
+        self.current_user.map(|user| {
             match user {
-                NonvolatileUser::Kernel => {
-                    self.kernel_client.map(move |client| {
-                        client.read_done(buffer, length);
+                NonvolatileUser::Kernel | NonvolatileUser::KernelUser(_) => {
+                    handed_on = self.pipeline(user);
+                    self.client_of(user).map(move |client| {
+                        client.read_done(buffer, length, result);
                     });
                 }
                 NonvolatileUser::App { processid } => {
@@ -469,23 +1245,43 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
 
                         // And then signal the app.
                         kernel_data
-                            .schedule_upcall(upcall::READ_DONE, (length, 0, 0))
+                            .schedule_upcall(
+                                upcall::READ_DONE,
+                                (length, into_statuscode(result), 0),
+                            )
                             .ok();
                     });
                 }
             }
         });
 
-        self.check_queue();
+        if !handed_on {
+            self.current_user.clear();
+            self.check_queue();
+        }
     }
 
-    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
-        // Switch on which user of this capsule generated this callback.
-        self.current_user.take().map(|user| {
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        // The next queued operation, if any, starts the watchdog wait again.
+        self.work_progress
+            .map(|work_progress| work_progress.finish());
+
+        if self.batch_active.get() {
+            self.batch_write_done(buffer, result);
+            return;
+        }
+
+        // Switch on which user of this capsule generated this callback. It
+        // is still the current user, so that a kernel client asking for the
+        // storage again waits for its turn, unless the storage was already
+        // handed on.
+        let mut handed_on = false;
+        self.current_user.map(|user| {
             match user {
-                NonvolatileUser::Kernel => {
-                    self.kernel_client.map(move |client| {
-                        client.write_done(buffer, length);
+                NonvolatileUser::Kernel | NonvolatileUser::KernelUser(_) => {
+                    handed_on = self.pipeline(user);
+                    self.client_of(user).map(move |client| {
+                        client.write_done(buffer, length, result);
                     });
                 }
                 NonvolatileUser::App { processid } => {
@@ -495,17 +1291,61 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
 
                         // And then signal the app.
                         kernel_data
-                            .schedule_upcall(upcall::WRITE_DONE, (length, 0, 0))
+                            .schedule_upcall(
+                                upcall::WRITE_DONE,
+                                (length, into_statuscode(result), 0),
+                            )
                             .ok();
                     });
                 }
             }
         });
 
+        if !handed_on {
+            self.current_user.clear();
+            self.check_queue();
+        }
+    }
+
+    fn erase_done(&self, length: usize, result: Result<(), ErrorCode>) {
+        // The next queued operation, if any, starts the watchdog wait again.
+        self.work_progress
+            .map(|work_progress| work_progress.finish());
+
+        // Switch on which user of this capsule generated this callback.
+        self.current_user.map(|user| match user {
+            NonvolatileUser::Kernel | NonvolatileUser::KernelUser(_) => {
+                self.client_of(user).map(|client| {
+                    client.erase_done(length, result);
+                });
+            }
+            NonvolatileUser::App { processid } => {
+                let _ = self.apps.enter(processid, |_app, kernel_data| {
+                    kernel_data
+                        .schedule_upcall(upcall::ERASE_DONE, (length, into_statuscode(result), 0))
+                        .ok();
+                });
+            }
+        });
+
+        self.current_user.clear();
         self.check_queue();
     }
 }
 
+impl DeferredCallClient for NonvolatileStorage<'_> {
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    fn handle_deferred_call(&self) {
+        // A request which came in meanwhile may already have started.
+        if self.current_user.is_none() {
+            self.check_queue();
+        }
+    }
+}
+
 /// Provide an interface for the kernel.
 impl<'a> hil::nonvolatile_storage::NonvolatileStorage<'a> for NonvolatileStorage<'a> {
     fn set_client(&self, client: &'a dyn hil::nonvolatile_storage::NonvolatileStorageClient) {
@@ -518,8 +1358,14 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorage<'a> for NonvolatileStorage
         address: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelRead, address, length, None)
+        self.enqueue_kernel_command(
+            NonvolatileUser::Kernel,
+            &self.kernel_request,
+            NonvolatileCommand::KernelRead,
+            buffer,
+            address,
+            length,
+        )
     }
 
     fn write(
@@ -528,8 +1374,450 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorage<'a> for NonvolatileStorage
         address: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelWrite, address, length, None)
+        self.enqueue_kernel_command(
+            NonvolatileUser::Kernel,
+            &self.kernel_request,
+            NonvolatileCommand::KernelWrite,
+            buffer,
+            address,
+            length,
+        )
+    }
+}
+
+/// A kernel client of a `NonvolatileStorage`, with its own waiting request.
+/// Its accesses are limited to the kernel region, at absolute addresses.
+pub struct NonvolatileStorageUser<'a> {
+    storage: &'a NonvolatileStorage<'a>,
+    priority: Priority,
+    request: KernelRequest,
+    client: OptionalCell<&'a dyn hil::nonvolatile_storage::NonvolatileStorageClient>,
+    next: ListLink<'a, NonvolatileStorageUser<'a>>,
+}
+
+impl<'a> ListNode<'a, NonvolatileStorageUser<'a>> for NonvolatileStorageUser<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, NonvolatileStorageUser<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> NonvolatileStorageUser<'a> {
+    pub fn new(storage: &'a NonvolatileStorage<'a>, priority: Priority) -> Self {
+        Self {
+            storage: storage,
+            priority: priority,
+            request: KernelRequest::new(),
+            client: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Adds this user to its `NonvolatileStorage`.
+    pub fn setup(&'a self) {
+        self.storage.users.push_tail(self);
+    }
+
+    fn enqueue(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        // The storage refers to users through its list, which `setup()` adds
+        // this one to.
+        let user = self
+            .storage
+            .users
+            .iter()
+            .find(|user| core::ptr::eq(*user, self))
+            .ok_or(ErrorCode::OFF)?;
+        self.storage.enqueue_kernel_command(
+            NonvolatileUser::KernelUser(user),
+            &user.request,
+            command,
+            buffer,
+            address,
+            length,
+        )
+    }
+}
+
+impl<'a> hil::nonvolatile_storage::NonvolatileStorage<'a> for NonvolatileStorageUser<'a> {
+    fn set_client(&self, client: &'a dyn hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.enqueue(NonvolatileCommand::KernelRead, buffer, address, length)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.enqueue(NonvolatileCommand::KernelWrite, buffer, address, length)
+    }
+}
+
+/// What `NonvolatileLog::append()` does with a record that does not fit in
+/// the rest of its region.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFullPolicy {
+    /// Start over at the beginning of the region, the records before are
+    /// dropped.
+    Wrap,
+    /// Refuse the record with `NOMEM`.
+    Error,
+}
+
+/// Bytes at the start of the region of a `NonvolatileLog`: a magic word and
+/// the write cursor, as `u32` little endian.
+pub const LOG_HEADER_LEN: usize = 8;
+
+/// Bytes before each record of a `NonvolatileLog`: its length, as `u16`
+/// little endian.
+pub const LOG_RECORD_PREFIX_LEN: usize = 2;
+
+const LOG_MAGIC: u32 = 0x474c_564e;
+
+pub trait NonvolatileLogClient {
+    /// `mount()` completed. A region without a log holds an empty one.
+    fn mounted(&self, result: Result<(), ErrorCode>);
+
+    /// `append()` or `clear()` completed. After an append, `offset` is where
+    /// the record starts.
+    fn append_done(&self, offset: usize, result: Result<(), ErrorCode>);
+
+    /// `read_record()` completed with the record of `length` bytes at the
+    /// start of `buffer`. The next record starts at `next`, there is none
+    /// once `next` is `NonvolatileLog::end()`.
+    fn record_read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        next: usize,
+        result: Result<(), ErrorCode>,
+    );
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LogState {
+    Unmounted,
+    Mount,
+    Idle,
+    /// The cursor is set back to the start before a record that wraps
+    /// around is written, so that the records it overwrites are never
+    /// replayed.
+    Wrap,
+    WriteRecord,
+    WriteCursor,
+    ReadRecord,
+}
+
+/// Records of variable length appended one after the other in part of the
+/// kernel region, for kernel capsules.
+///
+/// The region starts with a header holding the write cursor, which
+/// `mount()` reads back after a reset. Each record is written with its
+/// length before it, at the cursor, then the cursor is moved past it. A
+/// record lost to a reset in between is never replayed. Records are replayed
+/// with `read_record()`, from offset 0 to `end()`.
+///
+/// The log uses the storage through the kernel interface, like any other
+/// kernel client:
+///
+/// ```rust
+/// let log_storage = static_init!(
+///     capsules::nonvolatile_storage_driver::NonvolatileStorageUser<'static>,
+///     capsules::nonvolatile_storage_driver::NonvolatileStorageUser::new(
+///         nonvolatile_storage,
+///         capsules::nonvolatile_storage_driver::Priority::Normal));
+/// log_storage.setup();
+/// let log = static_init!(
+///     capsules::nonvolatile_storage_driver::NonvolatileLog<'static>,
+///     capsules::nonvolatile_storage_driver::NonvolatileLog::new(
+///         log_storage,
+///         0x400,                       // Start of the log in the kernel region.
+///         0x400,                       // Length of the log.
+///         capsules::nonvolatile_storage_driver::LogFullPolicy::Wrap,
+///         &mut LOG_BUFFER));
+/// hil::nonvolatile_storage::NonvolatileStorage::set_client(log_storage, log);
+/// log.set_client(recorder);
+/// log.mount();
+/// ```
+pub struct NonvolatileLog<'a> {
+    storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'a>,
+    // Absolute address of the header, the records follow it.
+    start_address: usize,
+    // Bytes of the region after the header.
+    capacity: usize,
+    policy: LogFullPolicy,
+    // Holds the header and the record being appended after it.
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<LogState>,
+    // Offset of the next record.
+    cursor: Cell<usize>,
+    // The record being appended, or read: its offset and its length with the
+    // prefix.
+    offset: Cell<usize>,
+    length: Cell<usize>,
+    client: OptionalCell<&'a dyn NonvolatileLogClient>,
+}
+
+impl<'a> NonvolatileLog<'a> {
+    /// A log in the `length` bytes at `start_address`, which must be in the
+    /// kernel region of `storage`. Records are copied to `buffer` before they
+    /// are written, after a header and their length.
+    pub fn new(
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'a>,
+        start_address: usize,
+        length: usize,
+        policy: LogFullPolicy,
+        buffer: &'static mut [u8],
+    ) -> NonvolatileLog<'a> {
+        NonvolatileLog {
+            storage: storage,
+            start_address: start_address,
+            capacity: length.saturating_sub(LOG_HEADER_LEN),
+            policy: policy,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(LogState::Unmounted),
+            cursor: Cell::new(0),
+            offset: Cell::new(0),
+            length: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn NonvolatileLogClient) {
+        self.client.set(client);
+    }
+
+    /// The offset the next record is appended at, where replaying ends.
+    pub fn end(&self) -> usize {
+        self.cursor.get()
+    }
+
+    /// Read the write cursor from the header. The other operations are
+    /// refused with `OFF` until it completed.
+    pub fn mount(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != LogState::Unmounted {
+            return Err(ErrorCode::ALREADY);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        self.start(LogState::Mount, |storage| {
+            storage.read(buffer, self.start_address, LOG_HEADER_LEN)
+        })
+    }
+
+    /// Append `record` after the last one. Fails with `SIZE` if it does not
+    /// fit in the buffer or in the region.
+    pub fn append(&self, record: &[u8]) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        let length = LOG_RECORD_PREFIX_LEN + record.len();
+        if record.len() > u16::MAX as usize || length > self.capacity {
+            return Err(ErrorCode::SIZE);
+        }
+        let wraps = self.cursor.get() + length > self.capacity;
+        if wraps && self.policy == LogFullPolicy::Error {
+            return Err(ErrorCode::NOMEM);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        if LOG_HEADER_LEN + length > buffer.len() {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::SIZE);
+        }
+        let prefix = LOG_HEADER_LEN + LOG_RECORD_PREFIX_LEN;
+        buffer[LOG_HEADER_LEN..prefix].copy_from_slice(&(record.len() as u16).to_le_bytes());
+        buffer[prefix..LOG_HEADER_LEN + length].copy_from_slice(record);
+        self.length.set(length);
+        if wraps {
+            self.offset.set(0);
+            self.write_cursor(LogState::Wrap, buffer, 0)
+        } else {
+            self.offset.set(self.cursor.get());
+            self.write_record(buffer)
+        }
+    }
+
+    /// Drop every record.
+    pub fn clear(&self) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        self.offset.set(0);
+        self.length.set(0);
+        self.write_cursor(LogState::WriteCursor, buffer, 0)
+    }
+
+    /// Read the record at `offset` into `buffer`, which must also hold its
+    /// length.
+    pub fn read_record(&self, buffer: &'static mut [u8], offset: usize) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        if offset + LOG_RECORD_PREFIX_LEN > self.cursor.get() {
+            return Err(ErrorCode::INVAL);
+        }
+        // The record is at most the rest of the log long, and is read with
+        // its length in one go.
+        let length = cmp::min(buffer.len(), self.cursor.get() - offset);
+        if length < LOG_RECORD_PREFIX_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.offset.set(offset);
+        self.start(LogState::ReadRecord, |storage| {
+            storage.read(buffer, self.start_address + LOG_HEADER_LEN + offset, length)
+        })
+    }
+
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            LogState::Idle => Ok(()),
+            LogState::Unmounted => Err(ErrorCode::OFF),
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    // Start an operation of the storage, handled in `state` once done. The
+    // storage does not give back the buffer of an operation it refuses.
+    fn start<F>(&self, state: LogState, operation: F) -> Result<(), ErrorCode>
+    where
+        F: FnOnce(&dyn hil::nonvolatile_storage::NonvolatileStorage<'a>) -> Result<(), ErrorCode>,
+    {
+        let previous = self.state.replace(state);
+        let res = operation(self.storage);
+        if res.is_err() {
+            self.state.set(if previous == LogState::Unmounted {
+                previous
+            } else {
+                LogState::Idle
+            });
+        }
+        res
+    }
+
+    // Write the record, which follows the header in `buffer`.
+    fn write_record(&self, buffer: &'static mut [u8]) -> Result<(), ErrorCode> {
+        let length = self.length.get();
+        buffer.copy_within(LOG_HEADER_LEN..LOG_HEADER_LEN + length, 0);
+        let address = self.start_address + LOG_HEADER_LEN + self.offset.get();
+        self.start(LogState::WriteRecord, |storage| {
+            storage.write(buffer, address, length)
+        })
+    }
+
+    // Write the header with `cursor`, leaving the record after it in
+    // `buffer`.
+    fn write_cursor(
+        &self,
+        state: LogState,
+        buffer: &'static mut [u8],
+        cursor: usize,
+    ) -> Result<(), ErrorCode> {
+        buffer[0..4].copy_from_slice(&LOG_MAGIC.to_le_bytes());
+        buffer[4..8].copy_from_slice(&(cursor as u32).to_le_bytes());
+        self.start(state, |storage| {
+            storage.write(buffer, self.start_address, LOG_HEADER_LEN)
+        })
+    }
+
+    fn append_done(&self, result: Result<(), ErrorCode>) {
+        self.state.set(LogState::Idle);
+        self.client
+            .map(|client| client.append_done(self.offset.get(), result));
+    }
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileLog<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        match self.state.get() {
+            LogState::Mount => {
+                let result = result.and_then(|()| {
+                    if length < LOG_HEADER_LEN {
+                        return Err(ErrorCode::FAIL);
+                    }
+                    let magic = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                    let cursor =
+                        u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+                    // Erased storage, or anything else than a log, is an
+                    // empty log.
+                    let valid = magic == LOG_MAGIC && cursor <= self.capacity;
+                    self.cursor.set(if valid { cursor } else { 0 });
+                    Ok(())
+                });
+                self.buffer.replace(buffer);
+                self.state.set(if result.is_ok() {
+                    LogState::Idle
+                } else {
+                    LogState::Unmounted
+                });
+                self.client.map(|client| client.mounted(result));
+            }
+            LogState::ReadRecord => {
+                self.state.set(LogState::Idle);
+                let mut record_length = 0;
+                let mut next = self.offset.get();
+                let result = result.and_then(|()| {
+                    if length < LOG_RECORD_PREFIX_LEN {
+                        return Err(ErrorCode::FAIL);
+                    }
+                    record_length = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+                    next += LOG_RECORD_PREFIX_LEN + record_length;
+                    if next > self.cursor.get() {
+                        // Not a record written by the log.
+                        Err(ErrorCode::FAIL)
+                    } else if LOG_RECORD_PREFIX_LEN + record_length > length {
+                        Err(ErrorCode::SIZE)
+                    } else {
+                        buffer.copy_within(
+                            LOG_RECORD_PREFIX_LEN..LOG_RECORD_PREFIX_LEN + record_length,
+                            0,
+                        );
+                        Ok(())
+                    }
+                });
+                let record_length = if result.is_ok() { record_length } else { 0 };
+                self.client
+                    .map(move |client| client.record_read(buffer, record_length, next, result));
+            }
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize, result: Result<(), ErrorCode>) {
+        if let Err(error) = result {
+            self.buffer.replace(buffer);
+            self.append_done(Err(error));
+            return;
+        }
+        match self.state.get() {
+            LogState::Wrap => {
+                self.cursor.set(0);
+                if let Err(error) = self.write_record(buffer) {
+                    self.append_done(Err(error));
+                }
+            }
+            LogState::WriteRecord => {
+                let cursor = self.offset.get() + self.length.get();
+                if let Err(error) = self.write_cursor(LogState::WriteCursor, buffer, cursor) {
+                    self.append_done(Err(error));
+                }
+            }
+            LogState::WriteCursor => {
+                self.buffer.replace(buffer);
+                self.cursor.set(self.offset.get() + self.length.get());
+                self.append_done(Ok(()));
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
     }
 }
 
@@ -545,6 +1833,13 @@ impl SyscallDriver for NonvolatileStorage<'_> {
     /// - `1`: Return the number of bytes available to userspace.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `4`: Start an erase of `length` bytes from `offset`.
+    /// - `5`: Start a batch of writes of the first `offset` entries of the
+    ///   allowed descriptors.
+    /// - `6`: Return the page size of the physical storage.
+    /// - `7`: Return the erase block size of the physical storage.
+    ///
+    /// Sizes that do not fit in 32 bits fail with `SIZE`.
     fn command(
         &self,
         command_num: usize,
@@ -557,8 +1852,7 @@ impl SyscallDriver for NonvolatileStorage<'_> {
 
             1 => {
                 // How many bytes are accessible from userspace
-                // TODO: Would break on 64-bit platforms
-                CommandReturn::success_u32(self.userspace_length as u32)
+                size_u32(self.userspace_length)
             }
 
             2 => {
@@ -567,7 +1861,7 @@ impl SyscallDriver for NonvolatileStorage<'_> {
                     NonvolatileCommand::UserspaceRead,
                     offset,
                     length,
-                    Some(processid),
+                    processid,
                 );
 
                 match res {
@@ -582,7 +1876,7 @@ impl SyscallDriver for NonvolatileStorage<'_> {
                     NonvolatileCommand::UserspaceWrite,
                     offset,
                     length,
-                    Some(processid),
+                    processid,
                 );
 
                 match res {
@@ -591,6 +1885,33 @@ impl SyscallDriver for NonvolatileStorage<'_> {
                 }
             }
 
+            4 => {
+                // Issue an erase command
+                let res = self.enqueue_command(
+                    NonvolatileCommand::UserspaceErase,
+                    offset,
+                    length,
+                    processid,
+                );
+
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            5 => {
+                // Issue a batch of writes
+                match self.enqueue_batch(offset, processid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            6 => size_u32(self.geometry.page_size),
+
+            7 => size_u32(self.geometry.erase_size),
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
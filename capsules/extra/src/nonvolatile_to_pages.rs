@@ -76,6 +76,9 @@ pub struct NonvolatileToPages<'a, F: hil::flash::Flash + 'static> {
     remaining_length: Cell<usize>,
     /// Where we are in the user buffer.
     buffer_index: Cell<usize>,
+    /// How many bytes of the user buffer the outstanding page write covers.
+    /// These are already counted in `buffer_index` but are not written yet.
+    write_in_flight: Cell<usize>,
 }
 
 impl<'a, F: hil::flash::Flash> NonvolatileToPages<'a, F> {
@@ -90,8 +93,29 @@ impl<'a, F: hil::flash::Flash> NonvolatileToPages<'a, F> {
             length: Cell::new(0),
             remaining_length: Cell::new(0),
             buffer_index: Cell::new(0),
+            write_in_flight: Cell::new(0),
         }
     }
+
+    /// Stop the current operation and return the user's buffer along with
+    /// `error` and the number of bytes that were completed.
+    fn abort(&self, pagebuffer: &'static mut F::Page, error: ErrorCode) {
+        self.pagebuffer.replace(pagebuffer);
+        let state = self.state.replace(State::Idle);
+        self.buffer.take().map(move |buffer| match state {
+            State::Read => {
+                let length = self.buffer_index.get();
+                self.client
+                    .map(move |client| client.read_done(buffer, length, Err(error)));
+            }
+            State::Write => {
+                let length = self.buffer_index.get() - self.write_in_flight.get();
+                self.client
+                    .map(move |client| client.write_done(buffer, length, Err(error)));
+            }
+            State::Idle => {}
+        });
+    }
 }
 
 impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'a>
@@ -129,6 +153,7 @@ impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'a>
                     Ok(()) => Ok(()),
                     Err((error_code, pagebuffer)) => {
                         self.pagebuffer.replace(pagebuffer);
+                        self.state.set(State::Idle);
                         Err(error_code)
                     }
                 }
@@ -164,11 +189,13 @@ impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'a>
                     self.address.set(address + page_size);
                     self.remaining_length.set(length - page_size);
                     self.buffer_index.set(page_size);
+                    self.write_in_flight.set(page_size);
 
                     match self.driver.write_page(address / page_size, pagebuffer) {
                         Ok(()) => Ok(()),
                         Err((error_code, pagebuffer)) => {
                             self.pagebuffer.replace(pagebuffer);
+                            self.state.set(State::Idle);
                             Err(error_code)
                         }
                     }
@@ -178,11 +205,13 @@ impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'a>
                     self.address.set(address);
                     self.remaining_length.set(length);
                     self.buffer_index.set(0);
+                    self.write_in_flight.set(0);
 
                     match self.driver.read_page(address / page_size, pagebuffer) {
                         Ok(()) => Ok(()),
                         Err((error_code, pagebuffer)) => {
                             self.pagebuffer.replace(pagebuffer);
+                            self.state.set(State::Idle);
                             Err(error_code)
                         }
                     }
@@ -195,8 +224,13 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
     fn read_complete(
         &self,
         pagebuffer: &'static mut F::Page,
-        _result: Result<(), hil::flash::Error>,
+        result: Result<(), hil::flash::Error>,
     ) {
+        if result.is_err() {
+            self.abort(pagebuffer, ErrorCode::FAIL);
+            return;
+        }
+
         match self.state.get() {
             State::Read => {
                 // OK we got a page from flash. Copy what we actually want from it
@@ -221,7 +255,7 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
                         self.pagebuffer.replace(pagebuffer);
                         self.state.set(State::Idle);
                         self.client
-                            .map(move |client| client.read_done(buffer, self.length.get()));
+                            .map(move |client| client.read_done(buffer, self.length.get(), Ok(())));
                     } else {
                         // More to do!
                         self.buffer.replace(buffer);
@@ -230,11 +264,11 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
                         self.address.add(len);
                         self.buffer_index.set(buffer_index + len);
 
-                        if let Err((_, pagebuffer)) = self
+                        if let Err((error_code, pagebuffer)) = self
                             .driver
                             .read_page(self.address.get() / page_size, pagebuffer)
                         {
-                            self.pagebuffer.replace(pagebuffer);
+                            self.abort(pagebuffer, error_code);
                         }
                     }
                 });
@@ -262,8 +296,11 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
                     self.remaining_length.subtract(len);
                     self.address.add(len);
                     self.buffer_index.set(buffer_index + len);
-                    if let Err((_, pagebuffer)) = self.driver.write_page(page_number, pagebuffer) {
-                        self.pagebuffer.replace(pagebuffer);
+                    self.write_in_flight.set(len);
+                    if let Err((error_code, pagebuffer)) =
+                        self.driver.write_page(page_number, pagebuffer)
+                    {
+                        self.abort(pagebuffer, error_code);
                     }
                 });
             }
@@ -274,8 +311,14 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
     fn write_complete(
         &self,
         pagebuffer: &'static mut F::Page,
-        _result: Result<(), hil::flash::Error>,
+        result: Result<(), hil::flash::Error>,
     ) {
+        if result.is_err() {
+            self.abort(pagebuffer, ErrorCode::FAIL);
+            return;
+        }
+        self.write_in_flight.set(0);
+
         // After a write we could be done, need to do another write, or need to
         // do a read.
         self.buffer.take().map(move |buffer| {
@@ -286,7 +329,7 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
                 self.pagebuffer.replace(pagebuffer);
                 self.state.set(State::Idle);
                 self.client
-                    .map(move |client| client.write_done(buffer, self.length.get()));
+                    .map(move |client| client.write_done(buffer, self.length.get(), Ok(())));
             } else if self.remaining_length.get() >= page_size {
                 // Write an entire page!
                 let buffer_index = self.buffer_index.get();
@@ -300,17 +343,20 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
                 self.remaining_length.subtract(page_size);
                 self.address.add(page_size);
                 self.buffer_index.set(buffer_index + page_size);
-                if let Err((_, pagebuffer)) = self.driver.write_page(page_number, pagebuffer) {
-                    self.pagebuffer.replace(pagebuffer);
+                self.write_in_flight.set(page_size);
+                if let Err((error_code, pagebuffer)) =
+                    self.driver.write_page(page_number, pagebuffer)
+                {
+                    self.abort(pagebuffer, error_code);
                 }
             } else {
                 // Write a partial page!
                 self.buffer.replace(buffer);
-                if let Err((_, pagebuffer)) = self
+                if let Err((error_code, pagebuffer)) = self
                     .driver
                     .read_page(self.address.get() / page_size, pagebuffer)
                 {
-                    self.pagebuffer.replace(pagebuffer);
+                    self.abort(pagebuffer, error_code);
                 }
             }
         });
@@ -0,0 +1,262 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! RAM ring of the most recent motion sensor samples, kept so that the last
+//! moments before a fault can be read after the fact without streaming every
+//! sample to an app.
+//!
+//! A sensor driver with a history attached (see `L3gd20Spi::set_history()`
+//! and `Lsm303dlhcI2C::set_history()`) appends every X, Y and Z reading it
+//! completes while recording is enabled, together with a timestamp in
+//! milliseconds. Appending takes constant time, and once the ring is full
+//! each sample replaces the oldest one. Kernel code reads the samples,
+//! oldest first, with `for_each()`. Apps read them through the drivers,
+//! which copy them with `copy_to_process()` as `HISTORY_RECORD_SIZE` byte
+//! records:
+//!
+//! - bytes 0-3: the timestamp, a little endian `u32`.
+//! - byte 4: the `SensorKind`.
+//! - byte 5: 0.
+//! - bytes 6-11: the raw X, Y and Z values of the sensor, little endian
+//!   `i16`.
+//!
+//! The timestamp is the time of the clock converted to milliseconds, it
+//! wraps around with the ticks of the clock.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let history = static_init!(
+//!     capsules_extra::sample_history::SampleHistory<'static>,
+//!     capsules_extra::sample_history::SampleHistory::new(
+//!         virtual_alarm,
+//!         static_init!([HistorySample; 128], [HistorySample::default(); 128]),
+//!     )
+//! );
+//! l3gd20.set_history(history);
+//! let _ = l3gd20.set_history_enabled(true);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::time::{ConvertTicks, Time};
+use kernel::processbuffer::WriteableProcessSlice;
+use kernel::utilities::cells::TakeCell;
+
+/// Size of a sample copied to an app.
+pub const HISTORY_RECORD_SIZE: usize = 12;
+
+/// Source of the timestamps of the samples.
+pub trait HistoryClock {
+    /// The current time in milliseconds.
+    fn now_ms(&self) -> u32;
+}
+
+impl<T: Time> HistoryClock for T {
+    fn now_ms(&self) -> u32 {
+        self.ticks_to_ms(self.now())
+    }
+}
+
+/// Sensor that produced a sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SensorKind {
+    #[default]
+    Gyroscope = 0,
+    Accelerometer = 1,
+    Magnetometer = 2,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistorySample {
+    pub timestamp_ms: u32,
+    pub sensor: SensorKind,
+    /// Raw X, Y and Z values of the sensor.
+    pub xyz: [i16; 3],
+}
+
+impl HistorySample {
+    /// The sample in the format copied to apps.
+    pub fn to_bytes(&self) -> [u8; HISTORY_RECORD_SIZE] {
+        let mut bytes = [0; HISTORY_RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        bytes[4] = self.sensor as u8;
+        for (axis, value) in self.xyz.iter().enumerate() {
+            bytes[6 + 2 * axis..8 + 2 * axis].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+pub struct SampleHistory<'a> {
+    clock: &'a dyn HistoryClock,
+    ring: TakeCell<'a, [HistorySample]>,
+    capacity: usize,
+    /// Index the next sample is written at.
+    next: Cell<usize>,
+    len: Cell<usize>,
+}
+
+impl<'a> SampleHistory<'a> {
+    pub fn new(clock: &'a dyn HistoryClock, ring: &'a mut [HistorySample]) -> SampleHistory<'a> {
+        SampleHistory {
+            clock,
+            capacity: ring.len(),
+            ring: TakeCell::new(ring),
+            next: Cell::new(0),
+            len: Cell::new(0),
+        }
+    }
+
+    /// The number of samples the ring holds when full.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of samples in the ring.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    pub fn clear(&self) {
+        self.next.set(0);
+        self.len.set(0);
+    }
+
+    /// Append a sample taken now, replacing the oldest one if the ring is
+    /// full.
+    pub fn push(&self, sensor: SensorKind, xyz: [i16; 3]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let sample = HistorySample {
+            timestamp_ms: self.clock.now_ms(),
+            sensor,
+            xyz,
+        };
+        self.ring.map(|ring| {
+            let next = self.next.get();
+            ring[next] = sample;
+            self.next.set(if next + 1 == self.capacity {
+                0
+            } else {
+                next + 1
+            });
+            self.len
+                .set(core::cmp::min(self.len.get() + 1, self.capacity));
+        });
+    }
+
+    /// Call `f` with each sample, oldest first.
+    pub fn for_each<F: FnMut(&HistorySample)>(&self, f: F) {
+        self.for_each_from(0, f);
+    }
+
+    /// Copy the samples to `dest`, oldest first, in the format described in
+    /// the module documentation. If `dest` cannot hold all the samples, the
+    /// oldest ones are left out. Returns the number of samples copied.
+    pub fn copy_to_process(&self, dest: &WriteableProcessSlice) -> usize {
+        let count = core::cmp::min(self.len(), dest.len() / HISTORY_RECORD_SIZE);
+        let mut offset = 0;
+        self.for_each_from(self.len() - count, |sample| {
+            dest[offset..offset + HISTORY_RECORD_SIZE].copy_from_slice(&sample.to_bytes());
+            offset += HISTORY_RECORD_SIZE;
+        });
+        count
+    }
+
+    /// Call `f` with each sample, oldest first, skipping the `skip` oldest.
+    fn for_each_from<F: FnMut(&HistorySample)>(&self, skip: usize, mut f: F) {
+        let len = self.len();
+        // The oldest sample is the one written `len` samples ago.
+        let oldest = (self.next.get() + self.capacity - len) % self.capacity.max(1);
+        self.ring.map(|ring| {
+            for i in skip..len {
+                f(&ring[(oldest + i) % self.capacity]);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HistoryClock, HistorySample, SampleHistory, SensorKind};
+    use core::cell::Cell;
+
+    struct Clock(Cell<u32>);
+
+    impl HistoryClock for Clock {
+        fn now_ms(&self) -> u32 {
+            let now = self.0.get();
+            self.0.set(now + 10);
+            now
+        }
+    }
+
+    /// Timestamps and X values of the samples, and their number.
+    fn contents(history: &SampleHistory) -> ([(u32, i16); 4], usize) {
+        let mut samples = [(0, 0); 4];
+        let mut len = 0;
+        history.for_each(|sample| {
+            samples[len] = (sample.timestamp_ms, sample.xyz[0]);
+            len += 1;
+        });
+        (samples, len)
+    }
+
+    fn assert_contents(history: &SampleHistory, expected: &[(u32, i16)]) {
+        let (samples, len) = contents(history);
+        assert_eq!(&samples[..len], expected);
+    }
+
+    #[test]
+    fn keeps_the_most_recent_samples() {
+        let clock = Clock(Cell::new(0));
+        let mut ring = [HistorySample::default(); 3];
+        let history = SampleHistory::new(&clock, &mut ring);
+        assert!(history.is_empty());
+
+        history.push(SensorKind::Gyroscope, [1, 0, 0]);
+        history.push(SensorKind::Gyroscope, [2, 0, 0]);
+        assert_contents(&history, &[(0, 1), (10, 2)]);
+
+        for x in 3..=7 {
+            history.push(SensorKind::Gyroscope, [x, 0, 0]);
+        }
+        assert_eq!(history.len(), 3);
+        assert_contents(&history, &[(40, 5), (50, 6), (60, 7)]);
+
+        history.clear();
+        assert_contents(&history, &[]);
+        history.push(SensorKind::Gyroscope, [8, 0, 0]);
+        assert_contents(&history, &[(70, 8)]);
+    }
+
+    #[test]
+    fn empty_ring_records_nothing() {
+        let clock = Clock(Cell::new(0));
+        let history = SampleHistory::new(&clock, &mut []);
+        history.push(SensorKind::Gyroscope, [1, 2, 3]);
+        assert!(history.is_empty());
+        assert_contents(&history, &[]);
+    }
+
+    #[test]
+    fn record_format() {
+        let sample = HistorySample {
+            timestamp_ms: 0x0403_0201,
+            sensor: SensorKind::Magnetometer,
+            xyz: [1, -2, 0x0708],
+        };
+        assert_eq!(
+            sample.to_bytes(),
+            [0x01, 0x02, 0x03, 0x04, 2, 0, 0x01, 0x00, 0xFE, 0xFF, 0x08, 0x07]
+        );
+    }
+}
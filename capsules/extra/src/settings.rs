@@ -0,0 +1,527 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Board-tunable settings persisted in nonvolatile storage.
+//!
+//! Capsules with runtime-configurable parameters (sampling periods,
+//! thresholds, ...) each register a [`Setting`]: a key, a default value, the
+//! range of valid values and the [`SettingClient`] applying the value. At
+//! boot, the board loads the persisted values and every setting is applied
+//! in registration order. Changes, from the manager process or from the
+//! process console, are checked against the range of the setting, persisted,
+//! then applied again.
+//!
+//! Values are `i32`s. The settings are stored as a table of records in a
+//! region of nonvolatile storage:
+//!
+//! ```text
+//! +-----+-------+-----+-------+-----
+//! | key | value | key | value | ...
+//! +-----+-------+-----+-------+-----
+//!    4      4      4      4
+//! ```
+//!
+//! Both fields are little endian. A record whose key is `0` or `0xFFFFFFFF`
+//! is free, so these keys cannot be registered. Records of keys that are not
+//! registered are left untouched, and each setting keeps its record once it
+//! was given one.
+//!
+//! Boot
+//! ----
+//!
+//! `load()` reads the table. A setting without a record gets its default
+//! value. A persisted value out of the range of its setting, for instance
+//! because the range changed in a kernel update, is clamped to the range and
+//! the setting is flagged until its value is changed. If the table cannot be
+//! read, all the settings get their default value and cannot be changed
+//! until the next boot. The `LoadClient` is called once all the settings were
+//! applied:
+//!
+//! ```rust,ignore
+//! let settings = static_init!(
+//!     capsules_extra::settings::Settings<'static>,
+//!     capsules_extra::settings::Settings::new(
+//!         settings_storage,           // Storage holding the settings.
+//!         0x0,                        // Start of the settings region.
+//!         0x100,                      // Length of the settings region.
+//!         ShortId::Fixed(manager_id), // ShortId of the manager.
+//!         board_kernel.create_grant(capsules_extra::settings::DRIVER_NUM, &grant_cap),
+//!         static_init!([u8; 0x100], [0; 0x100]),
+//!     )
+//! );
+//! NonvolatileStorage::set_client(settings_storage, settings);
+//!
+//! let logger_period = static_init!(
+//!     capsules_extra::settings::Setting<'static>,
+//!     capsules_extra::settings::Setting::new(LOGGER_PERIOD, 1000, 100, 60_000, adc_logger)
+//! );
+//! settings.register(logger_period).unwrap();
+//!
+//! settings.set_load_client(board_boot);
+//! let _ = settings.load();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Only the process whose `ShortId` is the manager's may use the driver,
+//! every command returns `NOSUPPORT` for the other processes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver existence check.
+//! - `1`: Get a setting.
+//!   - `data1`: key.
+//!   - Return: the value and `1` if it was clamped at boot, `0` otherwise,
+//!     `INVAL` if there is no such setting.
+//! - `2`: Get the range of a setting.
+//!   - `data1`: key.
+//!   - Return: the minimum and the maximum, `INVAL` if there is no such
+//!     setting.
+//! - `3`: Change a setting.
+//!   - `data1`: key.
+//!   - `data2`: value.
+//!   - Return: `Ok(())` if the change started, `BUSY` if another change is in
+//!     progress, `INVAL` if there is no such setting or the value is out of
+//!     its range, `NOMEM` if there is no free record for it, `OFF` if the
+//!     settings are not loaded.
+//! - `4`: Key of the setting registered in position `data1`.
+//!   - Return: the key, `INVAL` past the last setting.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Change done, once the value was persisted and applied.
+//!   - `data1`: statuscode.
+//!   - `data2`: key.
+//!   - `data3`: value.
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::process::ShortId;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Settings as usize;
+
+/// IDs for subscribed upcalls.
+mod upcall {
+    /// Change done callback.
+    pub const SET_DONE: usize = 0;
+    /// Number of upcalls.
+    pub const COUNT: u8 = 1;
+}
+
+/// Length of a record of the table.
+pub const RECORD_LEN: usize = 8;
+
+/// Whether a record with `key` is free.
+fn is_free(key: u32) -> bool {
+    key == 0 || key == 0xFFFF_FFFF
+}
+
+/// Applies the value of a setting to the capsule owning it.
+pub trait SettingClient {
+    /// Called at boot with the loaded value, and after each change.
+    fn apply_setting(&self, key: u32, value: i32);
+}
+
+/// Client for the loading of the settings at boot.
+pub trait LoadClient {
+    /// Called once every setting was applied. On error, the settings were
+    /// applied with their default value.
+    fn load_done(&self, result: Result<(), ErrorCode>);
+}
+
+/// Client for the changes made by the kernel.
+pub trait SetClient {
+    /// Called once the change of `key` was persisted and applied, or failed.
+    fn set_done(&self, key: u32, result: Result<(), ErrorCode>);
+}
+
+/// A setting registered with [`Settings::register`].
+pub struct Setting<'a> {
+    key: u32,
+    default: i32,
+    min: i32,
+    max: i32,
+    value: Cell<i32>,
+    /// The persisted value was out of range.
+    clamped: Cell<bool>,
+    /// Record of the setting in the table.
+    slot: OptionalCell<usize>,
+    client: &'a dyn SettingClient,
+    next: ListLink<'a, Setting<'a>>,
+}
+
+impl<'a> ListNode<'a, Setting<'a>> for Setting<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, Setting<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> Setting<'a> {
+    /// A setting between `min` and `max` included, `client` applies its
+    /// value.
+    pub fn new(
+        key: u32,
+        default: i32,
+        min: i32,
+        max: i32,
+        client: &'a dyn SettingClient,
+    ) -> Setting<'a> {
+        Setting {
+            key,
+            default,
+            min,
+            max,
+            value: Cell::new(default),
+            clamped: Cell::new(false),
+            slot: OptionalCell::empty(),
+            client,
+            next: ListLink::empty(),
+        }
+    }
+
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    pub fn value(&self) -> i32 {
+        self.value.get()
+    }
+
+    pub fn range(&self) -> (i32, i32) {
+        (self.min, self.max)
+    }
+
+    /// Whether the persisted value was out of range and was clamped at boot.
+    pub fn is_clamped(&self) -> bool {
+        self.clamped.get()
+    }
+
+    fn contains(&self, value: i32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+
+    fn apply(&self) {
+        self.client.apply_setting(self.key, self.value.get());
+    }
+}
+
+/// Who asked for a change.
+#[derive(Clone, Copy)]
+enum Requester {
+    App(ProcessId),
+    Kernel,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// The settings are not loaded yet.
+    Unloaded,
+    /// Reading the table at boot.
+    Load,
+    Idle,
+    /// Writing the record of the setting `key`.
+    Set {
+        key: u32,
+        value: i32,
+    },
+    /// The table could not be read, the settings cannot be changed.
+    Failed,
+}
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct Settings<'a> {
+    /// Storage holding the table.
+    storage: &'a dyn NonvolatileStorage<'a>,
+    /// Address of the table in `storage`.
+    start: usize,
+    /// Number of records of the table.
+    records: usize,
+    /// The only process allowed to use the driver.
+    manager: ShortId,
+    settings: List<'a, Setting<'a>>,
+    apps: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    requester: OptionalCell<Requester>,
+    load_client: OptionalCell<&'a dyn LoadClient>,
+    set_client: OptionalCell<&'a dyn SetClient>,
+}
+
+impl<'a> Settings<'a> {
+    /// The table is in the `length` bytes of `storage` at `start`, and holds
+    /// as many records as fit in `buffer`.
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'a>,
+        start: usize,
+        length: usize,
+        manager: ShortId,
+        grant: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+        buffer: &'static mut [u8],
+    ) -> Settings<'a> {
+        Settings {
+            storage,
+            start,
+            records: core::cmp::min(length, buffer.len()) / RECORD_LEN,
+            manager,
+            settings: List::new(),
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Unloaded),
+            requester: OptionalCell::empty(),
+            load_client: OptionalCell::empty(),
+            set_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_load_client(&self, client: &'a dyn LoadClient) {
+        self.load_client.set(client);
+    }
+
+    pub fn set_client(&self, client: &'a dyn SetClient) {
+        self.set_client.set(client);
+    }
+
+    /// Register `setting`, before the settings are loaded. Returns `INVAL`
+    /// if its key is reserved for free records or its default value is out
+    /// of its range, `ALREADY` if its key is already registered and `BUSY`
+    /// once the settings are loaded.
+    pub fn register(&self, setting: &'a Setting<'a>) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Unloaded {
+            return Err(ErrorCode::BUSY);
+        }
+        if is_free(setting.key) || !setting.contains(setting.default) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.find(setting.key).is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.settings.push_tail(setting);
+        Ok(())
+    }
+
+    /// The registered settings, in registration order.
+    pub fn settings(&self) -> impl Iterator<Item = &'a Setting<'a>> + '_ {
+        self.settings.iter()
+    }
+
+    fn find(&self, key: u32) -> Option<&'a Setting<'a>> {
+        self.settings.iter().find(|setting| setting.key == key)
+    }
+
+    /// Load and apply the settings, see the module documentation. The
+    /// `LoadClient` is only called if this returns `Ok(())`.
+    pub fn load(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Unloaded {
+            return Err(ErrorCode::ALREADY);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        self.state.set(State::Load);
+        self.storage
+            .read(buffer, self.start, self.records * RECORD_LEN)
+            .map_err(|error| {
+                self.state.set(State::Unloaded);
+                error
+            })
+    }
+
+    /// Change the setting `key` to `value` for the kernel. The `SetClient`
+    /// is only called if this returns `Ok(())`.
+    pub fn set(&self, key: u32, value: i32) -> Result<(), ErrorCode> {
+        self.change(key, value, Requester::Kernel)
+    }
+
+    fn change(&self, key: u32, value: i32, requester: Requester) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Idle => {}
+            State::Load | State::Set { .. } => return Err(ErrorCode::BUSY),
+            State::Unloaded | State::Failed => return Err(ErrorCode::OFF),
+        }
+        let setting = self.find(key).ok_or(ErrorCode::INVAL)?;
+        // The range is only checked here, for every requester.
+        if !setting.contains(value) {
+            return Err(ErrorCode::INVAL);
+        }
+        let slot = setting.slot.get().ok_or(ErrorCode::NOMEM)?;
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        buffer[..4].copy_from_slice(&key.to_le_bytes());
+        buffer[4..RECORD_LEN].copy_from_slice(&value.to_le_bytes());
+        self.state.set(State::Set { key, value });
+        self.requester.set(requester);
+        self.storage
+            .write(buffer, self.start + slot * RECORD_LEN, RECORD_LEN)
+            .map_err(|error| {
+                self.state.set(State::Idle);
+                self.requester.clear();
+                error
+            })
+    }
+
+    /// Give every setting its value and record from `table`, `None` if it
+    /// could not be read.
+    fn assign(&self, table: Option<&[u8]>) {
+        let record = |table: &[u8], slot: usize| {
+            let field = |offset: usize| {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&table[slot * RECORD_LEN + offset..][..4]);
+                bytes
+            };
+            (u32::from_le_bytes(field(0)), i32::from_le_bytes(field(4)))
+        };
+
+        // The settings without a record take the free ones in order.
+        let mut free = 0;
+        for setting in self.settings.iter() {
+            setting.clamped.set(false);
+            setting.value.set(setting.default);
+            let Some(table) = table else {
+                continue;
+            };
+            match (0..self.records).find(|slot| record(table, *slot).0 == setting.key) {
+                Some(slot) => {
+                    let value = record(table, slot).1;
+                    setting.slot.set(slot);
+                    setting.value.set(value.clamp(setting.min, setting.max));
+                    setting.clamped.set(!setting.contains(value));
+                }
+                None => {
+                    while free < self.records && !is_free(record(table, free).0) {
+                        free += 1;
+                    }
+                    if free < self.records {
+                        setting.slot.set(free);
+                        free += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_done(&self, key: u32, value: i32, result: Result<(), ErrorCode>) {
+        self.state.set(State::Idle);
+        if result.is_ok() {
+            self.find(key).map(|setting| {
+                setting.value.set(value);
+                setting.clamped.set(false);
+                setting.apply();
+            });
+        }
+        match self.requester.take() {
+            Some(Requester::App(processid)) => {
+                let _ = self.apps.enter(processid, |_app, kernel_data| {
+                    kernel_data
+                        .schedule_upcall(
+                            upcall::SET_DONE,
+                            (into_statuscode(result), key as usize, value as usize),
+                        )
+                        .ok();
+                });
+            }
+            Some(Requester::Kernel) => {
+                self.set_client.map(|client| client.set_done(key, result));
+            }
+            None => {}
+        }
+    }
+}
+
+impl NonvolatileStorageClient for Settings<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        if self.state.get() != State::Load {
+            self.buffer.replace(buffer);
+            return;
+        }
+        let result = result.and(if length == self.records * RECORD_LEN {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        });
+        match result {
+            Ok(()) => {
+                self.assign(Some(&buffer[..length]));
+                self.state.set(State::Idle);
+            }
+            Err(_) => {
+                self.assign(None);
+                self.state.set(State::Failed);
+            }
+        }
+        self.buffer.replace(buffer);
+
+        for setting in self.settings.iter() {
+            setting.apply();
+        }
+        self.load_client.map(|client| client.load_done(result));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        if let State::Set { key, value } = self.state.get() {
+            let result = result.and(if length == RECORD_LEN {
+                Ok(())
+            } else {
+                Err(ErrorCode::FAIL)
+            });
+            self.set_done(key, value, result);
+        }
+    }
+}
+
+impl SyscallDriver for Settings<'_> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        if processid.short_app_id() != self.manager {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        match command_num {
+            0 => CommandReturn::success(),
+            // Get
+            1 => match self.find(data1 as u32) {
+                Some(setting) => CommandReturn::success_u32_u32(
+                    setting.value() as u32,
+                    setting.is_clamped() as u32,
+                ),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // Range
+            2 => match self.find(data1 as u32) {
+                Some(setting) => {
+                    CommandReturn::success_u32_u32(setting.min as u32, setting.max as u32)
+                }
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // Change
+            3 => self
+                .change(data1 as u32, data2 as i32, Requester::App(processid))
+                .into(),
+            // Key
+            4 => match self.settings.iter().nth(data1) {
+                Some(setting) => CommandReturn::success_u32(setting.key),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
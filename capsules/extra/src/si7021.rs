@@ -18,6 +18,40 @@
 //! > accuracy applications, while the Si7006 is targeted toward lower-accuracy
 //! > applications that traditionally have used discrete RH/T sensors.
 //!
+//! `read_id()` reads the 64 bit electronic serial number and the firmware
+//! revision of the chip, and passes them to the `Si7021IdClient`. The part
+//! number byte of the serial number tells the members of the family apart,
+//! e.g. an Si7020 from an Si7021.
+//!
+//! `set_resolution()` selects the resolution of the humidity and temperature
+//! measurements. Lower resolutions convert faster, and the driver waits for
+//! less time before reading their results.
+//!
+//! The on-chip heater can be set with `set_heater()`, or pulsed to burn off
+//! condensation before a humidity measurement with
+//! `read_humidity_after_heating()`.
+//!
+//! `set_resolution()` and `set_heater()` tell the `Si7021ConfigClient` when
+//! they are done. Both return `BUSY` while a measurement or another change
+//! is in progress. `Si7021Driver` gives processes access to them.
+//!
+//! Measurements and the serial number are checked against the CRC the chip
+//! sends along. A corrupted measurement is taken once more, and if that one
+//! is corrupted too the temperature client gets `FAIL` and the humidity
+//! client gets `usize::MAX`.
+//!
+//! A temperature and a humidity measurement requested while the chip is busy
+//! wait on deck and are taken once it is done, the humidity first. A
+//! measurement that cannot be taken is reported the same way as a corrupted
+//! one, and the next one waiting goes ahead.
+//!
+//! Every humidity conversion also measures the temperature. A temperature
+//! requested within `PREVIOUS_RH_MAX_AGE_MS` of an intact humidity
+//! measurement is read from that conversion, without waiting for one of its
+//! own, so polling both costs a single conversion. Older temperatures are
+//! measured again. If reading the previous temperature fails, it is measured
+//! again too.
+//!
 //! Usage
 //! -----
 //!
@@ -40,15 +74,41 @@
 //!         &mut capsules::si7021::BUFFER));
 //! si7021_i2c.set_client(si7021);
 //! si7021_virtual_alarm.set_client(si7021);
+//!
+//! let si7021_driver = static_init!(
+//!     capsules::si7021::Si7021Driver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::si7021::Si7021Driver::new(si7021, board_kernel.create_grant(
+//!         capsules::si7021::DRIVER_NUM, &grant_cap)));
+//! si7021.set_config_client(si7021_driver);
 //! ```
 
 use core::cell::Cell;
+use core::cmp;
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::i2c;
-use kernel::hil::time::{self, ConvertTicks};
+use kernel::hil::time::{self, ConvertTicks, Ticks};
+use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
-use kernel::ErrorCode;
+use kernel::{ErrorCode, ProcessId};
+
+use capsules_core::single_owner::{SingleOwner, SingleOwnerCommands};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Si7021 as usize;
+
+/// Ids for subscribed upcalls.
+mod upcall {
+    /// A change of the resolution or the heater is done. The first argument
+    /// is the status code.
+    pub const CONFIG_DONE: usize = 0;
+    /// Number of upcalls.
+    pub const COUNT: u8 = 1;
+}
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 enum Registers {
     MeasRelativeHumidityHoldMode = 0xe5,
     MeasRelativeHumidityNoHoldMode = 0xf5,
@@ -80,6 +140,14 @@ enum State {
     ReadElectronicId1,
     SelectElectronicId2,
     ReadElectronicId2,
+    ReadFirmwareVersion,
+
+    /// States to change the resolution or the heater
+    WriteHeaterControl,
+    ReadUserRegister1,
+    WriteUserRegister1,
+    /// Waiting for the heater pulse to end
+    Heating,
 
     /// States to take the current measurement
     TakeTempMeasurementInit,
@@ -88,13 +156,117 @@ enum State {
     ReadTempMeasurement,
     GotTempMeasurement,
     GotRhMeasurement,
+    /// Reading the temperature of the last humidity conversion
+    GotPreviousRhTemp,
+}
+
+/// Identity of the sensor, read by `SI7021::read_id()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Si7021Id {
+    /// Electronic serial number, SNA_3 in the most significant byte.
+    pub serial_number: u64,
+    /// Device identification byte of the serial number (SNB_3): 0x0D for an
+    /// Si7013, 0x14 for an Si7020, 0x15 for an Si7021.
+    pub part_number: u8,
+    /// Firmware revision: 0xFF for version 1.0, 0x20 for version 2.0.
+    pub firmware_version: u8,
 }
 
-#[derive(PartialEq, Eq, Copy, Clone)]
-enum OnDeck {
-    Nothing,
-    Temperature,
-    Humidity,
+/// Resolutions of the humidity and temperature measurements, selected by
+/// bits 7 (RES1) and 0 (RES0) of User Register 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Si7021Resolution {
+    /// 12 bit humidity and 14 bit temperature, the power-on default.
+    Rh12Temp14 = 0x00,
+    Rh8Temp12 = 0x01,
+    Rh10Temp13 = 0x80,
+    Rh11Temp11 = 0x81,
+}
+
+impl Si7021Resolution {
+    /// Bits of User Register 1 selecting the resolution.
+    const MASK: u8 = 0x81;
+
+    fn from_register(register: u8) -> Si7021Resolution {
+        match register & Si7021Resolution::MASK {
+            0x01 => Si7021Resolution::Rh8Temp12,
+            0x80 => Si7021Resolution::Rh10Temp13,
+            0x81 => Si7021Resolution::Rh11Temp11,
+            _ => Si7021Resolution::Rh12Temp14,
+        }
+    }
+
+    /// Time to wait for a measurement before reading it. This is the 20 ms
+    /// used at the default resolution, scaled by the longest conversion time
+    /// at this resolution (12 ms for a 12 bit humidity measurement) and
+    /// rounded up.
+    fn measurement_delay_ms(self) -> u32 {
+        match self {
+            // 12 ms for 12 bit humidity
+            Si7021Resolution::Rh12Temp14 => 20,
+            // 3.8 ms for 12 bit temperature
+            Si7021Resolution::Rh8Temp12 => 7,
+            // 6.2 ms for 13 bit temperature
+            Si7021Resolution::Rh10Temp13 => 11,
+            // 7 ms for 11 bit humidity
+            Si7021Resolution::Rh11Temp11 => 12,
+        }
+    }
+}
+
+/// Age of the last humidity measurement up to which the temperature measured
+/// along with it is used.
+pub const PREVIOUS_RH_MAX_AGE_MS: u32 = 1000;
+
+/// HTRE bit of User Register 1, enabling the heater.
+const HEATER_ENABLE: u8 = 0x04;
+/// Highest heater current level.
+pub const HEATER_LEVEL_MAX: u8 = 15;
+
+/// CRC-8 of the checksums sent by the chip, polynomial x^8 + x^5 + x^4 + 1
+/// initialized to 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    let polynomial = 0x31;
+    let mut crc = 0;
+
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            if (crc & 0x80) != 0 {
+                crc = crc << 1 ^ polynomial;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Temperature in hundredths of degrees centigrade of a raw reading.
+fn centi_celsius(msb: u8, lsb: u8) -> i32 {
+    let temp_raw = ((msb as u32) << 8) | (lsb as u32);
+    ((temp_raw * 17572) / 65536) as i32 - 4685
+}
+
+/// Step of a heater pulse started by `read_humidity_after_heating()`.
+#[derive(Clone, Copy, PartialEq)]
+enum HeaterPulse {
+    None,
+    /// Turning the heater on, for this many milliseconds.
+    Starting(u32),
+    /// Turning the heater off, the humidity is measured next.
+    Ending,
+}
+
+pub trait Si7021IdClient {
+    /// Called when the identity read started by `read_id()` is done.
+    fn id_read(&self, id: Result<Si7021Id, ErrorCode>);
+}
+
+pub trait Si7021ConfigClient {
+    /// Called when the change started by `set_resolution()` or
+    /// `set_heater()` is done.
+    fn config_done(&self, result: Result<(), ErrorCode>);
 }
 
 pub struct SI7021<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> {
@@ -102,8 +274,23 @@ pub struct SI7021<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> {
     alarm: &'a A,
     temp_callback: OptionalCell<&'a dyn kernel::hil::sensors::TemperatureClient>,
     humidity_callback: OptionalCell<&'a dyn kernel::hil::sensors::HumidityClient>,
+    id_client: OptionalCell<&'a dyn Si7021IdClient>,
+    config_client: OptionalCell<&'a dyn Si7021ConfigClient>,
+    /// Serial number bytes read so far by `read_id()`.
+    serial_number: Cell<u64>,
+    resolution: Cell<Si7021Resolution>,
+    /// Bits of User Register 1 being changed, and their new value.
+    user_register_change: Cell<(u8, u8)>,
+    heater_pulse: Cell<HeaterPulse>,
     state: Cell<State>,
-    on_deck: Cell<OnDeck>,
+    /// Measurements requested while the chip was busy, serviced temperature
+    /// first once it is done.
+    temp_on_deck: Cell<bool>,
+    humidity_on_deck: Cell<bool>,
+    /// The measurement in progress is taken again after a corrupted read.
+    remeasuring: Cell<bool>,
+    /// When the last intact humidity measurement was read.
+    last_rh: OptionalCell<A::Ticks>,
     buffer: TakeCell<'static, [u8]>,
 }
 
@@ -115,27 +302,295 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> SI7021<'a, A, I> {
             alarm: alarm,
             temp_callback: OptionalCell::empty(),
             humidity_callback: OptionalCell::empty(),
+            id_client: OptionalCell::empty(),
+            config_client: OptionalCell::empty(),
+            serial_number: Cell::new(0),
+            resolution: Cell::new(Si7021Resolution::Rh12Temp14),
+            user_register_change: Cell::new((0, 0)),
+            heater_pulse: Cell::new(HeaterPulse::None),
             state: Cell::new(State::Idle),
-            on_deck: Cell::new(OnDeck::Nothing),
+            temp_on_deck: Cell::new(false),
+            humidity_on_deck: Cell::new(false),
+            remeasuring: Cell::new(false),
+            last_rh: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
         }
     }
 
-    pub fn read_id(&self) {
-        self.buffer.take().map(|buffer| {
+    pub fn set_id_client(&self, client: &'a dyn Si7021IdClient) {
+        self.id_client.set(client);
+    }
+
+    pub fn set_config_client(&self, client: &'a dyn Si7021ConfigClient) {
+        self.config_client.set(client);
+    }
+
+    /// Read the serial number and firmware revision of the chip, which are
+    /// passed to the `Si7021IdClient`.
+    pub fn read_id(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
             // turn on i2c to send commands
             self.i2c.enable();
 
             buffer[0] = Registers::ReadElectronicIdByteOneA as u8;
             buffer[1] = Registers::ReadElectronicIdByteOneB as u8;
-            // TODO verify errors
-            let _ = self.i2c.write(buffer, 2);
-            self.state.set(State::SelectElectronicId1);
+            match self.i2c.write(buffer, 2) {
+                Ok(()) => {
+                    self.state.set(State::SelectElectronicId1);
+                    Ok(())
+                }
+                Err((error, buffer)) => {
+                    self.set_idle(buffer);
+                    Err(error.into())
+                }
+            }
+        })
+    }
+
+    /// The resolution of the measurements.
+    pub fn resolution(&self) -> Si7021Resolution {
+        self.resolution.get()
+    }
+
+    /// Change the resolution of the measurements. This reads User Register 1
+    /// and writes it back with the resolution bits changed. Measurements
+    /// requested in the meantime start once it is done.
+    pub fn set_resolution(&self, resolution: Si7021Resolution) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            // turn on i2c to send commands
+            self.i2c.enable();
+
+            self.change_user_register(buffer, Si7021Resolution::MASK, resolution as u8)
+                .map_err(|(error, buffer)| {
+                    self.set_idle(buffer);
+                    error.into()
+                })
+        })
+    }
+
+    /// Enable or disable the heater, at current `level` from 0 (about 3 mA)
+    /// to `HEATER_LEVEL_MAX` (about 94 mA). Higher levels are clamped. This
+    /// writes the Heater Control Register, then sets the HTRE bit of User
+    /// Register 1.
+    pub fn set_heater(&self, enable: bool, level: u8) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            // turn on i2c to send commands
+            self.i2c.enable();
+
+            buffer[0] = Registers::WriteHeaterControlRegister as u8;
+            buffer[1] = cmp::min(level, HEATER_LEVEL_MAX);
+            match self.i2c.write(buffer, 2) {
+                Ok(()) => {
+                    let htre = if enable { HEATER_ENABLE } else { 0 };
+                    self.user_register_change.set((HEATER_ENABLE, htre));
+                    self.state.set(State::WriteHeaterControl);
+                    Ok(())
+                }
+                Err((error, buffer)) => {
+                    self.set_idle(buffer);
+                    Err(error.into())
+                }
+            }
+        })
+    }
+
+    /// Turn the heater on at `level` for `duration_ms`, then off, and
+    /// measure the humidity, which is passed to the `HumidityClient`. If the
+    /// heater cannot be turned on the humidity is measured right away.
+    pub fn read_humidity_after_heating(
+        &self,
+        level: u8,
+        duration_ms: u32,
+    ) -> Result<(), ErrorCode> {
+        self.set_heater(true, level)?;
+        self.heater_pulse.set(HeaterPulse::Starting(duration_ms));
+        Ok(())
+    }
+
+    /// Read User Register 1 to change `mask` bits to `bits`.
+    fn change_user_register(
+        &self,
+        buffer: &'static mut [u8],
+        mask: u8,
+        bits: u8,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        buffer[0] = Registers::ReadRHTUserRegister1 as u8;
+        self.i2c.write_read(buffer, 1, 1)?;
+        self.user_register_change.set((mask, bits));
+        self.state.set(State::ReadUserRegister1);
+        Ok(())
+    }
+
+    /// Go on once User Register 1 is written, or failed to be.
+    fn user_register_written(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
+        match self.heater_pulse.replace(HeaterPulse::None) {
+            HeaterPulse::Starting(duration_ms) if status.is_ok() => {
+                self.heater_pulse.set(HeaterPulse::Ending);
+                self.buffer.replace(buffer);
+                self.i2c.disable();
+                self.state.set(State::Heating);
+                self.alarm
+                    .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(duration_ms));
+            }
+            HeaterPulse::Starting(_) | HeaterPulse::Ending => {
+                self.set_idle(buffer);
+                // The humidity measurement takes the place of a queued one.
+                self.humidity_on_deck.set(false);
+                let _ = kernel::hil::sensors::HumidityDriver::read_humidity(self);
+            }
+            HeaterPulse::None => {
+                self.measure_on_deck(buffer);
+                self.config_client
+                    .map(|client| client.config_done(status.map_err(|error| error.into())));
+            }
+        }
+    }
+
+    /// Start the measurement waiting on deck, the humidity before the
+    /// temperature so that the temperature can be read from its conversion,
+    /// or go back to idle if there is none.
+    fn measure_on_deck(&self, buffer: &'static mut [u8]) {
+        let (register, state) = if self.humidity_on_deck.take() {
+            (
+                Registers::MeasRelativeHumidityNoHoldMode,
+                State::TakeRhMeasurementInit,
+            )
+        } else if self.temp_on_deck.take() {
+            self.temperature_measurement()
+        } else {
+            self.set_idle(buffer);
+            return;
+        };
+        self.start_measurement(buffer, register, state);
+    }
+
+    /// How to get the temperature: from the last humidity conversion if it
+    /// is recent enough, or by measuring it.
+    fn temperature_measurement(&self) -> (Registers, State) {
+        let max_age = self.alarm.ticks_from_ms(PREVIOUS_RH_MAX_AGE_MS);
+        let fresh = self.last_rh.map_or(false, |last_rh| {
+            self.alarm.now().wrapping_sub(last_rh) <= max_age
         });
+        if fresh {
+            (
+                Registers::ReadTemperaturePreviousRHMeasurement,
+                State::GotPreviousRhTemp,
+            )
+        } else {
+            (
+                Registers::MeasTemperatureNoHoldMode,
+                State::TakeTempMeasurementInit,
+            )
+        }
+    }
+
+    /// Send the command of `register`. The temperature of the last humidity
+    /// conversion is read right away, with no checksum.
+    fn measurement_transfer(
+        &self,
+        buffer: &'static mut [u8],
+        register: Registers,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        buffer[0] = register as u8;
+        match register {
+            Registers::ReadTemperaturePreviousRHMeasurement => self.i2c.write_read(buffer, 1, 2),
+            _ => self.i2c.write(buffer, 1),
+        }
+    }
+
+    fn start_measurement(&self, buffer: &'static mut [u8], register: Registers, state: State) {
+        match self.measurement_transfer(buffer, register) {
+            Ok(()) => self.state.set(state),
+            Err((error, buffer)) => {
+                self.remeasuring.set(false);
+                self.measurement_failed(register, error.into());
+                self.measure_on_deck(buffer);
+            }
+        }
+    }
+
+    /// Tell the client of the measurement of `register` that it failed.
+    fn measurement_failed(&self, register: Registers, error: ErrorCode) {
+        match register {
+            Registers::MeasTemperatureNoHoldMode
+            | Registers::ReadTemperaturePreviousRHMeasurement => {
+                self.temp_callback.map(|cb| cb.callback(Err(error)));
+            }
+            Registers::MeasRelativeHumidityNoHoldMode => {
+                self.humidity_callback.map(|cb| cb.callback(usize::MAX));
+            }
+            _ => {}
+        }
+    }
+
+    /// Read the result of the measurement, in `next` once read.
+    fn read_measurement(&self, buffer: &'static mut [u8], next: State) {
+        self.state.set(next);
+        if let Err((error, buffer)) = self.i2c.read(buffer, 3) {
+            i2c::I2CClient::command_complete(self, buffer, Err(error));
+        }
+    }
+
+    /// Start a measurement of `register` on the idle chip, in `state`.
+    fn start_idle_measurement(&self, register: Registers, state: State) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+            // turn on i2c to send commands
+            self.i2c.enable();
+
+            if let Err((error, buffer)) = self.measurement_transfer(buffer, register) {
+                self.set_idle(buffer);
+                return Err(error.into());
+            }
+            self.state.set(state);
+            Ok(())
+        })
+    }
+
+    /// Whether the measurement read into `buffer` arrived intact. If not, and
+    /// it was not taken again yet, it is started again with `register`, in
+    /// `state`, and the buffer is gone.
+    fn measurement_intact(
+        &self,
+        buffer: &'static mut [u8],
+        status: Result<(), i2c::Error>,
+        register: Registers,
+        state: State,
+    ) -> Option<(&'static mut [u8], bool)> {
+        let intact = status.is_ok() && crc8(&buffer[0..2]) == buffer[2];
+        if !intact && !self.remeasuring.replace(true) {
+            self.start_measurement(buffer, register, state);
+            return None;
+        }
+        self.remeasuring.set(false);
+        Some((buffer, intact))
+    }
+
+    /// Continue the ID read with `transfer`, in state `next`.
+    fn id_transfer(&self, transfer: Result<(), (i2c::Error, &'static mut [u8])>, next: State) {
+        match transfer {
+            Ok(()) => self.state.set(next),
+            Err((error, buffer)) => self.id_done(buffer, Err(error.into())),
+        }
+    }
+
+    fn id_done(&self, buffer: &'static mut [u8], id: Result<Si7021Id, ErrorCode>) {
+        self.measure_on_deck(buffer);
+        self.id_client.map(|client| client.id_read(id));
     }
 
     fn init_measurement(&self, buffer: &'static mut [u8]) {
-        let delay = self.alarm.ticks_from_ms(20);
+        let delay = self
+            .alarm
+            .ticks_from_ms(self.resolution.get().measurement_delay_ms());
         self.alarm.set_alarm(self.alarm.now(), delay);
 
         // Now wait for timer to expire
@@ -151,35 +606,97 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> SI7021<'a, A, I> {
 }
 
 impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> i2c::I2CClient for SI7021<'a, A, I> {
-    fn command_complete(&self, buffer: &'static mut [u8], _status: Result<(), i2c::Error>) {
+    fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
+        let id_state = matches!(
+            self.state.get(),
+            State::SelectElectronicId1
+                | State::ReadElectronicId1
+                | State::SelectElectronicId2
+                | State::ReadElectronicId2
+                | State::ReadFirmwareVersion
+        );
+        if let (true, Err(error)) = (id_state, status) {
+            self.id_done(buffer, Err(error.into()));
+            return;
+        }
+
         match self.state.get() {
+            State::WriteHeaterControl => {
+                if status.is_err() {
+                    self.user_register_written(buffer, status);
+                    return;
+                }
+                let (mask, bits) = self.user_register_change.get();
+                if let Err((error, buffer)) = self.change_user_register(buffer, mask, bits) {
+                    self.user_register_written(buffer, Err(error));
+                }
+            }
+            State::ReadUserRegister1 => {
+                if status.is_err() {
+                    self.user_register_written(buffer, status);
+                    return;
+                }
+                let (mask, bits) = self.user_register_change.get();
+                let register = buffer[0] & !mask;
+                buffer[0] = Registers::WriteRHTUserRegister1 as u8;
+                buffer[1] = register | bits;
+                match self.i2c.write(buffer, 2) {
+                    Ok(()) => self.state.set(State::WriteUserRegister1),
+                    Err((error, buffer)) => self.user_register_written(buffer, Err(error)),
+                }
+            }
+            State::WriteUserRegister1 => {
+                if status.is_ok() {
+                    self.resolution
+                        .set(Si7021Resolution::from_register(buffer[1]));
+                }
+                self.user_register_written(buffer, status);
+            }
             State::SelectElectronicId1 => {
-                // TODO verify errors
-                let _ = self.i2c.read(buffer, 8);
-                self.state.set(State::ReadElectronicId1);
+                self.id_transfer(self.i2c.read(buffer, 8), State::ReadElectronicId1);
             }
             State::ReadElectronicId1 => {
-                buffer[6] = buffer[0];
-                buffer[7] = buffer[1];
-                buffer[8] = buffer[2];
-                buffer[9] = buffer[3];
-                buffer[10] = buffer[4];
-                buffer[11] = buffer[5];
-                buffer[12] = buffer[6];
-                buffer[13] = buffer[7];
+                // SNA_3, CRC, SNA_2, CRC, SNA_1, CRC, SNA_0, CRC, each CRC
+                // covering the bytes of SNA up to it.
+                let sna = [buffer[0], buffer[2], buffer[4], buffer[6]];
+                if (0..4).any(|i| crc8(&sna[..=i]) != buffer[2 * i + 1]) {
+                    self.id_done(buffer, Err(ErrorCode::FAIL));
+                    return;
+                }
+                self.serial_number
+                    .set((u32::from_be_bytes(sna) as u64) << 32);
                 buffer[0] = Registers::ReadElectronicIdByteTwoA as u8;
                 buffer[1] = Registers::ReadElectronicIdByteTwoB as u8;
-                // TODO verify errors
-                let _ = self.i2c.write(buffer, 2);
-                self.state.set(State::SelectElectronicId2);
+                self.id_transfer(self.i2c.write(buffer, 2), State::SelectElectronicId2);
             }
             State::SelectElectronicId2 => {
-                // TODO verify errors
-                let _ = self.i2c.read(buffer, 6);
-                self.state.set(State::ReadElectronicId2);
+                self.id_transfer(self.i2c.read(buffer, 6), State::ReadElectronicId2);
             }
             State::ReadElectronicId2 => {
-                self.set_idle(buffer);
+                // SNB_3, SNB_2, CRC, SNB_1, SNB_0, CRC, each CRC covering the
+                // bytes of SNB up to it.
+                let snb = [buffer[0], buffer[1], buffer[3], buffer[4]];
+                if crc8(&snb[..2]) != buffer[2] || crc8(&snb) != buffer[5] {
+                    self.id_done(buffer, Err(ErrorCode::FAIL));
+                    return;
+                }
+                self.serial_number
+                    .set(self.serial_number.get() | u32::from_be_bytes(snb) as u64);
+                buffer[0] = Registers::ReadFirmwareVersionA as u8;
+                buffer[1] = Registers::ReadFirmwareVersionB as u8;
+                self.id_transfer(
+                    self.i2c.write_read(buffer, 2, 1),
+                    State::ReadFirmwareVersion,
+                );
+            }
+            State::ReadFirmwareVersion => {
+                let serial_number = self.serial_number.get();
+                let id = Si7021Id {
+                    serial_number: serial_number,
+                    part_number: (serial_number >> 24) as u8,
+                    firmware_version: buffer[0],
+                };
+                self.id_done(buffer, Ok(id));
             }
             State::TakeTempMeasurementInit => {
                 self.init_measurement(buffer);
@@ -190,54 +707,64 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> i2c::I2CClient for SI7021<'a, A,
                 self.state.set(State::WaitRh);
             }
             State::ReadRhMeasurement => {
-                // TODO verify errors
-                let _ = self.i2c.read(buffer, 2);
-                self.state.set(State::GotRhMeasurement);
+                self.read_measurement(buffer, State::GotRhMeasurement);
             }
             State::ReadTempMeasurement => {
-                // TODO verify errors
-                let _ = self.i2c.read(buffer, 2);
-                self.state.set(State::GotTempMeasurement);
+                self.read_measurement(buffer, State::GotTempMeasurement);
             }
             State::GotTempMeasurement => {
-                // Temperature in hundredths of degrees centigrade
-                let temp_raw = ((buffer[0] as u32) << 8) | (buffer[1] as u32);
-                let temp = ((temp_raw * 17572) / 65536) as i32 - 4685;
+                let Some((buffer, intact)) = self.measurement_intact(
+                    buffer,
+                    status,
+                    Registers::MeasTemperatureNoHoldMode,
+                    State::TakeTempMeasurementInit,
+                ) else {
+                    return;
+                };
+                let temp = if intact {
+                    Ok(centi_celsius(buffer[0], buffer[1]))
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
 
-                self.temp_callback.map(|cb| cb.callback(Ok(temp)));
-
-                match self.on_deck.get() {
-                    OnDeck::Humidity => {
-                        self.on_deck.set(OnDeck::Nothing);
-                        buffer[0] = Registers::MeasRelativeHumidityNoHoldMode as u8;
-                        // TODO verify errors
-                        let _ = self.i2c.write(buffer, 1);
-                        self.state.set(State::TakeRhMeasurementInit);
-                    }
-                    _ => {
-                        self.set_idle(buffer);
-                    }
-                }
+                self.temp_callback.map(|cb| cb.callback(temp));
+                self.measure_on_deck(buffer);
             }
             State::GotRhMeasurement => {
-                // Humidity in hundredths of percent
-                let humidity_raw = ((buffer[0] as u32) << 8) | (buffer[1] as u32);
-                let humidity = (((humidity_raw * 125 * 100) / 65536) - 600) as u16;
-
-                self.humidity_callback
-                    .map(|cb| cb.callback(humidity as usize));
-                match self.on_deck.get() {
-                    OnDeck::Temperature => {
-                        self.on_deck.set(OnDeck::Nothing);
-                        buffer[0] = Registers::MeasTemperatureNoHoldMode as u8;
-                        // TODO verify errors
-                        let _ = self.i2c.write(buffer, 1);
-                        self.state.set(State::TakeTempMeasurementInit);
-                    }
-                    _ => {
-                        self.set_idle(buffer);
-                    }
+                let Some((buffer, intact)) = self.measurement_intact(
+                    buffer,
+                    status,
+                    Registers::MeasRelativeHumidityNoHoldMode,
+                    State::TakeRhMeasurementInit,
+                ) else {
+                    return;
+                };
+                let humidity = if intact {
+                    self.last_rh.set(self.alarm.now());
+                    // Humidity in hundredths of percent
+                    let humidity_raw = ((buffer[0] as u32) << 8) | (buffer[1] as u32);
+                    (((humidity_raw * 125 * 100) / 65536) - 600) as u16 as usize
+                } else {
+                    usize::MAX
+                };
+
+                self.humidity_callback.map(|cb| cb.callback(humidity));
+                self.measure_on_deck(buffer);
+            }
+            State::GotPreviousRhTemp => {
+                if status.is_err() {
+                    // Measure it instead.
+                    self.last_rh.clear();
+                    self.start_measurement(
+                        buffer,
+                        Registers::MeasTemperatureNoHoldMode,
+                        State::TakeTempMeasurementInit,
+                    );
+                    return;
                 }
+                let temp = centi_celsius(buffer[0], buffer[1]);
+                self.temp_callback.map(|cb| cb.callback(Ok(temp)));
+                self.measure_on_deck(buffer);
             }
             _ => {}
         }
@@ -251,25 +778,16 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> kernel::hil::sensors::Temperatur
         // This chip handles both humidity and temperature measurements. We can
         // only start a new measurement if the chip is idle. If it isn't then we
         // can put this request "on deck" and it will happen after the
-        // temperature measurement has finished.
+        // current operation has finished.
         if self.state.get() == State::Idle {
-            self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
-                // turn on i2c to send commands
-                self.i2c.enable();
-
-                buffer[0] = Registers::MeasTemperatureNoHoldMode as u8;
-                // TODO verify errors
-                let _ = self.i2c.write(buffer, 1);
-                self.state.set(State::TakeTempMeasurementInit);
-                Ok(())
-            })
+            let (register, state) = self.temperature_measurement();
+            self.start_idle_measurement(register, state)
         } else {
-            // Queue this request if nothing else queued.
-            if self.on_deck.get() == OnDeck::Nothing {
-                self.on_deck.set(OnDeck::Temperature);
-                Ok(())
-            } else {
+            // Queue this request if no temperature is queued yet.
+            if self.temp_on_deck.replace(true) {
                 Err(ErrorCode::BUSY)
+            } else {
+                Ok(())
             }
         }
     }
@@ -286,26 +804,19 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> kernel::hil::sensors::HumidityDr
         // This chip handles both humidity and temperature measurements. We can
         // only start a new measurement if the chip is idle. If it isn't then we
         // can put this request "on deck" and it will happen after the
-        // temperature measurement has finished.
+        // current operation has finished.
         if self.state.get() == State::Idle {
-            self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
-                // turn on i2c to send commands
-                self.i2c.enable();
-
-                buffer[0] = Registers::MeasRelativeHumidityNoHoldMode as u8;
-                // TODO verify errors
-                let _ = self.i2c.write(buffer, 1);
-                self.state.set(State::TakeRhMeasurementInit);
-                Ok(())
-            })
+            self.start_idle_measurement(
+                Registers::MeasRelativeHumidityNoHoldMode,
+                State::TakeRhMeasurementInit,
+            )
         } else {
-            // Not idle, so queue this request if nothing else is queued. If we have already
-            // queued a request return an error.
-            if self.on_deck.get() == OnDeck::Nothing {
-                self.on_deck.set(OnDeck::Humidity);
-                Ok(())
-            } else {
+            // Not idle, so queue this request. If we have already queued a
+            // humidity request return an error.
+            if self.humidity_on_deck.replace(true) {
                 Err(ErrorCode::BUSY)
+            } else {
+                Ok(())
             }
         }
     }
@@ -317,17 +828,120 @@ impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> kernel::hil::sensors::HumidityDr
 
 impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> time::AlarmClient for SI7021<'a, A, I> {
     fn alarm(&self) {
+        if self.state.get() == State::Heating {
+            self.buffer.take().map(|buffer| {
+                // turn on i2c to send commands
+                self.i2c.enable();
+
+                if let Err((error, buffer)) = self.change_user_register(buffer, HEATER_ENABLE, 0) {
+                    self.user_register_written(buffer, Err(error));
+                }
+            });
+            return;
+        }
         self.buffer.take().map(|buffer| {
             // turn on i2c to send commands
             self.i2c.enable();
 
-            // TODO verify errors
-            let _ = self.i2c.read(buffer, 2);
             match self.state.get() {
-                State::WaitRh => self.state.set(State::ReadRhMeasurement),
-                State::WaitTemp => self.state.set(State::ReadTempMeasurement),
-                _ => (),
+                State::WaitRh => self.read_measurement(buffer, State::ReadRhMeasurement),
+                State::WaitTemp => self.read_measurement(buffer, State::ReadTempMeasurement),
+                _ => self.set_idle(buffer),
             }
         });
     }
 }
+
+#[derive(Default)]
+pub struct App {}
+
+/// Gives the process owning it access to the resolution and the heater of
+/// an `SI7021`. Measurements go through the temperature and humidity
+/// drivers.
+pub struct Si7021Driver<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> {
+    si7021: &'a SI7021<'a, A, I>,
+    owner: SingleOwner<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> Si7021Driver<'a, A, I> {
+    pub fn new(
+        si7021: &'a SI7021<'a, A, I>,
+        grant: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> Si7021Driver<'a, A, I> {
+        Si7021Driver {
+            si7021: si7021,
+            owner: SingleOwner::new(grant),
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> Si7021ConfigClient for Si7021Driver<'a, A, I> {
+    fn config_done(&self, result: Result<(), ErrorCode>) {
+        self.owner
+            .schedule_upcall(upcall::CONFIG_DONE, (into_statuscode(result), 0, 0));
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> SingleOwnerCommands for Si7021Driver<'a, A, I> {
+    /// Control the SI7021.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Set the heater. `data1` is 1 to enable it and 0 to disable it,
+    ///   `data2` is the current level, from 0 to 15.
+    /// - `2`: Set the resolution. `data1` is the value of the RES1 and RES0
+    ///   bits: 0 for 12 bit humidity and 14 bit temperature, 1 for 8 and 12
+    ///   bits, 2 for 10 and 13 bits, 3 for 11 and 11 bits.
+    ///
+    /// Both return `BUSY` while a measurement or another change is in
+    /// progress, and upcall `0` once done.
+    fn owner_command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        _process_id: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            // Set the heater
+            1 => match (data1, u8::try_from(data2)) {
+                (0 | 1, Ok(level)) if level <= HEATER_LEVEL_MAX => {
+                    self.si7021.set_heater(data1 == 1, level).into()
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            // Set the resolution
+            2 => {
+                let resolution = match data1 {
+                    0 => Si7021Resolution::Rh12Temp14,
+                    1 => Si7021Resolution::Rh8Temp12,
+                    2 => Si7021Resolution::Rh10Temp13,
+                    3 => Si7021Resolution::Rh11Temp11,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.si7021.set_resolution(resolution).into()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, I: i2c::I2CDevice> SyscallDriver for Si7021Driver<'a, A, I> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn {
+        self.owner
+            .command(self, command_num, data1, data2, process_id)
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.owner.allocate_grant(processid)
+    }
+}
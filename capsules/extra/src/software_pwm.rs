@@ -0,0 +1,285 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Software PWM on GPIO pins, for boards whose timers cannot generate PWM.
+//!
+//! `SoftwarePwm` owns a dedicated alarm and toggles the pins of every
+//! registered `SoftwarePwmPin` from its alarm callback. Each pin implements
+//! `hil::pwm::PwmPin`, so it can be handed to anything expecting a hardware
+//! PWM pin, such as the PWM buzzer or a display backlight.
+//!
+//! Scheduling
+//! ----------
+//!
+//! Every running pin keeps the time of its next edge. When the alarm fires,
+//! the pins whose edge is due are toggled and the alarm is armed for the
+//! nearest edge across all running pins. Edge times are computed from the
+//! previous ideal edge, not from the time the alarm was serviced, so the
+//! frequency does not drift. A duty cycle of 0 or of the maximum keeps the
+//! pin at a constant level and does not use the alarm at all.
+//!
+//! Limits
+//! ------
+//!
+//! Each edge costs an interrupt, so the frequency ceiling is set by the
+//! board with `max_frequency_hz`, depending on the alarm frequency and on how
+//! much CPU time it is willing to spend. `DEFAULT_MAX_FREQUENCY_HZ` suits a
+//! 32 kHz alarm. A period is also never shorter than `MIN_PERIOD_TICKS`
+//! alarm ticks. Within a period, the duty cycle is rounded down to whole
+//! ticks.
+//!
+//! Jitter
+//! ------
+//!
+//! An edge happens late by the interrupt latency of the alarm, plus the time
+//! spent on the pins before it in the list. Edges of different pins that
+//! fall closer together than the alarm can be re-armed are serviced by the
+//! next firing, and are delayed by up to the alarm's minimum dt. If an edge
+//! is late by more than a whole high or low phase, the pin restarts its
+//! phase from the current time instead of catching up.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let pwm_alarm = static_init!(
+//!     capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!     capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! pwm_alarm.setup();
+//! let software_pwm = static_init!(
+//!     capsules_extra::software_pwm::SoftwarePwm<'static, VirtualMuxAlarm<'static, nrf52::rtc::Rtc>>,
+//!     capsules_extra::software_pwm::SoftwarePwm::new(
+//!         pwm_alarm,
+//!         capsules_extra::software_pwm::DEFAULT_MAX_FREQUENCY_HZ,
+//!     )
+//! );
+//! pwm_alarm.set_alarm_client(software_pwm);
+//! let backlight = static_init!(
+//!     capsules_extra::software_pwm::SoftwarePwmPin<'static, VirtualMuxAlarm<'static, nrf52::rtc::Rtc>>,
+//!     capsules_extra::software_pwm::SoftwarePwmPin::new(
+//!         software_pwm,
+//!         &nrf52::gpio::PORT[BACKLIGHT_PIN],
+//!         kernel::hil::gpio::ActivationMode::ActiveHigh,
+//!     )
+//! );
+//! backlight.add_to_mux();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::hil::gpio::{self, ActivationMode, ActivationState};
+use kernel::hil::pwm::PwmPin;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::ErrorCode;
+
+/// Frequency ceiling suitable for a 32 kHz alarm.
+pub const DEFAULT_MAX_FREQUENCY_HZ: usize = 1000;
+
+/// Duty cycle corresponding to a pin that is always active.
+pub const MAX_DUTY_CYCLE: usize = 10_000;
+
+/// Shortest period, in alarm ticks.
+pub const MIN_PERIOD_TICKS: u32 = 2;
+
+pub struct SoftwarePwm<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    max_frequency_hz: usize,
+    pins: List<'a, SoftwarePwmPin<'a, A>>,
+}
+
+impl<'a, A: Alarm<'a>> SoftwarePwm<'a, A> {
+    pub fn new(alarm: &'a A, max_frequency_hz: usize) -> SoftwarePwm<'a, A> {
+        SoftwarePwm {
+            alarm,
+            max_frequency_hz,
+            pins: List::new(),
+        }
+    }
+
+    /// The highest frequency a pin accepts, in Hz.
+    fn maximum_frequency_hz(&self) -> usize {
+        let alarm_hz = <A::Frequency>::frequency() / MIN_PERIOD_TICKS;
+        core::cmp::min(self.max_frequency_hz, alarm_hz as usize)
+    }
+
+    /// Arms the alarm for the nearest edge of all running pins, or disarms
+    /// it if no pin is running.
+    fn reschedule(&self, now: A::Ticks) {
+        let next = self
+            .pins
+            .iter()
+            .filter_map(|pin| pin.ticks_to_edge(now))
+            .min();
+        match next {
+            Some(dt) => self.alarm.set_alarm(now, A::Ticks::from(dt)),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for SoftwarePwm<'a, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        self.pins.iter().for_each(|pin| pin.service(now));
+        self.reschedule(now);
+    }
+}
+
+pub struct SoftwarePwmPin<'a, A: Alarm<'a>> {
+    mux: &'a SoftwarePwm<'a, A>,
+    pin: &'a dyn gpio::Pin,
+    mode: ActivationMode,
+    /// Whether the pin is toggling, as opposed to idle or held at a constant
+    /// level.
+    running: Cell<bool>,
+    /// The level of a running pin, `true` while active.
+    active: Cell<bool>,
+    high_ticks: Cell<u32>,
+    low_ticks: Cell<u32>,
+    next_edge: Cell<A::Ticks>,
+    next: ListLink<'a, SoftwarePwmPin<'a, A>>,
+}
+
+impl<'a, A: Alarm<'a>> ListNode<'a, SoftwarePwmPin<'a, A>> for SoftwarePwmPin<'a, A> {
+    fn next(&self) -> &'a ListLink<SoftwarePwmPin<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: Alarm<'a>> SoftwarePwmPin<'a, A> {
+    pub fn new(
+        mux: &'a SoftwarePwm<'a, A>,
+        pin: &'a dyn gpio::Pin,
+        mode: ActivationMode,
+    ) -> SoftwarePwmPin<'a, A> {
+        SoftwarePwmPin {
+            mux,
+            pin,
+            mode,
+            running: Cell::new(false),
+            active: Cell::new(false),
+            high_ticks: Cell::new(0),
+            low_ticks: Cell::new(0),
+            next_edge: Cell::new(A::Ticks::from(0)),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Registers the pin with the scheduler and drives it to its inactive
+    /// level.
+    pub fn add_to_mux(&'a self) {
+        self.pin.make_output();
+        self.write(false);
+        self.mux.pins.push_head(self);
+    }
+
+    fn write(&self, active: bool) {
+        let state = if active {
+            ActivationState::Active
+        } else {
+            ActivationState::Inactive
+        };
+        self.pin.write_activation(state, self.mode);
+    }
+
+    /// The length of the phase that ends with the next edge.
+    fn current_phase(&self) -> u32 {
+        if self.active.get() {
+            self.high_ticks.get()
+        } else {
+            self.low_ticks.get()
+        }
+    }
+
+    /// Whether the edge at `edge`, ending a phase of `phase` ticks, is due
+    /// at `now`. An edge is never further in the future than its phase, so
+    /// a larger distance means it has passed.
+    fn is_due(edge: A::Ticks, phase: u32, now: A::Ticks) -> bool {
+        let remaining = edge.wrapping_sub(now).into_u32();
+        remaining == 0 || remaining > phase
+    }
+
+    /// The number of ticks from `now` until the next edge, if the pin is
+    /// running.
+    fn ticks_to_edge(&self, now: A::Ticks) -> Option<u32> {
+        if !self.running.get() {
+            return None;
+        }
+        let edge = self.next_edge.get();
+        if Self::is_due(edge, self.current_phase(), now) {
+            Some(0)
+        } else {
+            Some(edge.wrapping_sub(now).into_u32())
+        }
+    }
+
+    /// Toggles the pin if its edge is due.
+    fn service(&self, now: A::Ticks) {
+        if !self.running.get() || !Self::is_due(self.next_edge.get(), self.current_phase(), now) {
+            return;
+        }
+        self.active.set(!self.active.get());
+        self.write(self.active.get());
+        let phase = self.current_phase();
+        let edge = self.next_edge.get().wrapping_add(A::Ticks::from(phase));
+        if Self::is_due(edge, phase, now) {
+            // Too late to keep the phase: restart it from now.
+            self.next_edge.set(now.wrapping_add(A::Ticks::from(phase)));
+        } else {
+            self.next_edge.set(edge);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> PwmPin for SoftwarePwmPin<'a, A> {
+    fn start(&self, frequency_hz: usize, duty_cycle: usize) -> Result<(), ErrorCode> {
+        if frequency_hz == 0
+            || frequency_hz > self.mux.maximum_frequency_hz()
+            || duty_cycle > MAX_DUTY_CYCLE
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        let period = <A::Frequency>::frequency() / frequency_hz as u32;
+        if period < MIN_PERIOD_TICKS {
+            return Err(ErrorCode::INVAL);
+        }
+        let high = (period as u64 * duty_cycle as u64 / MAX_DUTY_CYCLE as u64) as u32;
+
+        let now = self.mux.alarm.now();
+        if high == 0 || high >= period {
+            self.running.set(false);
+            self.write(high != 0);
+        } else {
+            self.high_ticks.set(high);
+            self.low_ticks.set(period - high);
+            self.active.set(true);
+            self.next_edge.set(now.wrapping_add(A::Ticks::from(high)));
+            self.running.set(true);
+            self.write(true);
+        }
+        self.mux.reschedule(now);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        self.running.set(false);
+        self.write(false);
+        self.mux.reschedule(self.mux.alarm.now());
+        Ok(())
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        self.mux.maximum_frequency_hz()
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        MAX_DUTY_CYCLE
+    }
+}
@@ -0,0 +1,312 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Accounting of the commands processes issue to system call drivers.
+//!
+//! A board wraps the drivers it returns from `with_driver()` in an
+//! [`AccountedDriver`], which tells a [`SyscallAccounting`] about every
+//! command before passing it on to the driver.
+//!
+//! [`SyscallCounters`] is an accounting that keeps, for each process
+//! `ShortId`, how many commands it issued to each driver of a set the board
+//! chooses. It is also the system call driver a designated monitoring
+//! process reads the counts with.
+//!
+//! The table has `SLOTS` slots, each taken by the first `ShortId` that is
+//! counted while it is free. Slots are not evicted: once they are all
+//! taken, and for processes without a fixed `ShortId`, commands are counted
+//! in a shared "other" bucket until the monitor clears the table. Counters
+//! are 16 bits and saturate, a saturated counter already tells the monitor
+//! the process hammers the driver.
+//!
+//! Counting a command looks up its driver in the `DRIVERS` driver numbers
+//! and its process in the `SLOTS` slots, boards should keep both small.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let syscall_counters = static_init!(
+//!     capsules_extra::syscall_accounting::SyscallCounters<4, 2>,
+//!     capsules_extra::syscall_accounting::SyscallCounters::new(
+//!         [capsules_core::rng::DRIVER_NUM, capsules_extra::kv_driver::DRIVER_NUM],
+//!         ShortId::Fixed(monitor_id),
+//!     )
+//! );
+//!
+//! impl SyscallDriverLookup for Platform {
+//!     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+//!     where
+//!         F: FnOnce(Option<&dyn kernel::syscall::SyscallDriver>) -> R,
+//!     {
+//!         let driver: Option<&dyn kernel::syscall::SyscallDriver> = match driver_num {
+//!             capsules_core::rng::DRIVER_NUM => Some(self.rng),
+//!             ...
+//!             _ => None,
+//!         };
+//!         match driver {
+//!             Some(driver) => f(Some(&AccountedDriver::new(
+//!                 driver,
+//!                 driver_num,
+//!                 self.syscall_counters,
+//!             ))),
+//!             None => f(None),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Only the monitor process may use the driver, every command returns
+//! `NOSUPPORT` for the other processes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver existence check.
+//! - `1`: Size of the table.
+//!   - Return: the number of slots and the number of drivers counted.
+//! - `2`: Driver number of a column of the table.
+//!   - `data1`: column, below the number of drivers.
+//!   - Return: the driver number, `INVAL` if there is no such column.
+//! - `3`: `ShortId` owning a slot.
+//!   - `data1`: slot.
+//!   - Return: the `ShortId`, `0` if the slot is free, `INVAL` if there is
+//!     no such slot.
+//! - `4`: Commands counted.
+//!   - `data1`: slot, the number of slots for the other bucket.
+//!   - `data2`: column.
+//!   - Return: the count, `INVAL` if there is no such slot or column.
+//! - `5`: Clear the table, freeing all the slots.
+
+use core::cell::Cell;
+use core::num::NonZeroU32;
+
+use kernel::process::ShortId;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SyscallAccounting as usize;
+
+/// Told about the commands processes issue, before they reach the driver.
+pub trait SyscallAccounting {
+    fn record(&self, driver_num: usize, command_num: usize, processid: ProcessId);
+}
+
+/// A system call driver whose commands are recorded by a
+/// [`SyscallAccounting`]. All system calls are passed on to `driver`.
+pub struct AccountedDriver<'a> {
+    driver: &'a dyn SyscallDriver,
+    driver_num: usize,
+    accounting: &'a dyn SyscallAccounting,
+}
+
+impl<'a> AccountedDriver<'a> {
+    pub fn new(
+        driver: &'a dyn SyscallDriver,
+        driver_num: usize,
+        accounting: &'a dyn SyscallAccounting,
+    ) -> AccountedDriver<'a> {
+        AccountedDriver {
+            driver,
+            driver_num,
+            accounting,
+        }
+    }
+}
+
+impl SyscallDriver for AccountedDriver<'_> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        self.accounting
+            .record(self.driver_num, command_num, processid);
+        self.driver.command(command_num, data1, data2, processid)
+    }
+
+    fn allow_userspace_readable(
+        &self,
+        processid: ProcessId,
+        which: usize,
+        slice: kernel::processbuffer::UserspaceReadableProcessBuffer,
+    ) -> Result<
+        kernel::processbuffer::UserspaceReadableProcessBuffer,
+        (
+            kernel::processbuffer::UserspaceReadableProcessBuffer,
+            ErrorCode,
+        ),
+    > {
+        self.driver
+            .allow_userspace_readable(processid, which, slice)
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.driver.allocate_grant(processid)
+    }
+}
+
+/// Per `ShortId` counts of the commands issued to a set of drivers, see the
+/// module documentation.
+pub struct SyscallCounters<const SLOTS: usize, const DRIVERS: usize> {
+    /// Driver numbers counted, one per column.
+    drivers: [usize; DRIVERS],
+    /// The process allowed to read the table.
+    monitor: ShortId,
+    /// `ShortId` owning each slot, `None` if the slot is free.
+    owners: [Cell<Option<NonZeroU32>>; SLOTS],
+    counts: [[Cell<u16>; DRIVERS]; SLOTS],
+    /// Commands of processes without a slot.
+    other: [Cell<u16>; DRIVERS],
+}
+
+impl<const SLOTS: usize, const DRIVERS: usize> SyscallCounters<SLOTS, DRIVERS> {
+    pub fn new(drivers: [usize; DRIVERS], monitor: ShortId) -> SyscallCounters<SLOTS, DRIVERS> {
+        SyscallCounters {
+            drivers,
+            monitor,
+            owners: core::array::from_fn(|_| Cell::new(None)),
+            counts: core::array::from_fn(|_| core::array::from_fn(|_| Cell::new(0))),
+            other: core::array::from_fn(|_| Cell::new(0)),
+        }
+    }
+
+    /// Count a command of the process `id` to `driver_num`.
+    pub fn count(&self, driver_num: usize, id: ShortId) {
+        let column = match self.column(driver_num) {
+            Some(column) => column,
+            None => return,
+        };
+        let counter = match id {
+            ShortId::Fixed(id) => self
+                .slot(id)
+                .map_or(&self.other[column], |slot| &self.counts[slot][column]),
+            ShortId::LocallyUnique => &self.other[column],
+        };
+        counter.set(counter.get().saturating_add(1));
+    }
+
+    /// Commands of the process `id` to `driver_num`, `None` if the driver is
+    /// not counted or the process has no slot.
+    pub fn commands(&self, id: ShortId, driver_num: usize) -> Option<u16> {
+        let column = self.column(driver_num)?;
+        let slot = self
+            .owners
+            .iter()
+            .position(|owner| owner.get().map(ShortId::Fixed) == Some(id))?;
+        Some(self.counts[slot][column].get())
+    }
+
+    /// Commands to `driver_num` counted in the other bucket, `None` if the
+    /// driver is not counted.
+    pub fn other_commands(&self, driver_num: usize) -> Option<u16> {
+        self.column(driver_num)
+            .map(|column| self.other[column].get())
+    }
+
+    /// Reset all the counts and free all the slots.
+    pub fn clear(&self) {
+        self.owners.iter().for_each(|owner| owner.set(None));
+        self.counts
+            .iter()
+            .chain(core::iter::once(&self.other))
+            .flatten()
+            .for_each(|count| count.set(0));
+    }
+
+    fn column(&self, driver_num: usize) -> Option<usize> {
+        self.drivers.iter().position(|num| *num == driver_num)
+    }
+
+    /// The slot of `id`, taking a free one if it has none.
+    fn slot(&self, id: NonZeroU32) -> Option<usize> {
+        let mut free = None;
+        for (slot, owner) in self.owners.iter().enumerate() {
+            match owner.get() {
+                Some(owner) if owner == id => return Some(slot),
+                None if free.is_none() => free = Some(slot),
+                _ => {}
+            }
+        }
+        if let Some(slot) = free {
+            self.owners[slot].set(Some(id));
+        }
+        free
+    }
+
+    /// The counter of `slot` and `column`, `SLOTS` is the other bucket.
+    fn counter(&self, slot: usize, column: usize) -> Option<&Cell<u16>> {
+        let counts = if slot == SLOTS {
+            &self.other
+        } else {
+            self.counts.get(slot)?
+        };
+        counts.get(column)
+    }
+}
+
+impl<const SLOTS: usize, const DRIVERS: usize> SyscallAccounting
+    for SyscallCounters<SLOTS, DRIVERS>
+{
+    fn record(&self, driver_num: usize, _command_num: usize, processid: ProcessId) {
+        // Commands are counted per driver, whatever their number.
+        self.count(driver_num, processid.short_app_id());
+    }
+}
+
+impl<const SLOTS: usize, const DRIVERS: usize> SyscallDriver for SyscallCounters<SLOTS, DRIVERS> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        if processid.short_app_id() != self.monitor {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        match command_num {
+            0 => CommandReturn::success(),
+            // Size
+            1 => CommandReturn::success_u32_u32(SLOTS as u32, DRIVERS as u32),
+            // Driver number
+            2 => self
+                .drivers
+                .get(data1)
+                .map_or(CommandReturn::failure(ErrorCode::INVAL), |num| {
+                    CommandReturn::success_u32(*num as u32)
+                }),
+            // Slot owner
+            3 => self
+                .owners
+                .get(data1)
+                .map_or(CommandReturn::failure(ErrorCode::INVAL), |owner| {
+                    CommandReturn::success_u32(owner.get().map_or(0, NonZeroU32::get))
+                }),
+            // Count
+            4 => self
+                .counter(data1, data2)
+                .map_or(CommandReturn::failure(ErrorCode::INVAL), |count| {
+                    CommandReturn::success_u32(count.get() as u32)
+                }),
+            // Clear
+            5 => {
+                self.clear();
+                CommandReturn::success()
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, _processid: ProcessId) -> Result<(), kernel::process::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Temperature compensation of voltages converted from ADC samples.
+//!
+//! Analog front ends drift with temperature. `TemperatureCompensation` keeps
+//! a recent reading of a temperature sensor, refreshed every `interval_ms`,
+//! and corrects millivolt values with a linear model of the gain error of
+//! each channel:
+//!
+//! ```text
+//! error_ppm = slope * temperature_in_degrees_celsius + intercept
+//! corrected_mv = mv - mv * error_ppm / 1_000_000
+//! ```
+//!
+//! The correction is only applied while the temperature reading is at most
+//! `max_age_ms` old, and only to millivolt values: raw samples are left as
+//! they are. Users of the compensation are told whether it was applied and
+//! how old the temperature reading was, see [`Compensation`].
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let compensation = static_init!(
+//!     capsules_extra::temperature_compensation::TemperatureCompensation<
+//!         'static,
+//!         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     >,
+//!     capsules_extra::temperature_compensation::TemperatureCompensation::new(
+//!         temperature_sensor,
+//!         compensation_alarm,
+//!         &COMPENSATION_COEFFICIENTS,
+//!         1000,
+//!         5000,
+//!     )
+//! );
+//! temperature_sensor.set_client(compensation);
+//! compensation_alarm.set_alarm_client(compensation);
+//! compensation.start();
+//! adc_command.set_compensation(compensation);
+//! ```
+
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+use kernel::hil::time::{self, Alarm, ConvertTicks, Ticks};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+/// Linear model of the gain error of a channel, in parts per million.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Coefficients {
+    /// Gain error change per degree Celsius, in ppm.
+    pub slope: i32,
+    /// Gain error at 0 degrees Celsius, in ppm.
+    pub intercept: i32,
+}
+
+/// A millivolt value after compensation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compensation {
+    /// The corrected value, or the given one if the correction was not
+    /// applied.
+    pub mv: usize,
+    /// Whether the correction was applied.
+    pub applied: bool,
+    /// Age of the temperature reading in milliseconds, `None` if there was
+    /// no reading yet.
+    pub temperature_age_ms: Option<u32>,
+}
+
+/// Corrects millivolt values converted from the samples of an ADC channel.
+pub trait VoltageCompensation {
+    fn compensate(&self, channel: usize, mv: usize) -> Compensation;
+}
+
+pub struct TemperatureCompensation<'a, A: Alarm<'a>> {
+    temperature: &'a dyn TemperatureDriver<'a>,
+    alarm: &'a A,
+    /// Coefficients of each channel, channels without any are not corrected.
+    coefficients: &'a [Coefficients],
+    interval_ms: u32,
+    max_age_ms: u32,
+    /// Last temperature read, in hundredths of a degree, and when.
+    reading: OptionalCell<(i32, A::Ticks)>,
+}
+
+impl<'a, A: Alarm<'a>> TemperatureCompensation<'a, A> {
+    pub fn new(
+        temperature: &'a dyn TemperatureDriver<'a>,
+        alarm: &'a A,
+        coefficients: &'a [Coefficients],
+        interval_ms: u32,
+        max_age_ms: u32,
+    ) -> TemperatureCompensation<'a, A> {
+        TemperatureCompensation {
+            temperature: temperature,
+            alarm: alarm,
+            coefficients: coefficients,
+            interval_ms: interval_ms,
+            max_age_ms: max_age_ms,
+            reading: OptionalCell::empty(),
+        }
+    }
+
+    /// Read the temperature now, then every `interval_ms`.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        self.arm();
+        self.temperature.read_temperature()
+    }
+
+    /// The last temperature read, in hundredths of a degree Celsius, and its
+    /// age in milliseconds.
+    pub fn temperature(&self) -> Option<(i32, u32)> {
+        self.reading.get().map(|(temperature, at)| {
+            let age = self.alarm.now().wrapping_sub(at);
+            (temperature, self.alarm.ticks_to_ms(age))
+        })
+    }
+
+    fn arm(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(self.interval_ms));
+    }
+}
+
+/// Apply the gain error given by `coefficients` at `temperature`, in
+/// hundredths of a degree, to `mv`.
+fn correct(mv: usize, coefficients: Coefficients, temperature: i32) -> usize {
+    let error_ppm =
+        coefficients.slope as i64 * temperature as i64 / 100 + coefficients.intercept as i64;
+    let corrected = mv as i64 - mv as i64 * error_ppm / 1_000_000;
+    corrected.max(0) as usize
+}
+
+impl<'a, A: Alarm<'a>> VoltageCompensation for TemperatureCompensation<'a, A> {
+    fn compensate(&self, channel: usize, mv: usize) -> Compensation {
+        let reading = self.temperature();
+        let coefficients = self.coefficients.get(channel);
+        match (reading, coefficients) {
+            (Some((temperature, age)), Some(coefficients)) if age <= self.max_age_ms => {
+                Compensation {
+                    mv: correct(mv, *coefficients, temperature),
+                    applied: true,
+                    temperature_age_ms: Some(age),
+                }
+            }
+            _ => Compensation {
+                mv: mv,
+                applied: false,
+                temperature_age_ms: reading.map(|(_, age)| age),
+            },
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> TemperatureClient for TemperatureCompensation<'a, A> {
+    fn callback(&self, value: Result<i32, ErrorCode>) {
+        // On errors the previous reading is kept, and ages.
+        if let Ok(temperature) = value {
+            self.reading.set((temperature, self.alarm.now()));
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for TemperatureCompensation<'a, A> {
+    fn alarm(&self) {
+        self.arm();
+        // If the previous read is still running, it refreshes the reading.
+        let _ = self.temperature.read_temperature();
+    }
+}
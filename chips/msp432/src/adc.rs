@@ -759,7 +759,7 @@ impl<'a> dma::DmaClient for Adc<'a> {
             }
 
             self.highspeed_client.map(|client| {
-                client.samples_ready(buf, samples);
+                client.samples_ready(buf, samples, Ok(()));
             });
         }
     }
@@ -503,7 +503,7 @@ impl<'a> Adc<'a> {
                     }
 
                     self.highspeed_client.map(|client| {
-                        client.samples_ready(ret_buf, length);
+                        client.samples_ready(ret_buf, length, Ok(()));
                     });
 
                     // Optionally setup to continue reading. We already
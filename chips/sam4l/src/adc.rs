@@ -10,10 +10,13 @@
 //!
 //! - are 12 bits
 //! - use the ground pad as the negative reference
-//! - use a VCC/2 positive reference
-//! - use a gain of 0.5x
+//! - use a VCC/2 positive reference, unless configured otherwise
+//! - use a gain of 0.5x, unless configured otherwise
 //! - are left justified
 //!
+//! The gain and reference of the next sampling of a channel can be chosen
+//! with `hil::adc::AdcChannelConfig`, with a word made by `channel_config()`.
+//!
 //! Samples can either be collected individually or continuously at a specified
 //! frequency.
 //!
@@ -80,6 +83,38 @@ impl AdcChannel {
     }
 }
 
+/// Gains of `channel_config()`.
+#[derive(Copy, Clone, Debug)]
+pub enum Gain {
+    X1 = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+    X32 = 5,
+    X64 = 6,
+    X0p5 = 7,
+}
+
+/// Positive references of `channel_config()`.
+#[derive(Copy, Clone, Debug)]
+pub enum Reference {
+    Internal1V = 0,
+    VccX0p625 = 1,
+    ExternalRef1 = 2,
+    ExternalRef2 = 3,
+    VccX0p5 = 4,
+}
+
+/// The configuration word of `hil::adc::AdcChannelConfig`: the gain in bits
+/// 0 to 2 and the reference in bits 4 to 6.
+pub const fn channel_config(gain: Gain, reference: Reference) -> u32 {
+    gain as u32 | (reference as u32) << 4
+}
+
+/// The configuration of samplings which were not configured.
+const DEFAULT_CONFIG: u32 = channel_config(Gain::X0p5, Reference::VccX0p5);
+
 /// ADC driver code for the SAM4L.
 pub struct Adc<'a> {
     registers: StaticRef<AdcRegisters>,
@@ -91,6 +126,12 @@ pub struct Adc<'a> {
     continuous: Cell<bool>,
     dma_running: Cell<bool>,
     cpu_clock: Cell<bool>,
+    // reference the ADC is configured with
+    refsel: Cell<u32>,
+    // configuration of the sampling in progress, or of the last one
+    config: Cell<u32>,
+    // configuration of the next sampling, with its channel
+    next_config: Cell<Option<(u32, u32, u32)>>,
 
     // timer fire counting for slow sampling rates
     timer_repeats: Cell<u8>,
@@ -338,6 +379,9 @@ impl<'a> Adc<'a> {
             continuous: Cell::new(false),
             dma_running: Cell::new(false),
             cpu_clock: Cell::new(false),
+            refsel: Cell::new(Reference::VccX0p5 as u32),
+            config: Cell::new(DEFAULT_CONFIG),
+            next_config: Cell::new(None),
 
             // timer repeating state for slow sampling rates
             timer_repeats: Cell::new(0),
@@ -426,15 +470,36 @@ impl<'a> Adc<'a> {
         );
     }
 
+    // Takes the configuration of the next sampling, which is on `channel`,
+    // and returns its gain.
+    fn take_config(&self, channel: &AdcChannel) -> u32 {
+        if self.active.get() {
+            // The sampling is refused, the configuration waits for the next
+            // one.
+            return self.config.get() & 0x7;
+        }
+        let config = match self.next_config.take() {
+            Some((chan_num, internal, config))
+                if chan_num == channel.chan_num && internal == channel.internal =>
+            {
+                config
+            }
+            _ => DEFAULT_CONFIG,
+        };
+        self.config.set(config);
+        config & 0x7
+    }
+
     // Configures the ADC with the slowest clock that can provide continuous sampling at
     // the desired frequency and enables the ADC. Subsequent calls with the same frequency
-    // value have no effect. Using the slowest clock also ensures efficient discrete
-    // sampling.
+    // value and reference of the sampling have no effect. Using the slowest clock also
+    // ensures efficient discrete sampling.
     fn config_and_enable(&self, frequency: u32) -> Result<(), ErrorCode> {
+        let refsel = (self.config.get() >> 4) & 0x7;
         if self.active.get() {
             // disallow reconfiguration during sampling
             Err(ErrorCode::BUSY)
-        } else if frequency == self.adc_clk_freq.get() {
+        } else if frequency == self.adc_clk_freq.get() && refsel == self.refsel.get() {
             // already configured to work on this frequency
             Ok(())
         } else {
@@ -455,7 +520,8 @@ impl<'a> Adc<'a> {
             self.enabled.set(true);
 
             // configure the ADC max speed and reference select
-            let mut cfg_val = Configuration::SPEED::ksps300 + Configuration::REFSEL::VccX0p5;
+            let mut cfg_val = Configuration::SPEED::ksps300 + Configuration::REFSEL.val(refsel);
+            self.refsel.set(refsel);
 
             // First, enable the clocks
             // Both the ADCIFE clock and GCLK10 are needed,
@@ -600,6 +666,7 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
     ///
     /// - `channel`: the ADC channel to sample
     fn sample(&self, channel: &Self::Channel) -> Result<(), ErrorCode> {
+        let gain = self.take_config(channel);
         // always configure to 1KHz to get the slowest clock with single sampling
         let res = self.config_and_enable(1000);
 
@@ -623,7 +690,7 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
                 + SequencerConfig::RES::Bits12
                 + SequencerConfig::TRGSEL::Software
                 + SequencerConfig::GCOMP::Disable
-                + SequencerConfig::GAIN::Gain0p5x
+                + SequencerConfig::GAIN.val(gain)
                 + SequencerConfig::BIPOLAR::Disable
                 + SequencerConfig::HWLA::Enable;
             self.registers.seqcfg.write(cfg);
@@ -650,6 +717,7 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
     /// - `channel`: the ADC channel to sample
     /// - `frequency`: the number of samples per second to collect
     fn sample_continuous(&self, channel: &Self::Channel, frequency: u32) -> Result<(), ErrorCode> {
+        let gain = self.take_config(channel);
         let res = self.config_and_enable(frequency);
 
         if res != Ok(()) {
@@ -673,7 +741,7 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
                 + SequencerConfig::INTERNAL.val(0x2 | channel.internal)
                 + SequencerConfig::RES::Bits12
                 + SequencerConfig::GCOMP::Disable
-                + SequencerConfig::GAIN::Gain0p5x
+                + SequencerConfig::GAIN.val(gain)
                 + SequencerConfig::BIPOLAR::Disable
                 + SequencerConfig::HWLA::Enable;
             // set trigger based on how good our clock is
@@ -797,10 +865,21 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
         12
     }
 
-    /// Voltage reference is VCC/2, we assume VCC is 3.3 V, and we use a gain
-    /// of 0.5.
+    /// Full scale voltage of the last sampling: its reference divided by
+    /// its gain. We assume VCC is 3.3 V, and the external references are
+    /// unknown. By default the reference is VCC/2 and the gain 0.5.
     fn get_voltage_reference_mv(&self) -> Option<usize> {
-        Some(3300)
+        let config = self.config.get();
+        let reference_mv = match (config >> 4) & 0x7 {
+            0 => 1000,
+            1 => 2062,
+            4 => 1650,
+            _ => return None,
+        };
+        Some(match config & 0x7 {
+            7 => reference_mv * 2,
+            gain => reference_mv >> gain,
+        })
     }
 
     /// Sets the client for this driver.
@@ -811,6 +890,25 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
     }
 }
 
+/// Implements the gain and reference configuration of the next sampling.
+impl hil::adc::AdcChannelConfig for Adc<'_> {
+    type Channel = AdcChannel;
+
+    /// Configure the gain and reference of the next sampling of `channel`,
+    /// with a word made by `channel_config()`.
+    fn configure_channel(&self, channel: &AdcChannel, config: u32) -> Result<(), ErrorCode> {
+        if config & !0x77 != 0 || (config >> 4) > Reference::VccX0p5 as u32 {
+            Err(ErrorCode::INVAL)
+        } else if self.active.get() {
+            Err(ErrorCode::BUSY)
+        } else {
+            self.next_config
+                .set(Some((channel.chan_num, channel.internal, config)));
+            Ok(())
+        }
+    }
+}
+
 /// Implements an ADC capable of continuous sampling
 impl<'a> hil::adc::AdcHighSpeed<'a> for Adc<'a> {
     /// Capture buffered samples from the ADC continuously at a given
@@ -835,6 +933,7 @@ impl<'a> hil::adc::AdcHighSpeed<'a> for Adc<'a> {
         buffer2: &'static mut [u16],
         length2: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        let gain = self.take_config(channel);
         let res = self.config_and_enable(frequency);
 
         if res != Ok(()) {
@@ -867,7 +966,7 @@ impl<'a> hil::adc::AdcHighSpeed<'a> for Adc<'a> {
                 + SequencerConfig::INTERNAL.val(0x2 | channel.internal)
                 + SequencerConfig::RES::Bits12
                 + SequencerConfig::GCOMP::Disable
-                + SequencerConfig::GAIN::Gain0p5x
+                + SequencerConfig::GAIN.val(gain)
                 + SequencerConfig::BIPOLAR::Disable
                 + SequencerConfig::HWLA::Enable;
             // set trigger based on how good our clock is
@@ -1043,7 +1142,7 @@ impl<'a> dma::DMAClient for Adc<'a> {
                     // pass the buffer up to the next layer. It will then either
                     // send down another buffer to continue sampling, or stop
                     // sampling
-                    client.samples_ready(buf, length);
+                    client.samples_ready(buf, length, Ok(()));
                 });
             });
         }
@@ -7,8 +7,8 @@
 use cortexm4::{CortexM4, CortexMVariant};
 
 pub use stm32f4xx::{
-    adc, can, chip, clocks, dac, dbg, dma, exti, flash, gpio, nvic, rcc, spi, syscfg, tim2, trng,
-    usart,
+    adc, can, chip, clocks, dac, dbg, dma, exti, flash, gpio, i2c, nvic, rcc, spi, syscfg, tim2,
+    trng, usart,
 };
 
 pub mod can_registers;
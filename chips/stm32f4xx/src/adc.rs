@@ -2,9 +2,25 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright Tock Contributors 2022.
 
+//! Analog to digital converter of the STM32F4.
+//!
+//! Besides the external inputs, ADC1 converts the die temperature sensor on
+//! channel 18 and the internal reference voltage VREFINT on channel 17. Both
+//! are connected when first sampled, or with `enable_temperature()`, and
+//! are sampled for 480 ADC clock cycles to meet their minimum sampling time
+//! of 10 µs.
+//!
+//! Every conversion of VREFINT measures the analog supply VDDA against the
+//! factory calibration of VREFINT, and `get_voltage_reference_mv()` returns
+//! the last VDDA measured, or a nominal 3.3 V until then. `TemperatureSensor`
+//! converts VREFINT then the temperature sensor, and reports the die
+//! temperature computed from the factory calibration.
+
 use crate::rcc;
 use core::cell::Cell;
 use kernel::hil;
+use kernel::hil::adc::AdcChannel;
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
 use kernel::platform::chip::ClockInterface;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable};
@@ -289,6 +305,90 @@ pub enum Channel {
     Channel18 = 0b10010,
 }
 
+impl Channel {
+    /// The internal reference voltage.
+    pub const VREFINT: Channel = Channel::Channel17;
+    /// The die temperature sensor, shared with VBAT.
+    pub const TEMPERATURE_SENSOR: Channel = Channel::Channel18;
+
+    fn is_internal(self) -> bool {
+        self == Channel::VREFINT || self == Channel::TEMPERATURE_SENSOR
+    }
+}
+
+/// Sampling time of the internal channels, 480 ADC clock cycles: at least
+/// 13 µs with the highest ADC clock of 36 MHz.
+const INTERNAL_SAMPLING_TIME: u32 = 0b111;
+
+/// The VDDA assumed until VREFINT is converted, and the one the factory
+/// calibration was measured at.
+const NOMINAL_VDDA_MV: usize = 3300;
+
+/// Addresses in system memory of the factory calibration values.
+const VREFINT_CAL: *const u16 = 0x1FFF_7A2A as *const u16;
+const TS_CAL1: *const u16 = 0x1FFF_7A2C as *const u16;
+const TS_CAL2: *const u16 = 0x1FFF_7A2E as *const u16;
+
+/// Temperatures of the factory calibration of the temperature sensor, in
+/// hundredths of a degree.
+const TS_CAL1_CENTI_CELSIUS: i64 = 3000;
+const TS_CAL2_CENTI_CELSIUS: i64 = 11000;
+
+/// Factory calibration of the internal channels: 12 bit conversions made
+/// with VDDA at 3.3 V.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration {
+    /// VREFINT, at 30 °C.
+    pub vrefint: u16,
+    /// The temperature sensor at 30 °C.
+    pub ts_cal1: u16,
+    /// The temperature sensor at 110 °C.
+    pub ts_cal2: u16,
+}
+
+impl Calibration {
+    /// Read the calibration stored in system memory.
+    pub fn read() -> Calibration {
+        // Safety: the system memory is always mapped, and the calibration
+        // values are aligned half-words.
+        unsafe {
+            Calibration {
+                vrefint: core::ptr::read_volatile(VREFINT_CAL),
+                ts_cal1: core::ptr::read_volatile(TS_CAL1),
+                ts_cal2: core::ptr::read_volatile(TS_CAL2),
+            }
+        }
+    }
+
+    /// VDDA in millivolts, from a 12 bit conversion of VREFINT. `None` for a
+    /// conversion of 0.
+    pub fn vdda_mv(&self, vrefint: u16) -> Option<usize> {
+        if vrefint == 0 {
+            return None;
+        }
+        let vrefint = vrefint as usize;
+        Some((NOMINAL_VDDA_MV * self.vrefint as usize + vrefint / 2) / vrefint)
+    }
+
+    /// Die temperature in hundredths of a degree, from a 12 bit conversion
+    /// of the temperature sensor with VDDA at `vdda_mv`. Fails if the
+    /// calibration values are not ordered, as on a blank system memory.
+    pub fn temperature(&self, ts: u16, vdda_mv: usize) -> Result<i32, ErrorCode> {
+        if self.ts_cal2 <= self.ts_cal1 {
+            return Err(ErrorCode::FAIL);
+        }
+        // The conversion scaled to the 3.3 V of the calibration, interpolated
+        // between the two calibration points. All terms are in 3.3 V
+        // conversions multiplied by VDDA.
+        let nominal = NOMINAL_VDDA_MV as i64;
+        let numerator = (TS_CAL2_CENTI_CELSIUS - TS_CAL1_CENTI_CELSIUS)
+            * (ts as i64 * vdda_mv as i64 - self.ts_cal1 as i64 * nominal);
+        let denominator = (self.ts_cal2 - self.ts_cal1) as i64 * nominal;
+        let delta = (2 * numerator + denominator).div_euclid(2 * denominator);
+        i32::try_from(TS_CAL1_CENTI_CELSIUS + delta).map_err(|_| ErrorCode::FAIL)
+    }
+}
+
 #[allow(dead_code)]
 #[repr(u32)]
 enum DataResolution {
@@ -311,6 +411,10 @@ pub struct Adc<'a> {
     clock: AdcClock<'a>,
     status: Cell<ADCStatus>,
     client: OptionalCell<&'a dyn hil::adc::Client>,
+    /// The channel of the conversion in progress.
+    channel: Cell<Channel>,
+    /// VDDA measured by the last conversion of VREFINT.
+    vdda_mv: Cell<usize>,
 }
 
 impl<'a> Adc<'a> {
@@ -324,6 +428,8 @@ impl<'a> Adc<'a> {
             )),
             status: Cell::new(ADCStatus::Off),
             client: OptionalCell::empty(),
+            channel: Cell::new(Channel::Channel0),
+            vdda_mv: Cell::new(NOMINAL_VDDA_MV),
         }
     }
 
@@ -347,8 +453,13 @@ impl<'a> Adc<'a> {
                 // set state
                 self.status.set(ADCStatus::Idle);
             }
-            self.client
-                .map(|client| client.sample_ready((self.registers.dr.read(DR::DATA) as u16) << 4));
+            let data = self.registers.dr.read(DR::DATA) as u16;
+            if self.channel.get() == Channel::VREFINT {
+                if let Some(vdda_mv) = Calibration::read().vdda_mv(data) {
+                    self.vdda_mv.set(vdda_mv);
+                }
+            }
+            self.client.map(|client| client.sample_ready(data << 4));
         }
     }
 
@@ -364,8 +475,15 @@ impl<'a> Adc<'a> {
         self.clock.disable();
     }
 
+    /// Connect the temperature sensor and VREFINT. VBAT is disconnected, as
+    /// it takes channel 18 over from the temperature sensor.
     pub fn enable_temperature(&self) {
-        self.common_registers.ccr.modify(CCR::TSVREFE::SET);
+        self.registers.smpr1.modify(
+            SMPR1::SMP17.val(INTERNAL_SAMPLING_TIME) + SMPR1::SMP18.val(INTERNAL_SAMPLING_TIME),
+        );
+        self.common_registers
+            .ccr
+            .modify(CCR::TSVREFE::SET + CCR::VBATE::CLEAR);
     }
 }
 
@@ -392,11 +510,12 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
         if self.status.get() == ADCStatus::Off {
             self.enable();
         }
-        if *channel as u32 == 18 {
+        if channel.is_internal() && !self.common_registers.ccr.is_set(CCR::TSVREFE) {
             self.enable_temperature();
         }
         if self.status.get() == ADCStatus::Idle {
             self.status.set(ADCStatus::OneSample);
+            self.channel.set(*channel);
             self.registers.sqr1.modify(SQR1::L.val(0b0000));
             self.registers.sqr3.modify(SQR3::SQ1.val(*channel as u32));
             self.registers.cr1.modify(CR1::EOCIE::SET);
@@ -424,7 +543,7 @@ impl<'a> hil::adc::Adc<'a> for Adc<'a> {
     }
 
     fn get_voltage_reference_mv(&self) -> Option<usize> {
-        Some(3300)
+        Some(self.vdda_mv.get())
     }
 
     fn set_client(&self, client: &'a dyn hil::adc::Client) {
@@ -482,3 +601,133 @@ impl<'a> hil::adc::AdcHighSpeed<'a> for Adc<'a> {
 
     fn set_highspeed_client(&self, _client: &'a dyn hil::adc::HighSpeedClient) {}
 }
+
+#[derive(Copy, Clone, PartialEq)]
+enum TemperatureState {
+    Idle,
+    Vrefint,
+    Temperature,
+}
+
+/// The die temperature, converted with VDDA measured just before.
+///
+/// `vrefint` and `temperature` sample `Channel::VREFINT` and
+/// `Channel::TEMPERATURE_SENSOR`, and both have this sensor as client.
+pub struct TemperatureSensor<'a, A: AdcChannel<'a>> {
+    vrefint: &'a A,
+    temperature: &'a A,
+    calibration: Calibration,
+    state: Cell<TemperatureState>,
+    vdda_mv: Cell<usize>,
+    client: OptionalCell<&'a dyn TemperatureClient>,
+}
+
+impl<'a, A: AdcChannel<'a>> TemperatureSensor<'a, A> {
+    pub fn new(vrefint: &'a A, temperature: &'a A, calibration: Calibration) -> Self {
+        TemperatureSensor {
+            vrefint,
+            temperature,
+            calibration,
+            state: Cell::new(TemperatureState::Idle),
+            vdda_mv: Cell::new(NOMINAL_VDDA_MV),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn done(&self, value: Result<i32, ErrorCode>) {
+        self.state.set(TemperatureState::Idle);
+        self.client.map(|client| client.callback(value));
+    }
+}
+
+impl<'a, A: AdcChannel<'a>> hil::adc::Client for TemperatureSensor<'a, A> {
+    fn sample_ready(&self, sample: u16) {
+        // Samples are left justified, the calibration is in 12 bits.
+        let conversion = sample >> 4;
+        match self.state.get() {
+            TemperatureState::Idle => {}
+            TemperatureState::Vrefint => match self.calibration.vdda_mv(conversion) {
+                Some(vdda_mv) => {
+                    self.vdda_mv.set(vdda_mv);
+                    self.state.set(TemperatureState::Temperature);
+                    if let Err(err) = self.temperature.sample() {
+                        self.done(Err(err));
+                    }
+                }
+                None => self.done(Err(ErrorCode::FAIL)),
+            },
+            TemperatureState::Temperature => {
+                self.done(self.calibration.temperature(conversion, self.vdda_mv.get()))
+            }
+        }
+    }
+}
+
+impl<'a, A: AdcChannel<'a>> TemperatureDriver<'a> for TemperatureSensor<'a, A> {
+    fn set_client(&self, client: &'a dyn TemperatureClient) {
+        self.client.set(client);
+    }
+
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != TemperatureState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.state.set(TemperatureState::Vrefint);
+        let result = self.vrefint.sample();
+        if result.is_err() {
+            self.state.set(TemperatureState::Idle);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Calibration;
+    use kernel::ErrorCode;
+
+    /// Typical values of the datasheet: VREFINT at 1.21 V, and the
+    /// temperature sensor at 0.76 V at 25 °C with a slope of 2.5 mV/°C,
+    /// converted at 3.3 V.
+    const CALIBRATION: Calibration = Calibration {
+        vrefint: 1502,
+        ts_cal1: 959,
+        ts_cal2: 1207,
+    };
+
+    #[test]
+    fn vdda_from_vrefint() {
+        // At the calibration supply, VREFINT converts to its calibration.
+        assert_eq!(CALIBRATION.vdda_mv(1502), Some(3300));
+        // A supply sagging to 3.0 V, and one at 3.6 V.
+        assert_eq!(CALIBRATION.vdda_mv(1652), Some(3000));
+        assert_eq!(CALIBRATION.vdda_mv(1377), Some(3600));
+        assert_eq!(CALIBRATION.vdda_mv(0), None);
+    }
+
+    #[test]
+    fn temperature_at_the_calibration_points() {
+        assert_eq!(CALIBRATION.temperature(959, 3300), Ok(3000));
+        assert_eq!(CALIBRATION.temperature(1207, 3300), Ok(11000));
+        // 0.76 V at 25 °C.
+        assert_eq!(CALIBRATION.temperature(943, 3300), Ok(2484));
+    }
+
+    #[test]
+    fn temperature_is_scaled_to_vdda() {
+        // The sensor at 0.7725 V, 30 °C, converted with VDDA at 3.0 V.
+        assert_eq!(CALIBRATION.temperature(1054, 3000), Ok(2974));
+        // A 3.3 V assumption would read about 31 °C too hot.
+        assert_eq!(CALIBRATION.temperature(1054, 3300), Ok(6065));
+    }
+
+    #[test]
+    fn blank_calibration_is_refused() {
+        let blank = Calibration {
+            vrefint: 0xFFFF,
+            ts_cal1: 0xFFFF,
+            ts_cal2: 0xFFFF,
+        };
+        assert_eq!(blank.temperature(959, 3300), Err(ErrorCode::FAIL));
+    }
+}
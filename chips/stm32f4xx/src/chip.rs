@@ -30,6 +30,8 @@ pub struct Stm32f4xxDefaultPeripherals<'a, ChipSpecs> {
     pub fsmc: crate::fsmc::Fsmc<'a>,
     pub gpio_ports: crate::gpio::GpioPorts<'a>,
     pub i2c1: crate::i2c::I2C<'a>,
+    pub i2c2: crate::i2c::I2C<'a>,
+    pub i2c3: crate::i2c::I2C<'a>,
     pub clocks: crate::clocks::Clocks<'a, ChipSpecs>,
     pub spi3: crate::spi::Spi<'a>,
     pub tim2: crate::tim2::Tim2<'a>,
@@ -63,7 +65,9 @@ impl<'a, ChipSpecs: ChipSpecsTrait> Stm32f4xxDefaultPeripherals<'a, ChipSpecs> {
                 rcc,
             ),
             gpio_ports: crate::gpio::GpioPorts::new(rcc, exti),
-            i2c1: crate::i2c::I2C::new(rcc),
+            i2c1: crate::i2c::I2C::new_i2c1(rcc),
+            i2c2: crate::i2c::I2C::new_i2c2(rcc),
+            i2c3: crate::i2c::I2C::new_i2c3(rcc),
             spi3: crate::spi::Spi::new(
                 crate::spi::SPI3_BASE,
                 crate::spi::SpiClock(crate::rcc::PeripheralClock::new(
@@ -133,6 +137,10 @@ impl<'a, ChipSpecs: ChipSpecsTrait> InterruptService
 
             nvic::I2C1_EV => self.i2c1.handle_event(),
             nvic::I2C1_ER => self.i2c1.handle_error(),
+            nvic::I2C2_EV => self.i2c2.handle_event(),
+            nvic::I2C2_ER => self.i2c2.handle_error(),
+            nvic::I2C3_EV => self.i2c3.handle_event(),
+            nvic::I2C3_ER => self.i2c3.handle_error(),
 
             nvic::SPI3 => self.spi3.handle_interrupt(),
 
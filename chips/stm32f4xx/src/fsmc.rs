@@ -2,11 +2,27 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright Tock Contributors 2022.
 
+//! Flexible static memory controller, driving a parallel display bus.
+//!
+//! Besides the `Bus8080` HIL, the FSMC runs scripts of register writes with
+//! their parameters, such as the initialization sequence of a display
+//! controller, with a single completion. A script is a list of steps, each
+//! made of the register, the number of parameters and the parameters:
+//!
+//! ```text
+//! [register, count, parameter 1, ..., parameter count]
+//! ```
+//!
+//! Registers and parameters are written as 8 bit values. At most
+//! `SCRIPT_WORDS_PER_CALL` values are written at a time, the rest of the
+//! script runs from the next deferred calls.
+
 use crate::rcc;
 use core::cell::Cell;
 use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil::bus8080::{Bus8080, BusWidth, Client};
 use kernel::platform::chip::ClockInterface;
+use kernel::platform::watchdog::WorkProgress;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable};
 use kernel::utilities::registers::{register_bitfields, ReadWrite};
@@ -143,6 +159,7 @@ pub struct FsmcBank {
 }
 
 #[repr(usize)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum FsmcBanks {
     Bank1 = 0,
     Bank2 = 1,
@@ -157,17 +174,148 @@ pub const FSMC_BANK3: StaticRef<FsmcBank> =
     unsafe { StaticRef::new(0x68000000 as *const FsmcBank) };
 // const FSMC_BANK4_RESERVED: StaticRef<FsmcBank> = unsafe { StaticRef::new(0x0 as *const FsmcBank) };
 
+/// Access mode of the extended mode, see the reference manual.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FsmcAccessMode {
+    A = 0b00,
+    B = 0b01,
+    C = 0b10,
+    D = 0b11,
+}
+
+/// Timings of the accesses in one direction, in HCLK cycles. Values out of
+/// range are saturated.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FsmcAccessTimings {
+    /// Address setup phase duration, 0 to 15.
+    pub addset: u8,
+    /// Address hold phase duration, 1 to 15.
+    pub addhld: u8,
+    /// Data phase duration, 1 to 255.
+    pub datast: u8,
+    /// Bus turnaround phase duration, 0 to 15.
+    pub busturn: u8,
+    pub accmod: FsmcAccessMode,
+}
+
+impl FsmcAccessTimings {
+    /// The timings brought into the range of the register fields.
+    fn saturated(self) -> FsmcAccessTimings {
+        FsmcAccessTimings {
+            addset: self.addset.min(15),
+            addhld: self.addhld.clamp(1, 15),
+            datast: self.datast.max(1),
+            busturn: self.busturn.min(15),
+            accmod: self.accmod,
+        }
+    }
+}
+
+/// Timings of the read and write accesses to a bank.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FsmcTimings {
+    pub read: FsmcAccessTimings,
+    pub write: FsmcAccessTimings,
+    /// Period of FSMC_CLK in HCLK cycles minus one, 1 to 15. Only used by
+    /// synchronous memories.
+    pub clkdiv: u8,
+    /// Data latency of synchronous memories in FSMC_CLK cycles, minus two,
+    /// 0 to 15.
+    pub datlat: u8,
+}
+
+/// Timings used by `enable()`, for the LCD of the STM32F412G Discovery.
+pub const DEFAULT_TIMINGS: FsmcTimings = FsmcTimings {
+    read: FsmcAccessTimings {
+        addset: 9,
+        addhld: 1,
+        datast: 36,
+        busturn: 1,
+        accmod: FsmcAccessMode::A,
+    },
+    write: FsmcAccessTimings {
+        addset: 1,
+        addhld: 1,
+        datast: 7,
+        busturn: 0,
+        accmod: FsmcAccessMode::A,
+    },
+    clkdiv: 2,
+    datlat: 2,
+};
+
+/// Words written between touches of the watchdog handle.
+const WORDS_PER_TOUCH: usize = 1024;
+
+/// Words of a script written before running the rest from a deferred call.
+pub const SCRIPT_WORDS_PER_CALL: usize = 64;
+
+/// Notified when a script passed to `Fsmc::run_script()` has run.
+pub trait ScriptClient {
+    fn script_done(&self, script: &'static mut [u8], result: Result<(), ErrorCode>);
+}
+
+/// Where the steps of a script are written.
+trait ScriptTarget {
+    fn write_register(&self, addr: u16);
+    fn write_parameter(&self, data: u16);
+}
+
+/// Checks that `script` is a whole number of steps.
+fn check_script(script: &[u8]) -> Result<(), ErrorCode> {
+    let mut pos = 0;
+    while pos < script.len() {
+        let count = *script.get(pos + 1).ok_or(ErrorCode::INVAL)? as usize;
+        pos += 2 + count;
+    }
+    if pos == script.len() {
+        Ok(())
+    } else {
+        Err(ErrorCode::INVAL)
+    }
+}
+
+/// Writes the steps of a checked `script` from `pos`, until the script ends
+/// or `max_words` were written. Steps are not split. Returns the position
+/// of the next step.
+fn run_steps(target: &dyn ScriptTarget, script: &[u8], mut pos: usize, max_words: usize) -> usize {
+    let mut words = 0;
+    while pos < script.len() && words < max_words {
+        let count = script[pos + 1] as usize;
+        target.write_register(script[pos] as u16);
+        for parameter in &script[pos + 2..pos + 2 + count] {
+            target.write_parameter(*parameter as u16);
+        }
+        words += 1 + count;
+        pos += 2 + count;
+    }
+    pos
+}
+
 pub struct Fsmc<'a> {
     registers: StaticRef<FsmcBankRegisters>,
     bank: [Option<StaticRef<FsmcBank>>; 4],
+    /// The bank the bus accesses.
+    active_bank: Cell<FsmcBanks>,
     clock: FsmcClock<'a>,
 
     client: OptionalCell<&'static dyn Client>,
+    /// Whether a `Bus8080` operation waits for the deferred call.
+    bus_pending: Cell<bool>,
+
+    script_client: OptionalCell<&'static dyn ScriptClient>,
+    script: TakeCell<'static, [u8]>,
+    script_len: Cell<usize>,
+    /// Position in the script of the next step to write.
+    script_pos: Cell<usize>,
 
     buffer: TakeCell<'static, [u8]>,
+    /// Width in bytes of the data bus of the active bank.
     bus_width: Cell<usize>,
     len: Cell<usize>,
 
+    work_progress: OptionalCell<WorkProgress<'a>>,
+
     deferred_call: DeferredCall,
 }
 
@@ -176,26 +324,101 @@ impl<'a> Fsmc<'a> {
         Self {
             registers: FSMC_BASE,
             bank: bank_addr,
+            active_bank: Cell::new(FsmcBanks::Bank1),
             clock: FsmcClock(rcc::PeripheralClock::new(
                 rcc::PeripheralClockType::AHB3(rcc::HCLK3::FMC),
                 rcc,
             )),
             client: OptionalCell::empty(),
+            bus_pending: Cell::new(false),
+
+            script_client: OptionalCell::empty(),
+            script: TakeCell::empty(),
+            script_len: Cell::new(0),
+            script_pos: Cell::new(0),
 
             buffer: TakeCell::empty(),
-            bus_width: Cell::new(1),
+            // MWID resets to a 16 bit bus.
+            bus_width: Cell::new(2),
             len: Cell::new(0),
 
+            work_progress: OptionalCell::empty(),
+
             deferred_call: DeferredCall::new(),
         }
     }
 
+    /// Touch `work_progress` while writing large buffers, which blocks for
+    /// as long as the transfer takes.
+    pub fn set_work_progress(&self, work_progress: WorkProgress<'a>) {
+        self.work_progress.set(work_progress);
+    }
+
+    /// Select the bank `enable()` configures and the bus accesses, bank 1
+    /// by default. Returns `INVAL` if the bank is not mapped.
+    pub fn set_bank(&self, bank: FsmcBanks) -> Result<(), ErrorCode> {
+        if self.bank[bank as usize].is_none() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.active_bank.set(bank);
+        Ok(())
+    }
+
+    /// Enable the selected bank with a 16 bit bus and the default timings.
     pub fn enable(&self) {
-        self.registers.bcr1.modify(
+        self.enable_with_timings(DEFAULT_TIMINGS);
+    }
+
+    /// Enable the selected bank with a 16 bit bus and `timings`, such as
+    /// those computed by STM32CubeMX for a display.
+    pub fn enable_with_timings(&self, timings: FsmcTimings) {
+        let _ = self.configure_bank(self.active_bank.get(), timings, BusWidth::Bits16LE);
+    }
+
+    /// Enable `bank` as an SRAM with a `bus_width` data bus and `timings`,
+    /// and use it for the bus. Returns `INVAL` if the bank is not mapped.
+    pub fn configure_bank(
+        &self,
+        bank: FsmcBanks,
+        timings: FsmcTimings,
+        bus_width: BusWidth,
+    ) -> Result<(), ErrorCode> {
+        if self.bank[bank as usize].is_none() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.enable_clock();
+
+        let (bcr, btr, bwtr) = match bank {
+            FsmcBanks::Bank1 => (
+                &self.registers.bcr1,
+                &self.registers.btr1,
+                &self.registers.bwtr1,
+            ),
+            FsmcBanks::Bank2 => (
+                &self.registers.bcr2,
+                &self.registers.btr2,
+                &self.registers.bwtr2,
+            ),
+            FsmcBanks::Bank3 => (
+                &self.registers.bcr3,
+                &self.registers.btr3,
+                &self.registers.bwtr3,
+            ),
+            FsmcBanks::Bank4 => (
+                &self.registers.bcr4,
+                &self.registers.btr4,
+                &self.registers.bwtr4,
+            ),
+        };
+        let mwid = match bus_width {
+            BusWidth::Bits8 => BCR::MWID::BITS_8,
+            BusWidth::Bits16LE | BusWidth::Bits16BE => BCR::MWID::BITS_16,
+        };
+        bcr.modify(
             BCR::MBKEN::SET
                 + BCR::MUXEN::CLEAR
                 + BCR::MTYP::SRAM
-                + BCR::MWID::BITS_16
+                + mwid
                 + BCR::BURSTEN::CLEAR
                 + BCR::WAITPOL::CLEAR
                 + BCR::WAITCFG::CLEAR
@@ -208,23 +431,76 @@ impl<'a> Fsmc<'a> {
                 + BCR::CPSIZE::NO_BURST
                 + BCR::CCLKEN::CLEAR,
         );
-        self.registers.btr1.modify(
-            BTR::ADDSET.val(9)
-                + BTR::ADDHLD.val(1)
-                + BTR::DATAST.val(36)
-                + BTR::BUSTURN.val(1)
-                + BTR::CLKDIV.val(2)
-                + BTR::DATLAT.val(2)
-                + BTR::ACCMOD::A,
+        let read = timings.read.saturated();
+        btr.modify(
+            BTR::ADDSET.val(read.addset as u32)
+                + BTR::ADDHLD.val(read.addhld as u32)
+                + BTR::DATAST.val(read.datast as u32)
+                + BTR::BUSTURN.val(read.busturn as u32)
+                + BTR::CLKDIV.val(timings.clkdiv.clamp(1, 15) as u32)
+                + BTR::DATLAT.val(timings.datlat.min(15) as u32)
+                + BTR::ACCMOD.val(read.accmod as u32),
         );
-        self.registers.bwtr1.modify(
-            BWTR::ADDSET.val(1)
-                + BWTR::ADDHLD.val(1)
-                + BWTR::DATAST.val(7)
-                + BWTR::BUSTURN.val(0)
-                + BWTR::ACCMOD::A,
+        let write = timings.write.saturated();
+        bwtr.modify(
+            BWTR::ADDSET.val(write.addset as u32)
+                + BWTR::ADDHLD.val(write.addhld as u32)
+                + BWTR::DATAST.val(write.datast as u32)
+                + BWTR::BUSTURN.val(write.busturn as u32)
+                + BWTR::ACCMOD.val(write.accmod as u32),
         );
-        self.enable_clock();
+        self.active_bank.set(bank);
+        self.bus_width.set(bus_width.width_in_bytes());
+        Ok(())
+    }
+
+    pub fn set_script_client(&self, client: &'static dyn ScriptClient) {
+        self.script_client.set(client);
+    }
+
+    /// Run the first `len` bytes of `script` on the active bank, see the
+    /// module documentation for the format. Returns `INVAL` if they are not
+    /// a whole number of steps, and `BUSY` if a script or a `Bus8080`
+    /// operation is in progress.
+    pub fn run_script(
+        &self,
+        script: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.script.is_some() || self.bus_pending.get() {
+            return Err((ErrorCode::BUSY, script));
+        }
+        if len > script.len() {
+            return Err((ErrorCode::INVAL, script));
+        }
+        if let Err(error) = check_script(&script[..len]) {
+            return Err((error, script));
+        }
+        let pos = run_steps(self, &script[..len], 0, SCRIPT_WORDS_PER_CALL);
+        self.script.replace(script);
+        self.script_len.set(len);
+        self.script_pos.set(pos);
+        self.deferred_call.set();
+        Ok(())
+    }
+
+    /// Returns `BUSY` while a script runs.
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        if self.script.is_some() {
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `INVAL` if `width` is wider than the data bus of the active
+    /// bank.
+    fn check_width(&self, width: &BusWidth) -> Result<(), ErrorCode> {
+        if width.width_in_bytes() > self.bus_width.get() {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn disable(&self) {
@@ -244,36 +520,36 @@ impl<'a> Fsmc<'a> {
         self.bank[bank as usize].map_or(None, |bank| Some(bank.ram.get()))
     }
 
-    #[cfg(all(target_arch = "arm", target_os = "none"))]
     #[inline]
     fn write_reg(&self, bank: FsmcBanks, addr: u16) {
         use kernel::utilities::registers::interfaces::Writeable;
         self.bank[bank as usize].map(|bank| bank.reg.set(addr));
+        #[cfg(all(target_arch = "arm", target_os = "none"))]
         unsafe {
             use core::arch::asm;
             asm!("dsb 0xf");
         }
     }
 
-    #[cfg(all(target_arch = "arm", target_os = "none"))]
     #[inline]
     fn write_data(&self, bank: FsmcBanks, data: u16) {
         use kernel::utilities::registers::interfaces::Writeable;
         self.bank[bank as usize].map(|bank| bank.ram.set(data));
+        #[cfg(all(target_arch = "arm", target_os = "none"))]
         unsafe {
             use core::arch::asm;
             asm!("dsb 0xf");
         }
     }
+}
 
-    #[cfg(not(all(target_arch = "arm", target_os = "none")))]
-    fn write_reg(&self, _bank: FsmcBanks, _addr: u16) {
-        unimplemented!()
+impl ScriptTarget for Fsmc<'_> {
+    fn write_register(&self, addr: u16) {
+        self.write_reg(self.active_bank.get(), addr);
     }
 
-    #[cfg(not(all(target_arch = "arm", target_os = "none")))]
-    fn write_data(&self, _bank: FsmcBanks, _data: u16) {
-        unimplemented!()
+    fn write_parameter(&self, data: u16) {
+        self.write_data(self.active_bank.get(), data);
     }
 }
 
@@ -283,6 +559,26 @@ impl DeferredCallClient for Fsmc<'_> {
     }
 
     fn handle_deferred_call(&self) {
+        if let Some(script) = self.script.take() {
+            let len = self.script_len.get();
+            if self.script_pos.get() < len {
+                let pos = run_steps(
+                    self,
+                    &script[..len],
+                    self.script_pos.get(),
+                    SCRIPT_WORDS_PER_CALL,
+                );
+                self.script_pos.set(pos);
+                self.script.replace(script);
+                self.deferred_call.set();
+            } else {
+                self.script_client
+                    .map(move |client| client.script_done(script, Ok(())));
+            }
+            return;
+        }
+
+        self.bus_pending.set(false);
         self.buffer.take().map_or_else(
             || {
                 self.client.map(move |client| {
@@ -316,14 +612,26 @@ impl ClockInterface for FsmcClock<'_> {
 
 impl Bus8080<'static> for Fsmc<'_> {
     fn set_addr(&self, addr_width: BusWidth, addr: usize) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.check_width(&addr_width)?;
         match addr_width {
             BusWidth::Bits8 => {
-                self.write_reg(FsmcBanks::Bank1, addr as u16);
-                self.deferred_call.set();
-                Ok(())
+                self.write_reg(self.active_bank.get(), addr as u16);
+            }
+            BusWidth::Bits16LE => {
+                let addr = u16::try_from(addr).map_err(|_| ErrorCode::INVAL)?;
+                self.write_reg(self.active_bank.get(), addr);
+            }
+            // The bus is little endian, the controller expects the high byte
+            // of the address on the low data lines.
+            BusWidth::Bits16BE => {
+                let addr = u16::try_from(addr).map_err(|_| ErrorCode::INVAL)?;
+                self.write_reg(self.active_bank.get(), addr.swap_bytes());
             }
-            _ => Err(ErrorCode::NOSUPPORT),
         }
+        self.bus_pending.set(true);
+        self.deferred_call.set();
+        Ok(())
     }
 
     fn write(
@@ -332,9 +640,21 @@ impl Bus8080<'static> for Fsmc<'_> {
         buffer: &'static mut [u8],
         len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(error) = self
+            .check_idle()
+            .and_then(|()| self.check_width(&data_width))
+        {
+            return Err((error, buffer));
+        }
         let bytes = data_width.width_in_bytes();
         if buffer.len() >= len * bytes {
+            self.work_progress
+                .map(|work_progress| work_progress.start());
             for pos in 0..len {
+                if pos % WORDS_PER_TOUCH == WORDS_PER_TOUCH - 1 {
+                    self.work_progress
+                        .map(|work_progress| work_progress.touch());
+                }
                 let mut data: u16 = 0;
                 for byte in 0..bytes {
                     data |= (buffer[bytes * pos
@@ -344,11 +664,13 @@ impl Bus8080<'static> for Fsmc<'_> {
                         }] as u16)
                         << (8 * byte);
                 }
-                self.write_data(FsmcBanks::Bank1, data);
+                self.write_data(self.active_bank.get(), data);
             }
+            self.work_progress
+                .map(|work_progress| work_progress.finish());
             self.buffer.replace(buffer);
-            self.bus_width.set(bytes);
             self.len.set(len);
+            self.bus_pending.set(true);
             self.deferred_call.set();
             Ok(())
         } else {
@@ -362,10 +684,16 @@ impl Bus8080<'static> for Fsmc<'_> {
         buffer: &'static mut [u8],
         len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(error) = self
+            .check_idle()
+            .and_then(|()| self.check_width(&data_width))
+        {
+            return Err((error, buffer));
+        }
         let bytes = data_width.width_in_bytes();
         if buffer.len() >= len * bytes {
             for pos in 0..len {
-                if let Some(data) = self.read_reg(FsmcBanks::Bank1) {
+                if let Some(data) = self.read_reg(self.active_bank.get()) {
                     for byte in 0..bytes {
                         buffer[bytes * pos
                             + match data_width {
@@ -378,8 +706,8 @@ impl Bus8080<'static> for Fsmc<'_> {
                 }
             }
             self.buffer.replace(buffer);
-            self.bus_width.set(bytes);
             self.len.set(len);
+            self.bus_pending.set(true);
             self.deferred_call.set();
             Ok(())
         } else {
@@ -391,3 +719,326 @@ impl Bus8080<'static> for Fsmc<'_> {
         self.client.replace(client);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    /// Part of the initialization of an ILI9341.
+    const ILI9341_INIT: [u8; 42] = [
+        0x01, 0, // Software reset
+        0xcf, 3, 0x00, 0xc1, 0x30, // Power control B
+        0xed, 4, 0x64, 0x03, 0x12, 0x81, // Power on sequence control
+        0xe8, 3, 0x85, 0x00, 0x78, // Driver timing control A
+        0xc0, 1, 0x23, // Power control 1
+        0xc1, 1, 0x10, // Power control 2
+        0xc5, 2, 0x3e, 0x28, // VCOM control 1
+        0x36, 1, 0x48, // Memory access control
+        0x3a, 1, 0x55, // Pixel format, 16 bits
+        0xb1, 2, 0x00, 0x18, // Frame rate control
+        0x11, 0, // Sleep out
+        0x29, 0, // Display on
+    ];
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Access {
+        Register(u16),
+        Parameter(u16),
+    }
+
+    /// A bank recording the accesses.
+    #[derive(Default)]
+    struct MockBank {
+        accesses: RefCell<Vec<Access>>,
+    }
+
+    impl ScriptTarget for MockBank {
+        fn write_register(&self, addr: u16) {
+            self.accesses.borrow_mut().push(Access::Register(addr));
+        }
+
+        fn write_parameter(&self, data: u16) {
+            self.accesses.borrow_mut().push(Access::Parameter(data));
+        }
+    }
+
+    #[derive(Default)]
+    struct ScriptDone {
+        results: RefCell<Vec<Result<(), ErrorCode>>>,
+    }
+
+    impl ScriptClient for ScriptDone {
+        fn script_done(&self, _script: &'static mut [u8], result: Result<(), ErrorCode>) {
+            self.results.borrow_mut().push(result);
+        }
+    }
+
+    /// An FSMC with in-memory registers and `bank` mapped to an in-memory
+    /// shadow of the bank, which keeps the last register and data written.
+    fn setup(bank: FsmcBanks) -> (Fsmc<'static>, &'static FsmcBankRegisters, &'static FsmcBank) {
+        let registers: &'static FsmcBankRegisters =
+            Box::leak(Box::new(unsafe { core::mem::zeroed() }));
+        let shadow: &'static FsmcBank = Box::leak(Box::new(unsafe { core::mem::zeroed() }));
+        let rcc: &'static rcc::Rcc = Box::leak(Box::new(rcc::Rcc::new_in_memory()));
+        let mut banks = [None, None, None, None];
+        banks[bank as usize] = Some(unsafe { StaticRef::new(shadow) });
+        let mut fsmc = Fsmc::new(banks, rcc);
+        fsmc.registers = unsafe { StaticRef::new(registers) };
+        (fsmc, registers, shadow)
+    }
+
+    #[test]
+    fn configure_bank_3() {
+        let (fsmc, registers, _) = setup(FsmcBanks::Bank3);
+
+        let timings = FsmcTimings {
+            read: FsmcAccessTimings {
+                addset: 2,
+                addhld: 3,
+                datast: 40,
+                busturn: 4,
+                accmod: FsmcAccessMode::B,
+            },
+            write: FsmcAccessTimings {
+                addset: 5,
+                addhld: 6,
+                datast: 12,
+                busturn: 7,
+                accmod: FsmcAccessMode::C,
+            },
+            clkdiv: 2,
+            datlat: 2,
+        };
+        assert_eq!(
+            fsmc.configure_bank(FsmcBanks::Bank1, timings, BusWidth::Bits8),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            fsmc.configure_bank(FsmcBanks::Bank3, timings, BusWidth::Bits8),
+            Ok(())
+        );
+
+        assert_eq!(registers.bcr1.get(), 0);
+        let bcr = registers.bcr3.extract();
+        assert!(bcr.is_set(BCR::MBKEN));
+        assert!(bcr.matches_all(BCR::MWID::BITS_8 + BCR::MTYP::SRAM));
+        assert_eq!(registers.btr3.get(), 0x1224_2832);
+        assert_eq!(registers.bwtr3.get(), 0x2007_0c65);
+        assert_eq!(fsmc.active_bank.get(), FsmcBanks::Bank3);
+    }
+
+    #[test]
+    fn sixteen_bit_addresses() {
+        let (fsmc, _, bank) = setup(FsmcBanks::Bank1);
+        fsmc.enable();
+
+        assert_eq!(fsmc.set_addr(BusWidth::Bits16LE, 0x1234), Ok(()));
+        assert_eq!(bank.reg.get(), 0x1234);
+        assert_eq!(fsmc.set_addr(BusWidth::Bits16BE, 0x1234), Ok(()));
+        assert_eq!(bank.reg.get(), 0x3412);
+        assert_eq!(fsmc.set_addr(BusWidth::Bits16BE, 0xb0e5), Ok(()));
+        assert_eq!(bank.reg.get(), 0xe5b0);
+        assert_eq!(fsmc.set_addr(BusWidth::Bits8, 0x2c), Ok(()));
+        assert_eq!(bank.reg.get(), 0x2c);
+
+        assert_eq!(
+            fsmc.set_addr(BusWidth::Bits16LE, 0x1_0000),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            fsmc.set_addr(BusWidth::Bits16BE, 0x1_0000),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(bank.reg.get(), 0x2c);
+    }
+
+    #[test]
+    fn sixteen_bit_access_to_an_8_bit_bank() {
+        let (fsmc, _, bank) = setup(FsmcBanks::Bank1);
+        assert_eq!(
+            fsmc.configure_bank(FsmcBanks::Bank1, DEFAULT_TIMINGS, BusWidth::Bits8),
+            Ok(())
+        );
+
+        assert_eq!(
+            fsmc.set_addr(BusWidth::Bits16LE, 0x12),
+            Err(ErrorCode::INVAL)
+        );
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0x34, 0x12]));
+        let buffer = match fsmc.write(BusWidth::Bits16BE, buffer, 1) {
+            Err((ErrorCode::INVAL, buffer)) => buffer,
+            _ => panic!("16 bit write to an 8 bit bank"),
+        };
+        let buffer = match fsmc.read(BusWidth::Bits16LE, buffer, 1) {
+            Err((ErrorCode::INVAL, buffer)) => buffer,
+            _ => panic!("16 bit read from an 8 bit bank"),
+        };
+        assert_eq!(bank.reg.get(), 0);
+        assert_eq!(bank.ram.get(), 0);
+
+        assert_eq!(fsmc.set_addr(BusWidth::Bits8, 0x12), Ok(()));
+        assert_eq!(bank.reg.get(), 0x12);
+        assert!(fsmc.write(BusWidth::Bits8, buffer, 1).is_ok());
+        assert_eq!(bank.ram.get(), 0x34);
+    }
+
+    #[test]
+    fn ili9341_init_script() {
+        use Access::{Parameter as P, Register as R};
+        let bank = MockBank::default();
+
+        assert_eq!(check_script(&ILI9341_INIT), Ok(()));
+        assert_eq!(
+            run_steps(&bank, &ILI9341_INIT, 0, usize::MAX),
+            ILI9341_INIT.len()
+        );
+        assert_eq!(
+            bank.accesses.take(),
+            [
+                R(0x01),
+                R(0xcf),
+                P(0x00),
+                P(0xc1),
+                P(0x30),
+                R(0xed),
+                P(0x64),
+                P(0x03),
+                P(0x12),
+                P(0x81),
+                R(0xe8),
+                P(0x85),
+                P(0x00),
+                P(0x78),
+                R(0xc0),
+                P(0x23),
+                R(0xc1),
+                P(0x10),
+                R(0xc5),
+                P(0x3e),
+                P(0x28),
+                R(0x36),
+                P(0x48),
+                R(0x3a),
+                P(0x55),
+                R(0xb1),
+                P(0x00),
+                P(0x18),
+                R(0x11),
+                R(0x29),
+            ]
+        );
+    }
+
+    #[test]
+    fn scripts_run_whole_steps_at_a_time() {
+        let bank = MockBank::default();
+
+        // The third step goes past 6 words, but is not split.
+        assert_eq!(run_steps(&bank, &ILI9341_INIT, 0, 6), 13);
+        assert_eq!(bank.accesses.take().len(), 10);
+        assert_eq!(run_steps(&bank, &ILI9341_INIT, 13, 1), 18);
+        assert_eq!(bank.accesses.take().len(), 4);
+
+        assert_eq!(check_script(&ILI9341_INIT[..4]), Err(ErrorCode::INVAL));
+        assert_eq!(check_script(&ILI9341_INIT[..1]), Err(ErrorCode::INVAL));
+        assert_eq!(check_script(&[]), Ok(()));
+    }
+
+    #[test]
+    fn long_scripts_run_from_deferred_calls() {
+        let (fsmc, _, bank) = setup(FsmcBanks::Bank1);
+        let fsmc: &'static Fsmc = Box::leak(Box::new(fsmc));
+        let done: &'static ScriptDone = Box::leak(Box::default());
+        fsmc.set_script_client(done);
+        fsmc.enable();
+
+        // A memory write of 100 pixels, then the ILI9341 initialization.
+        let mut script = std::vec![0x2c, 100];
+        script.extend((0..100).map(|pixel| pixel as u8));
+        script.extend_from_slice(&ILI9341_INIT);
+        let len = script.len();
+        let script: &'static mut [u8] = Box::leak(script.into_boxed_slice());
+
+        assert!(fsmc.run_script(script, len).is_ok());
+        assert_eq!(bank.reg.get(), 0x2c);
+        assert_eq!(bank.ram.get(), 99);
+        assert_eq!(fsmc.set_addr(BusWidth::Bits8, 0x2a), Err(ErrorCode::BUSY));
+
+        fsmc.handle_deferred_call();
+        assert_eq!(bank.reg.get(), 0x29);
+        assert!(done.results.borrow().is_empty());
+
+        fsmc.handle_deferred_call();
+        assert_eq!(done.results.take(), [Ok(())]);
+        assert_eq!(fsmc.set_addr(BusWidth::Bits8, 0x2a), Ok(()));
+        let script: &'static mut [u8] = Box::leak(Box::new(ILI9341_INIT));
+        assert!(matches!(
+            fsmc.run_script(script, ILI9341_INIT.len()),
+            Err((ErrorCode::BUSY, _))
+        ));
+    }
+
+    #[test]
+    fn enable_the_selected_bank() {
+        let (fsmc, registers, bank) = setup(FsmcBanks::Bank3);
+
+        assert_eq!(fsmc.set_bank(FsmcBanks::Bank2), Err(ErrorCode::INVAL));
+        assert_eq!(fsmc.active_bank.get(), FsmcBanks::Bank1);
+        assert_eq!(fsmc.set_bank(FsmcBanks::Bank3), Ok(()));
+        fsmc.enable();
+
+        assert_eq!(registers.bcr1.get(), 0);
+        let bcr = registers.bcr3.extract();
+        assert!(bcr.is_set(BCR::MBKEN));
+        assert!(bcr.matches_all(BCR::MWID::BITS_16));
+
+        assert_eq!(fsmc.set_addr(BusWidth::Bits16LE, 0x1234), Ok(()));
+        assert_eq!(bank.reg.get(), 0x1234);
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0x34, 0x12]));
+        assert!(fsmc.write(BusWidth::Bits16LE, buffer, 1).is_ok());
+        assert_eq!(bank.ram.get(), 0x1234);
+    }
+
+    #[test]
+    fn timings_out_of_range_are_saturated() {
+        let (fsmc, registers, _) = setup(FsmcBanks::Bank1);
+
+        let timings = FsmcTimings {
+            read: FsmcAccessTimings {
+                addset: 20,
+                addhld: 0,
+                datast: 0,
+                busturn: 16,
+                accmod: FsmcAccessMode::A,
+            },
+            write: FsmcAccessTimings {
+                addset: 3,
+                addhld: 200,
+                datast: 255,
+                busturn: 1,
+                accmod: FsmcAccessMode::A,
+            },
+            clkdiv: 0,
+            datlat: 40,
+        };
+        fsmc.enable_with_timings(timings);
+
+        let btr = registers.btr1.extract();
+        assert_eq!(btr.read(BTR::ADDSET), 15);
+        assert_eq!(btr.read(BTR::ADDHLD), 1);
+        assert_eq!(btr.read(BTR::DATAST), 1);
+        assert_eq!(btr.read(BTR::BUSTURN), 15);
+        assert_eq!(btr.read(BTR::CLKDIV), 1);
+        assert_eq!(btr.read(BTR::DATLAT), 15);
+        let bwtr = registers.bwtr1.extract();
+        assert_eq!(bwtr.read(BWTR::ADDSET), 3);
+        assert_eq!(bwtr.read(BWTR::ADDHLD), 15);
+        assert_eq!(bwtr.read(BWTR::DATAST), 255);
+        assert_eq!(bwtr.read(BWTR::BUSTURN), 1);
+    }
+}
@@ -5,13 +5,20 @@
 use core::cell::Cell;
 
 use kernel::hil;
-use kernel::hil::i2c::{self, Error, I2CHwMasterClient, I2CMaster};
+use kernel::hil::gpio::{Input, Output};
+use kernel::hil::i2c::{
+    self, Error, I2CHwMasterClient, I2CHwSlaveClient, I2CMaster, I2CMaster10Bit,
+    SlaveTransmissionType,
+};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Freq16KHz, Ticks32};
 use kernel::platform::chip::ClockInterface;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
-use kernel::utilities::registers::{register_bitfields, ReadWrite};
+use kernel::utilities::registers::{register_bitfields, LocalRegisterCopy, ReadWrite};
 use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
 
+use crate::gpio;
 use crate::rcc;
 
 pub enum I2CSpeed {
@@ -92,6 +99,8 @@ register_bitfields![u32,
     OAR1 [
         /// Addressing mode (slave mode)
         ADDMODE OFFSET(15) NUMBITS(1) [],
+        /// Should always be kept at 1 by software
+        BIT14 OFFSET(14) NUMBITS(1) [],
         /// Interface address
         ADD OFFSET(0) NUMBITS(10) []
     ],
@@ -178,17 +187,34 @@ register_bitfields![u32,
 
 const I2C1_BASE: StaticRef<I2CRegisters> =
     unsafe { StaticRef::new(0x4000_5400 as *const I2CRegisters) };
-// const I2C2_BASE: StaticRef<I2CRegisters> =
-//     unsafe { StaticRef::new(0x4000_5800 as *const I2CRegisters) };
-// const I2C3_BASE: StaticRef<I2CRegisters> =
-//     unsafe { StaticRef::new(0x4000_5C00 as *const I2CRegisters) };
+const I2C2_BASE: StaticRef<I2CRegisters> =
+    unsafe { StaticRef::new(0x4000_5800 as *const I2CRegisters) };
+const I2C3_BASE: StaticRef<I2CRegisters> =
+    unsafe { StaticRef::new(0x4000_5C00 as *const I2CRegisters) };
+
+/// A master transfer not done after this long is given up, unless the board
+/// sets another bound. Transfers of a couple hundred bytes at 100 kHz take
+/// less than 50 ms.
+const DEFAULT_TRANSFER_TIMEOUT_MS: u32 = 100;
+
+/// The alarm master transfers are timed with, such as a virtual alarm of
+/// TIM2.
+pub type TimeoutAlarm<'a> = dyn Alarm<'a, Frequency = Freq16KHz, Ticks = Ticks32> + 'a;
+
+/// Clock pulses `bus_recover()` sends at most: enough for a slave to finish
+/// the byte it sends and see a NAK.
+const RECOVERY_CLOCK_PULSES: usize = 9;
 
 pub struct I2C<'a> {
     registers: StaticRef<I2CRegisters>,
     clock: I2CClock<'a>,
+    timeout_alarm: OptionalCell<&'a TimeoutAlarm<'a>>,
+    transfer_timeout_ms: Cell<u32>,
+    /// SCL and SDA, for `bus_recover()`.
+    bus_pins: OptionalCell<(&'a gpio::Pin<'a>, &'a gpio::Pin<'a>)>,
 
-    // I2C slave support not yet implemented
     master_client: OptionalCell<&'a dyn hil::i2c::I2CHwMasterClient>,
+    slave_client: OptionalCell<&'a dyn hil::i2c::I2CHwSlaveClient>,
 
     buffer: TakeCell<'static, [u8]>,
     tx_position: Cell<usize>,
@@ -196,9 +222,27 @@ pub struct I2C<'a> {
     tx_len: Cell<usize>,
     rx_len: Cell<usize>,
 
-    slave_address: Cell<u8>,
+    slave_address: Cell<u16>,
+    /// Whether `slave_address` is a 10-bit address.
+    ten_bit_address: Cell<bool>,
+    /// Whether the whole 10-bit address was sent in the current transfer,
+    /// after which a repeated start only needs the header to read.
+    ten_bit_addressed: Cell<bool>,
+    /// Whether the slave acknowledged its address in the current phase.
+    address_acked: Cell<bool>,
 
     status: Cell<I2CStatus>,
+
+    /// Receives what other masters write to us.
+    slave_write_buffer: TakeCell<'static, [u8]>,
+    slave_write_len: Cell<usize>,
+    /// Sent to other masters reading from us.
+    slave_read_buffer: TakeCell<'static, [u8]>,
+    slave_read_len: Cell<usize>,
+    /// Bytes received, or loaded to be sent, in the current slave transfer.
+    slave_position: Cell<usize>,
+    slave_listening: Cell<bool>,
+    slave_status: Cell<SlaveStatus>,
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -209,18 +253,61 @@ enum I2CStatus {
     Reading,
 }
 
+#[derive(Copy, Clone, PartialEq)]
+enum SlaveStatus {
+    Idle,
+    /// Another master writes to us.
+    Receiving,
+    /// Another master reads from us.
+    Transmitting,
+}
+
 impl<'a> I2C<'a> {
-    pub fn new(rcc: &'a rcc::Rcc) -> Self {
-        Self {
-            registers: I2C1_BASE,
-            clock: I2CClock(rcc::PeripheralClock::new(
+    pub fn new_i2c1(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            I2C1_BASE,
+            I2CClock(rcc::PeripheralClock::new(
                 rcc::PeripheralClockType::APB1(rcc::PCLK1::I2C1),
                 rcc,
             )),
+        )
+    }
+
+    pub fn new_i2c2(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            I2C2_BASE,
+            I2CClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::I2C2),
+                rcc,
+            )),
+        )
+    }
+
+    pub fn new_i2c3(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            I2C3_BASE,
+            I2CClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::I2C3),
+                rcc,
+            )),
+        )
+    }
+
+    fn new(base_addr: StaticRef<I2CRegisters>, clock: I2CClock<'a>) -> Self {
+        Self {
+            registers: base_addr,
+            clock: clock,
+            timeout_alarm: OptionalCell::empty(),
+            transfer_timeout_ms: Cell::new(DEFAULT_TRANSFER_TIMEOUT_MS),
+            bus_pins: OptionalCell::empty(),
 
             master_client: OptionalCell::empty(),
+            slave_client: OptionalCell::empty(),
 
             slave_address: Cell::new(0),
+            ten_bit_address: Cell::new(false),
+            ten_bit_addressed: Cell::new(false),
+            address_acked: Cell::new(false),
 
             buffer: TakeCell::empty(),
             tx_position: Cell::new(0),
@@ -230,6 +317,14 @@ impl<'a> I2C<'a> {
             rx_len: Cell::new(0),
 
             status: Cell::new(I2CStatus::Idle),
+
+            slave_write_buffer: TakeCell::empty(),
+            slave_write_len: Cell::new(0),
+            slave_read_buffer: TakeCell::empty(),
+            slave_read_len: Cell::new(0),
+            slave_position: Cell::new(0),
+            slave_listening: Cell::new(false),
+            slave_status: Cell::new(SlaveStatus::Idle),
         }
     }
 
@@ -259,6 +354,88 @@ impl<'a> I2C<'a> {
         self.enable();
     }
 
+    /// Time master transfers with `alarm`: a transfer still not done when
+    /// it fires, for instance because a device holds SDA low, ends with
+    /// `Error::Timeout` and the peripheral is reset. The I2C must be set as
+    /// the client of `alarm`.
+    pub fn set_timeout_alarm(&self, alarm: &'a TimeoutAlarm<'a>) {
+        self.timeout_alarm.set(alarm);
+    }
+
+    /// Give up master transfers after `ms` milliseconds instead of 100, for
+    /// slow buses or long transfers. Applies from the next transfer.
+    pub fn set_transfer_timeout(&self, ms: u32) {
+        self.transfer_timeout_ms.set(ms);
+    }
+
+    /// The `scl` and `sda` pins of the bus, set up as open-drain in the I2C
+    /// alternate function, which `bus_recover()` drives by hand.
+    pub fn set_bus_pins(&self, scl: &'a gpio::Pin<'a>, sda: &'a gpio::Pin<'a>) {
+        self.bus_pins.set((scl, sda));
+    }
+
+    /// Frees a bus a slave holds SDA low on, as when it was sending a byte
+    /// while the MCU reset, which makes every transfer fail with
+    /// `Error::Busy` or time out. SCL is pulsed as a GPIO, up to nine times,
+    /// until the slave releases SDA, then a stop condition is sent and the
+    /// peripheral is reset with its configuration kept.
+    ///
+    /// Blocks for up to about a millisecond, so boards call it during
+    /// initialization or after a failed transfer. Returns `NOSUPPORT` without
+    /// `set_bus_pins()`, `BUSY` during a transfer, and `FAIL` if SDA is still
+    /// low after the last pulse.
+    pub fn bus_recover(&self) -> Result<(), ErrorCode> {
+        let (scl, sda) = self.bus_pins.get().ok_or(ErrorCode::NOSUPPORT)?;
+        if !self.is_idle() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // Half a period of a clock well below 100 kHz: FREQ is the APB1
+        // clock in MHz and the core runs at least as fast.
+        let half_period = self.registers.cr2.read(CR2::FREQ) as usize * 10;
+        let wait = || {
+            for _ in 0..half_period {
+                core::hint::spin_loop();
+            }
+        };
+
+        // The pins are open-drain: set releases the line, clear pulls it low.
+        scl.set();
+        sda.set();
+        scl.set_mode(gpio::Mode::GeneralPurposeOutputMode);
+        sda.set_mode(gpio::Mode::GeneralPurposeOutputMode);
+        wait();
+        for _ in 0..RECOVERY_CLOCK_PULSES {
+            if sda.read() {
+                break;
+            }
+            scl.clear();
+            wait();
+            scl.set();
+            wait();
+        }
+        let released = sda.read();
+
+        // Stop condition: SDA rises while SCL is high.
+        scl.clear();
+        wait();
+        sda.clear();
+        wait();
+        scl.set();
+        wait();
+        sda.set();
+        wait();
+        scl.set_mode(gpio::Mode::AlternateFunctionMode);
+        sda.set_mode(gpio::Mode::AlternateFunctionMode);
+
+        self.recover_bus();
+        if released {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
     pub fn is_enabled_clock(&self) -> bool {
         self.clock.is_enabled()
     }
@@ -272,21 +449,69 @@ impl<'a> I2C<'a> {
     }
 
     pub fn handle_event(&self) {
+        // Reading SR1 then SR2 clears ADDR, so both are read once here.
+        let sr1 = self.registers.sr1.extract();
+        let sr2 = self.registers.sr2.extract();
+        if !sr2.is_set(SR2::MSL) {
+            self.handle_slave_event(sr1, sr2);
+        } else if self.status.get() != I2CStatus::Idle {
+            self.handle_master_event(sr1);
+        }
+        // Otherwise a stop was requested and the hardware has not left master
+        // mode yet, there is nothing left to do for the transfer.
+    }
+
+    fn handle_master_event(&self, sr1: LocalRegisterCopy<u32, SR1::Register>) {
         if self.registers.sr1.is_set(SR1::SB) {
             let dir = match self.status.get() {
                 I2CStatus::Writing | I2CStatus::WritingReading => 0,
                 I2CStatus::Reading => 1,
                 _ => panic!("invalid i2c state when setting address"),
             };
+            if dir == 1 {
+                // The last byte is NACKed from the start of a read of one or
+                // two bytes, as ADDR is cleared before this is called again.
+                match self.rx_len.get() {
+                    1 => self.registers.cr1.modify(CR1::ACK::CLEAR),
+                    2 => self.registers.cr1.modify(CR1::ACK::CLEAR + CR1::POS::SET),
+                    _ => {}
+                }
+            }
+            let address = self.slave_address.get() as u32;
+            if !self.ten_bit_address.get() {
+                self.registers.dr.write(DR::DR.val((address << 1) | dir));
+            } else if dir == 1 && self.ten_bit_addressed.get() {
+                self.registers
+                    .dr
+                    .write(DR::DR.val(ten_bit_header(address) | 1));
+            } else {
+                // The second byte of the address follows the header, which
+                // is always sent for a write.
+                self.registers.dr.write(DR::DR.val(ten_bit_header(address)));
+            }
+        }
+        if sr1.is_set(SR1::ADD10) {
             self.registers
                 .dr
-                .write(DR::DR.val(((self.slave_address.get() << 1) as u32) | dir));
+                .write(DR::DR.val(self.slave_address.get() as u32 & 0xFF));
         }
-        if self.registers.sr1.is_set(SR1::ADDR) {
-            // i2c requires a sr2 read
-            self.registers.sr2.get();
+        if sr1.is_set(SR1::ADDR) {
+            self.address_acked.set(true);
+            if self.ten_bit_address.get() && !self.ten_bit_addressed.get() {
+                self.ten_bit_addressed.set(true);
+                if self.status.get() == I2CStatus::Reading {
+                    // The device is addressed, a repeated start with the
+                    // header turns the transfer into a read.
+                    self.address_acked.set(false);
+                    self.registers.cr1.modify(CR1::START::SET);
+                    return;
+                }
+            }
+            if self.status.get() == I2CStatus::Reading && self.rx_len.get() == 1 {
+                self.registers.cr1.modify(CR1::STOP::SET);
+            }
         }
-        if self.registers.sr1.is_set(SR1::TXE) {
+        if self.registers.sr1.is_set(SR1::TXE) && self.status.get() != I2CStatus::Reading {
             // send the next byte
             if self.buffer.is_some() && self.tx_position.get() < self.tx_len.get() {
                 self.buffer.map(|buf| {
@@ -297,28 +522,9 @@ impl<'a> I2C<'a> {
             }
         }
 
-        while self.registers.sr1.is_set(SR1::RXNE) {
-            // send the next byte
-            let byte = self.registers.dr.read(DR::DR);
-            if self.buffer.is_some() && self.rx_position.get() < self.rx_len.get() {
-                self.buffer.map(|buf| {
-                    buf[self.rx_position.get()] = byte as u8;
-                    self.rx_position.set(self.rx_position.get() + 1);
-                });
-            }
-
-            if self.buffer.is_some() && self.rx_position.get() == self.rx_len.get() {
-                self.registers.cr1.modify(CR1::STOP::SET);
-                self.stop();
-                self.master_client.map(|client| {
-                    self.buffer
-                        .take()
-                        .map(|buf| client.command_complete(buf, Ok(())))
-                });
-            }
-        }
-
-        if self.registers.sr1.is_set(SR1::BTF) {
+        if self.status.get() == I2CStatus::Reading {
+            self.receive(sr1);
+        } else if self.registers.sr1.is_set(SR1::BTF) {
             match self.status.get() {
                 I2CStatus::Writing | I2CStatus::WritingReading => {
                     if self.tx_position.get() < self.tx_len.get() {
@@ -344,32 +550,164 @@ impl<'a> I2C<'a> {
                         }
                     }
                 }
-                I2CStatus::Reading => {
-                    let status = if self.rx_position.get() == self.rx_len.get() {
-                        Ok(())
-                    } else {
-                        Err(Error::DataNak)
-                    };
-                    self.registers.cr1.modify(CR1::STOP::SET);
-                    self.stop();
-                    self.master_client.map(|client| {
-                        self.buffer
-                            .take()
-                            .map(|buf| client.command_complete(buf, status))
-                    });
-                }
                 _ => panic!("i2c status error"),
             }
         }
     }
 
-    pub fn handle_error(&self) {
+    /// Receives the bytes of a read with the sequences of the reference
+    /// manual, which NACK the last byte and stop after it:
+    ///
+    /// - one byte: ACK is cleared before ADDR is, and STOP set after.
+    /// - two bytes: ACK is cleared and POS set before ADDR is cleared, so
+    ///   the second byte is NACKed. Both are read once BTF is set, after
+    ///   setting STOP.
+    /// - more bytes: they are read on RXNE until three are left. Once BTF is
+    ///   set the third to last is in DR and the second to last in the shift
+    ///   register, ACK is cleared before reading the former and STOP set
+    ///   before reading the latter. The last one comes with RXNE.
+    fn receive(&self, sr1: LocalRegisterCopy<u32, SR1::Register>) {
+        let remaining = self.rx_len.get() - self.rx_position.get();
+        if sr1.is_set(SR1::BTF) && remaining == 2 {
+            self.registers.cr1.modify(CR1::STOP::SET);
+            self.receive_byte();
+            self.receive_byte();
+            self.receive_done();
+        } else if sr1.is_set(SR1::BTF) && remaining == 3 {
+            self.registers.cr1.modify(CR1::ACK::CLEAR);
+            self.receive_byte();
+            self.registers.cr1.modify(CR1::STOP::SET);
+            self.receive_byte();
+            self.registers.cr2.modify(CR2::ITBUFEN::SET);
+        } else if sr1.is_set(SR1::RXNE) && remaining == 1 {
+            self.receive_byte();
+            self.receive_done();
+        } else if sr1.is_set(SR1::RXNE) && remaining > 3 {
+            self.receive_byte();
+            if remaining == 4 {
+                // The end of the read waits for BTF.
+                self.registers.cr2.modify(CR2::ITBUFEN::CLEAR);
+            }
+        }
+    }
+
+    fn receive_byte(&self) {
+        let byte = self.registers.dr.read(DR::DR) as u8;
+        let position = self.rx_position.get();
+        self.buffer.map(|buf| buf[position] = byte);
+        self.rx_position.set(position + 1);
+    }
+
+    fn receive_done(&self) {
+        self.stop();
         self.master_client.map(|client| {
             self.buffer
                 .take()
-                .map(|buf| client.command_complete(buf, Err(Error::DataNak)))
+                .map(|buf| client.command_complete(buf, Ok(())))
         });
+    }
+
+    fn handle_slave_event(
+        &self,
+        sr1: LocalRegisterCopy<u32, SR1::Register>,
+        sr2: LocalRegisterCopy<u32, SR2::Register>,
+    ) {
+        if sr1.is_set(SR1::RXNE) {
+            let byte = self.registers.dr.read(DR::DR) as u8;
+            if self.slave_status.get() == SlaveStatus::Receiving {
+                // Bytes past the end of the buffer are dropped.
+                let position = self.slave_position.get();
+                if position < self.slave_write_len.get() {
+                    self.slave_write_buffer.map(|buf| buf[position] = byte);
+                    self.slave_position.set(position + 1);
+                }
+            }
+        }
+
+        if sr1.is_set(SR1::ADDR) {
+            // A repeated start ends the write that came before it.
+            if self.slave_status.get() == SlaveStatus::Receiving {
+                self.slave_done(self.slave_position.get());
+            }
+            self.slave_position.set(0);
+            if sr2.is_set(SR2::TRA) {
+                self.slave_status.set(SlaveStatus::Transmitting);
+                if self.slave_read_buffer.is_none() {
+                    // The clock is stretched until `read_send()` is called.
+                    self.pause_slave();
+                    self.slave_client.map(|client| client.read_expected());
+                }
+            } else {
+                self.slave_status.set(SlaveStatus::Receiving);
+                if self.slave_write_buffer.is_none() {
+                    // The clock is stretched until `write_receive()` is called.
+                    self.pause_slave();
+                    self.slave_client.map(|client| client.write_expected());
+                }
+            }
+        }
+
+        if sr1.is_set(SR1::TXE) && self.slave_status.get() == SlaveStatus::Transmitting {
+            // Once the buffer is sent, the master gets 0xFF until it stops
+            // reading.
+            let position = self.slave_position.get();
+            let byte = if position < self.slave_read_len.get() {
+                self.slave_read_buffer.map_or(0xFF, |buf| buf[position])
+            } else {
+                0xFF
+            };
+            self.registers.dr.write(DR::DR.val(byte as u32));
+            self.slave_position.set(position + 1);
+        }
+
+        if sr1.is_set(SR1::STOPF) {
+            // STOPF is cleared by reading SR1, done above, then writing CR1.
+            self.registers.cr1.modify(CR1::PE::SET);
+            match self.slave_status.get() {
+                SlaveStatus::Receiving => self.slave_done(self.slave_position.get()),
+                SlaveStatus::Transmitting => self.slave_done(self.slave_sent(sr1)),
+                SlaveStatus::Idle => {}
+            }
+        }
+    }
+
+    pub fn handle_error(&self) {
+        let sr1 = self.registers.sr1.extract();
+
+        // The error flags are cleared by writing 0, writing 1 has no effect.
+        self.registers.sr1.modify(
+            SR1::TIMEOUT::CLEAR
+                + SR1::PECERR::CLEAR
+                + SR1::OVR::CLEAR
+                + SR1::AF::CLEAR
+                + SR1::ARLO::CLEAR
+                + SR1::BERR::CLEAR,
+        );
+
+        if self.status.get() == I2CStatus::Idle {
+            // A master ends its read from us by not acknowledging the last
+            // byte. On other errors the transfer ends with what got through.
+            match self.slave_status.get() {
+                SlaveStatus::Receiving => self.slave_done(self.slave_position.get()),
+                SlaveStatus::Transmitting => self.slave_done(self.slave_sent(sr1)),
+                SlaveStatus::Idle => {}
+            }
+            return;
+        }
+
+        let error = decode_error(sr1, self.address_acked.get());
+        // The bus is released by the hardware when arbitration is lost,
+        // otherwise we still own it and release it with a stop condition.
+        if error != Error::ArbitrationLost {
+            self.registers.cr1.modify(CR1::STOP::SET);
+        }
         self.stop();
+
+        self.master_client.map(|client| {
+            self.buffer
+                .take()
+                .map(|buf| client.command_complete(buf, Err(error)))
+        });
     }
 
     fn reset(&self) {
@@ -377,8 +715,42 @@ impl<'a> I2C<'a> {
         self.enable();
     }
 
+    /// Sends a stop condition to release the bus, then resets the
+    /// peripheral and restores its configuration.
+    fn recover_bus(&self) {
+        self.registers.cr1.modify(CR1::STOP::SET);
+
+        let freq = self.registers.cr2.read(CR2::FREQ);
+        let oar1 = self.registers.oar1.get();
+        let ccr = self.registers.ccr.get();
+        let trise = self.registers.trise.get();
+        self.registers.cr1.modify(CR1::SWRST::SET);
+        self.registers.cr1.modify(CR1::SWRST::CLEAR);
+        self.registers.cr2.write(CR2::FREQ.val(freq));
+        self.registers.oar1.set(oar1);
+        self.registers.ccr.set(ccr);
+        self.registers.trise.set(trise);
+
+        self.stop();
+        self.registers.cr1.modify(CR1::PE::SET);
+        if self.slave_listening.get() {
+            self.registers.cr1.modify(CR1::ACK::SET);
+            self.resume_slave();
+        }
+    }
+
+    fn arm_timeout(&self) {
+        self.timeout_alarm.map(|alarm| {
+            alarm.set_alarm(
+                alarm.now(),
+                alarm.ticks_from_ms(self.transfer_timeout_ms.get()),
+            );
+        });
+    }
+
     fn start_write(&self) {
         self.tx_position.set(0);
+        self.address_acked.set(false);
         self.registers
             .cr2
             .modify(CR2::ITEVTEN::SET + CR2::ITERREN::SET + CR2::ITBUFEN::SET);
@@ -390,15 +762,71 @@ impl<'a> I2C<'a> {
         self.registers
             .cr2
             .modify(CR2::ITEVTEN::CLEAR + CR2::ITERREN::CLEAR + CR2::ITBUFEN::CLEAR);
-        self.registers.cr1.modify(CR1::ACK::CLEAR);
+        self.registers.cr1.modify(CR1::ACK::CLEAR + CR1::POS::CLEAR);
+        if self.status.get() != I2CStatus::Idle {
+            self.timeout_alarm.map(|alarm| alarm.disarm());
+        }
         self.status.set(I2CStatus::Idle);
     }
 
+    /// Bytes of the read buffer the master got. A byte still waiting in DR
+    /// was loaded but not sent.
+    fn slave_sent(&self, sr1: LocalRegisterCopy<u32, SR1::Register>) -> usize {
+        let loaded = self.slave_position.get();
+        let sent = if sr1.is_set(SR1::TXE) {
+            loaded
+        } else {
+            loaded.saturating_sub(1)
+        };
+        sent.min(self.slave_read_len.get())
+    }
+
+    /// Ends the slave transfer and hands its buffer back to the client.
+    fn slave_done(&self, length: usize) {
+        let (buffer, transmission_type) = match self.slave_status.get() {
+            SlaveStatus::Receiving => (&self.slave_write_buffer, SlaveTransmissionType::Write),
+            SlaveStatus::Transmitting => (&self.slave_read_buffer, SlaveTransmissionType::Read),
+            SlaveStatus::Idle => return,
+        };
+        self.slave_status.set(SlaveStatus::Idle);
+        self.slave_position.set(0);
+        self.slave_client.map(|client| {
+            buffer
+                .take()
+                .map(|buf| client.command_complete(buf, length, transmission_type))
+        });
+    }
+
+    /// Stops serving events, which stretches the clock, until a buffer is
+    /// provided.
+    fn pause_slave(&self) {
+        self.registers
+            .cr2
+            .modify(CR2::ITEVTEN::CLEAR + CR2::ITBUFEN::CLEAR);
+    }
+
+    fn resume_slave(&self) {
+        if self.slave_listening.get() {
+            self.registers
+                .cr2
+                .modify(CR2::ITEVTEN::SET + CR2::ITERREN::SET + CR2::ITBUFEN::SET);
+        }
+    }
+
+    /// Whether master transfers can start: they reset the peripheral, which
+    /// would cut a slave transfer short.
+    fn is_idle(&self) -> bool {
+        self.status.get() == I2CStatus::Idle && self.slave_status.get() == SlaveStatus::Idle
+    }
+
     fn start_read(&self) {
         self.rx_position.set(0);
+        self.address_acked.set(false);
+        // The end of a read of two or three bytes is driven by BTF alone.
+        let buffer_events = !matches!(self.rx_len.get(), 2 | 3);
         self.registers
             .cr2
-            .modify(CR2::ITEVTEN::SET + CR2::ITERREN::SET + CR2::ITBUFEN::SET);
+            .modify(CR2::ITEVTEN::SET + CR2::ITERREN::SET + CR2::ITBUFEN.val(buffer_events as u32));
         self.registers.cr1.modify(CR1::ACK::SET);
         self.registers.cr1.modify(CR1::START::SET);
     }
@@ -421,50 +849,127 @@ impl<'a> i2c::I2CMaster<'a> for I2C<'a> {
         write_len: usize,
         read_len: usize,
     ) -> Result<(), (Error, &'static mut [u8])> {
-        if self.status.get() == I2CStatus::Idle {
+        self.master_write_read(addr as u16, false, data, write_len, read_len)
+    }
+    fn write(
+        &self,
+        addr: u8,
+        data: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        self.master_write(addr as u16, false, data, len)
+    }
+    fn read(
+        &self,
+        addr: u8,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        self.master_read(addr as u16, false, buffer, len)
+    }
+}
+
+impl<'a> I2CMaster10Bit<'a> for I2C<'a> {
+    fn write_read_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if addr > 0x3FF {
+            return Err((Error::NotSupported, data));
+        }
+        self.master_write_read(addr, true, data, write_len, read_len)
+    }
+    fn write_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if addr > 0x3FF {
+            return Err((Error::NotSupported, data));
+        }
+        self.master_write(addr, true, data, len)
+    }
+    fn read_10bit(
+        &self,
+        addr: u16,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if addr > 0x3FF {
+            return Err((Error::NotSupported, buffer));
+        }
+        self.master_read(addr, true, buffer, len)
+    }
+}
+
+impl I2C<'_> {
+    fn set_target(&self, addr: u16, ten_bit: bool) {
+        self.slave_address.set(addr);
+        self.ten_bit_address.set(ten_bit);
+        self.ten_bit_addressed.set(false);
+    }
+
+    fn master_write_read(
+        &self,
+        addr: u16,
+        ten_bit: bool,
+        data: &'static mut [u8],
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.is_idle() {
             self.reset();
             self.status.set(I2CStatus::WritingReading);
-            self.slave_address.set(addr);
+            self.set_target(addr, ten_bit);
             self.buffer.replace(data);
             self.tx_len.set(write_len);
             self.rx_len.set(read_len);
             self.start_write();
+            self.arm_timeout();
             Ok(())
         } else {
             Err((Error::Busy, data))
         }
     }
-    fn write(
+    fn master_write(
         &self,
-        addr: u8,
+        addr: u16,
+        ten_bit: bool,
         data: &'static mut [u8],
         len: usize,
     ) -> Result<(), (Error, &'static mut [u8])> {
-        if self.status.get() == I2CStatus::Idle {
+        if self.is_idle() {
             self.reset();
             self.status.set(I2CStatus::Writing);
-            self.slave_address.set(addr);
+            self.set_target(addr, ten_bit);
             self.buffer.replace(data);
             self.tx_len.set(len);
             self.start_write();
+            self.arm_timeout();
             Ok(())
         } else {
             Err((Error::Busy, data))
         }
     }
-    fn read(
+    fn master_read(
         &self,
-        addr: u8,
+        addr: u16,
+        ten_bit: bool,
         buffer: &'static mut [u8],
         len: usize,
     ) -> Result<(), (Error, &'static mut [u8])> {
-        if self.status.get() == I2CStatus::Idle {
+        if self.is_idle() {
             self.reset();
             self.status.set(I2CStatus::Reading);
-            self.slave_address.set(addr);
+            self.set_target(addr, ten_bit);
             self.buffer.replace(buffer);
             self.rx_len.set(len);
             self.start_read();
+            self.arm_timeout();
             Ok(())
         } else {
             Err((Error::ArbitrationLost, buffer))
@@ -472,6 +977,111 @@ impl<'a> i2c::I2CMaster<'a> for I2C<'a> {
     }
 }
 
+impl<'a> i2c::I2CSlave<'a> for I2C<'a> {
+    fn set_slave_client(&self, slave_client: &'a dyn I2CHwSlaveClient) {
+        self.slave_client.replace(slave_client);
+    }
+    fn enable(&self) {
+        self.registers.cr1.modify(CR1::PE::SET);
+    }
+    fn disable(&self) {
+        self.slave_listening.set(false);
+        self.slave_status.set(SlaveStatus::Idle);
+        self.stop();
+        self.registers.cr1.modify(CR1::PE::CLEAR);
+    }
+    fn set_address(&self, addr: u8) -> Result<(), Error> {
+        if addr > 0x7F {
+            return Err(Error::NotSupported);
+        }
+        self.registers
+            .oar1
+            .write(OAR1::ADDMODE::CLEAR + OAR1::BIT14::SET + OAR1::ADD.val((addr as u32) << 1));
+        Ok(())
+    }
+    fn write_receive(
+        &self,
+        data: &'static mut [u8],
+        max_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.slave_write_buffer.is_some() {
+            return Err((Error::Busy, data));
+        }
+        self.slave_write_len.set(max_len.min(data.len()));
+        self.slave_write_buffer.replace(data);
+        self.resume_slave();
+        Ok(())
+    }
+    fn read_send(
+        &self,
+        data: &'static mut [u8],
+        max_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.slave_read_buffer.is_some() {
+            return Err((Error::Busy, data));
+        }
+        self.slave_read_len.set(max_len.min(data.len()));
+        self.slave_read_buffer.replace(data);
+        self.resume_slave();
+        Ok(())
+    }
+    fn listen(&self) {
+        self.slave_listening.set(true);
+        self.registers.cr1.modify(CR1::ACK::SET);
+        self.resume_slave();
+    }
+}
+
+impl<'a> i2c::I2CMasterSlave<'a> for I2C<'a> {}
+
+impl AlarmClient for I2C<'_> {
+    fn alarm(&self) {
+        // The transfer may have completed right as the alarm fired.
+        if self.status.get() == I2CStatus::Idle {
+            return;
+        }
+        self.recover_bus();
+        self.master_client.map(|client| {
+            self.buffer
+                .take()
+                .map(|buf| client.command_complete(buf, Err(Error::Timeout)))
+        });
+    }
+}
+
+/// The first byte sent to address a device at the 10-bit `address`, for a
+/// write: `11110`, then the two high bits of the address.
+fn ten_bit_header(address: u32) -> u32 {
+    0xF0 | ((address >> 7) & 0x06)
+}
+
+/// The error reported by the error flags of `sr1`. `address_acked` tells
+/// whether the slave acknowledged its address, so that an acknowledge failure
+/// can be reported as a NAK of the address or of the data.
+fn decode_error(sr1: LocalRegisterCopy<u32, SR1::Register>, address_acked: bool) -> Error {
+    if sr1.is_set(SR1::ARLO) {
+        Error::ArbitrationLost
+    } else if sr1.is_set(SR1::BERR) {
+        // A misplaced start or stop condition, most likely from another
+        // master: like a lost arbitration, the hardware released the bus.
+        Error::ArbitrationLost
+    } else if sr1.is_set(SR1::AF) {
+        if address_acked {
+            Error::DataNak
+        } else {
+            Error::AddressNak
+        }
+    } else if sr1.is_set(SR1::OVR) {
+        Error::Overrun
+    } else if sr1.is_set(SR1::TIMEOUT) {
+        // A slave held the clock low for too long.
+        Error::Timeout
+    } else {
+        // PECERR means the data did not get through.
+        Error::DataNak
+    }
+}
+
 struct I2CClock<'a>(rcc::PeripheralClock<'a>);
 
 impl ClockInterface for I2CClock<'_> {
@@ -487,3 +1097,518 @@ impl ClockInterface for I2CClock<'_> {
         self.0.disable();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::{decode_error, I2CClock, I2CRegisters, CR1, CR2, DR, I2C, SR1, SR2};
+    use crate::exti::Exti;
+    use crate::gpio::{Pin, PinId};
+    use crate::rcc;
+    use crate::syscfg::Syscfg;
+    use kernel::hil::i2c::{
+        Error, I2CHwMasterClient, I2CHwSlaveClient, I2CMaster, I2CMaster10Bit, I2CSlave,
+        SlaveTransmissionType,
+    };
+    use kernel::hil::time::{Alarm, AlarmClient, Freq16KHz, Ticks, Ticks32, Time};
+    use kernel::utilities::registers::interfaces::{Readable, Writeable};
+    use kernel::utilities::registers::LocalRegisterCopy;
+    use kernel::utilities::StaticRef;
+    use kernel::ErrorCode;
+
+    /// An alarm the test fires by hand.
+    #[derive(Default)]
+    struct FakeAlarm {
+        armed: Cell<Option<u32>>,
+    }
+
+    impl Time for FakeAlarm {
+        type Frequency = Freq16KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            0.into()
+        }
+    }
+
+    impl<'a> Alarm<'a> for FakeAlarm {
+        fn set_alarm_client(&self, _client: &'a dyn AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, dt: Ticks32) {
+            self.armed.set(Some(dt.into_u32()));
+        }
+
+        fn get_alarm(&self) -> Ticks32 {
+            self.armed.get().unwrap_or(0).into()
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(None);
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get().is_some()
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            1.into()
+        }
+    }
+
+    #[derive(Default)]
+    struct Client {
+        completed: Cell<Option<Result<(), Error>>>,
+        data: Cell<Option<&'static mut [u8]>>,
+    }
+
+    impl I2CHwMasterClient for Client {
+        fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), Error>) {
+            self.completed.set(Some(status));
+            self.data.set(Some(buffer));
+        }
+    }
+
+    /// An I2C over registers in memory, timed by a fake alarm.
+    fn setup() -> (&'static I2C<'static>, &'static FakeAlarm, &'static Client) {
+        let registers: &'static I2CRegisters = Box::leak(Box::new(unsafe { core::mem::zeroed() }));
+        let rcc: &'static rcc::Rcc = Box::leak(Box::new(rcc::Rcc::new_in_memory()));
+        let i2c: &'static I2C = Box::leak(Box::new(I2C::new(
+            unsafe { StaticRef::new(registers) },
+            I2CClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::I2C1),
+                rcc,
+            )),
+        )));
+        let alarm: &'static FakeAlarm = Box::leak(Box::default());
+        let client: &'static Client = Box::leak(Box::default());
+        i2c.set_timeout_alarm(alarm);
+        i2c.set_master_client(client);
+        registers.cr2.write(CR2::FREQ.val(16));
+        registers.ccr.set(80);
+        (i2c, alarm, client)
+    }
+
+    #[test]
+    fn stuck_transfer_times_out() {
+        let (i2c, alarm, client) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.write_read(0x40, buffer, 1, 2).is_ok());
+        assert_eq!(alarm.armed.get(), Some(1_600));
+
+        // The bus is stuck: no event comes until the alarm fires.
+        i2c.alarm();
+        assert_eq!(client.completed.take(), Some(Err(Error::Timeout)));
+        // The bus was released and the configuration kept.
+        let cr1 = i2c.registers.cr1.extract();
+        assert!(cr1.is_set(CR1::STOP));
+        assert!(cr1.is_set(CR1::PE));
+        assert!(!cr1.is_set(CR1::SWRST));
+        assert_eq!(i2c.registers.cr2.get(), 16);
+        assert_eq!(i2c.registers.ccr.get(), 80);
+
+        // The buffer came back and the next transfer can start.
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read(0x40, buffer, 2).is_ok());
+    }
+
+    #[test]
+    fn bus_recovery_needs_the_pins_and_an_idle_bus() {
+        let (i2c, _, _) = setup();
+
+        assert_eq!(i2c.bus_recover(), Err(ErrorCode::NOSUPPORT));
+
+        // The pins are not touched while a transfer is in progress.
+        let rcc: &'static rcc::Rcc = Box::leak(Box::new(rcc::Rcc::new_in_memory()));
+        let syscfg: &'static Syscfg = Box::leak(Box::new(Syscfg::new(rcc)));
+        let exti: &'static Exti = Box::leak(Box::new(Exti::new(syscfg)));
+        let scl: &'static Pin = Box::leak(Box::new(Pin::new(PinId::PB08, exti)));
+        let sda: &'static Pin = Box::leak(Box::new(Pin::new(PinId::PB09, exti)));
+        i2c.set_bus_pins(scl, sda);
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read(0x40, buffer, 2).is_ok());
+        assert_eq!(i2c.bus_recover(), Err(ErrorCode::BUSY));
+    }
+
+    #[test]
+    fn transfer_timeout_is_configurable() {
+        let (i2c, alarm, client) = setup();
+
+        i2c.set_transfer_timeout(500);
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read(0x40, buffer, 2).is_ok());
+        assert_eq!(alarm.armed.get(), Some(8_000));
+        i2c.alarm();
+        assert_eq!(client.completed.take(), Some(Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn completed_transfer_cancels_the_timeout() {
+        let (i2c, alarm, client) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.write(0x40, buffer, 0).is_ok());
+        assert!(alarm.is_armed());
+
+        // Nothing left to send once the address is sent.
+        i2c.registers.sr2.write(SR2::MSL::SET);
+        i2c.registers.sr1.write(SR1::BTF::SET);
+        i2c.handle_event();
+        assert_eq!(client.completed.take(), Some(Ok(())));
+        assert!(!alarm.is_armed());
+
+        // An alarm firing late leaves the idle peripheral alone.
+        i2c.registers.cr1.set(0);
+        i2c.alarm();
+        assert_eq!(client.completed.take(), None);
+        assert_eq!(i2c.registers.cr1.get(), 0);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        const AF: u32 = 1 << 10;
+        const ARLO: u32 = 1 << 9;
+        const BERR: u32 = 1 << 8;
+        const OVR: u32 = 1 << 11;
+        const TIMEOUT: u32 = 1 << 14;
+        const TEST_VECTORS: [(u32, bool, Error); 11] = [
+            (AF, false, Error::AddressNak),
+            (AF, true, Error::DataNak),
+            (ARLO, false, Error::ArbitrationLost),
+            (ARLO | AF, true, Error::ArbitrationLost),
+            (BERR, true, Error::ArbitrationLost),
+            (OVR, true, Error::Overrun),
+            (OVR | AF, false, Error::AddressNak),
+            (TIMEOUT, false, Error::Timeout),
+            (TIMEOUT | AF, true, Error::DataNak),
+            // Event flags set alongside the error are ignored.
+            (AF | 0x86, true, Error::DataNak),
+            (0, true, Error::DataNak),
+        ];
+        for (sr1, address_acked, expected) in &TEST_VECTORS {
+            let sr1 = LocalRegisterCopy::<u32, SR1::Register>::new(*sr1);
+            assert_eq!(decode_error(sr1, *address_acked), *expected);
+        }
+    }
+
+    /// Raises the `sr1` events and returns what was written to DR.
+    fn event(i2c: &I2C, sr1: u32) -> u32 {
+        i2c.registers.dr.set(0);
+        i2c.registers.sr2.write(SR2::MSL::SET);
+        i2c.registers.sr1.set(sr1);
+        i2c.handle_event();
+        i2c.registers.dr.read(DR::DR)
+    }
+
+    const SB: u32 = 1 << 0;
+    const ADDR: u32 = 1 << 1;
+    const ADD10: u32 = 1 << 3;
+    const BTF: u32 = 1 << 2;
+    const RXNE: u32 = 1 << 6;
+    const TXE: u32 = 1 << 7;
+
+    #[test]
+    fn seven_bit_address() {
+        let (i2c, _, _) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read(0x42, buffer, 2).is_ok());
+        assert_eq!(event(i2c, SB), 0x85);
+    }
+
+    #[test]
+    fn ten_bit_write() {
+        let (i2c, _, _) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0x5A; 4]);
+        assert!(i2c.write_10bit(0x2A5, buffer, 1).is_ok());
+        // The header with the two high bits of the address, then the low
+        // byte, then the data.
+        assert_eq!(event(i2c, SB), 0xF4);
+        assert_eq!(event(i2c, ADD10), 0xA5);
+        assert_eq!(event(i2c, ADDR | TXE), 0x5A);
+        assert!(i2c.address_acked.get());
+    }
+
+    #[test]
+    fn ten_bit_read() {
+        let (i2c, _, _) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read_10bit(0x2A5, buffer, 2).is_ok());
+        // The device is addressed for a write first.
+        assert_eq!(event(i2c, SB), 0xF4);
+        assert_eq!(event(i2c, ADD10), 0xA5);
+        i2c.registers.cr1.set(0);
+        assert_eq!(event(i2c, ADDR | TXE), 0);
+        assert!(i2c.registers.cr1.is_set(CR1::START));
+        // Then the header alone turns it into a read.
+        assert_eq!(event(i2c, SB), 0xF5);
+    }
+
+    #[test]
+    fn ten_bit_read_to_completion() {
+        let (i2c, _, client) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(i2c.read_10bit(0x2A5, buffer, 2).is_ok());
+        assert_eq!(event(i2c, SB), 0xF4);
+        assert_eq!(event(i2c, ADD10), 0xA5);
+        i2c.registers.cr1.set(0);
+        event(i2c, ADDR | TXE);
+        assert!(i2c.registers.cr1.is_set(CR1::START));
+        assert_eq!(event(i2c, SB), 0xF5);
+
+        // From the header on, the read goes as with a 7-bit address.
+        let cr1 = i2c.registers.cr1.extract();
+        assert!(!cr1.is_set(CR1::ACK));
+        assert!(cr1.is_set(CR1::POS));
+        assert!(!receive_event(i2c, ADDR, 0).is_set(CR1::STOP));
+        assert!(!receive_event(i2c, RXNE, 0x21).is_set(CR1::STOP));
+        assert_eq!(client.completed.get(), None);
+        assert!(receive_event(i2c, RXNE | BTF, 0x22).is_set(CR1::STOP));
+        assert_eq!(client.completed.take(), Some(Ok(())));
+        assert!(i2c.address_acked.get());
+    }
+
+    #[test]
+    fn ten_bit_write_read_restarts_with_the_header() {
+        let (i2c, _, _) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0x11; 4]);
+        assert!(i2c.write_read_10bit(0x0FF, buffer, 1, 1).is_ok());
+        assert_eq!(event(i2c, SB), 0xF0);
+        assert_eq!(event(i2c, ADD10), 0xFF);
+        assert_eq!(event(i2c, ADDR | TXE), 0x11);
+        // Nothing is left to write, the read starts.
+        event(i2c, BTF);
+        assert_eq!(event(i2c, SB), 0xF1);
+    }
+
+    #[test]
+    fn ten_bit_address_out_of_range() {
+        let (i2c, _, _) = setup();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 4]);
+        assert!(matches!(
+            i2c.write_10bit(0x400, buffer, 2),
+            Err((Error::NotSupported, _))
+        ));
+    }
+
+    /// Delivers `sr1` events of a read with `dr` holding the byte received,
+    /// and returns CR1 afterwards.
+    fn receive_event(i2c: &I2C, sr1: u32, dr: u8) -> LocalRegisterCopy<u32, CR1::Register> {
+        i2c.registers.dr.set(dr as u32);
+        i2c.registers.sr2.write(SR2::MSL::SET);
+        i2c.registers.sr1.set(sr1);
+        i2c.handle_event();
+        i2c.registers.cr1.extract()
+    }
+
+    /// Starts a read of `len` bytes and sends the address.
+    fn start_reading(len: usize) -> (&'static I2C<'static>, &'static Client) {
+        let (i2c, _, client) = setup();
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 8]);
+        assert!(i2c.read(0x40, buffer, len).is_ok());
+        assert_eq!(event(i2c, SB), 0x81);
+        (i2c, client)
+    }
+
+    #[test]
+    fn read_one_byte() {
+        let (i2c, client) = start_reading(1);
+
+        // NACKed from the start, and stopped once addressed.
+        let cr1 = i2c.registers.cr1.extract();
+        assert!(!cr1.is_set(CR1::ACK));
+        assert!(!cr1.is_set(CR1::STOP));
+        assert!(receive_event(i2c, ADDR, 0).is_set(CR1::STOP));
+        assert!(i2c.registers.cr2.is_set(CR2::ITBUFEN));
+
+        receive_event(i2c, RXNE, 0x11);
+        assert_eq!(client.completed.take(), Some(Ok(())));
+        assert_eq!(client.data.take().unwrap()[0], 0x11);
+    }
+
+    #[test]
+    fn read_two_bytes() {
+        let (i2c, client) = start_reading(2);
+
+        // The second byte is NACKed, and both wait for BTF.
+        let cr1 = i2c.registers.cr1.extract();
+        assert!(!cr1.is_set(CR1::ACK));
+        assert!(cr1.is_set(CR1::POS));
+        assert!(!i2c.registers.cr2.is_set(CR2::ITBUFEN));
+        assert!(!receive_event(i2c, ADDR, 0).is_set(CR1::STOP));
+        assert!(!receive_event(i2c, RXNE, 0x21).is_set(CR1::STOP));
+        assert_eq!(client.completed.get(), None);
+
+        let cr1 = receive_event(i2c, RXNE | BTF, 0x22);
+        assert!(cr1.is_set(CR1::STOP));
+        assert!(!cr1.is_set(CR1::POS));
+        assert_eq!(client.completed.take(), Some(Ok(())));
+        assert_eq!(client.data.take().unwrap()[..2], [0x22, 0x22]);
+    }
+
+    #[test]
+    fn read_five_bytes() {
+        let (i2c, client) = start_reading(5);
+
+        assert!(i2c.registers.cr1.is_set(CR1::ACK));
+        assert!(i2c.registers.cr2.is_set(CR2::ITBUFEN));
+        receive_event(i2c, ADDR, 0);
+        receive_event(i2c, RXNE, 0x51);
+        // Three bytes are left: the end waits for BTF.
+        receive_event(i2c, RXNE, 0x52);
+        assert!(!i2c.registers.cr2.is_set(CR2::ITBUFEN));
+        let cr1 = receive_event(i2c, RXNE, 0x53);
+        assert!(cr1.is_set(CR1::ACK));
+        assert!(!cr1.is_set(CR1::STOP));
+
+        // The third and second to last bytes are read, the last one NACKed
+        // and followed by a stop.
+        let cr1 = receive_event(i2c, RXNE | BTF, 0x53);
+        assert!(!cr1.is_set(CR1::ACK));
+        assert!(cr1.is_set(CR1::STOP));
+        assert!(i2c.registers.cr2.is_set(CR2::ITBUFEN));
+        assert_eq!(client.completed.get(), None);
+
+        receive_event(i2c, RXNE, 0x55);
+        assert_eq!(client.completed.take(), Some(Ok(())));
+        assert_eq!(
+            client.data.take().unwrap()[..5],
+            [0x51, 0x52, 0x53, 0x53, 0x55]
+        );
+    }
+
+    #[derive(Default)]
+    struct SlaveClient {
+        completed: Cell<Option<(usize, SlaveTransmissionType)>>,
+        data: Cell<Option<&'static mut [u8]>>,
+        read_expected: Cell<bool>,
+        write_expected: Cell<bool>,
+    }
+
+    impl I2CHwSlaveClient for SlaveClient {
+        fn command_complete(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            transmission_type: SlaveTransmissionType,
+        ) {
+            self.completed.set(Some((length, transmission_type)));
+            self.data.set(Some(buffer));
+        }
+
+        fn read_expected(&self) {
+            self.read_expected.set(true);
+        }
+
+        fn write_expected(&self) {
+            self.write_expected.set(true);
+        }
+    }
+
+    fn setup_slave() -> (&'static I2C<'static>, &'static SlaveClient) {
+        let (i2c, _, _) = setup();
+        let client: &'static SlaveClient = Box::leak(Box::default());
+        i2c.set_slave_client(client);
+        assert!(i2c.set_address(0x42).is_ok());
+        i2c.listen();
+        (i2c, client)
+    }
+
+    /// Delivers an event while addressed as a slave, with `dr` holding the
+    /// byte received, and returns what was loaded into DR.
+    fn slave_event(i2c: &I2C, sr1: u32, sr2: u32, dr: u8) -> u32 {
+        i2c.registers.dr.set(dr as u32);
+        i2c.registers.sr2.set(sr2);
+        i2c.registers.sr1.set(sr1);
+        i2c.handle_event();
+        i2c.registers.dr.read(DR::DR)
+    }
+
+    const STOPF: u32 = 1 << 4;
+    const TRA: u32 = 1 << 2;
+
+    #[test]
+    fn slave_address() {
+        let (i2c, _) = setup_slave();
+
+        // 7-bit mode, the address in ADD[7:1] and bit 14 kept set.
+        assert_eq!(i2c.registers.oar1.get(), (1 << 14) | (0x42 << 1));
+        assert!(i2c.registers.cr1.is_set(CR1::ACK));
+        assert!(matches!(i2c.set_address(0x80), Err(Error::NotSupported)));
+    }
+
+    #[test]
+    fn slave_receives_a_write() {
+        let (i2c, client) = setup_slave();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 2]);
+        assert!(i2c.write_receive(buffer, 2).is_ok());
+        slave_event(i2c, ADDR, 0, 0);
+        slave_event(i2c, RXNE, 0, 0xA1);
+        slave_event(i2c, RXNE, 0, 0xA2);
+        // The third byte does not fit and is dropped.
+        slave_event(i2c, RXNE, 0, 0xA3);
+        assert!(client.completed.get().is_none());
+
+        slave_event(i2c, STOPF, 0, 0);
+        assert!(matches!(
+            client.completed.take(),
+            Some((2, SlaveTransmissionType::Write))
+        ));
+        assert_eq!(client.data.take().unwrap(), &[0xA1, 0xA2]);
+        // A master transfer can start again.
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 2]);
+        assert!(i2c.write(0x40, buffer, 1).is_ok());
+    }
+
+    #[test]
+    fn slave_sends_a_read() {
+        let (i2c, client) = setup_slave();
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0xB1, 0xB2]);
+        assert!(i2c.read_send(buffer, 2).is_ok());
+        assert_eq!(slave_event(i2c, ADDR | TXE, TRA, 0), 0xB1);
+        assert_eq!(slave_event(i2c, TXE, TRA, 0), 0xB2);
+        // Past the end of the buffer the master reads 0xFF.
+        assert_eq!(slave_event(i2c, TXE, TRA, 0), 0xFF);
+
+        slave_event(i2c, STOPF, 0, 0);
+        assert!(matches!(
+            client.completed.take(),
+            Some((2, SlaveTransmissionType::Read))
+        ));
+    }
+
+    #[test]
+    fn slave_stretches_the_clock_without_a_buffer() {
+        let (i2c, client) = setup_slave();
+
+        slave_event(i2c, ADDR, 0, 0);
+        assert!(client.write_expected.get());
+        assert!(!i2c.registers.cr2.is_set(CR2::ITEVTEN));
+        // A master transfer would cut the slave transfer short.
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 2]);
+        assert!(matches!(i2c.write(0x40, buffer, 1), Err((Error::Busy, _))));
+
+        let buffer: &'static mut [u8] = Vec::leak(std::vec![0; 2]);
+        assert!(i2c.write_receive(buffer, 2).is_ok());
+        assert!(i2c.registers.cr2.is_set(CR2::ITEVTEN));
+        slave_event(i2c, RXNE, 0, 0xC1);
+        slave_event(i2c, STOPF, 0, 0);
+        assert!(matches!(
+            client.completed.take(),
+            Some((1, SlaveTransmissionType::Write))
+        ));
+    }
+}
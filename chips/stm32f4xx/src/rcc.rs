@@ -737,6 +737,20 @@ pub struct Rcc {
     registers: StaticRef<RccRegisters>,
 }
 
+#[cfg(test)]
+impl Rcc {
+    /// An `Rcc` over zeroed registers in memory, for unit tests of the
+    /// peripherals.
+    pub(crate) fn new_in_memory() -> Self {
+        extern crate std;
+        let registers: &'static RccRegisters =
+            std::boxed::Box::leak(std::boxed::Box::new(unsafe { core::mem::zeroed() }));
+        Self {
+            registers: unsafe { StaticRef::new(registers) },
+        }
+    }
+}
+
 pub enum RtcClockSource {
     LSI,
     LSE,
@@ -1075,6 +1089,38 @@ impl Rcc {
         self.registers.apb1enr.modify(APB1ENR::I2C1EN::CLEAR)
     }
 
+    // I2C2 clock
+
+    fn is_enabled_i2c2_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::I2C2EN)
+    }
+
+    fn enable_i2c2_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::I2C2EN::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::I2C2RST::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::I2C2RST::CLEAR);
+    }
+
+    fn disable_i2c2_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::I2C2EN::CLEAR)
+    }
+
+    // I2C3 clock
+
+    fn is_enabled_i2c3_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::I2C3EN)
+    }
+
+    fn enable_i2c3_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::I2C3EN::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::I2C3RST::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::I2C3RST::CLEAR);
+    }
+
+    fn disable_i2c3_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::I2C3EN::CLEAR)
+    }
+
     // SPI3 clock
 
     fn is_enabled_spi3_clock(&self) -> bool {
@@ -1608,6 +1654,8 @@ pub enum PCLK1 {
     USART3,
     SPI3,
     I2C1,
+    I2C2,
+    I2C3,
     CAN1,
     DAC,
 }
@@ -1700,6 +1748,8 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::USART2 => self.rcc.is_enabled_usart2_clock(),
                 PCLK1::USART3 => self.rcc.is_enabled_usart3_clock(),
                 PCLK1::I2C1 => self.rcc.is_enabled_i2c1_clock(),
+                PCLK1::I2C2 => self.rcc.is_enabled_i2c2_clock(),
+                PCLK1::I2C3 => self.rcc.is_enabled_i2c3_clock(),
                 PCLK1::SPI3 => self.rcc.is_enabled_spi3_clock(),
                 PCLK1::CAN1 => self.rcc.is_enabled_can1_clock(),
                 PCLK1::DAC => self.rcc.is_enabled_dac_clock(),
@@ -1772,6 +1822,12 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::I2C1 => {
                     self.rcc.enable_i2c1_clock();
                 }
+                PCLK1::I2C2 => {
+                    self.rcc.enable_i2c2_clock();
+                }
+                PCLK1::I2C3 => {
+                    self.rcc.enable_i2c3_clock();
+                }
                 PCLK1::SPI3 => {
                     self.rcc.enable_spi3_clock();
                 }
@@ -1856,6 +1912,12 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::I2C1 => {
                     self.rcc.disable_i2c1_clock();
                 }
+                PCLK1::I2C2 => {
+                    self.rcc.disable_i2c2_clock();
+                }
+                PCLK1::I2C3 => {
+                    self.rcc.disable_i2c3_clock();
+                }
                 PCLK1::SPI3 => {
                     self.rcc.disable_spi3_clock();
                 }
@@ -3,6 +3,11 @@
 // Copyright Tock Contributors 2022.
 
 //! Internal Timer
+//!
+//! The counter of an internal timer restarts from 0 once it reaches the
+//! bound. When a timer is used as the `SchedulerTimer`, the trap handler has
+//! to call `Timer::stop_in_trap()` when its interrupt is taken, so that the
+//! kernel still sees the timeslice as expired.
 
 use kernel::hil::time;
 use kernel::hil::time::{Alarm, ConvertTicks, Counter, Ticks, Ticks32, Time};
@@ -68,6 +73,17 @@ impl Timer<'_> {
         }
     }
 
+    /// Stop the counter of timer `number` from a trap handler, which has no
+    /// reference to the `Timer`.
+    pub fn stop_in_trap(number: TimerNumber) {
+        match number {
+            TimerNumber::ZERO => ReadWriteRiscvCsr::<usize, MITCTL::Register, 0x7D4>::new()
+                .modify(MITCTL::ENABLE::CLEAR),
+            TimerNumber::ONE => ReadWriteRiscvCsr::<usize, MITCTL::Register, 0x7D7>::new()
+                .modify(MITCTL::ENABLE::CLEAR),
+        }
+    }
+
     pub fn handle_interrupt(&self) {
         let _ = self.stop();
         self.alarm_client.map(|client| {
@@ -186,13 +202,29 @@ impl<'a> Alarm<'a> for Timer<'a> {
 
 impl kernel::platform::scheduler_timer::SchedulerTimer for Timer<'_> {
     fn start(&self, us: u32) {
-        let now = self.now();
-        let tics = self.ticks_from_us(us);
-        self.set_alarm(now, tics);
+        let _ = self.stop();
+
+        // 0xFFFF_FFFF is reserved to indicate disabled, don't set that value
+        let tics = self.ticks_from_us(us).into_u32().clamp(1, 0xFFFF_FFFE) as usize;
+
+        // Count from 0 up to the length of the timeslice
+        match self.number {
+            TimerNumber::ZERO => {
+                self.mitcnt0.write(MITCNT::COUNT.val(0));
+                self.mitb0.write(MITB::BOUND.val(tics));
+            }
+            TimerNumber::ONE => {
+                self.mitcnt1.write(MITCNT::COUNT.val(0));
+                self.mitb1.write(MITB::BOUND.val(tics));
+            }
+        }
+
+        let _ = Counter::start(self);
     }
 
     fn reset(&self) {
         let _ = self.stop();
+        let _ = Alarm::disarm(self);
     }
 
     fn arm(&self) {
@@ -205,11 +237,17 @@ impl kernel::platform::scheduler_timer::SchedulerTimer for Timer<'_> {
     }
 
     fn get_remaining_us(&self) -> Option<u32> {
+        // The trap handler stops the counter when the timer expires, it
+        // would otherwise have started over from 0.
+        if !self.is_running() {
+            return None;
+        }
+
         let alarm = self.get_alarm();
         let now = self.now();
 
         if alarm > now {
-            Some(alarm.wrapping_sub(now).into_u32())
+            Some(self.ticks_to_us(alarm.wrapping_sub(now)))
         } else {
             None
         }
@@ -38,6 +38,7 @@ pub struct SweRVolf<'a, I: InterruptService + 'a> {
 pub struct SweRVolfDefaultPeripherals<'a> {
     pub uart: crate::uart::Uart<'a>,
     pub timer1: swerv::eh1_timer::Timer<'a>,
+    pub gpio_port: [crate::gpio::GpioPin<'a>; crate::gpio::NUM_PINS],
 }
 
 impl<'a> SweRVolfDefaultPeripherals<'a> {
@@ -45,8 +46,15 @@ impl<'a> SweRVolfDefaultPeripherals<'a> {
         Self {
             uart: crate::uart::Uart::new(crate::uart::UART_BASE),
             timer1: swerv::eh1_timer::Timer::new(swerv::eh1_timer::TimerNumber::ONE),
+            gpio_port: core::array::from_fn(|pin| {
+                crate::gpio::GpioPin::new(crate::gpio::GPIO_BASE, pin)
+            }),
         }
     }
+
+    pub fn init(&'static self) {
+        kernel::deferred_call::DeferredCallClient::register(&self.uart);
+    }
 }
 
 impl<'a> InterruptService for SweRVolfDefaultPeripherals<'a> {
@@ -253,8 +261,10 @@ unsafe fn handle_interrupt(intr: mcause::Interrupt) {
 
         mcause::Interrupt::Unknown => {
             if CSR.mcause.get() == 0x8000_001D {
-                // Timer0
+                // Timer0, the scheduler timer. Its counter starts over
+                // from 0, stop it so that the timeslice stays expired.
                 CSR.mie.modify(mie::BIT29::CLEAR);
+                swerv::eh1_timer::Timer::stop_in_trap(swerv::eh1_timer::TimerNumber::ZERO);
                 TIMER0_IRQ.set(true);
                 return;
             } else if CSR.mcause.get() == 0x8000_001C {
@@ -0,0 +1,120 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! General Purpose Input/Output driver.
+//!
+//! The system controller of SweRVolf has 64 GPIO bits, which are always
+//! driven and read back. On the Nexys A7 board bits 0 to 15 drive the LEDs
+//! and bits 16 to 31 read the switches. The pins have no pulls and no
+//! interrupts.
+
+use kernel::hil;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::registers::interfaces::{Readable, Writeable};
+use kernel::utilities::registers::{register_structs, ReadWrite};
+use kernel::utilities::StaticRef;
+
+/// Number of GPIO bits.
+pub const NUM_PINS: usize = 64;
+
+register_structs! {
+    pub GpioRegisters {
+        /// 64 readable and writable GPIO bits
+        (0x000 => gpio: [ReadWrite<u32>; 2]),
+        (0x008 => @END),
+    }
+}
+
+pub const GPIO_BASE: StaticRef<GpioRegisters> =
+    unsafe { StaticRef::new(0x8000_1010 as *const GpioRegisters) };
+
+pub struct GpioPin<'a> {
+    registers: StaticRef<GpioRegisters>,
+    pin: usize,
+    client: OptionalCell<&'a dyn hil::gpio::Client>,
+}
+
+impl<'a> GpioPin<'a> {
+    pub const fn new(registers: StaticRef<GpioRegisters>, pin: usize) -> GpioPin<'a> {
+        GpioPin {
+            registers: registers,
+            pin: pin,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn word(&self) -> &ReadWrite<u32> {
+        &self.registers.gpio[self.pin / 32]
+    }
+
+    fn mask(&self) -> u32 {
+        1 << (self.pin % 32)
+    }
+}
+
+impl hil::gpio::Configure for GpioPin<'_> {
+    fn configuration(&self) -> hil::gpio::Configuration {
+        hil::gpio::Configuration::InputOutput
+    }
+
+    fn set_floating_state(&self, _mode: hil::gpio::FloatingState) {}
+
+    fn floating_state(&self) -> hil::gpio::FloatingState {
+        hil::gpio::FloatingState::PullNone
+    }
+
+    fn deactivate_to_low_power(&self) {}
+
+    fn make_output(&self) -> hil::gpio::Configuration {
+        hil::gpio::Configuration::InputOutput
+    }
+
+    fn disable_output(&self) -> hil::gpio::Configuration {
+        hil::gpio::Configuration::InputOutput
+    }
+
+    fn make_input(&self) -> hil::gpio::Configuration {
+        hil::gpio::Configuration::InputOutput
+    }
+
+    fn disable_input(&self) -> hil::gpio::Configuration {
+        hil::gpio::Configuration::InputOutput
+    }
+}
+
+impl hil::gpio::Input for GpioPin<'_> {
+    fn read(&self) -> bool {
+        self.word().get() & self.mask() != 0
+    }
+}
+
+impl hil::gpio::Output for GpioPin<'_> {
+    fn set(&self) {
+        self.word().set(self.word().get() | self.mask());
+    }
+
+    fn clear(&self) {
+        self.word().set(self.word().get() & !self.mask());
+    }
+
+    fn toggle(&self) -> bool {
+        let value = self.word().get() ^ self.mask();
+        self.word().set(value);
+        value & self.mask() != 0
+    }
+}
+
+impl<'a> hil::gpio::Interrupt<'a> for GpioPin<'a> {
+    fn set_client(&self, client: &'a dyn hil::gpio::Client) {
+        self.client.set(client);
+    }
+
+    fn enable_interrupts(&self, _mode: hil::gpio::InterruptEdge) {}
+
+    fn disable_interrupts(&self) {}
+
+    fn is_pending(&self) -> bool {
+        false
+    }
+}
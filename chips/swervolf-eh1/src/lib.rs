@@ -9,5 +9,6 @@
 #![crate_type = "rlib"]
 
 pub mod chip;
+pub mod gpio;
 pub mod syscon;
 pub mod uart;
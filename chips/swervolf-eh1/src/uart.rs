@@ -3,32 +3,53 @@
 // Copyright Tock Contributors 2022.
 
 //! ns16550 compatible UART driver.
+//!
+//! The transmitter holding register empty interrupt is always enabled, the
+//! received data available interrupt only while a receive buffer is held.
+//! Received bytes are read out of the FIFO when it reaches its trigger level
+//! or when the character timeout expires, until the expected length is
+//! reached.
 
 pub const UART_BASE: StaticRef<UartRegisters> =
     unsafe { StaticRef::new(0x8000_2000 as *const UartRegisters) };
 
 use core::cell::Cell;
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
-use kernel::utilities::registers::{register_bitfields, register_structs, ReadWrite};
+use kernel::utilities::registers::{register_bitfields, register_structs, Aliased, ReadWrite};
 use kernel::utilities::StaticRef;
 use kernel::ErrorCode;
 
 register_structs! {
     pub UartRegisters {
         (0x00 => brdl: ReadWrite<u32>),
-        (0x04 => ier: ReadWrite<u32>),
-        (0x08 => fcr: ReadWrite<u32, FCR::Register>),
+        (0x04 => ier: ReadWrite<u32, IER::Register>),
+        (0x08 => iir_fcr: Aliased<u32, IIR::Register, FCR::Register>),
         (0x0C => lcr: ReadWrite<u32>),
         (0x10 => _reserved0),
-        (0x14 => lsr: ReadWrite<u32>),
+        (0x14 => lsr: ReadWrite<u32, LSR::Register>),
         (0x18 => @END),
     }
 }
 
 register_bitfields![u32,
+    IER [
+        RECEIVED_DATA_AVAILABLE OFFSET(0) NUMBITS(1) [],
+        TX_HOLDING_REGISTER_EMPTY OFFSET(1) NUMBITS(1) [],
+    ],
+    IIR [
+        NOT_PENDING OFFSET(0) NUMBITS(1) [],
+        ID OFFSET(1) NUMBITS(3) [
+            ModemStatus = 0,
+            TxHoldingRegisterEmpty = 1,
+            ReceivedDataAvailable = 2,
+            ReceiverLineStatus = 3,
+            CharacterTimeout = 6,
+        ],
+    ],
     FCR [
         CLEAR_RX OFFSET(1) NUMBITS(1) [],
         CLEAR_TX OFFSET(2) NUMBITS(1) [],
@@ -39,15 +60,25 @@ register_bitfields![u32,
             FOURTEEN_BYTE = 3,
         ],
     ],
+    LSR [
+        DATA_READY OFFSET(0) NUMBITS(1) [],
+        TX_HOLDING_REGISTER_EMPTY OFFSET(5) NUMBITS(1) [],
+    ],
 ];
 
 pub struct Uart<'a> {
     registers: StaticRef<UartRegisters>,
     tx_client: OptionalCell<&'a dyn hil::uart::TransmitClient>,
     rx_client: OptionalCell<&'a dyn hil::uart::ReceiveClient>,
-    buffer: TakeCell<'static, [u8]>,
-    len: Cell<usize>,
-    index: Cell<usize>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_index: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    /// The receive was aborted, the buffer is returned from a deferred call.
+    rx_aborted: Cell<bool>,
+    deferred_call: DeferredCall,
 }
 
 impl<'a> Uart<'a> {
@@ -56,45 +87,111 @@ impl<'a> Uart<'a> {
             registers: base,
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
-            buffer: TakeCell::empty(),
-            len: Cell::new(0),
-            index: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_index: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_aborted: Cell::new(false),
+            deferred_call: DeferredCall::new(),
         }
     }
 
     pub fn handle_interrupt(&self) {
-        // Disable the interrupt
-        self.registers.fcr.modify(FCR::CLEAR_TX::SET);
+        // Reading IIR reports the pending interrupt of the highest priority
+        // and clears a transmitter holding register empty interrupt.
+        loop {
+            let iir = self.registers.iir_fcr.extract();
+            if iir.is_set(IIR::NOT_PENDING) {
+                break;
+            }
+            match iir.read_as_enum(IIR::ID) {
+                Some(IIR::ID::Value::ReceivedDataAvailable)
+                | Some(IIR::ID::Value::CharacterTimeout) => self.receive(),
+                Some(IIR::ID::Value::TxHoldingRegisterEmpty) => self.transmit_continue(),
+                // Reading LSR clears a line status interrupt.
+                _ => {
+                    let _ = self.registers.lsr.get();
+                }
+            }
+        }
+    }
 
-        if self.len.get() == self.index.get() {
+    fn transmit_continue(&self) {
+        // The interrupt also fires when the FIFO empties with nothing to
+        // send.
+        if self.tx_buffer.is_none() {
+            return;
+        }
+
+        if self.tx_len.get() == self.tx_index.get() {
             // We are done.
-            self.index.set(0);
+            self.tx_index.set(0);
 
             // Signal client write done
             self.tx_client.map(|client| {
-                self.buffer.take().map(|buffer| {
-                    client.transmitted_buffer(buffer, self.len.get(), Ok(()));
+                self.tx_buffer.take().map(|buffer| {
+                    client.transmitted_buffer(buffer, self.tx_len.get(), Ok(()));
                 });
             });
         } else {
-            self.buffer.map(|tx_data| {
-                // Fill the TX buffer until it reports full.
-                for i in self.index.get()..self.len.get() {
-                    // Chek to see if the buffer is full
-                    if self.registers.lsr.get() & 0x20 == 0 {
-                        break;
-                    }
-
-                    // Write the byte from the array to the tx register.
-                    self.registers.brdl.set(tx_data[i] as u32);
-                    self.index.set(i + 1);
-                }
+            self.tx_buffer.map(|tx_data| self.fill_fifo(tx_data));
+        }
+    }
+
+    /// Write the bytes of `tx_data` left to send until the TX FIFO is full.
+    fn fill_fifo(&self, tx_data: &[u8]) {
+        for i in self.tx_index.get()..self.tx_len.get() {
+            // Check to see if the buffer is full
+            if !self.registers.lsr.is_set(LSR::TX_HOLDING_REGISTER_EMPTY) {
+                break;
+            }
+
+            // Write the byte from the array to the tx register.
+            self.registers.brdl.set(tx_data[i] as u32);
+            self.tx_index.set(i + 1);
+        }
+    }
+
+    fn receive(&self) {
+        let Some(rx_buffer) = self.rx_buffer.take() else {
+            // Nothing to receive into, leave the bytes in the FIFO.
+            self.registers
+                .ier
+                .modify(IER::RECEIVED_DATA_AVAILABLE::CLEAR);
+            return;
+        };
+        let len = self.rx_len.get();
+        let mut index = self.rx_index.get();
+
+        // Empty the FIFO, up to the expected length.
+        while index < len && self.registers.lsr.is_set(LSR::DATA_READY) {
+            rx_buffer[index] = self.registers.brdl.get() as u8;
+            index += 1;
+        }
+
+        if index == len {
+            // Bytes received from now on wait in the FIFO for the next
+            // buffer.
+            self.registers
+                .ier
+                .modify(IER::RECEIVED_DATA_AVAILABLE::CLEAR);
+            self.rx_index.set(0);
+
+            self.rx_client.map(move |client| {
+                client.received_buffer(rx_buffer, len, Ok(()), hil::uart::Error::None)
             });
+        } else {
+            self.rx_index.set(index);
+            self.rx_buffer.replace(rx_buffer);
         }
     }
 
     fn enable_interrupts(&self) {
-        self.registers.ier.set(0xF);
+        self.registers
+            .ier
+            .write(IER::TX_HOLDING_REGISTER_EMPTY::SET);
     }
 }
 
@@ -118,7 +215,7 @@ impl hil::uart::Configure for Uart<'_> {
         self.registers.lcr.set(0x3 | 0x00 | 0x00);
 
         self.registers
-            .fcr
+            .iir_fcr
             .write(FCR::FIFO_TRIG_LVL::EIGHT_BYTE + FCR::CLEAR_TX::SET + FCR::CLEAR_RX::SET);
 
         self.enable_interrupts();
@@ -142,20 +239,12 @@ impl<'a> hil::uart::Transmit<'a> for Uart<'a> {
         }
 
         // Fill the TX buffer until it reports full.
-        for i in 0..tx_len {
-            // Chek to see if the buffer is full
-            if self.registers.lsr.get() & 0x20 == 0 {
-                break;
-            }
-
-            // Write the byte from the array to the tx register.
-            self.registers.brdl.set(tx_data[i] as u32);
-            self.index.set(i + 1);
-        }
+        self.tx_index.set(0);
+        self.tx_len.set(tx_len);
+        self.fill_fifo(tx_data);
 
         // Save the buffer so we can keep sending it.
-        self.buffer.replace(tx_data);
-        self.len.set(tx_len);
+        self.tx_buffer.replace(tx_data);
 
         Ok(())
     }
@@ -177,16 +266,65 @@ impl<'a> hil::uart::Receive<'a> for Uart<'a> {
     fn receive_buffer(
         &self,
         rx_buffer: &'static mut [u8],
-        _rx_len: usize,
+        rx_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        Err((ErrorCode::FAIL, rx_buffer))
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+
+        self.rx_buffer.replace(rx_buffer);
+        self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+
+        // The client is called back from the interrupt handler, even if
+        // bytes already wait in the FIFO.
+        self.registers.ier.modify(IER::RECEIVED_DATA_AVAILABLE::SET);
+
+        Ok(())
     }
 
     fn receive_abort(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        if self.rx_buffer.is_none() {
+            return Ok(());
+        }
+        if self.rx_aborted.get() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.registers
+            .ier
+            .modify(IER::RECEIVED_DATA_AVAILABLE::CLEAR);
+        self.rx_aborted.set(true);
+        self.deferred_call.set();
+        Err(ErrorCode::BUSY)
     }
 
     fn receive_word(&self) -> Result<(), ErrorCode> {
         Err(ErrorCode::FAIL)
     }
 }
+
+impl DeferredCallClient for Uart<'_> {
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    fn handle_deferred_call(&self) {
+        if self.rx_aborted.replace(false) {
+            let index = self.rx_index.replace(0);
+            self.rx_buffer.take().map(|buffer| {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        buffer,
+                        index,
+                        Err(ErrorCode::CANCEL),
+                        hil::uart::Error::Aborted,
+                    )
+                });
+            });
+        }
+    }
+}
@@ -211,6 +211,30 @@ impl DeferredCall {
         }
     }
 
+    /// Forgets all the deferred calls created so far, with their clients and
+    /// whether they are scheduled, so that new ones can be created.
+    ///
+    /// Boards never need this. It is meant for hosts that set up one kernel
+    /// after the other in a single process, such as test harnesses, and is
+    /// only built with the `host_test` feature.
+    ///
+    /// # Safety
+    ///
+    /// The deferred calls created before must not be used anymore, as the
+    /// new ones reuse their indices. No other thread may use deferred calls
+    /// at the same time.
+    #[cfg(any(test, feature = "host_test"))]
+    pub unsafe fn reset() {
+        // SAFETY: No accesses to CTR/BITMASK/DEFCALLS are via an &mut, and the
+        // caller guarantees that no other thread accesses them.
+        let ctr = unsafe { &*addr_of!(CTR) };
+        let bitmask = unsafe { &*addr_of!(BITMASK) };
+        let defcalls = unsafe { &*addr_of!(DEFCALLS) };
+        ctr.set(0);
+        bitmask.set(0);
+        defcalls.iter().for_each(|dc| dc.clear());
+    }
+
     /// Returns true if any deferred calls are waiting to be serviced,
     /// false otherwise.
     pub fn has_tasks() -> bool {
@@ -111,7 +111,27 @@ pub trait HighSpeedClient {
     /// The length provided will always be less than or equal to the length of
     /// the buffer. Expects an additional call to either provide another buffer
     /// or stop sampling
-    fn samples_ready(&self, buf: &'static mut [u16], length: usize);
+    ///
+    /// `result` is an error if the ADC failed while filling the buffer. The
+    /// first `length` samples are still valid, and the client is expected to
+    /// stop sampling.
+    fn samples_ready(&self, buf: &'static mut [u16], length: usize, result: Result<(), ErrorCode>);
+}
+
+/// Optional analog configuration of the channels of an ADC, such as their
+/// gain or reference, for chips which support it. The meaning of the
+/// configuration word is specific to the chip.
+pub trait AdcChannelConfig {
+    type Channel;
+
+    /// Use `config` for the next sampling started on `channel`, single,
+    /// continuous or high-speed. Samplings started afterwards use the
+    /// default configuration of the chip again, unless `channel` is
+    /// configured again before them.
+    ///
+    /// Returns `INVAL` if the chip does not support `config`, or `BUSY` if a
+    /// sampling is in progress.
+    fn configure_channel(&self, channel: &Self::Channel, config: u32) -> Result<(), ErrorCode>;
 }
 
 pub trait AdcChannel<'a> {
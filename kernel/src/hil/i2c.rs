@@ -33,6 +33,10 @@ pub enum Error {
 
     /// The underlying device has another request in progress
     Busy,
+
+    /// The transfer did not complete in time, most likely because a device
+    /// holds the bus.
+    Timeout,
 }
 
 impl Into<ErrorCode> for Error {
@@ -43,6 +47,7 @@ impl Into<ErrorCode> for Error {
             Self::Overrun => ErrorCode::SIZE,
             Self::NotSupported => ErrorCode::NOSUPPORT,
             Self::Busy => ErrorCode::BUSY,
+            Self::Timeout => ErrorCode::FAIL,
         }
     }
 }
@@ -56,6 +61,7 @@ impl Display for Error {
             Error::Overrun => "I2C receive overrun",
             Error::NotSupported => "I2C/SMBus command not supported",
             Error::Busy => "I2C/SMBus is busy",
+            Error::Timeout => "I2C transfer timed out",
         };
         write!(fmt, "{}", display_str)
     }
@@ -157,6 +163,41 @@ pub trait SMBusMaster<'a>: I2CMaster<'a> {
     ) -> Result<(), (Error, &'static mut [u8])>;
 }
 
+/// Interface for an I2C Master hardware driver that can address devices
+/// with 10-bit addresses. The device implementing this will also seperately
+/// implement I2CMaster, whose transfers address devices with 7-bit
+/// addresses.
+///
+/// The transfers complete with the `I2CHwMasterClient`, addresses above
+/// 0x3FF are refused with `NotSupported`.
+pub trait I2CMaster10Bit<'a>: I2CMaster<'a> {
+    /// Write then read data, with a repeated start, to the device at the
+    /// 10-bit address `addr`.
+    fn write_read_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])>;
+
+    /// Write data to the device at the 10-bit address `addr`.
+    fn write_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])>;
+
+    /// Read data from the device at the 10-bit address `addr`.
+    fn read_10bit(
+        &self,
+        addr: u16,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])>;
+}
+
 /// Interface for an I2C Slave hardware driver.
 pub trait I2CSlave<'a> {
     fn set_slave_client(&self, slave_client: &'a dyn I2CHwSlaveClient);
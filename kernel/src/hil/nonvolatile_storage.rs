@@ -3,6 +3,20 @@
 // Copyright Tock Contributors 2022.
 
 //! Generic interface for nonvolatile memory.
+//!
+//! Migrating from earlier versions
+//! -------------------------------
+//!
+//! `NonvolatileStorageClient::read_done()` and `write_done()` take an
+//! additional `result: Result<(), ErrorCode>` argument. Previously a driver
+//! that failed part way through an operation could only report a short
+//! length. Implementors of `NonvolatileStorage` should pass `Ok(())` when the
+//! operation completed and the error otherwise, and clients should check
+//! `result` before trusting the contents of the buffer.
+//!
+//! `NonvolatileStorage::erase()` and `NonvolatileStorageClient::erase_done()`
+//! were added later, with default implementations: storage that cannot erase
+//! returns `NOSUPPORT`, and clients that never erase can ignore the callback.
 
 use crate::errorcode::ErrorCode;
 
@@ -30,17 +44,31 @@ pub trait NonvolatileStorage<'a> {
         address: usize,
         length: usize,
     ) -> Result<(), ErrorCode>;
+
+    /// Erase `length` bytes starting at address `address`, leaving them in
+    /// the erased state of the storage, e.g. zeros for FRAM. Returns
+    /// `NOSUPPORT` if the storage cannot erase.
+    fn erase(&self, _address: usize, _length: usize) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
 }
 
 /// Client interface for nonvolatile storage.
 pub trait NonvolatileStorageClient {
     /// `read_done` is called when the implementor is finished reading in to the
     /// buffer. The callback returns the buffer and the number of bytes that
-    /// were actually read.
-    fn read_done(&self, buffer: &'static mut [u8], length: usize);
+    /// were actually read. If the read failed, `result` holds the error and
+    /// `length` is the number of bytes read before the failure.
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>);
 
     /// `write_done` is called when the implementor is finished writing from the
     /// buffer. The callback returns the buffer and the number of bytes that
-    /// were actually written.
-    fn write_done(&self, buffer: &'static mut [u8], length: usize);
+    /// were actually written. If the write failed, `result` holds the error
+    /// and `length` is the number of bytes written before the failure.
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>);
+
+    /// `erase_done` is called when the implementor is finished erasing, with
+    /// the number of bytes erased. If the erase failed, `result` holds the
+    /// error and `length` is the number of bytes erased before the failure.
+    fn erase_done(&self, _length: usize, _result: Result<(), ErrorCode>) {}
 }
@@ -37,3 +37,167 @@ pub trait WatchDog {
 
 /// Implement default WatchDog trait for unit.
 impl WatchDog for () {}
+
+/// Told about the progress of long running operations through their
+/// [`WorkProgress`] handles.
+pub trait WorkMonitor {
+    /// The operation of handle `slot` started.
+    fn start(&self, slot: usize);
+
+    /// The operation of handle `slot` made progress.
+    fn touch(&self, slot: usize);
+
+    /// The operation of handle `slot` is done.
+    fn finish(&self, slot: usize);
+}
+
+/// A handle a capsule or chip driver uses to tell the watchdog about the
+/// progress of its long running operations.
+///
+/// While an operation is in progress, the watchdog is only tickled if the
+/// operation touches its handle between tickles. An operation that gets
+/// stuck thus lets the watchdog reset the board, even though the kernel loop
+/// keeps running. Operations that run for longer than the watchdog period
+/// in one go, without returning to the kernel loop, must touch their handle
+/// regularly to keep the board alive.
+#[derive(Copy, Clone)]
+pub struct WorkProgress<'a> {
+    monitor: &'a dyn WorkMonitor,
+    slot: usize,
+}
+
+impl<'a> WorkProgress<'a> {
+    pub fn new(monitor: &'a dyn WorkMonitor, slot: usize) -> WorkProgress<'a> {
+        WorkProgress { monitor, slot }
+    }
+
+    /// Start an operation. Starting an operation already in progress counts
+    /// as progress.
+    pub fn start(&self) {
+        self.monitor.start(self.slot);
+    }
+
+    /// Tell the watchdog the operation in progress made progress.
+    pub fn touch(&self) {
+        self.monitor.touch(self.slot);
+    }
+
+    /// Finish the operation. The watchdog no longer waits on it.
+    pub fn finish(&self) {
+        self.monitor.finish(self.slot);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Work {
+    /// No handle was given out for this slot.
+    Unused,
+    /// The handle has no operation in progress.
+    Idle,
+    /// An operation is in progress, `touched` if it made progress since the
+    /// last tickle.
+    InProgress { touched: bool },
+}
+
+/// A watchdog that passes tickles on to `W` only while all the operations
+/// in progress make progress.
+///
+/// Boards use it as their platform watchdog, wrapping the chip's, and hand
+/// out up to `N` [`WorkProgress`] handles with
+/// [`ProgressWatchDog::work_progress`].
+pub struct ProgressWatchDog<'a, W: WatchDog, const N: usize> {
+    watchdog: &'a W,
+    work: [core::cell::Cell<Work>; N],
+    /// Whether `W` was suspended, it keeps running through sleep while an
+    /// operation is in progress so a stuck one still resets the board.
+    suspended: core::cell::Cell<bool>,
+}
+
+impl<'a, W: WatchDog, const N: usize> ProgressWatchDog<'a, W, N> {
+    pub fn new(watchdog: &'a W) -> ProgressWatchDog<'a, W, N> {
+        ProgressWatchDog {
+            watchdog,
+            work: core::array::from_fn(|_| core::cell::Cell::new(Work::Unused)),
+            suspended: core::cell::Cell::new(false),
+        }
+    }
+
+    /// A new handle, `None` once all the `N` handles were given out.
+    pub fn work_progress(&'a self) -> Option<WorkProgress<'a>> {
+        let slot = self
+            .work
+            .iter()
+            .position(|work| work.get() == Work::Unused)?;
+        self.work[slot].set(Work::Idle);
+        Some(WorkProgress::new(self, slot))
+    }
+
+    /// Whether an operation is in progress on any handle.
+    pub fn in_progress(&self) -> bool {
+        self.work
+            .iter()
+            .any(|work| matches!(work.get(), Work::InProgress { .. }))
+    }
+
+    /// Tickle `W` if all the operations in progress were touched since the
+    /// last tickle, and start waiting for them to be touched again.
+    fn tickle_if_progressing(&self) {
+        if self
+            .work
+            .iter()
+            .any(|work| work.get() == Work::InProgress { touched: false })
+        {
+            return;
+        }
+        for work in self.work.iter() {
+            if work.get() == (Work::InProgress { touched: true }) {
+                work.set(Work::InProgress { touched: false });
+            }
+        }
+        self.watchdog.tickle();
+    }
+}
+
+impl<W: WatchDog, const N: usize> WatchDog for ProgressWatchDog<'_, W, N> {
+    fn setup(&self) {
+        self.watchdog.setup();
+    }
+
+    fn tickle(&self) {
+        self.tickle_if_progressing();
+    }
+
+    fn suspend(&self) {
+        if !self.in_progress() {
+            self.suspended.set(true);
+            self.watchdog.suspend();
+        }
+    }
+
+    fn resume(&self) {
+        if self.suspended.replace(false) {
+            self.watchdog.resume();
+        } else {
+            self.tickle_if_progressing();
+        }
+    }
+}
+
+impl<W: WatchDog, const N: usize> WorkMonitor for ProgressWatchDog<'_, W, N> {
+    fn start(&self, slot: usize) {
+        self.work[slot].set(Work::InProgress { touched: true });
+    }
+
+    fn touch(&self, slot: usize) {
+        if let Work::InProgress { .. } = self.work[slot].get() {
+            self.work[slot].set(Work::InProgress { touched: true });
+            // Operations running without returning to the kernel loop rely
+            // on their touches to tickle the watchdog.
+            self.tickle_if_progressing();
+        }
+    }
+
+    fn finish(&self, slot: usize) {
+        self.work[slot].set(Work::Idle);
+    }
+}
@@ -47,7 +47,15 @@ macro_rules! count_expressions {
 ///
 /// Online calculator: <https://crccalc.com/>
 pub fn crc32_posix(b: &[u8]) -> u32 {
-    let mut crc: u32 = 0;
+    crc32_posix_continue(!0, b)
+}
+
+/// Continue the POSIX-style CRC32 checksum `crc` of some bytes with the bytes
+/// of `b`, so that data too large to hold at once can be checksummed in
+/// chunks. `crc32_posix_continue(crc32_posix(a), b)` is the checksum of `a`
+/// followed by `b`.
+pub fn crc32_posix_continue(crc: u32, b: &[u8]) -> u32 {
+    let mut crc: u32 = !crc;
 
     for c in b {
         crc ^= (*c as u32) << 24;